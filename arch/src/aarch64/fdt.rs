@@ -530,6 +530,10 @@ fn create_memory_node(
                     node_memory_addr = memory_region_start_addr;
                 }
             }
+            // Nodes only made of hotpluggable memory have no memory at boot.
+            if mem_reg_prop.is_empty() {
+                continue;
+            }
             let memory_node_name = format!("memory@{node_memory_addr:x}");
             let memory_node = fdt.begin_node(&memory_node_name)?;
             fdt.property_string("device_type", "memory")?;
@@ -41,3 +41,6 @@ arm64_sys_reg!(MPIDR_EL1, 3, 0, 0, 0, 5);
 arm64_sys_reg!(ID_AA64MMFR0_EL1, 3, 0, 0, 7, 0);
 arm64_sys_reg!(TTBR1_EL1, 3, 0, 2, 0, 1);
 arm64_sys_reg!(TCR_EL1, 3, 0, 2, 0, 2);
+// Virtual counter of the guest. KVM swapped the encodings of CNTVCT_EL0 and
+// CNTV_CVAL_EL0 in its ABI, this is the one KVM uses for the counter.
+arm64_sys_reg!(KVM_REG_ARM_TIMER_CNT, 3, 3, 14, 3, 2);
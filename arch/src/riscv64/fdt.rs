@@ -0,0 +1,784 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::{NumaNodes, PciSpaceInfo};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::result;
+
+use super::super::DeviceType;
+use super::super::GuestMemoryMmap;
+use super::super::InitramfsConfig;
+use super::layout::{
+    APLIC_SIZE, APLIC_START, IMSIC_SIZE_PER_VCPU, IMSIC_START, IRQ_NUM, MEM_32BIT_DEVICES_SIZE,
+    MEM_32BIT_DEVICES_START, MEM_PCI_IO_SIZE, MEM_PCI_IO_START, PCI_HIGH_BASE,
+    PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+};
+use thiserror::Error;
+use vm_fdt::{FdtWriter, FdtWriterResult};
+use vm_memory::{Address, Bytes, GuestMemory, GuestMemoryError, GuestMemoryRegion};
+
+// This is a value for uniquely identifying the FDT node declaring the APLIC.
+const APLIC_PHANDLE: u32 = 1;
+// This is a value for uniquely identifying the FDT node declaring the IMSIC,
+// which is the MSI controller.
+const IMSIC_PHANDLE: u32 = 2;
+// This is a value for virtio-iommu. Now only one virtio-iommu device is supported.
+const VIRTIO_IOMMU_PHANDLE: u32 = 3;
+// NOTE: Keep FIRST_VCPU_INTC_PHANDLE the last PHANDLE defined.
+// This is a value for uniquely identifying the FDT node containing the local
+// interrupt controller of the first vCPU.
+// The last number of vCPU phandle depends on the number of vCPUs.
+const FIRST_VCPU_INTC_PHANDLE: u32 = 8;
+
+// Read the documentation specified when appending the root node to the FDT.
+const ADDRESS_CELLS: u32 = 0x2;
+const SIZE_CELLS: u32 = 0x2;
+
+// Supervisor external interrupt of the local interrupt controller, as per the
+// RISC-V privileged specification.
+const IRQ_S_EXT: u32 = 9;
+// Number of MSI identities of each IMSIC interrupt file, as set up by KVM by
+// default.
+const IMSIC_NUM_IDS: u32 = 255;
+
+// From https://elixir.bootlin.com/linux/v4.9.62/source/include/dt-bindings/interrupt-controller/irq.h#L17
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+const IRQ_TYPE_LEVEL_HI: u32 = 4;
+
+/// Trait for devices to be added to the Flattened Device Tree.
+pub trait DeviceInfoForFdt {
+    /// Returns the address where this device will be loaded.
+    fn addr(&self) -> u64;
+    /// Returns the associated interrupt for this device.
+    fn irq(&self) -> u32;
+    /// Returns the amount of memory that needs to be reserved for this device.
+    fn length(&self) -> u64;
+}
+
+/// Errors thrown while configuring the Flattened Device Tree for riscv64.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Failure in writing FDT in memory.
+    #[error("Failure in writing FDT in memory: {0}")]
+    WriteFdtToMemory(GuestMemoryError),
+}
+type Result<T> = result::Result<T, Error>;
+
+/// Creates the flattened device tree for this riscv64 VM.
+#[allow(clippy::too_many_arguments)]
+pub fn create_fdt<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHasher>(
+    guest_mem: &GuestMemoryMmap,
+    cmdline: &str,
+    num_vcpus: u32,
+    vcpu_isa: &str,
+    timebase_frequency: u32,
+    device_info: &HashMap<(DeviceType, String), T, S>,
+    initrd: &Option<InitramfsConfig>,
+    pci_space_info: &[PciSpaceInfo],
+    numa_nodes: &NumaNodes,
+    virtio_iommu_bdf: Option<u32>,
+) -> FdtWriterResult<Vec<u8>> {
+    // Allocate stuff necessary for the holding the blob.
+    let mut fdt = FdtWriter::new().unwrap();
+
+    // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/booting-without-of.txt#L845
+    // for the required nodes and properties.
+    let root_node = fdt.begin_node("")?;
+    fdt.property_string("compatible", "linux,dummy-virt")?;
+    fdt.property_u32("#address-cells", ADDRESS_CELLS)?;
+    fdt.property_u32("#size-cells", SIZE_CELLS)?;
+    // The wired interrupts of the devices are all routed through the APLIC.
+    fdt.property_u32("interrupt-parent", APLIC_PHANDLE)?;
+    create_cpu_nodes(
+        &mut fdt,
+        num_vcpus,
+        vcpu_isa,
+        timebase_frequency,
+        numa_nodes,
+    )?;
+    create_memory_node(&mut fdt, guest_mem, numa_nodes)?;
+    create_chosen_node(&mut fdt, cmdline, initrd)?;
+    create_imsic_node(&mut fdt, num_vcpus)?;
+    create_aplic_node(&mut fdt)?;
+    create_devices_node(&mut fdt, device_info)?;
+    create_pci_nodes(&mut fdt, pci_space_info, virtio_iommu_bdf)?;
+    if numa_nodes.len() > 1 {
+        create_distance_map_node(&mut fdt, numa_nodes)?;
+    }
+
+    // End Header node.
+    fdt.end_node(root_node)?;
+
+    let fdt_final = fdt.finish()?;
+
+    Ok(fdt_final)
+}
+
+pub fn write_fdt_to_memory(fdt_final: Vec<u8>, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    // Write FDT to memory.
+    guest_mem
+        .write_slice(fdt_final.as_slice(), super::layout::FDT_START)
+        .map_err(Error::WriteFdtToMemory)?;
+    Ok(())
+}
+
+// Following are the auxiliary function for creating the different nodes that we append to our FDT.
+fn create_cpu_nodes(
+    fdt: &mut FdtWriter,
+    num_vcpus: u32,
+    vcpu_isa: &str,
+    timebase_frequency: u32,
+    numa_nodes: &NumaNodes,
+) -> FdtWriterResult<()> {
+    // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/riscv/cpus.yaml.
+    let cpus_node = fdt.begin_node("cpus")?;
+    fdt.property_u32("#address-cells", 0x1)?;
+    fdt.property_u32("#size-cells", 0x0)?;
+    // The frequency of the time CSR, which is the one of the host.
+    fdt.property_u32("timebase-frequency", timebase_frequency)?;
+
+    for cpu_id in 0..num_vcpus {
+        let cpu_name = format!("cpu@{cpu_id:x}");
+        let cpu_node = fdt.begin_node(&cpu_name)?;
+        fdt.property_string("device_type", "cpu")?;
+        fdt.property_string("compatible", "riscv")?;
+        // The hart ID, as passed to the kernel through a0 at boot.
+        fdt.property_u32("reg", cpu_id)?;
+        fdt.property_string("riscv,isa", vcpu_isa)?;
+        fdt.property_string("status", "okay")?;
+
+        // Set NUMA node ID in cpu node.
+        for (numa_node_idx, numa_node) in numa_nodes.iter() {
+            if numa_node.cpus.contains(&(cpu_id as u8)) {
+                fdt.property_u32("numa-node-id", *numa_node_idx)?;
+                break;
+            }
+        }
+
+        // The local interrupt controller the IMSIC delivers the external
+        // interrupts to.
+        let intc_node = fdt.begin_node("interrupt-controller")?;
+        fdt.property_string("compatible", "riscv,cpu-intc")?;
+        fdt.property_u32("#interrupt-cells", 1)?;
+        fdt.property_null("interrupt-controller")?;
+        fdt.property_u32("phandle", FIRST_VCPU_INTC_PHANDLE + cpu_id)?;
+        fdt.end_node(intc_node)?;
+
+        fdt.end_node(cpu_node)?;
+    }
+
+    fdt.end_node(cpus_node)?;
+
+    Ok(())
+}
+
+fn create_memory_node(
+    fdt: &mut FdtWriter,
+    guest_mem: &GuestMemoryMmap,
+    numa_nodes: &NumaNodes,
+) -> FdtWriterResult<()> {
+    // See https://github.com/torvalds/linux/blob/58ae0b51506802713aa0e9956d1853ba4c722c98/Documentation/devicetree/bindings/numa.txt
+    // for NUMA setting in memory node.
+    if numa_nodes.len() > 1 {
+        for (numa_node_idx, numa_node) in numa_nodes.iter() {
+            let mut mem_reg_prop: Vec<u64> = Vec::new();
+            let mut node_memory_addr: u64 = 0;
+            // Each memory zone of numa will have its own memory node, but
+            // different numa nodes should not share same memory zones.
+            for memory_region in numa_node.memory_regions.iter() {
+                let memory_region_start_addr: u64 = memory_region.start_addr().raw_value();
+                let memory_region_size: u64 = memory_region.size() as u64;
+                mem_reg_prop.push(memory_region_start_addr);
+                mem_reg_prop.push(memory_region_size);
+                // Set the node address the first non-zero region address
+                if node_memory_addr == 0 {
+                    node_memory_addr = memory_region_start_addr;
+                }
+            }
+            // Nodes only made of hotpluggable memory have no memory at boot.
+            if mem_reg_prop.is_empty() {
+                continue;
+            }
+            let memory_node_name = format!("memory@{node_memory_addr:x}");
+            let memory_node = fdt.begin_node(&memory_node_name)?;
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_array_u64("reg", &mem_reg_prop)?;
+            fdt.property_u32("numa-node-id", *numa_node_idx)?;
+            fdt.end_node(memory_node)?;
+        }
+    } else {
+        // Note: memory regions from "GuestMemory" are sorted and non-zero
+        // sized, the contiguous ones are merged into a single memory node.
+        let mut ram_regions: Vec<(u64, u64)> = Vec::new();
+        for (start, size) in guest_mem
+            .iter()
+            .map(|m| (m.start_addr().raw_value(), m.len()))
+        {
+            match ram_regions.last_mut() {
+                Some((last_start, last_size)) if *last_start + *last_size == start => {
+                    *last_size += size
+                }
+                _ => ram_regions.push((start, size)),
+            }
+        }
+
+        for (start, size) in ram_regions {
+            let memory_node = fdt.begin_node(&format!("memory@{start:x}"))?;
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_array_u64("reg", &[start, size])?;
+            fdt.end_node(memory_node)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn create_chosen_node(
+    fdt: &mut FdtWriter,
+    cmdline: &str,
+    initrd: &Option<InitramfsConfig>,
+) -> FdtWriterResult<()> {
+    let chosen_node = fdt.begin_node("chosen")?;
+    fdt.property_string("bootargs", cmdline)?;
+
+    if let Some(initrd_config) = initrd {
+        let initrd_start = initrd_config.address.raw_value();
+        let initrd_end = initrd_config.address.raw_value() + initrd_config.size as u64;
+        fdt.property_u64("linux,initrd-start", initrd_start)?;
+        fdt.property_u64("linux,initrd-end", initrd_end)?;
+    }
+
+    fdt.end_node(chosen_node)?;
+
+    Ok(())
+}
+
+fn create_imsic_node(fdt: &mut FdtWriter, num_vcpus: u32) -> FdtWriterResult<()> {
+    // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/interrupt-controller/riscv,imsics.yaml.
+    let interrupts_extended: Vec<u32> = (0..num_vcpus)
+        .flat_map(|cpu_id| [FIRST_VCPU_INTC_PHANDLE + cpu_id, IRQ_S_EXT])
+        .collect();
+    let reg = [
+        IMSIC_START.raw_value(),
+        IMSIC_SIZE_PER_VCPU * num_vcpus as u64,
+    ];
+
+    let imsic_node = fdt.begin_node(&format!("imsics@{:x}", IMSIC_START.raw_value()))?;
+    fdt.property_string("compatible", "riscv,imsics")?;
+    fdt.property_null("interrupt-controller")?;
+    fdt.property_u32("#interrupt-cells", 0)?;
+    fdt.property_null("msi-controller")?;
+    fdt.property_u32("#msi-cells", 0)?;
+    fdt.property_array_u32("interrupts-extended", &interrupts_extended)?;
+    fdt.property_array_u64("reg", &reg)?;
+    fdt.property_u32("riscv,num-ids", IMSIC_NUM_IDS)?;
+    fdt.property_u32("phandle", IMSIC_PHANDLE)?;
+    fdt.end_node(imsic_node)?;
+
+    Ok(())
+}
+
+fn create_aplic_node(fdt: &mut FdtWriter) -> FdtWriterResult<()> {
+    // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/interrupt-controller/riscv,aplic.yaml.
+    // The APLIC forwards the wired interrupts as MSIs to the IMSIC.
+    let aplic_node = fdt.begin_node(&format!("aplic@{:x}", APLIC_START.raw_value()))?;
+    fdt.property_string("compatible", "riscv,aplic")?;
+    fdt.property_null("interrupt-controller")?;
+    // The first cell is the interrupt source, the second one its trigger type.
+    fdt.property_u32("#interrupt-cells", 2)?;
+    fdt.property_u32("msi-parent", IMSIC_PHANDLE)?;
+    fdt.property_array_u64("reg", &[APLIC_START.raw_value(), APLIC_SIZE])?;
+    fdt.property_u32("riscv,num-sources", IRQ_NUM)?;
+    fdt.property_u32("phandle", APLIC_PHANDLE)?;
+    fdt.end_node(aplic_node)?;
+
+    Ok(())
+}
+
+fn create_virtio_node<T: DeviceInfoForFdt + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> FdtWriterResult<()> {
+    let device_reg_prop = [dev_info.addr(), dev_info.length()];
+    let irq = [dev_info.irq(), IRQ_TYPE_EDGE_RISING];
+
+    let virtio_node = fdt.begin_node(&format!("virtio_mmio@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "virtio,mmio")?;
+    fdt.property_array_u64("reg", &device_reg_prop)?;
+    fdt.property_array_u32("interrupts", &irq)?;
+    fdt.property_u32("interrupt-parent", APLIC_PHANDLE)?;
+    fdt.end_node(virtio_node)?;
+
+    Ok(())
+}
+
+fn create_serial_node<T: DeviceInfoForFdt + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> FdtWriterResult<()> {
+    let serial_reg_prop = [dev_info.addr(), dev_info.length()];
+    let irq = [dev_info.irq(), IRQ_TYPE_LEVEL_HI];
+
+    let serial_node = fdt.begin_node(&format!("serial@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "ns16550a")?;
+    fdt.property_array_u64("reg", &serial_reg_prop)?;
+    fdt.property_u32("clock-frequency", 1_843_200)?;
+    fdt.property_array_u32("interrupts", &irq)?;
+    fdt.property_u32("interrupt-parent", APLIC_PHANDLE)?;
+    fdt.end_node(serial_node)?;
+
+    Ok(())
+}
+
+fn create_devices_node<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHasher>(
+    fdt: &mut FdtWriter,
+    dev_info: &HashMap<(DeviceType, String), T, S>,
+) -> FdtWriterResult<()> {
+    // Create one temp Vec to store all virtio devices
+    let mut ordered_virtio_device: Vec<&T> = Vec::new();
+
+    for ((device_type, _device_id), info) in dev_info {
+        match device_type {
+            DeviceType::Serial => create_serial_node(fdt, info)?,
+            DeviceType::Virtio(_) => {
+                ordered_virtio_device.push(info);
+            }
+        }
+    }
+
+    // Sort out virtio devices by address from low to high and insert them into fdt table.
+    ordered_virtio_device.sort_by_key(|&a| a.addr());
+    // Current address allocation strategy in cloud-hypervisor is: the first created device
+    // will be allocated to higher address. Here we reverse the vector to make sure that
+    // the older created device will appear in front of the newer created device in FDT.
+    ordered_virtio_device.reverse();
+    for ordered_device_info in ordered_virtio_device.drain(..) {
+        create_virtio_node(fdt, ordered_device_info)?;
+    }
+
+    Ok(())
+}
+
+fn create_pci_nodes(
+    fdt: &mut FdtWriter,
+    pci_device_info: &[PciSpaceInfo],
+    virtio_iommu_bdf: Option<u32>,
+) -> FdtWriterResult<()> {
+    // Add node for PCIe controller.
+    // See Documentation/devicetree/bindings/pci/host-generic-pci.txt in the kernel
+    // and https://elinux.org/Device_Tree_Usage.
+    // In multiple PCI segments setup, each PCI segment needs a PCI node.
+    for pci_device_info_elem in pci_device_info.iter() {
+        // Keep the PCIe high space above 8G, as on aarch64, so that the same
+        // firmware constraints are met.
+        let (pci_device_base_64bit, pci_device_size_64bit) =
+            if pci_device_info_elem.pci_device_space_start < PCI_HIGH_BASE.raw_value() {
+                (
+                    PCI_HIGH_BASE.raw_value(),
+                    pci_device_info_elem.pci_device_space_size
+                        - (PCI_HIGH_BASE.raw_value() - pci_device_info_elem.pci_device_space_start),
+                )
+            } else {
+                (
+                    pci_device_info_elem.pci_device_space_start,
+                    pci_device_info_elem.pci_device_space_size,
+                )
+            };
+        // There is no specific requirement of the 32bit MMIO range, and
+        // therefore at least we can make these ranges 4K aligned.
+        let pci_device_size_32bit: u64 =
+            MEM_32BIT_DEVICES_SIZE / ((1 << 12) * pci_device_info.len() as u64) * (1 << 12);
+        let pci_device_base_32bit: u64 = MEM_32BIT_DEVICES_START.0
+            + pci_device_size_32bit * pci_device_info_elem.pci_segment_id as u64;
+
+        let ranges = [
+            // io addresses. Since RISC-V will not use IO address,
+            // we can set the same IO address range for every segment.
+            0x1000000,
+            0_u32,
+            0_u32,
+            (MEM_PCI_IO_START.0 >> 32) as u32,
+            MEM_PCI_IO_START.0 as u32,
+            (MEM_PCI_IO_SIZE >> 32) as u32,
+            MEM_PCI_IO_SIZE as u32,
+            // mmio addresses
+            0x2000000,                            // (ss = 10: 32-bit memory space)
+            (pci_device_base_32bit >> 32) as u32, // PCI address
+            pci_device_base_32bit as u32,
+            (pci_device_base_32bit >> 32) as u32, // CPU address
+            pci_device_base_32bit as u32,
+            (pci_device_size_32bit >> 32) as u32, // size
+            pci_device_size_32bit as u32,
+            // device addresses
+            0x3000000,                            // (ss = 11: 64-bit memory space)
+            (pci_device_base_64bit >> 32) as u32, // PCI address
+            pci_device_base_64bit as u32,
+            (pci_device_base_64bit >> 32) as u32, // CPU address
+            pci_device_base_64bit as u32,
+            (pci_device_size_64bit >> 32) as u32, // size
+            pci_device_size_64bit as u32,
+        ];
+        let bus_range = [0, 0]; // Only bus 0
+        let reg = [
+            pci_device_info_elem.mmio_config_address,
+            PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+        ];
+        // See kernel document Documentation/devicetree/bindings/pci/pci-msi.txt
+        let msi_map = [
+            // rid-base: A single cell describing the first RID matched by the entry.
+            0x0,
+            // msi-controller: A single phandle to an MSI controller.
+            IMSIC_PHANDLE,
+            // msi-base: An msi-specifier describing the msi-specifier produced for the
+            // first RID matched by the entry.
+            (pci_device_info_elem.pci_segment_id as u32) << 8,
+            // length: A single cell describing how many consecutive RIDs are matched
+            // following the rid-base.
+            0x100,
+        ];
+
+        let pci_node_name = format!("pci@{:x}", pci_device_info_elem.mmio_config_address);
+        let pci_node = fdt.begin_node(&pci_node_name)?;
+
+        fdt.property_string("compatible", "pci-host-ecam-generic")?;
+        fdt.property_string("device_type", "pci")?;
+        fdt.property_array_u32("ranges", &ranges)?;
+        fdt.property_array_u32("bus-range", &bus_range)?;
+        fdt.property_u32(
+            "linux,pci-domain",
+            pci_device_info_elem.pci_segment_id as u32,
+        )?;
+        fdt.property_u32("#address-cells", 3)?;
+        fdt.property_u32("#size-cells", 2)?;
+        fdt.property_array_u64("reg", &reg)?;
+        fdt.property_u32("#interrupt-cells", 1)?;
+        fdt.property_null("interrupt-map")?;
+        fdt.property_null("interrupt-map-mask")?;
+        fdt.property_null("dma-coherent")?;
+        fdt.property_array_u32("msi-map", &msi_map)?;
+        fdt.property_u32("msi-parent", IMSIC_PHANDLE)?;
+
+        if pci_device_info_elem.pci_segment_id == 0 {
+            if let Some(virtio_iommu_bdf) = virtio_iommu_bdf {
+                // See kernel document Documentation/devicetree/bindings/pci/pci-iommu.txt
+                // for 'iommu-map' attribute setting.
+                let iommu_map = [
+                    0_u32,
+                    VIRTIO_IOMMU_PHANDLE,
+                    0_u32,
+                    virtio_iommu_bdf,
+                    virtio_iommu_bdf + 1,
+                    VIRTIO_IOMMU_PHANDLE,
+                    virtio_iommu_bdf + 1,
+                    0xffff - virtio_iommu_bdf,
+                ];
+                fdt.property_array_u32("iommu-map", &iommu_map)?;
+
+                // See kernel document Documentation/devicetree/bindings/virtio/iommu.txt
+                // for virtio-iommu node settings.
+                let virtio_iommu_node_name = format!("virtio_iommu@{virtio_iommu_bdf:x}");
+                let virtio_iommu_node = fdt.begin_node(&virtio_iommu_node_name)?;
+                fdt.property_u32("#iommu-cells", 1)?;
+                fdt.property_string("compatible", "virtio,pci-iommu")?;
+
+                // 'reg' is a five-cell address encoded as
+                // (phys.hi phys.mid phys.lo size.hi size.lo). phys.hi should contain the
+                // device's BDF as 0b00000000 bbbbbbbb dddddfff 00000000. The other cells
+                // should be zero.
+                let reg = [virtio_iommu_bdf << 8, 0_u32, 0_u32, 0_u32, 0_u32];
+                fdt.property_array_u32("reg", &reg)?;
+                fdt.property_u32("phandle", VIRTIO_IOMMU_PHANDLE)?;
+
+                fdt.end_node(virtio_iommu_node)?;
+            }
+        }
+
+        fdt.end_node(pci_node)?;
+    }
+
+    Ok(())
+}
+
+fn create_distance_map_node(fdt: &mut FdtWriter, numa_nodes: &NumaNodes) -> FdtWriterResult<()> {
+    let distance_map_node = fdt.begin_node("distance-map")?;
+    fdt.property_string("compatible", "numa-distance-map-v1")?;
+    // Construct the distance matrix, with entries <from to distance> in
+    // lexicographical ascending order of nodes. The local distance is 10,
+    // all the internode distances are greater than 10.
+    let mut distance_matrix = Vec::new();
+    for (numa_node_idx, numa_node) in numa_nodes.iter() {
+        for dest_numa_node_idx in numa_nodes.keys() {
+            let distance = if numa_node_idx == dest_numa_node_idx {
+                10
+            } else {
+                *numa_node.distances.get(dest_numa_node_idx).unwrap() as u32
+            };
+            distance_matrix.extend([*numa_node_idx, *dest_numa_node_idx, distance]);
+        }
+    }
+    fdt.property_array_u32("distance-matrix", distance_matrix.as_ref())?;
+    fdt.end_node(distance_map_node)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::layout::{
+        FDT_START, LEGACY_SERIAL_MAPPED_IO_START, PCI_MMCONFIG_START, RAM_START,
+    };
+    use super::*;
+    use crate::MmioDeviceInfo;
+    use vm_memory::GuestAddress;
+
+    fn cells(value: &[u8]) -> Vec<u32> {
+        value
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    fn property(fdt: &fdt_parser::Fdt, path: &str, name: &str) -> Vec<u32> {
+        cells(fdt.find_node(path).unwrap().property(name).unwrap().value)
+    }
+
+    fn create(
+        guest_mem: &GuestMemoryMmap,
+        num_vcpus: u32,
+        initrd: &Option<InitramfsConfig>,
+        pci_space_info: &[PciSpaceInfo],
+    ) -> Vec<u8> {
+        let mut device_info = HashMap::new();
+        device_info.insert(
+            (DeviceType::Serial, DeviceType::Serial.to_string()),
+            MmioDeviceInfo {
+                addr: LEGACY_SERIAL_MAPPED_IO_START.raw_value(),
+                len: 0x1000,
+                irq: 1,
+            },
+        );
+        for (i, addr) in [0x0a00_0000, 0x0a00_1000].into_iter().enumerate() {
+            device_info.insert(
+                (DeviceType::Virtio(i as u32), format!("virtio{i}")),
+                MmioDeviceInfo {
+                    addr,
+                    len: 0x1000,
+                    irq: 2 + i as u32,
+                },
+            );
+        }
+
+        create_fdt(
+            guest_mem,
+            "console=ttyS0",
+            num_vcpus,
+            "rv64imafdc_sstc",
+            10_000_000,
+            &device_info,
+            initrd,
+            pci_space_info,
+            &NumaNodes::new(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cpu_nodes() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(RAM_START, 0x1000_0000)]).unwrap();
+        let dtb = create(&guest_mem, 4, &None, &[]);
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+
+        assert_eq!(
+            property(&fdt, "/cpus", "timebase-frequency"),
+            vec![10_000_000]
+        );
+        for cpu_id in 0..4 {
+            let cpu = format!("/cpus/cpu@{cpu_id:x}");
+            assert_eq!(property(&fdt, &cpu, "reg"), vec![cpu_id]);
+            assert_eq!(
+                fdt.find_node(&cpu)
+                    .unwrap()
+                    .property("riscv,isa")
+                    .unwrap()
+                    .as_str(),
+                Some("rv64imafdc_sstc")
+            );
+            assert_eq!(
+                property(&fdt, &format!("{cpu}/interrupt-controller"), "phandle"),
+                vec![FIRST_VCPU_INTC_PHANDLE + cpu_id]
+            );
+        }
+    }
+
+    #[test]
+    fn test_aia_nodes() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(RAM_START, 0x1000_0000)]).unwrap();
+        let dtb = create(&guest_mem, 2, &None, &[]);
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+
+        // Every device interrupt goes through the APLIC, which forwards it
+        // as an MSI to the IMSIC file of the vCPU.
+        let root = fdt.all_nodes().next().unwrap();
+        assert_eq!(
+            cells(root.property("interrupt-parent").unwrap().value),
+            vec![APLIC_PHANDLE]
+        );
+
+        let imsic = format!("/imsics@{:x}", IMSIC_START.raw_value());
+        assert_eq!(property(&fdt, &imsic, "phandle"), vec![IMSIC_PHANDLE]);
+        assert_eq!(
+            property(&fdt, &imsic, "interrupts-extended"),
+            vec![
+                FIRST_VCPU_INTC_PHANDLE,
+                IRQ_S_EXT,
+                FIRST_VCPU_INTC_PHANDLE + 1,
+                IRQ_S_EXT
+            ]
+        );
+        assert_eq!(
+            property(&fdt, &imsic, "reg"),
+            vec![
+                0,
+                IMSIC_START.raw_value() as u32,
+                0,
+                2 * IMSIC_SIZE_PER_VCPU as u32
+            ]
+        );
+
+        let aplic = format!("/aplic@{:x}", APLIC_START.raw_value());
+        assert_eq!(property(&fdt, &aplic, "phandle"), vec![APLIC_PHANDLE]);
+        assert_eq!(property(&fdt, &aplic, "msi-parent"), vec![IMSIC_PHANDLE]);
+        assert_eq!(property(&fdt, &aplic, "riscv,num-sources"), vec![IRQ_NUM]);
+    }
+
+    #[test]
+    fn test_device_nodes() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(RAM_START, 0x1000_0000)]).unwrap();
+        let dtb = create(&guest_mem, 1, &None, &[]);
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+
+        let serial = format!("/serial@{:x}", LEGACY_SERIAL_MAPPED_IO_START.raw_value());
+        assert_eq!(
+            property(&fdt, &serial, "interrupts"),
+            vec![1, IRQ_TYPE_LEVEL_HI]
+        );
+        assert_eq!(
+            property(&fdt, &serial, "interrupt-parent"),
+            vec![APLIC_PHANDLE]
+        );
+
+        // The virtio devices are listed from the highest address down, so
+        // the oldest one comes first.
+        let virtio: Vec<_> = fdt
+            .all_nodes()
+            .filter(|node| node.name.starts_with("virtio_mmio@"))
+            .map(|node| (node.name, cells(node.property("interrupts").unwrap().value)))
+            .collect();
+        assert_eq!(
+            virtio,
+            vec![
+                ("virtio_mmio@a001000", vec![3, IRQ_TYPE_EDGE_RISING]),
+                ("virtio_mmio@a000000", vec![2, IRQ_TYPE_EDGE_RISING]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_and_chosen_nodes() {
+        // Contiguous regions are described by a single memory node.
+        let guest_mem = GuestMemoryMmap::from_ranges(&[
+            (RAM_START, 0x1000_0000),
+            (RAM_START.unchecked_add(0x1000_0000), 0x1000_0000),
+            (GuestAddress(0x1_0000_0000), 0x1000_0000),
+        ])
+        .unwrap();
+        let initrd = Some(InitramfsConfig {
+            address: GuestAddress(0x9000_0000),
+            size: 0x10_0000,
+        });
+        let dtb = create(&guest_mem, 1, &initrd, &[]);
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+
+        assert_eq!(
+            property(&fdt, &format!("/memory@{:x}", RAM_START.raw_value()), "reg"),
+            vec![0, RAM_START.raw_value() as u32, 0, 0x2000_0000]
+        );
+        assert_eq!(
+            property(&fdt, "/memory@100000000", "reg"),
+            vec![1, 0, 0, 0x1000_0000]
+        );
+        assert_eq!(
+            fdt.all_nodes()
+                .filter(|node| node.name.starts_with("memory@"))
+                .count(),
+            2
+        );
+
+        assert_eq!(fdt.chosen().bootargs(), Some("console=ttyS0"));
+        assert_eq!(
+            property(&fdt, "/chosen", "linux,initrd-start"),
+            vec![0, 0x9000_0000]
+        );
+        assert_eq!(
+            property(&fdt, "/chosen", "linux,initrd-end"),
+            vec![0, 0x9010_0000]
+        );
+    }
+
+    #[test]
+    fn test_pci_node() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(RAM_START, 0x1000_0000)]).unwrap();
+        let pci_space_info = [PciSpaceInfo {
+            pci_segment_id: 0,
+            mmio_config_address: PCI_MMCONFIG_START.raw_value(),
+            pci_device_space_start: 0x1_0000_0000,
+            pci_device_space_size: 0x4_0000_0000,
+        }];
+        let dtb = create(&guest_mem, 1, &None, &pci_space_info);
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+
+        let pci = format!("/pci@{:x}", PCI_MMCONFIG_START.raw_value());
+        assert_eq!(
+            property(&fdt, &pci, "reg"),
+            vec![
+                0,
+                PCI_MMCONFIG_START.raw_value() as u32,
+                0,
+                PCI_MMIO_CONFIG_SIZE_PER_SEGMENT as u32
+            ]
+        );
+        assert_eq!(property(&fdt, &pci, "msi-parent"), vec![IMSIC_PHANDLE]);
+        assert_eq!(
+            property(&fdt, &pci, "msi-map"),
+            vec![0, IMSIC_PHANDLE, 0, 0x100]
+        );
+        // The 64-bit window starts at the PCI high base, its size shrunk
+        // accordingly.
+        let ranges = property(&fdt, &pci, "ranges");
+        assert_eq!(ranges[14..], [0x3000000, 2, 0, 2, 0, 3, 0]);
+    }
+
+    #[test]
+    fn test_write_fdt_to_memory() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(RAM_START, 0x1000_0000)]).unwrap();
+        let dtb = create(&guest_mem, 1, &None, &[]);
+        write_fdt_to_memory(dtb.clone(), &guest_mem).unwrap();
+
+        let mut written = vec![0; dtb.len()];
+        guest_mem.read_slice(&mut written, FDT_START).unwrap();
+        assert_eq!(written, dtb);
+        assert_eq!(written[..4], 0xd00d_feed_u32.to_be_bytes());
+
+        // The FDT must be placed in the guest memory.
+        let guest_mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0x1_0000_0000), 0x1000_0000)]).unwrap();
+        assert!(matches!(
+            write_fdt_to_memory(dtb, &guest_mem),
+            Err(Error::WriteFdtToMemory(_))
+        ));
+    }
+}
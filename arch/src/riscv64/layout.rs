@@ -0,0 +1,119 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//
+// Memory layout of RISC-V 64-bit guest:
+//
+// Physical  +---------------------------------------------------------------+
+// address   |                                                               |
+// end       |                                                               |
+//           ~                   ~                       ~                   ~
+//           |                                                               |
+//           |                      Highmem PCI MMIO space                   |
+//           |                                                               |
+// RAM end   +---------------------------------------------------------------+
+// (dynamic, |                                                               |
+// including |                                                               |
+// hotplug   ~                   ~                       ~                   ~
+// memory)   |                                                               |
+//           |                            DRAM                               |
+//           |                                                               |
+//           |                                                               |
+// 4GB       +---------------------------------------------------------------+
+//           |                      32-bit devices hole                      |
+// 4GB-64M   +---------------------------------------------------------------+
+//           |                                                               |
+//           |                            DRAM                               |
+//           |                                                               |
+// 2GB       +---------------------------------------------------------------+
+//           |                                                               |
+//           |                           Reserved                            |
+//           |                                                               |
+// 1GB       +---------------------------------------------------------------+
+//           |                                                               |
+//           |                        PCI MMCONFIG space                     |
+//           |                                                               |
+// 768 M     +---------------------------------------------------------------+
+//           |                                                               |
+//           |                           PCI MMIO space                      |
+//           |                                                               |
+// 256 M     +---------------------------------------------------------------|
+//           |                                                               |
+//           |                        Legacy devices space                   |
+//           |                                                               |
+// 144 M     +---------------------------------------------------------------|
+//           |                                                               |
+//           |                 Reserved (now AIA is here)                    |
+//           |                                                               |
+// 0GB       +---------------------------------------------------------------+
+//
+//
+
+use vm_memory::GuestAddress;
+
+/// Below this address will reside the AIA, above this address will reside the MMIO devices.
+const MAPPED_IO_START: GuestAddress = GuestAddress(0x0900_0000);
+
+/// See kernel file arch/riscv/include/uapi/asm/kvm.h for the AIA related definitions.
+/// 0x0800_0000 ~ 0x0880_0000 is reserved for the IMSIC interrupt files, one
+/// page per vcpu, which limits the number of vcpus to 2048.
+pub const IMSIC_START: GuestAddress = GuestAddress(0x0800_0000);
+pub const IMSIC_SIZE_PER_VCPU: u64 = 0x1000;
+/// 0x0880_0000 ~ 0x0880_4000 is reserved for the APLIC domain of the
+/// supervisor mode.
+pub const APLIC_START: GuestAddress = GuestAddress(0x0880_0000);
+pub const APLIC_SIZE: u64 = 0x4000;
+
+/// Space 0x0900_0000 ~ 0x0905_0000 is reserved for legacy devices.
+pub const LEGACY_SERIAL_MAPPED_IO_START: GuestAddress = MAPPED_IO_START;
+
+/// Space 0x0905_0000 ~ 0x0906_0000 is reserved for pcie io address
+pub const MEM_PCI_IO_START: GuestAddress = GuestAddress(0x0905_0000);
+pub const MEM_PCI_IO_SIZE: u64 = 0x10000;
+
+/// Starting from 0x1000_0000 (256MiB) to 0x3000_0000 (768MiB) is used for PCIE MMIO
+pub const MEM_32BIT_DEVICES_START: GuestAddress = GuestAddress(0x1000_0000);
+pub const MEM_32BIT_DEVICES_SIZE: u64 = 0x2000_0000;
+
+/// PCI MMCONFIG space (start: after the device space at 768 MiB, length: 256MiB)
+pub const PCI_MMCONFIG_START: GuestAddress = GuestAddress(0x3000_0000);
+pub const PCI_MMCONFIG_SIZE: u64 = 256 << 20;
+// One bus with potentially 256 devices (32 slots x 8 functions).
+pub const PCI_MMIO_CONFIG_SIZE_PER_SEGMENT: u64 = 4096 * 256;
+
+/// Start of RAM, where the firmware of the usual RISC-V platforms expects it.
+pub const RAM_START: GuestAddress = GuestAddress(0x8000_0000);
+
+/// 32-bit reserved area: 64MiB before 4GiB
+pub const MEM_32BIT_RESERVED_START: GuestAddress = GuestAddress(0xfc00_0000);
+pub const MEM_32BIT_RESERVED_SIZE: u64 = 0x0400_0000;
+
+/// Start of 64-bit RAM.
+pub const RAM_64BIT_START: GuestAddress = GuestAddress(0x1_0000_0000);
+
+/// Kernel command line maximum size.
+/// As per `arch/riscv/include/uapi/asm/setup.h`.
+pub const CMDLINE_MAX_SIZE: usize = 1024;
+
+/// FDT is at the beginning of RAM.
+pub const FDT_START: GuestAddress = RAM_START;
+/// Maximum size of the device tree blob, which also keeps the kernel aligned
+/// on 2 MiB as required by the [boot
+/// protocol](https://www.kernel.org/doc/html/latest/arch/riscv/boot.html).
+pub const FDT_MAX_SIZE: u64 = 0x20_0000;
+
+/// Kernel start after FDT
+pub const KERNEL_START: GuestAddress = GuestAddress(FDT_START.0 + FDT_MAX_SIZE);
+
+/// Pci high memory base
+pub const PCI_HIGH_BASE: GuestAddress = GuestAddress(0x2_0000_0000);
+
+// Interrupt 0 is reserved by the APLIC, the wired interrupts of the devices
+// start right after it.
+/// First usable interrupt on riscv64
+pub const IRQ_BASE: u32 = 1;
+
+/// Number of supported interrupts
+pub const IRQ_NUM: u32 = 256;
@@ -0,0 +1,264 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// Module for the flattened device tree.
+pub mod fdt;
+/// Layout for this riscv64 system.
+pub mod layout;
+
+pub use self::fdt::DeviceInfoForFdt;
+use crate::{DeviceType, GuestMemoryMmap, NumaNodes, PciSpaceInfo, RegionType};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use thiserror::Error;
+use vm_memory::{Address, GuestAddress, GuestMemory};
+
+pub const _NSIG: i32 = 65;
+
+/// Errors thrown while configuring riscv64 system.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Failed to create a FDT.
+    #[error("Failed to create a FDT")]
+    SetupFdt,
+
+    /// Failed to write FDT to memory.
+    #[error("Failed to write FDT to memory: {0}")]
+    WriteFdtToMemory(fdt::Error),
+
+    /// Failed to compute the initramfs address.
+    #[error("Failed to compute the initramfs address")]
+    InitramfsAddress,
+}
+
+impl From<Error> for super::Error {
+    fn from(e: Error) -> super::Error {
+        super::Error::PlatformSpecific(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// Specifies the entry point address where the guest must start
+/// executing code.
+///
+/// As per the RISC-V boot protocol, the boot hart enters the kernel in
+/// supervisor mode with its hart ID in `a0` and the address of the FDT
+/// (`layout::FDT_START`) in `a1`. The SBI services the kernel relies on are
+/// provided by KVM.
+pub struct EntryPoint {
+    /// Address in guest memory where the guest must start execution
+    pub entry_addr: GuestAddress,
+}
+
+pub fn arch_memory_regions() -> Vec<(GuestAddress, usize, RegionType)> {
+    vec![
+        // 0 MiB ~ 256 MiB: AIA and legacy devices
+        (
+            GuestAddress(0),
+            layout::MEM_32BIT_DEVICES_START.0 as usize,
+            RegionType::Reserved,
+        ),
+        // 256 MiB ~ 768 MiB: MMIO space
+        (
+            layout::MEM_32BIT_DEVICES_START,
+            layout::MEM_32BIT_DEVICES_SIZE as usize,
+            RegionType::SubRegion,
+        ),
+        // 768 MiB ~ 2 GiB: reserved. The leading 256M for PCIe MMCONFIG space
+        (
+            layout::PCI_MMCONFIG_START,
+            layout::RAM_START.unchecked_offset_from(layout::PCI_MMCONFIG_START) as usize,
+            RegionType::Reserved,
+        ),
+        // 2GiB ~ 4032 MiB: RAM before the gap
+        (
+            layout::RAM_START,
+            layout::MEM_32BIT_RESERVED_START.unchecked_offset_from(layout::RAM_START) as usize,
+            RegionType::Ram,
+        ),
+        // 4GiB ~ inf: RAM after the gap
+        (layout::RAM_64BIT_START, usize::MAX, RegionType::Ram),
+        // Add the 32-bit reserved memory hole as a reserved region
+        (
+            layout::MEM_32BIT_RESERVED_START,
+            layout::MEM_32BIT_RESERVED_SIZE as usize,
+            RegionType::Reserved,
+        ),
+    ]
+}
+
+/// Configures the system and should be called once per vm before starting vcpu threads.
+#[allow(clippy::too_many_arguments)]
+pub fn configure_system<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHasher>(
+    guest_mem: &GuestMemoryMmap,
+    cmdline: &str,
+    num_vcpus: u32,
+    vcpu_isa: &str,
+    timebase_frequency: u32,
+    device_info: &HashMap<(DeviceType, String), T, S>,
+    initrd: &Option<super::InitramfsConfig>,
+    pci_space_info: &[PciSpaceInfo],
+    virtio_iommu_bdf: Option<u32>,
+    numa_nodes: &NumaNodes,
+) -> super::Result<()> {
+    let fdt_final = fdt::create_fdt(
+        guest_mem,
+        cmdline,
+        num_vcpus,
+        vcpu_isa,
+        timebase_frequency,
+        device_info,
+        initrd,
+        pci_space_info,
+        numa_nodes,
+        virtio_iommu_bdf,
+    )
+    .map_err(|_| Error::SetupFdt)?;
+
+    fdt::write_fdt_to_memory(fdt_final, guest_mem).map_err(Error::WriteFdtToMemory)?;
+
+    Ok(())
+}
+
+/// Returns the memory address where the initramfs could be loaded.
+pub fn initramfs_load_addr(
+    guest_mem: &GuestMemoryMmap,
+    initramfs_size: usize,
+) -> super::Result<u64> {
+    let round_to_pagesize = |size| (size + (super::PAGE_SIZE - 1)) & !(super::PAGE_SIZE - 1);
+    match guest_mem
+        .last_addr()
+        .checked_sub(round_to_pagesize(initramfs_size) as u64 - 1)
+    {
+        Some(offset) => {
+            if guest_mem.address_in_range(offset) {
+                Ok(offset.raw_value())
+            } else {
+                Err(super::Error::PlatformSpecific(Error::InitramfsAddress))
+            }
+        }
+        None => Err(super::Error::PlatformSpecific(Error::InitramfsAddress)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MmioDeviceInfo;
+
+    #[test]
+    fn test_arch_memory_regions_dram() {
+        let regions = arch_memory_regions();
+        assert_eq!(6, regions.len());
+        assert_eq!(layout::RAM_START, regions[3].0);
+        assert_eq!(RegionType::Ram, regions[3].2);
+        assert_eq!(RegionType::Reserved, regions[5].2);
+        assert_eq!(RegionType::Ram, regions[4].2);
+
+        // Apart from the unbounded RAM, the regions cover the first 4 GiB
+        // without any overlap.
+        let mut regions: Vec<_> = regions
+            .iter()
+            .filter(|r| r.1 != usize::MAX)
+            .map(|r| (r.0.raw_value(), r.1 as u64))
+            .collect();
+        regions.sort();
+        let mut end = 0;
+        for (start, size) in regions {
+            assert_eq!(start, end);
+            end = start + size;
+        }
+        assert_eq!(end, layout::RAM_64BIT_START.raw_value());
+    }
+
+    #[test]
+    fn test_layout() {
+        // The AIA (with the interrupt files of up to 2048 vCPUs), the legacy
+        // devices and the PCI spaces are laid out in order below the RAM.
+        let ranges = [
+            (layout::IMSIC_START, layout::IMSIC_SIZE_PER_VCPU * 2048),
+            (layout::APLIC_START, layout::APLIC_SIZE),
+            (layout::LEGACY_SERIAL_MAPPED_IO_START, 0x1000),
+            (layout::MEM_PCI_IO_START, layout::MEM_PCI_IO_SIZE),
+            (
+                layout::MEM_32BIT_DEVICES_START,
+                layout::MEM_32BIT_DEVICES_SIZE,
+            ),
+            (layout::PCI_MMCONFIG_START, layout::PCI_MMCONFIG_SIZE),
+            (layout::RAM_START, 0),
+        ];
+        for pair in ranges.windows(2) {
+            assert!(
+                pair[0].0.raw_value() + pair[0].1 <= pair[1].0.raw_value(),
+                "{pair:x?}"
+            );
+        }
+
+        // The FDT is at the start of RAM, followed by the kernel which must be
+        // 2 MiB aligned.
+        assert_eq!(layout::FDT_START, layout::RAM_START);
+        assert_eq!(
+            layout::KERNEL_START.raw_value(),
+            layout::FDT_START.raw_value() + layout::FDT_MAX_SIZE
+        );
+        assert_eq!(layout::KERNEL_START.raw_value() & 0x1f_ffff, 0);
+    }
+
+    #[test]
+    fn test_initramfs_load_addr() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(layout::RAM_START, 0x1000_0000)]).unwrap();
+
+        // The initramfs is loaded page aligned at the end of the RAM.
+        assert_eq!(
+            initramfs_load_addr(&guest_mem, 0x1234).unwrap(),
+            layout::RAM_START.raw_value() + 0x1000_0000 - 0x2000
+        );
+        assert!(initramfs_load_addr(&guest_mem, 0x2000_0000).is_err());
+    }
+
+    #[test]
+    fn test_create_fdt() {
+        let guest_mem = GuestMemoryMmap::from_ranges(&[
+            (layout::RAM_START, 0x1000_0000),
+            (layout::RAM_64BIT_START, 0x1000_0000),
+        ])
+        .unwrap();
+        let mut device_info = HashMap::new();
+        device_info.insert(
+            (DeviceType::Serial, DeviceType::Serial.to_string()),
+            MmioDeviceInfo {
+                addr: layout::LEGACY_SERIAL_MAPPED_IO_START.raw_value(),
+                len: 0x1000,
+                irq: layout::IRQ_BASE,
+            },
+        );
+
+        let dtb = fdt::create_fdt(
+            &guest_mem,
+            "console=ttyS0",
+            2,
+            "rv64imafdc",
+            10_000_000,
+            &device_info,
+            &None,
+            &[],
+            &NumaNodes::new(),
+            None,
+        )
+        .unwrap();
+
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+        assert_eq!(fdt.cpus().count(), 2);
+        assert_eq!(
+            fdt.all_nodes()
+                .filter(|node| node.name.starts_with("memory@"))
+                .count(),
+            2
+        );
+        assert_eq!(fdt.chosen().bootargs(), Some("console=ttyS0"));
+        assert!(fdt.find_compatible(&["riscv,aplic"]).is_some());
+        assert!(fdt.find_compatible(&["ns16550a"]).is_some());
+    }
+}
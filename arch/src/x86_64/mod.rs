@@ -72,6 +72,9 @@ pub struct EntryPoint {
 
 const E820_RAM: u32 = 1;
 const E820_RESERVED: u32 = 2;
+// Linux specific type for memory left to drivers to online, such as CXL
+// memory handed over to dax_hmem.
+const E820_SOFT_RESERVED: u32 = 0xefff_ffff;
 
 #[derive(Clone)]
 pub struct SgxEpcSection {
@@ -805,7 +808,8 @@ pub fn generate_common_cpuid(
                    | 1 << 2 // AccessSynicRegs
                    | 1 << 3 // AccessSyntheticTimerRegs
                    | 1 << 9, // AccessPartitionReferenceTsc
-            edx: 1 << 3, // CPU dynamic partitioning
+            edx: 1 << 3 // CPU dynamic partitioning
+                   | 1 << 10, // Guest crash MSRs available
             ..Default::default()
         });
         cpuid.push(CpuIdEntry {
@@ -895,6 +899,9 @@ pub fn configure_vcpu(
     }
 
     regs::setup_msrs(vcpu).map_err(Error::MsrsConfiguration)?;
+    if kvm_hyperv {
+        regs::setup_hyperv_crash_msrs(vcpu).map_err(Error::MsrsConfiguration)?;
+    }
     if let Some((kernel_entry_point, guest_memory)) = boot_setup {
         regs::setup_regs(vcpu, kernel_entry_point).map_err(Error::RegsConfiguration)?;
         regs::setup_fpu(vcpu).map_err(Error::FpuConfiguration)?;
@@ -942,6 +949,7 @@ pub fn arch_memory_regions() -> Vec<(GuestAddress, usize, RegionType)> {
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `soft_reserved_regions` - Memory ranges left to the guest drivers to online.
 #[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
@@ -952,6 +960,7 @@ pub fn configure_system(
     setup_header: Option<setup_header>,
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    soft_reserved_regions: &[(GuestAddress, u64)],
     serial_number: Option<&str>,
     uuid: Option<&str>,
     oem_strings: Option<&[&str]>,
@@ -986,6 +995,7 @@ pub fn configure_system(
             hdr,
             rsdp_addr,
             sgx_epc_region,
+            soft_reserved_regions,
         ),
         None => configure_pvh(
             guest_mem,
@@ -993,6 +1003,7 @@ pub fn configure_system(
             initramfs,
             rsdp_addr,
             sgx_epc_region,
+            soft_reserved_regions,
         ),
     }
 }
@@ -1086,6 +1097,7 @@ fn configure_pvh(
     initramfs: &Option<InitramfsConfig>,
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    soft_reserved_regions: &[(GuestAddress, u64)],
 ) -> super::Result<()> {
     const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336ec578;
 
@@ -1160,6 +1172,10 @@ fn configure_pvh(
         );
     }
 
+    for (start, size) in soft_reserved_regions {
+        add_memmap_entry(&mut memmap, start.raw_value(), *size, E820_SOFT_RESERVED);
+    }
+
     start_info.memmap_entries = memmap.len() as u32;
 
     // Copy the vector with the memmap table to the MEMMAP_START address
@@ -1199,6 +1215,7 @@ fn configure_pvh(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn configure_32bit_entry(
     guest_mem: &GuestMemoryMmap,
     cmdline_addr: GuestAddress,
@@ -1207,6 +1224,7 @@ fn configure_32bit_entry(
     setup_hdr: setup_header,
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    soft_reserved_regions: &[(GuestAddress, u64)],
 ) -> super::Result<()> {
     const KERNEL_LOADER_OTHER: u8 = 0xff;
 
@@ -1271,6 +1289,10 @@ fn configure_32bit_entry(
         )?;
     }
 
+    for (start, size) in soft_reserved_regions {
+        add_e820_entry(&mut params, start.raw_value(), *size, E820_SOFT_RESERVED)?;
+    }
+
     if let Some(rsdp_addr) = rsdp_addr {
         params.acpi_rsdp_addr = rsdp_addr.0;
     }
@@ -1575,6 +1597,7 @@ mod tests {
             None,
             Some(layout::RSDP_POINTER),
             None,
+            &[],
             None,
             None,
             None,
@@ -1600,6 +1623,7 @@ mod tests {
             None,
             None,
             None,
+            &[],
             None,
             None,
             None,
@@ -1630,6 +1654,7 @@ mod tests {
             None,
             None,
             None,
+            &[],
             None,
             None,
             None,
@@ -1646,6 +1671,7 @@ mod tests {
             None,
             None,
             None,
+            &[],
             None,
             None,
             None,
@@ -12,7 +12,10 @@ use crate::layout::{
 use crate::{EntryPoint, GuestMemoryMmap};
 use hypervisor::arch::x86::gdt::{gdt_entry, segment_from_gdt};
 use hypervisor::arch::x86::regs::CR0_PE;
-use hypervisor::arch::x86::{FpuState, SpecialRegisters};
+use hypervisor::arch::x86::{
+    DescriptorTable, FpuState, MsrEntry, SegmentRegister, SpecialRegisters,
+    HV_CRASH_CTL_CRASH_NOTIFY, HV_X64_MSR_CRASH_CTL,
+};
 use std::sync::Arc;
 use std::{mem, result};
 use thiserror::Error;
@@ -87,6 +90,21 @@ pub fn setup_msrs(vcpu: &Arc<dyn hypervisor::Vcpu>) -> Result<()> {
     Ok(())
 }
 
+/// Let the guest report crashes through the Hyper-V crash MSRs.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+pub fn setup_hyperv_crash_msrs(vcpu: &Arc<dyn hypervisor::Vcpu>) -> Result<()> {
+    vcpu.set_msrs(&[MsrEntry {
+        index: HV_X64_MSR_CRASH_CTL,
+        data: HV_CRASH_CTL_CRASH_NOTIFY,
+    }])
+    .map_err(Error::SetModelSpecificRegisters)?;
+
+    Ok(())
+}
+
 /// Configure base registers for a given CPU.
 ///
 /// # Arguments
@@ -184,6 +202,66 @@ pub fn configure_segments_and_sregs(
     Ok(())
 }
 
+/// Puts a CPU back into real mode at the waking vector the guest registered in
+/// the FACS, as firmware does when resuming from the S3 sleep state.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `waking_vector` - Real mode address the guest resumes at.
+pub fn setup_wakeup_regs(vcpu: &Arc<dyn hypervisor::Vcpu>, waking_vector: u32) -> Result<()> {
+    let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
+    configure_wakeup_sregs(waking_vector, &mut sregs);
+    vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)?;
+
+    let mut regs = vcpu.create_standard_regs();
+    regs.set_rflags(0x0000000000000002u64);
+    regs.set_rip(u64::from(waking_vector & 0xf));
+    vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
+}
+
+pub fn configure_wakeup_sregs(waking_vector: u32, sregs: &mut SpecialRegisters) {
+    // The ACPI specification has the waking vector jumped to in real mode,
+    // with CS:IP set to (waking_vector >> 4):(waking_vector & 0xf).
+    let segment = |selector: u16, type_: u8| SegmentRegister {
+        base: u64::from(selector) << 4,
+        limit: 0xffff,
+        selector,
+        type_,
+        present: 1,
+        s: 1,
+        ..Default::default()
+    };
+    let real_mode_table = DescriptorTable {
+        base: 0,
+        limit: 0xffff,
+    };
+
+    sregs.cs = segment((waking_vector >> 4) as u16, 0xb);
+    sregs.ds = segment(0, 0x3);
+    sregs.es = segment(0, 0x3);
+    sregs.fs = segment(0, 0x3);
+    sregs.gs = segment(0, 0x3);
+    sregs.ss = segment(0, 0x3);
+    sregs.tr = SegmentRegister {
+        s: 0,
+        ..segment(0, 0xb)
+    };
+    sregs.ldt = SegmentRegister {
+        s: 0,
+        ..segment(0, 0x2)
+    };
+    sregs.gdt = real_mode_table;
+    sregs.idt = real_mode_table;
+
+    // Protected mode and paging disabled, with the cache enabled (ET set)
+    sregs.cr0 = 0x10;
+    sregs.cr2 = 0;
+    sregs.cr3 = 0;
+    sregs.cr4 = 0;
+    sregs.efer = 0;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -231,4 +309,27 @@ mod tests {
         assert_eq!(CR0_PE, sregs.cr0);
         assert_eq!(0, sregs.cr4);
     }
+
+    #[test]
+    fn wakeup_sregs() {
+        let mut sregs: SpecialRegisters = Default::default();
+        let gm = create_guest_mem();
+        configure_segments_and_sregs(&gm, &mut sregs).unwrap();
+        sregs.efer = 0x500;
+        configure_wakeup_sregs(0x9a1c5, &mut sregs);
+
+        assert_eq!(0x9a1c, sregs.cs.selector);
+        assert_eq!(0x9a1c0, sregs.cs.base);
+        assert_eq!(0xffff, sregs.cs.limit);
+        assert_eq!(0xb, sregs.cs.type_);
+        assert_eq!(0, sregs.cs.db);
+        assert_eq!(0, sregs.ds.base);
+        assert_eq!(0, sregs.ss.selector);
+        assert_eq!(0x3, sregs.ss.type_);
+        assert_eq!(0, sregs.tr.s);
+        assert_eq!(0, sregs.gdt.base);
+        assert_eq!(0xffff, sregs.idt.limit);
+        assert_eq!(0, sregs.cr0 & CR0_PE);
+        assert_eq!(0, sregs.efer);
+    }
 }
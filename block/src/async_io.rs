@@ -57,4 +57,9 @@ pub trait AsyncIo: Send {
     ) -> AsyncIoResult<()>;
     fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()>;
     fn next_completed_request(&mut self) -> Option<(u64, i32)>;
+    /// Whether the completed requests can be reaped cheaply by polling,
+    /// without waiting for the notifier to be signalled.
+    fn pollable_completions(&self) -> bool {
+        false
+    }
 }
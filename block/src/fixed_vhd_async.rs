@@ -100,4 +100,8 @@ impl AsyncIo for FixedVhdAsync {
     fn next_completed_request(&mut self) -> Option<(u64, i32)> {
         self.raw_file_async.next_completed_request()
     }
+
+    fn pollable_completions(&self) -> bool {
+        self.raw_file_async.pollable_completions()
+    }
 }
@@ -17,6 +17,8 @@ pub mod fixed_vhd;
 /// Enabled with the `"io_uring"` feature
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod luks;
+pub mod luks_sync;
 pub mod qcow;
 pub mod qcow_sync;
 #[cfg(feature = "io_uring")]
@@ -25,6 +27,7 @@ pub mod qcow_sync;
 /// Enabled with the `"io_uring"` feature
 pub mod raw_async;
 pub mod raw_async_aio;
+pub mod raw_async_thread_pool;
 pub mod raw_sync;
 pub mod vhd;
 pub mod vhdx;
@@ -89,6 +92,8 @@ pub enum Error {
     GetFileMetadata,
     #[error("The requested operation would cause a seek beyond disk end")]
     InvalidOffset,
+    #[error("LUKS images can't be opened without a key")]
+    LuksKeyRequired,
     #[error("Failure in qcow: {0}")]
     QcowError(qcow::Error),
     #[error("Failure in raw file: {0}")]
@@ -743,6 +748,7 @@ where
 
 pub enum ImageType {
     FixedVhd,
+    Luks,
     Qcow2,
     Raw,
     Vhdx,
@@ -779,6 +785,8 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
         ImageType::FixedVhd
     } else if u64::from_le_bytes(block[0..8].try_into().unwrap()) == VHDX_SIGN {
         ImageType::Vhdx
+    } else if luks::is_luks2(&block) {
+        ImageType::Luks
     } else {
         ImageType::Raw
     };
@@ -805,6 +813,7 @@ pub fn create_disk_file(mut file: File, direct_io: bool) -> Result<Box<dyn Block
         ImageType::Vhdx => {
             Box::new(Vhdx::new(file).map_err(Error::VhdxError)?) as Box<dyn BlockBackend>
         }
+        ImageType::Luks => return Err(Error::LuksKeyRequired),
         ImageType::Raw => Box::new(RawFile::new(file, direct_io)) as Box<dyn BlockBackend>,
     })
 }
@@ -0,0 +1,105 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
+use crate::luks::{Luks, Result as LuksResult};
+use crate::AsyncAdaptor;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::{Arc, Mutex, MutexGuard};
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct LuksDiskSync {
+    luks_file: Arc<Mutex<Luks>>,
+}
+
+impl LuksDiskSync {
+    /// Opens the LUKS2 image `file`, unlocking it with `passphrase`.
+    pub fn new(file: File, direct_io: bool, passphrase: &[u8]) -> LuksResult<Self> {
+        Ok(LuksDiskSync {
+            luks_file: Arc::new(Mutex::new(Luks::new(file, direct_io, passphrase)?)),
+        })
+    }
+}
+
+impl DiskFile for LuksDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.luks_file.lock().unwrap().virtual_disk_size())
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(
+            Box::new(LuksSync::new(self.luks_file.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+}
+
+pub struct LuksSync {
+    luks_file: Arc<Mutex<Luks>>,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl LuksSync {
+    pub fn new(luks_file: Arc<Mutex<Luks>>) -> std::io::Result<Self> {
+        Ok(LuksSync {
+            luks_file,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+            completion_list: VecDeque::new(),
+        })
+    }
+}
+
+impl AsyncAdaptor<Luks> for Arc<Mutex<Luks>> {
+    fn file(&mut self) -> MutexGuard<Luks> {
+        self.lock().unwrap()
+    }
+}
+
+impl AsyncIo for LuksSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.luks_file.read_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.luks_file.write_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.luks_file
+            .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+}
@@ -16,7 +16,8 @@ use crate::qcow::{
 };
 use crate::BlockBackend;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use libc::{EINVAL, ENOSPC, ENOTSUP};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use libc::{EINVAL, ENOSPC};
 use remain::sorted;
 use std::cmp::{max, min};
 use std::fs::OpenOptions;
@@ -28,23 +29,24 @@ use vmm_sys_util::{
     file_traits::FileSetLen, file_traits::FileSync, seek_hole::SeekHole, write_zeroes::PunchHole,
     write_zeroes::WriteZeroesAt,
 };
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
 
 pub use crate::qcow::raw_file::RawFile;
 
 /// Nesting depth limit for disk formats that can open other disk files.
-const MAX_NESTING_DEPTH: u32 = 10;
+pub(crate) const MAX_NESTING_DEPTH: u32 = 10;
 
 #[sorted]
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Backing file io error: {0}")]
     BackingFileIo(io::Error),
+    #[error("Backing file is not a qcow2 image: {0}")]
+    BackingFileNotQcow(String),
     #[error("Backing file open error: {0}")]
     BackingFileOpen(Box<Error>),
     #[error("Backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
-    #[error("Compressed blocks not supported")]
-    CompressedBlocksNotSupported,
     #[error("Failed to evict cache: {0}")]
     EvictingCache(io::Error),
     #[error("File larger than max of {}: {0}", MAX_QCOW_FILE_SIZE)]
@@ -111,6 +113,8 @@ pub enum Error {
     TooManyL1Entries(u64),
     #[error("Ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("Unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
     #[error("Unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("Unsupported version: {0}")]
@@ -156,6 +160,16 @@ const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1;
+// The compression type header field is set to something other than zlib.
+const INCOMPATIBLE_FEATURES_COMPRESSION_TYPE: u64 = 1 << 3;
+
+// Algorithms used for compressed clusters.
+const COMPRESSION_TYPE_ZLIB: u8 = 0;
+const COMPRESSION_TYPE_ZSTD: u8 = 1;
+// The size of compressed clusters is stored in units of 512 byte sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+// qemu uses raw deflate streams with a 4KiB window for zlib compressed clusters.
+const ZLIB_WINDOW_BITS: u8 = 12;
 
 // The format supports a "header extension area", that crosvm does not use.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
@@ -191,6 +205,7 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    pub compression_type: u8,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
@@ -256,8 +271,20 @@ impl QcowHeader {
             } else {
                 read_u32_from_file(f)?
             },
+            compression_type: COMPRESSION_TYPE_ZLIB,
             backing_file_path: None,
         };
+        // The compression type is only present in headers long enough to contain it.
+        if header.version == 3 && header.header_size > V3_BARE_HEADER_SIZE {
+            header.compression_type = f.read_u8().map_err(Error::ReadingHeader)?;
+        }
+        let compression_type_set =
+            header.incompatible_features & INCOMPATIBLE_FEATURES_COMPRESSION_TYPE != 0;
+        match header.compression_type {
+            COMPRESSION_TYPE_ZLIB => {}
+            COMPRESSION_TYPE_ZSTD if compression_type_set => {}
+            t => return Err(Error::UnsupportedCompressionType(t)),
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -337,6 +364,7 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size,
+            compression_type: COMPRESSION_TYPE_ZLIB,
             backing_file_path: backing_file.map(String::from),
         })
     }
@@ -375,8 +403,14 @@ impl QcowHeader {
             write_u64_to_file(file, self.autoclear_features)?;
             write_u32_to_file(file, self.refcount_order)?;
             write_u32_to_file(file, self.header_size)?;
-            write_u32_to_file(file, 0)?; // header extension type: end of header extension area
-            write_u32_to_file(file, 0)?; // length of header extension data: 0
+            if self.header_size > V3_BARE_HEADER_SIZE {
+                // Compression type followed by padding to a multiple of 8 bytes.
+                file.write_all(&[self.compression_type, 0, 0, 0, 0, 0, 0, 0])
+                    .map_err(Error::WritingHeader)?;
+            } else {
+                write_u32_to_file(file, 0)?; // header extension type: end of header extension area
+                write_u32_to_file(file, 0)?; // length of header extension data: 0
+            }
         }
 
         if let Some(backing_file_path) = self.backing_file_path.as_ref() {
@@ -439,7 +473,79 @@ pub struct QcowFile {
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
-    backing_file: Option<Box<Self>>,
+    backing_file: Option<BackingFile>,
+    // Whether writes covering a whole unallocated cluster are stored compressed.
+    compress_writes: bool,
+    // Next free byte in the host cluster compressed clusters are being packed into.
+    compressed_cursor: Option<u64>,
+    // The most recently decompressed cluster, keyed by its L2 entry.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+}
+
+/// Read-only image backing a qcow2 file, either raw or qcow2 itself.
+#[derive(Debug)]
+enum BackingFile {
+    Qcow(Box<QcowFile>),
+    Raw { file: RawFile, size: u64 },
+}
+
+impl BackingFile {
+    // A raw backing file is only accepted when `allow_raw` is set, that is
+    // for overlays created on purpose. The backing file of an existing image
+    // must be qcow2, since the format of a raw one can't be told apart from
+    // an image whose header was guessed wrong.
+    fn open(
+        path: &str,
+        direct_io: bool,
+        max_nesting_depth: u32,
+        allow_raw: bool,
+    ) -> Result<BackingFile> {
+        let mut file = RawFile::new(
+            OpenOptions::new()
+                .read(true)
+                .open(path)
+                .map_err(Error::BackingFileIo)?,
+            direct_io,
+        );
+
+        let magic = file.read_u32::<BigEndian>().map_err(Error::BackingFileIo)?;
+        if magic == QCOW_MAGIC {
+            let backing_file = QcowFile::from_with_nesting_depth(file, max_nesting_depth)
+                .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+            Ok(BackingFile::Qcow(Box::new(backing_file)))
+        } else if !allow_raw {
+            Err(Error::BackingFileNotQcow(path.to_string()))
+        } else {
+            let size = file.seek(SeekFrom::End(0)).map_err(Error::BackingFileIo)?;
+            Ok(BackingFile::Raw { file, size })
+        }
+    }
+
+    fn virtual_size(&self) -> u64 {
+        match self {
+            BackingFile::Qcow(qcow) => qcow.virtual_size(),
+            BackingFile::Raw { size, .. } => *size,
+        }
+    }
+
+    // Reads from `address`, past the end of a raw image being read as zeros.
+    fn read_at(&mut self, address: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        match self {
+            BackingFile::Qcow(qcow) => {
+                qcow.seek(SeekFrom::Start(address))?;
+                qcow.read_exact(buf)
+            }
+            BackingFile::Raw { file, size } => {
+                let count = min(buf.len() as u64, size.saturating_sub(address)) as usize;
+                if count > 0 {
+                    file.seek(SeekFrom::Start(address))?;
+                    file.read_exact(&mut buf[..count])?;
+                }
+                buf[count..].fill(0);
+                Ok(())
+            }
+        }
+    }
 }
 
 impl QcowFile {
@@ -452,7 +558,17 @@ impl QcowFile {
 
     /// Creates a QcowFile from `file` and with a max nesting depth. File must be a valid qcow2
     /// image.
-    pub fn from_with_nesting_depth(mut file: RawFile, max_nesting_depth: u32) -> Result<QcowFile> {
+    pub fn from_with_nesting_depth(file: RawFile, max_nesting_depth: u32) -> Result<QcowFile> {
+        Self::from_with_backing_file(file, max_nesting_depth, None)
+    }
+
+    // Creates a QcowFile from `file`, relying on `backing_file` rather than
+    // opening the one referenced by the header, if given.
+    fn from_with_backing_file(
+        mut file: RawFile,
+        max_nesting_depth: u32,
+        backing_file: Option<BackingFile>,
+    ) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v2 and v3 files are supported.
@@ -478,21 +594,18 @@ impl QcowFile {
 
         let direct_io = file.is_direct();
 
-        let backing_file = if let Some(backing_file_path) = header.backing_file_path.as_ref() {
+        let backing_file = if backing_file.is_some() {
+            backing_file
+        } else if let Some(backing_file_path) = header.backing_file_path.as_ref() {
             if max_nesting_depth == 0 {
                 return Err(Error::MaxNestingDepthExceeded);
             }
-            let path = backing_file_path.clone();
-            let backing_raw_file = OpenOptions::new()
-                .read(true)
-                .open(path)
-                .map_err(Error::BackingFileIo)?;
-            let backing_file = Self::from_with_nesting_depth(
-                RawFile::new(backing_raw_file, direct_io),
+            Some(BackingFile::open(
+                backing_file_path,
+                direct_io,
                 max_nesting_depth - 1,
-            )
-            .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
-            Some(Box::new(backing_file))
+                false,
+            )?)
         } else {
             None
         };
@@ -588,19 +701,6 @@ impl QcowFile {
 
         let l2_entries = cluster_size / size_of::<u64>() as u64;
 
-        // Check for compressed blocks
-        for l2_addr_disk in l1_table.get_values() {
-            if *l2_addr_disk != 0 {
-                if let Err(e) = Self::read_l2_cluster(&mut raw_file, *l2_addr_disk) {
-                    if let Some(os_error) = e.raw_os_error() {
-                        if os_error == ENOTSUP {
-                            return Err(Error::CompressedBlocksNotSupported);
-                        }
-                    }
-                }
-            }
-        }
-
         let mut qcow = QcowFile {
             raw_file,
             header,
@@ -612,6 +712,9 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            compress_writes: false,
+            compressed_cursor: None,
+            decompressed_cluster: None,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -632,10 +735,10 @@ impl QcowFile {
     /// Creates a new QcowFile at the given path.
     pub fn new(file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
         let header = QcowHeader::create_for_size_and_path(version, virtual_size, None)?;
-        QcowFile::new_from_header(file, header)
+        QcowFile::new_from_header(file, header, None)
     }
 
-    /// Creates a new QcowFile at the given path.
+    /// Creates a new QcowFile at the given path, on top of a raw or qcow2 backing file.
     pub fn new_from_backing(
         file: RawFile,
         version: u32,
@@ -643,27 +746,26 @@ impl QcowFile {
         backing_file_max_nesting_depth: u32,
     ) -> Result<QcowFile> {
         let direct_io = file.is_direct();
-        let backing_raw_file = OpenOptions::new()
-            .read(true)
-            .open(backing_file_name)
-            .map_err(Error::BackingFileIo)?;
-        let backing_file = Self::from_with_nesting_depth(
-            RawFile::new(backing_raw_file, direct_io),
+        let backing_file = BackingFile::open(
+            backing_file_name,
+            direct_io,
             backing_file_max_nesting_depth,
-        )
-        .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+            true,
+        )?;
         let size = backing_file.virtual_size();
         let header = QcowHeader::create_for_size_and_path(version, size, Some(backing_file_name))?;
-        let mut result = QcowFile::new_from_header(file, header)?;
-        result.backing_file = Some(Box::new(backing_file));
-        Ok(result)
+        QcowFile::new_from_header(file, header, Some(backing_file))
     }
 
-    fn new_from_header(mut file: RawFile, header: QcowHeader) -> Result<QcowFile> {
+    fn new_from_header(
+        mut file: RawFile,
+        header: QcowHeader,
+        backing_file: Option<BackingFile>,
+    ) -> Result<QcowFile> {
         file.rewind().map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
 
-        let mut qcow = Self::from(file)?;
+        let mut qcow = Self::from_with_backing_file(file, MAX_NESTING_DEPTH, backing_file)?;
 
         // Set the refcount for each refcount table cluster.
         let cluster_size = 0x01u64 << qcow.header.cluster_bits;
@@ -684,7 +786,14 @@ impl QcowFile {
     }
 
     pub fn set_backing_file(&mut self, backing: Option<Box<Self>>) {
-        self.backing_file = backing;
+        self.backing_file = backing.map(BackingFile::Qcow);
+    }
+
+    /// Stores writes covering a whole unallocated cluster compressed, as `qemu-img convert -c`
+    /// does. This is meant for write-once archival images: overwriting a compressed cluster
+    /// later replaces it with a regular one.
+    pub fn set_compress_writes(&mut self, compress_writes: bool) {
+        self.compress_writes = compress_writes;
     }
 
     /// Returns the `QcowHeader` for this file.
@@ -844,12 +953,17 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for entry in l2_table {
+                        if entry & COMPRESSED_FLAG != 0 {
+                            for host_cluster in compressed_host_clusters(entry, header.cluster_bits)
+                            {
+                                add_ref(refcounts, cluster_size, host_cluster)?;
+                            }
+                        } else if entry & L2_TABLE_OFFSET_MASK != 0 {
+                            add_ref(refcounts, cluster_size, entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -1053,8 +1167,18 @@ impl QcowFile {
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
+    // yet to be allocated, return None. Must not be used on compressed clusters.
     fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+        let cluster_addr = self.l2_entry(address)?;
+        if cluster_addr == 0 {
+            return Ok(None);
+        }
+        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+    }
+
+    // Gets the L2 entry describing the cluster containing `address`, 0 if the cluster has yet to
+    // be allocated.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1067,7 +1191,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1088,16 +1212,58 @@ impl QcowFile {
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
-        }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        Ok(self.l2_cache.get(l1_index).unwrap()[l2_index])
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
     // to be allocated, they will be.
     fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
+        let mut set_refcounts = Vec::new();
+        let (l1_index, l2_index) = self.cache_l2_table_for_write(address, &mut set_refcounts)?;
+
+        let cluster_addr = match self.l2_cache.get(l1_index).unwrap()[l2_index] {
+            0 => {
+                let initial_data = if let Some(backing) = self.backing_file.as_mut() {
+                    let cluster_size = self.raw_file.cluster_size();
+                    let cluster_begin = address - (address % cluster_size);
+                    let mut cluster_data = vec![0u8; cluster_size as usize];
+                    backing.read_at(cluster_begin, &mut cluster_data)?;
+                    Some(cluster_data)
+                } else {
+                    None
+                };
+                // Need to allocate a data cluster
+                let cluster_addr = self.append_data_cluster(initial_data)?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                cluster_addr
+            }
+            a if a & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters can't be modified in place, move the data to a regular one.
+                let initial_data = self.decompress_cluster(a)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.unref_compressed_cluster(a)?;
+                cluster_addr
+            }
+            a => a,
+        };
+
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+    }
+
+    // Makes sure the L2 table covering `address` is cached, allocating it if needed. Returns the
+    // L1 and L2 indices of `address`. Refcounts of newly allocated clusters are added to
+    // `set_refcounts`.
+    fn cache_l2_table_for_write(
+        &mut self,
+        address: u64,
+        set_refcounts: &mut Vec<(u64, u16)>,
+    ) -> std::io::Result<(usize, usize)> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1109,8 +1275,6 @@ impl QcowFile {
             .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
         let l2_index = self.l2_table_index(address) as usize;
 
-        let mut set_refcounts = Vec::new();
-
         if !self.l2_cache.contains_key(l1_index) {
             // Not in the cache.
             let l2_table = if l2_addr_disk == 0 {
@@ -1135,32 +1299,7 @@ impl QcowFile {
             })?;
         }
 
-        let cluster_addr = match self.l2_cache.get(l1_index).unwrap()[l2_index] {
-            0 => {
-                let initial_data = if let Some(backing) = self.backing_file.as_mut() {
-                    let cluster_size = self.raw_file.cluster_size();
-                    let cluster_begin = address - (address % cluster_size);
-                    let mut cluster_data = vec![0u8; cluster_size as usize];
-                    backing.seek(SeekFrom::Start(cluster_begin))?;
-                    backing.read_exact(&mut cluster_data)?;
-                    Some(cluster_data)
-                } else {
-                    None
-                };
-                // Need to allocate a data cluster
-                let cluster_addr = self.append_data_cluster(initial_data)?;
-                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
-                cluster_addr
-            }
-            a => a,
-        };
-
-        for (addr, count) in set_refcounts {
-            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
-            self.unref_clusters.append(&mut newly_unref);
-        }
-
-        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+        Ok((l1_index, l2_index))
     }
 
     // Updates the l1 and l2 tables to point to the new `cluster_addr`.
@@ -1342,6 +1481,12 @@ impl QcowFile {
             return Ok(());
         }
 
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            // unwrap is safe as we just checked/inserted this entry.
+            self.l2_cache.get_mut(l1_index).unwrap()[l2_index] = 0;
+            return self.unref_compressed_cluster(cluster_addr);
+        }
+
         // Decrement the refcount.
         let refcount = self
             .refcounts
@@ -1395,7 +1540,11 @@ impl QcowFile {
                 // Partial cluster - zero out the relevant bytes if it was allocated.
                 // Any space in unallocated clusters can be left alone, since
                 // unallocated clusters already read back as zeroes.
-                if let Some(offset) = self.file_offset_read(curr_addr)? {
+                if self.l2_entry(curr_addr)? & COMPRESSED_FLAG != 0 {
+                    // Compressed clusters are moved to a regular cluster first.
+                    let offset = self.file_offset_write(curr_addr)?;
+                    self.raw_file.file_mut().write_zeroes_at(offset, count)?;
+                } else if let Some(offset) = self.file_offset_read(curr_addr)? {
                     // Partial cluster - zero it out.
                     self.raw_file.file_mut().write_zeroes_at(offset, count)?;
                 }
@@ -1406,19 +1555,132 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk. Compressed cluster descriptors are kept as is, while only
+    // the offset is kept for regular clusters.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
+    // Returns the contents of the compressed cluster described by the L2 `entry`.
+    fn decompress_cluster(&mut self, entry: u64) -> std::io::Result<&[u8]> {
+        if !matches!(&self.decompressed_cluster, Some((e, _)) if *e == entry) {
+            let (offset, len) = compressed_cluster_location(entry, self.header.cluster_bits);
+            // The sector padding of the last compressed cluster can run past the end of the file.
+            let file_size = self.raw_file.file_mut().metadata()?.len();
+            let len = min(len, file_size.saturating_sub(offset));
+            let mut compressed = vec![0u8; len as usize];
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut compressed)?;
+
+            let mut data = vec![0u8; self.raw_file.cluster_size() as usize];
+            decompress_cluster_data(self.header.compression_type, &compressed, &mut data)?;
+            self.decompressed_cluster = Some((entry, data));
+        }
+        // unwrap is safe as the cluster was just decompressed if it wasn't cached already.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Stores the whole cluster `data` compressed for the guest `address`, which must either be
+    // unallocated or compressed. Returns false, without writing anything, if `data` doesn't
+    // compress.
+    fn write_compressed_cluster(&mut self, address: u64, data: &[u8]) -> std::io::Result<bool> {
+        let compressed = match compress_cluster_data(self.header.compression_type, data)? {
+            Some(compressed) => compressed,
+            None => return Ok(false),
+        };
+        let cluster_size = self.raw_file.cluster_size();
+        let len = compressed.len() as u64;
+
+        // Pack compressed clusters back to back, each one referencing the host cluster it is in.
+        let offset = match self.compressed_cursor {
+            Some(cursor) if cursor % cluster_size + len <= cluster_size => {
+                let host_cluster = cursor - cursor % cluster_size;
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, host_cluster)
+                    .map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("failed to get cluster refcount: {e}"),
+                        )
+                    })?;
+                let mut newly_unref = self.set_cluster_refcount(host_cluster, refcount + 1)?;
+                self.unref_clusters.append(&mut newly_unref);
+                cursor
+            }
+            _ => self.append_data_cluster(None)?,
+        };
+        self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
+        self.raw_file.file_mut().write_all(&compressed)?;
+        self.compressed_cursor = Some(offset + len).filter(|end| end % cluster_size != 0);
+
+        let mut set_refcounts = Vec::new();
+        let (l1_index, l2_index) = self.cache_l2_table_for_write(address, &mut set_refcounts)?;
+        let old_entry = self.l2_cache.get(l1_index).unwrap()[l2_index];
+        let entry = compressed_cluster_entry(offset, len, self.header.cluster_bits);
+        self.update_cluster_addr(l1_index, l2_index, entry, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        if old_entry & COMPRESSED_FLAG != 0 {
+            self.unref_compressed_cluster(old_entry)?;
+        }
+
+        Ok(true)
+    }
+
+    // Drops the references the compressed cluster `entry` holds on the host clusters its data is
+    // stored in.
+    fn unref_compressed_cluster(&mut self, entry: u64) -> std::io::Result<()> {
+        if matches!(&self.decompressed_cluster, Some((e, _)) if *e == entry) {
+            self.decompressed_cluster = None;
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        for host_cluster in compressed_host_clusters(entry, self.header.cluster_bits) {
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, host_cluster)
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to get cluster refcount: {e}"),
+                    )
+                })?;
+            if refcount == 0 {
+                return Err(std::io::Error::from_raw_os_error(EINVAL));
+            }
+
+            let mut newly_unref = self.set_cluster_refcount(host_cluster, refcount - 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+            if refcount == 1 {
+                // Stop packing into a host cluster that is about to be reused.
+                if matches!(self.compressed_cursor, Some(c) if c - c % cluster_size == host_cluster)
+                {
+                    self.compressed_cursor = None;
+                }
+                let _ = self
+                    .raw_file
+                    .file_mut()
+                    .punch_hole(host_cluster, cluster_size);
+                self.unref_clusters.push(host_cluster);
+            }
+        }
+        Ok(())
+    }
+
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
     // the old location can be reused.
@@ -1532,17 +1794,20 @@ impl Read for QcowFile {
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
+            let entry = self.l2_entry(curr_addr)?;
+            if entry & COMPRESSED_FLAG != 0 {
+                let offset = self.raw_file.cluster_offset(curr_addr) as usize;
+                let data = self.decompress_cluster(entry)?;
+                buf[nread..(nread + count)].copy_from_slice(&data[offset..(offset + count)]);
+            } else if let Some(offset) = self.file_offset_read(curr_addr)? {
                 self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                 self.raw_file
                     .file_mut()
                     .read_exact(&mut buf[nread..(nread + count)])?;
             } else if let Some(backing) = self.backing_file.as_mut() {
-                backing.seek(SeekFrom::Start(curr_addr))?;
-                backing.read_exact(&mut buf[nread..(nread + count)])?;
+                backing.read_at(curr_addr, &mut buf[nread..(nread + count)])?;
             } else {
                 // Previously unwritten region, return zeros
                 for b in &mut buf[nread..(nread + count)] {
@@ -1597,9 +1862,20 @@ impl Write for QcowFile {
         let mut nwritten: usize = 0;
         while nwritten < write_count {
             let curr_addr = address + nwritten as u64;
-            let offset = self.file_offset_write(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
+            if self.compress_writes && count as u64 == self.raw_file.cluster_size() {
+                let entry = self.l2_entry(curr_addr)?;
+                if (entry == 0 || entry & COMPRESSED_FLAG != 0)
+                    && self
+                        .write_compressed_cluster(curr_addr, &buf[nwritten..(nwritten + count)])?
+                {
+                    nwritten += count;
+                    continue;
+                }
+            }
+
+            let offset = self.file_offset_write(curr_addr)?;
             self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
             let count = self
                 .raw_file
@@ -1699,6 +1975,115 @@ fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     Ok(())
 }
 
+// Number of bits the sector count of a compressed cluster descriptor is shifted by.
+fn compressed_size_shift(cluster_bits: u32) -> u32 {
+    62 - (cluster_bits - 8)
+}
+
+// Returns the host offset of the compressed cluster described by the L2 `entry`, along with the
+// number of bytes its data may span. The length is rounded up to the end of the last sector.
+fn compressed_cluster_location(entry: u64, cluster_bits: u32) -> (u64, u64) {
+    let shift = compressed_size_shift(cluster_bits);
+    let offset = entry & ((1 << shift) - 1);
+    let sectors = ((entry >> shift) & ((1 << (cluster_bits - 8)) - 1)) + 1;
+    (
+        offset,
+        sectors * COMPRESSED_SECTOR_SIZE - offset % COMPRESSED_SECTOR_SIZE,
+    )
+}
+
+// Builds the L2 entry describing `len` bytes of compressed data stored at host `offset`.
+fn compressed_cluster_entry(offset: u64, len: u64, cluster_bits: u32) -> u64 {
+    let extra_sectors =
+        (offset + len - 1) / COMPRESSED_SECTOR_SIZE - offset / COMPRESSED_SECTOR_SIZE;
+    COMPRESSED_FLAG | (extra_sectors << compressed_size_shift(cluster_bits)) | offset
+}
+
+// Returns the host clusters the data of the compressed cluster `entry` is stored in.
+fn compressed_host_clusters(entry: u64, cluster_bits: u32) -> impl Iterator<Item = u64> {
+    let (offset, len) = compressed_cluster_location(entry, cluster_bits);
+    let cluster_size = 0x01u64 << cluster_bits;
+    let first = offset - offset % cluster_size;
+    (first..offset + len).step_by(cluster_size as usize)
+}
+
+// Decompresses a compressed cluster from `input` into `output`, which is one cluster long.
+// `input` may contain padding after the compressed data.
+fn decompress_cluster_data(
+    compression_type: u8,
+    input: &[u8],
+    output: &mut [u8],
+) -> io::Result<()> {
+    let cluster_size = output.len();
+    let decompressed = match compression_type {
+        COMPRESSION_TYPE_ZLIB => {
+            let mut decompress = Decompress::new(false);
+            decompress
+                .decompress(input, output, FlushDecompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            decompress.total_out() as usize
+        }
+        COMPRESSION_TYPE_ZSTD => {
+            // Stop as soon as the cluster is complete so the padding isn't parsed as a new frame.
+            let mut decoder = zstd::stream::raw::Decoder::new()?;
+            let mut in_buffer = InBuffer::around(input);
+            let mut out_buffer = OutBuffer::around(output);
+            while out_buffer.pos() < cluster_size && in_buffer.pos() < input.len() {
+                let progress = (in_buffer.pos(), out_buffer.pos());
+                decoder.run(&mut in_buffer, &mut out_buffer)?;
+                if (in_buffer.pos(), out_buffer.pos()) == progress {
+                    break;
+                }
+            }
+            out_buffer.pos()
+        }
+        t => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported compression type {t}"),
+            ))
+        }
+    };
+    if decompressed != cluster_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated compressed cluster",
+        ));
+    }
+    Ok(())
+}
+
+// Compresses the whole cluster `input`, returning None if the result isn't smaller.
+fn compress_cluster_data(compression_type: u8, input: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let compressed = match compression_type {
+        COMPRESSION_TYPE_ZLIB => {
+            let mut compress =
+                Compress::new_with_window_bits(Compression::default(), false, ZLIB_WINDOW_BITS);
+            let mut output = vec![0u8; input.len()];
+            let status = compress
+                .compress(input, &mut output, FlushCompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if status != Status::StreamEnd {
+                // The compressed stream doesn't fit in a cluster.
+                return Ok(None);
+            }
+            output.truncate(compress.total_out() as usize);
+            output
+        }
+        COMPRESSION_TYPE_ZSTD => zstd::bulk::compress(input, 0)?,
+        t => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported compression type {t}"),
+            ))
+        }
+    };
+    if compressed.len() >= input.len() {
+        return Ok(None);
+    }
+    Ok(Some(compressed))
+}
+
 // Ceiling of the division of `dividend`/`divisor`.
 fn div_round_up_u64(dividend: u64, divisor: u64) -> u64 {
     dividend / divisor + u64::from(dividend % divisor != 0)
@@ -1724,7 +2109,9 @@ where
         .seek(SeekFrom::Start(offset))
         .map_err(Error::SeekingFile)?;
     loop {
-        let this_count = min(CHUNK_SIZE as u64, size - read_count) as usize;
+        // Keep the chunks aligned so that they line up with the destination clusters.
+        let chunk_offset = (offset + read_count) % CHUNK_SIZE as u64;
+        let this_count = min(CHUNK_SIZE as u64 - chunk_offset, size - read_count) as usize;
         let nread = reader
             .read(&mut buf[..this_count])
             .map_err(Error::ReadingData)?;
@@ -1769,7 +2156,12 @@ where
     Ok(())
 }
 
-fn convert_reader<R>(reader: &mut R, dst_file: RawFile, dst_type: ImageType) -> Result<()>
+fn convert_reader<R>(
+    reader: &mut R,
+    dst_file: RawFile,
+    dst_type: ImageType,
+    compress: bool,
+) -> Result<()>
 where
     R: Read + Seek + SeekHole,
 {
@@ -1782,6 +2174,7 @@ where
     match dst_type {
         ImageType::Qcow2 => {
             let mut dst_writer = QcowFile::new(dst_file, 3, src_size)?;
+            dst_writer.set_compress_writes(compress);
             convert_reader_writer(reader, &mut dst_writer, src_size)
         }
         ImageType::Raw => {
@@ -1798,24 +2191,26 @@ where
 
 /// Copy the contents of a disk image in `src_file` into `dst_file`.
 /// The type of `src_file` is automatically detected, and the output file type is
-/// determined by `dst_type`.
+/// determined by `dst_type`. With `compress`, the data clusters of a qcow2 destination are
+/// stored compressed.
 pub fn convert(
     mut src_file: RawFile,
     dst_file: RawFile,
     dst_type: ImageType,
     src_max_nesting_depth: u32,
+    compress: bool,
 ) -> Result<()> {
     let src_type = detect_image_type(&mut src_file)?;
     match src_type {
         ImageType::Qcow2 => {
             let mut src_reader =
                 QcowFile::from_with_nesting_depth(src_file, src_max_nesting_depth)?;
-            convert_reader(&mut src_reader, dst_file, dst_type)
+            convert_reader(&mut src_reader, dst_file, dst_type, compress)
         }
         ImageType::Raw => {
             // src_file is a raw file.
             let mut src_reader = src_file;
-            convert_reader(&mut src_reader, dst_file, dst_type)
+            convert_reader(&mut src_reader, dst_file, dst_type, compress)
         }
     }
 }
@@ -1839,6 +2234,7 @@ pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
 mod tests {
     use super::*;
     use std::fs::File;
+    use std::os::unix::fs::FileExt;
     use std::path::Path;
     use vmm_sys_util::tempdir::TempDir;
     use vmm_sys_util::tempfile::TempFile;
@@ -2133,6 +2529,27 @@ mod tests {
         });
     }
 
+    #[test]
+    fn header_compression_type() {
+        let mut header = valid_header_v3();
+        header[79] = 0x08; // incompatible_features: compression type
+        header[103] = 0x70; // header_length
+        header.extend_from_slice(&[COMPRESSION_TYPE_ZSTD, 0, 0, 0, 0, 0, 0, 0]);
+        with_basic_file(&header, |mut disk_file: RawFile| {
+            let header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+            assert_eq!(header.compression_type, COMPRESSION_TYPE_ZSTD);
+        });
+
+        // A compression type other than zlib requires the incompatible feature bit.
+        header[79] = 0;
+        with_basic_file(&header, |mut disk_file: RawFile| {
+            assert!(matches!(
+                QcowHeader::new(&mut disk_file),
+                Err(Error::UnsupportedCompressionType(COMPRESSION_TYPE_ZSTD))
+            ));
+        });
+    }
+
     #[test]
     fn invalid_cluster_bits() {
         let mut header = valid_header_v3();
@@ -2250,6 +2667,64 @@ mod tests {
         assert_eq!(&buf, b"TEST first");
     }
 
+    #[test]
+    fn write_read_raw_backing() {
+        let backing = TempFile::new().unwrap();
+        backing
+            .as_file()
+            .write_all(b"test first bytes")
+            .expect("Failed to write test string.");
+        let overlay_file = RawFile::new(TempFile::new().unwrap().into_file(), false);
+        let mut overlay = QcowFile::new_from_backing(
+            overlay_file,
+            3,
+            backing.as_path().to_str().unwrap(),
+            MAX_NESTING_DEPTH,
+        )
+        .unwrap();
+        assert_eq!(overlay.virtual_size(), 16);
+
+        overlay
+            .write_all(b"TEST")
+            .expect("Failed to write second test string.");
+        let mut buf = [0u8; 10];
+        overlay.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+        overlay.read_exact(&mut buf).expect("Failed to read.");
+        assert_eq!(&buf, b"TEST first");
+
+        // The backing file is left untouched.
+        let mut buf = [0u8; 4];
+        backing
+            .as_file()
+            .read_exact_at(&mut buf, 0)
+            .expect("Failed to read backing file.");
+        assert_eq!(&buf, b"test");
+    }
+
+    #[test]
+    fn reopen_raw_backing() {
+        let backing = TempFile::new().unwrap();
+        backing
+            .as_file()
+            .write_all(b"test first bytes")
+            .expect("Failed to write test string.");
+        let overlay = TempFile::new().unwrap();
+        QcowFile::new_from_backing(
+            RawFile::new(overlay.as_file().try_clone().unwrap(), false),
+            3,
+            backing.as_path().to_str().unwrap(),
+            MAX_NESTING_DEPTH,
+        )
+        .unwrap();
+
+        // A raw backing file is only accepted for the overlays being created.
+        let overlay_file = RawFile::new(File::open(overlay.as_path()).unwrap(), false);
+        assert!(matches!(
+            QcowFile::from(overlay_file),
+            Err(Error::BackingFileNotQcow(_))
+        ));
+    }
+
     #[test]
     fn offset_write_read() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
@@ -2898,6 +3373,154 @@ mod tests {
         });
     }
 
+    fn compressed_write_read(compression_type: u8) {
+        let cluster_size = 0x1_0000;
+        let data: Vec<u8> = (0..cluster_size * 3).map(|i| (i / 1000) as u8).collect();
+        let disk_file = TempFile::new().unwrap().into_file();
+        {
+            let raw_file = RawFile::new(disk_file.try_clone().unwrap(), false);
+            let mut q = QcowFile::new(raw_file, 3, 0x10_0000).unwrap();
+            q.header.compression_type = compression_type;
+            q.set_compress_writes(true);
+            q.write_all(&data).expect("Failed to write test data.");
+            for i in 0..3 {
+                assert_ne!(q.l2_entry(i * cluster_size).unwrap() & COMPRESSED_FLAG, 0);
+            }
+            // Compressed clusters are packed together.
+            assert_eq!(
+                compressed_host_clusters(q.l2_entry(0).unwrap(), q.header.cluster_bits).next(),
+                compressed_host_clusters(q.l2_entry(cluster_size).unwrap(), q.header.cluster_bits)
+                    .next()
+            );
+        }
+
+        let raw_file = RawFile::new(disk_file, false);
+        let mut q = QcowFile::from(raw_file).unwrap();
+        q.header.compression_type = compression_type;
+        let mut buf = vec![0u8; data.len()];
+        q.rewind().unwrap();
+        q.read_exact(&mut buf)
+            .expect("Failed to read compressed data.");
+        assert_eq!(buf, data);
+
+        // Partial writes move the cluster out of its compressed storage.
+        q.seek(SeekFrom::Start(cluster_size + 10)).unwrap();
+        q.write_all(b"test").unwrap();
+        assert_eq!(q.l2_entry(cluster_size).unwrap() & COMPRESSED_FLAG, 0);
+        q.rewind().unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..cluster_size as usize + 10],
+            &data[..cluster_size as usize + 10]
+        );
+        assert_eq!(&buf[cluster_size as usize + 10..][..4], b"test");
+        assert_eq!(
+            &buf[cluster_size as usize + 14..],
+            &data[cluster_size as usize + 14..]
+        );
+
+        // Discarding a compressed cluster reads back as zeros.
+        q.punch_hole(0, cluster_size).unwrap();
+        q.rewind().unwrap();
+        q.read_exact(&mut buf[..cluster_size as usize]).unwrap();
+        assert!(buf[..cluster_size as usize].iter().all(|b| *b == 0));
+        q.flush().unwrap();
+    }
+
+    #[test]
+    fn compressed_write_read_zlib() {
+        compressed_write_read(COMPRESSION_TYPE_ZLIB);
+    }
+
+    #[test]
+    fn compressed_write_read_zstd() {
+        compressed_write_read(COMPRESSION_TYPE_ZSTD);
+    }
+
+    #[test]
+    fn compressed_write_incompressible() {
+        with_default_file(0x10_0000, false, |mut q: QcowFile| {
+            q.set_compress_writes(true);
+            // xorshift32 output doesn't compress.
+            let mut state = 0x1234_5678u32;
+            let data: Vec<u8> = (0..0x1_0000)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            q.write_all(&data).unwrap();
+            // Data that doesn't shrink is stored in a regular cluster.
+            assert_eq!(q.l2_entry(0).unwrap() & COMPRESSED_FLAG, 0);
+            let mut buf = vec![0u8; data.len()];
+            q.rewind().unwrap();
+            q.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data);
+        });
+    }
+
+    #[test]
+    fn compressed_cluster_entry_location() {
+        for cluster_bits in [MIN_CLUSTER_BITS, DEFAULT_CLUSTER_BITS, MAX_CLUSTER_BITS] {
+            let entry = compressed_cluster_entry(0x12_3456, 1000, cluster_bits);
+            let (offset, len) = compressed_cluster_location(entry, cluster_bits);
+            assert_eq!(offset, 0x12_3456);
+            assert!(len >= 1000);
+            assert_eq!((offset + len) % COMPRESSED_SECTOR_SIZE, 0);
+        }
+    }
+
+    #[test]
+    fn read_zstd_image() {
+        // Created by test_data/qcow2/create-zstd-qcow2.py, which describes the guest data,
+        // without qemu-img and with zstd v1.5.7.
+        let image = include_bytes!("../../../test_data/qcow2/zstd.qcow2");
+        let cluster_size = 0x1000;
+        let data: Vec<u8> = (0..16)
+            .flat_map(|i| {
+                let cluster: Vec<u8> = match i {
+                    0 => {
+                        let mut state = 0x1234_5678u32;
+                        (0..cluster_size)
+                            .map(|_| {
+                                state ^= state << 13;
+                                state ^= state >> 17;
+                                state ^= state << 5;
+                                state as u8
+                            })
+                            .collect()
+                    }
+                    i if i % 4 == 1 => vec![0; cluster_size],
+                    i => format!("cluster {i:02} ")
+                        .into_bytes()
+                        .into_iter()
+                        .cycle()
+                        .take(cluster_size)
+                        .collect(),
+                };
+                cluster
+            })
+            .collect();
+
+        let mut disk_file = TempFile::new().unwrap().into_file();
+        disk_file.write_all(image).unwrap();
+        let mut q = QcowFile::from(RawFile::new(disk_file, false)).unwrap();
+        assert_eq!(q.header.compression_type, COMPRESSION_TYPE_ZSTD);
+        assert_eq!(q.virtual_size(), data.len() as u64);
+        assert_eq!(q.l2_entry(0).unwrap() & COMPRESSED_FLAG, 0);
+        assert_ne!(
+            q.l2_entry(cluster_size as u64 * 2).unwrap() & COMPRESSED_FLAG,
+            0
+        );
+
+        let mut buf = vec![0u8; data.len()];
+        q.rewind().unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
     #[test]
     fn rebuild_refcounts() {
         with_basic_file(&valid_header_v3(), |mut disk_file: RawFile| {
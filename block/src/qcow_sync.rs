@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
-use crate::qcow::{QcowFile, RawFile, Result as QcowResult};
+use crate::qcow::{QcowFile, RawFile, Result as QcowResult, MAX_NESTING_DEPTH};
 use crate::AsyncAdaptor;
 use std::collections::VecDeque;
 use std::fs::File;
@@ -21,6 +21,19 @@ impl QcowDiskSync {
             qcow_file: Arc::new(Mutex::new(QcowFile::from(RawFile::new(file, direct_io))?)),
         })
     }
+
+    /// Formats `file` as a writable qcow2 overlay on top of the read-only
+    /// `backing_file`, which can be either a raw or a qcow2 image.
+    pub fn new_overlay(file: File, direct_io: bool, backing_file: &str) -> QcowResult<Self> {
+        Ok(QcowDiskSync {
+            qcow_file: Arc::new(Mutex::new(QcowFile::new_from_backing(
+                RawFile::new(file, direct_io),
+                3,
+                backing_file,
+                MAX_NESTING_DEPTH,
+            )?)),
+        })
+    }
 }
 
 impl DiskFile for QcowDiskSync {
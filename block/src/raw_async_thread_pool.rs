@@ -0,0 +1,264 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Asynchronous I/O relying on a pool of worker threads performing regular
+//! blocking system calls, for the filesystems on which io_uring or Linux AIO
+//! behave poorly, such as some network filesystems.
+//!
+//! The worker threads are shared by all the queues of a disk. The number of
+//! requests waiting for a worker thread is bounded by the queue depth of the
+//! disk, submitting more requests blocks until a worker thread is available.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use crate::DiskTopology;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+pub const DEFAULT_THREAD_POOL_QUEUE_DEPTH: usize = 128;
+
+enum Operation {
+    Read(Vec<libc::iovec>, libc::off_t),
+    Write(Vec<libc::iovec>, libc::off_t),
+    Fsync,
+}
+
+struct Job {
+    fd: RawFd,
+    operation: Operation,
+    user_data: u64,
+    completions: Arc<Completions>,
+}
+
+// SAFETY: The iovecs point to guest memory, which remains mapped until the
+// request is completed, as with the other asynchronous backends.
+unsafe impl Send for Job {}
+
+impl Job {
+    fn run(self) {
+        // SAFETY: FFI calls with a valid fd and valid iovecs
+        let result = unsafe {
+            match &self.operation {
+                Operation::Read(iovecs, offset) => libc::preadv(
+                    self.fd,
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                    *offset,
+                ),
+                Operation::Write(iovecs, offset) => libc::pwritev(
+                    self.fd,
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                    *offset,
+                ),
+                Operation::Fsync => libc::fsync(self.fd) as libc::ssize_t,
+            }
+        };
+        // Errors are reported as negative errno values, as io_uring does.
+        let result = if result < 0 {
+            -io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(libc::EIO)
+        } else {
+            result as i32
+        };
+
+        self.completions
+            .list
+            .lock()
+            .unwrap()
+            .push_back((self.user_data, result));
+        if let Err(e) = self.completions.eventfd.write(1) {
+            error!("Failed notifying request completion: {}", e);
+        }
+    }
+}
+
+struct Completions {
+    list: Mutex<VecDeque<(u64, i32)>>,
+    eventfd: EventFd,
+}
+
+pub struct RawFileDiskThreadPool {
+    file: File,
+    jobs: SyncSender<Job>,
+}
+
+impl RawFileDiskThreadPool {
+    pub fn new(file: File, pool_size: usize, queue_depth: usize) -> io::Result<Self> {
+        let (jobs, receiver) = sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..pool_size {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("disk_pool{i}"))
+                .spawn(move || Self::work(&receiver))?;
+        }
+
+        Ok(RawFileDiskThreadPool { file, jobs })
+    }
+
+    // The worker threads exit once the disk and all its queues are gone.
+    fn work(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+            match job {
+                Ok(job) => job.run(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl DiskFile for RawFileDiskThreadPool {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.file
+            .seek(SeekFrom::End(0))
+            .map_err(DiskFileError::Size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(
+            RawFileAsyncThreadPool::new(self.file.as_raw_fd(), self.jobs.clone())
+                .map_err(DiskFileError::NewAsyncIo)?,
+        ) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        if let Ok(topology) = DiskTopology::probe(&self.file) {
+            topology
+        } else {
+            warn!("Unable to get device topology. Using default topology");
+            DiskTopology::default()
+        }
+    }
+}
+
+pub struct RawFileAsyncThreadPool {
+    fd: RawFd,
+    jobs: SyncSender<Job>,
+    completions: Arc<Completions>,
+}
+
+impl RawFileAsyncThreadPool {
+    fn new(fd: RawFd, jobs: SyncSender<Job>) -> io::Result<Self> {
+        let completions = Arc::new(Completions {
+            list: Mutex::new(VecDeque::new()),
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+        });
+
+        Ok(RawFileAsyncThreadPool {
+            fd,
+            jobs,
+            completions,
+        })
+    }
+
+    fn submit(&self, operation: Operation, user_data: u64) -> io::Result<()> {
+        self.jobs
+            .send(Job {
+                fd: self.fd,
+                operation,
+                user_data,
+                completions: self.completions.clone(),
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Disk thread pool is gone"))
+    }
+}
+
+impl AsyncIo for RawFileAsyncThreadPool {
+    fn notifier(&self) -> &EventFd {
+        &self.completions.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.submit(Operation::Read(iovecs.to_vec(), offset), user_data)
+            .map_err(AsyncIoError::ReadVectored)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.submit(Operation::Write(iovecs.to_vec(), offset), user_data)
+            .map_err(AsyncIoError::WriteVectored)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        if let Some(user_data) = user_data {
+            self.submit(Operation::Fsync, user_data)
+                .map_err(AsyncIoError::Fsync)?;
+        } else {
+            // SAFETY: FFI call with a valid fd
+            unsafe { libc::fsync(self.fd) };
+        }
+
+        Ok(())
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completions.list.lock().unwrap().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn wait_for_completion(async_io: &mut dyn AsyncIo) -> (u64, i32) {
+        loop {
+            if let Some(completion) = async_io.next_completed_request() {
+                return completion;
+            }
+            let _ = async_io.notifier().read();
+        }
+    }
+
+    #[test]
+    fn test_thread_pool_read_write() {
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[0xaa; 4096]).unwrap();
+
+        let disk = RawFileDiskThreadPool::new(file, 2, 4).unwrap();
+        let mut async_io = disk.new_async_io(1).unwrap();
+
+        let mut buf = [0u8; 512];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        async_io.read_vectored(1024, &iovecs, 1).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut()), (1, 512));
+        assert_eq!(buf, [0xaa; 512]);
+
+        buf.fill(0x55);
+        async_io.write_vectored(4096, &iovecs, 2).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut()), (2, 512));
+        async_io.fsync(Some(3)).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut()), (3, 0));
+
+        buf.fill(0);
+        async_io.read_vectored(4096, &iovecs, 4).unwrap();
+        assert_eq!(wait_for_completion(async_io.as_mut()), (4, 512));
+        assert_eq!(buf, [0x55; 512]);
+    }
+}
@@ -16,10 +16,12 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub const GED_DEVICE_ACPI_SIZE: usize = 0x1;
 
-/// A device for handling ACPI shutdown and reboot
+/// A device for handling ACPI shutdown, suspend, hibernation and reboot
 pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
+    hibernate_evt: EventFd,
+    suspend_evt: EventFd,
     vcpus_kill_signalled: Arc<AtomicBool>,
 }
 
@@ -28,11 +30,15 @@ impl AcpiShutdownDevice {
     pub fn new(
         exit_evt: EventFd,
         reset_evt: EventFd,
+        hibernate_evt: EventFd,
+        suspend_evt: EventFd,
         vcpus_kill_signalled: Arc<AtomicBool>,
     ) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
+            hibernate_evt,
+            suspend_evt,
             vcpus_kill_signalled,
         }
     }
@@ -59,10 +65,35 @@ impl BusDevice for AcpiShutdownDevice {
                 thread::sleep(std::time::Duration::from_millis(1));
             }
         }
-        // The ACPI DSDT table specifies the S5 sleep state (shutdown) as value 5
+        // The ACPI DSDT table specifies the S3 sleep state (suspend to RAM) as
+        // value 3, the S4 sleep state (hibernation) as value 4 and the S5
+        // sleep state (shutdown) as value 5
+        const S3_SLEEP_VALUE: u8 = 3;
+        const S4_SLEEP_VALUE: u8 = 4;
         const S5_SLEEP_VALUE: u8 = 5;
         const SLEEP_STATUS_EN_BIT: u8 = 5;
         const SLEEP_VALUE_BIT: u8 = 2;
+        if data[0] == (S3_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
+            info!("ACPI Suspend signalled");
+            if let Err(e) = self.suspend_evt.write(1) {
+                error!("Error triggering ACPI suspend event: {}", e);
+            }
+            // No need to wait for the VM to be paused, the guest keeps polling
+            // the wake status, which is never set, until then.
+        }
+        if data[0] == (S4_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
+            info!("ACPI Hibernation signalled");
+            if let Err(e) = self.hibernate_evt.write(1) {
+                error!("Error triggering ACPI hibernation event: {}", e);
+            }
+            // The guest has saved its hibernation image, it must not run
+            // any further until powered off.
+            while !self.vcpus_kill_signalled.load(Ordering::SeqCst) {
+                // This is more effective than thread::yield_now() at
+                // avoiding a priority inversion with the VMM thread
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
         if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
             info!("ACPI Shutdown signalled");
             if let Err(e) = self.exit_evt.write(1) {
@@ -0,0 +1,795 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! CXL 1.1 Type-3 memory expander.
+//!
+//! The memory device is a Root Complex Integrated Endpoint (RCiEP) sitting
+//! on a CXL host bridge operating in Restricted CXL Host (RCH) mode. The host
+//! bridge only exposes its Root Complex Register Blocks (RCRB), pointing to
+//! component registers without any capability, as the host-managed device
+//! memory (HDM) range is set up ahead of time and reported through the range
+//! registers of the CXL device DVSEC.
+//!
+//! The device registers provide the primary mailbox, implementing the few
+//! commands the Linux driver relies on to identify the device.
+
+use anyhow::anyhow;
+use pci::{
+    BarReprogrammingParams, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType,
+    PciCapability, PciCapabilityId, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
+    PciHeaderType, PciProgrammingInterface, PciSubclass,
+};
+use std::any::Any;
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::Instant;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::{BusDevice, Resource};
+use vm_memory::{Address, GuestAddress};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+
+const CXL_VENDOR_ID: u16 = 0x8086;
+const CXL_TYPE3_DEVICE_ID: u16 = 0x0d93;
+const CXL_DVSEC_VENDOR_ID: u32 = 0x1e98;
+
+/// Granularity of the HDM ranges, both for their base and their size.
+pub const CXL_HDM_ALIGNMENT: u64 = 256 << 20;
+
+/// Size of the MMIO window of a host bridge, holding the downstream and
+/// upstream port RCRBs followed by their component registers.
+pub const CXL_HOST_BRIDGE_MMIO_SIZE: u64 = 0x30000;
+/// Alignment of the MMIO window of a host bridge, as required for the
+/// component registers.
+pub const CXL_HOST_BRIDGE_MMIO_ALIGNMENT: u64 = 0x10000;
+/// Size of the RCRBs of a host bridge, as reported in the CEDT.
+pub const CXL_RCRB_SIZE: u64 = 0x2000;
+
+const RCRB_SIZE: u64 = 0x1000;
+const COMPONENT_REGS_OFFSET: u64 = 0x10000;
+const COMPONENT_REGS_SIZE: u64 = 0x10000;
+// CXL.cache and CXL.mem registers, within the component registers
+const CACHE_MEM_REGS_OFFSET: u64 = 0x1000;
+// CXL capability header, with version 1 and an empty capability array
+const CACHE_MEM_CAP_HEADER: u32 = 0x0011_0001;
+
+// Extended configuration space
+const EXT_CONFIG_OFFSET: usize = 0x100;
+const EXT_CONFIG_SIZE: usize = 0x1000 - EXT_CONFIG_OFFSET;
+const DSN_OFFSET: usize = 0x100;
+const DEVICE_DVSEC_OFFSET: usize = 0x110;
+const DEVICE_DVSEC_LENGTH: u32 = 0x38;
+const REGLOC_DVSEC_OFFSET: usize = 0x150;
+const REGLOC_DVSEC_LENGTH: u32 = 0x14;
+const EXT_CAP_ID_DSN: u32 = 0x3;
+const EXT_CAP_ID_DVSEC: u32 = 0x23;
+const DVSEC_ID_CXL_DEVICE: u16 = 0x0;
+const DVSEC_ID_REGISTER_LOCATOR: u16 = 0x8;
+// IO and memory capable, memory initialized by the platform, one HDM range
+const DEVICE_DVSEC_CAPABILITY: u16 = 0x1e;
+const DEVICE_DVSEC_IO_ENABLE: u16 = 1 << 1;
+const DEVICE_DVSEC_MEM_ENABLE: u16 = 1 << 2;
+const RANGE_MEMORY_INFO_VALID: u32 = 1 << 0;
+const RANGE_MEMORY_ACTIVE: u32 = 1 << 1;
+const REGLOC_BLOCK_ID_MEMDEV: u32 = 0x3;
+
+const DEVICE_BAR_INDEX: usize = 0;
+const DEVICE_BAR_SIZE: u64 = 0x10000;
+
+// Device registers
+const DEVICE_CAP_ARRAY_OFFSET: usize = 0x0;
+const DEVICE_STATUS_OFFSET: usize = 0x80;
+const DEVICE_STATUS_LENGTH: usize = 0x8;
+const MAILBOX_OFFSET: usize = 0x100;
+const MAILBOX_CAPS: usize = MAILBOX_OFFSET;
+const MAILBOX_CONTROL: usize = MAILBOX_OFFSET + 0x4;
+const MAILBOX_COMMAND: usize = MAILBOX_OFFSET + 0x8;
+const MAILBOX_STATUS: usize = MAILBOX_OFFSET + 0x10;
+const MAILBOX_PAYLOAD: usize = MAILBOX_OFFSET + 0x20;
+const MAILBOX_PAYLOAD_SHIFT: u32 = 11;
+const MAILBOX_PAYLOAD_SIZE: usize = 1 << MAILBOX_PAYLOAD_SHIFT;
+const MAILBOX_LENGTH: usize = 0x20 + MAILBOX_PAYLOAD_SIZE;
+const MEMDEV_STATUS_OFFSET: usize = MAILBOX_OFFSET + MAILBOX_LENGTH;
+const MEMDEV_STATUS_LENGTH: usize = 0x8;
+const DEVICE_REGS_SIZE: usize = MEMDEV_STATUS_OFFSET + MEMDEV_STATUS_LENGTH;
+const CAP_ID_DEVICE_STATUS: u32 = 0x1;
+const CAP_ID_PRIMARY_MAILBOX: u32 = 0x2;
+const CAP_ID_MEMDEV: u32 = 0x4000;
+// Media ready, mailbox interface ready
+const MEMDEV_STATUS_READY: u64 = (1 << 2) | (1 << 4);
+const MAILBOX_DOORBELL: u8 = 1 << 0;
+
+// Mailbox commands
+const OPCODE_GET_TIMESTAMP: u16 = 0x0300;
+const OPCODE_SET_TIMESTAMP: u16 = 0x0301;
+const OPCODE_GET_SUPPORTED_LOGS: u16 = 0x0400;
+const OPCODE_GET_LOG: u16 = 0x0401;
+const OPCODE_IDENTIFY: u16 = 0x4000;
+const OPCODE_GET_HEALTH_INFO: u16 = 0x4200;
+const SUPPORTED_OPCODES: [u16; 6] = [
+    OPCODE_GET_TIMESTAMP,
+    OPCODE_SET_TIMESTAMP,
+    OPCODE_GET_SUPPORTED_LOGS,
+    OPCODE_GET_LOG,
+    OPCODE_IDENTIFY,
+    OPCODE_GET_HEALTH_INFO,
+];
+
+// Mailbox return codes
+const RC_SUCCESS: u16 = 0x0;
+const RC_INVALID_INPUT: u16 = 0x2;
+const RC_UNSUPPORTED: u16 = 0x3;
+const RC_INVALID_PAYLOAD_LENGTH: u16 = 0x16;
+
+// Command Effects Log UUID 0da9c0b5-bf41-4b78-8f79-96b1623b3f17
+const CEL_UUID: [u8; 16] = [
+    0x0d, 0xa9, 0xc0, 0xb5, 0xbf, 0x41, 0x4b, 0x78, 0x8f, 0x79, 0x96, 0xb1, 0x62, 0x3b, 0x3f, 0x17,
+];
+const FIRMWARE_REVISION: &str = "1.0";
+const IDENTIFY_SIZE: usize = 0x43;
+const HEALTH_INFO_SIZE: usize = 0x12;
+
+#[derive(Copy, Clone)]
+enum CxlSubclass {
+    CxlMemory = 0x02,
+}
+
+impl PciSubclass for CxlSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Copy, Clone)]
+enum CxlProgrammingInterface {
+    MemoryDevice = 0x10,
+}
+
+impl PciProgrammingInterface for CxlProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// PCI Express capability of a Root Complex Integrated Endpoint, which has
+// no link or slot registers.
+struct PcieCap {
+    bytes: [u8; 0x3a],
+}
+
+impl PcieCap {
+    fn new() -> Self {
+        let mut bytes = [0u8; 0x3a];
+        // Capability version 2, RCiEP
+        bytes[0..2].copy_from_slice(&0x0092u16.to_le_bytes());
+        // Role based error reporting
+        bytes[2..6].copy_from_slice(&(1u32 << 15).to_le_bytes());
+        PcieCap { bytes }
+    }
+}
+
+impl PciCapability for PcieCap {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PciExpress
+    }
+}
+
+fn write_le(buf: &mut [u8], offset: usize, value: &[u8]) {
+    buf[offset..offset + value.len()].copy_from_slice(value);
+}
+
+fn read_slice(regs: &[u8], offset: u64, data: &mut [u8]) {
+    data.fill(0);
+    let offset = offset as usize;
+    if offset < regs.len() {
+        let len = data.len().min(regs.len() - offset);
+        data[..len].copy_from_slice(&regs[offset..offset + len]);
+    }
+}
+
+/// Host bridge of a CXL 1.1 memory device, whose MMIO window holds the
+/// downstream and upstream port RCRBs and their component registers.
+pub struct CxlHostBridge {
+    base: GuestAddress,
+    // Command register of each RCRB
+    commands: [u16; 2],
+}
+
+impl CxlHostBridge {
+    pub fn new(base: GuestAddress) -> Self {
+        CxlHostBridge {
+            base,
+            // Memory space enabled by the platform
+            commands: [0x2; 2],
+        }
+    }
+
+    fn read_dword(&self, offset: u64) -> u32 {
+        if offset < 2 * RCRB_SIZE {
+            let port = (offset / RCRB_SIZE) as usize;
+            // Each port points to its own component registers.
+            let component_regs =
+                self.base.raw_value() + COMPONENT_REGS_OFFSET + port as u64 * COMPONENT_REGS_SIZE;
+            match offset % RCRB_SIZE {
+                0x0 => ((CXL_TYPE3_DEVICE_ID as u32) << 16) | CXL_VENDOR_ID as u32,
+                0x4 => self.commands[port] as u32,
+                // 64-bit memory BAR
+                0x10 => component_regs as u32 | 0x4,
+                0x14 => (component_regs >> 32) as u32,
+                _ => 0,
+            }
+        } else if offset >= COMPONENT_REGS_OFFSET
+            && (offset - COMPONENT_REGS_OFFSET) % COMPONENT_REGS_SIZE == CACHE_MEM_REGS_OFFSET
+        {
+            CACHE_MEM_CAP_HEADER
+        } else {
+            0
+        }
+    }
+}
+
+impl BusDevice for CxlHostBridge {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let aligned = offset & !0x3;
+        let mut value = self.read_dword(aligned) as u64;
+        if data.len() > 4 {
+            value |= (self.read_dword(aligned + 4) as u64) << 32;
+        }
+        let bytes = (value >> ((offset & 0x3) * 8)).to_le_bytes();
+        let len = data.len().min(bytes.len());
+        data.fill(0);
+        data[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        // Only memory space and bus master enable can be changed.
+        if offset < 2 * RCRB_SIZE && offset % RCRB_SIZE == 0x4 && data.len() >= 2 {
+            let port = (offset / RCRB_SIZE) as usize;
+            self.commands[port] = u16::from_le_bytes([data[0], data[1]]) & 0x6;
+        } else {
+            debug!("Ignoring CXL RCRB write at 0x{:x}", offset);
+        }
+
+        None
+    }
+}
+
+/// CXL 1.1 Type-3 memory device, exposing a range of guest memory as its
+/// host-managed device memory.
+pub struct CxlType3Device {
+    id: String,
+    hdm_size: u64,
+
+    // PCI configuration registers, the extended ones holding the DVSECs.
+    configuration: PciConfiguration,
+    ext_config: Vec<u8>,
+    ext_config_writable: Vec<u8>,
+    bar_regions: Vec<PciBarConfiguration>,
+
+    // Device registers, including the mailbox payload.
+    regs: Vec<u8>,
+    timestamp: Option<(u64, Instant)>,
+}
+
+impl CxlType3Device {
+    /// Create a device whose HDM range covers `hdm_size` bytes at
+    /// `hdm_base`, both aligned on `CXL_HDM_ALIGNMENT`.
+    pub fn new(id: String, hdm_base: GuestAddress, hdm_size: u64, serial: u64) -> Self {
+        assert_eq!(hdm_base.raw_value() % CXL_HDM_ALIGNMENT, 0);
+        assert_eq!(hdm_size % CXL_HDM_ALIGNMENT, 0);
+
+        let configuration = PciConfiguration::new(
+            CXL_VENDOR_ID,
+            CXL_TYPE3_DEVICE_ID,
+            0x1,
+            PciClassCode::MemoryController,
+            &CxlSubclass::CxlMemory,
+            Some(&CxlProgrammingInterface::MemoryDevice),
+            PciHeaderType::Device,
+            CXL_VENDOR_ID,
+            CXL_TYPE3_DEVICE_ID,
+            None,
+            None,
+        );
+
+        let mut device = CxlType3Device {
+            id,
+            hdm_size,
+            configuration,
+            ext_config: vec![0; EXT_CONFIG_SIZE],
+            ext_config_writable: vec![0; EXT_CONFIG_SIZE],
+            bar_regions: vec![],
+            regs: vec![0; DEVICE_REGS_SIZE],
+            timestamp: None,
+        };
+        device.init_ext_config(hdm_base, serial);
+        device.init_regs();
+
+        device
+    }
+
+    fn init_ext_config(&mut self, hdm_base: GuestAddress, serial: u64) {
+        let ext_cap_header = |id: u32, next: usize| id | (1 << 16) | ((next as u32) << 20);
+        let dvsec_header =
+            |revision: u32, length: u32| CXL_DVSEC_VENDOR_ID | (revision << 16) | (length << 20);
+        let cfg = &mut self.ext_config;
+        let at = |offset: usize| offset - EXT_CONFIG_OFFSET;
+
+        // Device serial number, reported as the memory device serial.
+        write_le(
+            cfg,
+            at(DSN_OFFSET),
+            &ext_cap_header(EXT_CAP_ID_DSN, DEVICE_DVSEC_OFFSET).to_le_bytes(),
+        );
+        write_le(cfg, at(DSN_OFFSET + 0x4), &serial.to_le_bytes());
+
+        // CXL device DVSEC, with its single range being the HDM.
+        let dvsec = at(DEVICE_DVSEC_OFFSET);
+        write_le(
+            cfg,
+            dvsec,
+            &ext_cap_header(EXT_CAP_ID_DVSEC, REGLOC_DVSEC_OFFSET).to_le_bytes(),
+        );
+        write_le(
+            cfg,
+            dvsec + 0x4,
+            &dvsec_header(1, DEVICE_DVSEC_LENGTH).to_le_bytes(),
+        );
+        write_le(cfg, dvsec + 0x8, &DVSEC_ID_CXL_DEVICE.to_le_bytes());
+        write_le(cfg, dvsec + 0xa, &DEVICE_DVSEC_CAPABILITY.to_le_bytes());
+        write_le(
+            cfg,
+            dvsec + 0xc,
+            &(DEVICE_DVSEC_IO_ENABLE | DEVICE_DVSEC_MEM_ENABLE).to_le_bytes(),
+        );
+        self.ext_config_writable[dvsec + 0xc] = DEVICE_DVSEC_MEM_ENABLE as u8;
+        let size_low =
+            (self.hdm_size as u32 & 0xf000_0000) | RANGE_MEMORY_INFO_VALID | RANGE_MEMORY_ACTIVE;
+        write_le(
+            cfg,
+            dvsec + 0x18,
+            &((self.hdm_size >> 32) as u32).to_le_bytes(),
+        );
+        write_le(cfg, dvsec + 0x1c, &size_low.to_le_bytes());
+        let base = hdm_base.raw_value();
+        write_le(cfg, dvsec + 0x20, &((base >> 32) as u32).to_le_bytes());
+        write_le(
+            cfg,
+            dvsec + 0x24,
+            &(base as u32 & 0xf000_0000).to_le_bytes(),
+        );
+
+        // Register locator DVSEC, pointing to the device registers in BAR0.
+        let regloc = at(REGLOC_DVSEC_OFFSET);
+        write_le(
+            cfg,
+            regloc,
+            &ext_cap_header(EXT_CAP_ID_DVSEC, 0).to_le_bytes(),
+        );
+        write_le(
+            cfg,
+            regloc + 0x4,
+            &dvsec_header(0, REGLOC_DVSEC_LENGTH).to_le_bytes(),
+        );
+        write_le(cfg, regloc + 0x8, &DVSEC_ID_REGISTER_LOCATOR.to_le_bytes());
+        write_le(
+            cfg,
+            regloc + 0xc,
+            &(DEVICE_BAR_INDEX as u32 | (REGLOC_BLOCK_ID_MEMDEV << 8)).to_le_bytes(),
+        );
+    }
+
+    fn init_regs(&mut self) {
+        let caps = [
+            (
+                CAP_ID_DEVICE_STATUS,
+                DEVICE_STATUS_OFFSET,
+                DEVICE_STATUS_LENGTH,
+            ),
+            (CAP_ID_PRIMARY_MAILBOX, MAILBOX_OFFSET, MAILBOX_LENGTH),
+            (CAP_ID_MEMDEV, MEMDEV_STATUS_OFFSET, MEMDEV_STATUS_LENGTH),
+        ];
+
+        // Capabilities array, version 1
+        let array = (1u64 << 16) | ((caps.len() as u64) << 32);
+        write_le(
+            &mut self.regs,
+            DEVICE_CAP_ARRAY_OFFSET,
+            &array.to_le_bytes(),
+        );
+        for (i, (id, offset, length)) in caps.iter().enumerate() {
+            let header = DEVICE_CAP_ARRAY_OFFSET + 0x10 * (i + 1);
+            write_le(&mut self.regs, header, &(id | (1 << 16)).to_le_bytes());
+            write_le(
+                &mut self.regs,
+                header + 0x4,
+                &(*offset as u32).to_le_bytes(),
+            );
+            write_le(
+                &mut self.regs,
+                header + 0x8,
+                &(*length as u32).to_le_bytes(),
+            );
+        }
+
+        write_le(
+            &mut self.regs,
+            MAILBOX_CAPS,
+            &MAILBOX_PAYLOAD_SHIFT.to_le_bytes(),
+        );
+        write_le(
+            &mut self.regs,
+            MEMDEV_STATUS_OFFSET,
+            &MEMDEV_STATUS_READY.to_le_bytes(),
+        );
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.regs[offset..offset + 8].try_into().unwrap())
+    }
+
+    // Commands are processed synchronously, the doorbell being cleared by
+    // the time the driver polls it.
+    fn process_mailbox(&mut self) {
+        let command = self.read_u64(MAILBOX_COMMAND);
+        let opcode = command as u16;
+        let input_len = ((command >> 16) & 0x1f_ffff) as usize;
+
+        let (rc, output) = if input_len > MAILBOX_PAYLOAD_SIZE {
+            (RC_INVALID_PAYLOAD_LENGTH, Vec::new())
+        } else {
+            let input = self.regs[MAILBOX_PAYLOAD..MAILBOX_PAYLOAD + input_len].to_vec();
+            match self.handle_command(opcode, &input) {
+                Ok(output) => (RC_SUCCESS, output),
+                Err(rc) => {
+                    debug!(
+                        "CXL mailbox command 0x{:04x} failed with 0x{:x}",
+                        opcode, rc
+                    );
+                    (rc, Vec::new())
+                }
+            }
+        };
+
+        write_le(&mut self.regs, MAILBOX_PAYLOAD, &output);
+        let command = (command & !(0x1f_ffff << 16)) | ((output.len() as u64) << 16);
+        write_le(&mut self.regs, MAILBOX_COMMAND, &command.to_le_bytes());
+        write_le(
+            &mut self.regs,
+            MAILBOX_STATUS,
+            &((rc as u64) << 32).to_le_bytes(),
+        );
+        self.regs[MAILBOX_CONTROL] &= !MAILBOX_DOORBELL;
+    }
+
+    fn handle_command(&mut self, opcode: u16, input: &[u8]) -> result::Result<Vec<u8>, u16> {
+        let expect_input = |len: usize| {
+            if input.len() == len {
+                Ok(())
+            } else {
+                Err(RC_INVALID_PAYLOAD_LENGTH)
+            }
+        };
+
+        match opcode {
+            OPCODE_GET_TIMESTAMP => {
+                expect_input(0)?;
+                let now = self
+                    .timestamp
+                    .map(|(value, set_at)| value + set_at.elapsed().as_nanos() as u64)
+                    .unwrap_or_default();
+                Ok(now.to_le_bytes().to_vec())
+            }
+            OPCODE_SET_TIMESTAMP => {
+                expect_input(8)?;
+                let value = u64::from_le_bytes(input.try_into().unwrap());
+                self.timestamp = Some((value, Instant::now()));
+                Ok(Vec::new())
+            }
+            OPCODE_GET_SUPPORTED_LOGS => {
+                expect_input(0)?;
+                let mut output = vec![0u8; 8];
+                write_le(&mut output, 0, &1u16.to_le_bytes());
+                output.extend_from_slice(&CEL_UUID);
+                output.extend_from_slice(&(Self::cel().len() as u32).to_le_bytes());
+                Ok(output)
+            }
+            OPCODE_GET_LOG => {
+                expect_input(0x18)?;
+                let offset = u32::from_le_bytes(input[0x10..0x14].try_into().unwrap()) as usize;
+                let length = u32::from_le_bytes(input[0x14..0x18].try_into().unwrap()) as usize;
+                let cel = Self::cel();
+                if input[..0x10] != CEL_UUID
+                    || length > MAILBOX_PAYLOAD_SIZE
+                    || offset + length > cel.len()
+                {
+                    return Err(RC_INVALID_INPUT);
+                }
+                Ok(cel[offset..offset + length].to_vec())
+            }
+            OPCODE_IDENTIFY => {
+                expect_input(0)?;
+                // Capacities are in multiples of 256 MiB, the whole HDM
+                // being volatile memory.
+                let capacity = self.hdm_size / CXL_HDM_ALIGNMENT;
+                let mut output = vec![0u8; IDENTIFY_SIZE];
+                write_le(&mut output, 0x0, FIRMWARE_REVISION.as_bytes());
+                write_le(&mut output, 0x10, &capacity.to_le_bytes());
+                write_le(&mut output, 0x18, &capacity.to_le_bytes());
+                Ok(output)
+            }
+            OPCODE_GET_HEALTH_INFO => {
+                expect_input(0)?;
+                // Healthy device, with the temperature not reported.
+                let mut output = vec![0u8; HEALTH_INFO_SIZE];
+                write_le(&mut output, 0x4, &0xffffu16.to_le_bytes());
+                Ok(output)
+            }
+            _ => Err(RC_UNSUPPORTED),
+        }
+    }
+
+    // Command Effects Log, listing the supported commands with no effect.
+    fn cel() -> Vec<u8> {
+        SUPPORTED_OPCODES
+            .iter()
+            .flat_map(|opcode| (*opcode as u32).to_le_bytes())
+            .collect()
+    }
+}
+
+impl BusDevice for CxlType3Device {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for CxlType3Device {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        let start = reg_idx * 4 + offset as usize;
+        if start < EXT_CONFIG_OFFSET {
+            self.configuration
+                .write_config_register(reg_idx, offset, data);
+        } else if start + data.len() <= EXT_CONFIG_OFFSET + EXT_CONFIG_SIZE {
+            let start = start - EXT_CONFIG_OFFSET;
+            for (i, byte) in data.iter().enumerate() {
+                let mask = self.ext_config_writable[start + i];
+                let value = &mut self.ext_config[start + i];
+                *value = (*value & !mask) | (byte & mask);
+            }
+        }
+
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        let start = reg_idx * 4;
+        if start < EXT_CONFIG_OFFSET {
+            self.configuration.read_reg(reg_idx)
+        } else if start + 4 <= EXT_CONFIG_OFFSET + EXT_CONFIG_SIZE {
+            let start = start - EXT_CONFIG_OFFSET;
+            u32::from_le_bytes(self.ext_config[start..start + 4].try_into().unwrap())
+        } else {
+            0xffff_ffff
+        }
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        _mmio32_allocator: &mut AddressAllocator,
+        mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let restoring = resources.is_some();
+        let bar_addr = mmio64_allocator
+            .allocate(None, DEVICE_BAR_SIZE, Some(DEVICE_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(DEVICE_BAR_SIZE))?;
+
+        let bar = PciBarConfiguration::default()
+            .set_index(DEVICE_BAR_INDEX)
+            .set_address(bar_addr.raw_value())
+            .set_size(DEVICE_BAR_SIZE)
+            .set_region_type(PciBarRegionType::Memory64BitRegion)
+            .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
+
+        debug!("CXL device {} registers at 0x{:x}", self.id, bar_addr.0);
+        if !restoring {
+            self.configuration
+                .add_pci_bar(&bar)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+            self.configuration
+                .add_capability(&PcieCap::new())
+                .map_err(PciDeviceError::CapabilitiesSetup)?;
+        }
+
+        self.bar_regions = vec![bar];
+
+        Ok(vec![bar])
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        _mmio32_allocator: &mut AddressAllocator,
+        mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            mmio64_allocator.free(GuestAddress(bar.addr()), bar.size());
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        read_slice(&self.regs, offset, data)
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        let start = offset as usize;
+        let end = start + data.len();
+        // Only the mailbox control, command and payload are writable.
+        if (MAILBOX_CONTROL <= start && end <= MAILBOX_STATUS)
+            || (MAILBOX_PAYLOAD <= start && end <= MAILBOX_PAYLOAD + MAILBOX_PAYLOAD_SIZE)
+        {
+            self.regs[start..end].copy_from_slice(data);
+            if start == MAILBOX_CONTROL && self.regs[MAILBOX_CONTROL] & MAILBOX_DOORBELL != 0 {
+                self.process_mailbox();
+            }
+        } else {
+            warn!(
+                "Unsupported CXL register write of {} bytes at 0x{:x}",
+                data.len(),
+                offset
+            );
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for CxlType3Device {}
+
+impl Snapshottable for CxlType3Device {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The HDM placement isn't restored along with the guest memory.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "Can't snapshot a CXL memory device"
+        )))
+    }
+}
+
+impl Transportable for CxlType3Device {}
+impl Migratable for CxlType3Device {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailbox_command(device: &mut CxlType3Device, opcode: u16, input: &[u8]) -> (u16, Vec<u8>) {
+        device.write_bar(0, MAILBOX_PAYLOAD as u64, input);
+        let command = opcode as u64 | ((input.len() as u64) << 16);
+        device.write_bar(0, MAILBOX_COMMAND as u64, &command.to_le_bytes());
+        device.write_bar(0, MAILBOX_CONTROL as u64, &1u32.to_le_bytes());
+
+        let mut control = [0u8; 4];
+        device.read_bar(0, MAILBOX_CONTROL as u64, &mut control);
+        assert_eq!(control[0] & MAILBOX_DOORBELL, 0);
+
+        let mut status = [0u8; 8];
+        device.read_bar(0, MAILBOX_STATUS as u64, &mut status);
+        let mut command = [0u8; 8];
+        device.read_bar(0, MAILBOX_COMMAND as u64, &mut command);
+        let output_len = ((u64::from_le_bytes(command) >> 16) & 0x1f_ffff) as usize;
+        let mut output = vec![0u8; output_len];
+        device.read_bar(0, MAILBOX_PAYLOAD as u64, &mut output);
+
+        ((u64::from_le_bytes(status) >> 32) as u16, output)
+    }
+
+    #[test]
+    fn test_cxl_mailbox() {
+        let mut device = CxlType3Device::new("cxl0".to_owned(), GuestAddress(1 << 32), 1 << 30, 1);
+
+        let (rc, output) = mailbox_command(&mut device, OPCODE_IDENTIFY, &[]);
+        assert_eq!(rc, RC_SUCCESS);
+        assert_eq!(output.len(), IDENTIFY_SIZE);
+        assert_eq!(
+            u64::from_le_bytes(output[0x10..0x18].try_into().unwrap()),
+            4
+        );
+
+        let (rc, output) = mailbox_command(&mut device, OPCODE_GET_SUPPORTED_LOGS, &[]);
+        assert_eq!(rc, RC_SUCCESS);
+        assert_eq!(output[8..24], CEL_UUID);
+
+        let mut input = CEL_UUID.to_vec();
+        input.extend_from_slice(&0u32.to_le_bytes());
+        input.extend_from_slice(&(4 * SUPPORTED_OPCODES.len() as u32).to_le_bytes());
+        let (rc, output) = mailbox_command(&mut device, OPCODE_GET_LOG, &input);
+        assert_eq!(rc, RC_SUCCESS);
+        assert_eq!(output[0..2], OPCODE_GET_TIMESTAMP.to_le_bytes());
+
+        assert_eq!(
+            mailbox_command(&mut device, OPCODE_SET_TIMESTAMP, &[0; 4]).0,
+            RC_INVALID_PAYLOAD_LENGTH
+        );
+        assert_eq!(mailbox_command(&mut device, 0x4100, &[]).0, RC_UNSUPPORTED);
+    }
+
+    #[test]
+    fn test_cxl_dvsec_ranges() {
+        let mut device =
+            CxlType3Device::new("cxl0".to_owned(), GuestAddress(0x1_5000_0000), 1 << 30, 1);
+        let reg = |offset: usize| offset / 4;
+
+        // Range 1 size and base
+        assert_eq!(
+            device.read_config_register(reg(DEVICE_DVSEC_OFFSET + 0x18)),
+            0
+        );
+        assert_eq!(
+            device.read_config_register(reg(DEVICE_DVSEC_OFFSET + 0x1c)),
+            0x4000_0003
+        );
+        assert_eq!(
+            device.read_config_register(reg(DEVICE_DVSEC_OFFSET + 0x20)),
+            1
+        );
+        assert_eq!(
+            device.read_config_register(reg(DEVICE_DVSEC_OFFSET + 0x24)),
+            0x5000_0000
+        );
+
+        // Only memory enable can be cleared.
+        device.write_config_register(reg(DEVICE_DVSEC_OFFSET + 0xc), 0, &[0, 0, 0, 0]);
+        assert_eq!(
+            device.read_config_register(reg(DEVICE_DVSEC_OFFSET + 0xc)) & 0xffff,
+            DEVICE_DVSEC_IO_ENABLE as u32
+        );
+    }
+}
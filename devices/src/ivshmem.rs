@@ -0,0 +1,596 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! ivshmem-plain device, exposing a host shared memory file to the guest
+//! through a PCI BAR. See docs/specs/ivshmem-spec.txt in the QEMU sources.
+
+use anyhow::anyhow;
+use pci::{
+    BarReprogrammingParams, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType, PciSubclass,
+    PCI_CONFIGURATION_ID,
+};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::{BusDevice, PciBarType, Resource};
+use vm_memory::{Address, GuestAddress};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+
+const IVSHMEM_VENDOR_ID: u16 = 0x1af4;
+const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+const IVSHMEM_REVISION_ID: u8 = 0x1;
+
+const IVSHMEM_REG_BAR_INDEX: usize = 0;
+const IVSHMEM_MEM_BAR_INDEX: usize = 2;
+const IVSHMEM_REG_BAR_SIZE: u64 = 0x100;
+
+// Register offsets in BAR0. Without the doorbell feature the IVPosition
+// register always reads as zero, since the device has no peer ID.
+const INTR_MASK: u64 = 0x0;
+const INTR_STATUS: u64 = 0x4;
+const DOORBELL: u64 = 0xc;
+
+#[derive(Debug, Error)]
+pub enum IvshmemError {
+    #[error("Failed to retrieve PciConfigurationState: {0}")]
+    RetrievePciConfigurationState(#[source] anyhow::Error),
+    #[error("Failed to retrieve IvshmemDeviceState: {0}")]
+    RetrieveIvshmemDeviceState(#[source] anyhow::Error),
+    #[error("Invalid shared memory size {0:#x}, must be a power of two of at least 4 KiB")]
+    InvalidSize(u64),
+    #[error("Failed to mmap the shared memory file: {0}")]
+    Mmap(#[source] std::io::Error),
+    #[error("Failed to map the shared memory into the guest: {0}")]
+    CreateUserMemoryRegion(#[source] hypervisor::HypervisorVmError),
+    #[error("Failed to unmap the shared memory from the guest: {0}")]
+    RemoveUserMemoryRegion(#[source] hypervisor::HypervisorVmError),
+}
+
+#[derive(Copy, Clone)]
+enum IvshmemSubclass {
+    Other = 0x80,
+}
+
+impl PciSubclass for IvshmemSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IvshmemDeviceState {
+    intr_mask: u32,
+    intr_status: u32,
+}
+
+/// Exposes a host shared memory file to the guest as BAR2 of a PCI device
+pub struct IvshmemDevice {
+    id: String,
+    intr_mask: u32,
+    intr_status: u32,
+
+    // PCI configuration registers.
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+
+    // Shared memory, mapped in the VMM address space.
+    vm: Arc<dyn hypervisor::Vm>,
+    host_addr: u64,
+    size: u64,
+    mem_slot: u32,
+    // Guest address the shared memory is currently mapped at.
+    mapped_addr: Option<u64>,
+}
+
+impl IvshmemDevice {
+    pub fn new(
+        id: String,
+        file: &File,
+        size: u64,
+        vm: Arc<dyn hypervisor::Vm>,
+        mem_slot: u32,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, IvshmemError> {
+        if !size.is_power_of_two() || size < 0x1000 {
+            return Err(IvshmemError::InvalidSize(size));
+        }
+
+        let pci_configuration_state =
+            vm_migration::state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID).map_err(|e| {
+                IvshmemError::RetrievePciConfigurationState(anyhow!(
+                    "Failed to get PciConfigurationState from Snapshot: {}",
+                    e
+                ))
+            })?;
+
+        let configuration = PciConfiguration::new(
+            IVSHMEM_VENDOR_ID,
+            IVSHMEM_DEVICE_ID,
+            IVSHMEM_REVISION_ID,
+            PciClassCode::MemoryController,
+            &IvshmemSubclass::Other,
+            None,
+            PciHeaderType::Device,
+            IVSHMEM_VENDOR_ID,
+            IVSHMEM_DEVICE_ID,
+            None,
+            pci_configuration_state,
+        );
+
+        let state: Option<IvshmemDeviceState> = snapshot
+            .as_ref()
+            .map(|s| s.to_state())
+            .transpose()
+            .map_err(|e| {
+                IvshmemError::RetrieveIvshmemDeviceState(anyhow!(
+                    "Failed to get IvshmemDeviceState from Snapshot: {}",
+                    e
+                ))
+            })?;
+        let (intr_mask, intr_status) = if let Some(state) = state {
+            (state.intr_mask, state.intr_status)
+        } else {
+            (0, 0)
+        };
+
+        // SAFETY: FFI call with a valid file descriptor and a size checked
+        // against the file by the caller.
+        let host_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return Err(IvshmemError::Mmap(std::io::Error::last_os_error()));
+        }
+
+        Ok(IvshmemDevice {
+            id,
+            intr_mask,
+            intr_status,
+            configuration,
+            bar_regions: vec![],
+            vm,
+            host_addr: host_addr as u64,
+            size,
+            mem_slot,
+            mapped_addr: None,
+        })
+    }
+
+    fn state(&self) -> IvshmemDeviceState {
+        IvshmemDeviceState {
+            intr_mask: self.intr_mask,
+            intr_status: self.intr_status,
+        }
+    }
+
+    fn mem_bar_addr(&self) -> Option<u64> {
+        self.bar_regions
+            .iter()
+            .find(|bar| bar.idx() == IVSHMEM_MEM_BAR_INDEX)
+            .map(|bar| bar.addr())
+    }
+
+    fn map_at(&mut self, guest_addr: u64) -> Result<(), IvshmemError> {
+        let mem_region = self.vm.make_user_memory_region(
+            self.mem_slot,
+            guest_addr,
+            self.size,
+            self.host_addr,
+            false,
+            false,
+        );
+        self.vm
+            .create_user_memory_region(mem_region)
+            .map_err(IvshmemError::CreateUserMemoryRegion)?;
+        self.mapped_addr = Some(guest_addr);
+
+        Ok(())
+    }
+
+    fn unmap(&mut self) -> Result<(), IvshmemError> {
+        if let Some(guest_addr) = self.mapped_addr.take() {
+            let mem_region = self.vm.make_user_memory_region(
+                self.mem_slot,
+                guest_addr,
+                self.size,
+                self.host_addr,
+                false,
+                false,
+            );
+            self.vm
+                .remove_user_memory_region(mem_region)
+                .map_err(IvshmemError::RemoveUserMemoryRegion)?;
+        }
+
+        Ok(())
+    }
+
+    /// Map the shared memory into the guest, at the address of BAR2. Must be
+    /// called once the BARs have been allocated.
+    pub fn map_shared_memory(&mut self) -> Result<(), IvshmemError> {
+        if let Some(guest_addr) = self.mem_bar_addr() {
+            self.unmap()?;
+            self.map_at(guest_addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for IvshmemDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.unmap() {
+            error!("Failed to unmap ivshmem region: {}", e);
+        }
+
+        // SAFETY: the region was mapped in new() and isn't accessed anymore.
+        unsafe {
+            libc::munmap(self.host_addr as *mut libc::c_void, self.size as usize);
+        }
+    }
+}
+
+impl BusDevice for IvshmemDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for IvshmemDevice {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        mmio32_allocator: &mut AddressAllocator,
+        mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let mut bars = Vec::new();
+        let mut reg_bar_addr = None;
+        let mut mem_bar_addr = None;
+        let restoring = resources.is_some();
+        if let Some(resources) = resources {
+            for resource in resources {
+                if let Resource::PciBar {
+                    index, base, type_, ..
+                } = resource
+                {
+                    match (index, type_) {
+                        (IVSHMEM_REG_BAR_INDEX, PciBarType::Mmio32) => {
+                            reg_bar_addr = Some(GuestAddress(base))
+                        }
+                        (IVSHMEM_MEM_BAR_INDEX, PciBarType::Mmio64) => {
+                            mem_bar_addr = Some(GuestAddress(base))
+                        }
+                        _ => return Err(PciDeviceError::InvalidResource(resource)),
+                    }
+                }
+            }
+            if reg_bar_addr.is_none() || mem_bar_addr.is_none() {
+                return Err(PciDeviceError::MissingResource);
+            }
+        }
+
+        // BAR0 holds the registers.
+        let addr = mmio32_allocator
+            .allocate(
+                reg_bar_addr,
+                IVSHMEM_REG_BAR_SIZE,
+                Some(IVSHMEM_REG_BAR_SIZE),
+            )
+            .ok_or(PciDeviceError::IoAllocationFailed(IVSHMEM_REG_BAR_SIZE))?;
+        bars.push(
+            PciBarConfiguration::default()
+                .set_index(IVSHMEM_REG_BAR_INDEX)
+                .set_address(addr.raw_value())
+                .set_size(IVSHMEM_REG_BAR_SIZE)
+                .set_region_type(PciBarRegionType::Memory32BitRegion)
+                .set_prefetchable(PciBarPrefetchable::NotPrefetchable),
+        );
+
+        // BAR2 exposes the shared memory, naturally aligned on its size.
+        let addr = mmio64_allocator
+            .allocate(mem_bar_addr, self.size, Some(self.size))
+            .ok_or(PciDeviceError::IoAllocationFailed(self.size))?;
+        bars.push(
+            PciBarConfiguration::default()
+                .set_index(IVSHMEM_MEM_BAR_INDEX)
+                .set_address(addr.raw_value())
+                .set_size(self.size)
+                .set_region_type(PciBarRegionType::Memory64BitRegion)
+                .set_prefetchable(PciBarPrefetchable::Prefetchable),
+        );
+
+        if !restoring {
+            for bar in bars.iter() {
+                self.configuration
+                    .add_pci_bar(bar)
+                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar.addr(), e))?;
+            }
+        }
+
+        debug!(
+            "ivshmem {} registers at 0x{:x}, shared memory at 0x{:x}",
+            self.id,
+            bars[0].addr(),
+            bars[1].addr()
+        );
+
+        self.bar_regions.clone_from(&bars);
+
+        Ok(bars)
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        mmio32_allocator: &mut AddressAllocator,
+        mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            match bar.region_type() {
+                PciBarRegionType::Memory32BitRegion => {
+                    mmio32_allocator.free(GuestAddress(bar.addr()), bar.size())
+                }
+                PciBarRegionType::Memory64BitRegion => {
+                    mmio64_allocator.free(GuestAddress(bar.addr()), bar.size())
+                }
+                PciBarRegionType::IoRegion => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        // The shared memory follows BAR2 wherever the guest moves it.
+        if self.mapped_addr == Some(old_base) {
+            self.unmap()
+                .and_then(|_| self.map_at(new_base))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        if Some(base) == self.mem_bar_addr() || data.len() != 4 {
+            return;
+        }
+
+        let value: u32 = match offset {
+            INTR_MASK => self.intr_mask,
+            // Reading the status acknowledges the interrupts.
+            INTR_STATUS => std::mem::take(&mut self.intr_status),
+            _ => 0,
+        };
+        data.copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if Some(base) == self.mem_bar_addr() || data.len() != 4 {
+            return None;
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        match offset {
+            INTR_MASK => self.intr_mask = value,
+            INTR_STATUS => self.intr_status = value,
+            DOORBELL => debug!("ivshmem {}: ignoring doorbell write", self.id),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for IvshmemDevice {}
+
+impl Snapshottable for IvshmemDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new_from_state(&self.state())?;
+
+        // Snapshot PciConfiguration
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for IvshmemDevice {}
+impl Migratable for IvshmemDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const SHM_SIZE: u64 = 0x10_0000;
+    const REG_BAR_ADDR: u64 = 0xd000_0000;
+    const MEM_BAR_ADDR: u64 = 0x1_0000_0000;
+
+    fn create_device(snapshot: Option<Snapshot>) -> IvshmemDevice {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(SHM_SIZE).unwrap();
+        let hv = hypervisor::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mut device =
+            IvshmemDevice::new("ivshmem0".to_owned(), &file, SHM_SIZE, vm, 0, snapshot).unwrap();
+        device.bar_regions = vec![
+            PciBarConfiguration::default()
+                .set_index(IVSHMEM_REG_BAR_INDEX)
+                .set_address(REG_BAR_ADDR)
+                .set_size(IVSHMEM_REG_BAR_SIZE),
+            PciBarConfiguration::default()
+                .set_index(IVSHMEM_MEM_BAR_INDEX)
+                .set_address(MEM_BAR_ADDR)
+                .set_size(SHM_SIZE),
+        ];
+        device
+    }
+
+    fn read_reg(device: &mut IvshmemDevice, offset: u64) -> u32 {
+        let mut data = [0xffu8; 4];
+        device.read_bar(REG_BAR_ADDR, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_reg(device: &mut IvshmemDevice, offset: u64, value: u32) {
+        device.write_bar(REG_BAR_ADDR, offset, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_invalid_size() {
+        let file = TempFile::new().unwrap().into_file();
+        let hv = hypervisor::new().unwrap();
+        for size in [0x800, 0x3000] {
+            assert!(matches!(
+                IvshmemDevice::new(
+                    "ivshmem0".to_owned(),
+                    &file,
+                    size,
+                    hv.create_vm().unwrap(),
+                    0,
+                    None
+                ),
+                Err(IvshmemError::InvalidSize(s)) if s == size
+            ));
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut device = create_device(None);
+
+        write_reg(&mut device, INTR_MASK, 0xffff_ffff);
+        assert_eq!(read_reg(&mut device, INTR_MASK), 0xffff_ffff);
+
+        // Reading the status clears it.
+        device.intr_status = 0x1;
+        assert_eq!(read_reg(&mut device, INTR_STATUS), 0x1);
+        assert_eq!(read_reg(&mut device, INTR_STATUS), 0);
+
+        // The IVPosition register and unknown registers read as zero.
+        assert_eq!(read_reg(&mut device, 0x8), 0);
+        assert_eq!(read_reg(&mut device, 0x40), 0);
+
+        // Accesses other than 32-bit wide ones are ignored.
+        device.write_bar(REG_BAR_ADDR, INTR_MASK, &[0u8; 2]);
+        let mut data = [0xffu8; 8];
+        device.read_bar(REG_BAR_ADDR, INTR_MASK, &mut data);
+        assert_eq!(data, [0u8; 8]);
+        assert_eq!(read_reg(&mut device, INTR_MASK), 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_doorbell() {
+        let mut device = create_device(None);
+        write_reg(&mut device, INTR_MASK, 0x1);
+
+        // Without peers, ringing the doorbell has no effect.
+        write_reg(&mut device, DOORBELL, 0x1_0001);
+        assert_eq!(read_reg(&mut device, DOORBELL), 0);
+        assert_eq!(read_reg(&mut device, INTR_STATUS), 0);
+        assert_eq!(read_reg(&mut device, INTR_MASK), 0x1);
+    }
+
+    #[test]
+    fn test_shared_memory_bar() {
+        let mut device = create_device(None);
+
+        // Accesses to BAR2 are not handled as register accesses.
+        device.write_bar(MEM_BAR_ADDR, INTR_MASK, &0x1u32.to_le_bytes());
+        assert_eq!(read_reg(&mut device, INTR_MASK), 0);
+        let mut data = [0xffu8; 4];
+        device.read_bar(MEM_BAR_ADDR, INTR_MASK, &mut data);
+        assert_eq!(data, [0u8; 4]);
+
+        // The shared memory is mapped at BAR2, and follows it when the
+        // guest moves it.
+        device.map_shared_memory().unwrap();
+        assert_eq!(device.mapped_addr, Some(MEM_BAR_ADDR));
+        device
+            .move_bar(MEM_BAR_ADDR, MEM_BAR_ADDR + SHM_SIZE)
+            .unwrap();
+        assert_eq!(device.mapped_addr, Some(MEM_BAR_ADDR + SHM_SIZE));
+        assert_eq!(device.mem_bar_addr(), Some(MEM_BAR_ADDR + SHM_SIZE));
+
+        // Moving BAR0 leaves the mapping untouched.
+        device
+            .move_bar(REG_BAR_ADDR, REG_BAR_ADDR + 0x1000)
+            .unwrap();
+        assert_eq!(device.mapped_addr, Some(MEM_BAR_ADDR + SHM_SIZE));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut device = create_device(None);
+        write_reg(&mut device, INTR_MASK, 0x3);
+        device.intr_status = 0x1;
+
+        let snapshot = device.snapshot().unwrap();
+        let mut restored = create_device(Some(snapshot));
+        assert_eq!(read_reg(&mut restored, INTR_MASK), 0x3);
+        assert_eq!(read_reg(&mut restored, INTR_STATUS), 0x1);
+    }
+}
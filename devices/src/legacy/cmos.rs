@@ -5,12 +5,14 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use libc::{clock_gettime, gmtime_r, timespec, tm, CLOCK_REALTIME};
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
 use std::mem;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use vm_device::BusDevice;
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 // https://github.com/rust-lang/libc/issues/1848
@@ -21,11 +23,37 @@ const INDEX_MASK: u8 = 0x7f;
 const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
+const NANOSECONDS_PER_SECOND: i64 = 1_000_000_000;
+
+fn host_realtime_ns() -> i64 {
+    let mut timespec = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: the parameters are valid.
+    unsafe { clock_gettime(CLOCK_REALTIME, &mut timespec) };
+    timespec.tv_sec * NANOSECONDS_PER_SECOND + timespec.tv_nsec
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CmosState {
+    index: u8,
+    data: Vec<u8>,
+    rtc_ns: i64,
+}
 
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
+    id: String,
     index: u8,
     data: [u8; DATA_LEN],
+    // Offset of the RTC from the host wall clock, in nanoseconds
+    rtc_offset_ns: i64,
+    // Whether the RTC keeps following the host wall clock while the VM is
+    // paused or saved, rather than only running along with the VM
+    rtc_track_host: bool,
+    // RTC time at which the VM was paused, if the RTC runs along with the VM
+    rtc_paused_ns: Option<i64>,
     reset_evt: EventFd,
     vcpus_kill_signalled: Option<Arc<AtomicBool>>,
 }
@@ -34,12 +62,42 @@ impl Cmos {
     /// Constructs a CMOS/RTC device with initial data.
     /// `mem_below_4g` is the size of memory in bytes below the 32-bit gap.
     /// `mem_above_4g` is the size of memory in bytes above the 32-bit gap.
+    /// `rtc_offset_ns` is the offset of the RTC from the host wall clock.
+    /// `rtc_track_host` tells whether the RTC follows the host wall clock
+    /// across pause and restore, or only runs along with the VM.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        id: String,
         mem_below_4g: u64,
         mem_above_4g: u64,
         reset_evt: EventFd,
         vcpus_kill_signalled: Option<Arc<AtomicBool>>,
+        rtc_offset_ns: i64,
+        rtc_track_host: bool,
+        state: Option<CmosState>,
     ) -> Cmos {
+        if let Some(state) = state {
+            let mut data = [0u8; DATA_LEN];
+            let len = min(DATA_LEN, state.data.len());
+            data[..len].copy_from_slice(&state.data[..len]);
+            let rtc_offset_ns = if rtc_track_host {
+                rtc_offset_ns
+            } else {
+                state.rtc_ns - host_realtime_ns()
+            };
+
+            return Cmos {
+                id,
+                index: state.index,
+                data,
+                rtc_offset_ns,
+                rtc_track_host,
+                rtc_paused_ns: None,
+                reset_evt,
+                vcpus_kill_signalled,
+            };
+        }
+
         let mut data = [0u8; DATA_LEN];
 
         // Extended memory from 16 MB to 4 GB in units of 64 KB
@@ -57,12 +115,29 @@ impl Cmos {
         data[0x5d] = (high_mem >> 16) as u8;
 
         Cmos {
+            id,
             index: 0,
             data,
+            rtc_offset_ns,
+            rtc_track_host,
+            rtc_paused_ns: None,
             reset_evt,
             vcpus_kill_signalled,
         }
     }
+
+    fn rtc_ns(&self) -> i64 {
+        self.rtc_paused_ns
+            .unwrap_or_else(|| host_realtime_ns() + self.rtc_offset_ns)
+    }
+
+    fn state(&self) -> CmosState {
+        CmosState {
+            index: self.index,
+            data: self.data.to_vec(),
+            rtc_ns: self.rtc_ns(),
+        }
+    }
 }
 
 impl BusDevice for Cmos {
@@ -117,16 +192,14 @@ impl BusDevice for Cmos {
                 let day;
                 let month;
                 let year;
-                // SAFETY: The clock_gettime and gmtime_r calls are safe as long as the structs they are
-                // given are large enough, and neither of them fail. It is safe to zero initialize
-                // the tm and timespec struct because it contains only plain data.
+                let rtc_ns = self.rtc_ns();
+                // SAFETY: The gmtime_r call is safe as long as the struct it is given is large
+                // enough, and it doesn't fail. It is safe to zero initialize the tm struct
+                // because it contains only plain data.
                 let update_in_progress = unsafe {
-                    let mut timespec: timespec = mem::zeroed();
-                    clock_gettime(CLOCK_REALTIME, &mut timespec as *mut _);
-
                     // https://github.com/rust-lang/libc/issues/1848
                     #[cfg_attr(target_env = "musl", allow(deprecated))]
-                    let now: time_t = timespec.tv_sec;
+                    let now: time_t = rtc_ns.div_euclid(NANOSECONDS_PER_SECOND);
                     let mut tm: tm = mem::zeroed();
                     gmtime_r(&now, &mut tm as *mut _);
 
@@ -140,9 +213,9 @@ impl BusDevice for Cmos {
                     year = tm.tm_year;
 
                     // Update in Progress bit held for last 224us of each second
-                    const NANOSECONDS_PER_SECOND: i64 = 1_000_000_000;
                     const UIP_HOLD_LENGTH: i64 = 8 * NANOSECONDS_PER_SECOND / 32768;
-                    timespec.tv_nsec >= (NANOSECONDS_PER_SECOND - UIP_HOLD_LENGTH)
+                    rtc_ns.rem_euclid(NANOSECONDS_PER_SECOND)
+                        >= (NANOSECONDS_PER_SECOND - UIP_HOLD_LENGTH)
                 };
                 match self.index {
                     0x00 => to_bcd(seconds as u8),
@@ -171,3 +244,32 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+impl Pausable for Cmos {
+    fn pause(&mut self) -> Result<(), MigratableError> {
+        if !self.rtc_track_host {
+            self.rtc_paused_ns = Some(self.rtc_ns());
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), MigratableError> {
+        if let Some(rtc_paused_ns) = self.rtc_paused_ns.take() {
+            self.rtc_offset_ns = rtc_paused_ns - host_realtime_ns();
+        }
+        Ok(())
+    }
+}
+
+impl Snapshottable for Cmos {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+
+impl Transportable for Cmos {}
+impl Migratable for Cmos {}
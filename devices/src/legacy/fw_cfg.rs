@@ -0,0 +1,324 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! QEMU compatible firmware configuration (fw_cfg) device.
+//!
+//! It exposes blobs supplied by the operator as named files, retrievable by
+//! the firmware or the guest through either the traditional selector/data
+//! registers, or the DMA interface.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+/// I/O port base of the device, where firmware and guests expect it.
+pub const FW_CFG_PORT_BASE: u64 = 0x510;
+/// Size of the I/O port range covered by the device.
+pub const FW_CFG_PORT_SIZE: u64 = 0xc;
+/// Maximum length of a file name, including the terminating NUL.
+pub const FW_CFG_MAX_FILE_NAME: usize = 56;
+
+const SELECTOR_OFFSET: u64 = 0x0;
+const DATA_OFFSET: u64 = 0x1;
+const DMA_ADDRESS_HIGH_OFFSET: u64 = 0x4;
+const DMA_ADDRESS_LOW_OFFSET: u64 = 0x8;
+
+// Well-known items
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_ID: u16 = 0x01;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_FIRST: u16 = 0x20;
+
+// Features advertised through FW_CFG_ID
+const FW_CFG_VERSION: u32 = 1 << 0;
+const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+
+// Value read from the DMA address register, "QEMU CFG"
+const FW_CFG_DMA_SIGNATURE: u64 = 0x5145_4d55_2043_4647;
+
+// Control field of the FWCfgDmaAccess structure
+const FW_CFG_DMA_CTL_ERROR: u32 = 1 << 0;
+const FW_CFG_DMA_CTL_READ: u32 = 1 << 1;
+const FW_CFG_DMA_CTL_SKIP: u32 = 1 << 2;
+const FW_CFG_DMA_CTL_SELECT: u32 = 1 << 3;
+const FW_CFG_DMA_CTL_WRITE: u32 = 1 << 4;
+
+/// Blob exposed to the guest under a file name, such as
+/// `opt/org.example/config`.
+pub struct FwCfgFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// A fw_cfg device on x86 I/O ports 0x510 to 0x51b.
+pub struct FwCfg {
+    items: HashMap<u16, Vec<u8>>,
+    selector: u16,
+    offset: usize,
+    dma_address_high: u32,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+}
+
+impl FwCfg {
+    /// Constructs a fw_cfg device exposing the given files, which guest
+    /// memory is used for DMA transfers.
+    pub fn new(mut files: Vec<FwCfgFile>, mem: GuestMemoryAtomic<GuestMemoryMmap>) -> Self {
+        let mut items = HashMap::new();
+        items.insert(FW_CFG_SIGNATURE, b"QEMU".to_vec());
+        items.insert(
+            FW_CFG_ID,
+            (FW_CFG_VERSION | FW_CFG_VERSION_DMA).to_le_bytes().to_vec(),
+        );
+
+        // The file directory is sorted by name, like QEMU does, and its
+        // fields are big endian.
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut dir = (files.len() as u32).to_be_bytes().to_vec();
+        for (i, file) in files.into_iter().enumerate() {
+            let select = FW_CFG_FILE_FIRST + i as u16;
+            let mut name = [0u8; FW_CFG_MAX_FILE_NAME];
+            let len = min(file.name.len(), FW_CFG_MAX_FILE_NAME - 1);
+            name[..len].copy_from_slice(&file.name.as_bytes()[..len]);
+
+            dir.extend_from_slice(&(file.data.len() as u32).to_be_bytes());
+            dir.extend_from_slice(&select.to_be_bytes());
+            dir.extend_from_slice(&[0u8; 2]);
+            dir.extend_from_slice(&name);
+            items.insert(select, file.data);
+        }
+        items.insert(FW_CFG_FILE_DIR, dir);
+
+        FwCfg {
+            items,
+            selector: FW_CFG_SIGNATURE,
+            offset: 0,
+            dma_address_high: 0,
+            mem,
+        }
+    }
+
+    fn select(&mut self, selector: u16) {
+        self.selector = selector;
+        self.offset = 0;
+    }
+
+    fn item(&self) -> &[u8] {
+        self.items
+            .get(&self.selector)
+            .map(|item| item.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let value = self.item().get(self.offset).copied().unwrap_or(0);
+        self.offset = self.offset.saturating_add(1);
+        value
+    }
+
+    // Process the FWCfgDmaAccess structure found at the given guest address,
+    // and report its completion through its control field.
+    fn dma_transfer(&mut self, access_addr: GuestAddress) {
+        let mem = self.mem.memory();
+        let mut access = [0u8; 16];
+        if let Err(e) = mem.read_slice(&mut access, access_addr) {
+            warn!(
+                "Invalid fw_cfg DMA access address {:#x}: {}",
+                access_addr.0, e
+            );
+            return;
+        }
+        let control = u32::from_be_bytes(access[0..4].try_into().unwrap());
+        let length = u32::from_be_bytes(access[4..8].try_into().unwrap()) as usize;
+        let address = GuestAddress(u64::from_be_bytes(access[8..16].try_into().unwrap()));
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+
+        let mut status = 0;
+        if control & FW_CFG_DMA_CTL_WRITE != 0 {
+            // Items are read-only.
+            status = FW_CFG_DMA_CTL_ERROR;
+        } else if control & (FW_CFG_DMA_CTL_READ | FW_CFG_DMA_CTL_SKIP) != 0 {
+            if control & FW_CFG_DMA_CTL_READ != 0 {
+                let item = self.item();
+                let start = min(self.offset, item.len());
+                let end = min(self.offset.saturating_add(length), item.len());
+                let mut result = mem.write_slice(&item[start..end], address);
+
+                // Reading past the end of the item returns zeros.
+                let zeros = [0u8; 4096];
+                let mut done = end - start;
+                while result.is_ok() && done < length {
+                    let len = min(length - done, zeros.len());
+                    result = mem.write_slice(&zeros[..len], address.unchecked_add(done as u64));
+                    done += len;
+                }
+
+                if let Err(e) = result {
+                    warn!("Invalid fw_cfg DMA address {:#x}: {}", address.0, e);
+                    status = FW_CFG_DMA_CTL_ERROR;
+                }
+            }
+            self.offset = self.offset.saturating_add(length);
+        }
+
+        if let Err(e) = mem.write_slice(&status.to_be_bytes(), access_addr) {
+            warn!("Cannot complete fw_cfg DMA access: {}", e);
+        }
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            SELECTOR_OFFSET if data.len() == 2 => {
+                data.copy_from_slice(&self.selector.to_le_bytes())
+            }
+            DATA_OFFSET if data.len() == 1 => data[0] = self.read_data(),
+            DMA_ADDRESS_HIGH_OFFSET | DMA_ADDRESS_LOW_OFFSET if data.len() == 4 => {
+                let start = (offset - DMA_ADDRESS_HIGH_OFFSET) as usize;
+                data.copy_from_slice(&FW_CFG_DMA_SIGNATURE.to_be_bytes()[start..start + 4]);
+            }
+            _ => {
+                warn!(
+                    "Invalid fw_cfg read: offset {:#x}, size {}",
+                    offset,
+                    data.len()
+                );
+                data.fill(0);
+            }
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match offset {
+            SELECTOR_OFFSET if data.len() == 2 => {
+                self.select(u16::from_le_bytes(data.try_into().unwrap()))
+            }
+            DMA_ADDRESS_HIGH_OFFSET if data.len() == 4 => {
+                self.dma_address_high = u32::from_be_bytes(data.try_into().unwrap())
+            }
+            // Writing the low half of the address starts the transfer.
+            DMA_ADDRESS_LOW_OFFSET if data.len() == 4 => {
+                let low = u32::from_be_bytes(data.try_into().unwrap());
+                let access_addr = (u64::from(self.dma_address_high) << 32) | u64::from(low);
+                self.dma_address_high = 0;
+                self.dma_transfer(GuestAddress(access_addr));
+            }
+            _ => warn!(
+                "Invalid fw_cfg write: offset {:#x}, size {}",
+                offset,
+                data.len()
+            ),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fw_cfg() -> (FwCfg, GuestMemoryAtomic<GuestMemoryMmap>) {
+        let mem = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+        );
+        let files = vec![
+            FwCfgFile {
+                name: "opt/org.example/b".to_owned(),
+                data: b"second".to_vec(),
+            },
+            FwCfgFile {
+                name: "opt/org.example/a".to_owned(),
+                data: b"first".to_vec(),
+            },
+        ];
+        (FwCfg::new(files, mem.clone()), mem)
+    }
+
+    fn read_item(fw_cfg: &mut FwCfg, selector: u16, len: usize) -> Vec<u8> {
+        fw_cfg.write(0, SELECTOR_OFFSET, &selector.to_le_bytes());
+        (0..len)
+            .map(|_| {
+                let mut data = [0u8];
+                fw_cfg.read(0, DATA_OFFSET, &mut data);
+                data[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_traditional_interface() {
+        let (mut fw_cfg, _) = fw_cfg();
+
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_SIGNATURE, 4), b"QEMU");
+        assert_eq!(
+            read_item(&mut fw_cfg, FW_CFG_ID, 4),
+            (FW_CFG_VERSION | FW_CFG_VERSION_DMA).to_le_bytes()
+        );
+
+        let dir = read_item(&mut fw_cfg, FW_CFG_FILE_DIR, 4 + 2 * 64);
+        assert_eq!(dir[0..4], 2u32.to_be_bytes());
+        assert_eq!(dir[4..8], 5u32.to_be_bytes());
+        assert_eq!(dir[8..10], FW_CFG_FILE_FIRST.to_be_bytes());
+        assert_eq!(&dir[12..29], b"opt/org.example/a");
+        assert_eq!(dir[29], 0);
+        assert_eq!(dir[68..70], (FW_CFG_FILE_FIRST + 1).to_be_bytes());
+
+        // Reading past the end of an item returns zeros.
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_FILE_FIRST, 7), b"first\0\0");
+    }
+
+    #[test]
+    fn test_dma_interface() {
+        let (mut fw_cfg, mem) = fw_cfg();
+        let access_addr = GuestAddress(0x1000);
+        let buffer_addr = GuestAddress(0x2000);
+
+        let mut dma_signature = [0u8; 8];
+        fw_cfg.read(0, DMA_ADDRESS_HIGH_OFFSET, &mut dma_signature[0..4]);
+        fw_cfg.read(0, DMA_ADDRESS_LOW_OFFSET, &mut dma_signature[4..8]);
+        assert_eq!(&dma_signature, b"QEMU CFG");
+
+        let control =
+            (u32::from(FW_CFG_FILE_FIRST + 1) << 16) | FW_CFG_DMA_CTL_SELECT | FW_CFG_DMA_CTL_READ;
+        let mut access = Vec::new();
+        access.extend_from_slice(&control.to_be_bytes());
+        access.extend_from_slice(&6u32.to_be_bytes());
+        access.extend_from_slice(&buffer_addr.0.to_be_bytes());
+        mem.memory().write_slice(&access, access_addr).unwrap();
+
+        fw_cfg.write(0, DMA_ADDRESS_HIGH_OFFSET, &0u32.to_be_bytes());
+        fw_cfg.write(
+            0,
+            DMA_ADDRESS_LOW_OFFSET,
+            &(access_addr.0 as u32).to_be_bytes(),
+        );
+
+        let mut data = [0u8; 6];
+        mem.memory().read_slice(&mut data, buffer_addr).unwrap();
+        assert_eq!(&data, b"second");
+        let status: u32 = mem.memory().read_obj(access_addr).unwrap();
+        assert_eq!(status, 0);
+
+        // Writes are rejected.
+        mem.memory()
+            .write_slice(&FW_CFG_DMA_CTL_WRITE.to_be_bytes(), access_addr)
+            .unwrap();
+        fw_cfg.write(
+            0,
+            DMA_ADDRESS_LOW_OFFSET,
+            &(access_addr.0 as u32).to_be_bytes(),
+        );
+        let status: u32 = mem.memory().read_obj(access_addr).unwrap();
+        assert_eq!(u32::from_be(status), FW_CFG_DMA_CTL_ERROR);
+    }
+}
@@ -9,6 +9,8 @@ mod cmos;
 #[cfg(target_arch = "x86_64")]
 mod debug_port;
 #[cfg(target_arch = "x86_64")]
+mod fw_cfg;
+#[cfg(target_arch = "x86_64")]
 mod fwdebug;
 #[cfg(target_arch = "aarch64")]
 mod gpio_pl061;
@@ -19,10 +21,14 @@ mod serial;
 #[cfg(target_arch = "aarch64")]
 mod uart_pl011;
 
-pub use self::cmos::Cmos;
+pub use self::cmos::{Cmos, CmosState};
 #[cfg(target_arch = "x86_64")]
 pub use self::debug_port::DebugPort;
 #[cfg(target_arch = "x86_64")]
+pub use self::fw_cfg::{
+    FwCfg, FwCfgFile, FW_CFG_MAX_FILE_NAME, FW_CFG_PORT_BASE, FW_CFG_PORT_SIZE,
+};
+#[cfg(target_arch = "x86_64")]
 pub use self::fwdebug::FwDebugDevice;
 pub use self::i8042::I8042Device;
 pub use self::serial::Serial;
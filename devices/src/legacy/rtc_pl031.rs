@@ -111,12 +111,13 @@ pub struct Rtc {
 }
 
 impl Rtc {
-    /// Constructs an AMBA PL031 RTC device.
-    pub fn new(interrupt: Arc<dyn InterruptSourceGroup>) -> Self {
+    /// Constructs an AMBA PL031 RTC device, `offset_ns` away from the host
+    /// wall clock.
+    pub fn new(interrupt: Arc<dyn InterruptSourceGroup>, offset_ns: i64) -> Self {
         Self {
             // This is used only for duration measuring purposes.
             previous_now: Instant::now(),
-            tick_offset: get_time(ClockType::Real) as i64,
+            tick_offset: get_time(ClockType::Real) as i64 + offset_ns,
             match_value: 0,
             load: 0,
             imsc: 0,
@@ -401,7 +402,10 @@ mod tests {
     fn test_rtc_read_write_and_event() {
         let intr_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
 
-        let mut rtc = Rtc::new(Arc::new(TestInterrupt::new(intr_evt.try_clone().unwrap())));
+        let mut rtc = Rtc::new(
+            Arc::new(TestInterrupt::new(intr_evt.try_clone().unwrap())),
+            0,
+        );
         let mut data = [0; 4];
 
         // Read and write to the MR register.
@@ -13,8 +13,11 @@ extern crate bitflags;
 extern crate event_monitor;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate vmm_sys_util;
 
 pub mod acpi;
+pub mod cxl;
 #[cfg(target_arch = "x86_64")]
 pub mod debug_console;
 #[cfg(target_arch = "aarch64")]
@@ -22,14 +25,21 @@ pub mod gic;
 pub mod interrupt_controller;
 #[cfg(target_arch = "x86_64")]
 pub mod ioapic;
+pub mod ivshmem;
 pub mod legacy;
+pub mod nvdimm;
+pub mod nvme;
 #[cfg(feature = "pvmemcontrol")]
 pub mod pvmemcontrol;
 pub mod pvpanic;
 pub mod tpm;
+pub mod usb;
+pub mod vmclock;
 
 pub use self::acpi::{AcpiGedDevice, AcpiPmTimerDevice, AcpiShutdownDevice};
+pub use self::nvdimm::{Nvdimm, NvdimmDevice, NvdimmLabelArea};
 pub use self::pvpanic::{PvPanicDevice, PVPANIC_DEVICE_MMIO_SIZE};
+pub use self::vmclock::VmclockDevice;
 
 bitflags! {
     pub struct AcpiNotificationFlags: u8 {
@@ -0,0 +1,539 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! ACPI NVDIMM support.
+//!
+//! The persistent memory ranges are described to the guest through the NFIT
+//! table, while this device exposes the namespace label storage areas through
+//! the ACPI 6.2 `_LSI`, `_LSR` and `_LSW` methods of each NVDIMM. The AML code
+//! relays the label accesses to the VMM through a small MMIO window, made of a
+//! few registers followed by a data buffer.
+
+use acpi_tables::{aml, Aml, AmlSink};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+use vm_memory::{GuestAddress, MmapRegion};
+
+pub const NVDIMM_DEVICE_MMIO_SIZE: u64 = 0x2000;
+
+/// Smallest label storage area, able to hold the two index blocks and the
+/// labels of 256 namespaces.
+pub const NVDIMM_LABEL_MIN_SIZE: u64 = 128 << 10;
+
+// Largest label transfer performed by a single _LSR or _LSW call
+const LABEL_MAX_TRANSFER: usize = 0x1000;
+
+// Registers
+const SELECTOR_OFFSET: u64 = 0x0;
+const LABEL_OFFSET_OFFSET: u64 = 0x4;
+const LENGTH_OFFSET: u64 = 0x8;
+const COMMAND_OFFSET: u64 = 0xc;
+const STATUS_OFFSET: u64 = 0x10;
+const DATA_OFFSET: u64 = 0x1000;
+
+const COMMAND_READ: u32 = 1;
+const COMMAND_WRITE: u32 = 2;
+
+// Status codes returned by _LSR and _LSW (ACPI 6.2, section 6.5.10)
+const STATUS_SUCCESS: u32 = 0;
+const STATUS_FAILURE: u32 = 1;
+const STATUS_INVALID_INPUT: u32 = 2;
+
+/// Namespace label storage area of an NVDIMM, located in its backing file.
+pub struct NvdimmLabelArea {
+    pub file: File,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Persistent memory range exposed to the guest as an NVDIMM.
+pub struct Nvdimm {
+    pub base: GuestAddress,
+    pub size: u64,
+    pub label_area: Option<NvdimmLabelArea>,
+    // Host mapping backing the guest range
+    pub mmap_region: MmapRegion,
+}
+
+impl Nvdimm {
+    /// NFIT device handle of the NVDIMM at the given index.
+    pub fn handle(index: usize) -> u32 {
+        index as u32 + 1
+    }
+}
+
+/// A device giving the guest access to the NVDIMM label storage areas
+pub struct NvdimmDevice {
+    address: GuestAddress,
+    nvdimms: Vec<Nvdimm>,
+    selector: u32,
+    label_offset: u32,
+    length: u32,
+    status: u32,
+    data: Vec<u8>,
+}
+
+impl NvdimmDevice {
+    pub fn new(address: GuestAddress, nvdimms: Vec<Nvdimm>) -> Self {
+        NvdimmDevice {
+            address,
+            nvdimms,
+            selector: 0,
+            label_offset: 0,
+            length: 0,
+            status: STATUS_SUCCESS,
+            data: vec![0; LABEL_MAX_TRANSFER],
+        }
+    }
+
+    pub fn nvdimms(&self) -> &[Nvdimm] {
+        &self.nvdimms
+    }
+
+    fn run_command(&mut self, command: u32) -> u32 {
+        let Some(label_area) = self
+            .nvdimms
+            .get(self.selector as usize)
+            .and_then(|nvdimm| nvdimm.label_area.as_ref())
+        else {
+            return STATUS_INVALID_INPUT;
+        };
+
+        let offset = self.label_offset as u64;
+        let length = self.length as usize;
+        if length > LABEL_MAX_TRANSFER || offset + length as u64 > label_area.size {
+            return STATUS_INVALID_INPUT;
+        }
+
+        let data = &mut self.data[..length];
+        let result = match command {
+            COMMAND_READ => label_area
+                .file
+                .read_exact_at(data, label_area.offset + offset),
+            COMMAND_WRITE => label_area
+                .file
+                .write_all_at(data, label_area.offset + offset),
+            _ => return STATUS_INVALID_INPUT,
+        };
+
+        match result {
+            Ok(()) => STATUS_SUCCESS,
+            Err(e) => {
+                error!("Failed accessing NVDIMM {} labels: {}", self.selector, e);
+                STATUS_FAILURE
+            }
+        }
+    }
+}
+
+impl BusDevice for NvdimmDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= DATA_OFFSET {
+            let start = (offset - DATA_OFFSET) as usize;
+            if let Some(src) = self.data.get(start..start + data.len()) {
+                data.copy_from_slice(src);
+                return;
+            }
+        } else if data.len() == 4 {
+            let value = match offset {
+                SELECTOR_OFFSET => self.selector,
+                LABEL_OFFSET_OFFSET => self.label_offset,
+                LENGTH_OFFSET => self.length,
+                STATUS_OFFSET => self.status,
+                _ => 0,
+            };
+            data.copy_from_slice(&value.to_le_bytes());
+            return;
+        }
+
+        warn!(
+            "Invalid NVDIMM device read: offset 0x{:x} length {}",
+            offset,
+            data.len()
+        );
+        data.fill(0);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset >= DATA_OFFSET {
+            let start = (offset - DATA_OFFSET) as usize;
+            if let Some(dst) = self.data.get_mut(start..start + data.len()) {
+                dst.copy_from_slice(data);
+                return None;
+            }
+        } else if data.len() == 4 {
+            let value = u32::from_le_bytes(data.try_into().unwrap());
+            match offset {
+                SELECTOR_OFFSET => self.selector = value,
+                LABEL_OFFSET_OFFSET => self.label_offset = value,
+                LENGTH_OFFSET => self.length = value,
+                COMMAND_OFFSET => self.status = self.run_command(value),
+                _ => {}
+            }
+            return None;
+        }
+
+        warn!(
+            "Invalid NVDIMM device write: offset 0x{:x} length {}",
+            offset,
+            data.len()
+        );
+        None
+    }
+}
+
+/// Mid(Source, Index, Length, Target)
+struct Mid<'a> {
+    source: &'a dyn Aml,
+    index: &'a dyn Aml,
+    length: &'a dyn Aml,
+    target: &'a dyn Aml,
+}
+
+impl Aml for Mid<'_> {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        sink.byte(0x9e); /* MidOp */
+        self.source.to_aml_bytes(sink);
+        self.index.to_aml_bytes(sink);
+        self.length.to_aml_bytes(sink);
+        self.target.to_aml_bytes(sink);
+    }
+}
+
+/// Index(Source, Index), used as a reference to a package element
+struct Index<'a> {
+    source: &'a dyn Aml,
+    index: &'a dyn Aml,
+}
+
+impl Aml for Index<'_> {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        sink.byte(0x88); /* IndexOp */
+        self.source.to_aml_bytes(sink);
+        self.index.to_aml_bytes(sink);
+        sink.byte(0x00); /* NullName */
+    }
+}
+
+struct NvdimmSlot<'a> {
+    index: usize,
+    nvdimm: &'a Nvdimm,
+}
+
+impl Aml for NvdimmSlot<'_> {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        let index = self.index as u32;
+        let handle = Nvdimm::handle(self.index);
+        let Some(label_area) = self.nvdimm.label_area.as_ref() else {
+            // Without any label storage area, the guest uses the whole
+            // range as a single namespace.
+            aml::Device::new(
+                format!("NV{:02X}", self.index).as_str().into(),
+                vec![&aml::Name::new("_ADR".into(), &handle)],
+            )
+            .to_aml_bytes(sink);
+            return;
+        };
+
+        aml::Device::new(
+            format!("NV{:02X}", self.index).as_str().into(),
+            vec![
+                &aml::Name::new("_ADR".into(), &handle),
+                // Label Storage Information: status, size and max transfer
+                &aml::Method::new(
+                    "_LSI".into(),
+                    0,
+                    false,
+                    vec![&aml::Return::new(&aml::Package::new(vec![
+                        &aml::ZERO,
+                        &(label_area.size as u32),
+                        &(LABEL_MAX_TRANSFER as u32),
+                    ]))],
+                ),
+                // Label Storage Read: offset and length
+                &aml::Method::new(
+                    "_LSR".into(),
+                    2,
+                    false,
+                    vec![&aml::Return::new(&aml::MethodCall::new(
+                        "\\_SB_.NVDR.NLSR".into(),
+                        vec![&index, &aml::Arg(0), &aml::Arg(1)],
+                    ))],
+                ),
+                // Label Storage Write: offset, length and data
+                &aml::Method::new(
+                    "_LSW".into(),
+                    3,
+                    false,
+                    vec![&aml::Return::new(&aml::MethodCall::new(
+                        "\\_SB_.NVDR.NLSW".into(),
+                        vec![&index, &aml::Arg(0), &aml::Arg(1), &aml::Arg(2)],
+                    ))],
+                ),
+            ],
+        )
+        .to_aml_bytes(sink)
+    }
+}
+
+struct NvdimmSlots<'a> {
+    nvdimms: &'a [Nvdimm],
+}
+
+impl Aml for NvdimmSlots<'_> {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        for (index, nvdimm) in self.nvdimms.iter().enumerate() {
+            NvdimmSlot { index, nvdimm }.to_aml_bytes(sink);
+        }
+    }
+}
+
+impl Aml for NvdimmDevice {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        aml::Device::new(
+            "_SB_.NVDR".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ACPI0012"),
+                &aml::OpRegion::new(
+                    "NVLR".into(),
+                    aml::OpRegionSpace::SystemMemory,
+                    &(self.address.0 as usize),
+                    &(NVDIMM_DEVICE_MMIO_SIZE as usize),
+                ),
+                &aml::Field::new(
+                    "NVLR".into(),
+                    aml::FieldAccessType::DWord,
+                    aml::FieldLockRule::NoLock,
+                    aml::FieldUpdateRule::WriteAsZeroes,
+                    vec![
+                        aml::FieldEntry::Named(*b"NSEL", 32),
+                        aml::FieldEntry::Named(*b"NOFF", 32),
+                        aml::FieldEntry::Named(*b"NLEN", 32),
+                        aml::FieldEntry::Named(*b"NCMD", 32),
+                        aml::FieldEntry::Named(*b"NSTA", 32),
+                        aml::FieldEntry::Reserved((DATA_OFFSET as usize - 0x14) * 8),
+                        aml::FieldEntry::Named(*b"NDAT", LABEL_MAX_TRANSFER * 8),
+                    ],
+                ),
+                &aml::Mutex::new("NLCK".into(), 0),
+                // Read labels: NVDIMM index, offset and length
+                &aml::Method::new(
+                    "NLSR".into(),
+                    3,
+                    true,
+                    vec![
+                        &aml::Acquire::new("NLCK".into(), 0xffff),
+                        &aml::Store::new(&aml::Path::new("NSEL"), &aml::Arg(0)),
+                        &aml::Store::new(&aml::Path::new("NOFF"), &aml::Arg(1)),
+                        &aml::Store::new(&aml::Path::new("NLEN"), &aml::Arg(2)),
+                        &aml::Store::new(&aml::Path::new("NCMD"), &COMMAND_READ),
+                        &aml::Store::new(&aml::Local(0), &aml::Path::new("NSTA")),
+                        &aml::Store::new(&aml::Local(1), &aml::Path::new("NDAT")),
+                        &aml::Release::new("NLCK".into()),
+                        // Only return the requested amount of data
+                        &Mid {
+                            source: &aml::Local(1),
+                            index: &aml::ZERO,
+                            length: &aml::Arg(2),
+                            target: &aml::Local(2),
+                        },
+                        &aml::Store::new(
+                            &aml::Local(3),
+                            &aml::Package::new(vec![&aml::ZERO, &aml::ZERO]),
+                        ),
+                        &aml::Store::new(
+                            &Index {
+                                source: &aml::Local(3),
+                                index: &aml::ZERO,
+                            },
+                            &aml::Local(0),
+                        ),
+                        &aml::Store::new(
+                            &Index {
+                                source: &aml::Local(3),
+                                index: &aml::ONE,
+                            },
+                            &aml::Local(2),
+                        ),
+                        &aml::Return::new(&aml::Local(3)),
+                    ],
+                ),
+                // Write labels: NVDIMM index, offset, length and data
+                &aml::Method::new(
+                    "NLSW".into(),
+                    4,
+                    true,
+                    vec![
+                        &aml::Acquire::new("NLCK".into(), 0xffff),
+                        &aml::Store::new(&aml::Path::new("NSEL"), &aml::Arg(0)),
+                        &aml::Store::new(&aml::Path::new("NOFF"), &aml::Arg(1)),
+                        &aml::Store::new(&aml::Path::new("NLEN"), &aml::Arg(2)),
+                        &aml::Store::new(&aml::Path::new("NDAT"), &aml::Arg(3)),
+                        &aml::Store::new(&aml::Path::new("NCMD"), &COMMAND_WRITE),
+                        &aml::Store::new(&aml::Local(0), &aml::Path::new("NSTA")),
+                        &aml::Release::new("NLCK".into()),
+                        &aml::Return::new(&aml::Local(0)),
+                    ],
+                ),
+                &NvdimmSlots {
+                    nvdimms: &self.nvdimms,
+                },
+            ],
+        )
+        .to_aml_bytes(sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const LABEL_AREA_OFFSET: u64 = 0x1000;
+    const LABEL_AREA_SIZE: u64 = NVDIMM_LABEL_MIN_SIZE;
+
+    fn nvdimm(label_area: bool) -> Nvdimm {
+        let label_area = label_area.then(|| {
+            let file = TempFile::new().unwrap().into_file();
+            file.set_len(LABEL_AREA_OFFSET + LABEL_AREA_SIZE).unwrap();
+            NvdimmLabelArea {
+                file,
+                offset: LABEL_AREA_OFFSET,
+                size: LABEL_AREA_SIZE,
+            }
+        });
+        Nvdimm {
+            base: GuestAddress(0x1_0000_0000),
+            size: 0x20_0000,
+            label_area,
+            mmap_region: MmapRegion::new(0x20_0000).unwrap(),
+        }
+    }
+
+    fn write_reg(device: &mut NvdimmDevice, offset: u64, value: u32) {
+        device.write(0, offset, &value.to_le_bytes());
+    }
+
+    fn read_reg(device: &mut NvdimmDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn run(
+        device: &mut NvdimmDevice,
+        selector: u32,
+        offset: u32,
+        length: u32,
+        command: u32,
+    ) -> u32 {
+        write_reg(device, SELECTOR_OFFSET, selector);
+        write_reg(device, LABEL_OFFSET_OFFSET, offset);
+        write_reg(device, LENGTH_OFFSET, length);
+        write_reg(device, COMMAND_OFFSET, command);
+        read_reg(device, STATUS_OFFSET)
+    }
+
+    #[test]
+    fn test_label_read_write() {
+        let mut device = NvdimmDevice::new(GuestAddress(0), vec![nvdimm(true)]);
+
+        device.write(0, DATA_OFFSET, &[0xaa; 16]);
+        assert_eq!(
+            run(&mut device, 0, 0x100, 16, COMMAND_WRITE),
+            STATUS_SUCCESS
+        );
+
+        // The labels are stored at the offset of the area in the file.
+        let mut buf = [0u8; 16];
+        device.nvdimms()[0]
+            .label_area
+            .as_ref()
+            .unwrap()
+            .file
+            .read_exact_at(&mut buf, LABEL_AREA_OFFSET + 0x100)
+            .unwrap();
+        assert_eq!(buf, [0xaa; 16]);
+
+        device.write(0, DATA_OFFSET, &[0u8; 16]);
+        assert_eq!(run(&mut device, 0, 0x100, 16, COMMAND_READ), STATUS_SUCCESS);
+        let mut data = [0u8; 16];
+        device.read(0, DATA_OFFSET, &mut data);
+        assert_eq!(data, [0xaa; 16]);
+
+        assert_eq!(read_reg(&mut device, SELECTOR_OFFSET), 0);
+        assert_eq!(read_reg(&mut device, LABEL_OFFSET_OFFSET), 0x100);
+        assert_eq!(read_reg(&mut device, LENGTH_OFFSET), 16);
+    }
+
+    #[test]
+    fn test_label_area_bounds() {
+        let mut device = NvdimmDevice::new(GuestAddress(0), vec![nvdimm(true), nvdimm(false)]);
+        let end = LABEL_AREA_SIZE as u32;
+        let max = LABEL_MAX_TRANSFER as u32;
+
+        // Accesses up to the end of the area are allowed.
+        assert_eq!(
+            run(&mut device, 0, end - max, max, COMMAND_READ),
+            STATUS_SUCCESS
+        );
+        assert_eq!(run(&mut device, 0, end, 0, COMMAND_READ), STATUS_SUCCESS);
+
+        // Going past the end of the area, or transferring more than the data
+        // buffer holds, is rejected.
+        assert_eq!(
+            run(&mut device, 0, end - max + 1, max, COMMAND_READ),
+            STATUS_INVALID_INPUT
+        );
+        assert_eq!(
+            run(&mut device, 0, end, 1, COMMAND_WRITE),
+            STATUS_INVALID_INPUT
+        );
+        assert_eq!(
+            run(&mut device, 0, u32::MAX, 1, COMMAND_READ),
+            STATUS_INVALID_INPUT
+        );
+        assert_eq!(
+            run(&mut device, 0, 0, max + 1, COMMAND_READ),
+            STATUS_INVALID_INPUT
+        );
+
+        // NVDIMMs without label area, unknown NVDIMMs and unknown commands.
+        assert_eq!(
+            run(&mut device, 1, 0, 16, COMMAND_READ),
+            STATUS_INVALID_INPUT
+        );
+        assert_eq!(
+            run(&mut device, 2, 0, 16, COMMAND_READ),
+            STATUS_INVALID_INPUT
+        );
+        assert_eq!(run(&mut device, 0, 0, 16, 3), STATUS_INVALID_INPUT);
+    }
+
+    #[test]
+    fn test_data_buffer_bounds() {
+        let mut device = NvdimmDevice::new(GuestAddress(0), vec![nvdimm(true)]);
+        let last = DATA_OFFSET + LABEL_MAX_TRANSFER as u64 - 4;
+
+        device.write(0, last, &[0x55; 4]);
+        let mut data = [0u8; 4];
+        device.read(0, last, &mut data);
+        assert_eq!(data, [0x55; 4]);
+
+        // Accesses crossing the end of the buffer are ignored.
+        device.write(0, last + 2, &[0xaa; 4]);
+        let mut data = [0xffu8; 4];
+        device.read(0, last + 2, &mut data);
+        assert_eq!(data, [0u8; 4]);
+        device.read(0, last, &mut data);
+        assert_eq!(data, [0x55; 4]);
+
+        // Registers are only accessed 32 bits at a time.
+        device.write(0, LENGTH_OFFSET, &[0x10; 2]);
+        assert_eq!(read_reg(&mut device, LENGTH_OFFSET), 0);
+    }
+}
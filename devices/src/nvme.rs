@@ -0,0 +1,2337 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulation of an NVMe controller, exposing disk images to the guest as
+//! namespaces.
+//!
+//! The controller implements the admin command set needed by the common
+//! drivers, and the Read, Write and Flush commands of the NVM command set.
+//! Queues must be physically contiguous and data is only described by PRPs.
+//! Doorbells are written by the vCPUs without taking the controller lock,
+//! commands being fetched and processed by a dedicated thread which also
+//! completes the asynchronous disk requests.
+
+use anyhow::anyhow;
+use block::async_io::{AsyncIo, DiskFile, DiskFileError};
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarPrefetchable,
+    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciMassStorageSubclass, PciProgrammingInterface,
+};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use thiserror::Error;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_device::{BusDevice, Resource};
+use vm_memory::bitmap::{AtomicBitmap, Bitmap};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    GuestMemoryError,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+const NVME_VENDOR_ID: u16 = 0x1b36;
+const NVME_DEVICE_ID: u16 = 0x0010;
+
+/// Maximum number of I/O queue pairs of a controller.
+pub const NVME_MAX_IO_QUEUES: u16 = 64;
+
+const NVME_BAR_INDEX: usize = 0;
+const NVME_BAR_SIZE: u64 = 0x4000;
+const MSIX_TABLE_OFFSET: u64 = 0x2000;
+const MSIX_PBA_OFFSET: u64 = 0x3000;
+const MSIX_REGION_SIZE: u64 = 0x1000;
+
+const PAGE_SIZE: u64 = 0x1000;
+const MAX_QUEUE_ENTRIES: u16 = 256;
+// Maximum data transfer size, as a power of two number of pages.
+const MDTS: u8 = 7;
+const MAX_TRANSFER_SIZE: usize = (PAGE_SIZE as usize) << MDTS;
+const LBA_SHIFT: u64 = 9;
+const SQ_ENTRY_SIZE: u64 = 64;
+const CQ_ENTRY_SIZE: u64 = 16;
+const SQES: u32 = 6;
+const CQES: u32 = 4;
+const AER_LIMIT: usize = 4;
+// Alignment required by the disk backends opened with O_DIRECT.
+const DIRECT_IO_ALIGNMENT: usize = 512;
+const BOUNCE_BUFFER_ALIGNMENT: usize = 0x1000;
+const EPOLL_EVENTS_LEN: usize = 16;
+const KILL_EVENT: u64 = u64::MAX;
+const DOORBELL_EVENT: u64 = u64::MAX - 1;
+
+// Controller registers.
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_INTMS: u64 = 0x0c;
+const REG_INTMC: u64 = 0x10;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+const DOORBELL_OFFSET: u64 = 0x1000;
+
+const VERSION: u32 = 0x0001_0400;
+// Contiguous queues required, 7.5 s timeout and NVM command set. Only 4 KiB
+// memory pages are supported.
+const CAP: u64 = (MAX_QUEUE_ENTRIES as u64 - 1) | (1 << 16) | (0xf << 24) | (1 << 37);
+
+const CC_EN: u32 = 1 << 0;
+const CC_CSS_SHIFT: u32 = 4;
+const CC_CSS_MASK: u32 = 0x7 << CC_CSS_SHIFT;
+const CC_MPS_SHIFT: u32 = 7;
+const CC_MPS_MASK: u32 = 0xf << CC_MPS_SHIFT;
+const CC_SHN_MASK: u32 = 0x3 << 14;
+const CC_IOSQES_SHIFT: u32 = 16;
+const CC_IOCQES_SHIFT: u32 = 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_MASK: u32 = 0x3 << 2;
+const CSTS_SHST_PROCESSING: u32 = 0x1 << 2;
+const CSTS_SHST_COMPLETE: u32 = 0x2 << 2;
+
+// Admin commands.
+const ADMIN_DELETE_SQ: u8 = 0x00;
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_DELETE_CQ: u8 = 0x04;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+const ADMIN_GET_FEATURES: u8 = 0x0a;
+const ADMIN_ASYNC_EVENT_REQUEST: u8 = 0x0c;
+
+// NVM commands.
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+// Identify CNS values.
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+const CNS_NAMESPACE_DESCRIPTORS: u32 = 0x03;
+const IDENTIFY_SIZE: usize = 0x1000;
+
+// Log pages.
+const LOG_ERROR_INFORMATION: u32 = 0x01;
+const LOG_SMART_HEALTH: u32 = 0x02;
+const LOG_FIRMWARE_SLOT: u32 = 0x03;
+
+// Features.
+const FEAT_ARBITRATION: u8 = 0x01;
+const FEAT_POWER_MANAGEMENT: u8 = 0x02;
+const FEAT_TEMPERATURE_THRESHOLD: u8 = 0x04;
+const FEAT_ERROR_RECOVERY: u8 = 0x05;
+const FEAT_VOLATILE_WRITE_CACHE: u8 = 0x06;
+const FEAT_NUMBER_OF_QUEUES: u8 = 0x07;
+const FEAT_INTERRUPT_COALESCING: u8 = 0x08;
+const FEAT_INTERRUPT_VECTOR_CONFIG: u8 = 0x09;
+const FEAT_WRITE_ATOMICITY: u8 = 0x0a;
+const FEAT_ASYNC_EVENT_CONFIG: u8 = 0x0b;
+const FEAT_SAVE: u32 = 1 << 31;
+const FEAT_SELECT_SUPPORTED: u32 = 3;
+const FEAT_CHANGEABLE: u32 = 1 << 2;
+
+// Temperatures, in Kelvin.
+const TEMPERATURE: u16 = 310;
+const WARNING_TEMPERATURE: u16 = 343;
+const CRITICAL_TEMPERATURE: u16 = 373;
+
+// Status codes, along with their type and Do Not Retry bit.
+const STATUS_DNR: u16 = 1 << 14;
+const SCT_COMMAND_SPECIFIC: u16 = 1 << 8;
+const SCT_MEDIA_ERROR: u16 = 2 << 8;
+const STATUS_SUCCESS: u16 = 0x00;
+const STATUS_INVALID_OPCODE: u16 = 0x01 | STATUS_DNR;
+const STATUS_INVALID_FIELD: u16 = 0x02 | STATUS_DNR;
+const STATUS_DATA_TRANSFER_ERROR: u16 = 0x04;
+const STATUS_INVALID_NAMESPACE: u16 = 0x0b | STATUS_DNR;
+const STATUS_PRP_OFFSET_INVALID: u16 = 0x13 | STATUS_DNR;
+const STATUS_NAMESPACE_WRITE_PROTECTED: u16 = 0x20 | STATUS_DNR;
+const STATUS_LBA_OUT_OF_RANGE: u16 = 0x80 | STATUS_DNR;
+const STATUS_INVALID_CQ: u16 = SCT_COMMAND_SPECIFIC | STATUS_DNR;
+const STATUS_INVALID_QUEUE_ID: u16 = SCT_COMMAND_SPECIFIC | 0x01 | STATUS_DNR;
+const STATUS_INVALID_QUEUE_SIZE: u16 = SCT_COMMAND_SPECIFIC | 0x02 | STATUS_DNR;
+const STATUS_AER_LIMIT_EXCEEDED: u16 = SCT_COMMAND_SPECIFIC | 0x05 | STATUS_DNR;
+const STATUS_INVALID_VECTOR: u16 = SCT_COMMAND_SPECIFIC | 0x08 | STATUS_DNR;
+const STATUS_INVALID_LOG_PAGE: u16 = SCT_COMMAND_SPECIFIC | 0x09 | STATUS_DNR;
+const STATUS_INVALID_QUEUE_DELETION: u16 = SCT_COMMAND_SPECIFIC | 0x0c | STATUS_DNR;
+const STATUS_FEATURE_NOT_SAVEABLE: u16 = SCT_COMMAND_SPECIFIC | 0x0d | STATUS_DNR;
+const STATUS_WRITE_FAULT: u16 = SCT_MEDIA_ERROR | 0x80;
+const STATUS_UNRECOVERED_READ_ERROR: u16 = SCT_MEDIA_ERROR | 0x81;
+
+const MODEL_NUMBER: &str = "Cloud Hypervisor NVMe Ctrl";
+const FIRMWARE_REVISION: &str = "1.0";
+
+#[derive(Debug, Error)]
+pub enum NvmeError {
+    #[error("Failed creating NVMe controller: {0}")]
+    CreateNvmeController(#[source] anyhow::Error),
+    #[error("Failed opening NVMe namespace {0}: {1}")]
+    OpenNamespace(String, #[source] DiskFileError),
+}
+
+#[derive(Copy, Clone)]
+enum NvmeProgrammingInterface {
+    Nvme = 0x02,
+}
+
+impl PciProgrammingInterface for NvmeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// Disk image exposed as a namespace of an NVMe controller.
+pub struct NvmeNamespace {
+    pub id: String,
+    pub disk: Box<dyn DiskFile>,
+    pub readonly: bool,
+}
+
+struct Namespace {
+    id: String,
+    // Kept for as long as the asynchronous I/O may use it.
+    _disk: Box<dyn DiskFile>,
+    io: Box<dyn AsyncIo>,
+    num_blocks: u64,
+    readonly: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Command {
+    opcode: u8,
+    psdt: u8,
+    cid: u16,
+    nsid: u32,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+}
+
+impl Command {
+    fn read(mem: &GuestMemoryMmap, addr: u64) -> result::Result<Self, GuestMemoryError> {
+        let mut bytes = [0u8; SQ_ENTRY_SIZE as usize];
+        mem.read_slice(&mut bytes, GuestAddress(addr))?;
+        let dword = |i: usize| u32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        let qword = |i: usize| u64::from_le_bytes(bytes[4 * i..4 * i + 8].try_into().unwrap());
+        Ok(Command {
+            opcode: bytes[0],
+            psdt: bytes[1] >> 6,
+            cid: u16::from_le_bytes([bytes[2], bytes[3]]),
+            nsid: dword(1),
+            prp1: qword(6),
+            prp2: qword(8),
+            cdw10: dword(10),
+            cdw11: dword(11),
+            cdw12: dword(12),
+            cdw13: dword(13),
+        })
+    }
+
+    fn queue_id(&self) -> u16 {
+        self.cdw10 as u16
+    }
+
+    // Zero based queue size of the queue creation commands.
+    fn queue_size(&self) -> u16 {
+        (self.cdw10 >> 16) as u16
+    }
+}
+
+struct SubmissionQueue {
+    base: u64,
+    size: u16,
+    head: u16,
+    cqid: u16,
+    in_flight: usize,
+    // Identifier of the Delete I/O Submission Queue command waiting for the
+    // in-flight requests of the queue to complete.
+    deleting: Option<u16>,
+}
+
+struct Completion {
+    sqid: u16,
+    cid: u16,
+    status: u16,
+    result: u32,
+}
+
+struct CompletionQueue {
+    base: u64,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    vector: u16,
+    interrupts: bool,
+    // Completions waiting for the guest to free entries of the queue.
+    pending: VecDeque<Completion>,
+}
+
+impl CompletionQueue {
+    fn new(base: u64, size: u16, vector: u16, interrupts: bool) -> Self {
+        CompletionQueue {
+            base,
+            size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector,
+            interrupts,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+}
+
+// Buffer aligned for direct I/O, used when the guest buffers aren't.
+struct BounceBuffer {
+    data: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl BounceBuffer {
+    fn new(len: usize) -> Self {
+        let data = vec![0u8; len + BOUNCE_BUFFER_ALIGNMENT];
+        let offset = data.as_ptr().align_offset(BOUNCE_BUFFER_ALIGNMENT);
+        BounceBuffer { data, offset, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.len]
+    }
+}
+
+struct Request {
+    sqid: u16,
+    cid: u16,
+    opcode: u8,
+    blocks: u64,
+    // Bounce buffer to copy to the guest segments once read.
+    bounce: Option<(BounceBuffer, Vec<(GuestAddress, usize)>)>,
+}
+
+// Doorbell registers, written by the vCPUs without taking the controller
+// lock and consumed by the worker thread.
+struct Doorbells {
+    values: Vec<AtomicU32>,
+    evt: EventFd,
+}
+
+impl Doorbells {
+    fn sq_tail(&self, qid: u16) -> u16 {
+        self.values[2 * qid as usize].load(Ordering::Acquire) as u16
+    }
+
+    fn cq_head(&self, qid: u16) -> u16 {
+        self.values[2 * qid as usize + 1].load(Ordering::Acquire) as u16
+    }
+
+    fn ring(&self, index: usize, value: u32) {
+        self.values[index].store(value, Ordering::Release);
+        self.kick();
+    }
+
+    fn kick(&self) {
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed signalling NVMe doorbell: {}", e);
+        }
+    }
+
+    fn clear(&self) {
+        for value in self.values.iter() {
+            value.store(0, Ordering::Release);
+        }
+    }
+}
+
+// Guest memory segments described by the PRP entries of a command.
+fn prp_segments(
+    mem: &GuestMemoryMmap,
+    prp1: u64,
+    prp2: u64,
+    len: usize,
+) -> result::Result<Vec<(GuestAddress, usize)>, u16> {
+    let mut segments = Vec::new();
+    let offset = prp1 % PAGE_SIZE;
+    if offset & 0x3 != 0 {
+        return Err(STATUS_PRP_OFFSET_INVALID);
+    }
+    let first = len.min((PAGE_SIZE - offset) as usize);
+    segments.push((GuestAddress(prp1), first));
+    let mut remaining = len - first;
+    if remaining == 0 {
+        return Ok(segments);
+    }
+
+    // The second entry is either the next page or a list of pages.
+    if remaining <= PAGE_SIZE as usize {
+        if prp2 % PAGE_SIZE != 0 {
+            return Err(STATUS_PRP_OFFSET_INVALID);
+        }
+        segments.push((GuestAddress(prp2), remaining));
+        return Ok(segments);
+    }
+
+    let mut list = prp2;
+    if list & 0x7 != 0 {
+        return Err(STATUS_PRP_OFFSET_INVALID);
+    }
+    loop {
+        let entries = (PAGE_SIZE - list % PAGE_SIZE) / 8;
+        for i in 0..entries {
+            let entry: u64 = mem
+                .read_obj(GuestAddress(list + 8 * i))
+                .map_err(|_| STATUS_DATA_TRANSFER_ERROR)?;
+            // The last entry of a page points to the next page of the list
+            // when more than a page of data remains.
+            if i == entries - 1 && remaining > PAGE_SIZE as usize {
+                if entry & 0x7 != 0 {
+                    return Err(STATUS_PRP_OFFSET_INVALID);
+                }
+                list = entry;
+                break;
+            }
+            if entry % PAGE_SIZE != 0 {
+                return Err(STATUS_PRP_OFFSET_INVALID);
+            }
+            let size = remaining.min(PAGE_SIZE as usize);
+            segments.push((GuestAddress(entry), size));
+            remaining -= size;
+            if remaining == 0 {
+                return Ok(segments);
+            }
+        }
+    }
+}
+
+// Copy an ASCII string to a space padded field.
+fn copy_padded(field: &mut [u8], value: &str) {
+    field.fill(b' ');
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+fn default_features() -> BTreeMap<u8, u32> {
+    BTreeMap::from([
+        (FEAT_ARBITRATION, 0),
+        (FEAT_POWER_MANAGEMENT, 0),
+        (FEAT_TEMPERATURE_THRESHOLD, WARNING_TEMPERATURE as u32),
+        (FEAT_ERROR_RECOVERY, 0),
+        (FEAT_VOLATILE_WRITE_CACHE, 1),
+        (FEAT_INTERRUPT_COALESCING, 0),
+        (FEAT_WRITE_ATOMICITY, 0),
+        (FEAT_ASYNC_EVENT_CONFIG, 0),
+    ])
+}
+
+#[derive(Default)]
+struct Statistics {
+    blocks_read: u64,
+    blocks_written: u64,
+    read_commands: u64,
+    write_commands: u64,
+}
+
+struct Nvme {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt: Arc<dyn InterruptSourceGroup>,
+    doorbells: Arc<Doorbells>,
+    serial: String,
+    num_queues: u16,
+    namespaces: Vec<Namespace>,
+
+    cc: u32,
+    csts: u32,
+    intms: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+    features: BTreeMap<u8, u32>,
+    aer_cids: Vec<u16>,
+    in_flight: HashMap<u64, Request>,
+    next_user_data: u64,
+    // Set when the controller is disabled while requests are in flight, the
+    // reset completing with the last of them.
+    resetting: bool,
+    paused: bool,
+    statistics: Statistics,
+}
+
+impl Nvme {
+    fn new(
+        serial: String,
+        num_queues: u16,
+        namespaces: Vec<Namespace>,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        msix_config: Arc<Mutex<MsixConfig>>,
+        interrupt: Arc<dyn InterruptSourceGroup>,
+        doorbells: Arc<Doorbells>,
+    ) -> Self {
+        let mut sqs = Vec::new();
+        sqs.resize_with(num_queues as usize + 1, || None);
+        let mut cqs = Vec::new();
+        cqs.resize_with(num_queues as usize + 1, || None);
+
+        Nvme {
+            mem,
+            msix_config,
+            interrupt,
+            doorbells,
+            serial,
+            num_queues,
+            namespaces,
+            cc: 0,
+            csts: 0,
+            intms: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sqs,
+            cqs,
+            features: default_features(),
+            aer_cids: Vec::new(),
+            in_flight: HashMap::new(),
+            next_user_data: 0,
+            resetting: false,
+            paused: false,
+            statistics: Statistics::default(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.csts = 0;
+        self.sqs.iter_mut().for_each(|sq| *sq = None);
+        self.cqs.iter_mut().for_each(|cq| *cq = None);
+        self.features = default_features();
+        self.aer_cids.clear();
+        self.resetting = false;
+        self.doorbells.clear();
+    }
+
+    fn enabled(&self) -> bool {
+        self.csts & CSTS_RDY != 0 && !self.resetting
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            REG_CAP => CAP as u32,
+            o if o == REG_CAP + 4 => (CAP >> 32) as u32,
+            REG_VS => VERSION,
+            REG_INTMS | REG_INTMC => self.intms,
+            REG_CC => self.cc,
+            REG_CSTS => self.csts,
+            REG_AQA => self.aqa,
+            REG_ASQ => self.asq as u32,
+            o if o == REG_ASQ + 4 => (self.asq >> 32) as u32,
+            REG_ACQ => self.acq as u32,
+            o if o == REG_ACQ + 4 => (self.acq >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            REG_INTMS => self.intms |= value,
+            REG_INTMC => self.intms &= !value,
+            REG_CC => self.write_cc(value),
+            REG_AQA => self.aqa = value & 0x0fff_0fff,
+            REG_ASQ => self.asq = (self.asq & !0xffff_ffff) | value as u64,
+            o if o == REG_ASQ + 4 => self.asq = (self.asq & 0xffff_ffff) | (value as u64) << 32,
+            REG_ACQ => self.acq = (self.acq & !0xffff_ffff) | value as u64,
+            o if o == REG_ACQ + 4 => self.acq = (self.acq & 0xffff_ffff) | (value as u64) << 32,
+            o => debug!("Ignoring write to NVMe register 0x{:x}", o),
+        }
+    }
+
+    fn write_cc(&mut self, value: u32) {
+        let old = self.cc;
+        self.cc = value;
+
+        if old & CC_EN != 0 && value & CC_EN == 0 {
+            // The guest may reuse the buffers as soon as the controller is
+            // reported as disabled.
+            if self.in_flight.is_empty() {
+                self.reset();
+            } else {
+                self.resetting = true;
+            }
+            return;
+        }
+
+        if old & CC_EN == 0 && value & CC_EN != 0 {
+            self.enable();
+        }
+
+        // The namespaces are flushed from the worker thread once the
+        // in-flight requests are completed.
+        if old & CC_SHN_MASK == 0 && value & CC_SHN_MASK != 0 && self.csts & CSTS_RDY != 0 {
+            self.csts = (self.csts & !CSTS_SHST_MASK) | CSTS_SHST_PROCESSING;
+            self.doorbells.kick();
+        }
+    }
+
+    fn enable(&mut self) {
+        let asqs = (self.aqa & 0xfff) as u16 + 1;
+        let acqs = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+        if self.cc & CC_CSS_MASK != 0
+            || self.cc & CC_MPS_MASK != 0
+            || asqs < 2
+            || acqs < 2
+            || self.asq % PAGE_SIZE != 0
+            || self.acq % PAGE_SIZE != 0
+        {
+            error!(
+                "Invalid NVMe controller configuration: CC 0x{:x} AQA 0x{:x}",
+                self.cc, self.aqa
+            );
+            self.csts |= CSTS_CFS;
+            return;
+        }
+
+        self.doorbells.clear();
+        self.sqs[0] = Some(SubmissionQueue {
+            base: self.asq,
+            size: asqs,
+            head: 0,
+            cqid: 0,
+            in_flight: 0,
+            deleting: None,
+        });
+        self.cqs[0] = Some(CompletionQueue::new(self.acq, acqs, 0, true));
+        self.csts = CSTS_RDY;
+    }
+
+    // Called once there are no more requests in flight.
+    fn quiesced(&mut self) {
+        if self.resetting {
+            self.reset();
+        }
+
+        if self.csts & CSTS_SHST_MASK == CSTS_SHST_PROCESSING {
+            for namespace in self.namespaces.iter_mut() {
+                if let Err(e) = namespace.io.fsync(None) {
+                    error!("Failed flushing NVMe namespace {}: {}", namespace.id, e);
+                }
+            }
+            self.csts = (self.csts & !CSTS_SHST_MASK) | CSTS_SHST_COMPLETE;
+        }
+    }
+
+    fn signal_interrupt(&self, vector: u16) {
+        let mut msix_config = self.msix_config.lock().unwrap();
+        if !msix_config.enabled() {
+            return;
+        }
+        // The Pending Bit Array is updated rather than injecting the
+        // interrupt when the vector is masked.
+        if msix_config.masked() || msix_config.table_entries[vector as usize].masked() {
+            msix_config.set_pba_bit(vector, false);
+            return;
+        }
+        if let Err(e) = self.interrupt.trigger(vector as InterruptIndex) {
+            error!("Failed signalling NVMe interrupt: {}", e);
+        }
+    }
+
+    fn complete(&mut self, sqid: u16, cid: u16, status: u16, result: u32) {
+        let Some(cqid) = self.sqs[sqid as usize].as_ref().map(|sq| sq.cqid) else {
+            return;
+        };
+        if let Some(cq) = self.cqs[cqid as usize].as_mut() {
+            cq.pending.push_back(Completion {
+                sqid,
+                cid,
+                status,
+                result,
+            });
+            self.post_completions(cqid);
+        }
+    }
+
+    fn post_completions(&mut self, cqid: u16) {
+        let mem = self.mem.memory();
+        let Some(cq) = self.cqs[cqid as usize].as_mut() else {
+            return;
+        };
+
+        let mut posted = false;
+        while !cq.full() {
+            let Some(completion) = cq.pending.pop_front() else {
+                break;
+            };
+            let sq_head = self.sqs[completion.sqid as usize]
+                .as_ref()
+                .map(|sq| sq.head)
+                .unwrap_or_default();
+            let mut entry = [0u8; CQ_ENTRY_SIZE as usize];
+            entry[0..4].copy_from_slice(&completion.result.to_le_bytes());
+            entry[8..10].copy_from_slice(&sq_head.to_le_bytes());
+            entry[10..12].copy_from_slice(&completion.sqid.to_le_bytes());
+            entry[12..14].copy_from_slice(&completion.cid.to_le_bytes());
+            let status = (completion.status << 1) | cq.phase as u16;
+            entry[14..16].copy_from_slice(&status.to_le_bytes());
+
+            let addr = cq.base + cq.tail as u64 * CQ_ENTRY_SIZE;
+            if let Err(e) = mem.write_slice(&entry, GuestAddress(addr)) {
+                error!("Failed writing NVMe completion at 0x{:x}: {}", addr, e);
+                cq.pending.push_front(completion);
+                break;
+            }
+            cq.tail = (cq.tail + 1) % cq.size;
+            if cq.tail == 0 {
+                cq.phase = !cq.phase;
+            }
+            posted = true;
+        }
+
+        if posted && cq.interrupts {
+            let vector = cq.vector;
+            self.signal_interrupt(vector);
+        }
+    }
+
+    fn process_doorbells(&mut self) {
+        if !self.enabled() || self.paused {
+            return;
+        }
+
+        for cqid in 0..=self.num_queues {
+            let head = self.doorbells.cq_head(cqid);
+            let Some(cq) = self.cqs[cqid as usize].as_mut() else {
+                continue;
+            };
+            if head >= cq.size {
+                warn!("Invalid NVMe completion queue {} head {}", cqid, head);
+                continue;
+            }
+            if head != cq.head {
+                cq.head = head;
+                self.post_completions(cqid);
+            }
+        }
+
+        for sqid in 0..=self.num_queues {
+            self.process_sq(sqid);
+        }
+    }
+
+    fn process_sq(&mut self, sqid: u16) {
+        let mem = self.mem.memory();
+        loop {
+            let tail = self.doorbells.sq_tail(sqid);
+            let Some(sq) = self.sqs[sqid as usize].as_mut() else {
+                return;
+            };
+            if sq.deleting.is_some() || sq.head == tail {
+                return;
+            }
+            if tail >= sq.size {
+                warn!("Invalid NVMe submission queue {} tail {}", sqid, tail);
+                return;
+            }
+            let addr = sq.base + sq.head as u64 * SQ_ENTRY_SIZE;
+            sq.head = (sq.head + 1) % sq.size;
+
+            let command = match Command::read(&mem, addr) {
+                Ok(command) => command,
+                Err(e) => {
+                    error!("Failed reading NVMe command at 0x{:x}: {}", addr, e);
+                    return;
+                }
+            };
+
+            let completion = if sqid == 0 {
+                self.admin_command(&command)
+            } else {
+                self.io_command(sqid, &command)
+            };
+            if let Some((status, result)) = completion {
+                self.complete(sqid, command.cid, status, result);
+            }
+        }
+    }
+
+    fn write_data(&self, command: &Command, data: &[u8]) -> u16 {
+        let mem = self.mem.memory();
+        let segments = match prp_segments(&mem, command.prp1, command.prp2, data.len()) {
+            Ok(segments) => segments,
+            Err(status) => return status,
+        };
+        let mut offset = 0;
+        for (addr, len) in segments {
+            if mem.write_slice(&data[offset..offset + len], addr).is_err() {
+                return STATUS_DATA_TRANSFER_ERROR;
+            }
+            offset += len;
+        }
+
+        STATUS_SUCCESS
+    }
+
+    // Returns the status and result of the command, unless it completes
+    // later.
+    fn admin_command(&mut self, command: &Command) -> Option<(u16, u32)> {
+        if command.psdt != 0 {
+            return Some((STATUS_INVALID_FIELD, 0));
+        }
+
+        let status = match command.opcode {
+            ADMIN_DELETE_SQ => return self.delete_sq(command),
+            ADMIN_CREATE_SQ => self.create_sq(command),
+            ADMIN_GET_LOG_PAGE => self.get_log_page(command),
+            ADMIN_DELETE_CQ => self.delete_cq(command),
+            ADMIN_CREATE_CQ => self.create_cq(command),
+            ADMIN_IDENTIFY => self.identify(command),
+            // Commands complete too fast to be aborted.
+            ADMIN_ABORT => return Some((STATUS_SUCCESS, 1)),
+            ADMIN_SET_FEATURES => return Some(self.set_features(command)),
+            ADMIN_GET_FEATURES => return Some(self.get_features(command)),
+            ADMIN_ASYNC_EVENT_REQUEST => {
+                // No asynchronous event is ever reported, the requests being
+                // held until the controller is reset.
+                if self.aer_cids.len() >= AER_LIMIT {
+                    STATUS_AER_LIMIT_EXCEEDED
+                } else {
+                    self.aer_cids.push(command.cid);
+                    return None;
+                }
+            }
+            opcode => {
+                debug!("Unsupported NVMe admin command 0x{:x}", opcode);
+                STATUS_INVALID_OPCODE
+            }
+        };
+
+        Some((status, 0))
+    }
+
+    fn create_cq(&mut self, command: &Command) -> u16 {
+        let qid = command.queue_id();
+        let size = command.queue_size();
+        let contiguous = command.cdw11 & 0x1 != 0;
+        let interrupts = command.cdw11 & 0x2 != 0;
+        let vector = (command.cdw11 >> 16) as u16;
+
+        if qid == 0 || qid > self.num_queues || self.cqs[qid as usize].is_some() {
+            return STATUS_INVALID_QUEUE_ID;
+        }
+        if size == 0 || size >= MAX_QUEUE_ENTRIES {
+            return STATUS_INVALID_QUEUE_SIZE;
+        }
+        if vector > self.num_queues {
+            return STATUS_INVALID_VECTOR;
+        }
+        if !contiguous || (self.cc >> CC_IOCQES_SHIFT) & 0xf != CQES {
+            return STATUS_INVALID_FIELD;
+        }
+        if command.prp1 % PAGE_SIZE != 0 {
+            return STATUS_PRP_OFFSET_INVALID;
+        }
+
+        self.cqs[qid as usize] = Some(CompletionQueue::new(
+            command.prp1,
+            size + 1,
+            vector,
+            interrupts,
+        ));
+
+        STATUS_SUCCESS
+    }
+
+    fn create_sq(&mut self, command: &Command) -> u16 {
+        let qid = command.queue_id();
+        let size = command.queue_size();
+        let contiguous = command.cdw11 & 0x1 != 0;
+        let cqid = (command.cdw11 >> 16) as u16;
+
+        if qid == 0 || qid > self.num_queues || self.sqs[qid as usize].is_some() {
+            return STATUS_INVALID_QUEUE_ID;
+        }
+        if cqid == 0 || cqid > self.num_queues || self.cqs[cqid as usize].is_none() {
+            return STATUS_INVALID_CQ;
+        }
+        if size == 0 || size >= MAX_QUEUE_ENTRIES {
+            return STATUS_INVALID_QUEUE_SIZE;
+        }
+        if !contiguous || (self.cc >> CC_IOSQES_SHIFT) & 0xf != SQES {
+            return STATUS_INVALID_FIELD;
+        }
+        if command.prp1 % PAGE_SIZE != 0 {
+            return STATUS_PRP_OFFSET_INVALID;
+        }
+
+        self.sqs[qid as usize] = Some(SubmissionQueue {
+            base: command.prp1,
+            size: size + 1,
+            head: 0,
+            cqid,
+            in_flight: 0,
+            deleting: None,
+        });
+
+        STATUS_SUCCESS
+    }
+
+    fn delete_sq(&mut self, command: &Command) -> Option<(u16, u32)> {
+        let qid = command.queue_id();
+        if qid == 0 || qid > self.num_queues {
+            return Some((STATUS_INVALID_QUEUE_ID, 0));
+        }
+        let Some(sq) = self.sqs[qid as usize].as_mut() else {
+            return Some((STATUS_INVALID_QUEUE_ID, 0));
+        };
+        if sq.deleting.is_some() {
+            return Some((STATUS_INVALID_QUEUE_ID, 0));
+        }
+
+        // The queue is deleted once its requests are completed.
+        if sq.in_flight > 0 {
+            sq.deleting = Some(command.cid);
+            return None;
+        }
+        self.sqs[qid as usize] = None;
+
+        Some((STATUS_SUCCESS, 0))
+    }
+
+    fn delete_cq(&mut self, command: &Command) -> u16 {
+        let qid = command.queue_id();
+        if qid == 0 || qid > self.num_queues || self.cqs[qid as usize].is_none() {
+            return STATUS_INVALID_QUEUE_ID;
+        }
+        if self.sqs.iter().flatten().any(|sq| sq.cqid == qid) {
+            return STATUS_INVALID_QUEUE_DELETION;
+        }
+        self.cqs[qid as usize] = None;
+
+        STATUS_SUCCESS
+    }
+
+    fn complete_deletions(&mut self) {
+        for qid in 1..=self.num_queues {
+            let Some(sq) = self.sqs[qid as usize].as_ref() else {
+                continue;
+            };
+            if sq.in_flight > 0 {
+                continue;
+            }
+            if let Some(cid) = sq.deleting {
+                self.sqs[qid as usize] = None;
+                self.complete(0, cid, STATUS_SUCCESS, 0);
+            }
+        }
+    }
+
+    fn identify(&self, command: &Command) -> u16 {
+        let cns = command.cdw10 & 0xff;
+        let data = match cns {
+            CNS_NAMESPACE => match self.namespace(command.nsid) {
+                Some(namespace) => identify_namespace(namespace),
+                None => return STATUS_INVALID_NAMESPACE,
+            },
+            CNS_CONTROLLER => self.identify_controller(),
+            CNS_ACTIVE_NAMESPACES => {
+                let mut data = vec![0u8; IDENTIFY_SIZE];
+                let first = command.nsid.saturating_add(1);
+                for (entry, nsid) in data
+                    .chunks_exact_mut(4)
+                    .zip(first..=self.namespaces.len() as u32)
+                {
+                    entry.copy_from_slice(&nsid.to_le_bytes());
+                }
+                data
+            }
+            // No namespace identifiers are reported.
+            CNS_NAMESPACE_DESCRIPTORS => {
+                if self.namespace(command.nsid).is_none() {
+                    return STATUS_INVALID_NAMESPACE;
+                }
+                vec![0u8; IDENTIFY_SIZE]
+            }
+            cns => {
+                debug!("Unsupported NVMe identify CNS 0x{:x}", cns);
+                return STATUS_INVALID_FIELD;
+            }
+        };
+
+        self.write_data(command, &data)
+    }
+
+    fn namespace(&self, nsid: u32) -> Option<&Namespace> {
+        let index = (nsid as usize).checked_sub(1)?;
+        self.namespaces.get(index)
+    }
+
+    fn identify_controller(&self) -> Vec<u8> {
+        let mut data = vec![0u8; IDENTIFY_SIZE];
+        data[0..2].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+        data[2..4].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+        copy_padded(&mut data[4..24], &self.serial);
+        copy_padded(&mut data[24..64], MODEL_NUMBER);
+        copy_padded(&mut data[64..72], FIRMWARE_REVISION);
+        // Recommended arbitration burst.
+        data[72] = 6;
+        data[77] = MDTS;
+        data[80..84].copy_from_slice(&VERSION.to_le_bytes());
+        // I/O controller.
+        data[111] = 1;
+        // Abort and asynchronous event request limits, zero based.
+        data[258] = 3;
+        data[259] = AER_LIMIT as u8 - 1;
+        // A single read-only firmware slot.
+        data[260] = 0x3;
+        // Error log page entries, zero based.
+        data[262] = 0;
+        data[266..268].copy_from_slice(&WARNING_TEMPERATURE.to_le_bytes());
+        data[268..270].copy_from_slice(&CRITICAL_TEMPERATURE.to_le_bytes());
+        data[512] = ((SQES << 4) | SQES) as u8;
+        data[513] = ((CQES << 4) | CQES) as u8;
+        data[516..520].copy_from_slice(&(self.namespaces.len() as u32).to_le_bytes());
+        // Volatile write cache, not flushed by a broadcast Flush.
+        data[525] = 0x5;
+        let subnqn = format!("nqn.2026-01.org.cloudhypervisor:nvme:{}", self.serial);
+        let len = subnqn.len().min(256);
+        data[768..768 + len].copy_from_slice(&subnqn.as_bytes()[..len]);
+        // Maximum power of the only power state, in centiwatts.
+        data[2048..2050].copy_from_slice(&2500u16.to_le_bytes());
+
+        data
+    }
+
+    fn get_log_page(&self, command: &Command) -> u16 {
+        let lid = command.cdw10 & 0xff;
+        let dwords = ((command.cdw11 & 0xffff) << 16 | command.cdw10 >> 16) as usize + 1;
+        let offset = (command.cdw13 as u64) << 32 | command.cdw12 as u64;
+        let len = 4 * dwords;
+        if len > MAX_TRANSFER_SIZE {
+            return STATUS_INVALID_FIELD;
+        }
+
+        let log = match lid {
+            LOG_ERROR_INFORMATION => vec![0u8; 64],
+            LOG_SMART_HEALTH => self.smart_log(),
+            LOG_FIRMWARE_SLOT => {
+                let mut log = vec![0u8; 512];
+                // Firmware of slot 1 active.
+                log[0] = 1;
+                copy_padded(&mut log[8..16], FIRMWARE_REVISION);
+                log
+            }
+            lid => {
+                debug!("Unsupported NVMe log page 0x{:x}", lid);
+                return STATUS_INVALID_LOG_PAGE;
+            }
+        };
+        if offset >= log.len() as u64 || offset % 4 != 0 {
+            return STATUS_INVALID_FIELD;
+        }
+
+        let mut data = vec![0u8; len];
+        let available = &log[offset as usize..];
+        let copied = available.len().min(len);
+        data[..copied].copy_from_slice(&available[..copied]);
+
+        self.write_data(command, &data)
+    }
+
+    fn smart_log(&self) -> Vec<u8> {
+        // Data units are thousands of 512 bytes blocks.
+        fn data_units(blocks: u64) -> u64 {
+            blocks.div_ceil(1000)
+        }
+
+        let mut log = vec![0u8; 512];
+        log[1..3].copy_from_slice(&TEMPERATURE.to_le_bytes());
+        // Available spare and its threshold, as percentages.
+        log[3] = 100;
+        log[4] = 10;
+        log[32..40].copy_from_slice(&data_units(self.statistics.blocks_read).to_le_bytes());
+        log[48..56].copy_from_slice(&data_units(self.statistics.blocks_written).to_le_bytes());
+        log[64..72].copy_from_slice(&self.statistics.read_commands.to_le_bytes());
+        log[80..88].copy_from_slice(&self.statistics.write_commands.to_le_bytes());
+        log
+    }
+
+    fn set_features(&mut self, command: &Command) -> (u16, u32) {
+        let fid = command.cdw10 as u8;
+        let value = command.cdw11;
+        if command.cdw10 & FEAT_SAVE != 0 {
+            return (STATUS_FEATURE_NOT_SAVEABLE, 0);
+        }
+
+        match fid {
+            FEAT_NUMBER_OF_QUEUES => {
+                if value & 0xffff == 0xffff || value >> 16 == 0xffff {
+                    return (STATUS_INVALID_FIELD, 0);
+                }
+                // The number of allocated queues is reported, whatever the
+                // number of requested ones.
+                return (STATUS_SUCCESS, self.allocated_queues());
+            }
+            // Only a single power state is supported.
+            FEAT_POWER_MANAGEMENT if value & 0x1f != 0 => return (STATUS_INVALID_FIELD, 0),
+            FEAT_VOLATILE_WRITE_CACHE => {
+                self.features.insert(fid, value & 0x1);
+            }
+            FEAT_INTERRUPT_VECTOR_CONFIG => {
+                if value & 0xffff > self.num_queues as u32 {
+                    return (STATUS_INVALID_FIELD, 0);
+                }
+            }
+            fid if self.features.contains_key(&fid) => {
+                self.features.insert(fid, value);
+            }
+            fid => {
+                debug!("Unsupported NVMe feature 0x{:x}", fid);
+                return (STATUS_INVALID_FIELD, 0);
+            }
+        }
+
+        (STATUS_SUCCESS, 0)
+    }
+
+    fn get_features(&self, command: &Command) -> (u16, u32) {
+        let fid = command.cdw10 as u8;
+        let select = (command.cdw10 >> 8) & 0x7;
+
+        let (current, default) = match fid {
+            FEAT_NUMBER_OF_QUEUES => (self.allocated_queues(), self.allocated_queues()),
+            FEAT_INTERRUPT_VECTOR_CONFIG => {
+                let vector = command.cdw11 & 0xffff;
+                if vector > self.num_queues as u32 {
+                    return (STATUS_INVALID_FIELD, 0);
+                }
+                (vector, vector)
+            }
+            fid => match (self.features.get(&fid), default_features().get(&fid)) {
+                (Some(current), Some(default)) => (*current, *default),
+                _ => {
+                    debug!("Unsupported NVMe feature 0x{:x}", fid);
+                    return (STATUS_INVALID_FIELD, 0);
+                }
+            },
+        };
+
+        let result = match select {
+            0 => current,
+            1 | 2 => default,
+            FEAT_SELECT_SUPPORTED => FEAT_CHANGEABLE,
+            _ => return (STATUS_INVALID_FIELD, 0),
+        };
+
+        (STATUS_SUCCESS, result)
+    }
+
+    fn allocated_queues(&self) -> u32 {
+        let queues = self.num_queues as u32 - 1;
+        queues << 16 | queues
+    }
+
+    // Returns the status of the command, unless it completes later.
+    fn io_command(&mut self, sqid: u16, command: &Command) -> Option<(u16, u32)> {
+        if command.psdt != 0 {
+            return Some((STATUS_INVALID_FIELD, 0));
+        }
+        let Some(index) = self
+            .namespace(command.nsid)
+            .map(|_| command.nsid as usize - 1)
+        else {
+            return Some((STATUS_INVALID_NAMESPACE, 0));
+        };
+
+        let user_data = self.next_user_data;
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+
+        let request = match command.opcode {
+            IO_FLUSH => {
+                let namespace = &mut self.namespaces[index];
+                if let Err(e) = namespace.io.fsync(Some(user_data)) {
+                    error!("Failed flushing NVMe namespace {}: {}", namespace.id, e);
+                    return Some((STATUS_WRITE_FAULT, 0));
+                }
+                Request {
+                    sqid,
+                    cid: command.cid,
+                    opcode: command.opcode,
+                    blocks: 0,
+                    bounce: None,
+                }
+            }
+            IO_READ | IO_WRITE => match self.submit_rw(sqid, index, command, user_data) {
+                Ok(request) => request,
+                Err(status) => return Some((status, 0)),
+            },
+            opcode => {
+                debug!("Unsupported NVMe I/O command 0x{:x}", opcode);
+                return Some((STATUS_INVALID_OPCODE, 0));
+            }
+        };
+
+        if let Some(sq) = self.sqs[sqid as usize].as_mut() {
+            sq.in_flight += 1;
+        }
+        self.in_flight.insert(user_data, request);
+
+        None
+    }
+
+    fn submit_rw(
+        &mut self,
+        sqid: u16,
+        index: usize,
+        command: &Command,
+        user_data: u64,
+    ) -> result::Result<Request, u16> {
+        let write = command.opcode == IO_WRITE;
+        let namespace = &self.namespaces[index];
+        if write && namespace.readonly {
+            return Err(STATUS_NAMESPACE_WRITE_PROTECTED);
+        }
+
+        let slba = (command.cdw11 as u64) << 32 | command.cdw10 as u64;
+        let blocks = (command.cdw12 & 0xffff) as u64 + 1;
+        if slba
+            .checked_add(blocks)
+            .map_or(true, |end| end > namespace.num_blocks)
+        {
+            return Err(STATUS_LBA_OUT_OF_RANGE);
+        }
+        let len = (blocks << LBA_SHIFT) as usize;
+        if len > MAX_TRANSFER_SIZE {
+            return Err(STATUS_INVALID_FIELD);
+        }
+
+        let mem = self.mem.memory();
+        let segments = prp_segments(&mem, command.prp1, command.prp2, len)?;
+
+        // The guest buffers are used directly when they are suitably
+        // aligned for the disk backend.
+        let mut iovecs = Vec::with_capacity(segments.len());
+        let mut aligned = true;
+        for (addr, segment_len) in segments.iter() {
+            let slice = mem
+                .get_slice(*addr, *segment_len)
+                .map_err(|_| STATUS_DATA_TRANSFER_ERROR)?;
+            if !write {
+                slice.bitmap().mark_dirty(0, *segment_len);
+            }
+            let ptr = slice.ptr_guard().as_ptr();
+            aligned &=
+                ptr as usize % DIRECT_IO_ALIGNMENT == 0 && *segment_len % DIRECT_IO_ALIGNMENT == 0;
+            iovecs.push(libc::iovec {
+                iov_base: ptr as *mut libc::c_void,
+                iov_len: *segment_len,
+            });
+        }
+
+        let bounce = if aligned {
+            None
+        } else {
+            let mut buffer = BounceBuffer::new(len);
+            if write {
+                let mut offset = 0;
+                for (addr, segment_len) in segments.iter() {
+                    mem.read_slice(
+                        &mut buffer.as_mut_slice()[offset..offset + segment_len],
+                        *addr,
+                    )
+                    .map_err(|_| STATUS_DATA_TRANSFER_ERROR)?;
+                    offset += segment_len;
+                }
+            }
+            iovecs = vec![libc::iovec {
+                iov_base: buffer.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+                iov_len: len,
+            }];
+            Some((buffer, segments))
+        };
+
+        let namespace = &mut self.namespaces[index];
+        let offset = (slba << LBA_SHIFT) as libc::off_t;
+        if write {
+            namespace
+                .io
+                .write_vectored(offset, &iovecs, user_data)
+                .map_err(|e| {
+                    error!("Failed writing NVMe namespace {}: {}", namespace.id, e);
+                    STATUS_WRITE_FAULT
+                })?;
+        } else {
+            namespace
+                .io
+                .read_vectored(offset, &iovecs, user_data)
+                .map_err(|e| {
+                    error!("Failed reading NVMe namespace {}: {}", namespace.id, e);
+                    STATUS_UNRECOVERED_READ_ERROR
+                })?;
+        }
+
+        Ok(Request {
+            sqid,
+            cid: command.cid,
+            opcode: command.opcode,
+            blocks,
+            bounce,
+        })
+    }
+
+    fn complete_requests(&mut self, index: usize) {
+        if let Err(e) = self.namespaces[index].io.notifier().read() {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                error!("Failed reading NVMe completion notifier: {}", e);
+            }
+        }
+
+        while let Some((user_data, result)) = self.namespaces[index].io.next_completed_request() {
+            let Some(request) = self.in_flight.remove(&user_data) else {
+                warn!("Unknown NVMe request completed: {}", user_data);
+                continue;
+            };
+
+            let status = if result < 0 {
+                error!(
+                    "NVMe request 0x{:x} on namespace {} failed: {}",
+                    request.opcode,
+                    self.namespaces[index].id,
+                    io::Error::from_raw_os_error(-result)
+                );
+                match request.opcode {
+                    IO_READ => STATUS_UNRECOVERED_READ_ERROR,
+                    _ => STATUS_WRITE_FAULT,
+                }
+            } else {
+                match request.opcode {
+                    IO_READ => {
+                        self.statistics.blocks_read += request.blocks;
+                        self.statistics.read_commands += 1;
+                    }
+                    IO_WRITE => {
+                        self.statistics.blocks_written += request.blocks;
+                        self.statistics.write_commands += 1;
+                    }
+                    _ => {}
+                }
+                match &request.bounce {
+                    Some((buffer, segments)) if request.opcode == IO_READ => {
+                        self.copy_to_guest(buffer, segments)
+                    }
+                    _ => STATUS_SUCCESS,
+                }
+            };
+
+            if let Some(sq) = self.sqs[request.sqid as usize].as_mut() {
+                sq.in_flight -= 1;
+            }
+            if !self.resetting {
+                self.complete(request.sqid, request.cid, status, 0);
+            }
+        }
+
+        self.complete_deletions();
+        if self.in_flight.is_empty() {
+            self.quiesced();
+        }
+    }
+
+    fn copy_to_guest(&self, buffer: &BounceBuffer, segments: &[(GuestAddress, usize)]) -> u16 {
+        let mem = self.mem.memory();
+        let mut offset = 0;
+        for (addr, len) in segments {
+            if mem
+                .write_slice(&buffer.as_slice()[offset..offset + len], *addr)
+                .is_err()
+            {
+                return STATUS_DATA_TRANSFER_ERROR;
+            }
+            offset += len;
+        }
+
+        STATUS_SUCCESS
+    }
+}
+
+fn identify_namespace(namespace: &Namespace) -> Vec<u8> {
+    let mut data = vec![0u8; IDENTIFY_SIZE];
+    // Size, capacity and utilization.
+    for field in data[0..24].chunks_exact_mut(8) {
+        field.copy_from_slice(&namespace.num_blocks.to_le_bytes());
+    }
+    // Write protection.
+    data[99] = namespace.readonly as u8;
+    // A single LBA format of 512 bytes blocks.
+    data[130] = LBA_SHIFT as u8;
+
+    data
+}
+
+/// NVMe controller, exposing each disk image as a namespace.
+pub struct NvmeController {
+    id: String,
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    state: Arc<Mutex<Nvme>>,
+    doorbells: Arc<Doorbells>,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl NvmeController {
+    /// Create an NVMe controller with `num_queues` I/O queue pairs, the
+    /// namespaces being numbered from 1 in the given order.
+    pub fn new(
+        id: String,
+        serial: String,
+        num_queues: u16,
+        namespaces: Vec<NvmeNamespace>,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_manager: &dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>,
+        pci_device_bdf: u32,
+    ) -> result::Result<Self, NvmeError> {
+        assert!((1..=NVME_MAX_IO_QUEUES).contains(&num_queues));
+
+        // The admin queue has its own vector.
+        let msix_vectors = num_queues + 1;
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_vectors as InterruptIndex,
+            })
+            .map_err(|e| {
+                NvmeError::CreateNvmeController(anyhow!(
+                    "Failed creating MSI interrupt group: {}",
+                    e
+                ))
+            })?;
+
+        let msix_config = Arc::new(Mutex::new(
+            MsixConfig::new(
+                msix_vectors,
+                interrupt_source_group.clone(),
+                pci_device_bdf,
+                None,
+            )
+            .map_err(|e| {
+                NvmeError::CreateNvmeController(anyhow!("Failed creating MSI-X config: {:?}", e))
+            })?,
+        ));
+
+        let configuration = PciConfiguration::new(
+            NVME_VENDOR_ID,
+            NVME_DEVICE_ID,
+            0x2,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::NvmController,
+            Some(&NvmeProgrammingInterface::Nvme),
+            PciHeaderType::Device,
+            NVME_VENDOR_ID,
+            NVME_DEVICE_ID,
+            Some(msix_config.clone()),
+            None,
+        );
+
+        let epoll_fd = epoll::create(true).map_err(|e| {
+            NvmeError::CreateNvmeController(anyhow!("Failed creating epoll: {}", e))
+        })?;
+        // SAFETY: epoll_fd is a valid file descriptor we own.
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        let register = |fd: i32, data: u64| {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, data),
+            )
+            .map_err(|e| {
+                NvmeError::CreateNvmeController(anyhow!("Failed registering eventfd: {}", e))
+            })
+        };
+
+        // Requests of all the queues may be in flight at once.
+        let ring_depth = (num_queues as u32 + 1) * MAX_QUEUE_ENTRIES as u32;
+        let mut nvme_namespaces = Vec::new();
+        for (index, mut namespace) in namespaces.into_iter().enumerate() {
+            let size = namespace
+                .disk
+                .size()
+                .map_err(|e| NvmeError::OpenNamespace(namespace.id.clone(), e))?;
+            let io = namespace
+                .disk
+                .new_async_io(ring_depth)
+                .map_err(|e| NvmeError::OpenNamespace(namespace.id.clone(), e))?;
+            register(io.notifier().as_raw_fd(), index as u64)?;
+            info!(
+                "NVMe namespace {}: {} ({} bytes)",
+                index + 1,
+                namespace.id,
+                size
+            );
+            nvme_namespaces.push(Namespace {
+                id: namespace.id,
+                _disk: namespace.disk,
+                io,
+                num_blocks: size >> LBA_SHIFT,
+                readonly: namespace.readonly,
+            });
+        }
+
+        let mut values = Vec::new();
+        values.resize_with(2 * msix_vectors as usize, AtomicU32::default);
+        let doorbells = Arc::new(Doorbells {
+            values,
+            evt: EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                NvmeError::CreateNvmeController(anyhow!("Failed creating eventfd: {}", e))
+            })?,
+        });
+        register(doorbells.evt.as_raw_fd(), DOORBELL_EVENT)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+            NvmeError::CreateNvmeController(anyhow!("Failed creating eventfd: {}", e))
+        })?;
+        register(kill_evt.as_raw_fd(), KILL_EVENT)?;
+
+        let state = Arc::new(Mutex::new(Nvme::new(
+            serial,
+            num_queues,
+            nvme_namespaces,
+            mem,
+            msix_config.clone(),
+            interrupt_source_group,
+            doorbells.clone(),
+        )));
+
+        let worker_state = state.clone();
+        let worker = thread::Builder::new()
+            .name(id.clone())
+            .spawn(move || run_worker(worker_state, epoll_file))
+            .map_err(|e| {
+                NvmeError::CreateNvmeController(anyhow!("Failed spawning thread: {}", e))
+            })?;
+
+        Ok(NvmeController {
+            id,
+            configuration,
+            bar_regions: vec![],
+            msix_config,
+            state,
+            doorbells,
+            kill_evt,
+            worker: Some(worker),
+        })
+    }
+}
+
+fn run_worker(state: Arc<Mutex<Nvme>>, epoll_file: File) {
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+    loop {
+        let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Failed waiting for NVMe events: {}", e);
+                return;
+            }
+        };
+
+        for event in events.iter().take(num_events) {
+            let mut state = state.lock().unwrap();
+            match event.data {
+                KILL_EVENT => return,
+                DOORBELL_EVENT => {
+                    let _ = state.doorbells.evt.read();
+                    state.process_doorbells();
+                    if state.in_flight.is_empty() {
+                        state.quiesced();
+                    }
+                }
+                index => state.complete_requests(index as usize),
+            }
+        }
+    }
+}
+
+impl Drop for NvmeController {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl BusDevice for NvmeController {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for NvmeController {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        _mmio32_allocator: &mut AddressAllocator,
+        mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let restoring = resources.is_some();
+        let bar_addr = mmio64_allocator
+            .allocate(None, NVME_BAR_SIZE, Some(NVME_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(NVME_BAR_SIZE))?;
+
+        let bar = PciBarConfiguration::default()
+            .set_index(NVME_BAR_INDEX)
+            .set_address(bar_addr.raw_value())
+            .set_size(NVME_BAR_SIZE)
+            .set_region_type(PciBarRegionType::Memory64BitRegion)
+            .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
+
+        debug!("NVMe bar address 0x{:x}", bar_addr.0);
+        if !restoring {
+            self.configuration
+                .add_pci_bar(&bar)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+
+            let msix_cap = MsixCap::new(
+                NVME_BAR_INDEX as u8,
+                self.msix_config.lock().unwrap().table_entries.len() as u16,
+                MSIX_TABLE_OFFSET as u32,
+                NVME_BAR_INDEX as u8,
+                MSIX_PBA_OFFSET as u32,
+            );
+            self.configuration
+                .add_capability(&msix_cap)
+                .map_err(PciDeviceError::CapabilitiesSetup)?;
+        }
+
+        self.bar_regions = vec![bar];
+
+        Ok(vec![bar])
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        _mmio32_allocator: &mut AddressAllocator,
+        mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            mmio64_allocator.free(GuestAddress(bar.addr()), bar.size());
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if (MSIX_TABLE_OFFSET..MSIX_TABLE_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_table(o - MSIX_TABLE_OFFSET, data),
+            o if (MSIX_PBA_OFFSET..MSIX_PBA_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_pba(o - MSIX_PBA_OFFSET, data),
+            // Doorbells are write only.
+            o if o >= DOORBELL_OFFSET => data.fill(0),
+            o => {
+                // Registers are dwords, the 64-bit ones being accessed as
+                // pairs of dwords or as a qword.
+                let state = self.state.lock().unwrap();
+                let aligned = o & !0x3;
+                let mut value = state.read_register(aligned) as u64;
+                if data.len() > 4 {
+                    value |= (state.read_register(aligned + 4) as u64) << 32;
+                }
+                let bytes = (value >> ((o & 0x3) * 8)).to_le_bytes();
+                let len = data.len().min(bytes.len());
+                data[..len].copy_from_slice(&bytes[..len]);
+            }
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match offset {
+            o if (MSIX_TABLE_OFFSET..MSIX_TABLE_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_table(o - MSIX_TABLE_OFFSET, data),
+            o if (MSIX_PBA_OFFSET..MSIX_PBA_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_pba(o - MSIX_PBA_OFFSET, data),
+            o if o >= DOORBELL_OFFSET && o & 0x3 == 0 && data.len() == 4 => {
+                let index = ((o - DOORBELL_OFFSET) / 4) as usize;
+                if index < self.doorbells.values.len() {
+                    let value = u32::from_le_bytes(data.try_into().unwrap());
+                    self.doorbells.ring(index, value);
+                } else {
+                    warn!("Write to invalid NVMe doorbell 0x{:x}", o);
+                }
+            }
+            o if o < DOORBELL_OFFSET && o & 0x3 == 0 && matches!(data.len(), 4 | 8) => {
+                let mut state = self.state.lock().unwrap();
+                for (i, dword) in data.chunks_exact(4).enumerate() {
+                    let value = u32::from_le_bytes(dword.try_into().unwrap());
+                    state.write_register(o + 4 * i as u64, value);
+                }
+            }
+            o => warn!(
+                "Unsupported NVMe register write of {} bytes at 0x{:x}",
+                data.len(),
+                o
+            ),
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for NvmeController {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.state.lock().unwrap().paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.state.lock().unwrap().paused = false;
+        // Process the doorbells written while paused.
+        self.doorbells.kick();
+        Ok(())
+    }
+}
+
+impl Snapshottable for NvmeController {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The queues and in-flight requests aren't part of the device state.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "Can't snapshot an NVMe controller"
+        )))
+    }
+}
+
+impl Transportable for NvmeController {}
+impl Migratable for NvmeController {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::raw_sync::RawFileDiskSync;
+    use std::io::{Read, Seek, SeekFrom};
+    use vm_device::interrupt::InterruptSourceConfig;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const ASQ: u64 = 0x1_0000;
+    const ACQ: u64 = 0x2_0000;
+    const IO_SQ: u64 = 0x3_0000;
+    const IO_CQ: u64 = 0x4_0000;
+    const DATA: u64 = 0x5_0000;
+    const NUM_BLOCKS: u64 = 16;
+
+    struct TestInterrupt;
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn set_gsi(&self) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+    }
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap()
+    }
+
+    // Controller with a writable namespace followed by a read-only one,
+    // returning the file backing the former.
+    fn create_nvme() -> (Nvme, File) {
+        let mut namespaces = Vec::new();
+        let mut files = Vec::new();
+        for readonly in [false, true] {
+            let file = TempFile::new().unwrap().into_file();
+            file.set_len(NUM_BLOCKS << LBA_SHIFT).unwrap();
+            let mut disk: Box<dyn DiskFile> =
+                Box::new(RawFileDiskSync::new(file.try_clone().unwrap()));
+            let num_blocks = disk.size().unwrap() >> LBA_SHIFT;
+            namespaces.push(Namespace {
+                id: format!("disk{}", namespaces.len()),
+                io: disk.new_async_io(1).unwrap(),
+                _disk: disk,
+                num_blocks,
+                readonly,
+            });
+            files.push(file);
+        }
+
+        let interrupt: Arc<dyn InterruptSourceGroup> = Arc::new(TestInterrupt);
+        let msix_config = Arc::new(Mutex::new(
+            MsixConfig::new(3, interrupt.clone(), 0, None).unwrap(),
+        ));
+        let mut values = Vec::new();
+        values.resize_with(6, AtomicU32::default);
+        let doorbells = Arc::new(Doorbells {
+            values,
+            evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        });
+
+        let nvme = Nvme::new(
+            "serial".to_owned(),
+            2,
+            namespaces,
+            GuestMemoryAtomic::new(guest_memory()),
+            msix_config,
+            interrupt,
+            doorbells,
+        );
+        (nvme, files.remove(0))
+    }
+
+    // Enable the controller with admin queues of 4 entries.
+    fn enable(nvme: &mut Nvme) {
+        nvme.write_register(REG_AQA, (3 << 16) | 3);
+        nvme.write_register(REG_ASQ, ASQ as u32);
+        nvme.write_register(REG_ACQ, ACQ as u32);
+        nvme.write_register(
+            REG_CC,
+            CC_EN | (SQES << CC_IOSQES_SHIFT) | (CQES << CC_IOCQES_SHIFT),
+        );
+        assert_eq!(nvme.read_register(REG_CSTS), CSTS_RDY);
+    }
+
+    fn command(opcode: u8, cid: u16, nsid: u32, prp1: u64, cdw: [u32; 4]) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[0] = opcode;
+        bytes[2..4].copy_from_slice(&cid.to_le_bytes());
+        bytes[4..8].copy_from_slice(&nsid.to_le_bytes());
+        bytes[24..32].copy_from_slice(&prp1.to_le_bytes());
+        for (i, dword) in cdw.iter().enumerate() {
+            bytes[40 + 4 * i..44 + 4 * i].copy_from_slice(&dword.to_le_bytes());
+        }
+        bytes
+    }
+
+    // Queue the command at the tail of the submission queue and ring its
+    // doorbell.
+    fn submit(nvme: &mut Nvme, sqid: u16, command: &[u8; 64]) {
+        let sq = nvme.sqs[sqid as usize].as_ref().unwrap();
+        let (base, size) = (sq.base, sq.size);
+        let tail = nvme.doorbells.sq_tail(sqid);
+        nvme.mem
+            .memory()
+            .write_slice(command, GuestAddress(base + tail as u64 * SQ_ENTRY_SIZE))
+            .unwrap();
+        nvme.doorbells
+            .ring(2 * sqid as usize, ((tail + 1) % size) as u32);
+        nvme.process_doorbells();
+    }
+
+    // Completion entry at the given index: result, SQ head, SQ identifier,
+    // command identifier, status and phase.
+    fn completion(nvme: &Nvme, base: u64, index: u64) -> (u32, u16, u16, u16, u16, bool) {
+        let mut entry = [0u8; CQ_ENTRY_SIZE as usize];
+        nvme.mem
+            .memory()
+            .read_slice(&mut entry, GuestAddress(base + index * CQ_ENTRY_SIZE))
+            .unwrap();
+        let word = |i: usize| u16::from_le_bytes([entry[i], entry[i + 1]]);
+        (
+            u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+            word(8),
+            word(10),
+            word(12),
+            word(14) >> 1,
+            word(14) & 1 != 0,
+        )
+    }
+
+    // Create the I/O completion and submission queues 1, of 8 entries.
+    fn create_io_queues(nvme: &mut Nvme) {
+        submit(
+            nvme,
+            0,
+            &command(
+                ADMIN_CREATE_CQ,
+                1,
+                0,
+                IO_CQ,
+                [(7 << 16) | 1, (1 << 16) | 0x3, 0, 0],
+            ),
+        );
+        assert!(nvme.cqs[1].is_some());
+        submit(
+            nvme,
+            0,
+            &command(
+                ADMIN_CREATE_SQ,
+                2,
+                0,
+                IO_SQ,
+                [(7 << 16) | 1, (1 << 16) | 0x1, 0, 0],
+            ),
+        );
+        assert!(nvme.sqs[1].is_some());
+    }
+
+    // Submit an I/O command, completing it as the worker thread would.
+    fn io(nvme: &mut Nvme, command: &[u8; 64]) {
+        submit(nvme, 1, command);
+        for index in 0..nvme.namespaces.len() {
+            nvme.complete_requests(index);
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let (mut nvme, _file) = create_nvme();
+        assert_eq!(nvme.read_register(REG_CAP), CAP as u32);
+        assert_eq!(nvme.read_register(REG_CAP + 4), (CAP >> 32) as u32);
+        assert_eq!(nvme.read_register(REG_VS), VERSION);
+        assert_eq!(nvme.read_register(REG_CSTS), 0);
+
+        nvme.write_register(REG_ASQ, 0x1234_5000);
+        nvme.write_register(REG_ASQ + 4, 0x1);
+        assert_eq!(nvme.asq, 0x1_1234_5000);
+        nvme.write_register(REG_INTMS, 0x5);
+        nvme.write_register(REG_INTMC, 0x1);
+        assert_eq!(nvme.read_register(REG_INTMS), 0x4);
+
+        // Misaligned admin queues are a fatal configuration error.
+        nvme.write_register(REG_AQA, (3 << 16) | 3);
+        nvme.write_register(REG_ACQ, ACQ as u32 + 0x100);
+        nvme.write_register(REG_CC, CC_EN);
+        assert_eq!(nvme.read_register(REG_CSTS), CSTS_CFS);
+        nvme.write_register(REG_CC, 0);
+
+        enable(&mut nvme);
+        nvme.write_register(REG_CC, 0);
+        assert_eq!(nvme.read_register(REG_CSTS), 0);
+        assert!(nvme.sqs.iter().all(|sq| sq.is_none()));
+    }
+
+    #[test]
+    fn test_admin_queue() {
+        let (mut nvme, _file) = create_nvme();
+        enable(&mut nvme);
+
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_IDENTIFY, 0x10, 0, DATA, [CNS_CONTROLLER, 0, 0, 0]),
+        );
+        assert_eq!(
+            completion(&nvme, ACQ, 0),
+            (0, 1, 0, 0x10, STATUS_SUCCESS, true)
+        );
+        let mut data = vec![0u8; IDENTIFY_SIZE];
+        nvme.mem
+            .memory()
+            .read_slice(&mut data, GuestAddress(DATA))
+            .unwrap();
+        assert_eq!(&data[0..2], &NVME_VENDOR_ID.to_le_bytes());
+        assert_eq!(&data[4..10], b"serial");
+        assert_eq!(&data[24..24 + MODEL_NUMBER.len()], MODEL_NUMBER.as_bytes());
+        assert_eq!(data[516], 2);
+
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_IDENTIFY, 0x11, 1, DATA, [CNS_NAMESPACE, 0, 0, 0]),
+        );
+        assert_eq!(completion(&nvme, ACQ, 1).4, STATUS_SUCCESS);
+        let num_blocks: u64 = nvme.mem.memory().read_obj(GuestAddress(DATA)).unwrap();
+        assert_eq!(num_blocks, NUM_BLOCKS);
+
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_IDENTIFY, 0x12, 3, DATA, [CNS_NAMESPACE, 0, 0, 0]),
+        );
+        assert_eq!(completion(&nvme, ACQ, 2).4, STATUS_INVALID_NAMESPACE);
+
+        // The completion queue is full until the guest consumes an entry,
+        // the phase being inverted once the queue wraps around.
+        submit(
+            &mut nvme,
+            0,
+            &command(
+                ADMIN_GET_FEATURES,
+                0x13,
+                0,
+                0,
+                [FEAT_NUMBER_OF_QUEUES as u32, 0, 0, 0],
+            ),
+        );
+        assert_eq!(completion(&nvme, ACQ, 3).3, 0);
+        nvme.doorbells.ring(1, 2);
+        nvme.process_doorbells();
+        assert_eq!(
+            completion(&nvme, ACQ, 3),
+            ((1 << 16) | 1, 0, 0, 0x13, STATUS_SUCCESS, true)
+        );
+        submit(&mut nvme, 0, &command(0xff, 0x14, 0, 0, [0; 4]));
+        assert_eq!(
+            completion(&nvme, ACQ, 0),
+            (0, 1, 0, 0x14, STATUS_INVALID_OPCODE, false)
+        );
+
+        submit(
+            &mut nvme,
+            0,
+            &command(
+                ADMIN_SET_FEATURES,
+                0x15,
+                0,
+                0,
+                [FEAT_SAVE | FEAT_VOLATILE_WRITE_CACHE as u32, 0, 0, 0],
+            ),
+        );
+        nvme.doorbells.ring(1, 0);
+        nvme.process_doorbells();
+        assert_eq!(completion(&nvme, ACQ, 1).4, STATUS_FEATURE_NOT_SAVEABLE);
+
+        // Asynchronous event requests are held until the reset.
+        for cid in 0..AER_LIMIT as u16 {
+            submit(
+                &mut nvme,
+                0,
+                &command(ADMIN_ASYNC_EVENT_REQUEST, cid, 0, 0, [0; 4]),
+            );
+        }
+        assert_eq!(nvme.aer_cids.len(), AER_LIMIT);
+        assert_eq!(completion(&nvme, ACQ, 2).3, 0x12);
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_ASYNC_EVENT_REQUEST, 0x16, 0, 0, [0; 4]),
+        );
+        assert_eq!(
+            completion(&nvme, ACQ, 2),
+            (0, 3, 0, 0x16, STATUS_AER_LIMIT_EXCEEDED, false)
+        );
+    }
+
+    #[test]
+    fn test_io_queue_creation() {
+        let (mut nvme, _file) = create_nvme();
+        enable(&mut nvme);
+
+        // The completion queue must exist first, and queue identifiers are
+        // limited to the number of queues.
+        submit(
+            &mut nvme,
+            0,
+            &command(
+                ADMIN_CREATE_SQ,
+                1,
+                0,
+                IO_SQ,
+                [(7 << 16) | 1, (1 << 16) | 0x1, 0, 0],
+            ),
+        );
+        assert_eq!(completion(&nvme, ACQ, 0).4, STATUS_INVALID_CQ);
+        submit(
+            &mut nvme,
+            0,
+            &command(
+                ADMIN_CREATE_CQ,
+                2,
+                0,
+                IO_CQ,
+                [(3 << 16) | 3, (1 << 16) | 0x3, 0, 0],
+            ),
+        );
+        assert_eq!(completion(&nvme, ACQ, 1).4, STATUS_INVALID_QUEUE_ID);
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_CREATE_CQ, 3, 0, IO_CQ, [1, (1 << 16) | 0x3, 0, 0]),
+        );
+        assert_eq!(completion(&nvme, ACQ, 2).4, STATUS_INVALID_QUEUE_SIZE);
+        nvme.doorbells.ring(1, 3);
+
+        create_io_queues(&mut nvme);
+        let cq = nvme.cqs[1].as_ref().unwrap();
+        assert_eq!((cq.base, cq.size, cq.vector), (IO_CQ, 8, 1));
+        let sq = nvme.sqs[1].as_ref().unwrap();
+        assert_eq!((sq.base, sq.size, sq.cqid), (IO_SQ, 8, 1));
+
+        // Queues can't be created twice, nor the completion queue deleted
+        // before the submission queue using it.
+        nvme.doorbells.ring(1, 1);
+        submit(
+            &mut nvme,
+            0,
+            &command(
+                ADMIN_CREATE_SQ,
+                4,
+                0,
+                IO_SQ,
+                [(7 << 16) | 1, (1 << 16) | 0x1, 0, 0],
+            ),
+        );
+        assert_eq!(completion(&nvme, ACQ, 1).4, STATUS_INVALID_QUEUE_ID);
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_DELETE_CQ, 5, 0, 0, [1, 0, 0, 0]),
+        );
+        assert_eq!(completion(&nvme, ACQ, 2).4, STATUS_INVALID_QUEUE_DELETION);
+        nvme.doorbells.ring(1, 3);
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_DELETE_SQ, 6, 0, 0, [1, 0, 0, 0]),
+        );
+        assert_eq!(completion(&nvme, ACQ, 3).4, STATUS_SUCCESS);
+        assert!(nvme.sqs[1].is_none());
+        submit(
+            &mut nvme,
+            0,
+            &command(ADMIN_DELETE_CQ, 7, 0, 0, [1, 0, 0, 0]),
+        );
+        assert_eq!(
+            completion(&nvme, ACQ, 0),
+            (0, 1, 0, 7, STATUS_SUCCESS, true)
+        );
+        assert!(nvme.cqs[1].is_none());
+    }
+
+    #[test]
+    fn test_io_queue() {
+        let (mut nvme, mut file) = create_nvme();
+        enable(&mut nvme);
+        create_io_queues(&mut nvme);
+        let mem = nvme.mem.memory();
+
+        // Write a block from an aligned buffer, used directly.
+        let data: Vec<u8> = (0..512u32).map(|i| i as u8).collect();
+        mem.write_slice(&data, GuestAddress(DATA)).unwrap();
+        io(&mut nvme, &command(IO_WRITE, 1, 1, DATA, [2, 0, 0, 0]));
+        assert_eq!(
+            completion(&nvme, IO_CQ, 0),
+            (0, 1, 1, 1, STATUS_SUCCESS, true)
+        );
+        let mut disk = vec![0u8; 512];
+        file.seek(SeekFrom::Start(2 << LBA_SHIFT)).unwrap();
+        file.read_exact(&mut disk).unwrap();
+        assert_eq!(disk, data);
+
+        // Read it back to a misaligned buffer, through a bounce buffer.
+        io(
+            &mut nvme,
+            &command(IO_READ, 2, 1, DATA + 0x1004, [2, 0, 0, 0]),
+        );
+        assert_eq!(completion(&nvme, IO_CQ, 1).4, STATUS_SUCCESS);
+        let mut read = vec![0u8; 512];
+        mem.read_slice(&mut read, GuestAddress(DATA + 0x1004))
+            .unwrap();
+        assert_eq!(read, data);
+
+        io(&mut nvme, &command(IO_FLUSH, 3, 1, 0, [0; 4]));
+        assert_eq!(completion(&nvme, IO_CQ, 2).4, STATUS_SUCCESS);
+        assert!(nvme.in_flight.is_empty());
+        assert_eq!(nvme.sqs[1].as_ref().unwrap().in_flight, 0);
+
+        // Invalid requests complete right away.
+        io(
+            &mut nvme,
+            &command(IO_READ, 4, 1, DATA, [NUM_BLOCKS as u32 - 1, 0, 1, 0]),
+        );
+        assert_eq!(completion(&nvme, IO_CQ, 3).4, STATUS_LBA_OUT_OF_RANGE);
+        io(&mut nvme, &command(IO_READ, 5, 3, DATA, [0; 4]));
+        assert_eq!(completion(&nvme, IO_CQ, 4).4, STATUS_INVALID_NAMESPACE);
+        io(&mut nvme, &command(IO_WRITE, 6, 2, DATA, [0; 4]));
+        assert_eq!(
+            completion(&nvme, IO_CQ, 5).4,
+            STATUS_NAMESPACE_WRITE_PROTECTED
+        );
+        io(&mut nvme, &command(0x7f, 7, 1, DATA, [0; 4]));
+        assert_eq!(completion(&nvme, IO_CQ, 6).4, STATUS_INVALID_OPCODE);
+
+        let mut smart = vec![0u8; 512];
+        submit(
+            &mut nvme,
+            0,
+            &command(
+                ADMIN_GET_LOG_PAGE,
+                8,
+                0,
+                DATA,
+                [(127 << 16) | LOG_SMART_HEALTH, 0, 0, 0],
+            ),
+        );
+        assert_eq!(completion(&nvme, ACQ, 2).4, STATUS_SUCCESS);
+        mem.read_slice(&mut smart, GuestAddress(DATA)).unwrap();
+        // One block read and one written, rounded up to a data unit.
+        assert_eq!(u64::from_le_bytes(smart[32..40].try_into().unwrap()), 1);
+        assert_eq!(u64::from_le_bytes(smart[48..56].try_into().unwrap()), 1);
+        assert_eq!(u64::from_le_bytes(smart[64..72].try_into().unwrap()), 1);
+        assert_eq!(u64::from_le_bytes(smart[80..88].try_into().unwrap()), 1);
+    }
+
+    #[test]
+    fn test_prp_segments() {
+        let mem = guest_memory();
+
+        // Single page, possibly starting within the page.
+        assert_eq!(
+            prp_segments(&mem, 0x1000, 0, 0x1000),
+            Ok(vec![(GuestAddress(0x1000), 0x1000)])
+        );
+        assert_eq!(
+            prp_segments(&mem, 0x1200, 0, 0x200),
+            Ok(vec![(GuestAddress(0x1200), 0x200)])
+        );
+
+        // Two pages, the second entry pointing to the second page.
+        assert_eq!(
+            prp_segments(&mem, 0x1e00, 0x5000, 0x1000),
+            Ok(vec![
+                (GuestAddress(0x1e00), 0x200),
+                (GuestAddress(0x5000), 0xe00)
+            ])
+        );
+        assert_eq!(
+            prp_segments(&mem, 0x1000, 0x5200, 0x2000),
+            Err(STATUS_PRP_OFFSET_INVALID)
+        );
+        assert_eq!(
+            prp_segments(&mem, 0x1002, 0, 0x200),
+            Err(STATUS_PRP_OFFSET_INVALID)
+        );
+
+        // List of pages, spanning two pages of the list.
+        let list = 0x8000;
+        for i in 0..511u64 {
+            mem.write_obj(0x10_0000 + i * 0x1000, GuestAddress(list + i * 8))
+                .unwrap();
+        }
+        mem.write_obj(0x9000u64, GuestAddress(list + 511 * 8))
+            .unwrap();
+        mem.write_obj(0x20_0000u64, GuestAddress(0x9000)).unwrap();
+        mem.write_obj(0x20_1000u64, GuestAddress(0x9008)).unwrap();
+
+        let segments = prp_segments(&mem, 0x1000, list, 514 * 0x1000 - 0x800).unwrap();
+        assert_eq!(segments.len(), 514);
+        assert_eq!(segments[0], (GuestAddress(0x1000), 0x1000));
+        assert_eq!(segments[1], (GuestAddress(0x10_0000), 0x1000));
+        assert_eq!(
+            segments[511],
+            (GuestAddress(0x10_0000 + 510 * 0x1000), 0x1000)
+        );
+        assert_eq!(segments[512], (GuestAddress(0x20_0000), 0x1000));
+        assert_eq!(segments[513], (GuestAddress(0x20_1000), 0x800));
+    }
+
+    #[test]
+    fn test_copy_padded() {
+        let mut field = [0u8; 8];
+        copy_padded(&mut field, "1.0");
+        assert_eq!(&field, b"1.0     ");
+        copy_padded(&mut field, "0123456789");
+        assert_eq!(&field, b"01234567");
+    }
+}
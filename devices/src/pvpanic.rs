@@ -18,6 +18,7 @@ use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::{BusDevice, Resource};
 use vm_memory::{Address, GuestAddress};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
 
 const PVPANIC_VENDOR_ID: u16 = 0x1b36;
 const PVPANIC_DEVICE_ID: u16 = 0x0011;
@@ -51,6 +52,9 @@ impl PciSubclass for PvPanicSubclass {
 pub struct PvPanicDevice {
     id: String,
     events: u8,
+    // Signalled when the guest reports a panic, so that the VMM can take
+    // the action configured for it.
+    panic_evt: EventFd,
 
     // PCI configuration registers.
     configuration: PciConfiguration,
@@ -63,7 +67,11 @@ pub struct PvPanicDeviceState {
 }
 
 impl PvPanicDevice {
-    pub fn new(id: String, snapshot: Option<Snapshot>) -> Result<Self, PvPanicError> {
+    pub fn new(
+        id: String,
+        panic_evt: EventFd,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, PvPanicError> {
         let pci_configuration_state =
             vm_migration::state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID).map_err(|e| {
                 PvPanicError::RetrievePciConfigurationState(anyhow!(
@@ -108,6 +116,7 @@ impl PvPanicDevice {
         let pvpanic_device = PvPanicDevice {
             id,
             events,
+            panic_evt,
             configuration,
             bar_regions: vec![],
         };
@@ -144,7 +153,24 @@ impl BusDevice for PvPanicDevice {
     fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
         let event = self.event_to_string(data[0]);
         info!("pvpanic got guest event {}", event);
-        event!("guest", "panic", "event", &event);
+        event!(
+            "guest",
+            "panic",
+            "source",
+            "pvpanic",
+            "event",
+            &event,
+            "code",
+            format!("{:#x}", data[0])
+        );
+
+        // A crash kernel being loaded isn't a panic, the guest keeps running.
+        if data[0] & PVPANIC_PANICKED != 0 {
+            if let Err(e) = self.panic_evt.write(1) {
+                error!("Failed to signal the guest panic: {}", e);
+            }
+        }
+
         None
     }
 }
@@ -0,0 +1,518 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Passthrough of host USB devices through usbfs.
+//!
+//! The interfaces of the device are claimed from the host kernel drivers for
+//! as long as the device is passed through, and given back to them when it is
+//! released. Transfers are submitted asynchronously as URBs, the usbfs file
+//! becoming writable whenever completed URBs are ready to be reaped.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::raw::{c_int, c_uint, c_void};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+
+const USBFS_PATH: &str = "/dev/bus/usb";
+
+const USBDEVFS_TYPE: c_uint = b'U' as c_uint;
+
+const USBDEVFS_URB_TYPE_INTERRUPT: u8 = 1;
+const USBDEVFS_URB_TYPE_CONTROL: u8 = 2;
+const USBDEVFS_URB_TYPE_BULK: u8 = 3;
+
+// Reconnect the host kernel driver, through USBDEVFS_IOCTL.
+const USBDEVFS_CONNECT: c_int = 0x5517;
+
+const USB_DT_DEVICE: u8 = 1;
+const USB_DT_CONFIG: u8 = 2;
+const USB_DT_INTERFACE: u8 = 4;
+const USB_DT_DEVICE_SIZE: usize = 18;
+
+const USB_REQ_GET_CONFIGURATION: u8 = 8;
+const USB_DIR_IN: u8 = 0x80;
+const CONTROL_TIMEOUT_MS: u32 = 1000;
+
+#[repr(C)]
+struct UsbdevfsUrb {
+    type_: u8,
+    endpoint: u8,
+    status: c_int,
+    flags: c_uint,
+    buffer: *mut c_void,
+    buffer_length: c_int,
+    actual_length: c_int,
+    start_frame: c_int,
+    stream_id: c_uint,
+    error_count: c_int,
+    signr: c_uint,
+    usercontext: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsCtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsSetInterface {
+    interface: c_uint,
+    altsetting: c_uint,
+}
+
+#[repr(C)]
+struct UsbdevfsIoctl {
+    ifno: c_int,
+    ioctl_code: c_int,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsDisconnectClaim {
+    interface: c_uint,
+    flags: c_uint,
+    driver: [u8; 256],
+}
+
+ioctl_iowr_nr!(USBDEVFS_CONTROL, USBDEVFS_TYPE, 0, UsbdevfsCtrlTransfer);
+ioctl_ior_nr!(
+    USBDEVFS_SETINTERFACE,
+    USBDEVFS_TYPE,
+    4,
+    UsbdevfsSetInterface
+);
+ioctl_ior_nr!(USBDEVFS_SETCONFIGURATION, USBDEVFS_TYPE, 5, c_uint);
+ioctl_ior_nr!(USBDEVFS_SUBMITURB, USBDEVFS_TYPE, 10, UsbdevfsUrb);
+ioctl_io_nr!(USBDEVFS_DISCARDURB, USBDEVFS_TYPE, 11);
+ioctl_iow_nr!(USBDEVFS_REAPURBNDELAY, USBDEVFS_TYPE, 13, *mut c_void);
+ioctl_ior_nr!(USBDEVFS_RELEASEINTERFACE, USBDEVFS_TYPE, 16, c_uint);
+ioctl_iowr_nr!(USBDEVFS_IOCTL, USBDEVFS_TYPE, 18, UsbdevfsIoctl);
+ioctl_io_nr!(USBDEVFS_RESET, USBDEVFS_TYPE, 20);
+ioctl_ior_nr!(USBDEVFS_CLEAR_HALT, USBDEVFS_TYPE, 21, c_uint);
+ioctl_ior_nr!(
+    USBDEVFS_DISCONNECT_CLAIM,
+    USBDEVFS_TYPE,
+    27,
+    UsbdevfsDisconnectClaim
+);
+ioctl_io_nr!(USBDEVFS_GET_SPEED, USBDEVFS_TYPE, 31);
+
+/// Speed of a USB device, as reported by usbfs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbSpeed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+/// Location of the host USB device to pass through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbHostAddress {
+    /// Device number on the given bus.
+    BusAddr { bus: u8, addr: u8 },
+    /// First device matching the given vendor and product identifiers.
+    VendorProduct { vendor_id: u16, product_id: u16 },
+}
+
+/// Transfer type of an endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Bulk,
+    Interrupt,
+}
+
+/// URB reaped after its completion.
+pub struct CompletedUrb {
+    pub token: u64,
+    /// Negative errno on failure.
+    pub status: i32,
+    /// Data transferred, following the setup packet for control transfers.
+    pub buffer: Vec<u8>,
+    pub actual_length: usize,
+}
+
+// URB in flight, boxed so that its address stays valid until it gets reaped.
+struct PendingUrb {
+    urb: UsbdevfsUrb,
+    buffer: Vec<u8>,
+}
+
+pub struct UsbHostDevice {
+    file: File,
+    path: PathBuf,
+    speed: UsbSpeed,
+    descriptors: Vec<u8>,
+    // Interfaces of the active configuration, claimed from the host.
+    claimed: Vec<u8>,
+    pending: HashMap<u64, Box<PendingUrb>>,
+    next_token: u64,
+}
+
+// SAFETY: the raw pointers of the pending URBs point to buffers owned by the
+// device itself.
+unsafe impl Send for UsbHostDevice {}
+
+fn device_path(bus: u8, addr: u8) -> PathBuf {
+    Path::new(USBFS_PATH).join(format!("{bus:03}/{addr:03}"))
+}
+
+// Look for the device with the given identifiers by reading the device
+// descriptor of every device under the usbfs directory.
+fn find_device(vendor_id: u16, product_id: u16) -> io::Result<PathBuf> {
+    let mut paths = Vec::new();
+    for bus in fs::read_dir(USBFS_PATH)? {
+        for device in fs::read_dir(bus?.path())? {
+            paths.push(device?.path());
+        }
+    }
+    paths.sort();
+
+    for path in paths {
+        let mut descriptor = [0u8; USB_DT_DEVICE_SIZE];
+        if File::open(&path)
+            .and_then(|mut f| f.read_exact(&mut descriptor))
+            .is_err()
+        {
+            continue;
+        }
+        if u16::from_le_bytes([descriptor[8], descriptor[9]]) == vendor_id
+            && u16::from_le_bytes([descriptor[10], descriptor[11]]) == product_id
+        {
+            return Ok(path);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no USB device {vendor_id:04x}:{product_id:04x}"),
+    ))
+}
+
+// Interface numbers of the configuration with the given value, out of the
+// descriptors read from usbfs.
+fn config_interfaces(descriptors: &[u8], value: u8) -> Vec<u8> {
+    let mut interfaces = Vec::new();
+    let mut in_config = false;
+    let mut offset = 0;
+    while offset + 2 <= descriptors.len() {
+        let len = descriptors[offset] as usize;
+        if len < 2 || offset + len > descriptors.len() {
+            break;
+        }
+        let desc = &descriptors[offset..offset + len];
+        match desc[1] {
+            USB_DT_CONFIG if len >= 6 => in_config = desc[5] == value,
+            USB_DT_INTERFACE if len >= 3 && in_config && !interfaces.contains(&desc[2]) => {
+                interfaces.push(desc[2])
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    interfaces
+}
+
+fn check(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl UsbHostDevice {
+    /// Open the host device and claim the interfaces of its active
+    /// configuration.
+    pub fn open(address: UsbHostAddress) -> io::Result<Self> {
+        let path = match address {
+            UsbHostAddress::BusAddr { bus, addr } => device_path(bus, addr),
+            UsbHostAddress::VendorProduct {
+                vendor_id,
+                product_id,
+            } => find_device(vendor_id, product_id)?,
+        };
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(&path)?;
+
+        let mut descriptors = Vec::new();
+        file.read_to_end(&mut descriptors)?;
+        if descriptors.len() < USB_DT_DEVICE_SIZE || descriptors[1] != USB_DT_DEVICE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid device descriptor",
+            ));
+        }
+
+        // SAFETY: FFI call with a valid usbfs file descriptor.
+        let speed = match check(unsafe { ioctl(&file, USBDEVFS_GET_SPEED()) })? {
+            1 => UsbSpeed::Low,
+            2 => UsbSpeed::Full,
+            // Wireless devices are reported as high speed ones.
+            3 | 4 => UsbSpeed::High,
+            _ => UsbSpeed::Super,
+        };
+
+        let mut device = UsbHostDevice {
+            file,
+            path,
+            speed,
+            descriptors,
+            claimed: Vec::new(),
+            pending: HashMap::new(),
+            next_token: 0,
+        };
+
+        let config = device.active_configuration()?;
+        device.claim_interfaces(config)?;
+
+        Ok(device)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+
+    fn active_configuration(&self) -> io::Result<u8> {
+        let mut value = 0u8;
+        let mut transfer = UsbdevfsCtrlTransfer {
+            request_type: USB_DIR_IN,
+            request: USB_REQ_GET_CONFIGURATION,
+            value: 0,
+            index: 0,
+            length: 1,
+            timeout: CONTROL_TIMEOUT_MS,
+            data: &mut value as *mut u8 as *mut c_void,
+        };
+        // SAFETY: FFI call with a valid usbfs file descriptor and a transfer
+        // pointing to a buffer of the given length.
+        check(unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_CONTROL(), &mut transfer) })?;
+        Ok(value)
+    }
+
+    fn claim_interfaces(&mut self, config: u8) -> io::Result<()> {
+        self.claimed.clear();
+        for interface in config_interfaces(&self.descriptors, config) {
+            let claim = UsbdevfsDisconnectClaim {
+                interface: interface.into(),
+                flags: 0,
+                driver: [0; 256],
+            };
+            // SAFETY: FFI call with a valid usbfs file descriptor and a
+            // properly initialized structure.
+            check(unsafe { ioctl_with_ref(&self.file, USBDEVFS_DISCONNECT_CLAIM(), &claim) })?;
+            self.claimed.push(interface);
+        }
+        Ok(())
+    }
+
+    fn release_interfaces(&mut self) {
+        for interface in self.claimed.drain(..) {
+            let value = c_uint::from(interface);
+            // SAFETY: FFI call with a valid usbfs file descriptor.
+            unsafe { ioctl_with_ref(&self.file, USBDEVFS_RELEASEINTERFACE(), &value) };
+
+            let mut request = UsbdevfsIoctl {
+                ifno: interface.into(),
+                ioctl_code: USBDEVFS_CONNECT,
+                data: std::ptr::null_mut(),
+            };
+            // SAFETY: FFI call with a valid usbfs file descriptor and a
+            // request not needing any data.
+            if unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_IOCTL(), &mut request) } < 0 {
+                debug!(
+                    "No host driver reconnected to interface {} of {:?}: {}",
+                    interface,
+                    self.path,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    /// Select the configuration with the given value, claiming its
+    /// interfaces in place of the ones of the previous configuration.
+    pub fn set_configuration(&mut self, value: u8) -> io::Result<()> {
+        let config = c_uint::from(value);
+        // SAFETY: FFI call with a valid usbfs file descriptor.
+        check(unsafe { ioctl_with_ref(&self.file, USBDEVFS_SETCONFIGURATION(), &config) })?;
+        self.claim_interfaces(value)
+    }
+
+    pub fn set_interface(&mut self, interface: u16, altsetting: u16) -> io::Result<()> {
+        let setting = UsbdevfsSetInterface {
+            interface: interface.into(),
+            altsetting: altsetting.into(),
+        };
+        // SAFETY: FFI call with a valid usbfs file descriptor and a properly
+        // initialized structure.
+        check(unsafe { ioctl_with_ref(&self.file, USBDEVFS_SETINTERFACE(), &setting) })?;
+        Ok(())
+    }
+
+    pub fn clear_halt(&mut self, endpoint: u8) -> io::Result<()> {
+        let endpoint = c_uint::from(endpoint);
+        // SAFETY: FFI call with a valid usbfs file descriptor.
+        check(unsafe { ioctl_with_ref(&self.file, USBDEVFS_CLEAR_HALT(), &endpoint) })?;
+        Ok(())
+    }
+
+    /// Reset the device, claiming its interfaces back from the host kernel
+    /// drivers which may have been bound to them in the meantime.
+    pub fn reset(&mut self) -> io::Result<()> {
+        // SAFETY: FFI call with a valid usbfs file descriptor.
+        check(unsafe { ioctl(&self.file, USBDEVFS_RESET()) })?;
+        let config = self.active_configuration()?;
+        self.claim_interfaces(config)
+    }
+
+    /// Submit a transfer on `endpoint`, returning the token identifying it
+    /// once completed. Control transfers must start with the setup packet.
+    pub fn submit(
+        &mut self,
+        transfer_type: TransferType,
+        endpoint: u8,
+        mut buffer: Vec<u8>,
+    ) -> io::Result<u64> {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        let type_ = match transfer_type {
+            TransferType::Control => USBDEVFS_URB_TYPE_CONTROL,
+            TransferType::Bulk => USBDEVFS_URB_TYPE_BULK,
+            TransferType::Interrupt => USBDEVFS_URB_TYPE_INTERRUPT,
+        };
+        let mut pending = Box::new(PendingUrb {
+            urb: UsbdevfsUrb {
+                type_,
+                endpoint,
+                status: 0,
+                flags: 0,
+                buffer: buffer.as_mut_ptr() as *mut c_void,
+                buffer_length: buffer.len() as c_int,
+                actual_length: 0,
+                start_frame: 0,
+                stream_id: 0,
+                error_count: 0,
+                signr: 0,
+                usercontext: token as *mut c_void,
+            },
+            buffer,
+        });
+
+        // SAFETY: FFI call with a valid usbfs file descriptor. The URB and
+        // its buffer are kept alive until the URB gets reaped.
+        check(unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_SUBMITURB(), &mut pending.urb) })?;
+        self.pending.insert(token, pending);
+
+        Ok(token)
+    }
+
+    /// Cancel the transfer identified by `token`, which still needs to be
+    /// reaped.
+    pub fn discard(&mut self, token: u64) {
+        if let Some(pending) = self.pending.get_mut(&token) {
+            // SAFETY: FFI call with a valid usbfs file descriptor and a URB
+            // previously submitted.
+            unsafe {
+                ioctl_with_val(
+                    &self.file,
+                    USBDEVFS_DISCARDURB(),
+                    &mut pending.urb as *mut UsbdevfsUrb as libc::c_ulong,
+                )
+            };
+        }
+    }
+
+    /// Reap a completed URB, if any.
+    pub fn reap(&mut self) -> io::Result<Option<CompletedUrb>> {
+        let mut urb: *mut UsbdevfsUrb = std::ptr::null_mut();
+        // SAFETY: FFI call with a valid usbfs file descriptor and a pointer
+        // to store the address of the reaped URB.
+        if unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_REAPURBNDELAY(), &mut urb) } < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EAGAIN) => Ok(None),
+                _ => Err(e),
+            };
+        }
+
+        // SAFETY: the kernel returned the address of one of the pending URBs.
+        let token = unsafe { (*urb).usercontext } as u64;
+        let pending = self
+            .pending
+            .remove(&token)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "reaped an unknown URB"))?;
+
+        Ok(Some(CompletedUrb {
+            token,
+            status: pending.urb.status,
+            actual_length: pending.urb.actual_length.max(0) as usize,
+            buffer: pending.buffer,
+        }))
+    }
+}
+
+impl AsRawFd for UsbHostDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for UsbHostDevice {
+    fn drop(&mut self) {
+        // Closing the file kills the pending URBs, and gives the interfaces
+        // back to the host kernel drivers.
+        self.release_interfaces();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_interfaces() {
+        #[rustfmt::skip]
+        let descriptors = [
+            // Device
+            18, USB_DT_DEVICE, 0x00, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0, 1, 1, 2, 3, 2,
+            // Configuration 1, with interfaces 0 and 1
+            9, USB_DT_CONFIG, 34, 0, 2, 1, 0, 0x80, 50,
+            9, USB_DT_INTERFACE, 0, 0, 1, 0xff, 0, 0, 0,
+            7, 5, 0x81, 2, 0, 2, 0,
+            9, USB_DT_INTERFACE, 1, 0, 0, 0xff, 0, 0, 0,
+            // Configuration 2, with interface 0 and its alternate setting
+            9, USB_DT_CONFIG, 27, 0, 1, 2, 0, 0x80, 50,
+            9, USB_DT_INTERFACE, 0, 0, 0, 0xff, 0, 0, 0,
+            9, USB_DT_INTERFACE, 0, 1, 0, 0xff, 0, 0, 0,
+        ];
+
+        assert_eq!(config_interfaces(&descriptors, 1), vec![0, 1]);
+        assert_eq!(config_interfaces(&descriptors, 2), vec![0]);
+        assert!(config_interfaces(&descriptors, 3).is_empty());
+        // Truncated descriptors are ignored.
+        assert_eq!(config_interfaces(&descriptors[..30], 1), vec![0]);
+    }
+}
@@ -0,0 +1,12 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! USB host controller emulation, with passthrough of host USB devices.
+
+mod host;
+mod xhci;
+
+pub use self::host::{UsbHostAddress, UsbHostDevice, UsbSpeed};
+pub use self::xhci::{XhciController, XhciError, XHCI_MAX_PORTS};
@@ -0,0 +1,2501 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulation of an xHCI USB host controller, exposing passed through host USB
+//! devices to the guest.
+//!
+//! Each of the `num_ports` ports of the controller has both a USB 2.0 and a
+//! USB 3.0 root hub port, a device being connected to one or the other
+//! depending on its speed. Transfers are forwarded to the host devices as
+//! URBs, reaped by a dedicated thread which generates the matching events.
+//! Isochronous endpoints, streams and hubs are not supported.
+
+use super::host::{TransferType, UsbHostDevice, UsbSpeed};
+use anyhow::anyhow;
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarPrefetchable,
+    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciProgrammingInterface, PciSerialBusSubClass, MSIX_CONFIG_ID, PCI_CONFIGURATION_ID,
+};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Instant;
+use thiserror::Error;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_device::{BusDevice, Resource};
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+type Result<T> = result::Result<T, GuestMemoryError>;
+
+const XHCI_VENDOR_ID: u16 = 0x1b36;
+const XHCI_DEVICE_ID: u16 = 0x000d;
+
+/// Maximum number of ports of each protocol.
+pub const XHCI_MAX_PORTS: u8 = 16;
+
+const XHCI_BAR_SIZE: u64 = 0x1_0000;
+const MSIX_VECTORS: u16 = 1;
+const MSIX_TABLE_OFFSET: u64 = 0x8000;
+const MSIX_PBA_OFFSET: u64 = 0x9000;
+const MSIX_REGION_SIZE: u64 = 0x1000;
+
+const MAX_SLOTS: u8 = 16;
+const MAX_ENDPOINTS: usize = 32;
+// Number of TDs submitted to a host device for each endpoint.
+const MAX_IN_FLIGHT: usize = 16;
+const MAX_TD_TRBS: usize = 256;
+const MAX_LINK_TRBS: usize = 16;
+const ERST_MAX: u32 = 4;
+const EPOLL_EVENTS_LEN: usize = 16;
+const KILL_EVENT: u64 = u64::MAX;
+
+// Capability registers.
+const REG_CAPLENGTH: u64 = 0x00;
+const REG_HCSPARAMS1: u64 = 0x04;
+const REG_HCSPARAMS2: u64 = 0x08;
+const REG_HCCPARAMS1: u64 = 0x10;
+const REG_DBOFF: u64 = 0x14;
+const REG_RTSOFF: u64 = 0x18;
+const CAPLENGTH: u32 = 0x40;
+const HCIVERSION: u32 = 0x0100;
+const HCCPARAMS1_AC64: u32 = 1 << 0;
+
+// Operational registers.
+const REG_USBCMD: u64 = 0x40;
+const REG_USBSTS: u64 = 0x44;
+const REG_PAGESIZE: u64 = 0x48;
+const REG_DNCTRL: u64 = 0x54;
+const REG_CRCR_LO: u64 = 0x58;
+const REG_CRCR_HI: u64 = 0x5c;
+const REG_DCBAAP_LO: u64 = 0x70;
+const REG_DCBAAP_HI: u64 = 0x74;
+const REG_CONFIG: u64 = 0x78;
+const PORTS_OFFSET: u64 = 0x440;
+const PORT_REGS_SIZE: u64 = 0x10;
+
+const USBCMD_RS: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBCMD_INTE: u32 = 1 << 2;
+// Save and restore state requests, completed right away.
+const USBCMD_CSS: u32 = 1 << 8;
+const USBCMD_CRS: u32 = 1 << 9;
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_EINT: u32 = 1 << 3;
+const USBSTS_PCD: u32 = 1 << 4;
+const USBSTS_RW1C: u32 = (1 << 2) | USBSTS_EINT | USBSTS_PCD | (1 << 10);
+const CRCR_RCS: u64 = 1 << 0;
+const CRCR_CS: u32 = 1 << 1;
+const CRCR_CA: u32 = 1 << 2;
+const CRCR_CRR: u32 = 1 << 3;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PLS_SHIFT: u32 = 5;
+const PORTSC_PLS_MASK: u32 = 0xf << PORTSC_PLS_SHIFT;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_SPEED_SHIFT: u32 = 10;
+const PORTSC_LWS: u32 = 1 << 16;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_WRC: u32 = 1 << 19;
+const PORTSC_PRC: u32 = 1 << 21;
+const PORTSC_PLC: u32 = 1 << 22;
+const PORTSC_CHANGE_BITS: u32 = 0x7f << 17;
+const PORTSC_WAKE_BITS: u32 = 0x7 << 25;
+const PORTSC_WPR: u32 = 1 << 31;
+const PLS_U0: u32 = 0;
+const PLS_U3: u32 = 3;
+const PLS_RX_DETECT: u32 = 5;
+const PLS_POLLING: u32 = 7;
+const PLS_RESUME: u32 = 15;
+
+// Runtime registers.
+const RUNTIME_OFFSET: u64 = 0x1000;
+const REG_MFINDEX: u64 = 0x1000;
+const REG_IMAN: u64 = 0x1020;
+const REG_IMOD: u64 = 0x1024;
+const REG_ERSTSZ: u64 = 0x1028;
+const REG_ERSTBA_LO: u64 = 0x1030;
+const REG_ERSTBA_HI: u64 = 0x1034;
+const REG_ERDP_LO: u64 = 0x1038;
+const REG_ERDP_HI: u64 = 0x103c;
+const IMAN_IP: u32 = 1 << 0;
+const IMAN_IE: u32 = 1 << 1;
+const ERDP_EHB: u64 = 1 << 3;
+
+const DOORBELL_OFFSET: u64 = 0x2000;
+
+// Extended capabilities, describing the USB 2.0 and USB 3.0 ports.
+const XECP_OFFSET: u64 = 0x3000;
+const XECP_SIZE: u64 = 0x20;
+const XECP_SUPPORTED_PROTOCOL: u32 = 2;
+const XECP_NAME_USB: u32 = 0x2042_5355;
+
+// TRB types.
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP_STAGE: u32 = 2;
+const TRB_DATA_STAGE: u32 = 3;
+const TRB_STATUS_STAGE: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_EVENT_DATA: u32 = 7;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_TR_DEQUEUE: u32 = 16;
+const TRB_RESET_DEVICE: u32 = 17;
+const TRB_NOOP_COMMAND: u32 = 23;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+const TRB_SIZE: u64 = 16;
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_EVENT_DATA_FLAG: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+// Block Set Address Request of Address Device, Deconfigure of Configure
+// Endpoint.
+const TRB_BSR: u32 = 1 << 9;
+const TRB_DC: u32 = 1 << 9;
+
+// Completion codes.
+const COMPLETION_SUCCESS: u32 = 1;
+const COMPLETION_BABBLE: u32 = 3;
+const COMPLETION_TRANSACTION_ERROR: u32 = 4;
+const COMPLETION_TRB_ERROR: u32 = 5;
+const COMPLETION_STALL: u32 = 6;
+const COMPLETION_NO_SLOTS: u32 = 9;
+const COMPLETION_SLOT_NOT_ENABLED: u32 = 11;
+const COMPLETION_SHORT_PACKET: u32 = 13;
+const COMPLETION_PARAMETER_ERROR: u32 = 17;
+const COMPLETION_CONTEXT_STATE_ERROR: u32 = 19;
+const COMPLETION_COMMAND_RING_STOPPED: u32 = 24;
+
+// Device contexts.
+const CONTEXT_SIZE: u64 = 32;
+const SLOT_STATE_SHIFT: u32 = 27;
+const SLOT_STATE_MASK: u32 = 0x1f << SLOT_STATE_SHIFT;
+const SLOT_DEFAULT: u32 = 1;
+const SLOT_ADDRESSED: u32 = 2;
+const SLOT_CONFIGURED: u32 = 3;
+const CONTEXT_ENTRIES_MASK: u32 = 0x1f << 27;
+const EP_STATE_MASK: u32 = 0x7;
+const EP_DISABLED: u32 = 0;
+const EP_RUNNING: u32 = 1;
+const EP_HALTED: u32 = 2;
+const EP_STOPPED: u32 = 3;
+const EP_ERROR: u32 = 4;
+const EP_TYPE_BULK_OUT: u32 = 2;
+const EP_TYPE_INTERRUPT_OUT: u32 = 3;
+const EP_TYPE_CONTROL: u32 = 4;
+const EP_TYPE_BULK_IN: u32 = 6;
+const EP_TYPE_INTERRUPT_IN: u32 = 7;
+
+// Standard requests handled by the controller rather than the host device,
+// since usbfs needs to know about them.
+const USB_REQ_CLEAR_FEATURE: u8 = 1;
+const USB_REQ_SET_CONFIGURATION: u8 = 9;
+const USB_REQ_SET_INTERFACE: u8 = 11;
+const USB_RECIP_DEVICE: u8 = 0;
+const USB_RECIP_INTERFACE: u8 = 1;
+const USB_RECIP_ENDPOINT: u8 = 2;
+const USB_ENDPOINT_HALT: u16 = 0;
+const USB_SETUP_SIZE: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum XhciError {
+    #[error("Failed creating xHCI controller: {0}")]
+    CreateXhciController(#[source] anyhow::Error),
+    #[error("No free xHCI port for the USB device")]
+    NoFreePort,
+    #[error("Failed registering USB device: {0}")]
+    RegisterUsbDevice(#[source] io::Error),
+}
+
+#[derive(Copy, Clone)]
+enum XhciProgrammingInterface {
+    Xhci = 0x30,
+}
+
+impl PciProgrammingInterface for XhciProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Trb {
+    addr: u64,
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn read(mem: &GuestMemoryMmap, addr: u64) -> Result<Self> {
+        let mut bytes = [0u8; TRB_SIZE as usize];
+        mem.read_slice(&mut bytes, GuestAddress(addr))?;
+        Ok(Trb {
+            addr,
+            parameter: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            status: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            control: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        })
+    }
+
+    fn trb_type(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    fn has(&self, flag: u32) -> bool {
+        self.control & flag != 0
+    }
+
+    fn transfer_length(&self) -> usize {
+        (self.status & 0x1_ffff) as usize
+    }
+
+    fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    fn endpoint_id(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+}
+
+// Position of the controller on a transfer or command ring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct RingCursor {
+    dequeue: u64,
+    cycle: bool,
+}
+
+impl RingCursor {
+    fn new(pointer: u64) -> Self {
+        RingCursor {
+            dequeue: pointer & !0xf,
+            cycle: pointer & 1 != 0,
+        }
+    }
+
+    fn pointer(&self) -> u64 {
+        self.dequeue | self.cycle as u64
+    }
+
+    // Fetch the next TRB owned by the controller, following link TRBs.
+    fn next(&mut self, mem: &GuestMemoryMmap) -> Result<Option<Trb>> {
+        for _ in 0..MAX_LINK_TRBS {
+            let trb = Trb::read(mem, self.dequeue)?;
+            if trb.has(TRB_CYCLE) != self.cycle {
+                return Ok(None);
+            }
+            if trb.trb_type() == TRB_LINK {
+                self.dequeue = trb.parameter & !0xf;
+                if trb.has(TRB_TOGGLE_CYCLE) {
+                    self.cycle = !self.cycle;
+                }
+                continue;
+            }
+            self.dequeue += TRB_SIZE;
+            return Ok(Some(trb));
+        }
+
+        warn!("xHCI ring at 0x{:x} only made of link TRBs", self.dequeue);
+        Ok(None)
+    }
+}
+
+fn read_context(mem: &GuestMemoryMmap, addr: u64) -> Result<[u32; 8]> {
+    let mut bytes = [0u8; CONTEXT_SIZE as usize];
+    mem.read_slice(&mut bytes, GuestAddress(addr))?;
+    let mut context = [0u32; 8];
+    for (dword, chunk) in context.iter_mut().zip(bytes.chunks_exact(4)) {
+        *dword = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    Ok(context)
+}
+
+fn write_context(mem: &GuestMemoryMmap, addr: u64, context: &[u32; 8]) -> Result<()> {
+    let mut bytes = [0u8; CONTEXT_SIZE as usize];
+    for (chunk, dword) in bytes.chunks_exact_mut(4).zip(context.iter()) {
+        chunk.copy_from_slice(&dword.to_le_bytes());
+    }
+    mem.write_slice(&bytes, GuestAddress(addr))
+}
+
+// Transfer Descriptor, made of the TRBs of a single transfer.
+struct Td {
+    trbs: Vec<Trb>,
+    start: RingCursor,
+    end: RingCursor,
+    control: bool,
+    token: u64,
+}
+
+impl Td {
+    fn data_trbs(&self) -> impl Iterator<Item = &Trb> {
+        self.trbs
+            .iter()
+            .filter(|t| matches!(t.trb_type(), TRB_NORMAL | TRB_DATA_STAGE))
+    }
+
+    fn data_length(&self) -> usize {
+        self.data_trbs().map(|t| t.transfer_length()).sum()
+    }
+
+    // Transfer events to generate once `actual` bytes have been transferred,
+    // as (parameter, completion code, residual length, event data) tuples.
+    // A single event is generated for a short Normal TD, while the Status
+    // stage of a short control transfer still completes successfully.
+    fn events(&self, code: u32, actual: usize) -> Vec<(u64, u32, usize, bool)> {
+        let failed = code != COMPLETION_SUCCESS;
+        let mut events = Vec::new();
+        let mut remaining = actual;
+        let mut event_data_length = 0;
+        let mut short = false;
+
+        for trb in self.trbs.iter() {
+            match trb.trb_type() {
+                TRB_NORMAL | TRB_DATA_STAGE => {
+                    if short {
+                        continue;
+                    }
+                    let length = trb.transfer_length();
+                    let done = length.min(remaining);
+                    remaining -= done;
+                    event_data_length += done;
+                    if done == length {
+                        if trb.has(TRB_IOC) {
+                            events.push((trb.addr, COMPLETION_SUCCESS, 0, false));
+                        }
+                        continue;
+                    }
+                    if failed {
+                        events.push((trb.addr, code, length - done, false));
+                        return events;
+                    }
+                    short = true;
+                    if trb.has(TRB_ISP) || trb.has(TRB_IOC) {
+                        events.push((trb.addr, COMPLETION_SHORT_PACKET, length - done, false));
+                    } else if !self.control {
+                        if let Some(last) = self.trbs.last().filter(|t| t.has(TRB_IOC)) {
+                            let length = last.transfer_length();
+                            events.push((last.addr, COMPLETION_SHORT_PACKET, length, false));
+                        }
+                    }
+                    if !self.control {
+                        return events;
+                    }
+                }
+                TRB_STATUS_STAGE => {
+                    if failed {
+                        events.push((trb.addr, code, 0, false));
+                        return events;
+                    }
+                    if trb.has(TRB_IOC) {
+                        events.push((trb.addr, COMPLETION_SUCCESS, 0, false));
+                    }
+                }
+                TRB_EVENT_DATA => {
+                    if trb.has(TRB_IOC) {
+                        let code = if short {
+                            COMPLETION_SHORT_PACKET
+                        } else {
+                            COMPLETION_SUCCESS
+                        };
+                        events.push((trb.parameter, code, event_data_length, true));
+                    }
+                    event_data_length = 0;
+                }
+                _ => {
+                    if trb.has(TRB_IOC) && !failed {
+                        events.push((trb.addr, COMPLETION_SUCCESS, 0, false));
+                    }
+                }
+            }
+        }
+
+        if failed {
+            if let Some(last) = self.trbs.last() {
+                events.push((last.addr, code, 0, false));
+            }
+        }
+
+        events
+    }
+}
+
+fn is_standard_request(setup: &[u8; USB_SETUP_SIZE]) -> bool {
+    let value = u16::from_le_bytes([setup[2], setup[3]]);
+    match (setup[0], setup[1]) {
+        (USB_RECIP_DEVICE, USB_REQ_SET_CONFIGURATION) => true,
+        (USB_RECIP_INTERFACE, USB_REQ_SET_INTERFACE) => true,
+        (USB_RECIP_ENDPOINT, USB_REQ_CLEAR_FEATURE) => value == USB_ENDPOINT_HALT,
+        _ => false,
+    }
+}
+
+enum TdAction {
+    // Submit a URB to the host device.
+    Submit(TransferType, u8, Vec<u8>),
+    // Complete the TD right away.
+    Complete(u32),
+    // Wait for the TDs in flight to complete.
+    Defer,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    state: u32,
+    ep_type: u32,
+    // Next TRB to fetch from the transfer ring.
+    ring: RingCursor,
+    // TDs submitted to the host device, completing in order.
+    in_flight: VecDeque<Td>,
+    // Cancelled URBs which still need to be reaped.
+    discarded: Vec<u64>,
+    // Stop Endpoint command waiting for the cancelled URBs.
+    stop_command: Option<u64>,
+}
+
+impl Endpoint {
+    fn new(context: &[u32; 8]) -> Self {
+        Endpoint {
+            state: EP_RUNNING,
+            ep_type: (context[1] >> 3) & 0x7,
+            ring: RingCursor::new((context[3] as u64) << 32 | context[2] as u64),
+            ..Default::default()
+        }
+    }
+}
+
+struct Slot {
+    // Root hub port of the device, once addressed.
+    port: Option<usize>,
+    endpoints: Vec<Endpoint>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        let mut endpoints = Vec::new();
+        endpoints.resize_with(MAX_ENDPOINTS, Default::default);
+        Slot {
+            port: None,
+            endpoints,
+        }
+    }
+}
+
+struct UsbDevice {
+    id: String,
+    host: UsbHostDevice,
+    // Slot the device has been addressed with.
+    slot: Option<u8>,
+    // Endpoint of each URB submitted to the host device.
+    urbs: HashMap<u64, u8>,
+}
+
+#[derive(Default)]
+struct Port {
+    portsc: u32,
+    device: Option<UsbDevice>,
+}
+
+#[derive(Default)]
+struct EventRing {
+    // Base address and size of the segments.
+    segments: Vec<(u64, u32)>,
+    segment: usize,
+    index: u32,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn enqueue_pointer(&self) -> Option<u64> {
+        self.segments
+            .get(self.segment)
+            .map(|(base, _)| base + self.index as u64 * TRB_SIZE)
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+        if self.index >= self.segments[self.segment].1 {
+            self.index = 0;
+            self.segment += 1;
+            if self.segment >= self.segments.len() {
+                self.segment = 0;
+                self.cycle = !self.cycle;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Interrupter {
+    iman: u32,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    ring: EventRing,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EndpointState {
+    state: u32,
+    ep_type: u32,
+    ring: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SlotState {
+    port: Option<usize>,
+    endpoints: Vec<EndpointState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct XhciState {
+    usbcmd: u32,
+    usbsts: u32,
+    dnctrl: u32,
+    crcr: u64,
+    dcbaap: u64,
+    config: u32,
+    command_ring: Option<u64>,
+    iman: u32,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    event_segments: Vec<(u64, u32)>,
+    event_segment: usize,
+    event_index: u32,
+    event_cycle: bool,
+    portsc: Vec<u32>,
+    slots: Vec<Option<SlotState>>,
+}
+
+struct Xhci {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt: Arc<dyn InterruptSourceGroup>,
+    num_ports: u8,
+
+    usbcmd: u32,
+    usbsts: u32,
+    dnctrl: u32,
+    crcr: u64,
+    dcbaap: u64,
+    config: u32,
+    started: Instant,
+
+    // Set while the command ring is running.
+    command_ring: Option<RingCursor>,
+    // Commands are processed in order, a Stop Endpoint command blocking the
+    // following ones until the transfers of the endpoint are cancelled.
+    command_blocked: bool,
+
+    interrupter: Interrupter,
+    ports: Vec<Port>,
+    slots: Vec<Option<Slot>>,
+}
+
+impl Xhci {
+    fn new(
+        num_ports: u8,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        msix_config: Arc<Mutex<MsixConfig>>,
+        interrupt: Arc<dyn InterruptSourceGroup>,
+    ) -> Self {
+        let mut ports = Vec::new();
+        ports.resize_with(2 * num_ports as usize, Default::default);
+        let mut slots = Vec::new();
+        slots.resize_with(MAX_SLOTS as usize, || None);
+
+        let mut xhci = Xhci {
+            mem,
+            msix_config,
+            interrupt,
+            num_ports,
+            usbcmd: 0,
+            usbsts: 0,
+            dnctrl: 0,
+            crcr: 0,
+            dcbaap: 0,
+            config: 0,
+            started: Instant::now(),
+            command_ring: None,
+            command_blocked: false,
+            interrupter: Interrupter::default(),
+            ports,
+            slots,
+        };
+        xhci.reset();
+        xhci
+    }
+
+    fn reset(&mut self) {
+        for slot_id in 1..=MAX_SLOTS {
+            if self.slots[slot_id as usize - 1].is_some() {
+                self.release_slot(slot_id);
+            }
+            self.slots[slot_id as usize - 1] = None;
+        }
+
+        self.usbcmd = 0;
+        self.usbsts = USBSTS_HCH;
+        self.dnctrl = 0;
+        self.crcr = 0;
+        self.dcbaap = 0;
+        self.config = 0;
+        self.command_ring = None;
+        self.command_blocked = false;
+        self.interrupter = Interrupter::default();
+
+        for index in 0..self.ports.len() {
+            let speed = self.ports[index].device.as_ref().map(|d| d.host.speed());
+            self.ports[index].portsc = match speed {
+                Some(speed) => self.connected_portsc(index, speed),
+                None => PORTSC_PP | (PLS_RX_DETECT << PORTSC_PLS_SHIFT),
+            };
+        }
+    }
+
+    // Only valid without any device attached, nothing being in flight then.
+    fn state(&self) -> XhciState {
+        XhciState {
+            usbcmd: self.usbcmd,
+            usbsts: self.usbsts,
+            dnctrl: self.dnctrl,
+            crcr: self.crcr,
+            dcbaap: self.dcbaap,
+            config: self.config,
+            command_ring: self.command_ring.map(|r| r.pointer()),
+            iman: self.interrupter.iman,
+            imod: self.interrupter.imod,
+            erstsz: self.interrupter.erstsz,
+            erstba: self.interrupter.erstba,
+            erdp: self.interrupter.erdp,
+            event_segments: self.interrupter.ring.segments.clone(),
+            event_segment: self.interrupter.ring.segment,
+            event_index: self.interrupter.ring.index,
+            event_cycle: self.interrupter.ring.cycle,
+            portsc: self.ports.iter().map(|p| p.portsc).collect(),
+            slots: self
+                .slots
+                .iter()
+                .map(|slot| {
+                    slot.as_ref().map(|slot| SlotState {
+                        port: slot.port,
+                        endpoints: slot
+                            .endpoints
+                            .iter()
+                            .map(|ep| EndpointState {
+                                state: ep.state,
+                                ep_type: ep.ep_type,
+                                ring: ep.ring.pointer(),
+                            })
+                            .collect(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn set_state(&mut self, state: &XhciState) {
+        self.usbcmd = state.usbcmd;
+        self.usbsts = state.usbsts;
+        self.dnctrl = state.dnctrl;
+        self.crcr = state.crcr;
+        self.dcbaap = state.dcbaap;
+        self.config = state.config;
+        self.command_ring = state.command_ring.map(RingCursor::new);
+        self.interrupter = Interrupter {
+            iman: state.iman,
+            imod: state.imod,
+            erstsz: state.erstsz,
+            erstba: state.erstba,
+            erdp: state.erdp,
+            ring: EventRing {
+                segments: state.event_segments.clone(),
+                segment: state.event_segment,
+                index: state.event_index,
+                cycle: state.event_cycle,
+            },
+        };
+        for (port, portsc) in self.ports.iter_mut().zip(state.portsc.iter()) {
+            port.portsc = *portsc;
+        }
+        for (slot, slot_state) in self.slots.iter_mut().zip(state.slots.iter()) {
+            *slot = slot_state.as_ref().map(|slot_state| {
+                let mut slot = Slot::new();
+                slot.port = slot_state.port;
+                for (ep, ep_state) in slot.endpoints.iter_mut().zip(&slot_state.endpoints) {
+                    ep.state = ep_state.state;
+                    ep.ep_type = ep_state.ep_type;
+                    ep.ring = RingCursor::new(ep_state.ring);
+                }
+                slot
+            });
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.usbcmd & USBCMD_RS != 0
+    }
+
+    fn is_usb3_port(&self, index: usize) -> bool {
+        index >= self.num_ports as usize
+    }
+
+    fn slot(&self, slot_id: u8) -> Option<&Slot> {
+        self.slots
+            .get((slot_id as usize).checked_sub(1)?)
+            .and_then(|s| s.as_ref())
+    }
+
+    fn slot_mut(&mut self, slot_id: u8) -> Option<&mut Slot> {
+        self.slots
+            .get_mut((slot_id as usize).checked_sub(1)?)
+            .and_then(|s| s.as_mut())
+    }
+
+    fn endpoint_mut(&mut self, slot_id: u8, dci: u8) -> Option<&mut Endpoint> {
+        self.slot_mut(slot_id)?.endpoints.get_mut(dci as usize)
+    }
+
+    fn device_mut(&mut self, slot_id: u8) -> Option<&mut UsbDevice> {
+        let port = self.slot(slot_id)?.port?;
+        self.ports[port].device.as_mut()
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let num_ports = self.num_ports as u64;
+        match offset {
+            REG_CAPLENGTH => CAPLENGTH | (HCIVERSION << 16),
+            REG_HCSPARAMS1 => MAX_SLOTS as u32 | (1 << 8) | ((2 * self.num_ports as u32) << 24),
+            REG_HCSPARAMS2 => ERST_MAX << 4,
+            REG_HCCPARAMS1 => HCCPARAMS1_AC64 | (((XECP_OFFSET >> 2) as u32) << 16),
+            REG_DBOFF => DOORBELL_OFFSET as u32,
+            REG_RTSOFF => RUNTIME_OFFSET as u32,
+            REG_USBCMD => self.usbcmd,
+            REG_USBSTS => self.usbsts,
+            // 4 KiB pages
+            REG_PAGESIZE => 1,
+            REG_DNCTRL => self.dnctrl,
+            REG_CRCR_LO => {
+                if self.command_ring.is_some() {
+                    CRCR_CRR
+                } else {
+                    0
+                }
+            }
+            REG_DCBAAP_LO => self.dcbaap as u32,
+            REG_DCBAAP_HI => (self.dcbaap >> 32) as u32,
+            REG_CONFIG => self.config,
+            o if (PORTS_OFFSET..PORTS_OFFSET + 2 * num_ports * PORT_REGS_SIZE).contains(&o) => {
+                if (o - PORTS_OFFSET) % PORT_REGS_SIZE == 0 {
+                    self.ports[((o - PORTS_OFFSET) / PORT_REGS_SIZE) as usize].portsc
+                } else {
+                    0
+                }
+            }
+            REG_MFINDEX => {
+                if self.running() {
+                    // 125 µs microframes
+                    ((self.started.elapsed().as_micros() / 125) & 0x3fff) as u32
+                } else {
+                    0
+                }
+            }
+            REG_IMAN => self.interrupter.iman,
+            REG_IMOD => self.interrupter.imod,
+            REG_ERSTSZ => self.interrupter.erstsz,
+            REG_ERSTBA_LO => self.interrupter.erstba as u32,
+            REG_ERSTBA_HI => (self.interrupter.erstba >> 32) as u32,
+            REG_ERDP_LO => self.interrupter.erdp as u32,
+            REG_ERDP_HI => (self.interrupter.erdp >> 32) as u32,
+            o if (XECP_OFFSET..XECP_OFFSET + XECP_SIZE).contains(&o) => {
+                let num_ports = self.num_ports as u32;
+                match o - XECP_OFFSET {
+                    0x00 => XECP_SUPPORTED_PROTOCOL | (4 << 8) | (0x0200 << 16),
+                    0x04 | 0x14 => XECP_NAME_USB,
+                    0x08 => 1 | (num_ports << 8),
+                    0x10 => XECP_SUPPORTED_PROTOCOL | (0x0300 << 16),
+                    0x18 => (num_ports + 1) | (num_ports << 8),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let num_ports = self.num_ports as u64;
+        match offset {
+            REG_USBCMD => self.write_usbcmd(value),
+            REG_USBSTS => self.usbsts &= !(value & USBSTS_RW1C),
+            REG_DNCTRL => self.dnctrl = value & 0xffff,
+            REG_CRCR_LO => self.write_crcr(value),
+            REG_CRCR_HI => {
+                if self.command_ring.is_none() {
+                    self.crcr = (self.crcr & 0xffff_ffff) | (value as u64) << 32;
+                }
+            }
+            REG_DCBAAP_LO => {
+                self.dcbaap = (self.dcbaap & !0xffff_ffff) | (value & !0x3f) as u64;
+            }
+            REG_DCBAAP_HI => self.dcbaap = (self.dcbaap & 0xffff_ffff) | (value as u64) << 32,
+            REG_CONFIG => self.config = value & 0x3ff,
+            o if (PORTS_OFFSET..PORTS_OFFSET + 2 * num_ports * PORT_REGS_SIZE).contains(&o) => {
+                if (o - PORTS_OFFSET) % PORT_REGS_SIZE == 0 {
+                    self.write_portsc(((o - PORTS_OFFSET) / PORT_REGS_SIZE) as usize, value);
+                }
+            }
+            REG_IMAN => {
+                let iman = &mut self.interrupter.iman;
+                *iman = (*iman & !(value & IMAN_IP) & !IMAN_IE) | (value & IMAN_IE);
+            }
+            REG_IMOD => self.interrupter.imod = value,
+            REG_ERSTSZ => self.interrupter.erstsz = value & 0xffff,
+            REG_ERSTBA_LO => {
+                let erstba = &mut self.interrupter.erstba;
+                *erstba = (*erstba & !0xffff_ffff) | (value & !0x3f) as u64;
+            }
+            REG_ERSTBA_HI => {
+                let erstba = &mut self.interrupter.erstba;
+                *erstba = (*erstba & 0xffff_ffff) | (value as u64) << 32;
+                // The high dword is written last when setting up the ring.
+                self.reset_event_ring();
+            }
+            REG_ERDP_LO => {
+                let erdp = &mut self.interrupter.erdp;
+                let ehb = *erdp & ERDP_EHB & !(value as u64);
+                *erdp = (*erdp & !0xffff_ffff) | (value as u64 & !0xf) | (value as u64 & 0x7) | ehb;
+                self.check_pending_events();
+            }
+            REG_ERDP_HI => {
+                let erdp = &mut self.interrupter.erdp;
+                *erdp = (*erdp & 0xffff_ffff) | (value as u64) << 32;
+                self.check_pending_events();
+            }
+            o if (DOORBELL_OFFSET..DOORBELL_OFFSET + 4 * (MAX_SLOTS as u64 + 1)).contains(&o) => {
+                self.ring_doorbell(((o - DOORBELL_OFFSET) / 4) as u8, value);
+            }
+            o => debug!("Unhandled xHCI register write at 0x{:x}: 0x{:x}", o, value),
+        }
+    }
+
+    fn write_usbcmd(&mut self, value: u32) {
+        if value & USBCMD_HCRST != 0 {
+            self.reset();
+            return;
+        }
+
+        let was_running = self.running();
+        self.usbcmd = value & !(USBCMD_HCRST | USBCMD_CSS | USBCMD_CRS);
+        if self.running() && !was_running {
+            self.usbsts &= !USBSTS_HCH;
+            self.started = Instant::now();
+        } else if !self.running() && was_running {
+            self.usbsts |= USBSTS_HCH;
+            if let Some(ring) = self.command_ring.take() {
+                self.crcr = ring.pointer();
+            }
+        }
+    }
+
+    fn write_crcr(&mut self, value: u32) {
+        match self.command_ring {
+            Some(ring) => {
+                if value & (CRCR_CS | CRCR_CA) != 0 {
+                    self.command_ring = None;
+                    self.crcr = ring.pointer();
+                    self.command_completion(ring.dequeue, COMPLETION_COMMAND_RING_STOPPED, 0);
+                }
+            }
+            None => {
+                self.crcr = (self.crcr & !0xffff_ffff) | (value as u64 & (!0x3f | CRCR_RCS));
+            }
+        }
+    }
+
+    fn reset_event_ring(&mut self) {
+        let mem = self.mem.memory();
+        let interrupter = &mut self.interrupter;
+        interrupter.ring = EventRing {
+            cycle: true,
+            ..Default::default()
+        };
+
+        for i in 0..interrupter.erstsz.min(1 << ERST_MAX) as u64 {
+            let entry = interrupter.erstba + i * 16;
+            let segment = mem
+                .read_obj::<u64>(GuestAddress(entry))
+                .and_then(|base| Ok((base, mem.read_obj::<u32>(GuestAddress(entry + 8))?)));
+            match segment {
+                Ok((base, size)) if size & 0xffff != 0 => interrupter
+                    .ring
+                    .segments
+                    .push((base & !0x3f, size & 0xffff)),
+                Ok(_) => warn!("Empty xHCI event ring segment"),
+                Err(e) => error!("Failed reading xHCI event ring segment table: {}", e),
+            }
+        }
+    }
+
+    fn send_event(&mut self, parameter: u64, status: u32, control: u32) {
+        let ring = &mut self.interrupter.ring;
+        let Some(addr) = ring.enqueue_pointer() else {
+            warn!("xHCI event ring not set up, dropping event");
+            return;
+        };
+        let (segment, index, cycle) = (ring.segment, ring.index, ring.cycle);
+        ring.advance();
+        if ring.enqueue_pointer() == Some(self.interrupter.erdp & !0xf) {
+            (ring.segment, ring.index, ring.cycle) = (segment, index, cycle);
+            error!("xHCI event ring full, dropping event");
+            return;
+        }
+
+        let mut bytes = [0u8; TRB_SIZE as usize];
+        bytes[0..8].copy_from_slice(&parameter.to_le_bytes());
+        bytes[8..12].copy_from_slice(&status.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(control | cycle as u32).to_le_bytes());
+        // The cycle bit must be updated last, after the rest of the TRB.
+        let mem = self.mem.memory();
+        if let Err(e) = mem
+            .write_slice(&bytes[..12], GuestAddress(addr))
+            .and_then(|_| mem.write_slice(&bytes[12..], GuestAddress(addr + 12)))
+        {
+            error!("Failed writing xHCI event: {}", e);
+            return;
+        }
+
+        self.usbsts |= USBSTS_EINT;
+        self.interrupter.iman |= IMAN_IP;
+        if self.interrupter.erdp & ERDP_EHB == 0 {
+            self.interrupter.erdp |= ERDP_EHB;
+            self.signal_interrupt();
+        }
+    }
+
+    // Interrupt again once the guest is done handling events, if some are
+    // still pending.
+    fn check_pending_events(&mut self) {
+        let interrupter = &mut self.interrupter;
+        if interrupter.erdp & ERDP_EHB == 0
+            && interrupter
+                .ring
+                .enqueue_pointer()
+                .is_some_and(|p| p != interrupter.erdp & !0xf)
+        {
+            interrupter.erdp |= ERDP_EHB;
+            interrupter.iman |= IMAN_IP;
+            self.signal_interrupt();
+        }
+    }
+
+    fn signal_interrupt(&self) {
+        if self.usbcmd & USBCMD_INTE == 0 || self.interrupter.iman & IMAN_IE == 0 {
+            return;
+        }
+
+        let mut msix_config = self.msix_config.lock().unwrap();
+        if !msix_config.enabled() {
+            return;
+        }
+        // The Pending Bit Array is updated rather than injecting the
+        // interrupt when the vector is masked.
+        if msix_config.masked() || msix_config.table_entries[0].masked() {
+            msix_config.set_pba_bit(0, false);
+            return;
+        }
+        if let Err(e) = self.interrupt.trigger(0) {
+            error!("Failed signalling xHCI interrupt: {}", e);
+        }
+    }
+
+    fn command_completion(&mut self, addr: u64, code: u32, slot_id: u8) {
+        self.send_event(
+            addr,
+            code << 24,
+            (slot_id as u32) << 24 | TRB_COMMAND_COMPLETION << 10,
+        );
+    }
+
+    fn transfer_event(&mut self, slot_id: u8, dci: u8, event: (u64, u32, usize, bool)) {
+        let (parameter, code, residual, event_data) = event;
+        let mut control = (slot_id as u32) << 24 | (dci as u32) << 16 | TRB_TRANSFER_EVENT << 10;
+        if event_data {
+            control |= TRB_EVENT_DATA_FLAG;
+        }
+        self.send_event(
+            parameter,
+            code << 24 | (residual as u32 & 0xff_ffff),
+            control,
+        );
+    }
+
+    fn port_changed(&mut self, index: usize) {
+        self.usbsts |= USBSTS_PCD;
+        if self.running() {
+            self.send_event(
+                (index as u64 + 1) << 24,
+                COMPLETION_SUCCESS << 24,
+                TRB_PORT_STATUS_CHANGE << 10,
+            );
+        }
+    }
+
+    fn connected_portsc(&self, index: usize, speed: UsbSpeed) -> u32 {
+        let speed_id = match speed {
+            UsbSpeed::Full => 1,
+            UsbSpeed::Low => 2,
+            UsbSpeed::High => 3,
+            UsbSpeed::Super => 4,
+        };
+        let portsc = PORTSC_CCS | PORTSC_PP | PORTSC_CSC | (speed_id << PORTSC_SPEED_SHIFT);
+        // USB 3.0 ports are enabled right away, USB 2.0 ones after a reset.
+        if self.is_usb3_port(index) {
+            portsc | PORTSC_PED | (PLS_U0 << PORTSC_PLS_SHIFT)
+        } else {
+            portsc | (PLS_POLLING << PORTSC_PLS_SHIFT)
+        }
+    }
+
+    fn write_portsc(&mut self, index: usize, value: u32) {
+        let usb3 = self.is_usb3_port(index);
+        let old = self.ports[index].portsc;
+        let mut portsc = old & !(value & PORTSC_CHANGE_BITS);
+        // Writing 1 disables the port.
+        if value & PORTSC_PED != 0 {
+            portsc &= !PORTSC_PED;
+        }
+        portsc = (portsc & !PORTSC_WAKE_BITS) | (value & PORTSC_WAKE_BITS);
+
+        let mut changed = false;
+        if value & PORTSC_LWS != 0 && portsc & PORTSC_CCS != 0 {
+            let old_pls = (old & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT;
+            let pls = (value & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT;
+            if matches!(pls, PLS_U0 | PLS_U3 | PLS_RESUME) {
+                portsc = (portsc & !PORTSC_PLS_MASK) | (pls << PORTSC_PLS_SHIFT);
+                if pls == PLS_U0 && matches!(old_pls, PLS_U3 | PLS_RESUME) {
+                    portsc |= PORTSC_PLC;
+                    changed = true;
+                }
+            }
+        }
+        self.ports[index].portsc = portsc;
+
+        if value & PORTSC_PR != 0 || (usb3 && value & PORTSC_WPR != 0) {
+            self.reset_port(index, value & PORTSC_WPR != 0);
+        } else if changed {
+            self.port_changed(index);
+        }
+    }
+
+    fn reset_port(&mut self, index: usize, warm: bool) {
+        let port = &mut self.ports[index];
+        if let Some(device) = port.device.as_mut() {
+            // The host device only needs to be reset once it has been used
+            // by the guest, the first reset being part of the enumeration.
+            if device.slot.is_some() {
+                if let Err(e) = device.host.reset() {
+                    warn!("Failed resetting USB device {}: {}", device.id, e);
+                }
+            }
+            port.portsc =
+                (port.portsc & !PORTSC_PLS_MASK) | PORTSC_PED | (PLS_U0 << PORTSC_PLS_SHIFT);
+        }
+        port.portsc |= PORTSC_PRC;
+        if warm {
+            port.portsc |= PORTSC_WRC;
+        }
+        self.port_changed(index);
+    }
+
+    fn free_port(&self, speed: UsbSpeed) -> Option<usize> {
+        let num_ports = self.num_ports as usize;
+        let mut ports = if speed == UsbSpeed::Super {
+            num_ports..2 * num_ports
+        } else {
+            0..num_ports
+        };
+        // Both ports of a pair are exposed to the guest, a device being
+        // connected to a single one of them.
+        ports.find(|&index| {
+            self.ports[index % num_ports].device.is_none()
+                && self.ports[index % num_ports + num_ports].device.is_none()
+        })
+    }
+
+    fn attach(&mut self, index: usize, id: String, host: UsbHostDevice) {
+        let speed = host.speed();
+        self.ports[index].device = Some(UsbDevice {
+            id,
+            host,
+            slot: None,
+            urbs: HashMap::new(),
+        });
+        self.ports[index].portsc = self.connected_portsc(index, speed);
+        self.port_changed(index);
+    }
+
+    fn detach(&mut self, index: usize) -> Option<UsbDevice> {
+        let device = self.ports[index].device.take()?;
+
+        // The URBs of the device are gone with it.
+        if let Some(slot_id) = device.slot {
+            for dci in 1..MAX_ENDPOINTS as u8 {
+                let Some(ep) = self.endpoint_mut(slot_id, dci) else {
+                    continue;
+                };
+                if let Some(td) = ep.in_flight.front() {
+                    ep.ring = td.start;
+                }
+                ep.in_flight.clear();
+                ep.discarded.clear();
+                if ep.stop_command.is_some() {
+                    self.complete_stop(slot_id, dci);
+                }
+            }
+        }
+
+        self.ports[index].portsc = PORTSC_PP | PORTSC_CSC | (PLS_RX_DETECT << PORTSC_PLS_SHIFT);
+        self.port_changed(index);
+
+        Some(device)
+    }
+
+    fn ring_doorbell(&mut self, target: u8, value: u32) {
+        if target == 0 {
+            if value & 0xff == 0 {
+                self.process_commands();
+            }
+            return;
+        }
+
+        let dci = (value & 0xff) as u8;
+        if !(1..MAX_ENDPOINTS as u8).contains(&dci) {
+            return;
+        }
+        let Some(ep) = self.endpoint_mut(target, dci) else {
+            return;
+        };
+        if ep.stop_command.is_some() {
+            return;
+        }
+        match ep.state {
+            EP_RUNNING => {}
+            EP_STOPPED => {
+                ep.state = EP_RUNNING;
+                self.update_endpoint_context(target, dci);
+            }
+            _ => return,
+        }
+
+        self.kick_endpoint(target, dci);
+    }
+
+    fn process_commands(&mut self) {
+        if !self.running() {
+            return;
+        }
+        let mem = self.mem.memory();
+        let mut ring = self
+            .command_ring
+            .unwrap_or_else(|| RingCursor::new(self.crcr));
+        self.command_ring = Some(ring);
+
+        while !self.command_blocked && self.command_ring.is_some() {
+            let trb = match ring.next(&mem) {
+                Ok(Some(trb)) => trb,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed reading xHCI command ring: {}", e);
+                    break;
+                }
+            };
+            self.command_ring = Some(ring);
+            self.process_command(&trb);
+        }
+    }
+
+    fn process_command(&mut self, trb: &Trb) {
+        let slot_id = trb.slot_id();
+        let result = match trb.trb_type() {
+            TRB_ENABLE_SLOT => {
+                let (code, slot_id) = self.enable_slot();
+                self.command_completion(trb.addr, code, slot_id);
+                return;
+            }
+            TRB_DISABLE_SLOT => Ok(self.disable_slot(slot_id)),
+            TRB_ADDRESS_DEVICE => self.address_device(trb),
+            TRB_CONFIGURE_ENDPOINT => self.configure_endpoint(trb),
+            TRB_EVALUATE_CONTEXT => self.evaluate_context(trb),
+            TRB_RESET_ENDPOINT => Ok(self.reset_endpoint(trb)),
+            TRB_STOP_ENDPOINT => match self.stop_endpoint(trb) {
+                Some(code) => Ok(code),
+                // Completed once the transfers have been cancelled.
+                None => return,
+            },
+            TRB_SET_TR_DEQUEUE => Ok(self.set_tr_dequeue(trb)),
+            TRB_RESET_DEVICE => self.reset_device(slot_id),
+            TRB_NOOP_COMMAND => Ok(COMPLETION_SUCCESS),
+            t => {
+                debug!("Unsupported xHCI command {}", t);
+                Ok(COMPLETION_TRB_ERROR)
+            }
+        };
+
+        let code = result.unwrap_or_else(|e| {
+            error!("Failed accessing xHCI contexts: {}", e);
+            COMPLETION_TRB_ERROR
+        });
+        self.command_completion(trb.addr, code, slot_id);
+    }
+
+    fn output_context(&self, slot_id: u8) -> Result<u64> {
+        let mem = self.mem.memory();
+        let addr = mem.read_obj::<u64>(GuestAddress(self.dcbaap + 8 * slot_id as u64))?;
+        Ok(addr & !0x3f)
+    }
+
+    fn update_endpoint_context(&self, slot_id: u8, dci: u8) {
+        let Some(ep) = self.slot(slot_id).map(|s| &s.endpoints[dci as usize]) else {
+            return;
+        };
+        let (state, pointer) = (ep.state, ep.ring.pointer());
+
+        let result = self.output_context(slot_id).and_then(|output| {
+            let mem = self.mem.memory();
+            let addr = output + dci as u64 * CONTEXT_SIZE;
+            let mut context = read_context(&mem, addr)?;
+            context[0] = (context[0] & !EP_STATE_MASK) | state;
+            if state != EP_DISABLED {
+                context[2] = pointer as u32;
+                context[3] = (pointer >> 32) as u32;
+            }
+            write_context(&mem, addr, &context)
+        });
+        if let Err(e) = result {
+            error!("Failed updating xHCI endpoint context: {}", e);
+        }
+    }
+
+    fn update_slot_context(&self, slot_id: u8, update: impl FnOnce(&mut [u32; 8])) -> Result<()> {
+        let mem = self.mem.memory();
+        let output = self.output_context(slot_id)?;
+        let mut context = read_context(&mem, output)?;
+        update(&mut context);
+        write_context(&mem, output, &context)
+    }
+
+    fn enable_slot(&mut self) -> (u32, u8) {
+        let max_slots = (self.config & 0xff).min(MAX_SLOTS as u32) as u8;
+        match (1..=max_slots).find(|&id| self.slots[id as usize - 1].is_none()) {
+            Some(slot_id) => {
+                self.slots[slot_id as usize - 1] = Some(Slot::new());
+                (COMPLETION_SUCCESS, slot_id)
+            }
+            None => (COMPLETION_NO_SLOTS, 0),
+        }
+    }
+
+    // Cancel the transfers of an endpoint, moving it back to the first TD
+    // which did not complete.
+    fn discard_transfers(&mut self, slot_id: u8, dci: u8) {
+        let port = self.slot(slot_id).and_then(|s| s.port);
+        let Some(ep) = (slot_id as usize)
+            .checked_sub(1)
+            .and_then(|i| self.slots.get_mut(i))
+            .and_then(|s| s.as_mut())
+            .map(|s| &mut s.endpoints[dci as usize])
+        else {
+            return;
+        };
+        let device = port.and_then(|p| self.ports[p].device.as_mut());
+
+        if let Some(td) = ep.in_flight.front() {
+            ep.ring = td.start;
+        }
+        for td in ep.in_flight.drain(..) {
+            if let Some(device) = device.as_ref() {
+                if device.urbs.contains_key(&td.token) {
+                    ep.discarded.push(td.token);
+                }
+            }
+        }
+        if let Some(device) = device {
+            for token in ep.discarded.iter() {
+                device.host.discard(*token);
+            }
+        }
+    }
+
+    fn release_slot(&mut self, slot_id: u8) {
+        for dci in 1..MAX_ENDPOINTS as u8 {
+            self.discard_transfers(slot_id, dci);
+        }
+        if let Some(device) = self.device_mut(slot_id) {
+            if device.slot == Some(slot_id) {
+                device.slot = None;
+            }
+        }
+    }
+
+    fn disable_slot(&mut self, slot_id: u8) -> u32 {
+        if self.slot(slot_id).is_none() {
+            return COMPLETION_SLOT_NOT_ENABLED;
+        }
+        self.release_slot(slot_id);
+        self.slots[slot_id as usize - 1] = None;
+        COMPLETION_SUCCESS
+    }
+
+    fn disable_endpoint(&mut self, slot_id: u8, dci: u8) {
+        self.discard_transfers(slot_id, dci);
+        if let Some(ep) = self.endpoint_mut(slot_id, dci) {
+            *ep = Endpoint::default();
+        }
+        self.update_endpoint_context(slot_id, dci);
+    }
+
+    fn address_device(&mut self, trb: &Trb) -> Result<u32> {
+        let slot_id = trb.slot_id();
+        if self.slot(slot_id).is_none() {
+            return Ok(COMPLETION_SLOT_NOT_ENABLED);
+        }
+
+        let mem = self.mem.memory();
+        let input = trb.parameter & !0xf;
+        let control = read_context(&mem, input)?;
+        if control[1] & 0x3 != 0x3 {
+            return Ok(COMPLETION_PARAMETER_ERROR);
+        }
+        let mut slot_context = read_context(&mem, input + CONTEXT_SIZE)?;
+        let mut ep0_context = read_context(&mem, input + 2 * CONTEXT_SIZE)?;
+
+        // Devices are connected to root hub ports only.
+        let port_id = ((slot_context[1] >> 16) & 0xff) as usize;
+        if slot_context[0] & 0xf_ffff != 0 || !(1..=self.ports.len()).contains(&port_id) {
+            return Ok(COMPLETION_PARAMETER_ERROR);
+        }
+        let index = port_id - 1;
+        if self.ports[index].device.is_none() {
+            return Ok(COMPLETION_TRANSACTION_ERROR);
+        }
+
+        // The host device already has an address, which does not need to
+        // match the one seen by the guest.
+        let (state, address) = if trb.has(TRB_BSR) {
+            (SLOT_DEFAULT, 0)
+        } else {
+            (SLOT_ADDRESSED, slot_id as u32)
+        };
+        slot_context[3] = (state << SLOT_STATE_SHIFT) | address;
+        ep0_context[0] = (ep0_context[0] & !EP_STATE_MASK) | EP_RUNNING;
+
+        let output = self.output_context(slot_id)?;
+        write_context(&mem, output, &slot_context)?;
+        write_context(&mem, output + CONTEXT_SIZE, &ep0_context)?;
+
+        if let Some(previous) = self.slot(slot_id).and_then(|s| s.port) {
+            if previous != index {
+                self.release_slot(slot_id);
+            }
+        }
+        self.discard_transfers(slot_id, 1);
+        let slot = self.slot_mut(slot_id).unwrap();
+        slot.port = Some(index);
+        slot.endpoints[1] = Endpoint::new(&ep0_context);
+        self.ports[index].device.as_mut().unwrap().slot = Some(slot_id);
+
+        Ok(COMPLETION_SUCCESS)
+    }
+
+    fn configure_endpoint(&mut self, trb: &Trb) -> Result<u32> {
+        let slot_id = trb.slot_id();
+        let Some(slot) = self.slot(slot_id) else {
+            return Ok(COMPLETION_SLOT_NOT_ENABLED);
+        };
+        if slot.port.is_none() {
+            return Ok(COMPLETION_CONTEXT_STATE_ERROR);
+        }
+
+        if trb.has(TRB_DC) {
+            for dci in 2..MAX_ENDPOINTS as u8 {
+                self.disable_endpoint(slot_id, dci);
+            }
+            self.update_slot_context(slot_id, |context| {
+                context[3] = (context[3] & !SLOT_STATE_MASK) | (SLOT_ADDRESSED << SLOT_STATE_SHIFT);
+            })?;
+            return Ok(COMPLETION_SUCCESS);
+        }
+
+        let mem = self.mem.memory();
+        let input = trb.parameter & !0xf;
+        let control = read_context(&mem, input)?;
+        let input_slot_context = read_context(&mem, input + CONTEXT_SIZE)?;
+        let output = self.output_context(slot_id)?;
+
+        for dci in 2..MAX_ENDPOINTS as u8 {
+            if control[0] & (1 << dci) != 0 {
+                self.disable_endpoint(slot_id, dci);
+            }
+            if control[1] & (1 << dci) != 0 {
+                let mut context = read_context(&mem, input + (dci as u64 + 1) * CONTEXT_SIZE)?;
+                context[0] = (context[0] & !EP_STATE_MASK) | EP_RUNNING;
+                write_context(&mem, output + dci as u64 * CONTEXT_SIZE, &context)?;
+                self.discard_transfers(slot_id, dci);
+                *self.endpoint_mut(slot_id, dci).unwrap() = Endpoint::new(&context);
+            }
+        }
+
+        let configured = self.slot(slot_id).unwrap().endpoints[2..]
+            .iter()
+            .any(|ep| ep.state != EP_DISABLED);
+        let state = if configured {
+            SLOT_CONFIGURED
+        } else {
+            SLOT_ADDRESSED
+        };
+        self.update_slot_context(slot_id, |context| {
+            context[0] = (context[0] & !CONTEXT_ENTRIES_MASK)
+                | (input_slot_context[0] & CONTEXT_ENTRIES_MASK);
+            context[3] = (context[3] & !SLOT_STATE_MASK) | (state << SLOT_STATE_SHIFT);
+        })?;
+
+        Ok(COMPLETION_SUCCESS)
+    }
+
+    fn evaluate_context(&mut self, trb: &Trb) -> Result<u32> {
+        let slot_id = trb.slot_id();
+        if self.slot(slot_id).is_none() {
+            return Ok(COMPLETION_SLOT_NOT_ENABLED);
+        }
+
+        let mem = self.mem.memory();
+        let input = trb.parameter & !0xf;
+        let control = read_context(&mem, input)?;
+        let output = self.output_context(slot_id)?;
+
+        // Only the interrupter target and maximum exit latency of the slot,
+        // and the maximum packet size of the default control endpoint can
+        // be evaluated.
+        if control[1] & (1 << 0) != 0 {
+            let input_context = read_context(&mem, input + CONTEXT_SIZE)?;
+            let mut context = read_context(&mem, output)?;
+            context[1] = (context[1] & !0xffff) | (input_context[1] & 0xffff);
+            context[2] = (context[2] & !(0x3ff << 22)) | (input_context[2] & (0x3ff << 22));
+            write_context(&mem, output, &context)?;
+        }
+        if control[1] & (1 << 1) != 0 {
+            let input_context = read_context(&mem, input + 2 * CONTEXT_SIZE)?;
+            let mut context = read_context(&mem, output + CONTEXT_SIZE)?;
+            context[1] = (context[1] & 0xffff) | (input_context[1] & 0xffff_0000);
+            write_context(&mem, output + CONTEXT_SIZE, &context)?;
+        }
+
+        Ok(COMPLETION_SUCCESS)
+    }
+
+    fn reset_endpoint(&mut self, trb: &Trb) -> u32 {
+        let (slot_id, dci) = (trb.slot_id(), trb.endpoint_id());
+        if self.slot(slot_id).is_none() {
+            return COMPLETION_SLOT_NOT_ENABLED;
+        }
+        match self.endpoint_mut(slot_id, dci) {
+            Some(ep) if ep.state == EP_HALTED => ep.state = EP_STOPPED,
+            _ => return COMPLETION_CONTEXT_STATE_ERROR,
+        }
+        self.update_endpoint_context(slot_id, dci);
+        COMPLETION_SUCCESS
+    }
+
+    fn stop_endpoint(&mut self, trb: &Trb) -> Option<u32> {
+        let (slot_id, dci) = (trb.slot_id(), trb.endpoint_id());
+        if self.slot(slot_id).is_none() {
+            return Some(COMPLETION_SLOT_NOT_ENABLED);
+        }
+        match self.endpoint_mut(slot_id, dci) {
+            Some(ep) if ep.state == EP_RUNNING => ep.state = EP_STOPPED,
+            _ => return Some(COMPLETION_CONTEXT_STATE_ERROR),
+        }
+
+        self.discard_transfers(slot_id, dci);
+        let ep = self.endpoint_mut(slot_id, dci).unwrap();
+        if !ep.discarded.is_empty() {
+            ep.stop_command = Some(trb.addr);
+            self.command_blocked = true;
+            return None;
+        }
+
+        self.update_endpoint_context(slot_id, dci);
+        Some(COMPLETION_SUCCESS)
+    }
+
+    fn complete_stop(&mut self, slot_id: u8, dci: u8) {
+        let Some(addr) = self
+            .endpoint_mut(slot_id, dci)
+            .and_then(|ep| ep.stop_command.take())
+        else {
+            return;
+        };
+        self.update_endpoint_context(slot_id, dci);
+        self.command_completion(addr, COMPLETION_SUCCESS, slot_id);
+        self.command_blocked = false;
+        self.process_commands();
+    }
+
+    fn set_tr_dequeue(&mut self, trb: &Trb) -> u32 {
+        let (slot_id, dci) = (trb.slot_id(), trb.endpoint_id());
+        if self.slot(slot_id).is_none() {
+            return COMPLETION_SLOT_NOT_ENABLED;
+        }
+        match self.endpoint_mut(slot_id, dci) {
+            Some(ep) if matches!(ep.state, EP_STOPPED | EP_ERROR) => {
+                ep.ring = RingCursor::new(trb.parameter);
+            }
+            _ => return COMPLETION_CONTEXT_STATE_ERROR,
+        }
+        self.update_endpoint_context(slot_id, dci);
+        COMPLETION_SUCCESS
+    }
+
+    fn reset_device(&mut self, slot_id: u8) -> Result<u32> {
+        match self.slot(slot_id) {
+            None => return Ok(COMPLETION_SLOT_NOT_ENABLED),
+            Some(slot) if slot.port.is_none() => return Ok(COMPLETION_CONTEXT_STATE_ERROR),
+            _ => {}
+        }
+
+        self.discard_transfers(slot_id, 1);
+        for dci in 2..MAX_ENDPOINTS as u8 {
+            self.disable_endpoint(slot_id, dci);
+        }
+        self.update_slot_context(slot_id, |context| {
+            context[0] = (context[0] & !CONTEXT_ENTRIES_MASK) | (1 << 27);
+            context[3] = SLOT_DEFAULT << SLOT_STATE_SHIFT;
+        })?;
+
+        Ok(COMPLETION_SUCCESS)
+    }
+
+    // Submit the TDs available on the transfer ring of an endpoint.
+    fn kick_endpoint(&mut self, slot_id: u8, dci: u8) {
+        let mem = self.mem.memory();
+        loop {
+            let Some(ep) = self.endpoint_mut(slot_id, dci) else {
+                return;
+            };
+            if ep.state != EP_RUNNING
+                || ep.stop_command.is_some()
+                || ep.in_flight.len() >= MAX_IN_FLIGHT
+            {
+                return;
+            }
+
+            let start = ep.ring;
+            let mut ring = start;
+            let mut trbs = Vec::new();
+            loop {
+                match ring.next(&mem) {
+                    Ok(Some(trb)) => {
+                        trbs.push(trb);
+                        if !trb.has(TRB_CHAIN) || trbs.len() == MAX_TD_TRBS {
+                            break;
+                        }
+                    }
+                    // The TD is not complete yet.
+                    Ok(None) => return,
+                    Err(e) => {
+                        error!("Failed reading xHCI transfer ring: {}", e);
+                        return;
+                    }
+                }
+            }
+            ep.ring = ring;
+
+            let td = Td {
+                trbs,
+                start,
+                end: ring,
+                control: ep.ep_type == EP_TYPE_CONTROL,
+                token: 0,
+            };
+            if !self.submit_td(slot_id, dci, td) {
+                self.endpoint_mut(slot_id, dci).unwrap().ring = start;
+                return;
+            }
+        }
+    }
+
+    // Submit a TD to the host device, returning false if it needs to wait
+    // for the TDs in flight to complete.
+    fn submit_td(&mut self, slot_id: u8, dci: u8, mut td: Td) -> bool {
+        let busy = !self
+            .endpoint_mut(slot_id, dci)
+            .unwrap()
+            .in_flight
+            .is_empty();
+
+        // Errors are only reported once the previous TDs have completed, so
+        // that the endpoint is halted on the failing TD.
+        let (transfer_type, endpoint, buffer) = match self.prepare_td(slot_id, dci, &td, busy) {
+            TdAction::Submit(transfer_type, endpoint, buffer) => (transfer_type, endpoint, buffer),
+            TdAction::Complete(_) | TdAction::Defer if busy => return false,
+            TdAction::Complete(code) => {
+                self.complete_td(slot_id, dci, td, code, &[], 0);
+                return true;
+            }
+            TdAction::Defer => return false,
+        };
+
+        let Some(device) = self.device_mut(slot_id) else {
+            if busy {
+                return false;
+            }
+            self.complete_td(slot_id, dci, td, COMPLETION_TRANSACTION_ERROR, &[], 0);
+            return true;
+        };
+        match device.host.submit(transfer_type, endpoint, buffer) {
+            Ok(token) => {
+                device.urbs.insert(token, dci);
+                td.token = token;
+                self.endpoint_mut(slot_id, dci)
+                    .unwrap()
+                    .in_flight
+                    .push_back(td);
+            }
+            Err(_) if busy => return false,
+            Err(e) => {
+                debug!("Failed submitting URB to USB device {}: {}", device.id, e);
+                self.complete_td(slot_id, dci, td, COMPLETION_TRANSACTION_ERROR, &[], 0);
+            }
+        }
+
+        true
+    }
+
+    fn prepare_td(&mut self, slot_id: u8, dci: u8, td: &Td, busy: bool) -> TdAction {
+        let ep_type = self.endpoint_mut(slot_id, dci).unwrap().ep_type;
+        let (transfer_type, endpoint, mut dir_in) = match ep_type {
+            EP_TYPE_CONTROL => (TransferType::Control, 0, false),
+            EP_TYPE_BULK_OUT => (TransferType::Bulk, dci / 2, false),
+            EP_TYPE_BULK_IN => (TransferType::Bulk, 0x80 | dci / 2, true),
+            EP_TYPE_INTERRUPT_OUT => (TransferType::Interrupt, dci / 2, false),
+            EP_TYPE_INTERRUPT_IN => (TransferType::Interrupt, 0x80 | dci / 2, true),
+            t => {
+                debug!("Unsupported xHCI endpoint type {}", t);
+                return TdAction::Complete(COMPLETION_TRB_ERROR);
+            }
+        };
+
+        let mut buffer = Vec::new();
+        if td.control {
+            let setup = match td.trbs.first() {
+                Some(trb) if trb.trb_type() == TRB_SETUP_STAGE && trb.has(TRB_IDT) => {
+                    trb.parameter.to_le_bytes()
+                }
+                _ => return TdAction::Complete(COMPLETION_TRB_ERROR),
+            };
+
+            if is_standard_request(&setup) {
+                // Handled synchronously, once the previous transfers are done.
+                if busy {
+                    return TdAction::Defer;
+                }
+                return match self.standard_request(slot_id, &setup) {
+                    Ok(()) => TdAction::Complete(COMPLETION_SUCCESS),
+                    Err(e) => {
+                        debug!("USB standard request failed: {}", e);
+                        TdAction::Complete(COMPLETION_STALL)
+                    }
+                };
+            }
+
+            dir_in = setup[0] & 0x80 != 0;
+            let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+            buffer.extend_from_slice(&setup);
+            if dir_in {
+                buffer.resize(USB_SETUP_SIZE + length.max(td.data_length()), 0);
+            }
+        } else if dir_in {
+            buffer.resize(td.data_length(), 0);
+        }
+
+        if !dir_in {
+            let mem = self.mem.memory();
+            for trb in td.data_trbs() {
+                let length = trb.transfer_length();
+                if trb.has(TRB_IDT) {
+                    buffer.extend_from_slice(&trb.parameter.to_le_bytes()[..length.min(8)]);
+                    continue;
+                }
+                let offset = buffer.len();
+                buffer.resize(offset + length, 0);
+                if let Err(e) = mem.read_slice(&mut buffer[offset..], GuestAddress(trb.parameter)) {
+                    error!("Failed reading USB transfer data: {}", e);
+                    return TdAction::Complete(COMPLETION_TRB_ERROR);
+                }
+            }
+        }
+
+        TdAction::Submit(transfer_type, endpoint, buffer)
+    }
+
+    // Standard requests changing the state of the host device need to go
+    // through usbfs rather than being submitted as control transfers.
+    fn standard_request(&mut self, slot_id: u8, setup: &[u8; USB_SETUP_SIZE]) -> io::Result<()> {
+        let request = setup[1];
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+
+        let Some(device) = self.device_mut(slot_id) else {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV));
+        };
+        match request {
+            USB_REQ_SET_CONFIGURATION => device.host.set_configuration(value as u8),
+            USB_REQ_SET_INTERFACE => device.host.set_interface(index, value),
+            _ => device.host.clear_halt(index as u8),
+        }
+    }
+
+    // Generate the events of a completed TD, halting the endpoint on error.
+    fn complete_td(&mut self, slot_id: u8, dci: u8, td: Td, code: u32, data: &[u8], actual: usize) {
+        let mem = self.mem.memory();
+
+        // Copy the data received to the buffers of the TD.
+        let mut remaining = &data[..actual.min(data.len())];
+        for trb in td.data_trbs() {
+            if remaining.is_empty() {
+                break;
+            }
+            let length = trb.transfer_length().min(remaining.len());
+            if let Err(e) = mem.write_slice(&remaining[..length], GuestAddress(trb.parameter)) {
+                error!("Failed writing USB transfer data: {}", e);
+                break;
+            }
+            remaining = &remaining[length..];
+        }
+
+        for event in td.events(code, actual) {
+            self.transfer_event(slot_id, dci, event);
+        }
+
+        if code != COMPLETION_SUCCESS {
+            let Some(ep) = self.endpoint_mut(slot_id, dci) else {
+                return;
+            };
+            ep.state = EP_HALTED;
+            self.discard_transfers(slot_id, dci);
+            // The endpoint is left on the failing TD.
+            self.endpoint_mut(slot_id, dci).unwrap().ring = td.start;
+            self.update_endpoint_context(slot_id, dci);
+        }
+    }
+
+    // Reap the URBs completed by the device connected to a port.
+    fn reap(&mut self, index: usize) {
+        loop {
+            let Some(device) = self.ports[index].device.as_mut() else {
+                return;
+            };
+            let urb = match device.host.reap() {
+                Ok(Some(urb)) => urb,
+                Ok(None) => return,
+                Err(e) => {
+                    if e.raw_os_error() != Some(libc::ENODEV) {
+                        error!("Failed reaping URB from USB device {}: {}", device.id, e);
+                    }
+                    return;
+                }
+            };
+
+            let Some(dci) = device.urbs.remove(&urb.token) else {
+                continue;
+            };
+            let Some(slot_id) = device.slot else {
+                continue;
+            };
+            let Some(ep) = self.endpoint_mut(slot_id, dci) else {
+                continue;
+            };
+
+            if let Some(pos) = ep.discarded.iter().position(|t| *t == urb.token) {
+                ep.discarded.swap_remove(pos);
+                if ep.discarded.is_empty() {
+                    self.complete_stop(slot_id, dci);
+                }
+                continue;
+            }
+            if ep.in_flight.front().map(|td| td.token) != Some(urb.token) {
+                debug!("Ignoring URB completed out of order");
+                continue;
+            }
+            let td = ep.in_flight.pop_front().unwrap();
+
+            let code = match -urb.status {
+                0 => COMPLETION_SUCCESS,
+                libc::EPIPE => COMPLETION_STALL,
+                libc::EOVERFLOW => COMPLETION_BABBLE,
+                _ => COMPLETION_TRANSACTION_ERROR,
+            };
+            // The data of control transfers follows the setup packet.
+            let data = if td.control {
+                &urb.buffer[USB_SETUP_SIZE.min(urb.buffer.len())..]
+            } else {
+                &urb.buffer[..]
+            };
+            self.complete_td(slot_id, dci, td, code, data, urb.actual_length);
+            self.kick_endpoint(slot_id, dci);
+        }
+    }
+
+    fn detach_by_id(&mut self, id: &str) -> Option<UsbDevice> {
+        let index = self
+            .ports
+            .iter()
+            .position(|p| p.device.as_ref().is_some_and(|d| d.id == id))?;
+        self.detach(index)
+    }
+}
+
+/// xHCI controller, to which host USB devices can be attached.
+pub struct XhciController {
+    id: String,
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    state: Arc<Mutex<Xhci>>,
+    epoll_file: File,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl XhciController {
+    /// Create an xHCI controller with `num_ports` USB 2.0 and USB 3.0 ports.
+    pub fn new(
+        id: String,
+        num_ports: u8,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_manager: &dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>,
+        pci_device_bdf: u32,
+        snapshot: Option<Snapshot>,
+    ) -> result::Result<Self, XhciError> {
+        assert!((1..=XHCI_MAX_PORTS).contains(&num_ports));
+
+        let msix_state =
+            vm_migration::state_from_id(snapshot.as_ref(), MSIX_CONFIG_ID).map_err(|e| {
+                XhciError::CreateXhciController(anyhow!(
+                    "Failed to get MsixConfigState from Snapshot: {}",
+                    e
+                ))
+            })?;
+        let pci_configuration_state =
+            vm_migration::state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID).map_err(|e| {
+                XhciError::CreateXhciController(anyhow!(
+                    "Failed to get PciConfigurationState from Snapshot: {}",
+                    e
+                ))
+            })?;
+        let xhci_state: Option<XhciState> = snapshot
+            .as_ref()
+            .map(|s| s.to_state())
+            .transpose()
+            .map_err(|e| {
+                XhciError::CreateXhciController(anyhow!(
+                    "Failed to get XhciState from Snapshot: {}",
+                    e
+                ))
+            })?;
+
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: MSIX_VECTORS as InterruptIndex,
+            })
+            .map_err(|e| {
+                XhciError::CreateXhciController(anyhow!(
+                    "Failed creating MSI interrupt group: {}",
+                    e
+                ))
+            })?;
+
+        let msix_config = Arc::new(Mutex::new(
+            MsixConfig::new(
+                MSIX_VECTORS,
+                interrupt_source_group.clone(),
+                pci_device_bdf,
+                msix_state,
+            )
+            .map_err(|e| {
+                XhciError::CreateXhciController(anyhow!("Failed creating MSI-X config: {:?}", e))
+            })?,
+        ));
+
+        let configuration = PciConfiguration::new(
+            XHCI_VENDOR_ID,
+            XHCI_DEVICE_ID,
+            0x1,
+            PciClassCode::SerialBusController,
+            &PciSerialBusSubClass::Usb,
+            Some(&XhciProgrammingInterface::Xhci),
+            PciHeaderType::Device,
+            0,
+            0,
+            Some(msix_config.clone()),
+            pci_configuration_state,
+        );
+
+        let mut xhci = Xhci::new(num_ports, mem, msix_config.clone(), interrupt_source_group);
+        if let Some(xhci_state) = xhci_state.as_ref() {
+            xhci.set_state(xhci_state);
+        }
+        let state = Arc::new(Mutex::new(xhci));
+
+        let epoll_fd = epoll::create(true).map_err(|e| {
+            XhciError::CreateXhciController(anyhow!("Failed creating epoll: {}", e))
+        })?;
+        // SAFETY: epoll_fd is a valid file descriptor we own.
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+            XhciError::CreateXhciController(anyhow!("Failed creating eventfd: {}", e))
+        })?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, KILL_EVENT),
+        )
+        .map_err(|e| {
+            XhciError::CreateXhciController(anyhow!("Failed registering eventfd: {}", e))
+        })?;
+
+        let worker_state = state.clone();
+        let worker_epoll = epoll_file
+            .try_clone()
+            .map_err(|e| XhciError::CreateXhciController(anyhow!("Failed cloning epoll: {}", e)))?;
+        let worker = thread::Builder::new()
+            .name(id.clone())
+            .spawn(move || run_worker(worker_state, worker_epoll))
+            .map_err(|e| {
+                XhciError::CreateXhciController(anyhow!("Failed spawning thread: {}", e))
+            })?;
+
+        Ok(XhciController {
+            id,
+            configuration,
+            bar_regions: vec![],
+            msix_config,
+            state,
+            epoll_file,
+            kill_evt,
+            worker: Some(worker),
+        })
+    }
+
+    /// Connect a host USB device to a free port of the controller.
+    pub fn attach_device(
+        &mut self,
+        id: String,
+        device: UsbHostDevice,
+    ) -> result::Result<(), XhciError> {
+        let mut state = self.state.lock().unwrap();
+        let index = state
+            .free_port(device.speed())
+            .ok_or(XhciError::NoFreePort)?;
+
+        // The usbfs file becomes writable when URBs can be reaped.
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            device.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLOUT, index as u64),
+        )
+        .map_err(XhciError::RegisterUsbDevice)?;
+
+        info!(
+            "Attaching USB device {} ({:?}) to xHCI port {}",
+            id,
+            device.path(),
+            index + 1
+        );
+        state.attach(index, id, device);
+
+        Ok(())
+    }
+
+    /// Disconnect the USB device with the given identifier, returning
+    /// whether it was attached to the controller.
+    pub fn detach_device(&mut self, id: &str) -> bool {
+        let device = self.state.lock().unwrap().detach_by_id(id);
+        // Closing the usbfs file gives the device back to the host.
+        device.is_some()
+    }
+
+    /// Whether a USB device with the given identifier is attached.
+    pub fn has_device(&self, id: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .ports
+            .iter()
+            .any(|p| p.device.as_ref().is_some_and(|d| d.id == id))
+    }
+}
+
+fn run_worker(state: Arc<Mutex<Xhci>>, epoll_file: File) {
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+    loop {
+        let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Failed waiting for USB devices: {}", e);
+                return;
+            }
+        };
+
+        for event in events.iter().take(num_events) {
+            let data = event.data;
+            if data == KILL_EVENT {
+                return;
+            }
+            let evset = epoll::Events::from_bits_truncate(event.events);
+            let mut state = state.lock().unwrap();
+            state.reap(data as usize);
+
+            // The device was unplugged from the host.
+            if evset.intersects(epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR) {
+                if let Some(device) = state.detach(data as usize) {
+                    warn!("USB device {} disconnected from the host", device.id);
+                    event!("usb", "device-disconnected", "id", &device.id);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for XhciController {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl BusDevice for XhciController {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for XhciController {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let mut bars = Vec::new();
+        let bar_id = 0;
+        let restoring = resources.is_some();
+        let bar_addr = mmio32_allocator
+            .allocate(None, XHCI_BAR_SIZE, Some(XHCI_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(XHCI_BAR_SIZE))?;
+
+        let bar = PciBarConfiguration::default()
+            .set_index(bar_id)
+            .set_address(bar_addr.raw_value())
+            .set_size(XHCI_BAR_SIZE)
+            .set_region_type(PciBarRegionType::Memory32BitRegion)
+            .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
+
+        debug!("xHCI bar address 0x{:x}", bar_addr.0);
+        if !restoring {
+            self.configuration
+                .add_pci_bar(&bar)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+
+            let msix_cap = MsixCap::new(
+                bar_id as u8,
+                MSIX_VECTORS,
+                MSIX_TABLE_OFFSET as u32,
+                bar_id as u8,
+                MSIX_PBA_OFFSET as u32,
+            );
+            self.configuration
+                .add_capability(&msix_cap)
+                .map_err(PciDeviceError::CapabilitiesSetup)?;
+        }
+
+        bars.push(bar);
+        self.bar_regions.clone_from(&bars);
+
+        Ok(bars)
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            mmio32_allocator.free(GuestAddress(bar.addr()), bar.size());
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if (MSIX_TABLE_OFFSET..MSIX_TABLE_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_table(o - MSIX_TABLE_OFFSET, data),
+            o if (MSIX_PBA_OFFSET..MSIX_PBA_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_pba(o - MSIX_PBA_OFFSET, data),
+            o => {
+                // Registers are dwords, the 64-bit ones being accessed as
+                // pairs of dwords or as a qword.
+                let state = self.state.lock().unwrap();
+                let aligned = o & !0x3;
+                let mut value = state.read_register(aligned) as u64;
+                if data.len() > 4 {
+                    value |= (state.read_register(aligned + 4) as u64) << 32;
+                }
+                let bytes = (value >> ((o & 0x3) * 8)).to_le_bytes();
+                let len = data.len().min(bytes.len());
+                data[..len].copy_from_slice(&bytes[..len]);
+            }
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match offset {
+            o if (MSIX_TABLE_OFFSET..MSIX_TABLE_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_table(o - MSIX_TABLE_OFFSET, data),
+            o if (MSIX_PBA_OFFSET..MSIX_PBA_OFFSET + MSIX_REGION_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_pba(o - MSIX_PBA_OFFSET, data),
+            o if o & 0x3 == 0 && matches!(data.len(), 4 | 8) => {
+                let mut state = self.state.lock().unwrap();
+                for (i, dword) in data.chunks_exact(4).enumerate() {
+                    let value = u32::from_le_bytes(dword.try_into().unwrap());
+                    state.write_register(o + 4 * i as u64, value);
+                }
+            }
+            o => warn!(
+                "Unsupported xHCI register write of {} bytes at 0x{:x}",
+                data.len(),
+                o
+            ),
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for XhciController {}
+
+impl Snapshottable for XhciController {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // Host USB devices cannot be carried over to another VMM, the controller
+    // can only be snapshotted while none is attached.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let state = self.state.lock().unwrap();
+        if state.ports.iter().any(|p| p.device.is_some()) {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Can't snapshot an xHCI controller with USB devices attached"
+            )));
+        }
+        let mut snapshot = Snapshot::new_from_state(&state.state())?;
+        drop(state);
+
+        // Snapshot PciConfiguration
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+
+        // Snapshot MSI-X
+        {
+            let mut msix_config = self.msix_config.lock().unwrap();
+            snapshot.add_snapshot(msix_config.id(), msix_config.snapshot()?);
+        }
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for XhciController {}
+impl Migratable for XhciController {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trb(addr: u64, trb_type: u32, length: usize, flags: u32) -> Trb {
+        Trb {
+            addr,
+            parameter: 0,
+            status: length as u32,
+            control: trb_type << 10 | flags,
+        }
+    }
+
+    fn write_trb(mem: &GuestMemoryMmap, addr: u64, parameter: u64, status: u32, control: u32) {
+        let mut bytes = [0u8; TRB_SIZE as usize];
+        bytes[0..8].copy_from_slice(&parameter.to_le_bytes());
+        bytes[8..12].copy_from_slice(&status.to_le_bytes());
+        bytes[12..16].copy_from_slice(&control.to_le_bytes());
+        mem.write_slice(&bytes, GuestAddress(addr)).unwrap();
+    }
+
+    fn td(trbs: Vec<Trb>, control: bool) -> Td {
+        Td {
+            trbs,
+            start: RingCursor::default(),
+            end: RingCursor::default(),
+            control,
+            token: 0,
+        }
+    }
+
+    #[test]
+    fn test_trb_fields() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        write_trb(
+            &mem,
+            0x1000,
+            0x1234_5678_9abc_def0,
+            0xfffe_0000 | 0x1_2345,
+            3 << 24 | 5 << 16 | TRB_CONFIGURE_ENDPOINT << 10 | TRB_IOC | TRB_CYCLE,
+        );
+
+        let trb = Trb::read(&mem, 0x1000).unwrap();
+        assert_eq!(trb.addr, 0x1000);
+        assert_eq!(trb.parameter, 0x1234_5678_9abc_def0);
+        assert_eq!(trb.trb_type(), TRB_CONFIGURE_ENDPOINT);
+        assert_eq!(trb.transfer_length(), 0x1_2345);
+        assert_eq!(trb.slot_id(), 3);
+        assert_eq!(trb.endpoint_id(), 5);
+        assert!(trb.has(TRB_IOC));
+        assert!(trb.has(TRB_CYCLE));
+        assert!(!trb.has(TRB_CHAIN));
+
+        // Reading past the end of guest memory fails.
+        assert!(Trb::read(&mem, 0x10000).is_err());
+    }
+
+    #[test]
+    fn test_ring_cursor() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        let cursor = RingCursor::new(0x1003);
+        assert_eq!(cursor.dequeue, 0x1000);
+        assert!(cursor.cycle);
+        assert_eq!(cursor.pointer(), 0x1001);
+
+        // Two TRBs followed by a link TRB toggling the cycle back to the
+        // start of the segment.
+        write_trb(&mem, 0x1000, 0, 0, TRB_NORMAL << 10 | TRB_CYCLE);
+        write_trb(&mem, 0x1010, 0, 0, TRB_NORMAL << 10 | TRB_CYCLE);
+        write_trb(
+            &mem,
+            0x1020,
+            0x1000,
+            0,
+            TRB_LINK << 10 | TRB_TOGGLE_CYCLE | TRB_CYCLE,
+        );
+
+        let mut cursor = RingCursor::new(0x1001);
+        assert_eq!(cursor.next(&mem).unwrap().unwrap().addr, 0x1000);
+        assert_eq!(cursor.next(&mem).unwrap().unwrap().addr, 0x1010);
+        // The first TRB is still owned by the driver for the new cycle.
+        assert!(cursor.next(&mem).unwrap().is_none());
+        assert_eq!(cursor.pointer(), 0x1000);
+
+        // Once the driver flipped its cycle bit, the TRB is fetched again.
+        write_trb(&mem, 0x1000, 0, 0, TRB_NORMAL << 10);
+        assert_eq!(cursor.next(&mem).unwrap().unwrap().addr, 0x1000);
+        assert_eq!(cursor.pointer(), 0x1010);
+        assert!(cursor.next(&mem).unwrap().is_none());
+
+        // A ring only made of link TRBs does not hang the controller.
+        write_trb(&mem, 0x2000, 0x2000, 0, TRB_LINK << 10 | TRB_CYCLE);
+        let mut cursor = RingCursor::new(0x2001);
+        assert!(cursor.next(&mem).unwrap().is_none());
+        assert_eq!(cursor.pointer(), 0x2001);
+    }
+
+    #[test]
+    fn test_event_ring() {
+        let mut ring = EventRing::default();
+        assert_eq!(ring.enqueue_pointer(), None);
+
+        ring.segments = vec![(0x1000, 2), (0x3000, 1)];
+        ring.cycle = true;
+        assert_eq!(ring.enqueue_pointer(), Some(0x1000));
+        ring.advance();
+        assert_eq!(ring.enqueue_pointer(), Some(0x1010));
+        ring.advance();
+        assert_eq!(ring.enqueue_pointer(), Some(0x3000));
+        assert!(ring.cycle);
+
+        // Wrapping back to the first segment toggles the cycle.
+        ring.advance();
+        assert_eq!(ring.enqueue_pointer(), Some(0x1000));
+        assert!(!ring.cycle);
+    }
+
+    #[test]
+    fn test_td_events() {
+        // Bulk transfer spread over two TRBs.
+        let bulk = td(
+            vec![
+                trb(0x1000, TRB_NORMAL, 512, TRB_CHAIN | TRB_ISP),
+                trb(0x1010, TRB_NORMAL, 512, TRB_ISP | TRB_IOC),
+            ],
+            false,
+        );
+        assert_eq!(
+            bulk.events(COMPLETION_SUCCESS, 1024),
+            vec![(0x1010, COMPLETION_SUCCESS, 0, false)]
+        );
+        // A single event is generated for a short packet.
+        assert_eq!(
+            bulk.events(COMPLETION_SUCCESS, 100),
+            vec![(0x1000, COMPLETION_SHORT_PACKET, 412, false)]
+        );
+        assert_eq!(
+            bulk.events(COMPLETION_STALL, 600),
+            vec![(0x1010, COMPLETION_STALL, 424, false)]
+        );
+
+        // Control transfer with a short data stage, the status stage still
+        // completing successfully.
+        let control = td(
+            vec![
+                trb(0x2000, TRB_SETUP_STAGE, 8, TRB_IDT),
+                trb(0x2010, TRB_DATA_STAGE, 64, TRB_ISP | TRB_CHAIN),
+                trb(0x2020, TRB_STATUS_STAGE, 0, TRB_IOC),
+            ],
+            true,
+        );
+        assert_eq!(
+            control.events(COMPLETION_SUCCESS, 18),
+            vec![
+                (0x2010, COMPLETION_SHORT_PACKET, 46, false),
+                (0x2020, COMPLETION_SUCCESS, 0, false)
+            ]
+        );
+        assert_eq!(
+            control.events(COMPLETION_STALL, 0),
+            vec![(0x2010, COMPLETION_STALL, 64, false)]
+        );
+
+        // Event data TRB reporting the length of the TD.
+        let event_data = td(
+            vec![
+                trb(0x3000, TRB_NORMAL, 256, TRB_CHAIN),
+                Trb {
+                    parameter: 0xdead,
+                    ..trb(0x3010, TRB_EVENT_DATA, 0, TRB_IOC)
+                },
+            ],
+            false,
+        );
+        assert_eq!(
+            event_data.events(COMPLETION_SUCCESS, 256),
+            vec![(0xdead, COMPLETION_SUCCESS, 256, true)]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! vmclock device, as handled by the Linux `ptp_vmclock` driver.
+//!
+//! The device is a read-only page of memory holding a `vmclock_abi`
+//! structure (see include/uapi/linux/vmclock-abi.h in the kernel code),
+//! described to the guest through ACPI. Only the disruption marker is
+//! provided: it changes whenever the guest counter may have been disrupted,
+//! that is after a restore or a live migration, letting the guest discard its
+//! calibration of the counter against external clocks. The guest clock
+//! itself is synchronized with the host through `ptp_kvm`.
+
+use acpi_tables::{aml, Aml, AmlSink};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use vm_memory::{ByteValued, Bytes, GuestAddress, MmapRegion, VolatileMemory};
+
+pub const VMCLOCK_SIZE: u64 = 0x1000;
+
+const VMCLOCK_MAGIC: u32 = 0x4b4c_4356; // "VCLK"
+const VMCLOCK_VERSION: u16 = 1;
+// No counter is described, hence no time either.
+const VMCLOCK_COUNTER_INVALID: u8 = 0xff;
+const VMCLOCK_TIME_UTC: u8 = 0;
+const VMCLOCK_STATUS_UNKNOWN: u8 = 0;
+
+#[derive(Debug, Error)]
+pub enum VmclockError {
+    #[error("Failed to allocate the vmclock page: {0}")]
+    AllocatePage(#[source] vm_memory::mmap::MmapRegionError),
+    #[error("Failed to write the vmclock page: {0}")]
+    WritePage(#[source] vm_memory::VolatileMemoryError),
+}
+
+// Fields are little endian, like all the supported architectures.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VmclockAbi {
+    magic: u32,
+    size: u32,
+    version: u16,
+    counter_id: u8,
+    time_type: u8,
+    seq_count: u32,
+    disruption_marker: u64,
+    flags: u64,
+    pad: [u8; 2],
+    clock_status: u8,
+    leap_second_smearing_hint: u8,
+    tai_offset_sec: i16,
+    leap_indicator: u8,
+    counter_period_shift: u8,
+    counter_value: u64,
+    counter_period_frac_sec: u64,
+    counter_period_esterror_rate_frac_sec: u64,
+    counter_period_maxerror_rate_frac_sec: u64,
+    time_sec: u64,
+    time_frac_sec: u64,
+    time_esterror_nanosec: u64,
+    time_maxerror_nanosec: u64,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VmclockAbi {}
+
+pub struct VmclockDevice {
+    address: GuestAddress,
+    // Host memory backing the guest page
+    mmap_region: MmapRegion,
+}
+
+impl VmclockDevice {
+    /// Creates the vmclock page, to be mapped read-only at `address` in the
+    /// guest. A new device is created each time the VM is booted, restored
+    /// or migrated, which gives it a new disruption marker.
+    pub fn new(address: GuestAddress) -> Result<Self, VmclockError> {
+        let mmap_region =
+            MmapRegion::new(VMCLOCK_SIZE as usize).map_err(VmclockError::AllocatePage)?;
+
+        // Any value which doesn't repeat does the job.
+        let disruption_marker = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let abi = VmclockAbi {
+            magic: VMCLOCK_MAGIC,
+            size: VMCLOCK_SIZE as u32,
+            version: VMCLOCK_VERSION,
+            counter_id: VMCLOCK_COUNTER_INVALID,
+            time_type: VMCLOCK_TIME_UTC,
+            disruption_marker,
+            clock_status: VMCLOCK_STATUS_UNKNOWN,
+            ..Default::default()
+        };
+        mmap_region
+            .as_volatile_slice()
+            .write_obj(abi, 0)
+            .map_err(VmclockError::WritePage)?;
+
+        Ok(VmclockDevice {
+            address,
+            mmap_region,
+        })
+    }
+
+    /// Host address of the page backing the device, to be mapped into the
+    /// guest.
+    pub fn host_addr(&self) -> u64 {
+        self.mmap_region.as_ptr() as u64
+    }
+}
+
+impl Aml for VmclockDevice {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        aml::Device::new(
+            "_SB_.VCLK".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"AMZNC10C"),
+                &aml::Name::new("_CID".into(), &"VMCLOCK"),
+                &aml::Name::new("_DDN".into(), &"VMCLOCK"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::AddressSpace::new_memory(
+                        aml::AddressSpaceCacheable::Cacheable,
+                        false,
+                        self.address.0,
+                        self.address.0 + VMCLOCK_SIZE - 1,
+                        None,
+                    )]),
+                ),
+            ],
+        )
+        .to_aml_bytes(sink);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vmclock_abi() {
+        assert_eq!(std::mem::size_of::<VmclockAbi>(), 104);
+
+        let device = VmclockDevice::new(GuestAddress(0)).unwrap();
+        let abi: VmclockAbi = device.mmap_region.as_volatile_slice().read_obj(0).unwrap();
+        assert_eq!(abi.magic, VMCLOCK_MAGIC);
+        assert_eq!(abi.size, VMCLOCK_SIZE as u32);
+        assert_eq!(abi.version, VMCLOCK_VERSION);
+        assert_eq!(abi.counter_id, VMCLOCK_COUNTER_INVALID);
+        assert_ne!(abi.disruption_marker, 0);
+    }
+}
@@ -47,6 +47,7 @@ fuzz_target!(|bytes| {
         BALLOON_SIZE,
         true,
         true,
+        None,
         SeccompAction::Allow,
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
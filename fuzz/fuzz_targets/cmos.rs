@@ -22,10 +22,14 @@ fuzz_target!(|bytes| {
     above_4g.copy_from_slice(&bytes[8..16]);
 
     let mut cmos = Cmos::new(
+        String::from("__cmos"),
         u64::from_le_bytes(below_4g),
         u64::from_le_bytes(above_4g),
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
+        0,
+        true,
+        None,
     );
 
     let mut i = 16;
@@ -105,6 +105,14 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
+    fn vm_snapshot_list(&self, _: &str) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_snapshot_delete(&mut self, _: &str, _: &str) -> Result<(), VmError> {
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn vm_coredump(&mut self, _: &str) -> Result<(), VmError> {
         Ok(())
@@ -128,6 +136,7 @@ impl RequestHandler for StubApiRequestHandler {
                     kvm_hyperv: false,
                     max_phys_bits: 46,
                     affinity: None,
+                    affinity_fallback: CpuAffinityFallback::default(),
                     features: CpuFeatures::default(),
                 },
                 memory: MemoryConfig {
@@ -136,12 +145,16 @@ impl RequestHandler for StubApiRequestHandler {
                     hotplug_method: HotplugMethod::Acpi,
                     hotplug_size: None,
                     hotplugged_size: None,
+                    boot_size: None,
                     shared: false,
                     hugepages: false,
                     hugepage_size: None,
                     prefault: false,
+                    mlock: false,
                     zones: None,
                     thp: true,
+                    dirty_ring: false,
+                    guest_memfd: false,
                 },
                 payload: Some(PayloadConfig {
                     kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -152,6 +165,7 @@ impl RequestHandler for StubApiRequestHandler {
                     igvm: None,
                 }),
                 rate_limit_groups: None,
+                io_affinities: None,
                 disks: None,
                 net: None,
                 rng: RngConfig {
@@ -160,37 +174,59 @@ impl RequestHandler for StubApiRequestHandler {
                 },
                 balloon: None,
                 fs: None,
+                fs9p: None,
+                i2c: None,
                 pmem: None,
                 serial: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Null,
                     iommu: false,
                     socket: None,
+                    log_file: None,
+                    capture_size: None,
                 },
                 console: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Tty,
                     iommu: false,
                     socket: None,
+                    log_file: None,
+                    capture_size: None,
                 },
                 #[cfg(target_arch = "x86_64")]
                 debug_console: DebugConsoleConfig::default(),
                 devices: None,
                 user_devices: None,
                 vdpa: None,
+                ivshmem: None,
+                nvme: None,
+                cxl: None,
+                xhci: None,
+                usb_devices: None,
+                vnc: None,
                 vsock: None,
+                guest_agent: None,
                 pvpanic: false,
+            vmclock: false,
+                panic: None,
                 #[cfg(feature = "pvmemcontrol")]
                 pvmemcontrol: None,
                 iommu: false,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
                 numa: None,
+                numa_placement: false,
+                numa_placed: None,
                 watchdog: false,
                 gdb: false,
                 pci_segments: None,
                 platform: None,
                 tpm: None,
+                rtc: None,
+                #[cfg(target_arch = "x86_64")]
+                fw_cfg: None,
+                cgroup: None,
+                security_label: None,
                 preserved_fds: None,
                 landlock_enable: false,
                 landlock_rules: None,
@@ -198,6 +234,7 @@ impl RequestHandler for StubApiRequestHandler {
             state: VmState::Running,
             memory_actual_size: 0,
             device_tree: None,
+            guest_network_interfaces: None,
         })
     }
 
@@ -218,7 +255,25 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
-    fn vm_resize(&mut self, _: Option<u8>, _: Option<u64>, _: Option<u64>) -> Result<(), VmError> {
+    fn vmm_add_vm(&mut self, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vmm_remove_vm(&mut self, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vmm_list_vms(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_resize(
+        &mut self,
+        _: Option<u8>,
+        _: Option<u8>,
+        _: Option<u64>,
+        _: Option<u64>,
+    ) -> Result<(), VmError> {
         Ok(())
     }
 
@@ -262,6 +317,10 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(None)
     }
 
+    fn vm_add_usb_device(&mut self, _: UsbDeviceConfig) -> Result<(), VmError> {
+        Ok(())
+    }
+
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError> {
         Ok(None)
     }
@@ -278,9 +337,21 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
+    fn vm_clock(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_serial_capture(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
     fn vm_nmi(&mut self) -> Result<(), VmError> {
         Ok(())
     }
+
+    fn vm_update_cgroup(&mut self, _: CgroupResources) -> Result<(), VmError> {
+        Ok(())
+    }
 }
 
 fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receiver<ApiRequest>) {
@@ -6,6 +6,7 @@
 
 use libfuzzer_sys::fuzz_target;
 use seccompiler::SeccompAction;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd};
@@ -79,6 +80,11 @@ fuzz_target!(|bytes| {
         true,
         true,
         true,
+        virtio_devices::NetIoUring::Disabled,
+        BTreeMap::new(),
+        None,
+        None,
+        false,
     )
     .unwrap();
 
@@ -26,6 +26,13 @@ pub const MTRR_MEM_TYPE_WB: u64 = 0x6;
 // IOAPIC pins
 pub const NUM_IOAPIC_PINS: usize = 24;
 
+// Hyper-V guest crash MSRs: the crash code and its parameters are written to
+// the P0-P4 MSRs before the guest sets CRASH_NOTIFY in the control MSR.
+pub const HV_X64_MSR_CRASH_P0: u32 = 0x4000_0100;
+pub const HV_X64_MSR_CRASH_PARAMS: u32 = 5;
+pub const HV_X64_MSR_CRASH_CTL: u32 = 0x4000_0105;
+pub const HV_CRASH_CTL_CRASH_NOTIFY: u64 = 1 << 63;
+
 // X86 Exceptions
 #[derive(Clone, Debug)]
 pub enum Exception {
@@ -17,6 +17,10 @@ use crate::kvm::{TdxExitDetails, TdxExitStatus};
 use crate::CpuState;
 use crate::MpState;
 use crate::StandardRegisters;
+use std::collections::HashMap;
+use std::num::Wrapping;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use thiserror::Error;
 use vm_memory::GuestAddress;
 
@@ -311,12 +315,76 @@ pub enum VmExit {
     Reset,
     Shutdown,
     Hyperv,
+    /// Guest crash reported through the Hyper-V crash MSRs
+    #[cfg(target_arch = "x86_64")]
+    HypervCrash,
     #[cfg(feature = "tdx")]
     Tdx,
     #[cfg(feature = "kvm")]
     Debug,
 }
 
+/// Reasons for which a vCPU exits to the VMM, as accounted by
+/// [`VcpuExitCounters`].
+#[derive(Clone, Copy, Debug)]
+pub enum VcpuExitReason {
+    Pio,
+    Mmio,
+    Msr,
+    Hlt,
+    EptViolation,
+    Other,
+}
+
+impl VcpuExitReason {
+    const ALL: [VcpuExitReason; 6] = [
+        VcpuExitReason::Pio,
+        VcpuExitReason::Mmio,
+        VcpuExitReason::Msr,
+        VcpuExitReason::Hlt,
+        VcpuExitReason::EptViolation,
+        VcpuExitReason::Other,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            VcpuExitReason::Pio => "pio_exits",
+            VcpuExitReason::Mmio => "mmio_exits",
+            VcpuExitReason::Msr => "msr_exits",
+            VcpuExitReason::Hlt => "hlt_exits",
+            VcpuExitReason::EptViolation => "ept_violation_exits",
+            VcpuExitReason::Other => "other_exits",
+        }
+    }
+}
+
+///
+/// Number of exits of a vCPU to the VMM, by reason. They are updated by the
+/// vCPU thread and can be read from any other thread while the vCPU runs.
+///
+#[derive(Default)]
+pub struct VcpuExitCounters {
+    counts: [AtomicU64; VcpuExitReason::ALL.len()],
+}
+
+impl VcpuExitCounters {
+    pub fn record(&self, reason: VcpuExitReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> HashMap<&'static str, Wrapping<u64>> {
+        VcpuExitReason::ALL
+            .iter()
+            .map(|reason| {
+                (
+                    reason.name(),
+                    Wrapping(self.counts[*reason as usize].load(Ordering::Relaxed)),
+                )
+            })
+            .collect()
+    }
+}
+
 ///
 /// Result type for returning from a function
 ///
@@ -437,6 +505,11 @@ pub trait Vcpu: Send + Sync {
     #[cfg(target_arch = "aarch64")]
     fn get_sys_reg(&self, sys_reg: u32) -> Result<u64>;
     ///
+    /// Sets the value of a system register
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_sys_reg(&self, sys_reg: u32, value: u64) -> Result<()>;
+    ///
     /// Configure core registers for a given CPU.
     ///
     #[cfg(target_arch = "aarch64")]
@@ -465,6 +538,10 @@ pub trait Vcpu: Send + Sync {
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
     fn run(&self) -> std::result::Result<VmExit, HypervisorCpuError>;
+    ///
+    /// Returns the counters of the exits of the virtual CPU, by reason.
+    ///
+    fn exit_counters(&self) -> Arc<VcpuExitCounters>;
     #[cfg(target_arch = "x86_64")]
     ///
     /// Translate guest virtual address to guest physical address
@@ -539,3 +616,22 @@ pub trait Vcpu: Send + Sync {
     ///
     fn nmi(&self) -> Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vcpu_exit_counters() {
+        let exit_counters = VcpuExitCounters::default();
+        exit_counters.record(VcpuExitReason::Mmio);
+        exit_counters.record(VcpuExitReason::Mmio);
+        exit_counters.record(VcpuExitReason::Hlt);
+
+        let counters = exit_counters.counters();
+        assert_eq!(counters.len(), 6);
+        assert_eq!(counters["mmio_exits"], Wrapping(2));
+        assert_eq!(counters["hlt_exits"], Wrapping(1));
+        assert_eq!(counters["pio_exits"], Wrapping(0));
+    }
+}
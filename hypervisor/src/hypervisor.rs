@@ -118,6 +118,16 @@ pub trait Hypervisor: Send + Sync {
     }
     #[cfg(target_arch = "x86_64")]
     ///
+    /// Create a Vm whose memory is private to the guest, except for the
+    /// pages it explicitly shares
+    ///
+    fn create_private_vm(&self) -> Result<Arc<dyn Vm>> {
+        Err(HypervisorError::VmCreate(anyhow!(
+            "Private memory is not supported"
+        )))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
     /// Get the supported CpuID
     ///
     fn get_supported_cpuid(&self) -> Result<Vec<CpuIdEntry>>;
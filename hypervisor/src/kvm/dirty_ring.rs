@@ -0,0 +1,345 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! KVM dirty ring based dirty page tracking.
+//!
+//! With `KVM_CAP_DIRTY_LOG_RING`, KVM reports the pages dirtied by each vCPU
+//! through a ring shared with userspace, instead of setting bits in a
+//! per-slot bitmap which has to be fetched and cleared as a whole. The cost
+//! of tracking is then proportional to the number of dirtied pages rather
+//! than to the size of the guest memory.
+
+use kvm_bindings::{kvm_enable_cap, KVMIO};
+use kvm_ioctls::{VcpuFd, VmFd};
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+const KVM_CAP_DIRTY_LOG_RING: u32 = 192;
+const KVM_DIRTY_LOG_PAGE_OFFSET: i64 = 64;
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1 << 0;
+const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+pub(crate) const KVM_EXIT_DIRTY_RING_FULL: u32 = 31;
+
+// Number of entries of each vCPU ring, capped by what KVM supports.
+const DIRTY_RING_ENTRIES: usize = 4096;
+
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_io_nr!(KVM_RESET_DIRTY_RINGS, KVMIO, 0xc7);
+
+/// Layout of `struct kvm_dirty_gfn`.
+#[repr(C)]
+struct KvmDirtyGfn {
+    flags: AtomicU32,
+    slot: u32,
+    offset: u64,
+}
+
+struct DirtyRing {
+    gfns: *mut KvmDirtyGfn,
+    entries: u32,
+    len: usize,
+    // Index of the next entry to be harvested
+    next: u32,
+}
+
+// SAFETY: the ring mapping is owned by the DirtyRing and only accessed with
+// the KvmDirtyRings lock held.
+unsafe impl Send for DirtyRing {}
+
+impl DirtyRing {
+    fn entry(&self, index: u32) -> &KvmDirtyGfn {
+        // SAFETY: the index is bounded by the number of entries of the
+        // mapping, which lives as long as self.
+        unsafe { &*self.gfns.add((index % self.entries) as usize) }
+    }
+
+    /// Move the entries published by KVM since the last call to the dirty
+    /// pages of their slot, and flag them for KVM to reset. Returns the
+    /// number of harvested entries.
+    fn collect(&mut self, dirty_pages: &mut HashMap<u32, Vec<u64>>) -> usize {
+        let mut harvested = 0;
+        loop {
+            let gfn = self.entry(self.next);
+            if gfn.flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                break;
+            }
+            // The upper 16 bits hold the address space identifier.
+            dirty_pages
+                .entry(gfn.slot & 0xffff)
+                .or_default()
+                .push(gfn.offset);
+            gfn.flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+            self.next = self.next.wrapping_add(1);
+            harvested += 1;
+        }
+        harvested
+    }
+}
+
+impl Drop for DirtyRing {
+    fn drop(&mut self) {
+        // SAFETY: FFI call with the address and length of the mapping
+        unsafe { libc::munmap(self.gfns as *mut libc::c_void, self.len) };
+    }
+}
+
+fn page_size() -> u64 {
+    // SAFETY: FFI call with a valid argument
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+/// Dirty rings of all the vCPUs of a VM.
+pub(crate) struct KvmDirtyRings {
+    vm_fd: Arc<VmFd>,
+    ring_len: usize,
+    rings: Mutex<Vec<DirtyRing>>,
+    // Dirty page offsets harvested from the rings, per memory slot
+    dirty_pages: Mutex<HashMap<u32, Vec<u64>>>,
+}
+
+impl KvmDirtyRings {
+    /// Enable the dirty ring on the VM. This must be done before any vCPU
+    /// is created.
+    pub fn new(vm_fd: &Arc<VmFd>) -> io::Result<Arc<Self>> {
+        // SAFETY: ioctl on a valid VM fd, returning the maximum ring size
+        let max_len = unsafe {
+            ioctl_with_val(
+                vm_fd.as_ref(),
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_DIRTY_LOG_RING as libc::c_ulong,
+            )
+        };
+        if max_len <= 0 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        let ring_len = (DIRTY_RING_ENTRIES * size_of::<KvmDirtyGfn>()).min(max_len as usize);
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_DIRTY_LOG_RING,
+            ..Default::default()
+        };
+        cap.args[0] = ring_len as u64;
+        vm_fd
+            .enable_cap(&cap)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+        info!("Using KVM dirty ring of {} bytes per vCPU", ring_len);
+
+        Ok(Arc::new(KvmDirtyRings {
+            vm_fd: vm_fd.clone(),
+            ring_len,
+            rings: Mutex::new(Vec::new()),
+            dirty_pages: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Map the dirty ring of a newly created vCPU.
+    pub fn add_vcpu(&self, vcpu_fd: &VcpuFd) -> io::Result<()> {
+        // SAFETY: FFI call mapping the ring exposed by the vCPU fd, the
+        // result is checked.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                self.ring_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu_fd.as_raw_fd(),
+                KVM_DIRTY_LOG_PAGE_OFFSET * page_size() as i64,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.rings.lock().unwrap().push(DirtyRing {
+            gfns: addr as *mut KvmDirtyGfn,
+            entries: (self.ring_len / size_of::<KvmDirtyGfn>()) as u32,
+            len: self.ring_len,
+            next: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Collect the dirty pages published in the rings, and hand the
+    /// harvested entries back to KVM.
+    pub fn harvest(&self) -> io::Result<()> {
+        let mut rings = self.rings.lock().unwrap();
+        let mut dirty_pages = self.dirty_pages.lock().unwrap();
+        let harvested: usize = rings
+            .iter_mut()
+            .map(|ring| ring.collect(&mut dirty_pages))
+            .sum();
+
+        if harvested > 0 {
+            // SAFETY: ioctl on a valid VM fd
+            let ret = unsafe { ioctl(self.vm_fd.as_ref(), KVM_RESET_DIRTY_RINGS()) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop the dirty pages reported so far.
+    pub fn discard(&self) -> io::Result<()> {
+        self.harvest()?;
+        self.dirty_pages.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Get and clear the bitmap of the dirty pages of a slot, in the same
+    /// format as returned by KVM_GET_DIRTY_LOG.
+    pub fn dirty_bitmap(&self, slot: u32, memory_size: u64) -> io::Result<Vec<u64>> {
+        self.harvest()?;
+
+        let pages = memory_size.div_ceil(page_size());
+        let offsets = self
+            .dirty_pages
+            .lock()
+            .unwrap()
+            .remove(&slot)
+            .unwrap_or_default();
+
+        Ok(dirty_bitmap(&offsets, pages))
+    }
+}
+
+/// Build a KVM_GET_DIRTY_LOG like bitmap of `pages` pages from the offsets
+/// of the dirty pages.
+fn dirty_bitmap(offsets: &[u64], pages: u64) -> Vec<u64> {
+    let mut bitmap = vec![0u64; pages.div_ceil(64) as usize];
+    for offset in offsets.iter().filter(|offset| **offset < pages) {
+        bitmap[(offset / 64) as usize] |= 1 << (offset % 64);
+    }
+    bitmap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(entries: u32) -> DirtyRing {
+        let len = entries as usize * size_of::<KvmDirtyGfn>();
+        // SAFETY: FFI call creating an anonymous mapping, the result is
+        // checked and the mapping is released when the ring is dropped.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        DirtyRing {
+            gfns: addr as *mut KvmDirtyGfn,
+            entries,
+            len,
+            next: 0,
+        }
+    }
+
+    // Publish a dirty page the way KVM does
+    fn push(ring: &DirtyRing, index: u32, slot: u32, offset: u64) {
+        let gfn = ring.entry(index);
+        assert_eq!(gfn.flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY, 0);
+        // SAFETY: the entry is not dirty, hence owned by the producer.
+        unsafe {
+            let gfn = gfn as *const KvmDirtyGfn as *mut KvmDirtyGfn;
+            (*gfn).slot = slot;
+            (*gfn).offset = offset;
+        }
+        gfn.flags.store(KVM_DIRTY_GFN_F_DIRTY, Ordering::Release);
+    }
+
+    // Reset the harvested entries the way KVM_RESET_DIRTY_RINGS does
+    fn reset(ring: &DirtyRing) {
+        for index in 0..ring.entries {
+            let gfn = ring.entry(index);
+            if gfn.flags.load(Ordering::Acquire) == KVM_DIRTY_GFN_F_RESET {
+                gfn.flags.store(0, Ordering::Release);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dirty_ring_collect() {
+        let mut ring = ring(4);
+        let mut dirty_pages = HashMap::new();
+
+        assert_eq!(ring.collect(&mut dirty_pages), 0);
+        assert!(dirty_pages.is_empty());
+
+        // The address space identifier is ignored.
+        push(&ring, 0, 1, 3);
+        push(&ring, 1, (1 << 16) | 1, 5);
+        push(&ring, 2, 2, 7);
+        assert_eq!(ring.collect(&mut dirty_pages), 3);
+        assert_eq!(ring.next, 3);
+        assert_eq!(dirty_pages[&1], vec![3, 5]);
+        assert_eq!(dirty_pages[&2], vec![7]);
+
+        // Harvested entries are handed back to KVM, and not collected again.
+        for index in 0..3 {
+            assert_eq!(
+                ring.entry(index).flags.load(Ordering::Acquire),
+                KVM_DIRTY_GFN_F_RESET
+            );
+        }
+        assert_eq!(ring.entry(3).flags.load(Ordering::Acquire), 0);
+        assert_eq!(ring.collect(&mut dirty_pages), 0);
+    }
+
+    #[test]
+    fn test_dirty_ring_wrap() {
+        let mut ring = ring(4);
+        let mut dirty_pages = HashMap::new();
+
+        for (index, offset) in (0..4).zip(10..) {
+            push(&ring, index, 0, offset);
+        }
+        assert_eq!(ring.collect(&mut dirty_pages), 4);
+        assert_eq!(ring.next, 4);
+
+        // Entries can only be reused once KVM has reset them.
+        reset(&ring);
+        push(&ring, 4, 0, 14);
+        push(&ring, 5, 0, 15);
+        assert_eq!(ring.collect(&mut dirty_pages), 2);
+        assert_eq!(ring.next, 6);
+        assert_eq!(dirty_pages[&0], vec![10, 11, 12, 13, 14, 15]);
+
+        // The index keeps going across the u32 boundary.
+        reset(&ring);
+        ring.next = u32::MAX - 1;
+        push(&ring, u32::MAX - 1, 0, 20);
+        push(&ring, u32::MAX, 0, 21);
+        push(&ring, 0, 0, 22);
+        dirty_pages.clear();
+        assert_eq!(ring.collect(&mut dirty_pages), 3);
+        assert_eq!(ring.next, 1);
+        assert_eq!(dirty_pages[&0], vec![20, 21, 22]);
+    }
+
+    #[test]
+    fn test_dirty_bitmap() {
+        assert!(dirty_bitmap(&[], 0).is_empty());
+        assert_eq!(dirty_bitmap(&[], 65), vec![0, 0]);
+        assert_eq!(dirty_bitmap(&[0, 2, 64], 65), vec![0b101, 1]);
+        // Offsets beyond the slot are ignored.
+        assert_eq!(dirty_bitmap(&[1, 65, 128], 65), vec![0b10, 0]);
+    }
+}
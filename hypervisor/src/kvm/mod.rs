@@ -37,10 +37,16 @@ use std::result;
 #[cfg(target_arch = "x86_64")]
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+#[cfg(target_arch = "x86_64")]
+use std::sync::OnceLock;
 use std::sync::{Arc, RwLock};
 use vmm_sys_util::eventfd::EventFd;
 // x86_64 dependencies
 #[cfg(target_arch = "x86_64")]
+mod dirty_ring;
+#[cfg(target_arch = "x86_64")]
+mod private_memory;
+#[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86::{
@@ -56,11 +62,15 @@ use crate::{
 #[cfg(target_arch = "aarch64")]
 use aarch64::{RegList, Register};
 #[cfg(target_arch = "x86_64")]
+use dirty_ring::{KvmDirtyRings, KVM_EXIT_DIRTY_RING_FULL};
+#[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
     kvm_enable_cap, kvm_msr_entry, MsrList, KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP,
-    KVM_GUESTDBG_USE_HW_BP,
+    KVM_GUESTDBG_USE_HW_BP, KVM_MEMORY_EXIT_FLAG_PRIVATE,
 };
 #[cfg(target_arch = "x86_64")]
+use private_memory::{map_gpa_range, KvmPrivateMemory, KVM_HC_MAP_GPA_RANGE};
+#[cfg(target_arch = "x86_64")]
 use x86_64::check_required_kvm_extensions;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{CpuId, ExtendedControlRegisters, MsrEntries, VcpuKvmState};
@@ -384,6 +394,10 @@ pub struct KvmVm {
     #[cfg(target_arch = "x86_64")]
     msrs: Vec<MsrEntry>,
     dirty_log_slots: Arc<RwLock<HashMap<u32, KvmDirtyLogSlot>>>,
+    #[cfg(target_arch = "x86_64")]
+    dirty_rings: OnceLock<Arc<KvmDirtyRings>>,
+    #[cfg(target_arch = "x86_64")]
+    private_memory: Option<Arc<KvmPrivateMemory>>,
 }
 
 impl KvmVm {
@@ -475,6 +489,12 @@ impl vm::Vm for KvmVm {
             .fd
             .create_vcpu(id as u64)
             .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_rings) = self.dirty_rings.get() {
+            dirty_rings
+                .add_vcpu(&fd)
+                .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+        }
         let vcpu = KvmVcpu {
             fd: Arc::new(Mutex::new(fd)),
             #[cfg(target_arch = "x86_64")]
@@ -482,6 +502,11 @@ impl vm::Vm for KvmVm {
             vm_ops,
             #[cfg(target_arch = "x86_64")]
             hyperv_synic: AtomicBool::new(false),
+            #[cfg(target_arch = "x86_64")]
+            dirty_rings: self.dirty_rings.get().cloned(),
+            #[cfg(target_arch = "x86_64")]
+            private_memory: self.private_memory.clone(),
+            exit_counters: Arc::new(cpu::VcpuExitCounters::default()),
         };
         Ok(Arc::new(vcpu))
     }
@@ -675,6 +700,13 @@ impl vm::Vm for KvmVm {
             region.flags = 0;
         }
 
+        #[cfg(target_arch = "x86_64")]
+        if let Some(private_memory) = &self.private_memory {
+            return private_memory
+                .create_region(region)
+                .map_err(|e| vm::HypervisorVmError::CreateUserMemory(e.into()));
+        }
+
         // SAFETY: Safe because guest regions are guaranteed not to overlap.
         unsafe {
             self.fd
@@ -698,8 +730,15 @@ impl vm::Vm for KvmVm {
         unsafe {
             self.fd
                 .set_user_memory_region(region)
-                .map_err(|e| vm::HypervisorVmError::RemoveUserMemory(e.into()))
+                .map_err(|e| vm::HypervisorVmError::RemoveUserMemory(e.into()))?;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(private_memory) = &self.private_memory {
+            private_memory.remove_region(region.slot);
         }
+
+        Ok(())
     }
 
     ///
@@ -741,6 +780,15 @@ impl vm::Vm for KvmVm {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn enable_dirty_ring(&self) -> vm::Result<()> {
+        let dirty_rings = KvmDirtyRings::new(&self.fd)
+            .map_err(|e| vm::HypervisorVmError::EnableDirtyRing(e.into()))?;
+        self.dirty_rings
+            .set(dirty_rings)
+            .map_err(|_| vm::HypervisorVmError::EnableDirtyRing(anyhow!("already enabled")))
+    }
+
     /// Retrieve guest clock.
     #[cfg(target_arch = "x86_64")]
     fn get_clock(&self) -> vm::Result<ClockData> {
@@ -793,6 +841,14 @@ impl vm::Vm for KvmVm {
             }
         }
 
+        // Pages reported by a previous tracking session are not relevant.
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_rings) = self.dirty_rings.get() {
+            dirty_rings
+                .discard()
+                .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
+        }
+
         Ok(())
     }
 
@@ -824,6 +880,14 @@ impl vm::Vm for KvmVm {
     /// Get dirty pages bitmap (one bit per page)
     ///
     fn get_dirty_log(&self, slot: u32, _base_gpa: u64, memory_size: u64) -> vm::Result<Vec<u64>> {
+        // The dirty bitmap is not available when the dirty ring is in use.
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_rings) = self.dirty_rings.get() {
+            return dirty_rings
+                .dirty_bitmap(slot, memory_size)
+                .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()));
+        }
+
         self.fd
             .get_dirty_log(slot, memory_size as usize)
             .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()))
@@ -972,6 +1036,60 @@ impl KvmHypervisor {
             .get_msr_index_list()
             .map_err(|e| hypervisor::HypervisorError::GetMsrList(e.into()))
     }
+
+    fn create_kvm_vm(&self, vm_type: u64) -> hypervisor::Result<KvmVm> {
+        let fd: VmFd;
+        loop {
+            match self.kvm.create_vm_with_type(vm_type) {
+                Ok(res) => fd = res,
+                Err(e) => {
+                    if e.errno() == libc::EINTR {
+                        // If the error returned is EINTR, which means the
+                        // ioctl has been interrupted, we have to retry as
+                        // this can't be considered as a regular error.
+                        continue;
+                    } else {
+                        return Err(hypervisor::HypervisorError::VmCreate(e.into()));
+                    }
+                }
+            }
+            break;
+        }
+
+        let vm_fd = Arc::new(fd);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let msr_list = self.get_msr_list()?;
+            let num_msrs = msr_list.as_fam_struct_ref().nmsrs as usize;
+            let mut msrs: Vec<MsrEntry> = vec![
+                MsrEntry {
+                    ..Default::default()
+                };
+                num_msrs
+            ];
+            let indices = msr_list.as_slice();
+            for (pos, index) in indices.iter().enumerate() {
+                msrs[pos].index = *index;
+            }
+
+            Ok(KvmVm {
+                fd: vm_fd,
+                msrs,
+                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
+                dirty_rings: OnceLock::new(),
+                private_memory: None,
+            })
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            Ok(KvmVm {
+                fd: vm_fd,
+                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
+            })
+        }
+    }
 }
 
 /// Enum for KVM related error
@@ -1039,55 +1157,19 @@ impl hypervisor::Hypervisor for KvmHypervisor {
     /// let vm = hypervisor.create_vm_with_type(0).unwrap();
     /// ```
     fn create_vm_with_type(&self, vm_type: u64) -> hypervisor::Result<Arc<dyn vm::Vm>> {
-        let fd: VmFd;
-        loop {
-            match self.kvm.create_vm_with_type(vm_type) {
-                Ok(res) => fd = res,
-                Err(e) => {
-                    if e.errno() == libc::EINTR {
-                        // If the error returned is EINTR, which means the
-                        // ioctl has been interrupted, we have to retry as
-                        // this can't be considered as a regular error.
-                        continue;
-                    } else {
-                        return Err(hypervisor::HypervisorError::VmCreate(e.into()));
-                    }
-                }
-            }
-            break;
-        }
-
-        let vm_fd = Arc::new(fd);
-
-        #[cfg(target_arch = "x86_64")]
-        {
-            let msr_list = self.get_msr_list()?;
-            let num_msrs = msr_list.as_fam_struct_ref().nmsrs as usize;
-            let mut msrs: Vec<MsrEntry> = vec![
-                MsrEntry {
-                    ..Default::default()
-                };
-                num_msrs
-            ];
-            let indices = msr_list.as_slice();
-            for (pos, index) in indices.iter().enumerate() {
-                msrs[pos].index = *index;
-            }
-
-            Ok(Arc::new(KvmVm {
-                fd: vm_fd,
-                msrs,
-                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
-            }))
-        }
+        Ok(Arc::new(self.create_kvm_vm(vm_type)?))
+    }
 
-        #[cfg(target_arch = "aarch64")]
-        {
-            Ok(Arc::new(KvmVm {
-                fd: vm_fd,
-                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
-            }))
-        }
+    /// Create a KVM_X86_SW_PROTECTED_VM, whose private memory is backed by
+    /// guest_memfd.
+    #[cfg(all(target_arch = "x86_64", not(feature = "tdx")))]
+    fn create_private_vm(&self) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+        let mut vm = self.create_kvm_vm(kvm_bindings::KVM_X86_SW_PROTECTED_VM as u64)?;
+        vm.private_memory = Some(
+            KvmPrivateMemory::new(&vm.fd)
+                .map_err(|e| hypervisor::HypervisorError::VmCreate(e.into()))?,
+        );
+        Ok(Arc::new(vm))
     }
 
     /// Create a KVM vm object and return the object as Vm trait object
@@ -1192,6 +1274,39 @@ pub struct KvmVcpu {
     vm_ops: Option<Arc<dyn vm::VmOps>>,
     #[cfg(target_arch = "x86_64")]
     hyperv_synic: AtomicBool,
+    #[cfg(target_arch = "x86_64")]
+    dirty_rings: Option<Arc<KvmDirtyRings>>,
+    #[cfg(target_arch = "x86_64")]
+    private_memory: Option<Arc<KvmPrivateMemory>>,
+    exit_counters: Arc<cpu::VcpuExitCounters>,
+}
+
+// Only the exits handled by the VMM are accounted for, as KVM handles the MSR
+// accesses and the EPT violations on guest RAM without leaving the kernel.
+fn exit_reason(exit: &VcpuExit) -> cpu::VcpuExitReason {
+    match exit {
+        #[cfg(target_arch = "x86_64")]
+        VcpuExit::IoIn(..) | VcpuExit::IoOut(..) => cpu::VcpuExitReason::Pio,
+        VcpuExit::MmioRead(..) | VcpuExit::MmioWrite(..) => cpu::VcpuExitReason::Mmio,
+        #[cfg(target_arch = "x86_64")]
+        VcpuExit::Hlt => cpu::VcpuExitReason::Hlt,
+        _ => cpu::VcpuExitReason::Other,
+    }
+}
+
+// Convert the Arm standard encoding of an AArch64 system register to the ID
+// KVM uses for it with `KVM_G/SET_ONE_REG`.
+#[cfg(target_arch = "aarch64")]
+fn sys_reg_id(sys_reg: u32) -> u64 {
+    KVM_REG_ARM64
+        | KVM_REG_SIZE_U64
+        | KVM_REG_ARM64_SYSREG as u64
+        | ((((sys_reg) >> 5)
+            & (KVM_REG_ARM64_SYSREG_OP0_MASK
+                | KVM_REG_ARM64_SYSREG_OP1_MASK
+                | KVM_REG_ARM64_SYSREG_CRN_MASK
+                | KVM_REG_ARM64_SYSREG_CRM_MASK
+                | KVM_REG_ARM64_SYSREG_OP2_MASK)) as u64)
 }
 
 /// Implementation of Vcpu trait for KVM
@@ -1710,7 +1825,13 @@ impl cpu::Vcpu for KvmVcpu {
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
-        match self.fd.lock().unwrap().run() {
+        let mut fd = self.fd.lock().unwrap();
+        let result = fd.run();
+        if let Ok(exit) = &result {
+            self.exit_counters.record(exit_reason(exit));
+        }
+
+        match result {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
@@ -1738,6 +1859,21 @@ impl cpu::Vcpu for KvmVcpu {
                 VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::SystemEvent(event_type, flags) => {
+                    use kvm_bindings::KVM_SYSTEM_EVENT_CRASH;
+                    // KVM reports the guest setting CRASH_NOTIFY in the
+                    // Hyper-V crash control MSR as a crash system event.
+                    if event_type == KVM_SYSTEM_EVENT_CRASH {
+                        Ok(cpu::VmExit::HypervCrash)
+                    } else {
+                        Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                            "Unexpected system event with type 0x{:x}, flags 0x{:x?}",
+                            event_type,
+                            flags
+                        )))
+                    }
+                }
 
                 #[cfg(target_arch = "aarch64")]
                 VcpuExit::SystemEvent(event_type, flags) => {
@@ -1781,6 +1917,47 @@ impl cpu::Vcpu for KvmVcpu {
                 #[cfg(feature = "tdx")]
                 VcpuExit::Unsupported(KVM_EXIT_TDX) => Ok(cpu::VmExit::Tdx),
                 VcpuExit::Debug(_) => Ok(cpu::VmExit::Debug),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Unsupported(KVM_EXIT_DIRTY_RING_FULL) => {
+                    // The vCPU can't run until its ring has been harvested.
+                    if let Some(dirty_rings) = &self.dirty_rings {
+                        dirty_rings
+                            .harvest()
+                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()))?;
+                    }
+                    Ok(cpu::VmExit::Ignore)
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::MemoryFault { flags, gpa, size } if self.private_memory.is_some() => {
+                    // The guest accessed memory as private while shared or
+                    // the other way around, convert it to what is expected.
+                    let private = flags & KVM_MEMORY_EXIT_FLAG_PRIVATE as u64 != 0;
+                    self.private_memory
+                        .as_ref()
+                        .unwrap()
+                        .convert(gpa, size, private)
+                        .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()))?;
+                    Ok(cpu::VmExit::Ignore)
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Hypercall(exit)
+                    if exit.nr == KVM_HC_MAP_GPA_RANGE && self.private_memory.is_some() =>
+                {
+                    let private_memory = self.private_memory.as_ref().unwrap();
+                    *exit.ret = match map_gpa_range(&exit.args) {
+                        Some((gpa, size, private)) => {
+                            match private_memory.convert(gpa, size, private) {
+                                Ok(()) => 0,
+                                Err(e) => {
+                                    warn!("Failed converting guest memory: {}", e);
+                                    -(libc::EINVAL as i64) as u64
+                                }
+                            }
+                        }
+                        None => -(libc::EINVAL as i64) as u64,
+                    };
+                    Ok(cpu::VmExit::Ignore)
+                }
 
                 r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
                     "Unexpected exit reason on vcpu run: {:?}",
@@ -1798,6 +1975,10 @@ impl cpu::Vcpu for KvmVcpu {
         }
     }
 
+    fn exit_counters(&self) -> Arc<cpu::VcpuExitCounters> {
+        self.exit_counters.clone()
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// Let the guest know that it has been paused, which prevents from
@@ -1920,24 +2101,28 @@ impl cpu::Vcpu for KvmVcpu {
         // it to the corresponding KVM ID, and call `KVM_GET_ONE_REG` API to
         // get the value of the system parameter.
         //
-        let id: u64 = KVM_REG_ARM64
-            | KVM_REG_SIZE_U64
-            | KVM_REG_ARM64_SYSREG as u64
-            | ((((sys_reg) >> 5)
-                & (KVM_REG_ARM64_SYSREG_OP0_MASK
-                    | KVM_REG_ARM64_SYSREG_OP1_MASK
-                    | KVM_REG_ARM64_SYSREG_CRN_MASK
-                    | KVM_REG_ARM64_SYSREG_CRM_MASK
-                    | KVM_REG_ARM64_SYSREG_OP2_MASK)) as u64);
         let mut bytes = [0_u8; 8];
         self.fd
             .lock()
             .unwrap()
-            .get_one_reg(id, &mut bytes)
+            .get_one_reg(sys_reg_id(sys_reg), &mut bytes)
             .map_err(|e| cpu::HypervisorCpuError::GetSysRegister(e.into()))?;
         Ok(u64::from_le_bytes(bytes))
     }
 
+    ///
+    /// Sets the value of a system register
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_sys_reg(&self, sys_reg: u32, value: u64) -> cpu::Result<()> {
+        self.fd
+            .lock()
+            .unwrap()
+            .set_one_reg(sys_reg_id(sys_reg), &value.to_le_bytes())
+            .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
+        Ok(())
+    }
+
     ///
     /// Configure core registers for a given CPU.
     ///
@@ -0,0 +1,170 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! KVM guest_memfd based private guest memory.
+//!
+//! Each memory slot is bound to a guest_memfd, from which KVM allocates the
+//! pages the guest uses as private. The guest_memfd is never mapped into the
+//! VMM, only the shared pages are accessed through the userspace mapping of
+//! the slot. Which of the two backs a page is given by its memory attributes,
+//! updated as the guest converts pages between shared and private.
+
+use super::dirty_ring::KVM_CHECK_EXTENSION;
+use kvm_bindings::{
+    kvm_create_guest_memfd, kvm_enable_cap, kvm_memory_attributes, kvm_userspace_memory_region,
+    kvm_userspace_memory_region2, KVM_CAP_EXIT_HYPERCALL, KVM_CAP_GUEST_MEMFD,
+    KVM_CAP_MEMORY_ATTRIBUTES, KVM_MEMORY_ATTRIBUTE_PRIVATE, KVM_MEM_GUEST_MEMFD,
+};
+use kvm_ioctls::VmFd;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::ioctl::ioctl_with_val;
+
+pub(crate) const KVM_HC_MAP_GPA_RANGE: u64 = 12;
+const KVM_MAP_GPA_RANGE_ENCRYPTED: u64 = 1 << 4;
+// The number of pages of KVM_HC_MAP_GPA_RANGE is always in 4 KiB pages,
+// whatever the preferred page size given in the attributes.
+const MAP_GPA_RANGE_PAGE_SIZE: u64 = 4096;
+
+fn check_extension(vm_fd: &VmFd, cap: u32) -> i32 {
+    // SAFETY: ioctl on a valid VM fd, with no memory passed
+    unsafe { ioctl_with_val(vm_fd, KVM_CHECK_EXTENSION(), cap as libc::c_ulong) }
+}
+
+fn errno_to_io(e: kvm_ioctls::Error) -> io::Error {
+    io::Error::from_raw_os_error(e.errno())
+}
+
+/// Private memory of a VM, with the guest_memfd backing each memory slot.
+pub(crate) struct KvmPrivateMemory {
+    vm_fd: Arc<VmFd>,
+    guest_memfds: Mutex<HashMap<u32, File>>,
+}
+
+impl KvmPrivateMemory {
+    /// Check the VM supports private memory, and let the guest request
+    /// conversions through the KVM_HC_MAP_GPA_RANGE hypercall. This must be
+    /// done before any memory slot is created.
+    pub fn new(vm_fd: &Arc<VmFd>) -> io::Result<Arc<Self>> {
+        let attributes = check_extension(vm_fd, KVM_CAP_MEMORY_ATTRIBUTES);
+        if check_extension(vm_fd, KVM_CAP_GUEST_MEMFD) <= 0
+            || attributes < 0
+            || attributes as u32 & KVM_MEMORY_ATTRIBUTE_PRIVATE == 0
+        {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_EXIT_HYPERCALL,
+            ..Default::default()
+        };
+        cap.args[0] = 1 << KVM_HC_MAP_GPA_RANGE;
+        vm_fd.enable_cap(&cap).map_err(errno_to_io)?;
+
+        Ok(Arc::new(KvmPrivateMemory {
+            vm_fd: vm_fd.clone(),
+            guest_memfds: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Create a memory slot whose private pages are backed by a new
+    /// guest_memfd of the size of the slot.
+    pub fn create_region(&self, region: kvm_userspace_memory_region) -> io::Result<()> {
+        let gmem = kvm_create_guest_memfd {
+            size: region.memory_size,
+            ..Default::default()
+        };
+        let fd = self.vm_fd.create_guest_memfd(gmem).map_err(errno_to_io)?;
+        // SAFETY: the fd has just been created and is owned by nothing else
+        let guest_memfd = unsafe { File::from_raw_fd(fd) };
+
+        let region = kvm_userspace_memory_region2 {
+            slot: region.slot,
+            flags: region.flags | KVM_MEM_GUEST_MEMFD,
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            userspace_addr: region.userspace_addr,
+            guest_memfd_offset: 0,
+            guest_memfd: guest_memfd.as_raw_fd() as u32,
+            ..Default::default()
+        };
+        // SAFETY: Safe because guest regions are guaranteed not to overlap.
+        unsafe { self.vm_fd.set_user_memory_region2(region) }.map_err(errno_to_io)?;
+
+        self.guest_memfds
+            .lock()
+            .unwrap()
+            .insert(region.slot, guest_memfd);
+
+        Ok(())
+    }
+
+    /// Release the guest_memfd of a removed memory slot.
+    pub fn remove_region(&self, slot: u32) {
+        self.guest_memfds.lock().unwrap().remove(&slot);
+    }
+
+    /// Convert a range of guest memory to private or shared.
+    pub fn convert(&self, gpa: u64, size: u64, private: bool) -> io::Result<()> {
+        let attributes = kvm_memory_attributes {
+            address: gpa,
+            size,
+            attributes: if private {
+                KVM_MEMORY_ATTRIBUTE_PRIVATE as u64
+            } else {
+                0
+            },
+            flags: 0,
+        };
+        self.vm_fd
+            .set_memory_attributes(attributes)
+            .map_err(errno_to_io)
+    }
+}
+
+/// Decode the arguments of a KVM_HC_MAP_GPA_RANGE hypercall into the guest
+/// physical address and size of the range, and whether it must be private.
+pub(crate) fn map_gpa_range(args: &[u64; 6]) -> Option<(u64, u64, bool)> {
+    let (gpa, pages, attributes) = (args[0], args[1], args[2]);
+    if gpa % MAP_GPA_RANGE_PAGE_SIZE != 0 || pages == 0 {
+        return None;
+    }
+    let size = pages.checked_mul(MAP_GPA_RANGE_PAGE_SIZE)?;
+    let private = attributes & KVM_MAP_GPA_RANGE_ENCRYPTED != 0;
+
+    // The range must not wrap around the address space.
+    gpa.checked_add(size).map(|_| (gpa, size, private))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_gpa_range() {
+        assert_eq!(
+            map_gpa_range(&[0x10_0000, 2, KVM_MAP_GPA_RANGE_ENCRYPTED, 0, 0, 0]),
+            Some((0x10_0000, 0x2000, true))
+        );
+        assert_eq!(
+            map_gpa_range(&[0x10_0000, 1, 0, 0, 0, 0]),
+            Some((0x10_0000, 0x1000, false))
+        );
+        // The preferred page size doesn't change the unit of the page count.
+        assert_eq!(
+            map_gpa_range(&[0x20_0000, 512, KVM_MAP_GPA_RANGE_ENCRYPTED | 1, 0, 0, 0]),
+            Some((0x20_0000, 0x20_0000, true))
+        );
+
+        // Unaligned address, empty range and overflows are rejected.
+        assert_eq!(map_gpa_range(&[0x10_0800, 1, 0, 0, 0, 0]), None);
+        assert_eq!(map_gpa_range(&[0x10_0000, 0, 0, 0, 0, 0]), None);
+        assert_eq!(map_gpa_range(&[0, u64::MAX, 0, 0, 0, 0]), None);
+        assert_eq!(map_gpa_range(&[u64::MAX - 0xfff, 2, 0, 0, 0, 0]), None);
+    }
+}
@@ -52,7 +52,7 @@ pub use crate::hypervisor::{Hypervisor, HypervisorError};
 use concat_idents::concat_idents;
 #[cfg(target_arch = "x86_64")]
 pub use cpu::CpuVendor;
-pub use cpu::{HypervisorCpuError, Vcpu, VmExit};
+pub use cpu::{HypervisorCpuError, Vcpu, VcpuExitCounters, VcpuExitReason, VmExit};
 pub use device::HypervisorDeviceError;
 #[cfg(all(feature = "kvm", target_arch = "aarch64"))]
 pub use kvm::{aarch64, GicState};
@@ -179,6 +179,40 @@ impl ClockData {
             _ => {}
         }
     }
+
+    /// Guest clock, in nanoseconds.
+    pub fn guest_ns(&self) -> u64 {
+        match self {
+            #[cfg(feature = "kvm")]
+            ClockData::Kvm(s) => s.clock,
+            #[cfg(feature = "mshv")]
+            ClockData::Mshv(s) => s.ref_time * 100,
+        }
+    }
+
+    /// Host wall clock time, in nanoseconds, sampled atomically with the
+    /// guest clock when the hypervisor supports it.
+    pub fn host_realtime_ns(&self) -> Option<u64> {
+        match self {
+            #[cfg(feature = "kvm")]
+            ClockData::Kvm(s) => {
+                (s.flags & kvm_bindings::KVM_CLOCK_REALTIME != 0).then_some(s.realtime)
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Move the guest clock forward by the given number of nanoseconds.
+    pub fn advance(&mut self, ns: u64) {
+        match self {
+            #[cfg(feature = "kvm")]
+            ClockData::Kvm(s) => s.clock += ns,
+            // The reference time is counted in 100ns units.
+            #[cfg(feature = "mshv")]
+            ClockData::Mshv(s) => s.ref_time += ns / 100,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -42,6 +42,8 @@ use std::fs::File;
 use std::os::unix::io::AsRawFd;
 use vmm_sys_util::eventfd::EventFd;
 #[cfg(target_arch = "x86_64")]
+use x86_64::synic::*;
+#[cfg(target_arch = "x86_64")]
 pub use x86_64::*;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{emulator, VcpuMshvState};
@@ -59,6 +61,13 @@ pub use {
 
 pub const PAGE_SHIFT: usize = 12;
 
+/// The MSHV bindings only cover x86_64, the arm64 vCPU and VM operations
+/// report this error instead of being implemented.
+#[cfg(target_arch = "aarch64")]
+fn aarch64_unsupported() -> anyhow::Error {
+    anyhow!("operation not supported by MSHV on aarch64")
+}
+
 impl From<mshv_user_mem_region> for UserMemoryRegion {
     fn from(region: mshv_user_mem_region) -> Self {
         let mut flags: u32 = 0;
@@ -225,12 +234,22 @@ impl MshvHypervisor {
     /// Create a hypervisor based on Mshv
     #[allow(clippy::new_ret_no_self)]
     pub fn new() -> hypervisor::Result<Arc<dyn hypervisor::Hypervisor>> {
+        // The MSHV bindings do not expose the arm64 vCPU registers, the GIC
+        // setup or the doorbells yet.
+        if cfg!(target_arch = "aarch64") {
+            return Err(hypervisor::HypervisorError::HypervisorCreate(anyhow!(
+                "MSHV is not supported on aarch64"
+            )));
+        }
         let mshv_obj =
             Mshv::new().map_err(|e| hypervisor::HypervisorError::HypervisorCreate(e.into()))?;
         Ok(Arc::new(MshvHypervisor { mshv: mshv_obj }))
     }
     /// Check if the hypervisor is available
     pub fn is_available() -> hypervisor::Result<bool> {
+        if cfg!(target_arch = "aarch64") {
+            return Ok(false);
+        }
         match std::fs::metadata("/dev/mshv") {
             Ok(_) => Ok(true),
             Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
@@ -419,6 +438,22 @@ pub struct MshvVcpu {
     msrs: Vec<MsrEntry>,
     vm_ops: Option<Arc<dyn vm::VmOps>>,
     vm_fd: Arc<VmFd>,
+    exit_counters: Arc<cpu::VcpuExitCounters>,
+}
+
+fn exit_reason(message_type: hv_message_type) -> cpu::VcpuExitReason {
+    match message_type {
+        hv_message_type_HVMSG_X64_HALT => cpu::VcpuExitReason::Hlt,
+        hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT => cpu::VcpuExitReason::Pio,
+        hv_message_type_HVMSG_UNMAPPED_GPA | hv_message_type_HVMSG_GPA_INTERCEPT => {
+            cpu::VcpuExitReason::Mmio
+        }
+        hv_message_type_HVMSG_GPA_ATTRIBUTE_INTERCEPT | hv_message_type_HVMSG_UNACCEPTED_GPA => {
+            cpu::VcpuExitReason::EptViolation
+        }
+        hv_message_type_HVMSG_X64_MSR_INTERCEPT => cpu::VcpuExitReason::Msr,
+        _ => cpu::VcpuExitReason::Other,
+    }
 }
 
 /// Implementation of Vcpu trait for Microsoft Hypervisor
@@ -557,7 +592,13 @@ impl cpu::Vcpu for MshvVcpu {
     #[allow(non_upper_case_globals)]
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
         let hv_message: hv_message = hv_message::default();
-        match self.fd.run(hv_message) {
+        let result = self.fd.run(hv_message);
+        if let Ok(x) = &result {
+            self.exit_counters
+                .record(exit_reason(x.header.message_type));
+        }
+
+        match result {
             Ok(x) => match x.header.message_type {
                 hv_message_type_HVMSG_X64_HALT => {
                     debug!("HALT");
@@ -792,6 +833,34 @@ impl cpu::Vcpu for MshvVcpu {
                         info.interrupt_vector.try_into().unwrap(),
                     ))
                 }
+                #[cfg(target_arch = "x86_64")]
+                hv_message_type_HVMSG_HYPERCALL_INTERCEPT => {
+                    let mut reg_assocs = [
+                        hv_register_name_HV_X64_REGISTER_RIP,
+                        hv_register_name_HV_X64_REGISTER_RCX,
+                        hv_register_name_HV_X64_REGISTER_RDX,
+                    ]
+                    .map(|name| hv_register_assoc {
+                        name,
+                        ..Default::default()
+                    });
+                    self.fd
+                        .get_reg(&mut reg_assocs)
+                        .map_err(|e| cpu::HypervisorCpuError::GetRegister(e.into()))?;
+                    // SAFETY: Accessing a union element from bindgen generated bindings.
+                    let [rip, control, input] = reg_assocs.map(|reg| unsafe { reg.value.reg64 });
+
+                    let status = self.handle_synic_hypercall(control, input);
+
+                    /* Skip the VMCALL or VMMCALL instruction, both 3 bytes long */
+                    let arr_reg_name_value = [
+                        (hv_register_name_HV_X64_REGISTER_RIP, rip + 3),
+                        (hv_register_name_HV_X64_REGISTER_RAX, status as u64),
+                    ];
+                    set_registers_64!(self.fd, arr_reg_name_value)
+                        .map_err(|e| cpu::HypervisorCpuError::SetRegister(e.into()))?;
+                    Ok(cpu::VmExit::Ignore)
+                }
                 #[cfg(feature = "sev_snp")]
                 hv_message_type_HVMSG_X64_SEV_VMGEXIT_INTERCEPT => {
                     let info = x.to_vmg_intercept_info().unwrap();
@@ -1156,44 +1225,63 @@ impl cpu::Vcpu for MshvVcpu {
         }
     }
 
+    fn exit_counters(&self) -> Arc<cpu::VcpuExitCounters> {
+        self.exit_counters.clone()
+    }
+
     #[cfg(target_arch = "aarch64")]
-    fn init_pmu(&self, irq: u32) -> cpu::Result<()> {
-        unimplemented!()
+    fn init_pmu(&self, _irq: u32) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::InitializePmu)
     }
 
     #[cfg(target_arch = "aarch64")]
     fn has_pmu_support(&self) -> bool {
-        unimplemented!()
+        false
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn setup_regs(&self, cpu_id: u8, boot_ip: u64, fdt_start: u64) -> cpu::Result<()> {
-        unimplemented!()
+    fn setup_regs(&self, _cpu_id: u8, _boot_ip: u64, _fdt_start: u64) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::SetCoreRegister(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn get_sys_reg(&self, sys_reg: u32) -> cpu::Result<u64> {
-        unimplemented!()
+    fn get_sys_reg(&self, _sys_reg: u32) -> cpu::Result<u64> {
+        Err(cpu::HypervisorCpuError::GetSysRegister(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn get_reg_list(&self, reg_list: &mut RegList) -> cpu::Result<()> {
-        unimplemented!()
+    fn set_sys_reg(&self, _sys_reg: u32, _value: u64) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::SetSysRegister(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn vcpu_init(&self, kvi: &VcpuInit) -> cpu::Result<()> {
-        unimplemented!()
+    fn get_reg_list(&self, _reg_list: &mut RegList) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::GetRegList(aarch64_unsupported()))
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn set_regs(&self, regs: &StandardRegisters) -> cpu::Result<()> {
-        unimplemented!()
+    fn vcpu_init(&self, _kvi: &VcpuInit) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::VcpuInit(aarch64_unsupported()))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn set_regs(&self, _regs: &StandardRegisters) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::SetStandardRegs(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "aarch64")]
     fn get_regs(&self) -> cpu::Result<StandardRegisters> {
-        unimplemented!()
+        Err(cpu::HypervisorCpuError::GetStandardRegs(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -1303,8 +1391,10 @@ impl cpu::Vcpu for MshvVcpu {
     ///
     /// Set CPU state for aarch64 guest.
     ///
-    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
-        unimplemented!()
+    fn set_state(&self, _state: &CpuState) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::SetAllVpStateComponents(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -1351,7 +1441,9 @@ impl cpu::Vcpu for MshvVcpu {
     /// Get CPU state for aarch64 guest.
     ///
     fn state(&self) -> cpu::Result<CpuState> {
-        unimplemented!()
+        Err(cpu::HypervisorCpuError::GetAllVpStateComponents(
+            aarch64_unsupported(),
+        ))
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -1459,6 +1551,53 @@ impl MshvVcpu {
 
         Ok(())
     }
+    ///
+    /// Forward a SynIC hypercall of the guest to the relay, returning the
+    /// hypercall status.
+    ///
+    #[cfg(target_arch = "x86_64")]
+    fn handle_synic_hypercall(&self, control: u64, input: u64) -> u16 {
+        let Some(vm_ops) = &self.vm_ops else {
+            return HV_STATUS_INVALID_CONNECTION_ID;
+        };
+
+        let result = match decode_hypercall(control, input) {
+            Ok(SynicHypercall::PostMessage { input_gpa }) => {
+                let mut input = [0u8; HV_POST_MESSAGE_INPUT_SIZE];
+                if vm_ops.guest_mem_read(input_gpa, &mut input).is_err() {
+                    return HV_STATUS_INVALID_PARAMETER;
+                }
+                match decode_post_message(&input) {
+                    Ok((connection_id, message_type, payload)) => {
+                        vm_ops.synic_post_message(connection_id, message_type, payload)
+                    }
+                    Err(status) => return status,
+                }
+            }
+            Ok(SynicHypercall::SignalEvent {
+                connection_id,
+                flag,
+            }) => vm_ops.synic_signal_event(connection_id, flag),
+            Ok(SynicHypercall::SignalEventMemory { input_gpa }) => {
+                let mut input = [0u8; 8];
+                if vm_ops.guest_mem_read(input_gpa, &mut input).is_err() {
+                    return HV_STATUS_INVALID_PARAMETER;
+                }
+                let (connection_id, flag) = decode_signal_event(u64::from_le_bytes(input));
+                vm_ops.synic_signal_event(connection_id, flag)
+            }
+            Err(status) => return status,
+        };
+
+        match result {
+            Ok(()) => HV_STATUS_SUCCESS,
+            Err(e) => {
+                debug!("SynIC hypercall not relayed: {}", e);
+                HV_STATUS_INVALID_CONNECTION_ID
+            }
+        }
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// X86 specific call that returns the vcpu's current "xcrs".
@@ -1668,6 +1807,7 @@ impl vm::Vm for MshvVm {
             msrs: self.msrs.clone(),
             vm_ops,
             vm_fd: self.fd.clone(),
+            exit_counters: Arc::new(cpu::VcpuExitCounters::default()),
         };
         Ok(Arc::new(vcpu))
     }
@@ -1682,6 +1822,32 @@ impl vm::Vm for MshvVm {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn enable_synic_relay(&self) -> vm::Result<()> {
+        let intercept = mshv_install_intercept {
+            access_type_mask: HV_INTERCEPT_ACCESS_MASK_EXECUTE,
+            intercept_type: hv_intercept_type_HV_INTERCEPT_TYPE_HYPERCALL,
+            intercept_parameter: Default::default(),
+        };
+        self.fd
+            .install_intercept(intercept)
+            .map_err(|e| vm::HypervisorVmError::EnableSynicRelay(e.into()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn post_synic_message(&self, vp: u32, sint: u8, message: &[u8]) -> vm::Result<()> {
+        self.fd
+            .post_message_direct(vp, sint.into(), message)
+            .map_err(|e| vm::HypervisorVmError::PostSynicMessage(e.into()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn signal_synic_event(&self, vp: u32, sint: u8, flag: u16) -> vm::Result<bool> {
+        self.fd
+            .signal_event_direct(vp, sint, flag)
+            .map_err(|e| vm::HypervisorVmError::SignalSynicEvent(e.into()))
+    }
+
     fn register_ioevent(
         &self,
         fd: &EventFd,
@@ -1868,11 +2034,11 @@ impl vm::Vm for MshvVm {
         for (_, s) in dirty_log_slots.iter() {
             self.fd
                 .get_dirty_log(s.guest_pfn, s.memory_size as usize, DIRTY_BITMAP_SET_DIRTY)
-                .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
+                .map_err(|e| vm::HypervisorVmError::StopDirtyLog(e.into()))?;
         }
         self.fd
             .disable_dirty_page_tracking()
-            .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
+            .map_err(|e| vm::HypervisorVmError::StopDirtyLog(e.into()))?;
         Ok(())
     }
 
@@ -2011,13 +2177,15 @@ impl vm::Vm for MshvVm {
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn create_vgic(&self, config: VgicConfig) -> vm::Result<Arc<Mutex<dyn Vgic>>> {
-        unimplemented!()
+    fn create_vgic(&self, _config: VgicConfig) -> vm::Result<Arc<Mutex<dyn Vgic>>> {
+        Err(vm::HypervisorVmError::CreateVgic(aarch64_unsupported()))
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn get_preferred_target(&self, kvi: &mut VcpuInit) -> vm::Result<()> {
-        unimplemented!()
+    fn get_preferred_target(&self, _kvi: &mut VcpuInit) -> vm::Result<()> {
+        Err(vm::HypervisorVmError::GetPreferredTarget(
+            aarch64_unsupported(),
+        ))
     }
 
     /// Pause the VM
@@ -14,6 +14,7 @@ use serde::{Deserialize, Serialize};
 use std::fmt;
 
 pub mod emulator;
+pub(crate) mod synic;
 
 ///
 /// Export generically-named wrappers of mshv_bindings for Unix-based platforms
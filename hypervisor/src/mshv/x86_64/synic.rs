@@ -0,0 +1,186 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Decoding of the SynIC hypercalls relayed to the VMM.
+//!
+//! VMBus guests talk to the host through two hypercalls: HvPostMessage,
+//! sending a message to a connection, and HvSignalEvent, setting an event
+//! flag of a connection. Once the hypercall intercept is installed, the
+//! hypervisor hands them over to the VMM which forwards them to the relay.
+
+pub(crate) const HVCALL_POST_MESSAGE: u16 = 0x005c;
+pub(crate) const HVCALL_SIGNAL_EVENT: u16 = 0x005d;
+
+pub(crate) const HV_STATUS_SUCCESS: u16 = 0x0000;
+pub(crate) const HV_STATUS_INVALID_HYPERCALL_CODE: u16 = 0x0002;
+pub(crate) const HV_STATUS_INVALID_HYPERCALL_INPUT: u16 = 0x0003;
+pub(crate) const HV_STATUS_INVALID_PARAMETER: u16 = 0x0005;
+pub(crate) const HV_STATUS_INVALID_CONNECTION_ID: u16 = 0x0012;
+
+/// Size of the HvPostMessage input, with the largest payload.
+pub(crate) const HV_POST_MESSAGE_INPUT_SIZE: usize = 256;
+const HV_MESSAGE_PAYLOAD_SIZE: usize = 240;
+const HV_POST_MESSAGE_HEADER_SIZE: usize = HV_POST_MESSAGE_INPUT_SIZE - HV_MESSAGE_PAYLOAD_SIZE;
+// Message types with the high bit set are reserved to the hypervisor.
+const HV_MESSAGE_TYPE_HYPERVISOR_MASK: u32 = 0x8000_0000;
+
+const HV_HYPERCALL_FAST_BIT: u64 = 1 << 16;
+// Beyond the call code and the fast bit, the variable header size, the rep
+// count and rep start index, as well as the reserved bits of the control
+// value must be zero for these hypercalls.
+const HV_HYPERCALL_RESERVED_MASK: u64 = !0x1_ffff;
+
+/// Hypercall issued by the guest, decoded from its control value and input
+/// registers.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SynicHypercall {
+    /// HvPostMessage, whose input is read from guest memory.
+    PostMessage { input_gpa: u64 },
+    /// HvSignalEvent
+    SignalEvent { connection_id: u32, flag: u16 },
+    /// HvSignalEvent, whose input is read from guest memory.
+    SignalEventMemory { input_gpa: u64 },
+}
+
+/// Decode the hypercall from the control value in RCX and the input in
+/// RDX, either a value for fast hypercalls or the guest physical address
+/// of the input.
+pub(crate) fn decode_hypercall(control: u64, input: u64) -> Result<SynicHypercall, u16> {
+    let code = control as u16;
+    let fast = control & HV_HYPERCALL_FAST_BIT != 0;
+
+    if code != HVCALL_POST_MESSAGE && code != HVCALL_SIGNAL_EVENT {
+        return Err(HV_STATUS_INVALID_HYPERCALL_CODE);
+    }
+    if control & HV_HYPERCALL_RESERVED_MASK != 0 {
+        return Err(HV_STATUS_INVALID_HYPERCALL_INPUT);
+    }
+
+    match (code, fast) {
+        (HVCALL_POST_MESSAGE, false) => Ok(SynicHypercall::PostMessage { input_gpa: input }),
+        // The input doesn't fit in the registers.
+        (HVCALL_POST_MESSAGE, true) => Err(HV_STATUS_INVALID_HYPERCALL_INPUT),
+        (_, true) => {
+            let (connection_id, flag) = decode_signal_event(input);
+            Ok(SynicHypercall::SignalEvent {
+                connection_id,
+                flag,
+            })
+        }
+        (_, false) => Ok(SynicHypercall::SignalEventMemory { input_gpa: input }),
+    }
+}
+
+/// Decode the 8 bytes input of HvSignalEvent into the connection and the
+/// event flag.
+pub(crate) fn decode_signal_event(input: u64) -> (u32, u16) {
+    (input as u32, (input >> 32) as u16)
+}
+
+/// Decode the input of HvPostMessage into the connection, message type and
+/// payload of the message.
+pub(crate) fn decode_post_message(
+    input: &[u8; HV_POST_MESSAGE_INPUT_SIZE],
+) -> Result<(u32, u32, &[u8]), u16> {
+    let field = |offset: usize| u32::from_le_bytes(input[offset..offset + 4].try_into().unwrap());
+    let connection_id = field(0);
+    let message_type = field(8);
+    let payload_size = field(12) as usize;
+
+    if message_type == 0
+        || message_type & HV_MESSAGE_TYPE_HYPERVISOR_MASK != 0
+        || payload_size > HV_MESSAGE_PAYLOAD_SIZE
+    {
+        return Err(HV_STATUS_INVALID_PARAMETER);
+    }
+
+    Ok((
+        connection_id,
+        message_type,
+        &input[HV_POST_MESSAGE_HEADER_SIZE..HV_POST_MESSAGE_HEADER_SIZE + payload_size],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_hypercall() {
+        assert_eq!(
+            decode_hypercall(HVCALL_POST_MESSAGE as u64, 0x1000),
+            Ok(SynicHypercall::PostMessage { input_gpa: 0x1000 })
+        );
+        assert_eq!(
+            decode_hypercall(HVCALL_POST_MESSAGE as u64 | HV_HYPERCALL_FAST_BIT, 0x1000),
+            Err(HV_STATUS_INVALID_HYPERCALL_INPUT)
+        );
+        assert_eq!(
+            decode_hypercall(
+                HVCALL_SIGNAL_EVENT as u64 | HV_HYPERCALL_FAST_BIT,
+                0x0003_0000_0042
+            ),
+            Ok(SynicHypercall::SignalEvent {
+                connection_id: 0x42,
+                flag: 3
+            })
+        );
+        assert_eq!(
+            decode_hypercall(HVCALL_SIGNAL_EVENT as u64, 0x2000),
+            Ok(SynicHypercall::SignalEventMemory { input_gpa: 0x2000 })
+        );
+
+        // Other hypercalls and rep hypercalls are rejected.
+        assert_eq!(
+            decode_hypercall(0x0008, 0),
+            Err(HV_STATUS_INVALID_HYPERCALL_CODE)
+        );
+        assert_eq!(
+            decode_hypercall(HVCALL_SIGNAL_EVENT as u64 | (1 << 32), 0),
+            Err(HV_STATUS_INVALID_HYPERCALL_INPUT)
+        );
+        assert_eq!(
+            decode_hypercall(HVCALL_POST_MESSAGE as u64 | (1 << 48), 0),
+            Err(HV_STATUS_INVALID_HYPERCALL_INPUT)
+        );
+    }
+
+    #[test]
+    fn test_decode_post_message() {
+        let mut input = [0u8; HV_POST_MESSAGE_INPUT_SIZE];
+        input[0..4].copy_from_slice(&0x42u32.to_le_bytes());
+        input[8..12].copy_from_slice(&1u32.to_le_bytes());
+        input[12..16].copy_from_slice(&3u32.to_le_bytes());
+        input[16..19].copy_from_slice(&[0xa, 0xb, 0xc]);
+
+        assert_eq!(
+            decode_post_message(&input),
+            Ok((0x42, 1, &[0xa, 0xb, 0xc][..]))
+        );
+
+        // The largest payload fills the input.
+        input[12..16].copy_from_slice(&240u32.to_le_bytes());
+        assert_eq!(decode_post_message(&input).unwrap().2.len(), 240);
+
+        input[12..16].copy_from_slice(&241u32.to_le_bytes());
+        assert_eq!(
+            decode_post_message(&input),
+            Err(HV_STATUS_INVALID_PARAMETER)
+        );
+
+        // Hypervisor and null message types can't be sent by the guest.
+        input[12..16].copy_from_slice(&0u32.to_le_bytes());
+        input[8..12].copy_from_slice(&0x8000_0001u32.to_le_bytes());
+        assert_eq!(
+            decode_post_message(&input),
+            Err(HV_STATUS_INVALID_PARAMETER)
+        );
+        input[8..12].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(
+            decode_post_message(&input),
+            Err(HV_STATUS_INVALID_PARAMETER)
+        );
+    }
+}
@@ -128,6 +128,11 @@ pub enum HypervisorVmError {
     #[error("Failed to enable SGX attribute: {0}")]
     EnableSgxAttribute(#[source] anyhow::Error),
     ///
+    /// Enable dirty ring error
+    ///
+    #[error("Failed to enable dirty ring: {0}")]
+    EnableDirtyRing(#[source] anyhow::Error),
+    ///
     /// Get clock error
     ///
     #[error("Failed to get clock: {0}")]
@@ -241,6 +246,21 @@ pub enum HypervisorVmError {
     #[cfg(feature = "sev_snp")]
     #[error("Failed to modify GPA host access: {0}")]
     ModifyGpaHostAccess(#[source] anyhow::Error),
+    ///
+    /// Enabling the SynIC relay error
+    ///
+    #[error("Failed to enable the SynIC relay: {0}")]
+    EnableSynicRelay(#[source] anyhow::Error),
+    ///
+    /// Posting a SynIC message error
+    ///
+    #[error("Failed to post SynIC message: {0}")]
+    PostSynicMessage(#[source] anyhow::Error),
+    ///
+    /// Signaling a SynIC event error
+    ///
+    #[error("Failed to signal SynIC event: {0}")]
+    SignalSynicEvent(#[source] anyhow::Error),
 }
 ///
 /// Result type for returning from a function
@@ -338,6 +358,39 @@ pub trait Vm: Send + Sync + Any {
     fn enable_split_irq(&self) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     fn enable_sgx_attribute(&self, file: File) -> Result<()>;
+    /// Track dirty pages with per vCPU rings instead of a bitmap. This must
+    /// be done before any vCPU is created.
+    #[cfg(target_arch = "x86_64")]
+    fn enable_dirty_ring(&self) -> Result<()> {
+        Err(HypervisorVmError::EnableDirtyRing(anyhow!(
+            "not supported by the hypervisor"
+        )))
+    }
+    /// Hand the HvPostMessage and HvSignalEvent hypercalls of the guest
+    /// over to the VMM, through [`VmOps::synic_post_message`] and
+    /// [`VmOps::synic_signal_event`].
+    #[cfg(target_arch = "x86_64")]
+    fn enable_synic_relay(&self) -> Result<()> {
+        Err(HypervisorVmError::EnableSynicRelay(anyhow!(
+            "not supported by the hypervisor"
+        )))
+    }
+    /// Post a Hyper-V message, as a 256 bytes `HV_MESSAGE`, to a SynIC
+    /// interrupt source of a vCPU.
+    #[cfg(target_arch = "x86_64")]
+    fn post_synic_message(&self, _vp: u32, _sint: u8, _message: &[u8]) -> Result<()> {
+        Err(HypervisorVmError::PostSynicMessage(anyhow!(
+            "not supported by the hypervisor"
+        )))
+    }
+    /// Set a flag of the SynIC event page of a vCPU, returning whether the
+    /// flag was newly set.
+    #[cfg(target_arch = "x86_64")]
+    fn signal_synic_event(&self, _vp: u32, _sint: u8, _flag: u16) -> Result<bool> {
+        Err(HypervisorVmError::SignalSynicEvent(anyhow!(
+            "not supported by the hypervisor"
+        )))
+    }
     /// Retrieve guest clock.
     #[cfg(target_arch = "x86_64")]
     fn get_clock(&self) -> Result<ClockData>;
@@ -426,4 +479,17 @@ pub trait VmOps: Send + Sync {
     fn pio_read(&self, port: u64, data: &mut [u8]) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     fn pio_write(&self, port: u64, data: &[u8]) -> Result<()>;
+    /// Message posted by the guest with HvPostMessage to a connection of
+    /// the SynIC relay.
+    #[cfg(target_arch = "x86_64")]
+    fn synic_post_message(
+        &self,
+        connection_id: u32,
+        message_type: u32,
+        payload: &[u8],
+    ) -> Result<()>;
+    /// Event signaled by the guest with HvSignalEvent to a connection of the
+    /// SynIC relay.
+    #[cfg(target_arch = "x86_64")]
+    fn synic_signal_event(&self, connection_id: u32, flag: u16) -> Result<()>;
 }
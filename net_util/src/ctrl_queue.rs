@@ -5,16 +5,18 @@
 use crate::GuestMemoryMmap;
 use crate::Tap;
 use libc::c_uint;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use virtio_bindings::virtio_net::{
-    VIRTIO_NET_CTRL_GUEST_OFFLOADS, VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET, VIRTIO_NET_CTRL_MQ,
-    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
-    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6,
-    VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_OK,
+    VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK, VIRTIO_NET_CTRL_GUEST_OFFLOADS,
+    VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_OK,
 };
 use virtio_queue::{Queue, QueueT};
-use vm_memory::{ByteValued, Bytes, GuestMemoryError};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError};
+use vm_virtio::packed::PackedQueue;
 use vm_virtio::{AccessPlatform, Translatable};
 
 #[derive(Debug)]
@@ -33,10 +35,60 @@ pub enum Error {
     QueueIterator(virtio_queue::Error),
     /// Failed enabling notification for the queue
     QueueEnableNotification(virtio_queue::Error),
+    /// Failed processing the packed queue
+    PackedQueue(vm_virtio::packed::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+// Notification coalescing definitions from the VIRTIO 1.3 specification,
+// which are not part of the virtio-bindings yet.
+pub const VIRTIO_NET_F_NOTF_COAL: u32 = 53;
+const VIRTIO_NET_CTRL_NOTF_COAL: u32 = 6;
+const VIRTIO_NET_CTRL_NOTF_COAL_TX_SET: u32 = 0;
+const VIRTIO_NET_CTRL_NOTF_COAL_RX_SET: u32 = 1;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CoalescingParameters {
+    max_packets: u32,
+    usecs: u32,
+}
+
+// SAFETY: CoalescingParameters only contains a series of integers
+unsafe impl ByteValued for CoalescingParameters {}
+
+// Value of the parameters until the driver sets them.
+const COALESCING_NOT_SET: u64 = u64::MAX;
+
+/// Notification coalescing parameters of the RX or TX queues, as set by the
+/// driver through the VIRTIO_NET_CTRL_NOTF_COAL commands.
+pub struct NotificationCoalescing(AtomicU64);
+
+impl Default for NotificationCoalescing {
+    fn default() -> Self {
+        NotificationCoalescing(AtomicU64::new(COALESCING_NOT_SET))
+    }
+}
+
+impl NotificationCoalescing {
+    pub fn set(&self, max_packets: u32, max_usecs: u32) {
+        self.0.store(
+            u64::from(max_packets) << 32 | u64::from(max_usecs),
+            Ordering::Release,
+        );
+    }
+
+    /// Maximum number of packets and delay in microseconds before a used
+    /// buffer notification must be sent, unless not set by the driver.
+    pub fn get(&self) -> Option<(u32, u32)> {
+        match self.0.load(Ordering::Acquire) {
+            COALESCING_NOT_SET => None,
+            v => Some(((v >> 32) as u32, v as u32)),
+        }
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ControlHeader {
@@ -49,11 +101,21 @@ unsafe impl ByteValued for ControlHeader {}
 
 pub struct CtrlQueue {
     pub taps: Vec<Tap>,
+    pub rx_coalescing: Option<Arc<NotificationCoalescing>>,
+    pub tx_coalescing: Option<Arc<NotificationCoalescing>>,
+    // Set while the guest is asked to announce itself on the network, until
+    // the driver acknowledges it.
+    pub announce: Option<Arc<AtomicBool>>,
 }
 
 impl CtrlQueue {
     pub fn new(taps: Vec<Tap>) -> Self {
-        CtrlQueue { taps }
+        CtrlQueue {
+            taps,
+            rx_coalescing: None,
+            tx_coalescing: None,
+            announce: None,
+        }
     }
 
     pub fn process(
@@ -62,85 +124,20 @@ impl CtrlQueue {
         queue: &mut Queue,
         access_platform: Option<&Arc<dyn AccessPlatform>>,
     ) -> Result<()> {
-        while let Some(mut desc_chain) = queue.pop_descriptor_chain(mem) {
-            let ctrl_desc = desc_chain.next().ok_or(Error::NoControlHeaderDescriptor)?;
-
-            let ctrl_hdr: ControlHeader = desc_chain
-                .memory()
-                .read_obj(
-                    ctrl_desc
-                        .addr()
-                        .translate_gva(access_platform, ctrl_desc.len() as usize),
-                )
-                .map_err(Error::GuestMemory)?;
-            let data_desc = desc_chain.next().ok_or(Error::NoDataDescriptor)?;
-
-            let data_desc_addr = data_desc
-                .addr()
-                .translate_gva(access_platform, data_desc.len() as usize);
-
-            let status_desc = desc_chain.next().ok_or(Error::NoStatusDescriptor)?;
-
-            let ok = match u32::from(ctrl_hdr.class) {
-                VIRTIO_NET_CTRL_MQ => {
-                    let queue_pairs = desc_chain
-                        .memory()
-                        .read_obj::<u16>(data_desc_addr)
-                        .map_err(Error::GuestMemory)?;
-                    if u32::from(ctrl_hdr.cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-                        warn!("Unsupported command: {}", ctrl_hdr.cmd);
-                        false
-                    } else if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
-                        || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
-                    {
-                        warn!("Number of MQ pairs out of range: {}", queue_pairs);
-                        false
-                    } else {
-                        info!("Number of MQ pairs requested: {}", queue_pairs);
-                        true
-                    }
-                }
-                VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
-                    let features = desc_chain
-                        .memory()
-                        .read_obj::<u64>(data_desc_addr)
-                        .map_err(Error::GuestMemory)?;
-                    if u32::from(ctrl_hdr.cmd) != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET {
-                        warn!("Unsupported command: {}", ctrl_hdr.cmd);
-                        false
-                    } else {
-                        let mut ok = true;
-                        for tap in self.taps.iter_mut() {
-                            info!("Reprogramming tap offload with features: {}", features);
-                            tap.set_offload(virtio_features_to_tap_offload(features))
-                                .map_err(|e| {
-                                    error!("Error programming tap offload: {:?}", e);
-                                    ok = false
-                                })
-                                .ok();
-                        }
-                        ok
-                    }
-                }
-                _ => {
-                    warn!("Unsupported command {:?}", ctrl_hdr);
-                    false
-                }
-            };
-
-            desc_chain
-                .memory()
-                .write_obj(
-                    if ok { VIRTIO_NET_OK } else { VIRTIO_NET_ERR } as u8,
-                    status_desc
-                        .addr()
-                        .translate_gva(access_platform, status_desc.len() as usize),
-                )
-                .map_err(Error::GuestMemory)?;
-            let len = ctrl_desc.len() + data_desc.len() + status_desc.len();
+        while let Some(desc_chain) = queue.pop_descriptor_chain(mem) {
+            let head_index = desc_chain.head_index();
+            let descriptors: Vec<(GuestAddress, u32)> = desc_chain
+                .map(|d| {
+                    (
+                        d.addr().translate_gva(access_platform, d.len() as usize),
+                        d.len(),
+                    )
+                })
+                .collect();
+            let len = self.process_command(mem, &descriptors)?;
 
             queue
-                .add_used(desc_chain.memory(), desc_chain.head_index(), len)
+                .add_used(mem, head_index, len)
                 .map_err(Error::QueueAddUsed)?;
 
             if !queue
@@ -153,6 +150,143 @@ impl CtrlQueue {
 
         Ok(())
     }
+
+    /// Process the control queue when VIRTIO_F_RING_PACKED is negotiated.
+    pub fn process_packed(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queue: &mut PackedQueue,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> Result<()> {
+        while let Some(desc_chain) = queue
+            .pop_descriptor_chain(mem)
+            .map_err(Error::PackedQueue)?
+        {
+            let descriptors: Vec<(GuestAddress, u32)> = desc_chain
+                .descriptors
+                .iter()
+                .map(|d| (d.addr.translate_gva(access_platform, d.len as usize), d.len))
+                .collect();
+            let len = self.process_command(mem, &descriptors)?;
+
+            queue
+                .add_used(mem, &desc_chain, len)
+                .map_err(Error::PackedQueue)?;
+
+            if !queue.enable_notification(mem).map_err(Error::PackedQueue)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Process the command of a chain, given as the translated address and
+    // length of its descriptors, and write its status. Return the length of
+    // the descriptors used.
+    fn process_command(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        descriptors: &[(GuestAddress, u32)],
+    ) -> Result<u32> {
+        // The commands without any data, such as the acknowledgement of the
+        // guest announcement, come without a data descriptor.
+        let (ctrl_desc, data_desc, status_desc) = match descriptors {
+            [] => return Err(Error::NoControlHeaderDescriptor),
+            [_] => return Err(Error::NoStatusDescriptor),
+            [ctrl_desc, status_desc] => (ctrl_desc, None, status_desc),
+            [ctrl_desc, data_desc, status_desc, ..] => (ctrl_desc, Some(data_desc), status_desc),
+        };
+
+        let ctrl_hdr: ControlHeader = mem.read_obj(ctrl_desc.0).map_err(Error::GuestMemory)?;
+        let data_desc_addr = || data_desc.map(|d| d.0).ok_or(Error::NoDataDescriptor);
+
+        let ok = match u32::from(ctrl_hdr.class) {
+            VIRTIO_NET_CTRL_MQ => {
+                let queue_pairs = mem
+                    .read_obj::<u16>(data_desc_addr()?)
+                    .map_err(Error::GuestMemory)?;
+                if u32::from(ctrl_hdr.cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
+                    warn!("Unsupported command: {}", ctrl_hdr.cmd);
+                    false
+                } else if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
+                    || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
+                {
+                    warn!("Number of MQ pairs out of range: {}", queue_pairs);
+                    false
+                } else {
+                    info!("Number of MQ pairs requested: {}", queue_pairs);
+                    true
+                }
+            }
+            VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
+                let features = mem
+                    .read_obj::<u64>(data_desc_addr()?)
+                    .map_err(Error::GuestMemory)?;
+                if u32::from(ctrl_hdr.cmd) != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET {
+                    warn!("Unsupported command: {}", ctrl_hdr.cmd);
+                    false
+                } else {
+                    let mut ok = true;
+                    for tap in self.taps.iter_mut() {
+                        info!("Reprogramming tap offload with features: {}", features);
+                        tap.set_offload(virtio_features_to_tap_offload(features))
+                            .map_err(|e| {
+                                error!("Error programming tap offload: {:?}", e);
+                                ok = false
+                            })
+                            .ok();
+                    }
+                    ok
+                }
+            }
+            VIRTIO_NET_CTRL_NOTF_COAL => {
+                let params = mem
+                    .read_obj::<CoalescingParameters>(data_desc_addr()?)
+                    .map_err(Error::GuestMemory)?;
+                let coalescing = match u32::from(ctrl_hdr.cmd) {
+                    VIRTIO_NET_CTRL_NOTF_COAL_TX_SET => self.tx_coalescing.as_ref(),
+                    VIRTIO_NET_CTRL_NOTF_COAL_RX_SET => self.rx_coalescing.as_ref(),
+                    _ => None,
+                };
+                if let Some(coalescing) = coalescing {
+                    let (max_packets, usecs) = (params.max_packets, params.usecs);
+                    info!(
+                        "Notification coalescing requested: max_packets={} usecs={}",
+                        max_packets, usecs
+                    );
+                    coalescing.set(max_packets, usecs);
+                    true
+                } else {
+                    warn!("Unsupported command: {}", ctrl_hdr.cmd);
+                    false
+                }
+            }
+            VIRTIO_NET_CTRL_ANNOUNCE => match (u32::from(ctrl_hdr.cmd), self.announce.as_ref()) {
+                (VIRTIO_NET_CTRL_ANNOUNCE_ACK, Some(announce)) => {
+                    info!("Guest announcement acknowledged");
+                    announce.store(false, Ordering::Release);
+                    true
+                }
+                _ => {
+                    warn!("Unsupported command: {}", ctrl_hdr.cmd);
+                    false
+                }
+            },
+            _ => {
+                warn!("Unsupported command {:?}", ctrl_hdr);
+                false
+            }
+        };
+
+        mem.write_obj(
+            if ok { VIRTIO_NET_OK } else { VIRTIO_NET_ERR } as u8,
+            status_desc.0,
+        )
+        .map_err(Error::GuestMemory)?;
+
+        Ok(ctrl_desc.1 + data_desc.map_or(0, |d| d.1) + status_desc.1)
+    }
 }
 
 pub fn virtio_features_to_tap_offload(features: u64) -> c_uint {
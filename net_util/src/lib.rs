@@ -13,6 +13,9 @@ mod mac;
 mod open_tap;
 mod queue_pair;
 mod tap;
+#[cfg(feature = "io_uring")]
+mod tap_io_uring;
+mod xdp;
 
 use serde::{Deserialize, Serialize};
 use std::io::Error as IoError;
@@ -30,11 +33,16 @@ use vm_memory::{bitmap::AtomicBitmap, ByteValued};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
-pub use ctrl_queue::{CtrlQueue, Error as CtrlQueueError};
+pub use ctrl_queue::{
+    CtrlQueue, Error as CtrlQueueError, NotificationCoalescing, VIRTIO_NET_F_NOTF_COAL,
+};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
-pub use open_tap::{open_tap, Error as OpenTapError};
+pub use open_tap::{open_macvtap, open_tap, Error as OpenTapError};
 pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
 pub use tap::{Error as TapError, Tap};
+#[cfg(feature = "io_uring")]
+pub use tap_io_uring::TapIoUring;
+pub use xdp::{open_xdp, Error as XdpError, XdpSocket};
 
 #[derive(Error, Debug)]
 pub enum Error {
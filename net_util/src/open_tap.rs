@@ -3,7 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::{vnet_hdr_len, MacAddr, Tap, TapError};
+use std::fs::{File, OpenOptions};
 use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::{fs, io};
 use thiserror::Error;
@@ -34,6 +36,14 @@ pub enum Error {
     TapSetMtu(TapError),
     #[error("Enabling tap interface failed: {0}")]
     TapEnable(TapError),
+    #[error("Interface {0} is not a macvtap interface")]
+    NotMacvtap(String),
+    #[error("Failed to read the macvtap interface from sysfs: {0}")]
+    ReadSysfsMacvtap(io::Error),
+    #[error("Failed to parse the macvtap MAC address: {0}")]
+    MacvtapMacParsing(io::Error),
+    #[error("Failed to open the macvtap device: {0}")]
+    MacvtapOpen(io::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -129,3 +139,35 @@ pub fn open_tap(
     }
     Ok(taps)
 }
+
+/// Open the character device of an existing macvtap interface once per
+/// queue pair, and return the MAC address of the interface, which is the
+/// one the guest must use for its traffic to be delivered.
+pub fn open_macvtap(if_name: &str, num_queue_pairs: usize) -> Result<(Vec<File>, MacAddr)> {
+    let sysfs = format!("/sys/class/net/{if_name}");
+    let ifindex =
+        fs::read_to_string(format!("{sysfs}/ifindex")).map_err(Error::ReadSysfsMacvtap)?;
+    let ifindex = ifindex.trim();
+    // Only macvtap interfaces come with a tap character device.
+    if !Path::new(&format!("{sysfs}/macvtap/tap{ifindex}")).exists() {
+        return Err(Error::NotMacvtap(if_name.to_owned()));
+    }
+
+    let address =
+        fs::read_to_string(format!("{sysfs}/address")).map_err(Error::ReadSysfsMacvtap)?;
+    let mac = MacAddr::parse_str(address.trim()).map_err(Error::MacvtapMacParsing)?;
+
+    // Each open of the character device attaches a new queue.
+    let mut files = Vec::new();
+    for _ in 0..num_queue_pairs {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(format!("/dev/tap{ifindex}"))
+            .map_err(Error::MacvtapOpen)?;
+        files.push(file);
+    }
+
+    Ok((files, mac))
+}
@@ -2,14 +2,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
+#[cfg(feature = "io_uring")]
+use super::TapIoUring;
+use super::{register_listener, unregister_listener, vnet_hdr_len, Tap, XdpSocket};
 use rate_limiter::{RateLimiter, TokenType};
 use std::io;
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
+use virtio_bindings::virtio_net::virtio_net_hdr;
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
 use vm_memory::bitmap::Bitmap;
 use vm_memory::{Bytes, GuestMemory};
@@ -147,6 +150,9 @@ impl TxVirtio {
 pub struct RxVirtio {
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    // Set when a legacy driver did not negotiate VIRTIO_NET_F_MRG_RXBUF, in
+    // which case the header has no num_buffers field.
+    pub legacy_hdr: bool,
     iovecs: IovecBuffer,
 }
 
@@ -161,10 +167,19 @@ impl RxVirtio {
         RxVirtio {
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            legacy_hdr: false,
             iovecs: IovecBuffer::new(),
         }
     }
 
+    pub(crate) fn hdr_len(&self) -> usize {
+        if self.legacy_hdr {
+            std::mem::size_of::<virtio_net_hdr>()
+        } else {
+            vnet_hdr_len()
+        }
+    }
+
     pub fn process_desc_chain<B: Bitmap + 'static>(
         &mut self,
         mem: &vm_memory::GuestMemoryMmap<B>,
@@ -248,18 +263,20 @@ impl RxVirtio {
                     return Err(NetQueuePairError::ReadTap(e));
                 }
 
-                if (result as usize) < vnet_hdr_len() {
+                if (result as usize) < self.hdr_len() {
                     return Err(NetQueuePairError::InvalidVirtioNetHeader);
                 }
 
                 // Write num_buffers to guest memory. We simply write 1 as we
                 // never spread the frame over more than one descriptor chain.
-                desc_chain
-                    .memory()
-                    .write_obj(1u16, num_buffers_addr)
-                    .map_err(NetQueuePairError::GuestMemory)?;
+                if !self.legacy_hdr {
+                    desc_chain
+                        .memory()
+                        .write_obj(1u16, num_buffers_addr)
+                        .map_err(NetQueuePairError::GuestMemory)?;
+                }
 
-                self.counter_bytes += Wrapping(result as u64 - vnet_hdr_len() as u64);
+                self.counter_bytes += Wrapping(result as u64 - self.hdr_len() as u64);
                 self.counter_frames += Wrapping(1);
 
                 result as u32
@@ -377,6 +394,8 @@ pub enum NetQueuePairError {
     DescriptorInvalidHeader,
     #[error("Invalid virtio-net header")]
     InvalidVirtioNetHeader,
+    #[error("Error submitting TAP I/O through io_uring: {0}")]
+    TapIoUring(io::Error),
 }
 
 pub struct NetQueuePair {
@@ -398,6 +417,9 @@ pub struct NetQueuePair {
     pub rx_rate_limiter: Option<RateLimiter>,
     pub tx_rate_limiter: Option<RateLimiter>,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
+    // Exchanges the frames through an AF_XDP socket when set, the TAP
+    // handle then being only used to poll the socket.
+    pub xsk: Option<Arc<Mutex<XdpSocket>>>,
 }
 
 impl NetQueuePair {
@@ -406,14 +428,54 @@ impl NetQueuePair {
         mem: &vm_memory::GuestMemoryMmap<B>,
         queue: &mut Queue,
     ) -> Result<bool, NetQueuePairError> {
-        let tx_tap_retry = self.tx.process_desc_chain(
+        let tx_tap_retry = if let Some(xsk) = &self.xsk {
+            xsk.lock().unwrap().process_tx(
+                &mut self.tx,
+                mem,
+                queue,
+                &mut self.tx_rate_limiter,
+                self.access_platform.as_ref(),
+            )?
+        } else {
+            self.tx.process_desc_chain(
+                mem,
+                &self.tap,
+                queue,
+                &mut self.tx_rate_limiter,
+                self.access_platform.as_ref(),
+            )?
+        };
+
+        self.complete_tx(mem, queue, tx_tap_retry)
+    }
+
+    /// Same as `process_tx()`, the frames being written to the TAP device in
+    /// batches through `io_uring`. Must not be used along with TX rate
+    /// limiting, which is accounted frame by frame.
+    #[cfg(feature = "io_uring")]
+    pub fn process_tx_io_uring<B: Bitmap + 'static>(
+        &mut self,
+        io_uring: &mut TapIoUring,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+    ) -> Result<bool, NetQueuePairError> {
+        let tx_tap_retry = io_uring.process_tx(
+            &mut self.tx,
             mem,
             &self.tap,
             queue,
-            &mut self.tx_rate_limiter,
             self.access_platform.as_ref(),
         )?;
 
+        self.complete_tx(mem, queue, tx_tap_retry)
+    }
+
+    fn complete_tx<B: Bitmap + 'static>(
+        &mut self,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+        tx_tap_retry: bool,
+    ) -> Result<bool, NetQueuePairError> {
         // We got told to try again when writing to the tap. Wait for the TAP to be writable
         if tx_tap_retry && !self.tx_tap_listening {
             register_listener(
@@ -456,13 +518,55 @@ impl NetQueuePair {
         mem: &vm_memory::GuestMemoryMmap<B>,
         queue: &mut Queue,
     ) -> Result<bool, NetQueuePairError> {
-        self.rx_desc_avail = !self.rx.process_desc_chain(
+        let exhausted_descs = if let Some(xsk) = &self.xsk {
+            xsk.lock().unwrap().process_rx(
+                &mut self.rx,
+                mem,
+                queue,
+                &mut self.rx_rate_limiter,
+                self.access_platform.as_ref(),
+            )?
+        } else {
+            self.rx.process_desc_chain(
+                mem,
+                &self.tap,
+                queue,
+                &mut self.rx_rate_limiter,
+                self.access_platform.as_ref(),
+            )?
+        };
+
+        self.complete_rx(mem, queue, exhausted_descs)
+    }
+
+    /// Same as `process_rx()`, the frames being read from the TAP device in
+    /// batches through `io_uring`. Must not be used along with RX rate
+    /// limiting, which is accounted frame by frame.
+    #[cfg(feature = "io_uring")]
+    pub fn process_rx_io_uring<B: Bitmap + 'static>(
+        &mut self,
+        io_uring: &mut TapIoUring,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+    ) -> Result<bool, NetQueuePairError> {
+        let exhausted_descs = io_uring.process_rx(
+            &mut self.rx,
             mem,
             &self.tap,
             queue,
-            &mut self.rx_rate_limiter,
             self.access_platform.as_ref(),
         )?;
+
+        self.complete_rx(mem, queue, exhausted_descs)
+    }
+
+    fn complete_rx<B: Bitmap + 'static>(
+        &mut self,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+        exhausted_descs: bool,
+    ) -> Result<bool, NetQueuePairError> {
+        self.rx_desc_avail = !exhausted_descs;
         let rate_limit_reached = self
             .rx_rate_limiter
             .as_ref()
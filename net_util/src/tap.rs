@@ -387,6 +387,20 @@ impl Tap {
         ifreq
     }
 
+    /// Wrap an AF_XDP socket so that it can be polled like a TAP device,
+    /// the interface ioctls applying to the interface it is bound to.
+    pub(crate) fn from_xdp_socket(socket: File, if_name: &str) -> Result<Tap> {
+        Ok(Tap {
+            tap_file: socket,
+            if_name: build_terminated_if_name(if_name)?,
+        })
+    }
+
+    /// The file of the TAP queue, which a vhost-net backend can be bound to.
+    pub fn as_file(&self) -> &File {
+        &self.tap_file
+    }
+
     pub fn get_if_name(&self) -> Vec<u8> {
         self.if_name.clone()
     }
@@ -0,0 +1,475 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! io_uring based TAP I/O.
+//!
+//! Rather than issuing one `readv()`/`writev()` system call per frame, all
+//! the frames available from a virtqueue are submitted at once as
+//! `IORING_OP_READV`/`IORING_OP_WRITEV` operations, and completed through a
+//! single `io_uring_enter()`.
+//!
+//! Transmitted frames are linked, so that they reach the TAP device in order
+//! and so that the frames following a failed write (typically `EAGAIN` when
+//! the TAP queue is full) are cancelled and can be put back into the queue.
+//! Frames cancelled after a successful but short write are submitted again.
+//!
+//! Received frames can't be linked since reads are expected to be short,
+//! which the kernel considers as breaking the link. A frame can then be
+//! received after a read which found the TAP empty, in which case it is
+//! moved to the earliest unused descriptor chain, so that the chains left
+//! unused are always the last ones popped from the queue.
+//!
+//! The guest memory can optionally be registered as fixed buffers, which
+//! saves the kernel from pinning and unpinning the guest pages for each
+//! operation. Frames made of a single buffer then go through
+//! `IORING_OP_READ_FIXED`/`IORING_OP_WRITE_FIXED`. This keeps the whole guest
+//! memory pinned while the device is active.
+
+use super::{vnet_hdr_len, NetQueuePairError, RxVirtio, Tap, TxVirtio};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::io;
+use std::num::Wrapping;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::bitmap::Bitmap;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+use vm_virtio::{AccessPlatform, Translatable};
+
+// Maximum number of frames submitted at once
+const TAP_IO_URING_DEPTH: usize = 256;
+// Number of reads submitted for the first RX batch, the following batches
+// being sized after the number of frames received by the previous one.
+const RX_INITIAL_BATCH: usize = 8;
+// Maximum size of a fixed buffer accepted by the kernel
+const MAX_FIXED_BUFFER_SIZE: usize = 1 << 30;
+// Maximum number of fixed buffers accepted by the kernel
+const MAX_FIXED_BUFFERS: usize = 1 << 14;
+
+struct Frame {
+    head_index: u16,
+    iovecs: Vec<libc::iovec>,
+    // Index of the fixed buffer holding the frame, if made of a single
+    // buffer within a registered range of the guest memory.
+    buf_index: Option<u16>,
+    // Address of the num_buffers field of the virtio-net header, only used
+    // for received frames.
+    num_buffers_addr: GuestAddress,
+}
+
+/// Batched TAP reads and writes through an io_uring instance.
+pub struct TapIoUring {
+    io_uring: IoUring,
+    rx_batch: usize,
+    // Host address ranges of the guest memory registered as fixed buffers,
+    // indexed by buffer index.
+    fixed_buffers: Vec<(usize, usize)>,
+}
+
+impl TapIoUring {
+    /// Create the io_uring instance, registering the guest memory as fixed
+    /// buffers if `register_memory` is set. Failing to register the memory,
+    /// typically because of `RLIMIT_MEMLOCK`, is not fatal.
+    pub fn new<B: Bitmap + 'static>(
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        register_memory: bool,
+    ) -> io::Result<Self> {
+        let io_uring = IoUring::new(TAP_IO_URING_DEPTH as u32)?;
+
+        let mut fixed_buffers = Vec::new();
+        if register_memory {
+            for region in mem.iter() {
+                let start = region.as_ptr() as usize;
+                let len = region.len() as usize;
+                for offset in (0..len).step_by(MAX_FIXED_BUFFER_SIZE) {
+                    let size = (len - offset).min(MAX_FIXED_BUFFER_SIZE);
+                    fixed_buffers.push((start + offset, size));
+                }
+            }
+            fixed_buffers.truncate(MAX_FIXED_BUFFERS);
+
+            let iovecs: Vec<libc::iovec> = fixed_buffers
+                .iter()
+                .map(|(start, len)| libc::iovec {
+                    iov_base: *start as *mut libc::c_void,
+                    iov_len: *len,
+                })
+                .collect();
+            // SAFETY: the guest memory stays mapped as long as the VM
+            // exists, which outlives the devices and their io_uring
+            // instances. The registered pages are pinned by the kernel.
+            if let Err(e) = unsafe { io_uring.submitter().register_buffers(&iovecs) } {
+                warn!("Failed to register the guest memory for TAP I/O: {}", e);
+                fixed_buffers.clear();
+            }
+        }
+
+        Ok(TapIoUring {
+            io_uring,
+            rx_batch: RX_INITIAL_BATCH,
+            fixed_buffers,
+        })
+    }
+
+    // Index of the fixed buffer holding the given host address range
+    fn fixed_buffer(&self, iovec: &libc::iovec) -> Option<u16> {
+        let start = iovec.iov_base as usize;
+        self.fixed_buffers
+            .iter()
+            .position(|(buf_start, buf_len)| {
+                start >= *buf_start && start + iovec.iov_len <= buf_start + buf_len
+            })
+            .map(|index| index as u16)
+    }
+
+    // Pop up to `max` descriptor chains from the queue, the descriptors being
+    // expected to be all device writable for RX and all device readable for
+    // TX.
+    fn pop_frames<B: Bitmap + 'static>(
+        &self,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+        max: usize,
+        rx: bool,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> Result<Vec<Frame>, NetQueuePairError> {
+        let mut frames = Vec::new();
+
+        while frames.len() < max {
+            let Some(desc_chain) = queue.pop_descriptor_chain(mem) else {
+                break;
+            };
+
+            let mut frame = Frame {
+                head_index: desc_chain.head_index(),
+                iovecs: Vec::new(),
+                buf_index: None,
+                num_buffers_addr: GuestAddress(0),
+            };
+
+            for desc in desc_chain {
+                let desc_addr = desc
+                    .addr()
+                    .translate_gva(access_platform, desc.len() as usize);
+                if desc.is_write_only() != rx || desc.len() == 0 {
+                    error!(
+                        "Invalid descriptor chain: address = 0x{:x} length = {} write_only = {}",
+                        desc_addr.0,
+                        desc.len(),
+                        desc.is_write_only()
+                    );
+                    return Err(NetQueuePairError::DescriptorChainInvalid);
+                }
+
+                if rx && frame.iovecs.is_empty() {
+                    frame.num_buffers_addr = mem
+                        .checked_offset(desc_addr, 10)
+                        .ok_or(NetQueuePairError::DescriptorInvalidHeader)?;
+                }
+
+                let buf = mem
+                    .get_slice(desc_addr, desc.len() as usize)
+                    .map_err(NetQueuePairError::GuestMemory)?
+                    .ptr_guard_mut();
+                frame.iovecs.push(libc::iovec {
+                    iov_base: buf.as_ptr() as *mut libc::c_void,
+                    iov_len: desc.len() as libc::size_t,
+                });
+            }
+
+            match frame.iovecs.as_slice() {
+                [] => return Err(NetQueuePairError::DescriptorChainTooShort),
+                [iovec] => frame.buf_index = self.fixed_buffer(iovec),
+                _ => {}
+            }
+
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+
+    // Submit a read or a write for each frame, and wait for all of them to
+    // complete. The results are returned in the order of the frames.
+    fn submit(&mut self, tap: &Tap, frames: &[Frame], rx: bool) -> io::Result<Vec<i32>> {
+        let fd = types::Fd(tap.as_raw_fd());
+        let (submitter, mut sq, mut cq) = self.io_uring.split();
+
+        for (index, frame) in frames.iter().enumerate() {
+            let iovecs = frame.iovecs.as_ptr();
+            let count = frame.iovecs.len() as u32;
+            let (buf, len) = (
+                frame.iovecs[0].iov_base as *mut u8,
+                frame.iovecs[0].iov_len as u32,
+            );
+            // An offset of -1 stands for the current file position, as the
+            // TAP device is not seekable.
+            let entry = match (rx, frame.buf_index) {
+                (true, Some(buf_index)) => opcode::ReadFixed::new(fd, buf, len, buf_index)
+                    .offset(u64::MAX)
+                    .build(),
+                (true, None) => opcode::Readv::new(fd, iovecs, count)
+                    .offset(u64::MAX)
+                    .build(),
+                (false, Some(buf_index)) => opcode::WriteFixed::new(fd, buf, len, buf_index)
+                    .offset(u64::MAX)
+                    .build(),
+                (false, None) => opcode::Writev::new(fd, iovecs, count)
+                    .offset(u64::MAX)
+                    .build(),
+            };
+            let entry = if !rx && index + 1 < frames.len() {
+                entry.flags(squeue::Flags::IO_LINK)
+            } else {
+                entry
+            };
+
+            // SAFETY: the TAP fd and the guest buffers described by the
+            // iovecs remain valid until the operations complete, which is
+            // waited for below.
+            unsafe { sq.push(&entry.user_data(index as u64)) }
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "io_uring submission full"))?;
+        }
+
+        sq.sync();
+        submitter.submit_and_wait(frames.len())?;
+        cq.sync();
+
+        let mut results = vec![-libc::ECANCELED; frames.len()];
+        for entry in cq {
+            results[entry.user_data() as usize] = entry.result();
+        }
+
+        Ok(results)
+    }
+
+    /// Transmit the frames available from the TX queue, returning whether
+    /// the TAP device should be written to again once writable.
+    pub fn process_tx<B: Bitmap + 'static>(
+        &mut self,
+        tx: &mut TxVirtio,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        tap: &Tap,
+        queue: &mut Queue,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> Result<bool, NetQueuePairError> {
+        let mut retry_write = false;
+
+        loop {
+            let next_avail = queue.next_avail();
+            let frames = self.pop_frames(mem, queue, TAP_IO_URING_DEPTH, false, access_platform)?;
+            if frames.is_empty() {
+                break;
+            }
+
+            let results = self
+                .submit(tap, &frames, false)
+                .map_err(NetQueuePairError::TapIoUring)?;
+
+            let mut completed = 0;
+            let mut cancelled = false;
+            for (frame, result) in frames.iter().zip(results) {
+                // The link was broken by a short write, the following frames
+                // have not been written and must be submitted again.
+                if result == -libc::ECANCELED {
+                    cancelled = true;
+                    break;
+                }
+                if result < 0 {
+                    let e = io::Error::from_raw_os_error(-result);
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        retry_write = true;
+                        break;
+                    }
+                    error!("net: tx: failed writing to tap: {}", e);
+                    return Err(NetQueuePairError::WriteTap(e));
+                }
+
+                if (result as usize) < vnet_hdr_len() {
+                    return Err(NetQueuePairError::InvalidVirtioNetHeader);
+                }
+
+                tx.counter_bytes += Wrapping(result as u64 - vnet_hdr_len() as u64);
+                tx.counter_frames += Wrapping(1);
+
+                queue
+                    .add_used(mem, frame.head_index, result as u32)
+                    .map_err(NetQueuePairError::QueueAddUsed)?;
+                completed += 1;
+            }
+
+            if retry_write || cancelled {
+                // Put back the frames which have not been written.
+                queue.set_next_avail(next_avail.wrapping_add(completed as u16));
+                if retry_write {
+                    break;
+                }
+                continue;
+            }
+
+            if !queue
+                .enable_notification(mem)
+                .map_err(NetQueuePairError::QueueEnableNotification)?
+            {
+                break;
+            }
+        }
+
+        Ok(retry_write)
+    }
+
+    /// Receive the frames pending on the TAP device into the RX queue,
+    /// returning whether the available descriptors have been exhausted.
+    pub fn process_rx<B: Bitmap + 'static>(
+        &mut self,
+        rx: &mut RxVirtio,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        tap: &Tap,
+        queue: &mut Queue,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> Result<bool, NetQueuePairError> {
+        let mut exhausted_descs = true;
+
+        loop {
+            let next_avail = queue.next_avail();
+            let frames = self.pop_frames(mem, queue, self.rx_batch, true, access_platform)?;
+            if frames.is_empty() {
+                break;
+            }
+
+            let results = self
+                .submit(tap, &frames, true)
+                .map_err(NetQueuePairError::TapIoUring)?;
+
+            let mut completed = 0;
+            for (index, result) in results.into_iter().enumerate() {
+                if result < 0 {
+                    let e = io::Error::from_raw_os_error(-result);
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        continue;
+                    }
+                    error!("net: rx: failed reading from tap: {}", e);
+                    return Err(NetQueuePairError::ReadTap(e));
+                }
+
+                let len = result as usize;
+                if len < vnet_hdr_len() {
+                    return Err(NetQueuePairError::InvalidVirtioNetHeader);
+                }
+
+                if index != completed
+                    && !copy_iovecs(&frames[index].iovecs, &frames[completed].iovecs, len)
+                {
+                    warn!("net: rx: dropping frame not fitting the descriptor chain");
+                    continue;
+                }
+
+                let frame = &frames[completed];
+                // Write num_buffers to guest memory. We simply write 1 as we
+                // never spread the frame over more than one descriptor chain.
+                mem.write_obj(1u16, frame.num_buffers_addr)
+                    .map_err(NetQueuePairError::GuestMemory)?;
+
+                rx.counter_bytes += Wrapping(len as u64 - vnet_hdr_len() as u64);
+                rx.counter_frames += Wrapping(1);
+
+                queue
+                    .add_used(mem, frame.head_index, len as u32)
+                    .map_err(NetQueuePairError::QueueAddUsed)?;
+                completed += 1;
+            }
+
+            if completed < frames.len() {
+                // The TAP device has been drained, put back the unused
+                // descriptor chains and size the next batch after the
+                // number of frames received.
+                self.rx_batch = completed.max(1);
+                queue.set_next_avail(next_avail.wrapping_add(completed as u16));
+                exhausted_descs = false;
+                break;
+            }
+
+            self.rx_batch = (self.rx_batch * 2).min(TAP_IO_URING_DEPTH);
+
+            if !queue
+                .enable_notification(mem)
+                .map_err(NetQueuePairError::QueueEnableNotification)?
+            {
+                break;
+            }
+        }
+
+        Ok(exhausted_descs)
+    }
+}
+
+// Copy the first `len` bytes of the buffers described by `src` to the ones
+// described by `dst`, returning false if they don't fit.
+fn copy_iovecs(src: &[libc::iovec], dst: &[libc::iovec], len: usize) -> bool {
+    if dst.iter().map(|iovec| iovec.iov_len).sum::<usize>() < len {
+        return false;
+    }
+
+    let (mut src_index, mut src_offset) = (0, 0);
+    let (mut dst_index, mut dst_offset) = (0, 0);
+    let mut remaining = len;
+    while remaining > 0 {
+        let (src_iovec, dst_iovec) = (&src[src_index], &dst[dst_index]);
+        let count = remaining
+            .min(src_iovec.iov_len - src_offset)
+            .min(dst_iovec.iov_len - dst_offset);
+
+        // SAFETY: both ranges are within guest memory buffers described by
+        // descriptor chains, the source one holding at least `len` bytes.
+        unsafe {
+            std::ptr::copy(
+                (src_iovec.iov_base as *const u8).add(src_offset),
+                (dst_iovec.iov_base as *mut u8).add(dst_offset),
+                count,
+            )
+        };
+
+        src_offset += count;
+        if src_offset == src_iovec.iov_len {
+            src_index += 1;
+            src_offset = 0;
+        }
+        dst_offset += count;
+        if dst_offset == dst_iovec.iov_len {
+            dst_index += 1;
+            dst_offset = 0;
+        }
+        remaining -= count;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_copy_iovecs() {
+        let mut src_0 = [1u8, 2, 3];
+        let mut src_1 = [4u8, 5, 6, 7];
+        let mut dst_0 = [0u8; 2];
+        let mut dst_1 = [0u8; 5];
+
+        let src = [iovec(&mut src_0), iovec(&mut src_1)];
+        let dst = [iovec(&mut dst_0), iovec(&mut dst_1)];
+        assert!(copy_iovecs(&src, &dst, 6));
+        assert!(!copy_iovecs(&src, &dst[1..], 6));
+
+        assert_eq!(dst_0, [1, 2]);
+        assert_eq!(dst_1, [3, 4, 5, 6, 0]);
+    }
+}
@@ -0,0 +1,1102 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! AF_XDP based network I/O.
+//!
+//! Each queue pair is backed by an AF_XDP socket bound to one queue of a
+//! physical interface, in zero-copy mode when its driver supports it and in
+//! copy mode otherwise. A minimal XDP program attached to the interface
+//! redirects the frames received on the bound queues to the sockets through
+//! an XSKMAP, the frames received on the other queues being passed to the
+//! kernel network stack.
+//!
+//! Frames are exchanged with the kernel through a UMEM region split into
+//! fixed size chunks. The first half of the chunks is handed to the kernel
+//! through the fill ring to receive frames, the second half being used to
+//! transmit frames and recycled through the completion ring. Frames are
+//! copied between the chunks and the guest buffers, the virtio-net header
+//! being added on RX and stripped on TX as no offload is supported.
+
+use super::{NetQueuePairError, RxVirtio, Tap, TapError, TxVirtio};
+use rate_limiter::{RateLimiter, TokenType};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::bitmap::Bitmap;
+use vm_memory::{Bytes, GuestMemory};
+use vm_virtio::{AccessPlatform, Translatable};
+
+// From include/uapi/linux/if_xdp.h
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1;
+
+// From include/uapi/linux/bpf.h
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+// Size of the UMEM chunks, holding one frame each.
+const FRAME_SIZE: usize = 4096;
+// Number of entries of each ring, which is also the number of chunks used
+// for RX and for TX.
+const RING_SIZE: u32 = 1024;
+const NUM_FRAMES: usize = 2 * RING_SIZE as usize;
+const UMEM_SIZE: usize = NUM_FRAMES * FRAME_SIZE;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid interface {0}: {1}")]
+    InvalidInterface(String, io::Error),
+    #[error("Failed to create AF_XDP socket: {0}")]
+    CreateSocket(io::Error),
+    #[error("Failed to allocate UMEM: {0}")]
+    AllocateUmem(io::Error),
+    #[error("Failed to register UMEM: {0}")]
+    RegisterUmem(io::Error),
+    #[error("Failed to set up AF_XDP rings: {0}")]
+    SetupRings(io::Error),
+    #[error("Failed to bind AF_XDP socket to queue {0}: {1}")]
+    Bind(u32, io::Error),
+    #[error("Failed to create XSKMAP: {0}")]
+    CreateMap(io::Error),
+    #[error("Failed to update XSKMAP: {0}")]
+    UpdateMap(io::Error),
+    #[error("Failed to load XDP program: {0}")]
+    LoadProgram(io::Error),
+    #[error("Failed to attach XDP program: {0}")]
+    AttachProgram(io::Error),
+    #[error("Failed to create TAP handle for AF_XDP socket: {0}")]
+    Tap(TapError),
+    #[error("Failed to duplicate AF_XDP socket: {0}")]
+    CloneSocket(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[repr(C)]
+#[derive(Default)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn bpf_insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<RawFd> {
+    // SAFETY: FFI call with a valid attribute structure, and we check the
+    // return value.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret as RawFd)
+}
+
+fn setsockopt<T>(fd: RawFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: FFI call with a valid option value, and we check the return
+    // value.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// XDP program and XSKMAP shared by the sockets bound to an interface, the
+// program being detached when the link is closed.
+struct XdpProgram {
+    map: File,
+    program: File,
+    _link: Option<File>,
+}
+
+impl XdpProgram {
+    fn load(max_entries: u32) -> Result<Self> {
+        let attr = BpfMapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: 4,
+            value_size: 4,
+            max_entries,
+            ..Default::default()
+        };
+        let map_fd = bpf(BPF_MAP_CREATE, &attr).map_err(Error::CreateMap)?;
+        // SAFETY: map_fd is a valid fd we own.
+        let map = unsafe { File::from_raw_fd(map_fd) };
+
+        let insns = [
+            // r2 = ((struct xdp_md *)r1)->rx_queue_index
+            bpf_insn(0x61, 2, 1, 16, 0),
+            // r1 = &xskmap
+            bpf_insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+            bpf_insn(0, 0, 0, 0, 0),
+            // r3 = XDP_PASS, returned when no socket is bound to the queue
+            bpf_insn(0xb7, 3, 0, 0, XDP_PASS),
+            // return bpf_redirect_map(r1, r2, r3)
+            bpf_insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+            bpf_insn(0x95, 0, 0, 0, 0),
+        ];
+        let license = CString::new("Apache-2.0").unwrap();
+        let mut prog_name = [0u8; 16];
+        prog_name[..6].copy_from_slice(b"ch_xsk");
+        let attr = BpfProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_XDP,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            prog_name,
+            expected_attach_type: BPF_XDP,
+            ..Default::default()
+        };
+        let program_fd = bpf(BPF_PROG_LOAD, &attr).map_err(Error::LoadProgram)?;
+
+        Ok(XdpProgram {
+            map,
+            // SAFETY: program_fd is a valid fd we own.
+            program: unsafe { File::from_raw_fd(program_fd) },
+            _link: None,
+        })
+    }
+
+    fn insert(&self, queue: u32, socket: RawFd) -> Result<()> {
+        let attr = BpfMapElemAttr {
+            map_fd: self.map.as_raw_fd() as u32,
+            key: &queue as *const u32 as u64,
+            value: &socket as *const RawFd as u64,
+            ..Default::default()
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &attr).map_err(Error::UpdateMap)?;
+
+        Ok(())
+    }
+
+    fn attach(&mut self, ifindex: u32) -> Result<()> {
+        // Let the kernel pick the native mode over the generic one when the
+        // driver supports it.
+        let attr = BpfLinkCreateAttr {
+            prog_fd: self.program.as_raw_fd() as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: 0,
+        };
+        let link_fd = bpf(BPF_LINK_CREATE, &attr).map_err(Error::AttachProgram)?;
+        // SAFETY: link_fd is a valid fd we own.
+        self._link = Some(unsafe { File::from_raw_fd(link_fd) });
+
+        Ok(())
+    }
+}
+
+// Ring shared with the kernel, with a single producer and a single consumer.
+struct Ring {
+    addr: *mut libc::c_void,
+    len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    descs: *mut u8,
+}
+
+impl Ring {
+    fn new<T>(fd: RawFd, offset: &XdpRingOffset, pgoff: libc::off_t) -> io::Result<Self> {
+        let len = offset.desc as usize + RING_SIZE as usize * std::mem::size_of::<T>();
+        // SAFETY: FFI call with valid arguments, and we check the return
+        // value.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let base = addr as *mut u8;
+        // SAFETY: the offsets provided by the kernel lie within the mapping.
+        unsafe {
+            Ok(Ring {
+                addr,
+                len,
+                producer: base.add(offset.producer as usize) as *const AtomicU32,
+                consumer: base.add(offset.consumer as usize) as *const AtomicU32,
+                flags: base.add(offset.flags as usize) as *const AtomicU32,
+                descs: base.add(offset.desc as usize),
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // SAFETY: the pointer is valid for the lifetime of the mapping.
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // SAFETY: the pointer is valid for the lifetime of the mapping.
+        unsafe { &*self.consumer }
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        // SAFETY: the pointer is valid for the lifetime of the mapping.
+        unsafe { &*self.flags }.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+    }
+
+    // Number of entries which can be produced.
+    fn free_entries(&self) -> u32 {
+        RING_SIZE
+            - self
+                .producer()
+                .load(Ordering::Relaxed)
+                .wrapping_sub(self.consumer().load(Ordering::Acquire))
+    }
+
+    // Number of entries which can be consumed.
+    fn available_entries(&self) -> u32 {
+        self.producer()
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.consumer().load(Ordering::Relaxed))
+    }
+
+    fn push<T>(&self, entry: T) {
+        let producer = self.producer().load(Ordering::Relaxed);
+        // SAFETY: the index is masked to fit the ring, and the entry is
+        // owned by the producer until the producer index is updated.
+        unsafe {
+            std::ptr::write_volatile(
+                (self.descs as *mut T).add((producer & (RING_SIZE - 1)) as usize),
+                entry,
+            )
+        };
+        self.producer()
+            .store(producer.wrapping_add(1), Ordering::Release);
+    }
+
+    fn peek<T: Copy>(&self) -> T {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        // SAFETY: the index is masked to fit the ring, and the entry is
+        // owned by the consumer until the consumer index is updated.
+        unsafe {
+            std::ptr::read_volatile(
+                (self.descs as *const T).add((consumer & (RING_SIZE - 1)) as usize),
+            )
+        }
+    }
+
+    fn release(&self) {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        self.consumer()
+            .store(consumer.wrapping_add(1), Ordering::Release);
+    }
+}
+
+// Location within the UMEM of a received frame, clamped so that a bogus
+// descriptor can't make it point outside of the UMEM.
+fn rx_frame(desc: &XdpDesc) -> (usize, usize) {
+    let offset = desc.addr as usize % UMEM_SIZE;
+    (offset, (desc.len as usize).min(UMEM_SIZE - offset))
+}
+
+// Start of the chunk holding the given UMEM offset.
+fn chunk_addr(offset: usize) -> u64 {
+    (offset - offset % FRAME_SIZE) as u64
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is not accessed anymore.
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+/// AF_XDP socket bound to one queue of a network interface.
+pub struct XdpSocket {
+    socket: File,
+    if_name: String,
+    umem: *mut u8,
+    fill: Ring,
+    completion: Ring,
+    rx: Ring,
+    tx: Ring,
+    // Chunks available to transmit frames.
+    tx_frames: Vec<u64>,
+    zero_copy: bool,
+    // Keeps the XDP program attached as long as the socket is open.
+    _program: Option<Arc<XdpProgram>>,
+}
+
+// SAFETY: the UMEM and the rings are only accessed through the socket
+// owning them.
+unsafe impl Send for XdpSocket {}
+
+impl XdpSocket {
+    fn new(if_name: &str, ifindex: u32, queue: u32) -> Result<Self> {
+        // SAFETY: FFI call, and we check the return value.
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::CreateSocket(io::Error::last_os_error()));
+        }
+        // SAFETY: fd is a valid fd we own.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        // SAFETY: FFI call with valid arguments, and we check the return
+        // value.
+        let umem = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                UMEM_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            return Err(Error::AllocateUmem(io::Error::last_os_error()));
+        }
+        let umem = umem as *mut u8;
+
+        let (fill, completion, rx, tx) = match Self::setup(fd, umem) {
+            Ok(rings) => rings,
+            Err(e) => {
+                // SAFETY: the UMEM isn't referenced anymore.
+                unsafe { libc::munmap(umem as *mut libc::c_void, UMEM_SIZE) };
+                return Err(e);
+            }
+        };
+
+        let mut xsk = XdpSocket {
+            socket,
+            if_name: if_name.to_owned(),
+            umem,
+            fill,
+            completion,
+            rx,
+            tx,
+            tx_frames: (NUM_FRAMES / 2..NUM_FRAMES)
+                .map(|i| (i * FRAME_SIZE) as u64)
+                .collect(),
+            zero_copy: true,
+            _program: None,
+        };
+
+        for i in 0..NUM_FRAMES / 2 {
+            xsk.fill.push((i * FRAME_SIZE) as u64);
+        }
+
+        // Fall back to copy mode if the driver doesn't support zero-copy.
+        if let Err(e) = xsk.bind(ifindex, queue, XDP_ZEROCOPY) {
+            info!(
+                "AF_XDP zero-copy not supported on {} queue {}, using copy mode: {}",
+                if_name, queue, e
+            );
+            xsk.zero_copy = false;
+            xsk.bind(ifindex, queue, XDP_COPY)
+                .map_err(|e| Error::Bind(queue, e))?;
+        }
+
+        Ok(xsk)
+    }
+
+    fn setup(fd: RawFd, umem: *mut u8) -> Result<(Ring, Ring, Ring, Ring)> {
+        let reg = XdpUmemReg {
+            addr: umem as u64,
+            len: UMEM_SIZE as u64,
+            chunk_size: FRAME_SIZE as u32,
+            ..Default::default()
+        };
+        setsockopt(fd, XDP_UMEM_REG, &reg).map_err(Error::RegisterUmem)?;
+
+        for ring in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            setsockopt(fd, ring, &RING_SIZE).map_err(Error::SetupRings)?;
+        }
+
+        let mut offsets = XdpMmapOffsets::default();
+        let mut len = std::mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        // SAFETY: FFI call with a valid buffer, and we check the return
+        // value.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SetupRings(io::Error::last_os_error()));
+        }
+
+        Ok((
+            Ring::new::<u64>(fd, &offsets.fr, XDP_UMEM_PGOFF_FILL_RING)
+                .map_err(Error::SetupRings)?,
+            Ring::new::<u64>(fd, &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING)
+                .map_err(Error::SetupRings)?,
+            Ring::new::<XdpDesc>(fd, &offsets.rx, XDP_PGOFF_RX_RING).map_err(Error::SetupRings)?,
+            Ring::new::<XdpDesc>(fd, &offsets.tx, XDP_PGOFF_TX_RING).map_err(Error::SetupRings)?,
+        ))
+    }
+
+    fn bind(&self, ifindex: u32, queue: u32, mode: u16) -> io::Result<()> {
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: mode | XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        // SAFETY: FFI call with a valid address, and we check the return
+        // value.
+        let ret = unsafe {
+            libc::bind(
+                self.socket.as_raw_fd(),
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Handle polling the socket for readability and writability, on
+    /// which the interface ioctls apply to the interface it is bound to.
+    pub fn tap(&self) -> Result<Tap> {
+        let socket = self.socket.try_clone().map_err(Error::CloneSocket)?;
+        Tap::from_xdp_socket(socket, &self.if_name).map_err(Error::Tap)
+    }
+
+    // Wake the kernel up so that it processes the rings, as requested when
+    // the driver isn't already polling them.
+    fn kick(&self, rx: bool) -> io::Result<()> {
+        let fd = self.socket.as_raw_fd();
+        // SAFETY: FFI calls without any buffer, and we check the return
+        // value.
+        let ret = unsafe {
+            if rx {
+                libc::recvfrom(
+                    fd,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                )
+            } else {
+                libc::sendto(
+                    fd,
+                    std::ptr::null(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    std::ptr::null(),
+                    0,
+                )
+            }
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS)
+                | Some(libc::ENETDOWN) => {}
+                _ => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Get back the chunks of the frames which have been transmitted.
+    fn reclaim_tx_frames(&mut self) {
+        while self.completion.available_entries() > 0 {
+            self.tx_frames.push(self.completion.peek::<u64>());
+            self.completion.release();
+        }
+    }
+
+    /// Transmit the frames available from the TX queue, returning whether
+    /// the socket should be written to again once writable.
+    pub fn process_tx<B: Bitmap + 'static>(
+        &mut self,
+        tx: &mut TxVirtio,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+        rate_limiter: &mut Option<RateLimiter>,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> Result<bool, NetQueuePairError> {
+        let hdr_len = super::vnet_hdr_len();
+        let mut retry_write = false;
+        let mut rate_limit_reached = false;
+        let mut produced = false;
+
+        self.reclaim_tx_frames();
+
+        while let Some(mut desc_chain) = queue.pop_descriptor_chain(mem) {
+            if rate_limit_reached {
+                queue.go_to_previous_position();
+                break;
+            }
+
+            let Some(&chunk) = self.tx_frames.last() else {
+                queue.go_to_previous_position();
+                retry_write = true;
+                break;
+            };
+            if self.tx.free_entries() == 0 {
+                queue.go_to_previous_position();
+                retry_write = true;
+                break;
+            }
+
+            // SAFETY: the chunk lies within the UMEM and isn't owned by the
+            // kernel.
+            let frame = unsafe {
+                std::slice::from_raw_parts_mut(self.umem.add(chunk as usize), FRAME_SIZE)
+            };
+            // Gather the frame following the virtio-net header.
+            let mut chain_len = 0;
+            let mut frame_len = 0;
+            let mut truncated = false;
+            while let Some(desc) = desc_chain.next() {
+                let desc_addr = desc
+                    .addr()
+                    .translate_gva(access_platform, desc.len() as usize);
+                if desc.is_write_only() {
+                    error!(
+                        "Invalid descriptor chain: address = 0x{:x} length = {} write_only = {}",
+                        desc_addr.0,
+                        desc.len(),
+                        desc.is_write_only()
+                    );
+                    return Err(NetQueuePairError::DescriptorChainInvalid);
+                }
+
+                let desc_len = desc.len() as usize;
+                let skip = hdr_len.saturating_sub(chain_len).min(desc_len);
+                chain_len += desc_len;
+                let len = desc_len - skip;
+                if len == 0 {
+                    continue;
+                }
+                if frame_len + len > FRAME_SIZE {
+                    truncated = true;
+                    continue;
+                }
+
+                let addr = desc_chain
+                    .memory()
+                    .checked_offset(desc_addr, skip)
+                    .ok_or(NetQueuePairError::DescriptorChainInvalid)?;
+                desc_chain
+                    .memory()
+                    .read_slice(&mut frame[frame_len..frame_len + len], addr)
+                    .map_err(NetQueuePairError::GuestMemory)?;
+                frame_len += len;
+            }
+
+            if chain_len < hdr_len {
+                return Err(NetQueuePairError::InvalidVirtioNetHeader);
+            }
+
+            if truncated {
+                warn!("net: tx: dropping frame not fitting an AF_XDP chunk");
+            } else {
+                self.tx.push(XdpDesc {
+                    addr: chunk,
+                    len: frame_len as u32,
+                    options: 0,
+                });
+                self.tx_frames.pop();
+                produced = true;
+
+                tx.counter_bytes += Wrapping(frame_len as u64);
+                tx.counter_frames += Wrapping(1);
+            }
+
+            // For the sake of simplicity (similar to the RX rate limiting), we always
+            // let the 'last' descriptor chain go-through even if it was over the rate
+            // limit, and simply stop processing oncoming `avail_desc` if any.
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limit_reached = !rate_limiter.consume(1, TokenType::Ops)
+                    || !rate_limiter.consume(chain_len as u64, TokenType::Bytes);
+            }
+
+            queue
+                .add_used(
+                    desc_chain.memory(),
+                    desc_chain.head_index(),
+                    chain_len as u32,
+                )
+                .map_err(NetQueuePairError::QueueAddUsed)?;
+
+            if !queue
+                .enable_notification(mem)
+                .map_err(NetQueuePairError::QueueEnableNotification)?
+            {
+                break;
+            }
+        }
+
+        if produced && (self.tx.needs_wakeup() || !self.zero_copy) {
+            self.kick(false).map_err(|e| {
+                error!("net: tx: failed kicking AF_XDP socket: {}", e);
+                NetQueuePairError::WriteTap(e)
+            })?;
+        }
+
+        Ok(retry_write)
+    }
+
+    /// Receive the frames pending on the socket into the RX queue,
+    /// returning whether the available descriptors have been exhausted.
+    pub fn process_rx<B: Bitmap + 'static>(
+        &mut self,
+        rx: &mut RxVirtio,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        queue: &mut Queue,
+        rate_limiter: &mut Option<RateLimiter>,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> Result<bool, NetQueuePairError> {
+        let hdr_len = rx.hdr_len();
+        // Zeroed virtio-net header, with num_buffers set to 1 as a frame is
+        // never spread over more than one descriptor chain.
+        let mut hdr = [0u8; 12];
+        if !rx.legacy_hdr {
+            hdr[10..12].copy_from_slice(&1u16.to_le_bytes());
+        }
+        let mut exhausted_descs = true;
+        let mut rate_limit_reached = false;
+        let mut consumed = false;
+
+        while self.rx.available_entries() > 0 {
+            let Some(mut desc_chain) = queue.pop_descriptor_chain(mem) else {
+                break;
+            };
+            if rate_limit_reached {
+                exhausted_descs = false;
+                queue.go_to_previous_position();
+                break;
+            }
+
+            let desc = self.rx.peek::<XdpDesc>();
+            let (offset, frame_len) = rx_frame(&desc);
+            // SAFETY: the frame lies within the UMEM and is owned by the
+            // consumer until released.
+            let frame = unsafe { std::slice::from_raw_parts(self.umem.add(offset), frame_len) };
+            let len = hdr_len + frame_len;
+
+            let mut written = 0;
+            while let Some(desc) = desc_chain.next() {
+                let desc_addr = desc
+                    .addr()
+                    .translate_gva(access_platform, desc.len() as usize);
+                if !desc.is_write_only() || desc.len() == 0 {
+                    error!(
+                        "Invalid descriptor chain: address = 0x{:x} length = {} write_only = {}",
+                        desc_addr.0,
+                        desc.len(),
+                        desc.is_write_only()
+                    );
+                    return Err(NetQueuePairError::DescriptorChainInvalid);
+                }
+
+                let mut desc_offset = 0;
+                while desc_offset < desc.len() as usize && written < len {
+                    let src = if written < hdr_len {
+                        &hdr[written..hdr_len]
+                    } else {
+                        &frame[written - hdr_len..]
+                    };
+                    let count = src.len().min(desc.len() as usize - desc_offset);
+                    let addr = desc_chain
+                        .memory()
+                        .checked_offset(desc_addr, desc_offset)
+                        .ok_or(NetQueuePairError::DescriptorChainInvalid)?;
+                    desc_chain
+                        .memory()
+                        .write_slice(&src[..count], addr)
+                        .map_err(NetQueuePairError::GuestMemory)?;
+                    written += count;
+                    desc_offset += count;
+                }
+            }
+
+            // Hand the chunk back to the kernel.
+            self.rx.release();
+            self.fill.push(chunk_addr(offset));
+            consumed = true;
+
+            if written < len {
+                warn!("net: rx: dropping frame not fitting the descriptor chain");
+                queue.go_to_previous_position();
+                continue;
+            }
+
+            rx.counter_bytes += Wrapping(frame_len as u64);
+            rx.counter_frames += Wrapping(1);
+
+            // For the sake of simplicity (keeping the handling of RX_QUEUE_EVENT and
+            // RX_TAP_EVENT totally asynchronous), we always let the 'last' descriptor
+            // chain go-through even if it was over the rate limit, and simply stop
+            // processing oncoming `avail_desc` if any.
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limit_reached = !rate_limiter.consume(1, TokenType::Ops)
+                    || !rate_limiter.consume(len as u64, TokenType::Bytes);
+            }
+
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len as u32)
+                .map_err(NetQueuePairError::QueueAddUsed)?;
+
+            if !queue
+                .enable_notification(mem)
+                .map_err(NetQueuePairError::QueueEnableNotification)?
+            {
+                break;
+            }
+        }
+
+        // The socket has been drained.
+        if self.rx.available_entries() == 0 {
+            exhausted_descs = false;
+        }
+
+        if consumed && self.fill.needs_wakeup() {
+            self.kick(true).map_err(|e| {
+                error!("net: rx: failed kicking AF_XDP socket: {}", e);
+                NetQueuePairError::ReadTap(e)
+            })?;
+        }
+
+        Ok(exhausted_descs)
+    }
+}
+
+impl Drop for XdpSocket {
+    fn drop(&mut self) {
+        // SAFETY: the UMEM isn't accessed anymore, the kernel keeping its
+        // own reference on the pages until the socket is closed.
+        unsafe { libc::munmap(self.umem as *mut libc::c_void, UMEM_SIZE) };
+    }
+}
+
+/// Bind an AF_XDP socket to each of the `num_queue_pairs` queues of
+/// `if_name` starting from `queue`, and redirect the frames received on
+/// these queues to the sockets.
+pub fn open_xdp(if_name: &str, queue: u32, num_queue_pairs: usize) -> Result<Vec<XdpSocket>> {
+    let invalid_if_name = |e| Error::InvalidInterface(if_name.to_owned(), e);
+    let c_if_name = CString::new(if_name)
+        .map_err(|_| invalid_if_name(io::Error::from_raw_os_error(libc::EINVAL)))?;
+    // SAFETY: FFI call with a valid string, and we check the return value.
+    let ifindex = unsafe { libc::if_nametoindex(c_if_name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(invalid_if_name(io::Error::last_os_error()));
+    }
+
+    let mut program = XdpProgram::load(queue + num_queue_pairs as u32)?;
+    let mut sockets = Vec::new();
+    for queue in queue..queue + num_queue_pairs as u32 {
+        let socket = XdpSocket::new(if_name, ifindex, queue)?;
+        program.insert(queue, socket.socket.as_raw_fd())?;
+        sockets.push(socket);
+    }
+
+    // Only redirect frames once all the sockets are in the map.
+    program.attach(ifindex)?;
+    let program = Arc::new(program);
+    for socket in sockets.iter_mut() {
+        socket._program = Some(program.clone());
+    }
+
+    Ok(sockets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const OFFSETS: XdpRingOffset = XdpRingOffset {
+        producer: 0,
+        consumer: 64,
+        flags: 128,
+        desc: 192,
+    };
+
+    // Ring backed by a file instead of an AF_XDP socket, the file being
+    // kept alongside as the mapping outlives its fd anyway.
+    fn create_ring<T>() -> (TempFile, Ring) {
+        let file = TempFile::new().unwrap();
+        file.as_file()
+            .set_len(OFFSETS.desc + RING_SIZE as u64 * std::mem::size_of::<T>() as u64)
+            .unwrap();
+        let ring = Ring::new::<T>(file.as_file().as_raw_fd(), &OFFSETS, 0).unwrap();
+        (file, ring)
+    }
+
+    #[test]
+    fn test_umem_layout() {
+        assert_eq!(UMEM_SIZE, NUM_FRAMES * FRAME_SIZE);
+        assert!(RING_SIZE.is_power_of_two());
+        assert_eq!(chunk_addr(0), 0);
+        assert_eq!(chunk_addr(FRAME_SIZE - 1), 0);
+        assert_eq!(chunk_addr(FRAME_SIZE), FRAME_SIZE as u64);
+        assert_eq!(chunk_addr(3 * FRAME_SIZE + 256), 3 * FRAME_SIZE as u64);
+        assert_eq!(chunk_addr(UMEM_SIZE - 1), (UMEM_SIZE - FRAME_SIZE) as u64);
+
+        // Frames received in zero-copy mode may start past the headroom.
+        let desc = XdpDesc {
+            addr: (5 * FRAME_SIZE + 256) as u64,
+            len: 1500,
+            options: 0,
+        };
+        assert_eq!(rx_frame(&desc), (5 * FRAME_SIZE + 256, 1500));
+
+        // Descriptors can't point outside of the UMEM.
+        let desc = XdpDesc {
+            addr: (UMEM_SIZE + FRAME_SIZE) as u64,
+            len: 64,
+            options: 0,
+        };
+        assert_eq!(rx_frame(&desc), (FRAME_SIZE, 64));
+        let desc = XdpDesc {
+            addr: (UMEM_SIZE - 16) as u64,
+            len: u32::MAX,
+            options: 0,
+        };
+        assert_eq!(rx_frame(&desc), (UMEM_SIZE - 16, 16));
+    }
+
+    #[test]
+    fn test_ring() {
+        let (_file, ring) = create_ring::<u64>();
+        assert_eq!(ring.free_entries(), RING_SIZE);
+        assert_eq!(ring.available_entries(), 0);
+        assert!(!ring.needs_wakeup());
+
+        ring.push(0x1000u64);
+        ring.push(0x2000u64);
+        assert_eq!(ring.free_entries(), RING_SIZE - 2);
+        assert_eq!(ring.available_entries(), 2);
+        assert_eq!(ring.peek::<u64>(), 0x1000);
+        // Peeking doesn't consume the entry.
+        assert_eq!(ring.peek::<u64>(), 0x1000);
+        ring.release();
+        assert_eq!(ring.peek::<u64>(), 0x2000);
+        ring.release();
+        assert_eq!(ring.available_entries(), 0);
+        assert_eq!(ring.free_entries(), RING_SIZE);
+
+        // Fill the ring up.
+        for i in 0..RING_SIZE as u64 {
+            ring.push(i);
+        }
+        assert_eq!(ring.free_entries(), 0);
+        assert_eq!(ring.available_entries(), RING_SIZE);
+        for i in 0..RING_SIZE as u64 {
+            assert_eq!(ring.peek::<u64>(), i);
+            ring.release();
+        }
+        assert_eq!(ring.free_entries(), RING_SIZE);
+
+        // SAFETY: the pointer is valid for the lifetime of the mapping.
+        unsafe { &*ring.flags }.store(XDP_RING_NEED_WAKEUP, Ordering::Relaxed);
+        assert!(ring.needs_wakeup());
+    }
+
+    #[test]
+    fn test_ring_wrap() {
+        let (_file, ring) = create_ring::<XdpDesc>();
+        // Start right before the indexes wrap around.
+        ring.producer().store(u32::MAX - 1, Ordering::Relaxed);
+        ring.consumer().store(u32::MAX - 1, Ordering::Relaxed);
+        assert_eq!(ring.free_entries(), RING_SIZE);
+        assert_eq!(ring.available_entries(), 0);
+
+        for i in 0..4 {
+            ring.push(XdpDesc {
+                addr: i * FRAME_SIZE as u64,
+                len: i as u32,
+                options: 0,
+            });
+        }
+        assert_eq!(ring.producer().load(Ordering::Relaxed), 2);
+        assert_eq!(ring.free_entries(), RING_SIZE - 4);
+        assert_eq!(ring.available_entries(), 4);
+
+        for i in 0..4 {
+            let desc = ring.peek::<XdpDesc>();
+            assert_eq!(desc.addr, i * FRAME_SIZE as u64);
+            assert_eq!(desc.len, i as u32);
+            ring.release();
+        }
+        assert_eq!(ring.consumer().load(Ordering::Relaxed), 2);
+        assert_eq!(ring.available_entries(), 0);
+        assert_eq!(ring.free_entries(), RING_SIZE);
+
+        // The entries are stored at the masked indexes.
+        // SAFETY: the index fits the ring.
+        let desc = unsafe {
+            std::ptr::read_volatile((ring.descs as *const XdpDesc).add(RING_SIZE as usize - 1))
+        };
+        assert_eq!(desc.addr, FRAME_SIZE as u64);
+        // SAFETY: the index fits the ring.
+        let desc = unsafe { std::ptr::read_volatile((ring.descs as *const XdpDesc).add(1)) };
+        assert_eq!(desc.addr, 3 * FRAME_SIZE as u64);
+    }
+}
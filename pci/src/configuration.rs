@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display};
 use std::sync::{Arc, Mutex};
 use vm_device::PciBarType;
+use vm_memory::ByteValued;
 use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable};
 
 // The number of 32bit registers in the config space, 4096 bytes.
@@ -26,10 +27,18 @@ const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
 const ROM_BAR_ADDR_MASK: u32 = 0xffff_f800;
 const MSI_CAPABILITY_REGISTER_MASK: u32 = 0x0071_0000;
 const MSIX_CAPABILITY_REGISTER_MASK: u32 = 0xc000_0000;
+// Only the PowerState field of the PMCSR register is writable, as neither PME
+// nor the optional data registers are supported.
+const PM_CAPABILITY_PMCSR_MASK: u32 = 0x0000_0003;
+// Version 1.2 of the PCI power management specification.
+const PM_CAPABILITY_VERSION: u16 = 0x3;
+// The device state is preserved when going back from D3hot to D0, which
+// saves the driver from reinitializing it.
+const PM_PMCSR_NO_SOFT_RESET: u16 = 0x8;
 const NUM_BAR_REGS: usize = 6;
 const CAPABILITY_LIST_HEAD_OFFSET: usize = 0x34;
 const FIRST_CAPABILITY_OFFSET: usize = 0x40;
-const CAPABILITY_MAX_OFFSET: usize = 192;
+const CAPABILITY_MAX_OFFSET: usize = 256;
 
 const INTERRUPT_LINE_PIN_REG: usize = 15;
 
@@ -249,6 +258,62 @@ impl From<u8> for PciCapabilityId {
     }
 }
 
+/// Device power states, as set through the PowerState field of the power
+/// management capability.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum PciPowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+impl From<u32> for PciPowerState {
+    fn from(pmcsr: u32) -> Self {
+        match pmcsr & PM_CAPABILITY_PMCSR_MASK {
+            0 => PciPowerState::D0,
+            1 => PciPowerState::D1,
+            2 => PciPowerState::D2,
+            _ => PciPowerState::D3Hot,
+        }
+    }
+}
+
+/// PCI power management capability, supporting the mandatory D0 and D3hot
+/// states only.
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+pub struct PciPmCap {
+    pmc: u16,
+    pmcsr: u16,
+    pmcsr_bse: u8,
+    data: u8,
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for PciPmCap {}
+
+impl PciCapability for PciPmCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PowerManagement
+    }
+}
+
+impl PciPmCap {
+    pub fn new() -> Self {
+        PciPmCap {
+            pmc: PM_CAPABILITY_VERSION,
+            pmcsr: PM_PMCSR_NO_SOFT_RESET,
+            ..Default::default()
+        }
+    }
+}
+
 /// Types of PCI Express capabilities.
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[allow(dead_code)]
@@ -417,6 +482,8 @@ pub struct PciConfigurationState {
     rom_bar_used: bool,
     last_capability: Option<(usize, usize)>,
     msix_cap_reg_idx: Option<usize>,
+    #[serde(default)]
+    pm_cap_reg_idx: Option<usize>,
 }
 
 /// Contains the configuration space of a PCI node.
@@ -434,6 +501,7 @@ pub struct PciConfiguration {
     last_capability: Option<(usize, usize)>,
     msix_cap_reg_idx: Option<usize>,
     msix_config: Option<Arc<Mutex<MsixConfig>>>,
+    pm_cap_reg_idx: Option<usize>,
 }
 
 /// See pci_regs.h in kernel
@@ -566,6 +634,7 @@ impl PciConfiguration {
             rom_bar_used,
             last_capability,
             msix_cap_reg_idx,
+            pm_cap_reg_idx,
         ) = if let Some(state) = state {
             (
                 state.registers.try_into().unwrap(),
@@ -576,6 +645,7 @@ impl PciConfiguration {
                 state.rom_bar_used,
                 state.last_capability,
                 state.msix_cap_reg_idx,
+                state.pm_cap_reg_idx,
             )
         } else {
             let mut registers = [0u32; NUM_CONFIGURATION_REGISTERS];
@@ -615,6 +685,7 @@ impl PciConfiguration {
                 false,
                 None,
                 None,
+                None,
             )
         };
 
@@ -628,6 +699,7 @@ impl PciConfiguration {
             last_capability,
             msix_cap_reg_idx,
             msix_config,
+            pm_cap_reg_idx,
         }
     }
 
@@ -641,6 +713,7 @@ impl PciConfiguration {
             rom_bar_used: self.rom_bar_used,
             last_capability: self.last_capability,
             msix_cap_reg_idx: self.msix_cap_reg_idx,
+            pm_cap_reg_idx: self.pm_cap_reg_idx,
         }
     }
 
@@ -893,6 +966,10 @@ impl PciConfiguration {
                 self.msix_cap_reg_idx = Some(cap_offset / 4);
                 self.writable_bits[self.msix_cap_reg_idx.unwrap()] = MSIX_CAPABILITY_REGISTER_MASK;
             }
+            PciCapabilityId::PowerManagement => {
+                self.pm_cap_reg_idx = Some(cap_offset / 4);
+                self.writable_bits[cap_offset / 4 + 1] = PM_CAPABILITY_PMCSR_MASK;
+            }
             _ => {}
         }
 
@@ -927,6 +1004,20 @@ impl PciConfiguration {
             }
         }
 
+        // Writes of the unsupported D1 and D2 states are discarded, as per
+        // the PCI power management specification.
+        if let Some(pm_cap_reg_idx) = self.pm_cap_reg_idx {
+            if pm_cap_reg_idx + 1 == reg_idx
+                && offset == 0
+                && matches!(
+                    PciPowerState::from(u32::from(data[0])),
+                    PciPowerState::D1 | PciPowerState::D2
+                )
+            {
+                return;
+            }
+        }
+
         match data.len() {
             1 => self.write_byte(reg_idx * 4 + offset as usize, data[0]),
             2 => self.write_word(
@@ -942,6 +1033,22 @@ impl PciConfiguration {
         self.read_reg(reg_idx)
     }
 
+    /// Returns the power state of the device, if it has a power management
+    /// capability.
+    pub fn power_state(&self) -> Option<PciPowerState> {
+        self.pm_cap_reg_idx
+            .map(|reg_idx| PciPowerState::from(self.registers[reg_idx + 1]))
+    }
+
+    /// Sets the power state of the device, regardless of what the driver
+    /// asked for.
+    pub fn set_power_state(&mut self, power_state: PciPowerState) {
+        if let Some(reg_idx) = self.pm_cap_reg_idx {
+            self.registers[reg_idx + 1] =
+                (self.registers[reg_idx + 1] & !PM_CAPABILITY_PMCSR_MASK) | power_state as u32;
+        }
+    }
+
     pub fn detect_bar_reprogramming(
         &mut self,
         reg_idx: usize,
@@ -1224,6 +1331,45 @@ mod tests {
         assert_eq!((cap2_data >> 24) & 0xFF, 0x55); // cap2.foo
     }
 
+    #[test]
+    fn power_management() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+            None,
+        );
+        assert_eq!(cfg.power_state(), None);
+
+        let cap_offset = cfg.add_capability(&PciPmCap::new()).unwrap();
+        let pmcsr_reg_idx = cap_offset / 4 + 1;
+        assert_eq!(cfg.read_reg(cap_offset / 4) & 0xFF, 0x01); // capability ID
+        assert_eq!(cfg.read_reg(cap_offset / 4) >> 16, 0x3); // version
+        assert_eq!(cfg.power_state(), Some(PciPowerState::D0));
+
+        cfg.write_config_register(pmcsr_reg_idx, 0, &[0x3, 0x0]);
+        assert_eq!(cfg.power_state(), Some(PciPowerState::D3Hot));
+
+        // D1 and D2 aren't supported.
+        cfg.write_config_register(pmcsr_reg_idx, 0, &[0x1, 0x0]);
+        assert_eq!(cfg.power_state(), Some(PciPowerState::D3Hot));
+
+        // Only the PowerState field is writable.
+        cfg.write_config_register(pmcsr_reg_idx, 0, &[0x0, 0x0, 0xff, 0xff]);
+        assert_eq!(cfg.power_state(), Some(PciPowerState::D0));
+        assert_eq!(cfg.read_reg(pmcsr_reg_idx), 0x8); // No_Soft_Reset
+
+        cfg.set_power_state(PciPowerState::D3Hot);
+        assert_eq!(cfg.power_state(), Some(PciPowerState::D3Hot));
+    }
+
     #[derive(Copy, Clone)]
     enum TestPi {
         Test = 0x5a,
@@ -20,8 +20,8 @@ pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
 pub use self::configuration::{
     PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability, PciCapabilityId,
     PciClassCode, PciConfiguration, PciExpressCapabilityId, PciHeaderType, PciMassStorageSubclass,
-    PciNetworkControllerSubclass, PciProgrammingInterface, PciSerialBusSubClass, PciSubclass,
-    PCI_CONFIGURATION_ID,
+    PciNetworkControllerSubclass, PciPmCap, PciPowerState, PciProgrammingInterface,
+    PciSerialBusSubClass, PciSubclass, PCI_CONFIGURATION_ID,
 };
 pub use self::device::{
     BarReprogrammingParams, DeviceRelocation, Error as PciDeviceError, PciDevice,
@@ -34,6 +34,7 @@ use serde::de::Visitor;
 use std::fmt::{self, Display};
 use std::num::ParseIntError;
 use std::str::FromStr;
+use thiserror::Error;
 
 /// PCI has four interrupt pins A->D.
 #[derive(Copy, Clone)]
@@ -55,9 +56,17 @@ pub const PCI_CONFIG_IO_PORT: u64 = 0xcf8;
 #[cfg(target_arch = "x86_64")]
 pub const PCI_CONFIG_IO_PORT_SIZE: u64 = 0x8;
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PciBdf(u32);
 
+#[derive(Debug, Error)]
+pub enum PciBdfParseError {
+    #[error("Invalid PCI BDF format, expected <segment:bus:device.function>: {0}")]
+    InvalidFormat(String),
+    #[error("Invalid PCI BDF value: {0}")]
+    InvalidValue(#[source] ParseIntError),
+}
+
 struct PciBdfVisitor;
 
 impl<'de> Visitor<'de> for PciBdfVisitor {
@@ -71,7 +80,7 @@ impl<'de> Visitor<'de> for PciBdfVisitor {
     where
         E: serde::de::Error,
     {
-        Ok(v.into())
+        v.parse().map_err(E::custom)
     }
 }
 
@@ -163,18 +172,29 @@ impl Display for PciBdf {
     }
 }
 
+impl fmt::Debug for PciBdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
 impl FromStr for PciBdf {
-    type Err = ParseIntError;
+    type Err = PciBdfParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let items: Vec<&str> = s.split('.').collect();
-        assert_eq!(items.len(), 2);
-        let function = u8::from_str_radix(items[1], 16)?;
-        let items: Vec<&str> = items[0].split(':').collect();
-        assert_eq!(items.len(), 3);
-        let segment = u16::from_str_radix(items[0], 16)?;
-        let bus = u8::from_str_radix(items[1], 16)?;
-        let device = u8::from_str_radix(items[2], 16)?;
+        let invalid_format = || PciBdfParseError::InvalidFormat(s.to_owned());
+        let (address, function) = s.split_once('.').ok_or_else(invalid_format)?;
+        let items: Vec<&str> = address.split(':').collect();
+        if items.len() != 3 {
+            return Err(invalid_format());
+        }
+        let segment = u16::from_str_radix(items[0], 16).map_err(PciBdfParseError::InvalidValue)?;
+        let bus = u8::from_str_radix(items[1], 16).map_err(PciBdfParseError::InvalidValue)?;
+        let device = u8::from_str_radix(items[2], 16).map_err(PciBdfParseError::InvalidValue)?;
+        let function = u8::from_str_radix(function, 16).map_err(PciBdfParseError::InvalidValue)?;
+        if device > 0x1f || function > 0x7 {
+            return Err(invalid_format());
+        }
         Ok(PciBdf::new(segment, bus, device, function))
     }
 }
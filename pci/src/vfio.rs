@@ -9,7 +9,8 @@ use crate::{
     msi_num_enabled_vectors, BarReprogrammingParams, MsiCap, MsiConfig, MsixCap, MsixConfig,
     PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciBdf, PciCapabilityId,
     PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciExpressCapabilityId,
-    PciHeaderType, PciSubclass, MSIX_CONFIG_ID, MSIX_TABLE_ENTRY_SIZE, PCI_CONFIGURATION_ID,
+    PciHeaderType, PciPowerState, PciSubclass, MSIX_CONFIG_ID, MSIX_TABLE_ENTRY_SIZE,
+    PCI_CONFIGURATION_ID,
 };
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
@@ -39,9 +40,25 @@ use vm_device::{BusDevice, Resource};
 use vm_memory::{Address, GuestAddress, GuestAddressSpace, GuestMemory, GuestUsize};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
 
 pub(crate) const VFIO_COMMON_ID: &str = "vfio_common";
 
+// See include/uapi/linux/vfio.h in the kernel code.
+const VFIO_IOCTL_TYPE: u32 = b';' as u32;
+const VFIO_DEVICE_FEATURE_SET: u32 = 1 << 17;
+const VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY: u32 = 3;
+const VFIO_DEVICE_FEATURE_LOW_POWER_EXIT: u32 = 5;
+
+ioctl_io_nr!(VFIO_DEVICE_FEATURE, VFIO_IOCTL_TYPE, 117);
+
+#[repr(C)]
+struct VfioDeviceFeature {
+    argsz: u32,
+    flags: u32,
+}
+
 #[derive(Debug, Error)]
 pub enum VfioPciError {
     #[error("Failed to create user memory region: {0}")]
@@ -320,6 +337,8 @@ pub enum VfioError {
     KernelVfio(#[source] vfio_ioctls::VfioError),
     #[error("VFIO user error: {0}")]
     VfioUser(#[source] vfio_user::Error),
+    #[error("Failed to change the low power state: {0}")]
+    LowPower(#[source] io::Error),
 }
 
 pub(crate) trait Vfio: Send + Sync {
@@ -393,6 +412,12 @@ pub(crate) trait Vfio: Send + Sync {
     fn unmask_irq(&self, _irq_index: u32) -> Result<(), VfioError> {
         unimplemented!()
     }
+
+    fn set_low_power(&self, _low_power: bool) -> Result<(), VfioError> {
+        Err(VfioError::LowPower(io::Error::from(
+            io::ErrorKind::Unsupported,
+        )))
+    }
 }
 
 struct VfioDeviceWrapper {
@@ -435,6 +460,26 @@ impl Vfio for VfioDeviceWrapper {
             .unmask_irq(irq_index)
             .map_err(VfioError::KernelVfio)
     }
+
+    fn set_low_power(&self, low_power: bool) -> Result<(), VfioError> {
+        let feature = VfioDeviceFeature {
+            argsz: std::mem::size_of::<VfioDeviceFeature>() as u32,
+            flags: VFIO_DEVICE_FEATURE_SET
+                | if low_power {
+                    VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY
+                } else {
+                    VFIO_DEVICE_FEATURE_LOW_POWER_EXIT
+                },
+        };
+        // SAFETY: feature is a valid vfio_device_feature structure, with no
+        // payload as expected by both features.
+        let ret = unsafe { ioctl_with_ref(self.device.as_ref(), VFIO_DEVICE_FEATURE(), &feature) };
+        if ret < 0 {
+            return Err(VfioError::LowPower(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -458,6 +503,8 @@ pub(crate) struct VfioCommon {
     pub(crate) vfio_wrapper: Arc<dyn Vfio>,
     pub(crate) patches: HashMap<usize, ConfigPatch>,
     x_nv_gpudirect_clique: Option<u8>,
+    // Whether the host was allowed to move the device to a low power state
+    low_power: bool,
 }
 
 impl VfioCommon {
@@ -505,6 +552,7 @@ impl VfioCommon {
             vfio_wrapper,
             patches: HashMap::new(),
             x_nv_gpudirect_clique,
+            low_power: false,
         };
 
         let state: Option<VfioCommonState> = snapshot
@@ -882,13 +930,17 @@ impl VfioCommon {
     }
 
     pub(crate) fn get_msix_cap_idx(&self) -> Option<usize> {
+        self.get_cap_idx(PciCapabilityId::MsiX)
+    }
+
+    fn get_cap_idx(&self, id: PciCapabilityId) -> Option<usize> {
         let mut cap_next = self
             .vfio_wrapper
             .read_config_byte(PCI_CONFIG_CAPABILITY_OFFSET);
 
         while cap_next != 0 {
             let cap_id = self.vfio_wrapper.read_config_byte(cap_next.into());
-            if PciCapabilityId::from(cap_id) == PciCapabilityId::MsiX {
+            if PciCapabilityId::from(cap_id) == id {
                 return Some(cap_next as usize);
             } else {
                 cap_next = self.vfio_wrapper.read_config_byte((cap_next + 1).into());
@@ -898,6 +950,17 @@ impl VfioCommon {
         None
     }
 
+    // Returns the power state the guest is setting through the PMCSR
+    // register of the power management capability, if it is accessed.
+    fn power_state_written(&self, reg: u64, offset: u64, data: &[u8]) -> Option<PciPowerState> {
+        let pmcsr = self.get_cap_idx(PciCapabilityId::PowerManagement)? as u64 + 4;
+        if reg + offset != pmcsr {
+            return None;
+        }
+
+        Some(PciPowerState::from(u32::from(data[0])))
+    }
+
     pub(crate) fn parse_capabilities(&mut self, bdf: PciBdf) {
         let mut cap_iter = self
             .vfio_wrapper
@@ -1268,6 +1331,17 @@ impl VfioCommon {
             }
         }
 
+        // While the guest keeps the device in D3hot, the host is allowed to
+        // move it to a lower power state, from which it must be brought
+        // back before the guest can use it again.
+        let power_state = self.power_state_written(reg, offset, data);
+        if power_state == Some(PciPowerState::D0) && self.low_power {
+            if let Err(e) = self.vfio_wrapper.set_low_power(false) {
+                error!("Could not leave the low power state: {}", e);
+            }
+            self.low_power = false;
+        }
+
         // Make sure to write to the device's PCI config space after MSI/MSI-X
         // interrupts have been enabled/disabled. In case of MSI, when the
         // interrupts are enabled through VFIO (using VFIO_DEVICE_SET_IRQS),
@@ -1278,6 +1352,13 @@ impl VfioCommon {
         // to the device region to update the MSI Enable bit.
         self.vfio_wrapper.write_config((reg + offset) as u32, data);
 
+        if power_state == Some(PciPowerState::D3Hot) && !self.low_power {
+            match self.vfio_wrapper.set_low_power(true) {
+                Ok(()) => self.low_power = true,
+                Err(e) => debug!("Could not enter a low power state: {}", e),
+            }
+        }
+
         None
     }
 
@@ -3,9 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use api_client::simple_api_command;
 use api_client::simple_api_command_with_fds;
 use api_client::simple_api_full_command;
+use api_client::simple_api_full_command_with_fds;
 use api_client::Error as ApiClientError;
 use clap::{Arg, ArgAction, ArgMatches, Command};
 use option_parser::{ByteSized, ByteSizedParseError};
@@ -27,6 +27,7 @@ enum Error {
     InvalidCpuCount(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
     InvalidBalloonSize(ByteSizedParseError),
+    InvalidCpuWeight(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
@@ -35,6 +36,7 @@ enum Error {
     AddUserDeviceConfig(vmm::config::Error),
     AddVdpaConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    AddUsbDeviceConfig(vmm::config::Error),
     Restore(vmm::config::Error),
     ReadingStdin(std::io::Error),
     ReadingFile(std::io::Error),
@@ -50,6 +52,7 @@ impl fmt::Display for Error {
             InvalidCpuCount(e) => write!(f, "Error parsing CPU count: {e}"),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {e:?}"),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {e:?}"),
+            InvalidCpuWeight(e) => write!(f, "Error parsing CPU weight: {e}"),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {e}"),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {e}"),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {e}"),
@@ -58,6 +61,7 @@ impl fmt::Display for Error {
             AddUserDeviceConfig(e) => write!(f, "Error parsing user device syntax: {e}"),
             AddVdpaConfig(e) => write!(f, "Error parsing vDPA device syntax: {e}"),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {e}"),
+            AddUsbDeviceConfig(e) => write!(f, "Error parsing USB device syntax: {e}"),
             Restore(e) => write!(f, "Error parsing restore syntax: {e}"),
             ReadingStdin(e) => write!(f, "Error reading from stdin: {e}"),
             ReadingFile(e) => write!(f, "Error reading from file: {e}"),
@@ -76,15 +80,20 @@ enum TargetApi<'a> {
 trait DBusApi1 {
     fn vmm_ping(&self) -> zbus::Result<String>;
     fn vmm_shutdown(&self) -> zbus::Result<()>;
+    fn vmm_add_vm(&self, vmm_add_vm_data: &str) -> zbus::Result<()>;
+    fn vmm_list_vms(&self) -> zbus::Result<Optional<String>>;
+    fn vmm_remove_vm(&self, vmm_remove_vm_data: &str) -> zbus::Result<()>;
     fn vm_add_device(&self, device_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_disk(&self, disk_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_fs(&self, fs_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_net(&self, net_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_pmem(&self, pmem_config: &str) -> zbus::Result<Optional<String>>;
+    fn vm_add_usb_device(&self, usb_device_config: &str) -> zbus::Result<()>;
     fn vm_add_user_device(&self, vm_add_user_device: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_vdpa(&self, vdpa_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_vsock(&self, vsock_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_boot(&self) -> zbus::Result<()>;
+    fn vm_clock(&self) -> zbus::Result<Optional<String>>;
     fn vm_coredump(&self, vm_coredump_data: &str) -> zbus::Result<()>;
     fn vm_counters(&self) -> zbus::Result<Optional<String>>;
     fn vm_create(&self, vm_config: &str) -> zbus::Result<()>;
@@ -100,8 +109,12 @@ trait DBusApi1 {
     fn vm_receive_migration(&self, receive_migration_data: &str) -> zbus::Result<()>;
     fn vm_send_migration(&self, receive_migration_data: &str) -> zbus::Result<()>;
     fn vm_resume(&self) -> zbus::Result<()>;
+    fn vm_serial_capture(&self) -> zbus::Result<Optional<String>>;
     fn vm_shutdown(&self) -> zbus::Result<()>;
     fn vm_snapshot(&self, vm_snapshot_config: &str) -> zbus::Result<()>;
+    fn vm_snapshot_delete(&self, vm_snapshot_delete_data: &str) -> zbus::Result<()>;
+    fn vm_snapshot_list(&self, vm_snapshot_list_data: &str) -> zbus::Result<Optional<String>>;
+    fn vm_update_cgroup(&self, vm_update_cgroup: &str) -> zbus::Result<()>;
 }
 
 #[cfg(feature = "dbus_api")]
@@ -139,6 +152,20 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vmm_shutdown().map_err(Error::DBusApiClient)
     }
 
+    fn api_vmm_add_vm(&self, vmm_add_vm_data: &str) -> ApiResult {
+        self.vmm_add_vm(vmm_add_vm_data)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vmm_list_vms(&self) -> ApiResult {
+        self.print_response(self.vmm_list_vms())
+    }
+
+    fn api_vmm_remove_vm(&self, vmm_remove_vm_data: &str) -> ApiResult {
+        self.vmm_remove_vm(vmm_remove_vm_data)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_add_device(&self, device_config: &str) -> ApiResult {
         self.print_response(self.vm_add_device(device_config))
     }
@@ -159,6 +186,11 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.print_response(self.vm_add_pmem(pmem_config))
     }
 
+    fn api_vm_add_usb_device(&self, usb_device_config: &str) -> ApiResult {
+        self.vm_add_usb_device(usb_device_config)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_add_user_device(&self, vm_add_user_device: &str) -> ApiResult {
         self.print_response(self.vm_add_user_device(vm_add_user_device))
     }
@@ -180,10 +212,18 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_clock(&self) -> ApiResult {
+        self.print_response(self.vm_clock())
+    }
+
     fn api_vm_counters(&self) -> ApiResult {
         self.print_response(self.vm_counters())
     }
 
+    fn api_vm_serial_capture(&self) -> ApiResult {
+        self.print_response(self.vm_serial_capture())
+    }
+
     fn api_vm_create(&self, vm_config: &str) -> ApiResult {
         self.vm_create(vm_config).map_err(Error::DBusApiClient)
     }
@@ -224,6 +264,11 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_update_cgroup(&self, vm_update_cgroup: &str) -> ApiResult {
+        self.vm_update_cgroup(vm_update_cgroup)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_restore(&self, restore_config: &str) -> ApiResult {
         self.vm_restore(restore_config)
             .map_err(Error::DBusApiClient)
@@ -251,53 +296,108 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vm_snapshot(vm_snapshot_config)
             .map_err(Error::DBusApiClient)
     }
+
+    fn api_vm_snapshot_delete(&self, vm_snapshot_delete_data: &str) -> ApiResult {
+        self.vm_snapshot_delete(vm_snapshot_delete_data)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_snapshot_list(&self, vm_snapshot_list_data: &str) -> ApiResult {
+        self.print_response(self.vm_snapshot_list(vm_snapshot_list_data))
+    }
 }
 
 impl<'a> TargetApi<'a> {
     fn do_command(&mut self, matches: &ArgMatches) -> ApiResult {
         match self {
-            Self::HttpApi(api_socket, _) => rest_api_do_command(matches, api_socket),
+            Self::HttpApi(api_socket, _) => rest_api_do_command(
+                matches,
+                api_socket,
+                matches.get_one::<String>("vm").map(|x| x as &str),
+            ),
             #[cfg(feature = "dbus_api")]
             Self::DBusApi(proxy) => dbus_api_do_command(matches, proxy),
         }
     }
 }
 
-fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResult {
+// Sends a request to the VM of the VMM, or to one of the VMs added to it in
+// multi-VM mode.
+fn vm_api_command_with_fds(
+    socket: &mut UnixStream,
+    vm: Option<&str>,
+    method: &str,
+    c: &str,
+    request_body: Option<&str>,
+    request_fds: Vec<i32>,
+) -> Result<(), ApiClientError> {
+    match vm {
+        Some(vm) => simple_api_full_command_with_fds(
+            socket,
+            method,
+            &format!("vms/{vm}/vm.{c}"),
+            request_body,
+            request_fds,
+        ),
+        None => simple_api_command_with_fds(socket, method, c, request_body, request_fds),
+    }
+}
+
+fn vm_api_command(
+    socket: &mut UnixStream,
+    vm: Option<&str>,
+    method: &str,
+    c: &str,
+    request_body: Option<&str>,
+) -> Result<(), ApiClientError> {
+    vm_api_command_with_fds(socket, vm, method, c, request_body, Vec::new())
+}
+
+fn rest_api_do_command(
+    matches: &ArgMatches,
+    socket: &mut UnixStream,
+    vm: Option<&str>,
+) -> ApiResult {
     match matches.subcommand_name() {
         Some("boot") => {
-            simple_api_command(socket, "PUT", "boot", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "boot", None).map_err(Error::HttpApiClient)
         }
         Some("delete") => {
-            simple_api_command(socket, "PUT", "delete", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "delete", None).map_err(Error::HttpApiClient)
         }
         Some("shutdown-vmm") => simple_api_full_command(socket, "PUT", "vmm.shutdown", None)
             .map_err(Error::HttpApiClient),
         Some("resume") => {
-            simple_api_command(socket, "PUT", "resume", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "resume", None).map_err(Error::HttpApiClient)
         }
         Some("power-button") => {
-            simple_api_command(socket, "PUT", "power-button", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "power-button", None).map_err(Error::HttpApiClient)
         }
         Some("reboot") => {
-            simple_api_command(socket, "PUT", "reboot", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "reboot", None).map_err(Error::HttpApiClient)
         }
         Some("pause") => {
-            simple_api_command(socket, "PUT", "pause", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "pause", None).map_err(Error::HttpApiClient)
         }
         Some("info") => {
-            simple_api_command(socket, "GET", "info", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "GET", "info", None).map_err(Error::HttpApiClient)
         }
         Some("counters") => {
-            simple_api_command(socket, "GET", "counters", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "GET", "counters", None).map_err(Error::HttpApiClient)
+        }
+        Some("clock") => {
+            vm_api_command(socket, vm, "GET", "clock", None).map_err(Error::HttpApiClient)
+        }
+        Some("serial-capture") => {
+            vm_api_command(socket, vm, "GET", "serial-capture", None).map_err(Error::HttpApiClient)
         }
         Some("ping") => {
             simple_api_full_command(socket, "GET", "vmm.ping", None).map_err(Error::HttpApiClient)
         }
         Some("shutdown") => {
-            simple_api_command(socket, "PUT", "shutdown", None).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "shutdown", None).map_err(Error::HttpApiClient)
         }
-        Some("nmi") => simple_api_command(socket, "PUT", "nmi", None).map_err(Error::HttpApiClient),
+        Some("nmi") => vm_api_command(socket, vm, "PUT", "nmi", None).map_err(Error::HttpApiClient),
         Some("resize") => {
             let resize = resize_config(
                 matches
@@ -305,6 +405,11 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .unwrap()
                     .get_one::<String>("cpus")
                     .map(|x| x as &str),
+                matches
+                    .subcommand_matches("resize")
+                    .unwrap()
+                    .get_one::<String>("max_cpus")
+                    .map(|x| x as &str),
                 matches
                     .subcommand_matches("resize")
                     .unwrap()
@@ -316,7 +421,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("balloon")
                     .map(|x| x as &str),
             )?;
-            simple_api_command(socket, "PUT", "resize", Some(&resize)).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "resize", Some(&resize)).map_err(Error::HttpApiClient)
         }
         Some("resize-zone") => {
             let resize_zone = resize_zone_config(
@@ -331,7 +436,13 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("size")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "resize-zone", Some(&resize_zone))
+            vm_api_command(socket, vm, "PUT", "resize-zone", Some(&resize_zone))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("update-cgroup") => {
+            let update_cgroup =
+                update_cgroup_config(matches.subcommand_matches("update-cgroup").unwrap())?;
+            vm_api_command(socket, vm, "PUT", "update-cgroup", Some(&update_cgroup))
                 .map_err(Error::HttpApiClient)
         }
         Some("add-device") => {
@@ -342,7 +453,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("device_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-device", Some(&device_config))
+            vm_api_command(socket, vm, "PUT", "add-device", Some(&device_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("remove-device") => {
@@ -353,8 +464,14 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("id")
                     .unwrap(),
             );
-            simple_api_command(socket, "PUT", "remove-device", Some(&remove_device_data))
-                .map_err(Error::HttpApiClient)
+            vm_api_command(
+                socket,
+                vm,
+                "PUT",
+                "remove-device",
+                Some(&remove_device_data),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-disk") => {
             let disk_config = add_disk_config(
@@ -364,7 +481,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("disk_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-disk", Some(&disk_config))
+            vm_api_command(socket, vm, "PUT", "add-disk", Some(&disk_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("add-fs") => {
@@ -375,7 +492,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("fs_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-fs", Some(&fs_config))
+            vm_api_command(socket, vm, "PUT", "add-fs", Some(&fs_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("add-pmem") => {
@@ -386,7 +503,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("pmem_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-pmem", Some(&pmem_config))
+            vm_api_command(socket, vm, "PUT", "add-pmem", Some(&pmem_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("add-net") => {
@@ -397,7 +514,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("net_config")
                     .unwrap(),
             )?;
-            simple_api_command_with_fds(socket, "PUT", "add-net", Some(&net_config), fds)
+            vm_api_command_with_fds(socket, vm, "PUT", "add-net", Some(&net_config), fds)
                 .map_err(Error::HttpApiClient)
         }
         Some("add-user-device") => {
@@ -408,7 +525,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("device_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-user-device", Some(&device_config))
+            vm_api_command(socket, vm, "PUT", "add-user-device", Some(&device_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("add-vdpa") => {
@@ -419,7 +536,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("vdpa_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-vdpa", Some(&vdpa_config))
+            vm_api_command(socket, vm, "PUT", "add-vdpa", Some(&vdpa_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("add-vsock") => {
@@ -430,9 +547,26 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("vsock_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-vsock", Some(&vsock_config))
+            vm_api_command(socket, vm, "PUT", "add-vsock", Some(&vsock_config))
                 .map_err(Error::HttpApiClient)
         }
+        Some("add-usb-device") => {
+            let usb_device_config = add_usb_device_config(
+                matches
+                    .subcommand_matches("add-usb-device")
+                    .unwrap()
+                    .get_one::<String>("usb_device_config")
+                    .unwrap(),
+            )?;
+            vm_api_command(
+                socket,
+                vm,
+                "PUT",
+                "add-usb-device",
+                Some(&usb_device_config),
+            )
+            .map_err(Error::HttpApiClient)
+        }
         Some("snapshot") => {
             let snapshot_config = snapshot_config(
                 matches
@@ -441,7 +575,34 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("snapshot_config")
                     .unwrap(),
             );
-            simple_api_command(socket, "PUT", "snapshot", Some(&snapshot_config))
+            vm_api_command(socket, vm, "PUT", "snapshot", Some(&snapshot_config))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("snapshot-list") => {
+            let snapshot_list = snapshot_list_data(
+                matches
+                    .subcommand_matches("snapshot-list")
+                    .unwrap()
+                    .get_one::<String>("tree_url")
+                    .unwrap(),
+            );
+            vm_api_command(socket, vm, "PUT", "snapshot-list", Some(&snapshot_list))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("snapshot-delete") => {
+            let snapshot_delete = snapshot_delete_data(
+                matches
+                    .subcommand_matches("snapshot-delete")
+                    .unwrap()
+                    .get_one::<String>("tree_url")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("snapshot-delete")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            vm_api_command(socket, vm, "PUT", "snapshot-delete", Some(&snapshot_delete))
                 .map_err(Error::HttpApiClient)
         }
         Some("restore") => {
@@ -452,7 +613,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("restore_config")
                     .unwrap(),
             )?;
-            simple_api_command_with_fds(socket, "PUT", "restore", Some(&restore_config), fds)
+            vm_api_command_with_fds(socket, vm, "PUT", "restore", Some(&restore_config), fds)
                 .map_err(Error::HttpApiClient)
         }
         Some("coredump") => {
@@ -463,7 +624,7 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("coredump_config")
                     .unwrap(),
             );
-            simple_api_command(socket, "PUT", "coredump", Some(&coredump_config))
+            vm_api_command(socket, vm, "PUT", "coredump", Some(&coredump_config))
                 .map_err(Error::HttpApiClient)
         }
         Some("send-migration") => {
@@ -478,8 +639,21 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .unwrap()
                     .get_flag("send_migration_local"),
             );
-            simple_api_command(socket, "PUT", "send-migration", Some(&send_migration_data))
-                .map_err(Error::HttpApiClient)
+            vm_api_command_with_fds(
+                socket,
+                vm,
+                "PUT",
+                "send-migration",
+                Some(&send_migration_data),
+                migration_fds(
+                    matches
+                        .subcommand_matches("send-migration")
+                        .unwrap()
+                        .get_one::<String>("send_migration_config")
+                        .unwrap(),
+                ),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("receive-migration") => {
             let receive_migration_data = receive_migration_data(
@@ -488,12 +662,24 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .unwrap()
                     .get_one::<String>("receive_migration_config")
                     .unwrap(),
+                matches
+                    .subcommand_matches("receive-migration")
+                    .unwrap()
+                    .get_flag("receive_migration_resync_clock"),
             );
-            simple_api_command(
+            vm_api_command_with_fds(
                 socket,
+                vm,
                 "PUT",
                 "receive-migration",
                 Some(&receive_migration_data),
+                migration_fds(
+                    matches
+                        .subcommand_matches("receive-migration")
+                        .unwrap()
+                        .get_one::<String>("receive_migration_config")
+                        .unwrap(),
+                ),
             )
             .map_err(Error::HttpApiClient)
         }
@@ -505,8 +691,32 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("path")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "create", Some(&data)).map_err(Error::HttpApiClient)
+            vm_api_command(socket, vm, "PUT", "create", Some(&data)).map_err(Error::HttpApiClient)
         }
+        Some("add-vm") => {
+            let add_vm_data = vm_id_data(
+                matches
+                    .subcommand_matches("add-vm")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            simple_api_full_command(socket, "PUT", "vmm.add-vm", Some(&add_vm_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("remove-vm") => {
+            let remove_vm_data = vm_id_data(
+                matches
+                    .subcommand_matches("remove-vm")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            simple_api_full_command(socket, "PUT", "vmm.remove-vm", Some(&remove_vm_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("list-vms") => simple_api_full_command(socket, "GET", "vmm.list-vms", None)
+            .map_err(Error::HttpApiClient),
         _ => unreachable!(),
     }
 }
@@ -523,6 +733,8 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
         Some("pause") => proxy.api_vm_pause(),
         Some("info") => proxy.api_vm_info(),
         Some("counters") => proxy.api_vm_counters(),
+        Some("clock") => proxy.api_vm_clock(),
+        Some("serial-capture") => proxy.api_vm_serial_capture(),
         Some("ping") => proxy.api_vmm_ping(),
         Some("shutdown") => proxy.api_vm_shutdown(),
         Some("resize") => {
@@ -532,6 +744,11 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .unwrap()
                     .get_one::<String>("cpus")
                     .map(|x| x as &str),
+                matches
+                    .subcommand_matches("resize")
+                    .unwrap()
+                    .get_one::<String>("max_cpus")
+                    .map(|x| x as &str),
                 matches
                     .subcommand_matches("resize")
                     .unwrap()
@@ -560,6 +777,11 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_resize_zone(&resize_zone)
         }
+        Some("update-cgroup") => {
+            let update_cgroup =
+                update_cgroup_config(matches.subcommand_matches("update-cgroup").unwrap())?;
+            proxy.api_vm_update_cgroup(&update_cgroup)
+        }
         Some("add-device") => {
             let device_config = add_device_config(
                 matches
@@ -650,6 +872,16 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_add_vsock(&vsock_config)
         }
+        Some("add-usb-device") => {
+            let usb_device_config = add_usb_device_config(
+                matches
+                    .subcommand_matches("add-usb-device")
+                    .unwrap()
+                    .get_one::<String>("usb_device_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_usb_device(&usb_device_config)
+        }
         Some("snapshot") => {
             let snapshot_config = snapshot_config(
                 matches
@@ -660,6 +892,31 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             );
             proxy.api_vm_snapshot(&snapshot_config)
         }
+        Some("snapshot-list") => {
+            let snapshot_list = snapshot_list_data(
+                matches
+                    .subcommand_matches("snapshot-list")
+                    .unwrap()
+                    .get_one::<String>("tree_url")
+                    .unwrap(),
+            );
+            proxy.api_vm_snapshot_list(&snapshot_list)
+        }
+        Some("snapshot-delete") => {
+            let snapshot_delete = snapshot_delete_data(
+                matches
+                    .subcommand_matches("snapshot-delete")
+                    .unwrap()
+                    .get_one::<String>("tree_url")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("snapshot-delete")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            proxy.api_vm_snapshot_delete(&snapshot_delete)
+        }
         Some("restore") => {
             let (restore_config, _fds) = restore_config(
                 matches
@@ -701,6 +958,10 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .unwrap()
                     .get_one::<String>("receive_migration_config")
                     .unwrap(),
+                matches
+                    .subcommand_matches("receive-migration")
+                    .unwrap()
+                    .get_flag("receive_migration_resync_clock"),
             );
             proxy.api_vm_receive_migration(&receive_migration_data)
         }
@@ -714,12 +975,34 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_create(&data)
         }
+        Some("add-vm") => {
+            let add_vm_data = vm_id_data(
+                matches
+                    .subcommand_matches("add-vm")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            proxy.api_vmm_add_vm(&add_vm_data)
+        }
+        Some("remove-vm") => {
+            let remove_vm_data = vm_id_data(
+                matches
+                    .subcommand_matches("remove-vm")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            proxy.api_vmm_remove_vm(&remove_vm_data)
+        }
+        Some("list-vms") => proxy.api_vmm_list_vms(),
         _ => unreachable!(),
     }
 }
 
 fn resize_config(
     cpus: Option<&str>,
+    max_cpus: Option<&str>,
     memory: Option<&str>,
     balloon: Option<&str>,
 ) -> Result<String, Error> {
@@ -729,6 +1012,12 @@ fn resize_config(
         None
     };
 
+    let desired_max_vcpus: Option<u8> = if let Some(max_cpus) = max_cpus {
+        Some(max_cpus.parse().map_err(Error::InvalidCpuCount)?)
+    } else {
+        None
+    };
+
     let desired_ram: Option<u64> = if let Some(memory) = memory {
         Some(
             memory
@@ -753,6 +1042,7 @@ fn resize_config(
 
     let resize = vmm::api::VmResizeData {
         desired_vcpus,
+        desired_max_vcpus,
         desired_ram,
         desired_balloon,
     };
@@ -760,6 +1050,36 @@ fn resize_config(
     Ok(serde_json::to_string(&resize).unwrap())
 }
 
+fn update_cgroup_config(matches: &ArgMatches) -> Result<String, Error> {
+    let parse_weight = |name: &str| -> Result<Option<u64>, Error> {
+        matches
+            .get_one::<String>(name)
+            .map(|w| w.parse().map_err(Error::InvalidCpuWeight))
+            .transpose()
+    };
+
+    let memory_high = matches
+        .get_one::<String>("memory_high")
+        .map(|m| {
+            m.parse::<ByteSized>()
+                .map_err(Error::InvalidMemorySize)
+                .map(|m| m.0)
+        })
+        .transpose()?;
+
+    let resources = vmm::config::CgroupResources {
+        memory_high,
+        io_max: matches
+            .get_many::<String>("io_max")
+            .map(|x| x.cloned().collect()),
+        vmm_cpu_weight: parse_weight("vmm_cpu_weight")?,
+        vcpu_cpu_weight: parse_weight("vcpu_cpu_weight")?,
+        iothread_cpu_weight: parse_weight("iothread_cpu_weight")?,
+    };
+
+    Ok(serde_json::to_string(&resources).unwrap())
+}
+
 fn resize_zone_config(id: &str, size: &str) -> Result<String, Error> {
     let resize_zone = vmm::api::VmResizeZoneData {
         id: id.to_owned(),
@@ -793,6 +1113,12 @@ fn remove_device_config(id: &str) -> String {
     serde_json::to_string(&remove_device_data).unwrap()
 }
 
+fn vm_id_data(id: &str) -> String {
+    let vm_id_data = vmm::api::VmmAddVmData { id: id.to_owned() };
+
+    serde_json::to_string(&vm_id_data).unwrap()
+}
+
 fn add_disk_config(config: &str) -> Result<String, Error> {
     let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
     let disk_config = serde_json::to_string(&disk_config).unwrap();
@@ -841,6 +1167,14 @@ fn add_vsock_config(config: &str) -> Result<String, Error> {
     Ok(vsock_config)
 }
 
+fn add_usb_device_config(config: &str) -> Result<String, Error> {
+    let usb_device_config =
+        vmm::config::UsbDeviceConfig::parse(config).map_err(Error::AddUsbDeviceConfig)?;
+    let usb_device_config = serde_json::to_string(&usb_device_config).unwrap();
+
+    Ok(usb_device_config)
+}
+
 fn snapshot_config(url: &str) -> String {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
@@ -849,6 +1183,23 @@ fn snapshot_config(url: &str) -> String {
     serde_json::to_string(&snapshot_config).unwrap()
 }
 
+fn snapshot_list_data(url: &str) -> String {
+    let snapshot_list = vmm::api::VmSnapshotListData {
+        tree_url: String::from(url),
+    };
+
+    serde_json::to_string(&snapshot_list).unwrap()
+}
+
+fn snapshot_delete_data(url: &str, id: &str) -> String {
+    let snapshot_delete = vmm::api::VmSnapshotDeleteData {
+        tree_url: String::from(url),
+        id: id.to_owned(),
+    };
+
+    serde_json::to_string(&snapshot_delete).unwrap()
+}
+
 fn restore_config(config: &str) -> Result<(String, Vec<i32>), Error> {
     let mut restore_config = vmm::config::RestoreConfig::parse(config).map_err(Error::Restore)?;
     // RestoreConfig is modified on purpose to take out the file descriptors.
@@ -873,18 +1224,30 @@ fn coredump_config(destination_url: &str) -> String {
     serde_json::to_string(&coredump_config).unwrap()
 }
 
-fn receive_migration_data(url: &str) -> String {
+fn receive_migration_data(url: &str, resync_clock: bool) -> String {
     let receive_migration_data = vmm::api::VmReceiveMigrationData {
         receiver_url: url.to_owned(),
+        resync_clock,
+        fd: None,
     };
 
     serde_json::to_string(&receive_migration_data).unwrap()
 }
 
+// The file descriptor of a `fd:` migration URL is passed to the server side
+// process via SCM_RIGHTS.
+fn migration_fds(url: &str) -> Vec<i32> {
+    url.strip_prefix("fd:")
+        .and_then(|fd| fd.parse().ok())
+        .into_iter()
+        .collect()
+}
+
 fn send_migration_data(url: &str, local: bool) -> String {
     let send_migration_data = vmm::api::VmSendMigrationData {
         destination_url: url.to_owned(),
         local,
+        fd: None,
     };
 
     serde_json::to_string(&send_migration_data).unwrap()
@@ -915,6 +1278,10 @@ fn main() {
                 .long("api-socket")
                 .help("HTTP API socket path (UNIX domain socket).")
                 .num_args(1),
+            Arg::new("vm")
+                .long("vm")
+                .help("Identifier of the VM to control, among the ones added to the VMM in multi-VM mode.")
+                .num_args(1),
             #[cfg(feature = "dbus_api")]
             Arg::new("dbus-service-name")
                 .long("dbus-service-name")
@@ -994,6 +1361,15 @@ fn main() {
                     .help(vmm::config::VsockConfig::SYNTAX),
             ),
         )
+        .subcommand(
+            Command::new("add-usb-device")
+                .about("Add USB host device")
+                .arg(
+                    Arg::new("usb_device_config")
+                        .index(1)
+                        .help(vmm::config::UsbDeviceConfig::SYNTAX),
+                ),
+        )
         .subcommand(
             Command::new("remove-device")
                 .about("Remove VFIO and PCI device")
@@ -1001,6 +1377,7 @@ fn main() {
         )
         .subcommand(Command::new("info").about("Info on the VM"))
         .subcommand(Command::new("counters").about("Counters from the VM"))
+        .subcommand(Command::new("clock").about("Guest clock and its offset from the host"))
         .subcommand(Command::new("pause").about("Pause the VM"))
         .subcommand(Command::new("reboot").about("Reboot the VM"))
         .subcommand(Command::new("power-button").about("Trigger a power button in the VM"))
@@ -1013,6 +1390,12 @@ fn main() {
                         .help("New vCPUs count")
                         .num_args(1),
                 )
+                .arg(
+                    Arg::new("max_cpus")
+                        .long("max-cpus")
+                        .help("New maximum vCPUs count")
+                        .num_args(1),
+                )
                 .arg(
                     Arg::new("memory")
                         .long("memory")
@@ -1042,9 +1425,46 @@ fn main() {
                         .num_args(1),
                 ),
         )
+        .subcommand(
+            Command::new("update-cgroup")
+                .about("Update the cgroup resource limits of the VM")
+                .arg(
+                    Arg::new("memory_high")
+                        .long("memory-high")
+                        .help("New memory.high in bytes (supports K/M/G suffix)")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("io_max")
+                        .long("io-max")
+                        .help("New io.max lines, e.g. \"8:0 rbps=1048576\"")
+                        .num_args(1..),
+                )
+                .arg(
+                    Arg::new("vmm_cpu_weight")
+                        .long("vmm-cpu-weight")
+                        .help("New cpu.weight of the VMM threads")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("vcpu_cpu_weight")
+                        .long("vcpu-cpu-weight")
+                        .help("New cpu.weight of the vCPU threads")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("iothread_cpu_weight")
+                        .long("iothread-cpu-weight")
+                        .help("New cpu.weight of the virtio device threads")
+                        .num_args(1),
+                ),
+        )
         .subcommand(Command::new("resume").about("Resume the VM"))
         .subcommand(Command::new("boot").about("Boot a created VM"))
         .subcommand(Command::new("delete").about("Delete a VM"))
+        .subcommand(
+            Command::new("serial-capture").about("Last output captured from the serial port"),
+        )
         .subcommand(Command::new("shutdown").about("Shutdown the VM"))
         .subcommand(
             Command::new("snapshot")
@@ -1055,6 +1475,17 @@ fn main() {
                         .help("<destination_url>"),
                 ),
         )
+        .subcommand(
+            Command::new("snapshot-list")
+                .about("List the snapshots of a snapshot tree")
+                .arg(Arg::new("tree_url").index(1).help("<tree_url>")),
+        )
+        .subcommand(
+            Command::new("snapshot-delete")
+                .about("Delete a snapshot from a snapshot tree")
+                .arg(Arg::new("tree_url").index(1).help("<tree_url>"))
+                .arg(Arg::new("id").index(2).help("<snapshot_id>")),
+        )
         .subcommand(
             Command::new("restore")
                 .about("Restore VM from a snapshot")
@@ -1091,6 +1522,13 @@ fn main() {
                     Arg::new("receive_migration_config")
                         .index(1)
                         .help("<receiver_url>"),
+                )
+                .arg(
+                    Arg::new("receive_migration_resync_clock")
+                        .long("resync-clock")
+                        .help("Move the guest clock forward by the migration downtime")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -1100,6 +1538,17 @@ fn main() {
         )
         .subcommand(Command::new("ping").about("Ping the VMM to check for API server availability"))
         .subcommand(Command::new("shutdown-vmm").about("Shutdown the VMM"))
+        .subcommand(
+            Command::new("add-vm")
+                .about("Add a VM to the VMM in multi-VM mode")
+                .arg(Arg::new("id").index(1).help("<vm_id>")),
+        )
+        .subcommand(
+            Command::new("remove-vm")
+                .about("Remove a VM added to the VMM in multi-VM mode")
+                .arg(Arg::new("id").index(1).help("<vm_id>")),
+        )
+        .subcommand(Command::new("list-vms").about("List the VMs added to the VMM in multi-VM mode"))
         .subcommand(Command::new("nmi").about("Trigger NMI"));
 
     let matches = app.get_matches();
@@ -1153,6 +1602,12 @@ fn main() {
         }
     };
 
+    #[cfg(feature = "dbus_api")]
+    if matches!(target_api, TargetApi::DBusApi(_)) && matches.contains_id("vm") {
+        eprintln!("`vm` is only supported by the HTTP API");
+        process::exit(1);
+    }
+
     if let Err(e) = target_api.do_command(&matches) {
         eprintln!("Error running command: {e}");
         process::exit(1)
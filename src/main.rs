@@ -9,7 +9,7 @@ extern crate event_monitor;
 use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
 use libc::EFD_NONBLOCK;
 use log::{warn, LevelFilter};
-use option_parser::OptionParser;
+use option_parser::{ByteSized, OptionParser};
 use seccompiler::SeccompAction;
 use signal_hook::consts::SIGSYS;
 use std::fs::File;
@@ -44,6 +44,10 @@ enum Error {
     CreateHypervisor(#[source] hypervisor::HypervisorError),
     #[error("Failed to start the VMM thread: {0}")]
     StartVmmThread(#[source] vmm::Error),
+    #[error("Failed to start the seccomp audit thread: {0}")]
+    StartSeccompAudit(#[source] std::io::Error),
+    #[error("Failed to start the device backend launcher: {0}")]
+    StartBackendLauncher(#[source] vmm::device_backend::DeviceBackendError),
     #[error("Error parsing config: {0}")]
     ParsingConfig(vmm::config::Error),
     #[error("Error creating VM: {0:?}")]
@@ -77,6 +81,10 @@ enum Error {
     #[cfg(feature = "guest_debug")]
     #[error("Error parsing --gdb: {0}")]
     ParsingGdb(option_parser::OptionParserError),
+    #[error("Error parsing --rlimit: {0}")]
+    ParsingRlimit(option_parser::OptionParserError),
+    #[error("Error setting resource limit: {0}")]
+    SetRlimit(#[source] std::io::Error),
     #[cfg(feature = "guest_debug")]
     #[error("Error parsing --gdb: path required")]
     BareGdb,
@@ -180,6 +188,17 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .group(ArgGroup::new("vm-config").multiple(true))
         .group(ArgGroup::new("vmm-config").multiple(true))
         .group(ArgGroup::new("logging").multiple(true))
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .help(
+                    "Path to a TOML or JSON file describing the complete VM configuration, \
+                    using the vm.create API schema. Other VM options override the matching \
+                    parts of the file",
+                )
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("cpus")
                 .long("cpus")
@@ -188,6 +207,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                     topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
                     kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>,\
                     affinity=<list_of_vcpus_with_their_associated_cpuset>,\
+                    affinity_fallback=any|numa,\
                     features=<list_of_features_to_enable>",
                 )
                 .default_value(default_vcpus)
@@ -196,7 +216,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("platform")
                 .long("platform")
-                .help("num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>")
+                .help("num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,iommu_hotplug_slots=<num_slots>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>,oem_string_files=<list_of_paths>,virtio_mmio=on|off,vmbus_relay=<backend_socket_path>")
                 .num_args(1)
                 .group("vm-config"),
         )
@@ -210,7 +230,9 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                      hotplug_method=acpi|virtio-mem,\
                      hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off,thp=on|off\"",
+                     boot_size=<static_memory_size>,\
+                     prefault=on|off,mlock=on|off,thp=on|off,\
+                     dirty_ring=on|off,guest_memfd=on|off\"",
                 )
                 .default_value(default_memory)
                 .group("vm-config"),
@@ -226,7 +248,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                      host_numa_node=<node_id>,\
                      id=<zone_identifier>,hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off\"",
+                     prefault=on|off,mlock=on|off\"",
                 )
                 .num_args(1..)
                 .group("vm-config"),
@@ -262,6 +284,13 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("io-affinity")
+                .long("io-affinity")
+                .help(config::IoAffinityConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("rate-limit-group")
                 .long("rate-limit-group")
@@ -294,6 +323,20 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             .num_args(1..)
             .group("vm-config"),
         )
+        .arg(
+            Arg::new("cgroup")
+                .long("cgroup")
+                .help(config::CgroupConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("security-label")
+                .long("security-label")
+                .help(config::SecurityLabelConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("net")
                 .long("net")
@@ -324,6 +367,20 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1..)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("fs9p")
+                .long("fs9p")
+                .help(config::Fs9pConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("i2c")
+                .long("i2c")
+                .help(config::I2cConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("pmem")
                 .long("pmem")
@@ -334,7 +391,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("serial")
                 .long("serial")
-                .help("Control serial port: off|null|pty|tty|file=</path/to/a/file>|socket=</path/to/a/file>")
+                .help("Control serial port: off|null|pty|tty|file=</path/to/a/file>|socket=</path/to/a/file>[,log_file=</path/to/a/file>,capture_size=<capture_size>]")
                 .default_value("null")
                 .group("vm-config"),
         )
@@ -368,6 +425,48 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1..)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("ivshmem")
+                .long("ivshmem")
+                .help(config::IvshmemConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("nvme")
+                .long("nvme")
+                .help(config::NvmeConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("cxl")
+                .long("cxl")
+                .help(config::CxlConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("xhci")
+                .long("xhci")
+                .help(config::XhciConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("usb")
+                .long("usb")
+                .help(config::UsbDeviceConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("vnc")
+                .long("vnc")
+                .help(config::VncConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("vsock")
                 .long("vsock")
@@ -375,6 +474,13 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("guest-agent")
+                .long("guest-agent")
+                .help(config::GuestAgentConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("pvpanic")
                 .long("pvpanic")
@@ -383,6 +489,21 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .action(ArgAction::SetTrue)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("vmclock")
+                .long("vmclock")
+                .help("Enable vmclock device")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("panic")
+                .long("panic")
+                .help(config::PanicConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("numa")
                 .long("numa")
@@ -390,6 +511,14 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1..)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("numa-placement")
+                .long("numa-placement")
+                .help("Automatically place the guest NUMA nodes on the host NUMA nodes")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("pci-segment")
                 .long("pci-segment")
@@ -426,6 +555,16 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::new("multi-vm")
+                .long("multi-vm")
+                .help("Allow additional VMs to be created and managed from this VMM through the HTTP API")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .requires("api-socket")
+                .conflicts_with("landlock")
+                .group("vmm-config"),
+        )
         .arg(
             Arg::new("event-monitor")
                 .long("event-monitor")
@@ -444,9 +583,26 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             Arg::new("seccomp")
                 .long("seccomp")
                 .num_args(1)
-                .value_parser(["true", "false", "log"])
+                .value_parser(["true", "false", "log", "audit"])
                 .default_value("true"),
         )
+        .arg(
+            Arg::new("rlimit")
+                .long("rlimit")
+                .help(
+                    "Resource limits of the VMM process \
+                     \"nofile=<max_open_files>,memlock=<max_locked_memory_size>\"",
+                )
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("rtc")
+                .long("rtc")
+                .help(config::RtcConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("tpm")
                 .long("tpm")
@@ -473,6 +629,15 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             .group("vm-config"),
     );
 
+    #[cfg(target_arch = "x86_64")]
+    let app = app.arg(
+        Arg::new("fw-cfg")
+            .long("fw-cfg")
+            .help(config::FwCfgConfig::SYNTAX)
+            .num_args(1..)
+            .group("vm-config"),
+    );
+
     #[cfg(feature = "guest_debug")]
     let app = app.arg(
         Arg::new("gdb")
@@ -542,6 +707,41 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
     )
 }
 
+// Sets both the soft and hard limits, as any later change would otherwise
+// require privileges the VMM process may not have.
+fn set_rlimits(rlimit: &str) -> Result<(), Error> {
+    let mut parser = OptionParser::new();
+    parser.add("nofile").add("memlock");
+    parser.parse(rlimit).map_err(Error::ParsingRlimit)?;
+
+    let set_rlimit = |resource, limit: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        // SAFETY: FFI call with a valid rlimit structure
+        if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+            return Err(Error::SetRlimit(io::Error::last_os_error()));
+        }
+        Ok(())
+    };
+
+    if let Some(nofile) = parser
+        .convert::<u64>("nofile")
+        .map_err(Error::ParsingRlimit)?
+    {
+        set_rlimit(libc::RLIMIT_NOFILE, nofile)?;
+    }
+    if let Some(memlock) = parser
+        .convert::<ByteSized>("memlock")
+        .map_err(Error::ParsingRlimit)?
+    {
+        set_rlimit(libc::RLIMIT_MEMLOCK, memlock.0)?;
+    }
+
+    Ok(())
+}
+
 fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     let log_level = match cmd_arguments.get_count("v") {
         0 => LevelFilter::Warn,
@@ -599,6 +799,9 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
             "true" => SeccompAction::Trap,
             "false" => SeccompAction::Allow,
             "log" => SeccompAction::Log,
+            // Only the VMM thread reports its denials, the other threads
+            // have theirs logged by the kernel.
+            "audit" => SeccompAction::Log,
             val => {
                 // The user providing an invalid value will be rejected
                 panic!("Invalid parameter {val} for \"--seccomp\" flag");
@@ -645,6 +848,38 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         }
     }
 
+    if let Some(rlimit) = cmd_arguments.get_one::<String>("rlimit") {
+        set_rlimits(rlimit)?;
+    }
+
+    #[cfg(feature = "igvm")]
+    let payload_present = cmd_arguments.contains_id("kernel")
+        || cmd_arguments.contains_id("firmware")
+        || cmd_arguments.contains_id("igvm");
+    #[cfg(not(feature = "igvm"))]
+    let payload_present =
+        cmd_arguments.contains_id("kernel") || cmd_arguments.contains_id("firmware");
+
+    let vm_config = if payload_present || cmd_arguments.contains_id("config") {
+        let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+        Some(config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?)
+    } else {
+        None
+    };
+
+    // The launcher is forked while the process is still single threaded, and
+    // before any seccomp filter or Landlock rule is applied.
+    vmm::device_backend::start_launcher(vmm::device_backend::launcher_binaries(vm_config.as_ref()))
+        .map_err(Error::StartBackendLauncher)?;
+
+    // The audit thread must not be subject to any seccomp filter.
+    let seccomp_audit_enable = cmd_arguments
+        .get_one::<String>("seccomp")
+        .is_some_and(|seccomp_value| seccomp_value == "audit");
+    if seccomp_audit_enable {
+        vmm::seccomp_audit::start().map_err(Error::StartSeccompAudit)?;
+    }
+
     let hypervisor = hypervisor::new().map_err(Error::CreateHypervisor)?;
 
     #[cfg(feature = "guest_debug")]
@@ -746,6 +981,13 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
 
     event!("vmm", "starting");
 
+    // The VMM thread only allows what XDP network devices need from the
+    // kernel when the VM it is started with has some.
+    let xdp = vm_config
+        .as_ref()
+        .and_then(|config| config.net.as_ref())
+        .is_some_and(|net| net.iter().any(|net| net.xdp.is_some()));
+
     let vmm_thread_handle = vmm::start_vmm_thread(
         vmm::VmmVersionInfo::new(env!("BUILD_VERSION"), env!("CARGO_PKG_VERSION")),
         &api_socket_path,
@@ -763,24 +1005,16 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         vm_debug_evt.try_clone().unwrap(),
         exit_evt.try_clone().unwrap(),
         &seccomp_action,
+        seccomp_audit_enable,
         hypervisor,
         landlock_enable,
+        cmd_arguments.get_flag("multi-vm"),
+        xdp,
     )
     .map_err(Error::StartVmmThread)?;
 
     let r: Result<(), Error> = (|| {
-        #[cfg(feature = "igvm")]
-        let payload_present = cmd_arguments.contains_id("kernel")
-            || cmd_arguments.contains_id("firmware")
-            || cmd_arguments.contains_id("igvm");
-        #[cfg(not(feature = "igvm"))]
-        let payload_present =
-            cmd_arguments.contains_id("kernel") || cmd_arguments.contains_id("firmware");
-
-        if payload_present {
-            let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
-            let vm_config = config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?;
-
+        if let Some(vm_config) = vm_config {
             // Create and boot the VM based off the VM config we just built.
             let sender = api_request_sender.clone();
             vmm::api::VmCreate
@@ -961,11 +1195,12 @@ mod unit_tests {
     use crate::{create_app, prepare_default_values};
     use std::path::PathBuf;
     use vmm::config::{
-        ConsoleConfig, ConsoleOutputMode, CpuFeatures, CpusConfig, MemoryConfig, PayloadConfig,
-        RngConfig, VmConfig, VmParams,
+        ConsoleConfig, ConsoleOutputMode, CpuAffinityFallback, CpuFeatures, CpusConfig,
+        MemoryConfig, PayloadConfig, RngConfig, VmConfig, VmParams,
     };
     #[cfg(target_arch = "x86_64")]
     use vmm::vm_config::DebugConsoleConfig;
+    use vmm_sys_util::tempdir::TempDir;
 
     fn get_vm_config_from_vec(args: &[&str]) -> VmConfig {
         let (default_vcpus, default_memory, default_rng) = prepare_default_values();
@@ -1010,6 +1245,7 @@ mod unit_tests {
                 kvm_hyperv: false,
                 max_phys_bits: 46,
                 affinity: None,
+                affinity_fallback: CpuAffinityFallback::default(),
                 features: CpuFeatures::default(),
             },
             memory: MemoryConfig {
@@ -1018,12 +1254,16 @@ mod unit_tests {
                 hotplug_method: HotplugMethod::Acpi,
                 hotplug_size: None,
                 hotplugged_size: None,
+                boot_size: None,
                 shared: false,
                 hugepages: false,
                 hugepage_size: None,
                 prefault: false,
+                mlock: false,
                 zones: None,
                 thp: true,
+                dirty_ring: false,
+                guest_memfd: false,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -1036,6 +1276,7 @@ mod unit_tests {
                 host_data: None,
             }),
             rate_limit_groups: None,
+            io_affinities: None,
             disks: None,
             net: None,
             rng: RngConfig {
@@ -1044,38 +1285,60 @@ mod unit_tests {
             },
             balloon: None,
             fs: None,
+            fs9p: None,
+            i2c: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             },
             #[cfg(target_arch = "x86_64")]
             debug_console: DebugConsoleConfig::default(),
             devices: None,
             user_devices: None,
             vdpa: None,
+            ivshmem: None,
+            nvme: None,
+            cxl: None,
+            xhci: None,
+            usb_devices: None,
+            vnc: None,
             vsock: None,
+            guest_agent: None,
             pvpanic: false,
+            vmclock: false,
+            panic: None,
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
             numa: None,
+            numa_placement: false,
+            numa_placed: None,
             watchdog: false,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             pci_segments: None,
             platform: None,
             tpm: None,
+            rtc: None,
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg: None,
+            cgroup: None,
+            security_label: None,
             preserved_fds: None,
             landlock_enable: false,
             landlock_rules: None,
@@ -1961,6 +2224,132 @@ mod unit_tests {
         });
     }
 
+    #[test]
+    fn test_valid_vm_config_ivshmem() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--ivshmem",
+                "path=/dev/shm/ivshmem0,size=1M",
+                "path=/dev/shm/ivshmem1,id=shm1",
+            ],
+            r#"{
+                "payload": {"kernel": "/path/to/kernel"},
+                "ivshmem": [
+                    {"path": "/dev/shm/ivshmem0", "size": 1048576},
+                    {"path": "/dev/shm/ivshmem1", "id": "shm1"}
+                ]
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_nvme() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--nvme",
+                "id=nvme0,num_queues=2",
+                "--disk",
+                "path=/path/to/disk/1,nvme=nvme0",
+                "path=/path/to/disk/2,nvme=nvme0",
+            ],
+            r#"{
+                "payload": {"kernel": "/path/to/kernel"},
+                "nvme": [{"id": "nvme0", "num_queues": 2}],
+                "disks": [
+                    {"path": "/path/to/disk/1", "nvme": "nvme0"},
+                    {"path": "/path/to/disk/2", "nvme": "nvme0"}
+                ]
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_cxl() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--cxl",
+                "memory_zone=mem1,pci_segment=1",
+                "id=cxl1,memory_zone=mem2,pci_segment=2",
+            ],
+            r#"{
+                "payload": {"kernel": "/path/to/kernel"},
+                "cxl": [
+                    {"memory_zone": "mem1", "pci_segment": 1},
+                    {"id": "cxl1", "memory_zone": "mem2", "pci_segment": 2}
+                ]
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_usb() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--xhci",
+                    "num_ports=2",
+                    "--usb",
+                    "hostbus=1,hostaddr=4",
+                    "vendor_id=0x0483,product_id=df11,id=dfu0",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "xhci": {"num_ports": 2},
+                    "usb_devices": [
+                        {"hostbus": 1, "hostaddr": 4},
+                        {"vendor_id": 1155, "product_id": 57105, "id": "dfu0"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--xhci",
+                    "num_ports=4",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "xhci": {}
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
     #[test]
     fn test_valid_vm_config_vsock() {
         [
@@ -2065,4 +2454,94 @@ mod unit_tests {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
         });
     }
+
+    #[test]
+    fn test_valid_vm_config_file() {
+        let tmp_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let toml_file = tmp_dir.as_path().join("vm.toml");
+        std::fs::write(
+            &toml_file,
+            r#"
+                [cpus]
+                boot_vcpus = 2
+                max_vcpus = 2
+
+                [memory]
+                size = 1073741824
+
+                [payload]
+                kernel = "/path/to/kernel"
+                cmdline = "console=hvc0"
+
+                [[disks]]
+                path = "/path/to/disk"
+            "#,
+        )
+        .unwrap();
+        let json_file = tmp_dir.as_path().join("vm.json");
+        std::fs::write(
+            &json_file,
+            r#"{
+                "cpus": {"boot_vcpus": 2, "max_vcpus": 2},
+                "memory": {"size": 1073741824},
+                "payload": {"kernel": "/path/to/kernel", "cmdline": "console=hvc0"},
+                "disks": [{"path": "/path/to/disk"}]
+            }"#,
+        )
+        .unwrap();
+
+        for file in [&toml_file, &json_file] {
+            let file = file.to_str().unwrap();
+            [
+                (
+                    vec!["cloud-hypervisor", "--config", file],
+                    r#"{
+                        "cpus": {"boot_vcpus": 2, "max_vcpus": 2},
+                        "memory": {"size": 1073741824},
+                        "payload": {"kernel": "/path/to/kernel", "cmdline": "console=hvc0"},
+                        "disks": [{"path": "/path/to/disk"}]
+                    }"#,
+                    true,
+                ),
+                (
+                    vec![
+                        "cloud-hypervisor",
+                        "--config",
+                        file,
+                        "--memory",
+                        "size=2G",
+                        "--cmdline",
+                        "console=ttyS0",
+                    ],
+                    r#"{
+                        "cpus": {"boot_vcpus": 2, "max_vcpus": 2},
+                        "memory": {"size": 2147483648},
+                        "payload": {"kernel": "/path/to/kernel", "cmdline": "console=ttyS0"},
+                        "disks": [{"path": "/path/to/disk"}]
+                    }"#,
+                    true,
+                ),
+                (
+                    vec![
+                        "cloud-hypervisor",
+                        "--config",
+                        file,
+                        "--disk",
+                        "path=/path/to/other/disk",
+                    ],
+                    r#"{
+                        "cpus": {"boot_vcpus": 2, "max_vcpus": 2},
+                        "memory": {"size": 1073741824},
+                        "payload": {"kernel": "/path/to/kernel", "cmdline": "console=hvc0"},
+                        "disks": [{"path": "/path/to/other/disk"}]
+                    }"#,
+                    true,
+                ),
+            ]
+            .iter()
+            .for_each(|(cli, openapi, equal)| {
+                compare_vm_config_cli_vs_json(cli, openapi, *equal);
+            });
+        }
+    }
 }
@@ -0,0 +1,265 @@
+#!/usr/bin/env python3
+#
+# Copyright © 2024 Microsoft Corporation
+#
+# SPDX-License-Identifier: Apache-2.0
+#
+# Creates luks2.img, the LUKS2 image the block crate unit tests unlock with
+# the "cloud-hypervisor" passphrase, with the data segment holding the 16 KiB
+# generated by plaintext() below which the tests compare the decrypted
+# contents with. When cryptsetup is available the header is created with:
+#
+#   cryptsetup luksFormat --type luks2 --cipher aes-xts-plain64 \
+#       --key-size 256 --pbkdf argon2id --pbkdf-memory 32 \
+#       --pbkdf-force-iterations 4 --pbkdf-parallel 1 \
+#       --luks2-metadata-size 12k --luks2-keyslots-size 128k \
+#       --offset 320 luks2.img
+#
+# and the data segment is encrypted with the volume key it dumps, which
+# doesn't require privileges. Otherwise the header is written by this script,
+# following the on-disk format and the layout cryptsetup produces, from key
+# material derived from a fixed seed so the image can be recreated
+# identically. The tool used and its version are printed, and must be
+# recorded in the test reading the image. The cryptography module is used for
+# AES-XTS and Argon2id, independently from the Rust implementation.
+#
+# The current luks2.img was written by this script with Python 3.11.7 and
+# cryptography 48.0.0, cryptsetup not being available when it was generated.
+
+import base64
+import hashlib
+import json
+import random
+import re
+import shutil
+import struct
+import subprocess
+import sys
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.argon2 import Argon2id
+
+PASSPHRASE = b"cloud-hypervisor"
+KEY_SIZE = 32
+STRIPES = 4000
+SECTOR_SIZE = 512
+
+HEADER_SIZE = 0x4000
+KEYSLOTS_OFFSET = 2 * HEADER_SIZE
+KEYSLOTS_SIZE = 0x20000
+DATA_OFFSET = KEYSLOTS_OFFSET + KEYSLOTS_SIZE
+DATA_SIZE = 0x4000
+
+ARGON2_TIME = 4
+ARGON2_MEMORY = 32
+DIGEST_ITERATIONS = 1000
+
+
+def plaintext():
+    return bytes((i % 251) ^ ((i // SECTOR_SIZE) & 0xFF) for i in range(DATA_SIZE))
+
+
+def xts(key, data, encrypt=True):
+    out = bytearray()
+    for sector, i in enumerate(range(0, len(data), SECTOR_SIZE)):
+        # plain64: the little endian sector number is the tweak.
+        cipher = Cipher(algorithms.AES(key), modes.XTS(struct.pack("<QQ", sector, 0)))
+        ctx = cipher.encryptor() if encrypt else cipher.decryptor()
+        out += ctx.update(data[i : i + SECTOR_SIZE]) + ctx.finalize()
+    return bytes(out)
+
+
+def diffuse(buf):
+    digest_size = hashlib.sha256().digest_size
+    out = bytearray()
+    for i in range(0, len(buf), digest_size):
+        block = buf[i : i + digest_size]
+        h = hashlib.sha256(struct.pack(">I", i // digest_size) + block).digest()
+        out += h[: len(block)]
+    return bytes(out)
+
+
+def af_split(key, rng):
+    material = bytearray()
+    block = bytes(len(key))
+    for _ in range(STRIPES - 1):
+        stripe = rng.randbytes(len(key))
+        material += stripe
+        block = diffuse(bytes(a ^ b for a, b in zip(block, stripe)))
+    material += bytes(a ^ b for a, b in zip(block, key))
+    return bytes(material)
+
+
+def b64(data):
+    return base64.b64encode(data).decode()
+
+
+def header(metadata, magic, offset, rng_salt, uuid):
+    hdr = bytearray(HEADER_SIZE)
+    struct.pack_into(">6sHQQ", hdr, 0, magic, 2, HEADER_SIZE, 1)
+    struct.pack_into("32s", hdr, 72, b"sha256")
+    struct.pack_into("64s", hdr, 104, rng_salt)
+    struct.pack_into("40s", hdr, 168, uuid)
+    struct.pack_into(">Q", hdr, 256, offset)
+    hdr[4096 : 4096 + len(metadata)] = metadata
+    struct.pack_into("32s", hdr, 448, hashlib.sha256(hdr).digest())
+    return bytes(hdr)
+
+
+def run(*args, stdin=None):
+    return subprocess.run(
+        args, input=stdin, capture_output=True, check=True
+    ).stdout.decode()
+
+
+def luks_format(path):
+    version = run("cryptsetup", "--version").strip()
+    with open(path, "wb") as f:
+        f.truncate(DATA_OFFSET + DATA_SIZE)
+    run(
+        "cryptsetup",
+        "luksFormat",
+        "--batch-mode",
+        "--type",
+        "luks2",
+        "--cipher",
+        "aes-xts-plain64",
+        "--key-size",
+        str(KEY_SIZE * 8),
+        "--pbkdf",
+        "argon2id",
+        "--pbkdf-memory",
+        str(ARGON2_MEMORY),
+        "--pbkdf-force-iterations",
+        str(ARGON2_TIME),
+        "--pbkdf-parallel",
+        "1",
+        "--luks2-metadata-size",
+        "12k",
+        "--luks2-keyslots-size",
+        "128k",
+        "--offset",
+        str(DATA_OFFSET // SECTOR_SIZE),
+        "--key-file",
+        "-",
+        path,
+        stdin=PASSPHRASE,
+    )
+    dump = run(
+        "cryptsetup",
+        "luksDump",
+        "--batch-mode",
+        "--dump-volume-key",
+        "--key-file",
+        "-",
+        path,
+        stdin=PASSPHRASE,
+    )
+    # The key is dumped as hexadecimal bytes spread over several lines,
+    # following a label depending on the cryptsetup version.
+    key = re.search(r"(?:MK dump|Volume key):\s*((?:[0-9a-f]{2}\s+)+)", dump)
+    volume_key = bytes.fromhex("".join(key.group(1).split()))
+    assert len(volume_key) == KEY_SIZE
+    assert len(volume_key) == KEY_SIZE
+
+    with open(path, "r+b") as f:
+        f.seek(DATA_OFFSET)
+        f.write(xts(volume_key, plaintext()))
+    print("Created %s with %s" % (path, version))
+
+
+def main(path):
+    if shutil.which("cryptsetup"):
+        luks_format(path)
+        return
+
+    rng = random.Random(965)
+    volume_key = rng.randbytes(KEY_SIZE)
+    kdf_salt = rng.randbytes(32)
+    digest_salt = rng.randbytes(32)
+    material = af_split(volume_key, rng)
+
+    area_key = Argon2id(
+        salt=kdf_salt,
+        length=KEY_SIZE,
+        iterations=ARGON2_TIME,
+        lanes=1,
+        memory_cost=ARGON2_MEMORY,
+    ).derive(PASSPHRASE)
+    padded = material + bytes(-len(material) % SECTOR_SIZE)
+    area = xts(area_key, padded)
+    assert len(area) <= KEYSLOTS_SIZE
+
+    digest = hashlib.pbkdf2_hmac("sha256", volume_key, digest_salt, DIGEST_ITERATIONS)
+
+    metadata = {
+        "keyslots": {
+            "0": {
+                "type": "luks2",
+                "key_size": KEY_SIZE,
+                "af": {"type": "luks1", "stripes": STRIPES, "hash": "sha256"},
+                "area": {
+                    "type": "raw",
+                    "offset": str(KEYSLOTS_OFFSET),
+                    "size": str(KEYSLOTS_SIZE),
+                    "encryption": "aes-xts-plain64",
+                    "key_size": KEY_SIZE,
+                },
+                "kdf": {
+                    "type": "argon2id",
+                    "time": ARGON2_TIME,
+                    "memory": ARGON2_MEMORY,
+                    "cpus": 1,
+                    "salt": b64(kdf_salt),
+                },
+            }
+        },
+        "tokens": {},
+        "segments": {
+            "0": {
+                "type": "crypt",
+                "offset": str(DATA_OFFSET),
+                "size": "dynamic",
+                "iv_tweak": "0",
+                "encryption": "aes-xts-plain64",
+                "sector_size": SECTOR_SIZE,
+            }
+        },
+        "digests": {
+            "0": {
+                "type": "pbkdf2",
+                "keyslots": ["0"],
+                "segments": ["0"],
+                "hash": "sha256",
+                "iterations": DIGEST_ITERATIONS,
+                "salt": b64(digest_salt),
+                "digest": b64(digest),
+            }
+        },
+        "config": {
+            "json_size": str(HEADER_SIZE - 4096),
+            "keyslots_size": str(KEYSLOTS_SIZE),
+        },
+    }
+    metadata = json.dumps(metadata, separators=(",", ":")).encode()
+
+    uuid = b"a2a0b0f8-3c5d-4c62-9a4e-6b8f1e2d9c65"
+    image = bytearray(DATA_OFFSET)
+    image[:HEADER_SIZE] = header(metadata, b"LUKS\xba\xbe", 0, rng.randbytes(64), uuid)
+    image[HEADER_SIZE:KEYSLOTS_OFFSET] = header(
+        metadata, b"SKUL\xba\xbe", HEADER_SIZE, rng.randbytes(64), uuid
+    )
+    image[KEYSLOTS_OFFSET : KEYSLOTS_OFFSET + len(area)] = area
+    image += xts(volume_key, plaintext())
+
+    # Check the image round trips before writing it.
+    assert xts(volume_key, image[DATA_OFFSET:], encrypt=False) == plaintext()
+
+    with open(path, "wb") as f:
+        f.write(image)
+
+    print("Created %s with Python %s" % (path, sys.version.split()[0]))
+
+
+if __name__ == "__main__":
+    main(sys.argv[1] if len(sys.argv) > 1 else "luks2.img")
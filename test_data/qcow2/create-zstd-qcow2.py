@@ -0,0 +1,204 @@
+#!/usr/bin/env python3
+#
+# Copyright © 2024 Microsoft Corporation
+#
+# SPDX-License-Identifier: Apache-2.0
+#
+# Creates zstd.qcow2, the zstd compressed qcow2 image read by the block crate
+# unit tests, holding the guest data generated by guest_data() below which the
+# tests compare the image contents with. When qemu-img is available the image
+# is created with:
+#
+#   qemu-img convert -c -f raw -O qcow2 \
+#       -o compression_type=zstd,cluster_size=4096 zstd.raw zstd.qcow2
+#
+# and otherwise written by this script, laid out as qemu-img lays it out, which
+# requires the zstd command. The tool used and its version are printed, and
+# must be recorded in the test reading the image.
+#
+# The current zstd.qcow2 was written by this script with zstd v1.5.7, qemu-img
+# not being available when it was generated.
+
+import os
+import shutil
+import struct
+import subprocess
+import sys
+import tempfile
+
+CLUSTER_BITS = 12
+CLUSTER_SIZE = 1 << CLUSTER_BITS
+GUEST_CLUSTERS = 16
+SECTOR_SIZE = 512
+
+COPIED = 1 << 63
+COMPRESSED = 1 << 62
+CSIZE_SHIFT = 62 - (CLUSTER_BITS - 8)
+INCOMPATIBLE_COMPRESSION_TYPE = 1 << 3
+COMPRESSION_TYPE_ZSTD = 1
+HEADER_LENGTH = 112
+
+# Host clusters: header, refcount table, refcount block, L1 table, L2 table.
+REFCOUNT_TABLE = 1 * CLUSTER_SIZE
+REFCOUNT_BLOCK = 2 * CLUSTER_SIZE
+L1_TABLE = 3 * CLUSTER_SIZE
+L2_TABLE = 4 * CLUSTER_SIZE
+FIRST_DATA_CLUSTER = 5
+
+
+def guest_cluster(i):
+    if i % 4 == 1:
+        # Zero clusters are left unallocated.
+        return bytes(CLUSTER_SIZE)
+    if i == 0:
+        # xorshift32 output doesn't compress, it is stored uncompressed.
+        state = 0x1234_5678
+        data = bytearray()
+        for _ in range(CLUSTER_SIZE):
+            state ^= (state << 13) & 0xFFFF_FFFF
+            state ^= state >> 17
+            state ^= (state << 5) & 0xFFFF_FFFF
+            data.append(state & 0xFF)
+        return bytes(data)
+    pattern = b"cluster %02d " % i
+    return (pattern * (CLUSTER_SIZE // len(pattern) + 1))[:CLUSTER_SIZE]
+
+
+def guest_data():
+    return b"".join(guest_cluster(i) for i in range(GUEST_CLUSTERS))
+
+
+def zstd_compress(data):
+    # qemu uses the default level and no checksum.
+    return subprocess.run(
+        ["zstd", "-q", "-c", "--no-check"], input=data, capture_output=True, check=True
+    ).stdout
+
+
+def header():
+    h = struct.pack(
+        ">4sIQIIQIIQQIIQQQQII",
+        b"QFI\xfb",
+        3,  # version
+        0,  # backing file offset
+        0,  # backing file size
+        CLUSTER_BITS,
+        GUEST_CLUSTERS * CLUSTER_SIZE,  # size
+        0,  # crypt method
+        1,  # L1 size
+        L1_TABLE,
+        REFCOUNT_TABLE,
+        1,  # refcount table clusters
+        0,  # nb snapshots
+        0,  # snapshots offset
+        INCOMPATIBLE_COMPRESSION_TYPE,
+        0,  # compatible features
+        0,  # autoclear features
+        4,  # refcount order
+        HEADER_LENGTH,
+    )
+    h += struct.pack(">B7x", COMPRESSION_TYPE_ZSTD)
+    assert len(h) == HEADER_LENGTH
+
+    # Feature name table, as written by qemu.
+    names = [
+        (0, 0, b"dirty bit"),
+        (0, 1, b"corrupt bit"),
+        (0, 2, b"external data file"),
+        (0, 3, b"compression type"),
+        (0, 4, b"extended L2 entries"),
+        (1, 0, b"lazy refcounts"),
+        (2, 0, b"bitmaps"),
+        (2, 1, b"raw external data"),
+    ]
+    table = b"".join(struct.pack(">BB46s", t, b, n) for t, b, n in names)
+    h += struct.pack(">II", 0x6803F857, len(table)) + table
+    # End of the header extension area.
+    h += struct.pack(">II", 0, 0)
+    return h
+
+
+def convert(path):
+    version = subprocess.run(
+        ["qemu-img", "--version"], capture_output=True, check=True, text=True
+    ).stdout.splitlines()[0]
+    with tempfile.TemporaryDirectory() as tmp:
+        raw = os.path.join(tmp, "zstd.raw")
+        with open(raw, "wb") as f:
+            f.write(guest_data())
+        subprocess.run(
+            [
+                "qemu-img",
+                "convert",
+                "-c",
+                "-f",
+                "raw",
+                "-O",
+                "qcow2",
+                "-o",
+                "compression_type=zstd,cluster_size=%d" % CLUSTER_SIZE,
+                raw,
+                path,
+            ],
+            check=True,
+        )
+    print("Created %s with %s" % (path, version))
+
+
+def main(path):
+    if shutil.which("qemu-img"):
+        convert(path)
+        return
+
+    image = bytearray(FIRST_DATA_CLUSTER * CLUSTER_SIZE)
+    refcounts = [1] * FIRST_DATA_CLUSTER
+    l2 = [0] * (CLUSTER_SIZE // 8)
+
+    next_cluster = FIRST_DATA_CLUSTER
+    # Compressed clusters are packed back to back, qemu only moves on to a new
+    # host cluster when the data doesn't fit in the current one.
+    free_byte = 0
+    for i in range(GUEST_CLUSTERS):
+        data = guest_cluster(i)
+        if not any(data):
+            continue
+        compressed = zstd_compress(data)
+        if len(compressed) >= CLUSTER_SIZE:
+            offset = next_cluster * CLUSTER_SIZE
+            next_cluster += 1
+            refcounts.append(1)
+            image[len(image) :] = bytes(offset + CLUSTER_SIZE - len(image))
+            image[offset : offset + CLUSTER_SIZE] = data
+            l2[i] = COPIED | offset
+            continue
+
+        if free_byte == 0 or free_byte % CLUSTER_SIZE + len(compressed) > CLUSTER_SIZE:
+            free_byte = next_cluster * CLUSTER_SIZE
+            next_cluster += 1
+            refcounts.append(0)
+        refcounts[free_byte // CLUSTER_SIZE] += 1
+        image[len(image) :] = bytes(max(0, free_byte + len(compressed) - len(image)))
+        image[free_byte : free_byte + len(compressed)] = compressed
+        sectors = (free_byte + len(compressed) - 1) // SECTOR_SIZE - free_byte // SECTOR_SIZE
+        l2[i] = COMPRESSED | (sectors << CSIZE_SHIFT) | free_byte
+        free_byte += len(compressed)
+
+    h = header()
+    image[: len(h)] = h
+    image[REFCOUNT_TABLE : REFCOUNT_TABLE + 8] = struct.pack(">Q", REFCOUNT_BLOCK)
+    rb = struct.pack(">%dH" % len(refcounts), *refcounts)
+    image[REFCOUNT_BLOCK : REFCOUNT_BLOCK + len(rb)] = rb
+    image[L1_TABLE : L1_TABLE + 8] = struct.pack(">Q", COPIED | L2_TABLE)
+    image[L2_TABLE : L2_TABLE + CLUSTER_SIZE] = struct.pack(">%dQ" % len(l2), *l2)
+
+    with open(path, "wb") as f:
+        f.write(image)
+
+    version = subprocess.run(
+        ["zstd", "-V"], capture_output=True, check=True, text=True
+    ).stdout.strip()
+    print("Created %s with %s" % (path, version))
+
+
+if __name__ == "__main__":
+    main(sys.argv[1] if len(sys.argv) > 1 else "zstd.qcow2")
@@ -5849,6 +5849,34 @@ mod common_parallel {
                 0
             );
 
+            // Add the device back, reusing the guest address range it was
+            // given the first time, then remove it again
+            assert!(remote_command(
+                &api_socket,
+                "add-pmem",
+                Some(&format!(
+                    "file={},id=test0{}",
+                    pmem_temp_file.as_path().to_str().unwrap(),
+                    if let Some(pci_segment) = pci_segment {
+                        format!(",pci_segment={pci_segment}")
+                    } else {
+                        "".to_owned()
+                    }
+                )),
+            ));
+            thread::sleep(std::time::Duration::new(10, 0));
+            assert_eq!(
+                guest
+                    .ssh_command("lsblk | grep pmem0 | grep -c 128M")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+            assert!(remote_command(&api_socket, "remove-device", Some("test0")));
+            thread::sleep(std::time::Duration::new(20, 0));
+
             guest.reboot_linux(1, None);
 
             // Check still absent after reboot
@@ -100,6 +100,7 @@ impl VhostUserNetThread {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 access_platform: None,
+                xsk: None,
             },
         })
     }
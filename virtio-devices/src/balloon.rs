@@ -15,31 +15,36 @@
 // limitations under the License.
 
 use crate::{
-    seccomp_filters::Thread, thread_helper::spawn_virtio_thread, ActivateResult, EpollHelper,
-    EpollHelperError, EpollHelperHandler, GuestMemoryMmap, VirtioCommon, VirtioDevice,
+    seccomp_filters::Thread, thread_helper::spawn_virtio_thread, ActivateError, ActivateResult,
+    EpollHelper, EpollHelperError, EpollHelperHandler, GuestMemoryMmap, VirtioCommon, VirtioDevice,
     VirtioDeviceType, VirtioInterrupt, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
     VIRTIO_F_VERSION_1,
 };
 use anyhow::anyhow;
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Write};
 use std::mem::size_of;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::{atomic::AtomicBool, Arc, Barrier};
+use std::sync::{atomic::AtomicBool, Arc, Barrier, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 use virtio_queue::{Queue, QueueT};
-use vm_allocator::page_size::{align_page_size_down, get_page_size};
+use vm_allocator::page_size::get_page_size;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryRegion,
 };
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 const QUEUE_SIZE: u16 = 128;
 const REPORTING_QUEUE_SIZE: u16 = 32;
+const STATS_QUEUE_SIZE: u16 = 1;
 const MIN_NUM_QUEUES: usize = 2;
 
 // Inflate virtio queue event.
@@ -48,10 +53,16 @@ const INFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const DEFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // Reporting virtio queue event.
 const REPORTING_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// Statistics virtio queue event.
+const STATS_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// Balloon policy timer event.
+const POLICY_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 
 // Size of a PFN in the balloon interface.
 const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
 
+// Enable an additional virtqueue to let the guest report memory statistics.
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1;
 // Deflate balloon on OOM
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
 // Enable an additional virtqueue to let the guest notify the host about free
@@ -70,6 +81,8 @@ pub enum Error {
     FallocateFail(std::io::Error),
     #[error("Madvise fail.: {0}")]
     MadviseFail(std::io::Error),
+    #[error("Fstatfs fail.: {0}")]
+    Fstatfs(std::io::Error),
     #[error("Failed to EventFd write.: {0}")]
     EventFdWriteFail(std::io::Error),
     #[error("Invalid queue index: {0}")]
@@ -94,48 +107,111 @@ pub struct VirtioBalloonConfig {
     actual: u32,
 }
 
+// Memory statistic tags, from include/uapi/linux/virtio_balloon.h
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioBalloonStat {
+    tag: u16,
+    val: u64,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioBalloonStat {}
+
+/// Policy automatically adjusting the balloon size from the memory
+/// statistics reported by the guest.
+#[derive(Clone, Copy, Debug)]
+pub struct BalloonPolicy {
+    /// Guest RAM the balloon never goes below.
+    pub min_guest_ram: u64,
+    /// Guest RAM the balloon never goes above.
+    pub max_guest_ram: u64,
+    /// Memory kept available to the guest on top of what it uses.
+    pub free_reserve: u64,
+    /// Minimum change of the balloon size worth requesting to the guest.
+    pub hysteresis: u64,
+    /// Interval at which the guest statistics are requested.
+    pub interval: Duration,
+}
+
+// Page backing the guest memory, larger than a balloon PFN, for which only
+// some of the PFNs have been ballooned so far.
 #[derive(Clone, Debug)]
 struct PartiallyBalloonedPage {
-    addr: u64,
     bitmap: Vec<u64>,
     page_size: u64,
 }
 
 impl PartiallyBalloonedPage {
-    fn new() -> Self {
-        let page_size = get_page_size();
+    fn new(page_size: u64) -> Self {
         let len = ((page_size >> VIRTIO_BALLOON_PFN_SHIFT) + 63) / 64;
         // Initial each padding bit as 1 in bitmap.
         let mut bitmap = vec![0_u64; len as usize];
         let pad_num = len * 64 - (page_size >> VIRTIO_BALLOON_PFN_SHIFT);
-        bitmap[(len - 1) as usize] = !((1 << (64 - pad_num)) - 1);
-        Self {
-            addr: 0,
-            bitmap,
-            page_size,
+        if pad_num != 0 {
+            bitmap[(len - 1) as usize] = !((1 << (64 - pad_num)) - 1);
         }
-    }
-
-    fn pfn_match(&self, addr: u64) -> bool {
-        self.addr == addr & !(self.page_size - 1)
+        Self { bitmap, page_size }
     }
 
     fn bitmap_full(&self) -> bool {
         self.bitmap.iter().all(|b| *b == u64::MAX)
     }
 
+    fn bitmap_empty(&self) -> bool {
+        Self::new(self.page_size).bitmap == self.bitmap
+    }
+
     fn set_bit(&mut self, addr: u64) {
         let addr_offset = (addr % self.page_size) >> VIRTIO_BALLOON_PFN_SHIFT;
         self.bitmap[(addr_offset / 64) as usize] |= 1 << (addr_offset % 64);
     }
 
-    fn reset(&mut self) {
-        let len = ((self.page_size >> VIRTIO_BALLOON_PFN_SHIFT) + 63) / 64;
-        self.addr = 0;
-        self.bitmap = vec![0; len as usize];
-        let pad_num = len * 64 - (self.page_size >> VIRTIO_BALLOON_PFN_SHIFT);
-        self.bitmap[(len - 1) as usize] = !((1 << (64 - pad_num)) - 1);
+    fn clear_bit(&mut self, addr: u64) {
+        let addr_offset = (addr % self.page_size) >> VIRTIO_BALLOON_PFN_SHIFT;
+        self.bitmap[(addr_offset / 64) as usize] &= !(1 << (addr_offset % 64));
+    }
+}
+
+// See include/uapi/linux/magic.h in the kernel code.
+const HUGETLBFS_MAGIC: u64 = 0x9584_58f6;
+
+// Size of the pages backing the guest memory region containing `addr`, which
+// is the granularity at which its memory can be given back to the host.
+// Hugetlbfs memory can't be released by smaller chunks than its huge pages.
+fn backing_page_size(
+    page_sizes: &mut HashMap<u64, u64>,
+    memory: &GuestMemoryMmap,
+    addr: GuestAddress,
+) -> result::Result<u64, Error> {
+    let region = memory.find_region(addr).ok_or(Error::GuestMemory(
+        GuestMemoryError::InvalidGuestAddress(addr),
+    ))?;
+    if let Some(page_size) = page_sizes.get(&region.start_addr().0) {
+        return Ok(*page_size);
     }
+
+    let mut page_size = get_page_size();
+    if let Some(f_off) = region.file_offset() {
+        let mut buf = std::mem::MaybeUninit::<libc::statfs>::uninit();
+        // SAFETY: FFI call with a valid fd and buffer, and we check the
+        // return value.
+        let ret = unsafe { libc::fstatfs(f_off.file().as_raw_fd(), buf.as_mut_ptr()) };
+        if ret != 0 {
+            return Err(Error::Fstatfs(io::Error::last_os_error()));
+        }
+        // SAFETY: `buf` was initialized by the successful call above.
+        let buf = unsafe { buf.assume_init() };
+        if buf.f_type as u64 == HUGETLBFS_MAGIC {
+            page_size = buf.f_bsize as u64;
+        }
+    }
+    page_sizes.insert(region.start_addr().0, page_size);
+
+    Ok(page_size)
 }
 
 const CONFIG_ACTUAL_OFFSET: u64 = 4;
@@ -151,9 +227,20 @@ struct BalloonEpollHandler {
     inflate_queue_evt: EventFd,
     deflate_queue_evt: EventFd,
     reporting_queue_evt: Option<EventFd>,
+    stats_queue_evt: Option<EventFd>,
+    reporting_queue_index: usize,
+    stats_queue_index: usize,
     kill_evt: EventFd,
     pause_evt: EventFd,
-    pbp: Option<PartiallyBalloonedPage>,
+    config: Arc<Mutex<VirtioBalloonConfig>>,
+    policy: Option<BalloonPolicy>,
+    policy_timer: Option<TimerFd>,
+    // Head of the statistics buffer held until fresh statistics are needed.
+    stats_desc_index: Option<u16>,
+    // Partially ballooned pages, indexed by their guest address.
+    pbps: BTreeMap<u64, PartiallyBalloonedPage>,
+    // Size of the pages backing each guest memory region.
+    page_sizes: HashMap<u64, u64>,
 }
 
 impl BalloonEpollHandler {
@@ -210,41 +297,51 @@ impl BalloonEpollHandler {
         Self::advise_memory_range(memory, range_base, range_len, libc::MADV_DONTNEED)
     }
 
+    // Release the memory of a page only once all the PFNs it contains have
+    // been ballooned, coalescing them into whole host or huge pages.
     fn release_memory_range_4k(
-        pbp: &mut Option<PartiallyBalloonedPage>,
+        pbps: &mut BTreeMap<u64, PartiallyBalloonedPage>,
+        page_sizes: &mut HashMap<u64, u64>,
         memory: &GuestMemoryMmap,
         pfn: u32,
     ) -> result::Result<(), Error> {
         let range_base = GuestAddress((pfn as u64) << VIRTIO_BALLOON_PFN_SHIFT);
         let range_len = 1 << VIRTIO_BALLOON_PFN_SHIFT;
 
-        let page_size: u64 = get_page_size();
+        let page_size = backing_page_size(page_sizes, memory, range_base)?;
         if page_size == 1 << VIRTIO_BALLOON_PFN_SHIFT {
             return Self::release_memory_range(memory, range_base, range_len);
         }
 
-        if pbp.is_none() {
-            *pbp = Some(PartiallyBalloonedPage::new());
+        let page_addr = range_base.0 & !(page_size - 1);
+        let pbp = pbps
+            .entry(page_addr)
+            .or_insert_with(|| PartiallyBalloonedPage::new(page_size));
+        pbp.set_bit(range_base.0);
+        if pbp.bitmap_full() {
+            pbps.remove(&page_addr);
+            Self::release_memory_range(memory, GuestAddress(page_addr), page_size as usize)?;
         }
 
-        if !pbp.as_ref().unwrap().pfn_match(range_base.0) {
-            // We are trying to free memory region in a different pfn with current pbp. Flush pbp.
-            pbp.as_mut().unwrap().reset();
-            pbp.as_mut().unwrap().addr = align_page_size_down(range_base.0);
-        }
-
-        pbp.as_mut().unwrap().set_bit(range_base.0);
-        if pbp.as_ref().unwrap().bitmap_full() {
-            Self::release_memory_range(
-                memory,
-                vm_memory::GuestAddress(pbp.as_ref().unwrap().addr),
-                page_size as usize,
-            )?;
+        Ok(())
+    }
 
-            pbp.as_mut().unwrap().reset();
+    // Release the whole pages contained in a range reported as free, which
+    // might not be aligned on the size of the pages backing it.
+    fn release_reported_range(
+        page_sizes: &mut HashMap<u64, u64>,
+        memory: &GuestMemoryMmap,
+        range_base: GuestAddress,
+        range_len: usize,
+    ) -> result::Result<(), Error> {
+        let page_size = backing_page_size(page_sizes, memory, range_base)?;
+        let start = (range_base.0 + page_size - 1) & !(page_size - 1);
+        let end = (range_base.0 + range_len as u64) & !(page_size - 1);
+        if start >= end {
+            return Ok(());
         }
 
-        Ok(())
+        Self::release_memory_range(memory, GuestAddress(start), (end - start) as usize)
     }
 
     fn process_queue(&mut self, queue_index: usize) -> result::Result<(), Error> {
@@ -277,16 +374,35 @@ impl BalloonEpollHandler {
 
                 match queue_index {
                     0 => {
-                        Self::release_memory_range_4k(&mut self.pbp, desc_chain.memory(), pfn)?;
+                        Self::release_memory_range_4k(
+                            &mut self.pbps,
+                            &mut self.page_sizes,
+                            desc_chain.memory(),
+                            pfn,
+                        )?;
                     }
                     1 => {
-                        let page_size = get_page_size() as usize;
-                        let rbase = align_page_size_down((pfn as u64) << VIRTIO_BALLOON_PFN_SHIFT);
+                        let addr = (pfn as u64) << VIRTIO_BALLOON_PFN_SHIFT;
+                        let page_size = backing_page_size(
+                            &mut self.page_sizes,
+                            desc_chain.memory(),
+                            GuestAddress(addr),
+                        )?;
+                        let rbase = addr & !(page_size - 1);
+
+                        // The page is back in use by the guest, so it can't
+                        // be released anymore.
+                        if let Some(pbp) = self.pbps.get_mut(&rbase) {
+                            pbp.clear_bit(addr);
+                            if pbp.bitmap_empty() {
+                                self.pbps.remove(&rbase);
+                            }
+                        }
 
                         Self::advise_memory_range(
                             desc_chain.memory(),
                             vm_memory::GuestAddress(rbase),
-                            page_size,
+                            page_size as usize,
                             libc::MADV_WILLNEED,
                         )?;
                     }
@@ -315,7 +431,12 @@ impl BalloonEpollHandler {
             let mut descs_len = 0;
             while let Some(desc) = desc_chain.next() {
                 descs_len += desc.len();
-                Self::release_memory_range(desc_chain.memory(), desc.addr(), desc.len() as usize)?;
+                Self::release_reported_range(
+                    &mut self.page_sizes,
+                    desc_chain.memory(),
+                    desc.addr(),
+                    desc.len() as usize,
+                )?;
             }
 
             self.queues[queue_index]
@@ -331,6 +452,93 @@ impl BalloonEpollHandler {
         }
     }
 
+    fn process_stats_queue(&mut self) -> result::Result<(), Error> {
+        let queue_index = self.stats_queue_index;
+        while let Some(mut desc_chain) =
+            self.queues[queue_index].pop_descriptor_chain(self.mem.memory())
+        {
+            // The driver only gives a new buffer back once the previous one
+            // has been used, but don't leak it if that's not the case.
+            if let Some(head_index) = self.stats_desc_index.take() {
+                self.queues[queue_index]
+                    .add_used(desc_chain.memory(), head_index, 0)
+                    .map_err(Error::QueueAddUsed)?;
+            }
+
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+            if desc.is_write_only() {
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
+
+            let mut avail = None;
+            let mut free = None;
+            let stat_size = size_of::<VirtioBalloonStat>() as u64;
+            let mut offset = 0u64;
+            while offset + stat_size <= desc.len() as u64 {
+                let stat: VirtioBalloonStat = desc_chain
+                    .memory()
+                    .read_obj(desc.addr().unchecked_add(offset))
+                    .map_err(Error::GuestMemory)?;
+                match u16::from_le(stat.tag) {
+                    VIRTIO_BALLOON_S_AVAIL => avail = Some(u64::from_le(stat.val)),
+                    VIRTIO_BALLOON_S_MEMFREE => free = Some(u64::from_le(stat.val)),
+                    _ => {}
+                }
+                offset += stat_size;
+            }
+            self.stats_desc_index = Some(desc_chain.head_index());
+
+            if let Some(avail) = avail.or(free) {
+                self.apply_policy(avail)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Give the statistics buffer back to the driver, which fills it with
+    // fresh statistics before making it available again.
+    fn request_stats(&mut self) -> result::Result<(), Error> {
+        if let Some(head_index) = self.stats_desc_index.take() {
+            self.queues[self.stats_queue_index]
+                .add_used(self.mem.memory().deref(), head_index, 0)
+                .map_err(Error::QueueAddUsed)?;
+            self.signal(VirtioInterruptType::Queue(self.stats_queue_index as u16))?;
+        }
+
+        Ok(())
+    }
+
+    // Size the balloon so that the guest keeps the configured reserve of
+    // available memory on top of what it uses, within the policy bounds.
+    fn apply_policy(&mut self, avail: u64) -> result::Result<(), Error> {
+        let Some(policy) = self.policy else {
+            return Ok(());
+        };
+
+        let total: u64 = self.mem.memory().iter().map(|r| r.len()).sum();
+        let mut config = self.config.lock().unwrap();
+        let usable = total.saturating_sub((config.actual as u64) << VIRTIO_BALLOON_PFN_SHIFT);
+        let max = policy.max_guest_ram.min(total);
+        let min = policy.min_guest_ram.min(max);
+        let desired = (usable.saturating_sub(avail) + policy.free_reserve).clamp(min, max);
+
+        let size = (total - desired) & !((1 << VIRTIO_BALLOON_PFN_SHIFT) - 1);
+        let current = (config.num_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT;
+        if size.abs_diff(current) < policy.hysteresis {
+            return Ok(());
+        }
+
+        debug!(
+            "Balloon policy resizing the balloon from {} to {} bytes (available {})",
+            current, size, avail
+        );
+        config.num_pages = (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
+        drop(config);
+
+        self.signal(VirtioInterruptType::Config)
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -342,6 +550,12 @@ impl BalloonEpollHandler {
         if let Some(reporting_queue_evt) = self.reporting_queue_evt.as_ref() {
             helper.add_event(reporting_queue_evt.as_raw_fd(), REPORTING_QUEUE_EVENT)?;
         }
+        if let Some(stats_queue_evt) = self.stats_queue_evt.as_ref() {
+            helper.add_event(stats_queue_evt.as_raw_fd(), STATS_QUEUE_EVENT)?;
+        }
+        if let Some(policy_timer) = self.policy_timer.as_ref() {
+            helper.add_event(policy_timer.as_raw_fd(), POLICY_TIMER_EVENT)?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -392,18 +606,55 @@ impl EpollHelperHandler for BalloonEpollHandler {
                             e
                         ))
                     })?;
-                    self.process_reporting_queue(2).map_err(|e| {
+                    self.process_reporting_queue(self.reporting_queue_index)
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal used inflate queue: {:?}",
+                                e
+                            ))
+                        })?;
+                } else {
+                    return Err(EpollHelperError::HandleEvent(anyhow!(
+                        "Invalid reporting queue event as no eventfd registered"
+                    )));
+                }
+            }
+            STATS_QUEUE_EVENT => {
+                if let Some(stats_queue_evt) = self.stats_queue_evt.as_ref() {
+                    stats_queue_evt.read().map_err(|e| {
                         EpollHelperError::HandleEvent(anyhow!(
-                            "Failed to signal used inflate queue: {:?}",
+                            "Failed to get stats queue event: {:?}",
+                            e
+                        ))
+                    })?;
+                    self.process_stats_queue().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to process stats queue: {:?}",
                             e
                         ))
                     })?;
                 } else {
                     return Err(EpollHelperError::HandleEvent(anyhow!(
-                        "Invalid reporting queue event as no eventfd registered"
+                        "Invalid stats queue event as no eventfd registered"
                     )));
                 }
             }
+            POLICY_TIMER_EVENT => {
+                if let Some(policy_timer) = self.policy_timer.as_mut() {
+                    policy_timer.wait().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to get policy timer event: {:?}",
+                            e
+                        ))
+                    })?;
+                    self.request_stats().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to request balloon stats: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unknown event for virtio-balloon"
@@ -426,7 +677,8 @@ pub struct BalloonState {
 pub struct Balloon {
     common: VirtioCommon,
     id: String,
-    config: VirtioBalloonConfig,
+    config: Arc<Mutex<VirtioBalloonConfig>>,
+    policy: Option<BalloonPolicy>,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
@@ -439,6 +691,7 @@ impl Balloon {
         size: u64,
         deflate_on_oom: bool,
         free_page_reporting: bool,
+        policy: Option<BalloonPolicy>,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
         state: Option<BalloonState>,
@@ -461,6 +714,9 @@ impl Balloon {
             if free_page_reporting {
                 avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
             }
+            if policy.is_some() {
+                avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+            }
 
             let config = VirtioBalloonConfig {
                 num_pages: (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
@@ -470,6 +726,9 @@ impl Balloon {
             (avail_features, 0, config, false)
         };
 
+        if avail_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0 {
+            queue_sizes.push(STATS_QUEUE_SIZE);
+        }
         if free_page_reporting {
             queue_sizes.push(REPORTING_QUEUE_SIZE);
         }
@@ -486,7 +745,8 @@ impl Balloon {
                 ..Default::default()
             },
             id,
-            config,
+            config: Arc::new(Mutex::new(config)),
+            policy,
             seccomp_action,
             exit_evt,
             interrupt_cb: None,
@@ -494,7 +754,7 @@ impl Balloon {
     }
 
     pub fn resize(&mut self, size: u64) -> Result<(), Error> {
-        self.config.num_pages = (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
+        self.config.lock().unwrap().num_pages = (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
 
         if let Some(interrupt_cb) = &self.interrupt_cb {
             interrupt_cb
@@ -507,14 +767,14 @@ impl Balloon {
 
     // Get the actual size of the virtio-balloon.
     pub fn get_actual(&self) -> u64 {
-        (self.config.actual as u64) << VIRTIO_BALLOON_PFN_SHIFT
+        (self.config.lock().unwrap().actual as u64) << VIRTIO_BALLOON_PFN_SHIFT
     }
 
     fn state(&self) -> BalloonState {
         BalloonState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
-            config: self.config,
+            config: *self.config.lock().unwrap(),
         }
     }
 
@@ -552,7 +812,7 @@ impl VirtioDevice for Balloon {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        self.read_config_from_slice(self.config.lock().unwrap().as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
@@ -566,7 +826,8 @@ impl VirtioDevice for Balloon {
             return;
         }
 
-        let config = self.config.as_mut_slice();
+        let mut config = self.config.lock().unwrap();
+        let config = config.as_mut_slice();
         let config_len = config.len() as u64;
         let data_len = data.len() as u64;
         if offset + data_len > config_len {
@@ -603,6 +864,16 @@ impl VirtioDevice for Balloon {
         let (_, queue, queue_evt) = queues.remove(0);
         virtqueues.push(queue);
         let deflate_queue_evt = queue_evt;
+        let stats_queue_index = virtqueues.len();
+        let stats_queue_evt =
+            if self.common.feature_acked(VIRTIO_BALLOON_F_STATS_VQ) && !queues.is_empty() {
+                let (_, queue, queue_evt) = queues.remove(0);
+                virtqueues.push(queue);
+                Some(queue_evt)
+            } else {
+                None
+            };
+        let reporting_queue_index = virtqueues.len();
         let reporting_queue_evt =
             if self.common.feature_acked(VIRTIO_BALLOON_F_REPORTING) && !queues.is_empty() {
                 let (_, queue, queue_evt) = queues.remove(0);
@@ -612,6 +883,18 @@ impl VirtioDevice for Balloon {
                 None
             };
 
+        let policy_timer = match self.policy {
+            Some(policy) if stats_queue_evt.is_some() => {
+                let mut timer =
+                    TimerFd::new().map_err(|e| ActivateError::CreatePolicyTimer(e.into()))?;
+                timer
+                    .reset(policy.interval, Some(policy.interval))
+                    .map_err(|e| ActivateError::CreatePolicyTimer(e.into()))?;
+                Some(timer)
+            }
+            _ => None,
+        };
+
         self.interrupt_cb = Some(interrupt_cb.clone());
 
         let mut handler = BalloonEpollHandler {
@@ -621,9 +904,17 @@ impl VirtioDevice for Balloon {
             inflate_queue_evt,
             deflate_queue_evt,
             reporting_queue_evt,
+            stats_queue_evt,
+            reporting_queue_index,
+            stats_queue_index,
             kill_evt,
             pause_evt,
-            pbp: None,
+            config: self.config.clone(),
+            policy: self.policy,
+            policy_timer,
+            stats_desc_index: None,
+            pbps: BTreeMap::new(),
+            page_sizes: HashMap::new(),
         };
 
         let paused = self.common.paused.clone();
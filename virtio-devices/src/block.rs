@@ -13,8 +13,10 @@ use super::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
     VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
+use crate::completion_polling::CompletionPoller;
+use crate::interrupt_coalescing::InterruptCoalescer;
 use crate::seccomp_filters::Thread;
-use crate::thread_helper::spawn_virtio_thread;
+use crate::thread_helper::{set_thread_affinity, spawn_virtio_thread};
 use crate::GuestMemoryMmap;
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
@@ -27,6 +29,7 @@ use rate_limiter::TokenType;
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::io;
@@ -36,7 +39,8 @@ use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use virtio_bindings::virtio_blk::*;
 use virtio_bindings::virtio_config::*;
@@ -56,6 +60,8 @@ const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New 'wake up' event from the rate limiter
 const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// Deferred used buffer notification
+const COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
 // latency scale, for reduce precision loss in calculate.
 const LATENCY_SCALE: u64 = 10000;
@@ -133,10 +139,20 @@ struct BlockEpollHandler {
     counters: BlockCounters,
     queue_evt: EventFd,
     inflight_requests: VecDeque<(u16, Request)>,
+    // Position in the available ring of each in-flight request, indexed by
+    // its head descriptor, shared with the device for snapshotting.
+    inflight_positions: Arc<Mutex<BTreeMap<u16, u16>>>,
+    // Positions in the available ring of the requests completed before a
+    // restore, which must be skipped when processing the queue again.
+    completed_positions: BTreeSet<u16>,
     rate_limiter: Option<RateLimiterGroupHandle>,
     access_platform: Option<Arc<dyn AccessPlatform>>,
     read_only: bool,
     host_cpus: Option<Vec<usize>>,
+    busy_poll: Option<Duration>,
+    io_uring_event_loop: bool,
+    coalescer: Option<InterruptCoalescer>,
+    completion_poller: Option<CompletionPoller>,
 }
 
 impl BlockEpollHandler {
@@ -144,6 +160,11 @@ impl BlockEpollHandler {
         let queue = &mut self.queue;
 
         while let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            let avail_position = queue.next_avail().wrapping_sub(1);
+            if self.completed_positions.remove(&avail_position) {
+                continue;
+            }
+
             let mut request = Request::parse(&mut desc_chain, self.access_platform.as_ref())
                 .map_err(Error::RequestParsing)?;
 
@@ -213,6 +234,10 @@ impl BlockEpollHandler {
                 )
                 .map_err(Error::RequestExecuting)?
             {
+                self.inflight_positions
+                    .lock()
+                    .unwrap()
+                    .insert(desc_chain.head_index(), avail_position);
                 self.inflight_requests
                     .push_back((desc_chain.head_index(), request));
             } else {
@@ -246,9 +271,20 @@ impl BlockEpollHandler {
                 ))
             })?
         {
-            self.signal_used_queue().map_err(|e| {
-                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
-            })?;
+            let signal = match self.coalescer.as_mut() {
+                Some(coalescer) => coalescer.notify(self.queue.next_used()).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to coalesce used queue notification: {:?}",
+                        e
+                    ))
+                })?,
+                None => true,
+            };
+            if signal {
+                self.signal_used_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+                })?;
+            }
         }
 
         Ok(())
@@ -258,10 +294,40 @@ impl BlockEpollHandler {
         self.process_queue_submit().map_err(|e| {
             EpollHelperError::HandleEvent(anyhow!("Failed to process queue (submit): {:?}", e))
         })?;
+        self.poll_completions()?;
 
         self.try_signal_used_queue()
     }
 
+    // Look for completed requests for a short while rather than waiting for
+    // the completion notification, until at least one request completed.
+    fn poll_completions(&mut self) -> result::Result<(), EpollHelperError> {
+        let Some(duration) = self
+            .completion_poller
+            .as_ref()
+            .and_then(|poller| poller.poll_duration())
+        else {
+            return Ok(());
+        };
+
+        let start = Instant::now();
+        while !self.inflight_requests.is_empty() && start.elapsed() < duration {
+            let inflight = self.inflight_requests.len();
+            self.process_queue_complete().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!(
+                    "Failed to process queue (complete): {:?}",
+                    e
+                ))
+            })?;
+            if self.inflight_requests.len() != inflight {
+                break;
+            }
+            std::hint::spin_loop();
+        }
+
+        Ok(())
+    }
+
     #[inline]
     fn find_inflight_request(&mut self, completed_head: u16) -> Result<Request> {
         // This loop neatly handles the fast path where the completions are
@@ -276,6 +342,10 @@ impl BlockEpollHandler {
         // now be the new front.
         for (i, (head, _)) in self.inflight_requests.iter().enumerate() {
             if head == &completed_head {
+                self.inflight_positions
+                    .lock()
+                    .unwrap()
+                    .remove(&completed_head);
                 return Ok(self.inflight_requests.swap_remove_front(i).unwrap().1);
             }
         }
@@ -297,7 +367,11 @@ impl BlockEpollHandler {
 
             request.complete_async().map_err(Error::RequestCompleting)?;
 
-            let latency = request.start.elapsed().as_micros() as u64;
+            let elapsed = request.start.elapsed();
+            if let Some(poller) = self.completion_poller.as_mut() {
+                poller.record(elapsed);
+            }
+            let latency = elapsed.as_micros() as u64;
             let read_ops_last = self.counters.read_ops.load(Ordering::Relaxed);
             let write_ops_last = self.counters.write_ops.load(Ordering::Relaxed);
             let read_max = self.counters.read_latency_max.load(Ordering::Relaxed);
@@ -428,35 +502,12 @@ impl BlockEpollHandler {
     }
 
     fn set_queue_thread_affinity(&self) {
-        // Prepare the CPU set the current queue thread is expected to run onto.
-        let cpuset = self.host_cpus.as_ref().map(|host_cpus| {
-            // SAFETY: all zeros is a valid pattern
-            let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
-            // SAFETY: FFI call, trivially safe
-            unsafe { libc::CPU_ZERO(&mut cpuset) };
-            for host_cpu in host_cpus {
-                // SAFETY: FFI call, trivially safe
-                unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
-            }
-            cpuset
-        });
-
         // Schedule the thread to run on the expected CPU set
-        if let Some(cpuset) = cpuset.as_ref() {
-            // SAFETY: FFI call with correct arguments
-            let ret = unsafe {
-                libc::sched_setaffinity(
-                    0,
-                    std::mem::size_of::<libc::cpu_set_t>(),
-                    cpuset as *const libc::cpu_set_t,
-                )
-            };
-
-            if ret != 0 {
+        if let Some(host_cpus) = self.host_cpus.as_ref() {
+            if let Err(e) = set_thread_affinity(host_cpus) {
                 error!(
                     "Failed scheduling the virtqueue thread {} on the expected CPU set: {}",
-                    self.queue_index,
-                    io::Error::last_os_error()
+                    self.queue_index, e
                 )
             }
         }
@@ -468,11 +519,20 @@ impl BlockEpollHandler {
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        if self.io_uring_event_loop {
+            helper.use_io_uring();
+        }
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
         helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
         if let Some(rate_limiter) = &self.rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
         }
+        if let Some(coalescer) = &self.coalescer {
+            helper.add_event(coalescer.as_raw_fd(), COALESCING_EVENT)?;
+        }
+        if let Some(budget) = self.busy_poll {
+            helper.set_busy_poll(budget);
+        }
         self.set_queue_thread_affinity();
         helper.run(paused, paused_sync, self)?;
 
@@ -524,6 +584,7 @@ impl EpollHelperHandler for BlockEpollHandler {
                             e
                         ))
                     })?;
+                    self.poll_completions()?;
                 }
                 self.try_signal_used_queue()?;
             }
@@ -545,6 +606,27 @@ impl EpollHelperHandler for BlockEpollHandler {
                     )));
                 }
             }
+            COALESCING_EVENT => {
+                if let Some(coalescer) = &mut self.coalescer {
+                    if coalescer.expired().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to get coalescing timer event: {:?}",
+                            e
+                        ))
+                    })? {
+                        self.signal_used_queue().map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal used queue: {:?}",
+                                e
+                            ))
+                        })?;
+                    }
+                } else {
+                    return Err(EpollHelperError::HandleEvent(anyhow!(
+                        "Unexpected 'COALESCING_EVENT' when coalescing is not enabled."
+                    )));
+                }
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unexpected event: {}",
@@ -554,6 +636,36 @@ impl EpollHelperHandler for BlockEpollHandler {
         }
         Ok(())
     }
+
+    fn poll(&mut self, _helper: &mut EpollHelper) -> result::Result<bool, EpollHelperError> {
+        // Reap the completed requests without waiting for the completion
+        // notification.
+        let inflight = self.inflight_requests.len();
+        self.process_queue_complete().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process queue (complete): {:?}", e))
+        })?;
+        let completed = self.inflight_requests.len() != inflight;
+
+        // Submit the new requests without waiting for the queue notification.
+        let rate_limit_reached = self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked());
+        let submitted = !rate_limit_reached
+            && self
+                .queue
+                .avail_idx(self.mem.memory().deref(), Ordering::Acquire)
+                .map_err(EpollHelperError::QueueRingIndex)?
+                != Wrapping(self.queue.next_avail());
+        if submitted {
+            self.process_queue_submit().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to process queue (submit): {:?}", e))
+            })?;
+        }
+
+        if completed || submitted {
+            self.try_signal_used_queue()?;
+        }
+
+        Ok(completed || submitted)
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
@@ -572,6 +684,12 @@ pub struct Block {
     read_only: bool,
     serial: Vec<u8>,
     queue_affinity: BTreeMap<u16, Vec<usize>>,
+    busy_poll: Option<Duration>,
+    coalescing: Option<Duration>,
+    completion_poll: Option<Duration>,
+    io_uring_event_loop: bool,
+    inflight_positions: Vec<Arc<Mutex<BTreeMap<u16, u16>>>>,
+    restored_inflight: Option<Vec<Vec<u16>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -581,6 +699,10 @@ pub struct BlockState {
     pub avail_features: u64,
     pub acked_features: u64,
     pub config: VirtioBlockConfig,
+    /// Positions in the available ring of the requests submitted to the
+    /// backend but not completed yet, for each queue.
+    #[serde(default)]
+    pub inflight: Vec<Vec<u16>>,
 }
 
 impl Block {
@@ -600,8 +722,12 @@ impl Block {
         exit_evt: EventFd,
         state: Option<BlockState>,
         queue_affinity: BTreeMap<u16, Vec<usize>>,
+        busy_poll: Option<Duration>,
+        coalescing: Option<Duration>,
+        completion_poll: Option<Duration>,
+        io_uring_event_loop: bool,
     ) -> io::Result<Self> {
-        let (disk_nsectors, avail_features, acked_features, config, paused) =
+        let (disk_nsectors, avail_features, acked_features, config, restored_inflight, paused) =
             if let Some(state) = state {
                 info!("Restoring virtio-block {}", id);
                 (
@@ -609,6 +735,7 @@ impl Block {
                     state.avail_features,
                     state.acked_features,
                     state.config,
+                    Some(state.inflight),
                     true,
                 )
             } else {
@@ -674,7 +801,7 @@ impl Block {
                     config.num_queues = num_queues as u16;
                 }
 
-                (disk_nsectors, avail_features, 0, config, false)
+                (disk_nsectors, avail_features, 0, config, None, false)
             };
 
         let serial = serial
@@ -705,6 +832,12 @@ impl Block {
             read_only,
             serial,
             queue_affinity,
+            busy_poll,
+            coalescing,
+            completion_poll,
+            io_uring_event_loop,
+            inflight_positions: Vec::new(),
+            restored_inflight,
         })
     }
 
@@ -715,7 +848,49 @@ impl Block {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
             config: self.config,
+            inflight: self
+                .inflight_positions
+                .iter()
+                .map(|positions| positions.lock().unwrap().values().copied().collect())
+                .collect(),
+        }
+    }
+
+    // Rewind the queue to the oldest request which was in-flight when the
+    // snapshot was taken, so that the in-flight requests are submitted again
+    // while the ones completed in the meantime are skipped. Returns the
+    // positions in the available ring of the latter.
+    //
+    // Only the last queue size entries of the available ring can be read
+    // back, the older ones having possibly been overwritten by the driver,
+    // so the requests outside of this window can't be submitted again.
+    fn rewind_inflight(queue: &mut Queue, inflight: &[u16]) -> BTreeSet<u16> {
+        let next_avail = queue.next_avail();
+        let window = queue.size();
+        let (inflight, lost): (Vec<u16>, Vec<u16>) = inflight
+            .iter()
+            .partition(|position| (1..=window).contains(&next_avail.wrapping_sub(**position)));
+        if !lost.is_empty() {
+            warn!(
+                "Dropping {} in-flight requests outside of the available ring: {:?}",
+                lost.len(),
+                lost
+            );
         }
+
+        let Some(oldest) = inflight
+            .iter()
+            .copied()
+            .max_by_key(|position| next_avail.wrapping_sub(*position))
+        else {
+            return BTreeSet::new();
+        };
+
+        queue.set_next_avail(oldest);
+        (0..next_avail.wrapping_sub(oldest))
+            .map(|i| oldest.wrapping_add(i))
+            .filter(|position| !inflight.contains(position))
+            .collect()
     }
 
     fn update_writeback(&mut self) {
@@ -805,26 +980,60 @@ impl VirtioDevice for Block {
 
         let mut epoll_threads = Vec::new();
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
+        let restored_inflight = self.restored_inflight.take().unwrap_or_default();
+        self.inflight_positions.clear();
 
         for i in 0..queues.len() {
             let (_, mut queue, queue_evt) = queues.remove(0);
             queue.set_event_idx(event_idx);
 
+            let inflight = restored_inflight.get(i).cloned().unwrap_or_default();
+            let completed_positions = Self::rewind_inflight(&mut queue, &inflight);
+            if !inflight.is_empty() {
+                info!(
+                    "Resubmitting {} in-flight requests on queue {}",
+                    inflight.len(),
+                    i
+                );
+                // Process the queue as soon as the device runs.
+                queue_evt.write(1).map_err(|e| {
+                    error!("failed to kick queue: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            }
+            let inflight_positions = Arc::new(Mutex::new(BTreeMap::new()));
+            self.inflight_positions.push(inflight_positions.clone());
+
             let queue_size = queue.size();
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
             let queue_idx = i as u16;
 
+            let disk_image = self
+                .disk_image
+                .new_async_io(queue_size as u32)
+                .map_err(|e| {
+                    error!("failed to create new AsyncIo: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            let completion_poller = match self.completion_poll {
+                Some(duration) if disk_image.pollable_completions() => {
+                    Some(CompletionPoller::new(duration))
+                }
+                Some(_) => {
+                    warn!(
+                        "Completion polling not supported by the backend of {}",
+                        self.id
+                    );
+                    None
+                }
+                None => None,
+            };
+
             let mut handler = BlockEpollHandler {
                 queue_index: queue_idx,
                 queue,
                 mem: mem.clone(),
-                disk_image: self
-                    .disk_image
-                    .new_async_io(queue_size as u32)
-                    .map_err(|e| {
-                        error!("failed to create new AsyncIo: {}", e);
-                        ActivateError::BadActivate
-                    })?,
+                disk_image,
                 disk_nsectors: self.disk_nsectors,
                 interrupt_cb: interrupt_cb.clone(),
                 serial: self.serial.clone(),
@@ -837,6 +1046,8 @@ impl VirtioDevice for Block {
                 // This gives head room for systems with slower I/O without
                 // compromising the cost of the reallocation or memory overhead
                 inflight_requests: VecDeque::with_capacity(64),
+                inflight_positions,
+                completed_positions,
                 rate_limiter: self
                     .rate_limiter
                     .as_ref()
@@ -846,6 +1057,14 @@ impl VirtioDevice for Block {
                 access_platform: self.common.access_platform.clone(),
                 read_only: self.read_only,
                 host_cpus: self.queue_affinity.get(&queue_idx).cloned(),
+                busy_poll: self.busy_poll,
+                io_uring_event_loop: self.io_uring_event_loop,
+                coalescer: self
+                    .coalescing
+                    .map(|max_delay| InterruptCoalescer::new(Some(max_delay), None))
+                    .transpose()
+                    .map_err(ActivateError::CreateInterruptCoalescer)?,
+                completion_poller,
             };
 
             let paused = self.common.paused.clone();
@@ -946,3 +1165,46 @@ impl Snapshottable for Block {
 }
 impl Transportable for Block {}
 impl Migratable for Block {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewind(next_avail: u16, inflight: &[u16]) -> (u16, Vec<u16>) {
+        let mut queue = Queue::new(8).unwrap();
+        queue.set_next_avail(next_avail);
+        let completed = Block::rewind_inflight(&mut queue, inflight);
+        (queue.next_avail(), completed.into_iter().collect())
+    }
+
+    #[test]
+    fn test_rewind_inflight() {
+        // Nothing in flight.
+        assert_eq!(rewind(5, &[]), (5, vec![]));
+        // Requests completed after the oldest in-flight one are skipped.
+        assert_eq!(rewind(5, &[1, 3]), (1, vec![2, 4]));
+        assert_eq!(rewind(5, &[4]), (4, vec![]));
+        // Up to the whole available ring can be rewound.
+        assert_eq!(rewind(8, &[0, 7]), (0, vec![1, 2, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn test_rewind_inflight_wrap() {
+        // Positions wrapping around.
+        assert_eq!(
+            rewind(2, &[u16::MAX - 1, 1]),
+            (u16::MAX - 1, vec![0, u16::MAX])
+        );
+        assert_eq!(rewind(0, &[u16::MAX]), (u16::MAX, vec![]));
+    }
+
+    #[test]
+    fn test_rewind_inflight_outside_ring() {
+        // Position 1 has been overwritten by position 9.
+        assert_eq!(rewind(10, &[1, 5]), (5, vec![6, 7, 8, 9]));
+        // Same across the wrap, and with a position not yet available.
+        assert_eq!(rewind(3, &[u16::MAX - 5, 0, 3]), (0, vec![1, 2]));
+        // Every in-flight request is lost.
+        assert_eq!(rewind(100, &[10, 20]), (100, vec![]));
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Hybrid polling of the request completions.
+//!
+//! Once requests have been submitted to a backend which can report their
+//! completion without any system call, such as io_uring, the queue thread can
+//! poll for their completion for a short while instead of going back to sleep
+//! until the completion notification, saving the wakeup latency. Polling
+//! adapts to the backend: it only happens as long as the requests are expected
+//! to complete within the poll duration, based on a moving average of their
+//! completion latency, so that no CPU time gets wasted on slower backends.
+
+use std::time::Duration;
+
+// Weight of the latest completion latency in the moving average, as a shift.
+const LATENCY_WEIGHT_SHIFT: u32 = 3;
+
+pub(crate) struct CompletionPoller {
+    duration: Duration,
+    // Exponentially weighted moving average of the completion latency.
+    latency: Duration,
+}
+
+impl CompletionPoller {
+    pub(crate) fn new(duration: Duration) -> Self {
+        CompletionPoller {
+            duration,
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// Accounts for the latency of a completed request.
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let weight = 1 << LATENCY_WEIGHT_SHIFT;
+        self.latency = (self.latency * (weight - 1) + latency) / weight;
+    }
+
+    /// Returns for how long to poll for completions, if at all.
+    pub(crate) fn poll_duration(&self) -> Option<Duration> {
+        (self.latency <= self.duration).then_some(self.duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_polling() {
+        let mut poller = CompletionPoller::new(Duration::from_micros(20));
+
+        // Polling until some completion latency is known.
+        assert_eq!(poller.poll_duration(), Some(Duration::from_micros(20)));
+
+        // A slow backend stops the polling.
+        for _ in 0..8 {
+            poller.record(Duration::from_micros(200));
+        }
+        assert_eq!(poller.poll_duration(), None);
+
+        // Which resumes once the backend gets faster again.
+        for _ in 0..32 {
+            poller.record(Duration::from_micros(5));
+        }
+        assert_eq!(poller.poll_duration(), Some(Duration::from_micros(20)));
+    }
+}
@@ -556,14 +556,12 @@ pub struct ConsoleResizer {
 
 impl ConsoleResizer {
     pub fn update_console_size(&self) {
-        if let Some(tty) = self.tty.as_ref() {
-            let (cols, rows) = get_win_size(tty);
+        // Keep the last known size if the endpoint isn't a terminal, or if
+        // no size has been set on it yet, as it is the case for a PTY no
+        // client has attached to.
+        if let Some((cols, rows)) = self.tty.as_ref().and_then(get_win_size) {
             self.config.lock().unwrap().update_console_size(cols, rows);
-            if self
-                .acked_features
-                .fetch_and(1u64 << VIRTIO_CONSOLE_F_SIZE, Ordering::AcqRel)
-                != 0
-            {
+            if self.acked_features.load(Ordering::Acquire) & (1u64 << VIRTIO_CONSOLE_F_SIZE) != 0 {
                 // Send the interrupt to the driver
                 let _ = self.config_evt.write(1);
             }
@@ -599,7 +597,7 @@ pub struct ConsoleState {
     in_buffer: Vec<u8>,
 }
 
-fn get_win_size(tty: &dyn AsRawFd) -> (u16, u16) {
+fn get_win_size(tty: &File) -> Option<(u16, u16)> {
     #[repr(C)]
     #[derive(Default)]
     struct WindowSize {
@@ -611,11 +609,15 @@ fn get_win_size(tty: &dyn AsRawFd) -> (u16, u16) {
     let mut ws: WindowSize = WindowSize::default();
 
     // SAFETY: FFI call with correct arguments
-    unsafe {
-        libc::ioctl(tty.as_raw_fd(), TIOCGWINSZ, &mut ws);
+    if unsafe { libc::ioctl(tty.as_raw_fd(), TIOCGWINSZ, &mut ws) } < 0 {
+        return None;
     }
 
-    (ws.cols, ws.rows)
+    if ws.cols == 0 || ws.rows == 0 {
+        return None;
+    }
+
+    Some((ws.cols, ws.rows))
 }
 
 impl Console {
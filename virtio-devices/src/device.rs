@@ -311,6 +311,13 @@ impl VirtioCommon {
 
 impl Pausable for VirtioCommon {
     fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+        // The device might already be paused, for instance because its
+        // driver placed it in D3hot, in which case the threads won't
+        // acknowledge the pause again.
+        if self.paused.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
         info!(
             "Pausing virtio-{}",
             VirtioDeviceType::from(self.device_type)
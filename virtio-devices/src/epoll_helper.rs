@@ -8,17 +8,25 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+#[cfg(feature = "io_uring")]
+use crate::io_uring_reactor::IoUringReactor;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 
 pub struct EpollHelper {
     pause_evt: EventFd,
     epoll_file: File,
+    // Polls the file descriptors registered from the moment io_uring got
+    // enabled, along with the epoll file descriptor itself.
+    #[cfg(feature = "io_uring")]
+    reactor: Option<IoUringReactor>,
+    busy_poll: Option<Duration>,
 }
 
 #[derive(Error, Debug)]
@@ -41,8 +49,15 @@ pub enum EpollHelperError {
 
 pub const EPOLL_HELPER_EVENT_PAUSE: u16 = 0;
 pub const EPOLL_HELPER_EVENT_KILL: u16 = 1;
+// Events pending on the epoll file descriptor, when waiting through io_uring
+#[cfg(feature = "io_uring")]
+const EPOLL_HELPER_EVENT_EPOLL: u16 = 2;
 pub const EPOLL_HELPER_EVENT_LAST: u16 = 15;
 
+// Maximum busy polling time after handling events, in busy poll budgets
+#[cfg(not(fuzzing))]
+const BUSY_POLL_MAX_BUDGETS: u32 = 10;
+
 pub trait EpollHelperHandler {
     // Handle one event at a time. The EpollHelper iterates over a list of
     // events that have been returned by epoll_wait(). For each event, the
@@ -77,6 +92,16 @@ pub trait EpollHelperHandler {
     ) -> Result<(), EpollHelperError> {
         Ok(())
     }
+
+    // This method is only invoked if busy polling has been enabled on the
+    // EpollHelper. After the events have been handled, it is repeatedly
+    // invoked for the duration of the poll budget, letting the implementation
+    // look for work, such as new available descriptors, without waiting to be
+    // notified. It returns whether some work has been found. By default, it
+    // provides a no-op implementation.
+    fn poll(&mut self, _helper: &mut EpollHelper) -> Result<bool, EpollHelperError> {
+        Ok(false)
+    }
 }
 
 impl EpollHelper {
@@ -93,6 +118,9 @@ impl EpollHelper {
         let mut helper = Self {
             pause_evt: pause_evt.try_clone().unwrap(),
             epoll_file,
+            #[cfg(feature = "io_uring")]
+            reactor: None,
+            busy_poll: None,
         };
 
         helper.add_event(kill_evt.as_raw_fd(), EPOLL_HELPER_EVENT_KILL)?;
@@ -100,16 +128,119 @@ impl EpollHelper {
         Ok(helper)
     }
 
+    /// Wait for the events of the file descriptors registered from now on
+    /// through io_uring rather than epoll, saving system calls on each
+    /// wakeup. Edge-triggered registrations, and the ones made directly on
+    /// the epoll file descriptor, keep on going through epoll. Falls back to
+    /// epoll if the host lacks the required io_uring support.
+    #[cfg(feature = "io_uring")]
+    pub fn use_io_uring(&mut self) {
+        if self.reactor.is_some() {
+            return;
+        }
+
+        match IoUringReactor::new() {
+            Ok(mut reactor) => {
+                reactor.add(
+                    self.epoll_file.as_raw_fd(),
+                    EPOLL_HELPER_EVENT_EPOLL,
+                    epoll::Events::EPOLLIN,
+                );
+                self.reactor = Some(reactor);
+            }
+            Err(e) => warn!("Using epoll instead of io_uring: {}", e),
+        }
+    }
+
+    #[cfg(not(feature = "io_uring"))]
+    pub fn use_io_uring(&mut self) {
+        warn!("Using epoll instead of io_uring: not built with io_uring support");
+    }
+
+    /// Busy poll for the given duration after handling events, before
+    /// going back to sleep.
+    pub fn set_busy_poll(&mut self, budget: Duration) {
+        self.busy_poll = Some(budget);
+    }
+
+    #[cfg(not(fuzzing))]
+    fn busy_poll(
+        &mut self,
+        paused: &AtomicBool,
+        handler: &mut dyn EpollHelperHandler,
+    ) -> std::result::Result<(), EpollHelperError> {
+        let Some(budget) = self.busy_poll else {
+            return Ok(());
+        };
+
+        // The budget is renewed each time some work is found, unless some
+        // events are already pending, and the overall polling time is capped
+        // so that the thread goes back to waiting for events regularly.
+        // Polling also stops as soon as the device gets paused.
+        let start = std::time::Instant::now();
+        let max_end = start + budget * BUSY_POLL_MAX_BUDGETS;
+        let mut end = start + budget;
+        while std::time::Instant::now() < end.min(max_end) && !paused.load(Ordering::SeqCst) {
+            if handler.poll(self)? {
+                if self.events_pending() {
+                    break;
+                }
+                end = std::time::Instant::now() + budget;
+            } else {
+                std::hint::spin_loop();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Check whether some events are pending, without consuming them.
+    #[cfg(not(fuzzing))]
+    fn events_pending(&mut self) -> bool {
+        #[cfg(feature = "io_uring")]
+        if self
+            .reactor
+            .as_mut()
+            .is_some_and(|reactor| reactor.has_completions())
+        {
+            return true;
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: self.epoll_file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: FFI call with a valid pollfd and a zero timeout
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        ret > 0 && pollfd.revents & libc::POLLIN != 0
+    }
+
     pub fn add_event(&mut self, fd: RawFd, id: u16) -> std::result::Result<(), EpollHelperError> {
         self.add_event_custom(fd, id, epoll::Events::EPOLLIN)
     }
 
+    // Reactor handling the given registration, if any. Edge-triggered
+    // registrations are left to epoll, which is the only one supporting them.
+    #[cfg(feature = "io_uring")]
+    fn reactor_for(&mut self, evts: epoll::Events) -> Option<&mut IoUringReactor> {
+        self.reactor
+            .as_mut()
+            .filter(|_| !evts.contains(epoll::Events::EPOLLET))
+    }
+
     pub fn add_event_custom(
         &mut self,
         fd: RawFd,
         id: u16,
         evts: epoll::Events,
     ) -> std::result::Result<(), EpollHelperError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(reactor) = self.reactor_for(evts) {
+            reactor.add(fd, id, evts);
+            return Ok(());
+        }
+
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
@@ -125,6 +256,12 @@ impl EpollHelper {
         id: u16,
         evts: epoll::Events,
     ) -> std::result::Result<(), EpollHelperError> {
+        #[cfg(feature = "io_uring")]
+        if self.reactor.as_ref().is_some_and(|r| r.contains(fd)) {
+            self.del_event_custom(fd, id, evts)?;
+            return self.add_event_custom(fd, id, evts);
+        }
+
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_MOD,
@@ -140,6 +277,11 @@ impl EpollHelper {
         id: u16,
         evts: epoll::Events,
     ) -> std::result::Result<(), EpollHelperError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(reactor) = self.reactor.as_mut().filter(|r| r.contains(fd)) {
+            return reactor.del(fd).map_err(EpollHelperError::Ctl);
+        }
+
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_DEL,
@@ -149,6 +291,26 @@ impl EpollHelper {
         .map_err(EpollHelperError::Ctl)
     }
 
+    fn wait(&mut self, timeout: i32, events: &mut [epoll::Event]) -> std::io::Result<usize> {
+        #[cfg(feature = "io_uring")]
+        if let Some(reactor) = self.reactor.as_mut() {
+            let mut count = reactor.wait(timeout, events)?;
+            // Replace the event of the epoll file descriptor with the
+            // events pending on it.
+            if let Some(index) = events[..count]
+                .iter()
+                .position(|e| e.data == EPOLL_HELPER_EVENT_EPOLL as u64)
+            {
+                count -= 1;
+                events.swap(index, count);
+                count += epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[count..])?;
+            }
+            return Ok(count);
+        }
+
+        epoll::wait(self.epoll_file.as_raw_fd(), timeout, events)
+    }
+
     pub fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -179,23 +341,22 @@ impl EpollHelper {
         }
 
         loop {
-            let num_events =
-                match epoll::wait(self.epoll_file.as_raw_fd(), timeout, &mut events[..]) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        if e.kind() == std::io::ErrorKind::Interrupted {
-                            // It's well defined from the epoll_wait() syscall
-                            // documentation that the epoll loop can be interrupted
-                            // before any of the requested events occurred or the
-                            // timeout expired. In both those cases, epoll_wait()
-                            // returns an error of type EINTR, but this should not
-                            // be considered as a regular error. Instead it is more
-                            // appropriate to retry, by calling into epoll_wait().
-                            continue;
-                        }
-                        return Err(EpollHelperError::Wait(e));
+            let num_events = match self.wait(timeout, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
                     }
-                };
+                    return Err(EpollHelperError::Wait(e));
+                }
+            };
 
             if num_events == 0 {
                 // This case happens when the timeout is reached before any of
@@ -240,6 +401,8 @@ impl EpollHelper {
                     }
                 }
             }
+
+            self.busy_poll(&paused, handler)?;
         }
     }
 
@@ -258,7 +421,7 @@ impl EpollHelper {
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
         loop {
-            let num_events = match epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[..]) {
+            let num_events = match self.wait(0, &mut events[..]) {
                 Ok(res) => res,
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::Interrupted {
@@ -0,0 +1,355 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! virtio-9p device, sharing a host directory with the guest through the
+//! 9P2000.L protocol served by the VMM itself.
+
+mod server;
+
+use self::server::Server;
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccompiler::SeccompAction;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier};
+use thiserror::Error;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+// The mount tag is exposed through the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Failed to read from guest memory: {0}")]
+    GuestMemoryRead(vm_memory::guest_memory::Error),
+    #[error("Failed to write to guest memory: {0}")]
+    GuestMemoryWrite(vm_memory::guest_memory::Error),
+    #[error("Reply of {0} bytes does not fit the descriptors")]
+    ReplyTooLarge(usize),
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+struct Fs9pEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queue: Queue,
+    server: Server,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl Fs9pEpollHandler {
+    fn process_queue(&mut self) -> result::Result<bool, Error> {
+        let queue = &mut self.queue;
+
+        let mut used_descs = false;
+        while let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            let descs: Vec<_> = desc_chain.by_ref().collect();
+            let mem = desc_chain.memory();
+
+            // The request is held by the readable descriptors, the reply
+            // goes to the writable ones following them.
+            let mut request = Vec::new();
+            for desc in descs.iter().filter(|d| !d.is_write_only()) {
+                let offset = request.len();
+                request.resize(offset + desc.len() as usize, 0);
+                mem.read_slice(
+                    &mut request[offset..],
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                )
+                .map_err(Error::GuestMemoryRead)?;
+            }
+
+            let reply = self.server.handle(&request);
+
+            let mut written = 0;
+            for desc in descs.iter().filter(|d| d.is_write_only()) {
+                if written == reply.len() {
+                    break;
+                }
+                let len = (desc.len() as usize).min(reply.len() - written);
+                mem.write_slice(
+                    &reply[written..written + len],
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), len),
+                )
+                .map_err(Error::GuestMemoryWrite)?;
+                written += len;
+            }
+            if written != reply.len() {
+                return Err(Error::ReplyTooLarge(reply.len()));
+            }
+
+            queue
+                .add_used(mem, desc_chain.head_index(), written as u32)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(0))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for Fs9pEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        match ev_type {
+            QUEUE_AVAIL_EVENT => {
+                self.queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process queue : {:?}", e))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to signal used queue: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+            }
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {}",
+                    ev_type
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Virtio device sharing a host directory with the guest through 9P.
+pub struct Fs9p {
+    common: VirtioCommon,
+    id: String,
+    // Exported directory, opened as O_PATH.
+    root: File,
+    msize: u32,
+    config: Vec<u8>,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+impl Fs9p {
+    /// Create a new virtio-9p device exporting the directory at `path`
+    /// under the mount tag `tag`, with messages of at most `msize` bytes.
+    pub fn new(
+        id: String,
+        tag: &str,
+        path: &Path,
+        msize: u32,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+    ) -> io::Result<Fs9p> {
+        let root = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(path)?;
+
+        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_9P_MOUNT_TAG);
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+        let mut config = (tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(tag.as_bytes());
+
+        Ok(Fs9p {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Fs9P as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                min_queues: 1,
+                ..Default::default()
+            },
+            id,
+            root,
+            msize,
+            config,
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl Drop for Fs9p {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for Fs9p {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(&self.config, offset, data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        // Every activation starts a new session, without any fid.
+        let root = self.root.try_clone().map_err(|e| {
+            error!("failed cloning exported directory: {}", e);
+            ActivateError::BadActivate
+        })?;
+
+        let (_, queue, queue_evt) = queues.remove(0);
+
+        let mut handler = Fs9pEpollHandler {
+            mem,
+            queue,
+            server: Server::new(root, self.msize),
+            interrupt_cb,
+            queue_evt,
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioFs9p,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for Fs9p {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Fs9p {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The fids refer to host files opened by the server, which cannot be
+    // carried over to another VMM.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "Can't snapshot a virtio-9p device"
+        )))
+    }
+}
+
+impl Transportable for Fs9p {}
+impl Migratable for Fs9p {}
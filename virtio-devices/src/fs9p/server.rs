@@ -0,0 +1,1197 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! 9P2000.L server exporting a host directory.
+//!
+//! Each fid refers to a file through its path relative to the exported
+//! directory. Paths are resolved one component at a time relatively to the
+//! exported directory, without following symbolic links, so that the guest
+//! cannot reach any file outside of it. The files are accessed with the
+//! credentials of the VMM process, the ownership requested by the guest being
+//! ignored when creating files.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+
+const P9_VERSION: &[u8] = b"9P2000.L";
+const P9_VERSION_UNKNOWN: &[u8] = b"unknown";
+
+// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+// Largest header of the Rread and Twrite messages, which the payload of the
+// I/O operations must leave room for.
+const IO_HEADER_SIZE: u32 = 24;
+// Smallest message size accepted from the driver, leaving room for the
+// largest message header along with some payload.
+const MIN_MSIZE: u32 = 4096;
+// Maximum number of names walked at once.
+const MAX_WALK_NAMES: u16 = 16;
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+// Open flags of the protocol, which are the x86 Linux ones.
+const P9_ACCMODE: u32 = 0o3;
+const P9_TRUNC: u32 = 0o1000;
+const P9_APPEND: u32 = 0o2000;
+const P9_DSYNC: u32 = 0o10000;
+const P9_DIRECTORY: u32 = 0o200000;
+const P9_SYNC: u32 = 0o4010000;
+
+const P9_LOCK_SUCCESS: u8 = 0;
+const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+const V9FS_MAGIC: u32 = 0x01021997;
+
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(einval());
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<OsString> {
+        let len = self.u16()? as usize;
+        Ok(OsString::from_vec(self.bytes(len)?.to_vec()))
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.u16(s.len() as u16);
+        self.buf.extend_from_slice(s);
+    }
+
+    fn qid(&mut self, qid: Qid) {
+        self.u8(qid.kind);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Qid {
+    kind: u8,
+    version: u32,
+    path: u64,
+}
+
+impl From<&libc::stat> for Qid {
+    fn from(st: &libc::stat) -> Self {
+        let kind = match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => QTDIR,
+            libc::S_IFLNK => QTSYMLINK,
+            _ => QTFILE,
+        };
+        Qid {
+            kind,
+            version: st.st_mtime as u32,
+            path: st.st_ino,
+        }
+    }
+}
+
+struct DirEntry {
+    ino: u64,
+    kind: u8,
+    name: Vec<u8>,
+}
+
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+    // Entries of the directory, as read when starting from offset 0.
+    dir_entries: Option<Vec<DirEntry>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Fid {
+            path,
+            file: None,
+            dir_entries: None,
+        }
+    }
+}
+
+fn openat(dir: &File, name: &CStr, flags: i32, mode: u32) -> io::Result<File> {
+    // SAFETY: FFI call with a valid file descriptor and a NUL-terminated
+    // string.
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd was just opened and is exclusively owned.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn fstat(file: &File) -> io::Result<libc::stat> {
+    let mut st = MaybeUninit::<libc::stat>::zeroed();
+    // SAFETY: FFI call with a valid file descriptor, an empty NUL-terminated
+    // string and a buffer large enough for the result.
+    check(unsafe {
+        libc::fstatat(
+            file.as_raw_fd(),
+            b"\0".as_ptr() as *const libc::c_char,
+            st.as_mut_ptr(),
+            libc::AT_EMPTY_PATH,
+        )
+    })?;
+    // SAFETY: initialized by the successful fstatat() call.
+    Ok(unsafe { st.assume_init() })
+}
+
+fn cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| einval())
+}
+
+// Name of a file to create, move or remove in a directory.
+fn child_name(name: &OsStr) -> io::Result<CString> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        return Err(einval());
+    }
+    cstring(name)
+}
+
+fn walk_path(path: &Path, name: &OsStr) -> io::Result<PathBuf> {
+    match name.as_bytes() {
+        b"." => Ok(path.to_path_buf()),
+        // Walking up from the exported directory leaves the guest in it.
+        b".." => Ok(path.parent().map(Path::to_path_buf).unwrap_or_default()),
+        _ => {
+            child_name(name)?;
+            Ok(path.join(name))
+        }
+    }
+}
+
+fn open_flags(flags: u32) -> i32 {
+    let mut host_flags = (flags & P9_ACCMODE) as i32;
+    for (flag, host_flag) in [
+        (P9_TRUNC, libc::O_TRUNC),
+        (P9_APPEND, libc::O_APPEND),
+        (P9_DSYNC, libc::O_DSYNC),
+        (P9_SYNC, libc::O_SYNC),
+        (P9_DIRECTORY, libc::O_DIRECTORY),
+    ] {
+        if flags & flag == flag {
+            host_flags |= host_flag;
+        }
+    }
+    host_flags
+}
+
+fn read_dir(dir: &File) -> io::Result<Vec<DirEntry>> {
+    let fd = dir.try_clone()?.into_raw_fd();
+    // SAFETY: FFI call with a valid file descriptor, which the stream owns
+    // from now on.
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let e = io::Error::last_os_error();
+        // SAFETY: the file descriptor is still owned on failure.
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    // SAFETY: FFI call with a valid stream. The file descriptor shares its
+    // offset with the duplicated one, start from the first entry.
+    unsafe { libc::rewinddir(stream) };
+
+    let mut entries = Vec::new();
+    loop {
+        // SAFETY: FFI call with a valid stream.
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        // SAFETY: a non-NULL entry returned by readdir() is valid until
+        // the next call, and d_name is NUL-terminated.
+        let (ino, kind, name) = unsafe {
+            (
+                (*entry).d_ino,
+                (*entry).d_type,
+                CStr::from_ptr((*entry).d_name.as_ptr()).to_bytes().to_vec(),
+            )
+        };
+        entries.push(DirEntry { ino, kind, name });
+    }
+    // SAFETY: FFI call with a valid stream, closing its file descriptor.
+    unsafe { libc::closedir(stream) };
+
+    Ok(entries)
+}
+
+pub struct Server {
+    root: File,
+    max_msize: u32,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    /// Serve the directory `root` was opened on, negotiating a maximum
+    /// message size up to `max_msize`.
+    pub fn new(root: File, max_msize: u32) -> Self {
+        Server {
+            root,
+            max_msize,
+            msize: max_msize,
+            fids: HashMap::new(),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(ebadf)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(ebadf)
+    }
+
+    fn fid_file(&self, fid: u32) -> io::Result<&File> {
+        self.fid(fid)?.file.as_ref().ok_or_else(ebadf)
+    }
+
+    // Open the file at `path`, relative to the exported directory, without
+    // following any symbolic link.
+    fn open_path(&self, path: &Path, flags: i32) -> io::Result<File> {
+        let mut components = path.components().peekable();
+        if components.peek().is_none() {
+            let dot = CStr::from_bytes_with_nul(b".\0").unwrap();
+            return openat(&self.root, dot, flags, 0);
+        }
+
+        let mut dir = None;
+        while let Some(component) = components.next() {
+            let name = cstring(component.as_os_str())?;
+            let parent = dir.as_ref().unwrap_or(&self.root);
+            if components.peek().is_none() {
+                return openat(parent, &name, flags | libc::O_NOFOLLOW, 0);
+            }
+            dir = Some(openat(
+                parent,
+                &name,
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+                0,
+            )?);
+        }
+        unreachable!()
+    }
+
+    fn open_dir(&self, path: &Path) -> io::Result<File> {
+        self.open_path(path, libc::O_PATH | libc::O_DIRECTORY)
+    }
+
+    // Open the directory containing the file at `path`, returning it along
+    // with the name of the file.
+    fn open_parent(&self, path: &Path) -> io::Result<(File, CString)> {
+        let name = path.file_name().ok_or_else(einval)?;
+        let dir = self.open_dir(path.parent().unwrap())?;
+        Ok((dir, cstring(name)?))
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<libc::stat> {
+        fstat(&self.open_path(path, libc::O_PATH)?)
+    }
+
+    // Update the paths of the fids affected by a rename.
+    fn rename_fids(&mut self, old_path: &Path, new_path: &Path) {
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.path.strip_prefix(old_path) {
+                fid.path = if rest.as_os_str().is_empty() {
+                    new_path.to_path_buf()
+                } else {
+                    new_path.join(rest)
+                };
+            }
+        }
+    }
+
+    /// Handle a request, returning the reply to send back.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut r = Reader { buf: request };
+        let header = (|| Ok::<_, io::Error>((r.u32()?, r.u8()?, r.u16()?)))();
+        let (msg_type, tag) = match header {
+            Ok((_, msg_type, tag)) => (msg_type, tag),
+            Err(_) => (0, u16::MAX),
+        };
+
+        let mut w = Writer::default();
+        let result = match msg_type {
+            TSTATFS => self.statfs(&mut r, &mut w),
+            TLOPEN => self.lopen(&mut r, &mut w),
+            TLCREATE => self.lcreate(&mut r, &mut w),
+            TSYMLINK => self.symlink(&mut r, &mut w),
+            TMKNOD => self.mknod(&mut r, &mut w),
+            TRENAME => self.rename(&mut r),
+            TREADLINK => self.readlink(&mut r, &mut w),
+            TGETATTR => self.getattr(&mut r, &mut w),
+            TSETATTR => self.setattr(&mut r),
+            TREADDIR => self.readdir(&mut r, &mut w),
+            TFSYNC => self.fsync(&mut r),
+            TLOCK => self.lock(&mut r, &mut w),
+            TGETLOCK => self.getlock(&mut r, &mut w),
+            TLINK => self.link(&mut r),
+            TMKDIR => self.mkdir(&mut r, &mut w),
+            TRENAMEAT => self.renameat(&mut r),
+            TUNLINKAT => self.unlinkat(&mut r),
+            TVERSION => self.version(&mut r, &mut w),
+            TATTACH => self.attach(&mut r, &mut w),
+            // Requests are handled synchronously, nothing is left to flush.
+            TFLUSH => Ok(()),
+            TWALK => self.walk(&mut r, &mut w),
+            TREAD => self.read(&mut r, &mut w),
+            TWRITE => self.write(&mut r, &mut w),
+            TCLUNK => self.clunk(&mut r),
+            TREMOVE => self.remove(&mut r),
+            // Neither authentication nor extended attributes are supported.
+            TAUTH | TXATTRWALK | TXATTRCREATE => {
+                Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+            }
+            _ => {
+                warn!("Unsupported 9P message type {}", msg_type);
+                Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+            }
+        };
+
+        let (reply_type, body) = match result {
+            Ok(()) => (msg_type + 1, w.buf),
+            Err(e) => {
+                let mut w = Writer::default();
+                w.u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+                (RLERROR, w.buf)
+            }
+        };
+
+        let mut reply = Writer::default();
+        reply.u32((HEADER_SIZE + body.len()) as u32);
+        reply.u8(reply_type);
+        reply.u16(tag);
+        reply.buf.extend_from_slice(&body);
+        reply.buf
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+        if msize < MIN_MSIZE {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // A version request aborts all the outstanding I/O.
+        self.fids.clear();
+        self.msize = msize.min(self.max_msize);
+
+        w.u32(self.msize);
+        if version.as_bytes() == P9_VERSION {
+            w.string(P9_VERSION);
+        } else {
+            w.string(P9_VERSION_UNKNOWN);
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _afid = r.u32()?;
+        let _uname = r.string()?;
+        let _aname = r.string()?;
+        let _n_uname = r.u32()?;
+
+        if self.fids.contains_key(&fid) {
+            return Err(ebadf());
+        }
+        let st = self.stat(Path::new(""))?;
+        self.fids.insert(fid, Fid::new(PathBuf::new()));
+
+        w.qid(Qid::from(&st));
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let nwname = r.u16()?;
+        if nwname > MAX_WALK_NAMES {
+            return Err(einval());
+        }
+        let names = (0..nwname)
+            .map(|_| r.string())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut path = self.fid(fid)?.path.clone();
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(ebadf());
+        }
+
+        let mut qids = Vec::new();
+        for name in names.iter() {
+            let st = match walk_path(&path, name).and_then(|p| Ok((self.stat(&p)?, p))) {
+                Ok((st, p)) => {
+                    path = p;
+                    st
+                }
+                // Only the first failure is an error, otherwise the qids
+                // of the names walked so far are returned.
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            qids.push(Qid::from(&st));
+        }
+
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, Fid::new(path));
+        }
+
+        w.u16(qids.len() as u16);
+        for qid in qids {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        self.fid(fid)?;
+
+        let mut st = MaybeUninit::<libc::statvfs>::zeroed();
+        // SAFETY: FFI call with a valid file descriptor and a buffer large
+        // enough for the result.
+        check(unsafe { libc::fstatvfs(self.root.as_raw_fd(), st.as_mut_ptr()) })?;
+        // SAFETY: initialized by the successful fstatvfs() call.
+        let st = unsafe { st.assume_init() };
+
+        w.u32(V9FS_MAGIC);
+        w.u32(st.f_bsize as u32);
+        w.u64(st.f_blocks);
+        w.u64(st.f_bfree);
+        w.u64(st.f_bavail);
+        w.u64(st.f_files);
+        w.u64(st.f_ffree);
+        w.u64(st.f_fsid);
+        w.u32(st.f_namemax as u32);
+        Ok(())
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let flags = r.u32()?;
+
+        let entry = self.fid(fid)?;
+        if entry.file.is_some() {
+            return Err(ebadf());
+        }
+        let file = self.open_path(&entry.path, open_flags(flags))?;
+        let st = fstat(&file)?;
+        self.fid_mut(fid)?.file = Some(file);
+
+        w.qid(Qid::from(&st));
+        w.u32(self.msize - IO_HEADER_SIZE);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()?;
+        let mode = r.u32()?;
+        let _gid = r.u32()?;
+
+        let entry = self.fid(fid)?;
+        if entry.file.is_some() {
+            return Err(ebadf());
+        }
+        let dir = self.open_dir(&entry.path)?;
+        let file = openat(
+            &dir,
+            &child_name(&name)?,
+            open_flags(flags) | libc::O_CREAT | libc::O_NOFOLLOW,
+            mode & 0o7777,
+        )?;
+        let st = fstat(&file)?;
+
+        // The fid now refers to the newly created file.
+        let entry = self.fid_mut(fid)?;
+        entry.path.push(name);
+        entry.file = Some(file);
+
+        w.qid(Qid::from(&st));
+        w.u32(self.msize - IO_HEADER_SIZE);
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let target = r.string()?;
+        let _gid = r.u32()?;
+
+        let path = self.fid(fid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        let target = cstring(&target)?;
+        // SAFETY: FFI call with a valid file descriptor and NUL-terminated
+        // strings.
+        check(unsafe {
+            libc::symlinkat(
+                target.as_ptr(),
+                dir.as_raw_fd(),
+                child_name(&name)?.as_ptr(),
+            )
+        })?;
+
+        w.qid(Qid::from(&self.stat(&path.join(name))?));
+        Ok(())
+    }
+
+    fn mknod(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let major = r.u32()?;
+        let minor = r.u32()?;
+        let _gid = r.u32()?;
+
+        let path = self.fid(fid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        // SAFETY: FFI call with a valid file descriptor and a NUL-terminated
+        // string.
+        check(unsafe {
+            libc::mknodat(
+                dir.as_raw_fd(),
+                child_name(&name)?.as_ptr(),
+                mode,
+                libc::makedev(major, minor),
+            )
+        })?;
+
+        w.qid(Qid::from(&self.stat(&path.join(name))?));
+        Ok(())
+    }
+
+    fn do_rename(
+        &mut self,
+        old_dir: &Path,
+        old_name: &OsStr,
+        new_dir: &Path,
+        new_name: &OsStr,
+    ) -> io::Result<()> {
+        let old = self.open_dir(old_dir)?;
+        let new = self.open_dir(new_dir)?;
+        // SAFETY: FFI call with valid file descriptors and NUL-terminated
+        // strings.
+        check(unsafe {
+            libc::renameat(
+                old.as_raw_fd(),
+                child_name(old_name)?.as_ptr(),
+                new.as_raw_fd(),
+                child_name(new_name)?.as_ptr(),
+            )
+        })?;
+
+        self.rename_fids(&old_dir.join(old_name), &new_dir.join(new_name));
+        Ok(())
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let dfid = r.u32()?;
+        let name = r.string()?;
+
+        let path = self.fid(fid)?.path.clone();
+        let old_name = path.file_name().ok_or_else(einval)?;
+        let new_dir = self.fid(dfid)?.path.clone();
+        self.do_rename(path.parent().unwrap(), old_name, &new_dir, &name)
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let old_dfid = r.u32()?;
+        let old_name = r.string()?;
+        let new_dfid = r.u32()?;
+        let new_name = r.string()?;
+
+        let old_dir = self.fid(old_dfid)?.path.clone();
+        let new_dir = self.fid(new_dfid)?.path.clone();
+        self.do_rename(&old_dir, &old_name, &new_dir, &new_name)
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+
+        let (dir, name) = self.open_parent(&self.fid(fid)?.path)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        // SAFETY: FFI call with a valid file descriptor, a NUL-terminated
+        // string and a buffer of the given size.
+        let len = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        w.string(&buf[..len as usize]);
+        Ok(())
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _request_mask = r.u64()?;
+
+        let entry = self.fid(fid)?;
+        let st = match entry.file.as_ref() {
+            Some(file) => fstat(file)?,
+            None => self.stat(&entry.path)?,
+        };
+
+        w.u64(GETATTR_BASIC);
+        w.qid(Qid::from(&st));
+        w.u32(st.st_mode);
+        w.u32(st.st_uid);
+        w.u32(st.st_gid);
+        w.u64(st.st_nlink as u64);
+        w.u64(st.st_rdev);
+        w.u64(st.st_size as u64);
+        w.u64(st.st_blksize as u64);
+        w.u64(st.st_blocks as u64);
+        w.u64(st.st_atime as u64);
+        w.u64(st.st_atime_nsec as u64);
+        w.u64(st.st_mtime as u64);
+        w.u64(st.st_mtime_nsec as u64);
+        w.u64(st.st_ctime as u64);
+        w.u64(st.st_ctime_nsec as u64);
+        // Birth time, generation and data version are not reported.
+        w.u64(0);
+        w.u64(0);
+        w.u64(0);
+        w.u64(0);
+        Ok(())
+    }
+
+    // Open the file at `path` for updating its attributes, which symbolic
+    // links cannot get as they are never followed.
+    fn open_for_setattr(&self, path: &Path) -> io::Result<File> {
+        self.open_path(path, libc::O_RDONLY | libc::O_NONBLOCK)
+            .or_else(|e| {
+                if e.raw_os_error() == Some(libc::EACCES) {
+                    self.open_path(path, libc::O_WRONLY | libc::O_NONBLOCK)
+                } else {
+                    Err(e)
+                }
+            })
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime_sec = r.u64()?;
+        let atime_nsec = r.u64()?;
+        let mtime_sec = r.u64()?;
+        let mtime_nsec = r.u64()?;
+
+        let path = self.fid(fid)?.path.clone();
+
+        if valid & SETATTR_SIZE != 0 {
+            let file = self.open_path(&path, libc::O_WRONLY | libc::O_NONBLOCK)?;
+            file.set_len(size)?;
+        }
+
+        if valid & (SETATTR_MODE | SETATTR_UID | SETATTR_GID | SETATTR_ATIME | SETATTR_MTIME) == 0 {
+            return Ok(());
+        }
+        let file = self.open_for_setattr(&path)?;
+
+        if valid & SETATTR_MODE != 0 {
+            // SAFETY: FFI call with a valid file descriptor.
+            check(unsafe { libc::fchmod(file.as_raw_fd(), mode & 0o7777) })?;
+        }
+
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            let uid = if valid & SETATTR_UID != 0 {
+                uid
+            } else {
+                u32::MAX
+            };
+            let gid = if valid & SETATTR_GID != 0 {
+                gid
+            } else {
+                u32::MAX
+            };
+            // SAFETY: FFI call with a valid file descriptor.
+            check(unsafe { libc::fchown(file.as_raw_fd(), uid, gid) })?;
+        }
+
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let time = |update, set, sec, nsec| libc::timespec {
+                tv_sec: if valid & set != 0 {
+                    sec as libc::time_t
+                } else {
+                    0
+                },
+                tv_nsec: if valid & update == 0 {
+                    libc::UTIME_OMIT
+                } else if valid & set != 0 {
+                    nsec as libc::c_long
+                } else {
+                    libc::UTIME_NOW
+                },
+            };
+            let times = [
+                time(SETATTR_ATIME, SETATTR_ATIME_SET, atime_sec, atime_nsec),
+                time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime_sec, mtime_nsec),
+            ];
+            // SAFETY: FFI call with a valid file descriptor and an array of
+            // two timestamps.
+            check(unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) })?;
+        }
+
+        Ok(())
+    }
+
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize - IO_HEADER_SIZE) as usize;
+
+        let entry = self.fid_mut(fid)?;
+        let file = entry.file.as_ref().ok_or_else(ebadf)?;
+        if offset == 0 || entry.dir_entries.is_none() {
+            entry.dir_entries = Some(read_dir(file)?);
+        }
+
+        let mut data = Writer::default();
+        for (i, dir_entry) in entry
+            .dir_entries
+            .as_ref()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .skip(offset as usize)
+        {
+            // qid[13] offset[8] type[1] name[s]
+            if data.buf.len() + 24 + dir_entry.name.len() > count {
+                break;
+            }
+            let kind = match dir_entry.kind {
+                libc::DT_DIR => QTDIR,
+                libc::DT_LNK => QTSYMLINK,
+                _ => QTFILE,
+            };
+            data.qid(Qid {
+                kind,
+                version: 0,
+                path: dir_entry.ino,
+            });
+            data.u64(i as u64 + 1);
+            data.u8(dir_entry.kind);
+            data.string(&dir_entry.name);
+        }
+
+        w.u32(data.buf.len() as u32);
+        w.buf.extend_from_slice(&data.buf);
+        Ok(())
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let datasync = r.u32()?;
+
+        let file = self.fid_file(fid)?;
+        if datasync != 0 {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+
+    // Locks are only advisory and local to the guest, always grant them.
+    fn lock(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        self.fid(fid)?;
+
+        w.u8(P9_LOCK_SUCCESS);
+        Ok(())
+    }
+
+    fn getlock(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _type = r.u8()?;
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let proc_id = r.u32()?;
+        let client_id = r.string()?;
+        self.fid(fid)?;
+
+        w.u8(P9_LOCK_TYPE_UNLCK);
+        w.u64(start);
+        w.u64(length);
+        w.u32(proc_id);
+        w.string(client_id.as_bytes());
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let fid = r.u32()?;
+        let name = r.string()?;
+
+        let (old_dir, old_name) = self.open_parent(&self.fid(fid)?.path)?;
+        let new_dir = self.open_dir(&self.fid(dfid)?.path)?;
+        // SAFETY: FFI call with valid file descriptors and NUL-terminated
+        // strings.
+        check(unsafe {
+            libc::linkat(
+                old_dir.as_raw_fd(),
+                old_name.as_ptr(),
+                new_dir.as_raw_fd(),
+                child_name(&name)?.as_ptr(),
+                0,
+            )
+        })
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let _gid = r.u32()?;
+
+        let path = self.fid(dfid)?.path.clone();
+        let dir = self.open_dir(&path)?;
+        // SAFETY: FFI call with a valid file descriptor and a NUL-terminated
+        // string.
+        check(unsafe {
+            libc::mkdirat(dir.as_raw_fd(), child_name(&name)?.as_ptr(), mode & 0o7777)
+        })?;
+
+        w.qid(Qid::from(&self.stat(&path.join(name))?));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()?;
+
+        let dir = self.open_dir(&self.fid(dfid)?.path)?;
+        // SAFETY: FFI call with a valid file descriptor and a NUL-terminated
+        // string.
+        check(unsafe {
+            libc::unlinkat(
+                dir.as_raw_fd(),
+                child_name(&name)?.as_ptr(),
+                flags as i32 & libc::AT_REMOVEDIR,
+            )
+        })
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize - IO_HEADER_SIZE);
+
+        let file = self.fid_file(fid)?;
+        let mut buf = vec![0u8; count as usize];
+        let len = file.read_at(&mut buf, offset)?;
+
+        w.u32(len as u32);
+        w.buf.extend_from_slice(&buf[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?;
+        let data = r.bytes(count as usize)?;
+
+        let len = self.fid_file(fid)?.write_at(data, offset)?;
+
+        w.u32(len as u32);
+        Ok(())
+    }
+
+    fn clunk(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        self.fids.remove(&fid).map(|_| ()).ok_or_else(ebadf)
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+
+        // The fid is clunked even if the removal fails.
+        let entry = self.fids.remove(&fid).ok_or_else(ebadf)?;
+        let st = self.stat(&entry.path)?;
+        let (dir, name) = self.open_parent(&entry.path)?;
+        let flags = if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        // SAFETY: FFI call with a valid file descriptor and a NUL-terminated
+        // string.
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::OpenOptionsExt;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn request(msg_type: u8, body: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut w = Writer::default();
+        body(&mut w);
+        let mut req = Writer::default();
+        req.u32((HEADER_SIZE + w.buf.len()) as u32);
+        req.u8(msg_type);
+        req.u16(1);
+        req.buf.extend_from_slice(&w.buf);
+        req.buf
+    }
+
+    // Return the body of the reply, checking its type.
+    fn reply(server: &mut Server, req: &[u8], msg_type: u8) -> Vec<u8> {
+        let reply = server.handle(req);
+        let mut r = Reader { buf: &reply };
+        assert_eq!(r.u32().unwrap() as usize, reply.len());
+        assert_eq!(r.u8().unwrap(), msg_type);
+        assert_eq!(r.u16().unwrap(), 1);
+        r.buf.to_vec()
+    }
+
+    fn walk(fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+        request(TWALK, |w| {
+            w.u32(fid);
+            w.u32(newfid);
+            w.u16(names.len() as u16);
+            for name in names {
+                w.string(name.as_bytes());
+            }
+        })
+    }
+
+    #[test]
+    fn test_server() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        fs::create_dir(dir.as_path().join("sub")).unwrap();
+        fs::write(dir.as_path().join("sub/file"), b"hello").unwrap();
+        let root = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(dir.as_path())
+            .unwrap();
+        let mut server = Server::new(root, 8192);
+
+        // A message size without room for the I/O headers gets rejected.
+        let req = request(TVERSION, |w| {
+            w.u32(IO_HEADER_SIZE - 1);
+            w.string(P9_VERSION);
+        });
+        let body = reply(&mut server, &req, RLERROR);
+        assert_eq!(&body[..4], &(libc::EINVAL as u32).to_le_bytes());
+
+        let req = request(TVERSION, |w| {
+            w.u32(65536);
+            w.string(P9_VERSION);
+        });
+        let body = reply(&mut server, &req, TVERSION + 1);
+        assert_eq!(&body[..4], &8192u32.to_le_bytes());
+
+        let req = request(TATTACH, |w| {
+            w.u32(0);
+            w.u32(u32::MAX);
+            w.string(b"root");
+            w.string(b"");
+            w.u32(0);
+        });
+        reply(&mut server, &req, TATTACH + 1);
+
+        // Walking up the exported directory stays in it.
+        let body = reply(&mut server, &walk(0, 1, &["..", "sub", "file"]), TWALK + 1);
+        assert_eq!(&body[..2], &3u16.to_le_bytes());
+        assert_eq!(server.fid(1).unwrap().path, Path::new("sub/file"));
+
+        // Only the names walked successfully are reported.
+        let body = reply(&mut server, &walk(0, 2, &["sub", "missing"]), TWALK + 1);
+        assert_eq!(&body[..2], &1u16.to_le_bytes());
+        assert!(server.fid(2).is_err());
+        let body = reply(&mut server, &walk(0, 2, &["missing"]), RLERROR);
+        assert_eq!(body, (libc::ENOENT as u32).to_le_bytes());
+        reply(&mut server, &walk(0, 2, &["sub/file"]), RLERROR);
+
+        let req = request(TLOPEN, |w| {
+            w.u32(1);
+            w.u32(0);
+        });
+        reply(&mut server, &req, TLOPEN + 1);
+        let req = request(TREAD, |w| {
+            w.u32(1);
+            w.u64(1);
+            w.u32(100);
+        });
+        let body = reply(&mut server, &req, TREAD + 1);
+        assert_eq!(&body[..4], &4u32.to_le_bytes());
+        assert_eq!(&body[4..], b"ello");
+
+        // Symbolic links are never followed.
+        std::os::unix::fs::symlink("/", dir.as_path().join("escape")).unwrap();
+        reply(&mut server, &walk(0, 3, &["escape"]), TWALK + 1);
+        let req = request(TLOPEN, |w| {
+            w.u32(3);
+            w.u32(0);
+        });
+        reply(&mut server, &req, RLERROR);
+        reply(&mut server, &walk(0, 4, &["escape", "etc"]), TWALK + 1);
+        assert!(server.fid(4).is_err());
+
+        let req = request(TMKDIR, |w| {
+            w.u32(0);
+            w.string(b"new");
+            w.u32(0o755);
+            w.u32(0);
+        });
+        reply(&mut server, &req, TMKDIR + 1);
+        let req = request(TRENAMEAT, |w| {
+            w.u32(0);
+            w.string(b"sub");
+            w.u32(0);
+            w.string(b"renamed");
+        });
+        reply(&mut server, &req, TRENAMEAT + 1);
+        assert_eq!(server.fid(1).unwrap().path, Path::new("renamed/file"));
+        assert!(dir.as_path().join("new").is_dir());
+        assert!(dir.as_path().join("renamed/file").is_file());
+
+        let req = request(TCLUNK, |w| w.u32(1));
+        reply(&mut server, &req, TCLUNK + 1);
+        reply(&mut server, &req, RLERROR);
+    }
+}
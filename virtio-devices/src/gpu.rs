@@ -0,0 +1,1615 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! virtio-gpu device, limited to 2D resources and a single scanout. The
+//! guest draws into resources backed by its own memory, the content of the
+//! scanout being copied to a [`GpuDisplay`] whenever the guest flushes it, for
+//! the VMM to expose it to remote clients.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccompiler::SeccompAction;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use thiserror::Error;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 256;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+const CONTROL_QUEUE: usize = 0;
+const CURSOR_QUEUE: usize = 1;
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New descriptors are pending on the cursor queue.
+const CURSOR_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+
+/// Largest width and height of a display.
+pub const GPU_MAX_DISPLAY_SIZE: u32 = 8192;
+// Host memory the guest can allocate for its resources.
+const MAX_RESOURCES_SIZE: usize = 256 << 20;
+// Largest command, bounding the backing pages of a resource to 64 MiB.
+const MAX_COMMAND_SIZE: usize = 256 << 10;
+// Updates of the display remembered to compute the damage for its consumers.
+const MAX_DAMAGE_HISTORY: usize = 64;
+const BYTES_PER_PIXEL: u32 = 4;
+
+// Commands
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+// Responses
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1 << 0;
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+// Pixel formats, named after the order of the bytes in memory.
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Failed to read from guest memory: {0}")]
+    GuestMemoryRead(vm_memory::guest_memory::Error),
+    #[error("Failed to write to guest memory: {0}")]
+    GuestMemoryWrite(vm_memory::guest_memory::Error),
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuCtrlHdr {
+    type_: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuCtrlHdr {}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[repr(C)]
+struct VirtioGpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuRect {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuDisplayOne {
+    r: VirtioGpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuRespDisplayInfo {
+    hdr: VirtioGpuCtrlHdr,
+    pmodes: [VirtioGpuDisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuRespDisplayInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceCreate2d {
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceCreate2d {}
+
+// Also used by RESOURCE_DETACH_BACKING.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceUnref {
+    resource_id: u32,
+    padding: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceUnref {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuSetScanout {
+    r: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuSetScanout {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceFlush {
+    r: VirtioGpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceFlush {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuTransferToHost2d {
+    r: VirtioGpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuTransferToHost2d {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceAttachBacking {
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceAttachBacking {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuMemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuMemEntry {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuConfig {
+    events_read: u32,
+    events_clear: u32,
+    num_scanouts: u32,
+    num_capsets: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuConfig {}
+
+/// Area of a display, in pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Smallest rectangle containing both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    /// Area shared by both rectangles, if any.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x as u64 + self.width as u64).min(other.x as u64 + other.width as u64);
+        let bottom = (self.y as u64 + self.height as u64).min(other.y as u64 + other.height as u64);
+        let rect = Rect {
+            x,
+            y,
+            width: right.saturating_sub(x as u64) as u32,
+            height: bottom.saturating_sub(y as u64) as u32,
+        };
+        (!rect.is_empty()).then_some(rect)
+    }
+}
+
+impl From<VirtioGpuRect> for Rect {
+    fn from(r: VirtioGpuRect) -> Self {
+        Rect {
+            x: r.x,
+            y: r.y,
+            width: r.width,
+            height: r.height,
+        }
+    }
+}
+
+/// Content of the scanout, as last flushed by the guest.
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels in the `0x00RRGGBB` format, row after row.
+    pub pixels: Vec<u32>,
+    generation: u64,
+    // Areas updated by the last generations.
+    damage: VecDeque<(u64, Rect)>,
+}
+
+impl Framebuffer {
+    fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize],
+            generation: 0,
+            damage: VecDeque::new(),
+        }
+    }
+
+    /// Number of updates of the framebuffer.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn full(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Area updated after the given generation, the whole framebuffer when
+    /// the updates are too old to be known.
+    pub fn damage_since(&self, generation: u64) -> Option<Rect> {
+        if generation >= self.generation {
+            return None;
+        }
+        match self.damage.front() {
+            Some((oldest, _)) if *oldest <= generation + 1 => Some(
+                self.damage
+                    .iter()
+                    .filter(|(g, _)| *g > generation)
+                    .fold(Rect::default(), |area, (_, rect)| area.union(rect)),
+            ),
+            _ => Some(self.full()),
+        }
+    }
+
+    fn add_damage(&mut self, rect: Rect) {
+        self.generation += 1;
+        if self.damage.len() == MAX_DAMAGE_HISTORY {
+            self.damage.pop_front();
+        }
+        self.damage.push_back((self.generation, rect));
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![0; width as usize * height as usize];
+        // Consumers must redraw everything after a resize.
+        self.damage.clear();
+        self.generation += 1;
+    }
+}
+
+/// Display of a virtio-gpu device, shared with the consumers of its
+/// framebuffer which can wait for it to be updated.
+#[derive(Clone)]
+pub struct GpuDisplay {
+    framebuffer: Arc<(Mutex<Framebuffer>, Condvar)>,
+}
+
+impl GpuDisplay {
+    /// Create a display, reported to the guest with the given size.
+    pub fn new(width: u32, height: u32) -> Self {
+        GpuDisplay {
+            framebuffer: Arc::new((Mutex::new(Framebuffer::new(width, height)), Condvar::new())),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Framebuffer> {
+        self.framebuffer.0.lock().unwrap()
+    }
+
+    /// Wait for the framebuffer to be updated after the given generation,
+    /// or for the timeout to expire.
+    pub fn wait_update(&self, generation: u64, timeout: Duration) -> MutexGuard<'_, Framebuffer> {
+        let (framebuffer, updated) = &*self.framebuffer;
+        updated
+            .wait_timeout_while(framebuffer.lock().unwrap(), timeout, |fb| {
+                fb.generation <= generation
+            })
+            .unwrap()
+            .0
+    }
+
+    fn notify(&self) {
+        self.framebuffer.1.notify_all();
+    }
+}
+
+// Convert a pixel of the given format to 0x00RRGGBB.
+fn to_rgb(format: u32, p: &[u8]) -> u32 {
+    let (r, g, b) = match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => (p[2], p[1], p[0]),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => (p[1], p[2], p[3]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => (p[0], p[1], p[2]),
+        _ => (p[3], p[2], p[1]),
+    };
+    ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+}
+
+fn is_supported_format(format: u32) -> bool {
+    matches!(
+        format,
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM
+            | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM
+            | VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM
+            | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM
+            | VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM
+            | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM
+            | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM
+            | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM
+    )
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct ResourceState {
+    id: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    backing: Vec<(u64, u32)>,
+}
+
+struct Resource {
+    width: u32,
+    height: u32,
+    format: u32,
+    // Guest pages backing the resource.
+    backing: Vec<(GuestAddress, u32)>,
+    // Copy of the resource, updated by TRANSFER_TO_HOST_2D.
+    data: Vec<u8>,
+}
+
+impl Resource {
+    fn size(width: u32, height: u32) -> usize {
+        width as usize * height as usize * BYTES_PER_PIXEL as usize
+    }
+
+    fn stride(&self) -> usize {
+        (self.width * BYTES_PER_PIXEL) as usize
+    }
+
+    fn contains(&self, rect: &Rect) -> bool {
+        rect.x as u64 + rect.width as u64 <= self.width as u64
+            && rect.y as u64 + rect.height as u64 <= self.height as u64
+    }
+
+    // Read the given range of the backing, fails if it isn't entirely backed.
+    fn read_backing(&self, mem: &GuestMemoryMmap, mut offset: u64, mut buf: &mut [u8]) -> bool {
+        for (addr, len) in self.backing.iter() {
+            if buf.is_empty() {
+                break;
+            }
+            let len = *len as u64;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let count = (len - offset).min(buf.len() as u64) as usize;
+            let Some(entry_addr) = addr.0.checked_add(offset) else {
+                return false;
+            };
+            if mem
+                .read_slice(&mut buf[..count], GuestAddress(entry_addr))
+                .is_err()
+            {
+                return false;
+            }
+            buf = &mut buf[count..];
+            offset = 0;
+        }
+        buf.is_empty()
+    }
+
+    // Copy an area of the backing to the resource, `offset` being the
+    // location of its first pixel in the backing.
+    fn transfer(&mut self, mem: &GuestMemoryMmap, rect: &Rect, offset: u64) -> bool {
+        let stride = self.stride();
+        let row_len = (rect.width * BYTES_PER_PIXEL) as usize;
+        let mut row = vec![0u8; row_len];
+        for y in 0..rect.height as usize {
+            let row_offset = offset.saturating_add((y * stride) as u64);
+            if !self.read_backing(mem, row_offset, &mut row) {
+                return false;
+            }
+            let start = (rect.y as usize + y) * stride + (rect.x * BYTES_PER_PIXEL) as usize;
+            self.data[start..start + row_len].copy_from_slice(&row);
+        }
+        true
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct Scanout {
+    resource_id: u32,
+    rect: VirtioGpuRect,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct GpuState {
+    avail_features: u64,
+    acked_features: u64,
+    resources: Vec<ResourceState>,
+    scanout: Option<Scanout>,
+}
+
+struct GpuEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    control_queue: Queue,
+    cursor_queue: Option<Queue>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    control_queue_evt: EventFd,
+    cursor_queue_evt: Option<EventFd>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+    display: GpuDisplay,
+    // Size of the display suggested to the guest.
+    display_size: (u32, u32),
+    resources: HashMap<u32, Resource>,
+    resources_size: usize,
+    scanout: Option<Scanout>,
+    // Resources and scanout shared with the device for snapshots.
+    state: Arc<Mutex<(Vec<ResourceState>, Option<Scanout>)>>,
+}
+
+impl GpuEpollHandler {
+    fn create_resource(&mut self, cmd: &VirtioGpuResourceCreate2d) -> u32 {
+        if cmd.resource_id == 0 || self.resources.contains_key(&cmd.resource_id) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        }
+        if !is_supported_format(cmd.format)
+            || cmd.width == 0
+            || cmd.height == 0
+            || cmd.width > GPU_MAX_DISPLAY_SIZE
+            || cmd.height > GPU_MAX_DISPLAY_SIZE
+        {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+        let size = Resource::size(cmd.width, cmd.height);
+        if self.resources_size + size > MAX_RESOURCES_SIZE {
+            return VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY;
+        }
+
+        self.resources_size += size;
+        self.resources.insert(
+            cmd.resource_id,
+            Resource {
+                width: cmd.width,
+                height: cmd.height,
+                format: cmd.format,
+                backing: Vec::new(),
+                data: vec![0; size],
+            },
+        );
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn unref_resource(&mut self, resource_id: u32) -> u32 {
+        let Some(resource) = self.resources.remove(&resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        self.resources_size -= resource.data.len();
+        if self.scanout.is_some_and(|s| s.resource_id == resource_id) {
+            self.scanout = None;
+        }
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn set_scanout(&mut self, cmd: &VirtioGpuSetScanout) -> u32 {
+        if cmd.scanout_id != 0 {
+            return VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID;
+        }
+        if cmd.resource_id == 0 {
+            self.scanout = None;
+            return VIRTIO_GPU_RESP_OK_NODATA;
+        }
+        let Some(resource) = self.resources.get(&cmd.resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        let rect = Rect::from(cmd.r);
+        if rect.is_empty() || !resource.contains(&rect) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        self.scanout = Some(Scanout {
+            resource_id: cmd.resource_id,
+            rect: cmd.r,
+        });
+        self.display.lock().resize(rect.width, rect.height);
+        self.flush(&rect);
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    // Copy the flushed area of the scanout resource to the display.
+    fn flush(&self, rect: &Rect) {
+        let Some(scanout) = self.scanout else {
+            return;
+        };
+        let Some(resource) = self.resources.get(&scanout.resource_id) else {
+            return;
+        };
+        let scanout_rect = Rect::from(scanout.rect);
+        let Some(area) = rect.intersection(&scanout_rect) else {
+            return;
+        };
+
+        let mut fb = self.display.lock();
+        if fb.width != scanout_rect.width || fb.height != scanout_rect.height {
+            return;
+        }
+        let stride = resource.stride();
+        for y in area.y..area.y + area.height {
+            let src = y as usize * stride;
+            let dst = (y - scanout_rect.y) as usize * fb.width as usize;
+            for x in area.x..area.x + area.width {
+                let offset = src + (x * BYTES_PER_PIXEL) as usize;
+                fb.pixels[dst + (x - scanout_rect.x) as usize] = to_rgb(
+                    resource.format,
+                    &resource.data[offset..offset + BYTES_PER_PIXEL as usize],
+                );
+            }
+        }
+        fb.add_damage(Rect {
+            x: area.x - scanout_rect.x,
+            y: area.y - scanout_rect.y,
+            ..area
+        });
+        drop(fb);
+        self.display.notify();
+    }
+
+    fn resource_flush(&self, cmd: &VirtioGpuResourceFlush) -> u32 {
+        let Some(resource) = self.resources.get(&cmd.resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        let rect = Rect::from(cmd.r);
+        if !resource.contains(&rect) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+        if self
+            .scanout
+            .is_some_and(|s| s.resource_id == cmd.resource_id)
+        {
+            self.flush(&rect);
+        }
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn transfer_to_host(&mut self, cmd: &VirtioGpuTransferToHost2d) -> u32 {
+        let mem = self.mem.memory();
+        let Some(resource) = self.resources.get_mut(&cmd.resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        let rect = Rect::from(cmd.r);
+        if !resource.contains(&rect) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+        if !resource.transfer(&mem, &rect, cmd.offset) {
+            return VIRTIO_GPU_RESP_ERR_UNSPEC;
+        }
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn attach_backing(&mut self, cmd: &VirtioGpuResourceAttachBacking, entries: &[u8]) -> u32 {
+        let Some(resource) = self.resources.get_mut(&cmd.resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        let entry_size = size_of::<VirtioGpuMemEntry>();
+        if entries.len() < cmd.nr_entries as usize * entry_size {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+
+        resource.backing = (0..cmd.nr_entries as usize)
+            .filter_map(|i| read_struct::<VirtioGpuMemEntry>(entries, i * entry_size))
+            .map(|entry| {
+                (
+                    GuestAddress(entry.addr)
+                        .translate_gva(self.access_platform.as_ref(), entry.length as usize),
+                    entry.length,
+                )
+            })
+            .collect();
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn detach_backing(&mut self, resource_id: u32) -> u32 {
+        let Some(resource) = self.resources.get_mut(&resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        resource.backing.clear();
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn display_info(&self, hdr: &VirtioGpuCtrlHdr) -> VirtioGpuRespDisplayInfo {
+        let mut info = VirtioGpuRespDisplayInfo {
+            hdr: response_header(hdr, VIRTIO_GPU_RESP_OK_DISPLAY_INFO),
+            ..Default::default()
+        };
+        info.pmodes[0] = VirtioGpuDisplayOne {
+            r: VirtioGpuRect {
+                x: 0,
+                y: 0,
+                width: self.display_size.0,
+                height: self.display_size.1,
+            },
+            enabled: 1,
+            flags: 0,
+        };
+        info
+    }
+
+    // Run a command, returning the response.
+    fn run_command(&mut self, request: &[u8]) -> Vec<u8> {
+        let Some(hdr) = read_struct::<VirtioGpuCtrlHdr>(request, 0) else {
+            return response_header(&VirtioGpuCtrlHdr::default(), VIRTIO_GPU_RESP_ERR_UNSPEC)
+                .as_slice()
+                .to_vec();
+        };
+        let body = size_of::<VirtioGpuCtrlHdr>();
+
+        let status = match hdr.type_ {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                return self.display_info(&hdr).as_slice().to_vec();
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => read_struct(request, body)
+                .map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                    self.create_resource(&cmd)
+                }),
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => read_struct::<VirtioGpuResourceUnref>(request, body)
+                .map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                    self.unref_resource(cmd.resource_id)
+                }),
+            VIRTIO_GPU_CMD_SET_SCANOUT => read_struct(request, body)
+                .map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                    self.set_scanout(&cmd)
+                }),
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => read_struct(request, body)
+                .map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                    self.resource_flush(&cmd)
+                }),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => read_struct(request, body)
+                .map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                    self.transfer_to_host(&cmd)
+                }),
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
+                read_struct(request, body).map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                    let entries = body + size_of::<VirtioGpuResourceAttachBacking>();
+                    self.attach_backing(&cmd, &request[entries..])
+                })
+            }
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                read_struct::<VirtioGpuResourceUnref>(request, body)
+                    .map_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, |cmd| {
+                        self.detach_backing(cmd.resource_id)
+                    })
+            }
+            _ => {
+                debug!("Unsupported virtio-gpu command 0x{:x}", hdr.type_);
+                VIRTIO_GPU_RESP_ERR_UNSPEC
+            }
+        };
+
+        response_header(&hdr, status).as_slice().to_vec()
+    }
+
+    fn process_control_queue(&mut self) -> result::Result<bool, Error> {
+        let mut used_descs = false;
+        while let Some(mut desc_chain) = self.control_queue.pop_descriptor_chain(self.mem.memory())
+        {
+            let mut request = Vec::new();
+            let mut response_desc = None;
+            let mut valid = true;
+            for desc in desc_chain.by_ref() {
+                let addr = desc
+                    .addr()
+                    .translate_gva(self.access_platform.as_ref(), desc.len() as usize);
+                if desc.is_write_only() {
+                    response_desc.get_or_insert((addr, desc.len() as usize));
+                } else if response_desc.is_some()
+                    || request.len() + desc.len() as usize > MAX_COMMAND_SIZE
+                {
+                    valid = false;
+                } else {
+                    let offset = request.len();
+                    request.resize(offset + desc.len() as usize, 0);
+                    desc_chain
+                        .memory()
+                        .read_slice(&mut request[offset..], addr)
+                        .map_err(Error::GuestMemoryRead)?;
+                }
+            }
+
+            let mut len = 0;
+            if let Some((addr, size)) = response_desc {
+                let response = if valid {
+                    self.run_command(&request)
+                } else {
+                    error!("Invalid virtio-gpu command");
+                    response_header(&VirtioGpuCtrlHdr::default(), VIRTIO_GPU_RESP_ERR_UNSPEC)
+                        .as_slice()
+                        .to_vec()
+                };
+                len = response.len().min(size);
+                desc_chain
+                    .memory()
+                    .write_slice(&response[..len], addr)
+                    .map_err(Error::GuestMemoryWrite)?;
+            } else {
+                error!("virtio-gpu command without response buffer");
+            }
+
+            self.control_queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len as u32)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        self.update_state();
+        Ok(used_descs)
+    }
+
+    // The cursor is not exposed, its commands are only completed.
+    fn process_cursor_queue(&mut self) -> result::Result<bool, Error> {
+        let Some(queue) = self.cursor_queue.as_mut() else {
+            return Ok(false);
+        };
+        let mut used_descs = false;
+        while let Some(desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+        Ok(used_descs)
+    }
+
+    fn update_state(&self) {
+        let resources = self
+            .resources
+            .iter()
+            .map(|(id, r)| ResourceState {
+                id: *id,
+                width: r.width,
+                height: r.height,
+                format: r.format,
+                backing: r.backing.iter().map(|(a, l)| (a.0, *l)).collect(),
+            })
+            .collect();
+        *self.state.lock().unwrap() = (resources, self.scanout);
+    }
+
+    // Recreate the resources of a restored device, their content being read
+    // back from their backing.
+    fn restore(&mut self) {
+        let (resources, scanout) = self.state.lock().unwrap().clone();
+        let mem = self.mem.memory();
+        for r in resources {
+            let size = Resource::size(r.width, r.height);
+            let mut resource = Resource {
+                width: r.width,
+                height: r.height,
+                format: r.format,
+                backing: r
+                    .backing
+                    .iter()
+                    .map(|(a, l)| (GuestAddress(*a), *l))
+                    .collect(),
+                data: vec![0; size],
+            };
+            let full = Rect {
+                x: 0,
+                y: 0,
+                width: r.width,
+                height: r.height,
+            };
+            if !resource.backing.is_empty() && !resource.transfer(&mem, &full, 0) {
+                warn!("Failed restoring virtio-gpu resource {}", r.id);
+            }
+            self.resources_size += size;
+            self.resources.insert(r.id, resource);
+        }
+
+        self.scanout = scanout;
+        if let Some(scanout) = scanout {
+            let rect = Rect::from(scanout.rect);
+            self.display.lock().resize(rect.width, rect.height);
+            self.flush(&rect);
+        }
+    }
+
+    fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(queue_index))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.control_queue_evt.as_raw_fd(), CONTROL_QUEUE_EVENT)?;
+        if let Some(cursor_queue_evt) = self.cursor_queue_evt.as_ref() {
+            helper.add_event(cursor_queue_evt.as_raw_fd(), CURSOR_QUEUE_EVENT)?;
+        }
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for GpuEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        let (queue_index, needs_notification) = match ev_type {
+            CONTROL_QUEUE_EVENT => {
+                self.control_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_control_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process queue: {:?}", e))
+                })?;
+                (CONTROL_QUEUE, needs_notification)
+            }
+            CURSOR_QUEUE_EVENT => {
+                if let Some(cursor_queue_evt) = self.cursor_queue_evt.as_ref() {
+                    cursor_queue_evt.read().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                    })?;
+                }
+                let needs_notification = self.process_cursor_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process queue: {:?}", e))
+                })?;
+                (CURSOR_QUEUE, needs_notification)
+            }
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {}",
+                    ev_type
+                )));
+            }
+        };
+        if needs_notification {
+            self.signal_used_queue(queue_index as u16).map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn read_struct<T: ByteValued + Default>(bytes: &[u8], offset: usize) -> Option<T> {
+    let mut value = T::default();
+    let src = bytes.get(offset..offset + size_of::<T>())?;
+    value.as_mut_slice().copy_from_slice(src);
+    Some(value)
+}
+
+fn response_header(hdr: &VirtioGpuCtrlHdr, type_: u32) -> VirtioGpuCtrlHdr {
+    // Commands are completed in order, fences being signaled right away.
+    VirtioGpuCtrlHdr {
+        type_,
+        flags: hdr.flags & VIRTIO_GPU_FLAG_FENCE,
+        fence_id: hdr.fence_id,
+        ctx_id: hdr.ctx_id,
+        ring_idx: hdr.ring_idx,
+        padding: [0; 3],
+    }
+}
+
+/// Virtio device exposing a 2D display to the guest.
+pub struct Gpu {
+    common: VirtioCommon,
+    id: String,
+    config: VirtioGpuConfig,
+    display: GpuDisplay,
+    display_size: (u32, u32),
+    state: Arc<Mutex<(Vec<ResourceState>, Option<Scanout>)>>,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+impl Gpu {
+    /// Create a virtio-gpu device, updating the given display. Its size is
+    /// the one suggested to the guest.
+    pub fn new(
+        id: String,
+        display: GpuDisplay,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+        state: Option<GpuState>,
+    ) -> io::Result<Gpu> {
+        let display_size = {
+            let fb = display.lock();
+            (fb.width, fb.height)
+        };
+
+        let (avail_features, acked_features, resources, scanout, paused) =
+            if let Some(state) = state {
+                info!("Restoring virtio-gpu {}", id);
+                (
+                    state.avail_features,
+                    state.acked_features,
+                    state.resources,
+                    state.scanout,
+                    true,
+                )
+            } else {
+                let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+                if iommu {
+                    avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+                }
+
+                (avail_features, 0, Vec::new(), None, false)
+            };
+
+        Ok(Gpu {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Gpu as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                acked_features,
+                min_queues: 1,
+                paused: Arc::new(AtomicBool::new(paused)),
+                ..Default::default()
+            },
+            id,
+            config: VirtioGpuConfig {
+                num_scanouts: 1,
+                ..Default::default()
+            },
+            display,
+            display_size,
+            state: Arc::new(Mutex::new((resources, scanout))),
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    fn state(&self) -> GpuState {
+        let (resources, scanout) = self.state.lock().unwrap().clone();
+        GpuState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            resources,
+            scanout,
+        }
+    }
+}
+
+impl Drop for Gpu {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for Gpu {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only events_clear is writable, no event being ever raised.
+        if offset != 4 || data.len() != 4 {
+            warn!("Invalid virtio-gpu config write: offset {offset}");
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let (_, control_queue, control_queue_evt) = queues.remove(0);
+        let (cursor_queue, cursor_queue_evt) = match queues.pop() {
+            Some((_, queue, queue_evt)) => (Some(queue), Some(queue_evt)),
+            None => (None, None),
+        };
+
+        let mut handler = GpuEpollHandler {
+            mem,
+            control_queue,
+            cursor_queue,
+            interrupt_cb,
+            control_queue_evt,
+            cursor_queue_evt,
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+            display: self.display.clone(),
+            display_size: self.display_size,
+            resources: HashMap::new(),
+            resources_size: 0,
+            scanout: None,
+            state: self.state.clone(),
+        };
+        handler.restore();
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioGpu,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        // The resources are gone with the driver.
+        *self.state.lock().unwrap() = (Vec::new(), None);
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for Gpu {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Gpu {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+
+impl Transportable for Gpu {}
+impl Migratable for Gpu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKING_ADDR: u64 = 0x1_0000;
+
+    struct NoopInterrupt {}
+
+    impl VirtioInterrupt for NoopInterrupt {
+        fn trigger(&self, _int_type: VirtioInterruptType) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn create_handler(mem: &GuestMemoryMmap, display: &GpuDisplay) -> GpuEpollHandler {
+        GpuEpollHandler {
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            control_queue: Queue::new(QUEUE_SIZE).unwrap(),
+            cursor_queue: None,
+            interrupt_cb: Arc::new(NoopInterrupt {}),
+            control_queue_evt: EventFd::new(0).unwrap(),
+            cursor_queue_evt: None,
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+            access_platform: None,
+            display: display.clone(),
+            display_size: (640, 480),
+            resources: HashMap::new(),
+            resources_size: 0,
+            scanout: None,
+            state: Arc::new(Mutex::new((Vec::new(), None))),
+        }
+    }
+
+    fn command<T: ByteValued>(type_: u32, body: T) -> Vec<u8> {
+        let hdr = VirtioGpuCtrlHdr {
+            type_,
+            ..Default::default()
+        };
+        let mut request = hdr.as_slice().to_vec();
+        request.extend_from_slice(body.as_slice());
+        request
+    }
+
+    fn response_type(response: &[u8]) -> u32 {
+        read_struct::<VirtioGpuCtrlHdr>(response, 0).unwrap().type_
+    }
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> VirtioGpuRect {
+        VirtioGpuRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // Create a 4x2 resource backed by guest memory and attach it to the
+    // scanout.
+    fn setup_scanout(handler: &mut GpuEpollHandler, format: u32) {
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            format,
+            width: 4,
+            height: 2,
+        };
+        let response = handler.run_command(&command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create));
+        assert_eq!(response_type(&response), VIRTIO_GPU_RESP_OK_NODATA);
+
+        // Split the backing in two entries to cover reads across them.
+        let mut request = command(
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            VirtioGpuResourceAttachBacking {
+                resource_id: 1,
+                nr_entries: 2,
+            },
+        );
+        for (addr, length) in [(BACKING_ADDR, 20), (BACKING_ADDR + 0x1000, 12)] {
+            let entry = VirtioGpuMemEntry {
+                addr,
+                length,
+                padding: 0,
+            };
+            request.extend_from_slice(entry.as_slice());
+        }
+        assert_eq!(
+            response_type(&handler.run_command(&request)),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let set_scanout = VirtioGpuSetScanout {
+            r: rect(0, 0, 4, 2),
+            scanout_id: 0,
+            resource_id: 1,
+        };
+        let response = handler.run_command(&command(VIRTIO_GPU_CMD_SET_SCANOUT, set_scanout));
+        assert_eq!(response_type(&response), VIRTIO_GPU_RESP_OK_NODATA);
+    }
+
+    fn create_memory() -> GuestMemoryMmap {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        // Pixel i of the resource is (i, 0x10 + i, 0x20 + i, 0xff) in memory.
+        let pixels: Vec<u8> = (0..8u8)
+            .flat_map(|i| [i, 0x10 + i, 0x20 + i, 0xff])
+            .collect();
+        mem.write_slice(&pixels[..20], GuestAddress(BACKING_ADDR))
+            .unwrap();
+        mem.write_slice(&pixels[20..], GuestAddress(BACKING_ADDR + 0x1000))
+            .unwrap();
+        mem
+    }
+
+    #[test]
+    fn test_rect() {
+        let a = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+        let b = Rect {
+            x: 2,
+            y: 3,
+            width: 4,
+            height: 4,
+        };
+        assert_eq!(
+            a.union(&b),
+            Rect {
+                x: 0,
+                y: 0,
+                width: 6,
+                height: 7
+            }
+        );
+        assert_eq!(a.union(&Rect::default()), a);
+        assert_eq!(
+            a.intersection(&b),
+            Some(Rect {
+                x: 2,
+                y: 3,
+                width: 2,
+                height: 1
+            })
+        );
+        assert_eq!(a.intersection(&Rect { x: 4, ..a }), None);
+    }
+
+    #[test]
+    fn test_to_rgb() {
+        let p = [0x11, 0x22, 0x33, 0x44];
+        assert_eq!(to_rgb(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, &p), 0x33_22_11);
+        assert_eq!(to_rgb(VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM, &p), 0x22_33_44);
+        assert_eq!(to_rgb(VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM, &p), 0x11_22_33);
+        assert_eq!(to_rgb(VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM, &p), 0x44_33_22);
+    }
+
+    #[test]
+    fn test_damage() {
+        let mut fb = Framebuffer::new(8, 8);
+        assert_eq!(fb.damage_since(0), None);
+
+        fb.add_damage(Rect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        });
+        fb.add_damage(Rect {
+            x: 4,
+            y: 2,
+            width: 2,
+            height: 2,
+        });
+        assert_eq!(fb.generation(), 2);
+        assert_eq!(
+            fb.damage_since(0),
+            Some(Rect {
+                x: 1,
+                y: 1,
+                width: 5,
+                height: 3
+            })
+        );
+        assert_eq!(
+            fb.damage_since(1),
+            Some(Rect {
+                x: 4,
+                y: 2,
+                width: 2,
+                height: 2
+            })
+        );
+        assert_eq!(fb.damage_since(2), None);
+
+        // Updates older than the history cover the whole framebuffer.
+        for _ in 0..MAX_DAMAGE_HISTORY {
+            fb.add_damage(Rect::default());
+        }
+        assert_eq!(fb.damage_since(1), Some(fb.full()));
+
+        fb.resize(16, 4);
+        assert_eq!(fb.pixels.len(), 64);
+        assert_eq!(fb.damage_since(fb.generation() - 1), Some(fb.full()));
+    }
+
+    #[test]
+    fn test_display_info() {
+        let mem = create_memory();
+        let display = GpuDisplay::new(640, 480);
+        let mut handler = create_handler(&mem, &display);
+
+        let hdr = VirtioGpuCtrlHdr {
+            type_: VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
+            flags: VIRTIO_GPU_FLAG_FENCE,
+            fence_id: 42,
+            ..Default::default()
+        };
+        let response = handler.run_command(hdr.as_slice());
+        let info = read_struct::<VirtioGpuRespDisplayInfo>(&response, 0).unwrap();
+        assert_eq!(info.hdr.type_, VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+        assert_eq!(info.hdr.flags, VIRTIO_GPU_FLAG_FENCE);
+        assert_eq!(info.hdr.fence_id, 42);
+        assert_eq!(info.pmodes[0].r, rect(0, 0, 640, 480));
+        assert_eq!(info.pmodes[0].enabled, 1);
+        assert_eq!(info.pmodes[1].enabled, 0);
+    }
+
+    #[test]
+    fn test_transfer_and_flush() {
+        let mem = create_memory();
+        let display = GpuDisplay::new(640, 480);
+        let mut handler = create_handler(&mem, &display);
+        setup_scanout(&mut handler, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        assert_eq!(display.lock().width, 4);
+        assert_eq!(display.lock().height, 2);
+
+        // Transfer the second row only, starting from its second pixel.
+        let transfer = VirtioGpuTransferToHost2d {
+            r: rect(1, 1, 3, 1),
+            offset: 20,
+            resource_id: 1,
+            padding: 0,
+        };
+        let response = handler.run_command(&command(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, transfer));
+        assert_eq!(response_type(&response), VIRTIO_GPU_RESP_OK_NODATA);
+        // Nothing is shown before the flush.
+        let generation = display.lock().generation();
+        assert!(display.lock().pixels.iter().all(|p| *p == 0));
+
+        let flush = VirtioGpuResourceFlush {
+            r: rect(0, 0, 4, 2),
+            resource_id: 1,
+            padding: 0,
+        };
+        let response = handler.run_command(&command(VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush));
+        assert_eq!(response_type(&response), VIRTIO_GPU_RESP_OK_NODATA);
+
+        let fb = display.wait_update(generation, Duration::from_secs(1));
+        assert_eq!(fb.generation(), generation + 1);
+        assert_eq!(
+            fb.pixels,
+            vec![0, 0, 0, 0, 0, 0x25_15_05, 0x26_16_06, 0x27_17_07]
+        );
+        assert_eq!(
+            fb.damage_since(generation),
+            Some(Rect {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let mem = create_memory();
+        let display = GpuDisplay::new(640, 480);
+        let mut handler = create_handler(&mem, &display);
+        setup_scanout(&mut handler, VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM);
+
+        // Resource ids are unique and non-zero.
+        for resource_id in [0, 1] {
+            let create = VirtioGpuResourceCreate2d {
+                resource_id,
+                format: VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM,
+                width: 4,
+                height: 4,
+            };
+            let response = handler.run_command(&command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create));
+            assert_eq!(
+                response_type(&response),
+                VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+            );
+        }
+
+        // Unknown formats and oversized resources are rejected.
+        for (format, width) in [
+            (0, 4),
+            (VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM, GPU_MAX_DISPLAY_SIZE + 1),
+        ] {
+            let create = VirtioGpuResourceCreate2d {
+                resource_id: 2,
+                format,
+                width,
+                height: 4,
+            };
+            let response = handler.run_command(&command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create));
+            assert_eq!(
+                response_type(&response),
+                VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+            );
+        }
+
+        // The memory allocated to resources is limited.
+        let side = GPU_MAX_DISPLAY_SIZE;
+        for resource_id in 2.. {
+            let create = VirtioGpuResourceCreate2d {
+                resource_id,
+                format: VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM,
+                width: side,
+                height: side,
+            };
+            let response = handler.run_command(&command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create));
+            if response_type(&response) != VIRTIO_GPU_RESP_OK_NODATA {
+                assert_eq!(response_type(&response), VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY);
+                break;
+            }
+        }
+        assert!(handler.resources_size <= MAX_RESOURCES_SIZE);
+
+        // Transfers must fit in the resource and its backing.
+        for (r, offset) in [(rect(2, 0, 3, 1), 0), (rect(0, 0, 4, 2), 4)] {
+            let transfer = VirtioGpuTransferToHost2d {
+                r,
+                offset,
+                resource_id: 1,
+                padding: 0,
+            };
+            let response =
+                handler.run_command(&command(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, transfer));
+            assert_ne!(response_type(&response), VIRTIO_GPU_RESP_OK_NODATA);
+        }
+
+        let set_scanout = VirtioGpuSetScanout {
+            r: rect(0, 0, 4, 2),
+            scanout_id: 1,
+            resource_id: 1,
+        };
+        let response = handler.run_command(&command(VIRTIO_GPU_CMD_SET_SCANOUT, set_scanout));
+        assert_eq!(
+            response_type(&response),
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID
+        );
+
+        // Truncated commands are invalid.
+        let mut request = command(
+            VIRTIO_GPU_CMD_RESOURCE_UNREF,
+            VirtioGpuResourceUnref {
+                resource_id: 1,
+                padding: 0,
+            },
+        );
+        request.truncate(request.len() - 1);
+        assert_eq!(
+            response_type(&handler.run_command(&request)),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        assert_eq!(
+            response_type(&handler.run_command(&[0; 4])),
+            VIRTIO_GPU_RESP_ERR_UNSPEC
+        );
+
+        // Releasing the scanout resource disables the scanout.
+        request = command(
+            VIRTIO_GPU_CMD_RESOURCE_UNREF,
+            VirtioGpuResourceUnref {
+                resource_id: 1,
+                padding: 0,
+            },
+        );
+        assert_eq!(
+            response_type(&handler.run_command(&request)),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert!(handler.scanout.is_none());
+        assert_eq!(
+            response_type(&handler.run_command(&request)),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let mem = create_memory();
+        let display = GpuDisplay::new(640, 480);
+        let mut handler = create_handler(&mem, &display);
+        setup_scanout(&mut handler, VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM);
+        handler.update_state();
+
+        // The content of the resource is read back from its backing.
+        let restored_display = GpuDisplay::new(640, 480);
+        let mut restored = create_handler(&mem, &restored_display);
+        restored.state = handler.state.clone();
+        restored.restore();
+        assert_eq!(restored.resources_size, 32);
+        let fb = restored_display.lock();
+        assert_eq!((fb.width, fb.height), (4, 2));
+        assert_eq!(fb.pixels[0], 0x00_10_20);
+        assert_eq!(fb.pixels[7], 0x07_17_27);
+    }
+}
@@ -0,0 +1,707 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! virtio-i2c adapter, forwarding the transfers of the guest to a host I2C
+//! bus through the i2c-dev interface. Only the devices whose address has been
+//! allowed can be reached.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use libc::c_uint;
+use seccompiler::SeccompAction;
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier};
+use thiserror::Error;
+use virtio_queue::{DescriptorChain, Queue, QueueT};
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryLoadGuard,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+// Requests without any buffer, such as SMBus quick commands, are supported.
+const VIRTIO_I2C_F_ZERO_LENGTH_REQUEST: u64 = 0;
+
+// The request is part of a group, completed by the following request.
+const VIRTIO_I2C_FLAGS_FAIL_NEXT: u32 = 1 << 0;
+// The buffer is read from the I2C device.
+const VIRTIO_I2C_FLAGS_M_RD: u32 = 1 << 1;
+
+const VIRTIO_I2C_MSG_OK: u8 = 0;
+const VIRTIO_I2C_MSG_ERR: u8 = 1;
+
+// See include/uapi/linux/i2c-dev.h and i2c.h in the kernel code.
+const I2C_IOCTL_TYPE: c_uint = 0x07;
+ioctl_io_nr!(I2C_FUNCS, I2C_IOCTL_TYPE, 0x05);
+ioctl_io_nr!(I2C_RDWR, I2C_IOCTL_TYPE, 0x07);
+const I2C_FUNC_I2C: u64 = 0x1;
+const I2C_M_RD: u16 = 0x1;
+const I2C_RDWR_IOCTL_MAX_MSGS: usize = 42;
+const I2C_MAX_MSG_LEN: usize = 8192;
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Failed to read from guest memory: {0}")]
+    GuestMemoryRead(vm_memory::guest_memory::Error),
+    #[error("Failed to write to guest memory: {0}")]
+    GuestMemoryWrite(vm_memory::guest_memory::Error),
+    #[error("Descriptor chain is too short")]
+    DescriptorChainTooShort,
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioI2cOutHdr {
+    addr: u16,
+    padding: u16,
+    flags: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioI2cOutHdr {}
+
+#[repr(C)]
+struct I2cMsg {
+    addr: u16,
+    flags: u16,
+    len: u16,
+    buf: *mut u8,
+}
+
+#[repr(C)]
+struct I2cRdwrIoctlData {
+    msgs: *mut I2cMsg,
+    nmsgs: u32,
+}
+
+struct Request {
+    head_index: u16,
+    // 7-bit address of the I2C device
+    addr: u16,
+    flags: u32,
+    data: Vec<u8>,
+    // Guest buffers receiving the data read from the I2C device
+    read_buffers: Vec<(GuestAddress, usize)>,
+    status_addr: GuestAddress,
+    // Set when the buffer exceeds I2C_MAX_MSG_LEN, leaving the data empty.
+    too_long: bool,
+}
+
+impl Request {
+    fn parse(
+        desc_chain: &mut DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+        access_platform: Option<&Arc<dyn AccessPlatform>>,
+    ) -> result::Result<Request, Error> {
+        let head_index = desc_chain.head_index();
+        let descs: Vec<_> = desc_chain.by_ref().collect();
+        let mem = desc_chain.memory();
+
+        // The header comes first and the status last, the buffer being held
+        // by the descriptors in between, if any.
+        let (Some(hdr_desc), Some(status_desc)) = (descs.first(), descs.last()) else {
+            return Err(Error::DescriptorChainTooShort);
+        };
+        if descs.len() < 2
+            || hdr_desc.is_write_only()
+            || (hdr_desc.len() as usize) < std::mem::size_of::<VirtioI2cOutHdr>()
+            || !status_desc.is_write_only()
+        {
+            return Err(Error::DescriptorChainTooShort);
+        }
+
+        let hdr: VirtioI2cOutHdr = mem
+            .read_obj(
+                hdr_desc
+                    .addr()
+                    .translate_gva(access_platform, std::mem::size_of::<VirtioI2cOutHdr>()),
+            )
+            .map_err(Error::GuestMemoryRead)?;
+        let flags = u32::from_le(hdr.flags);
+        let status_addr = status_desc.addr().translate_gva(access_platform, 1);
+
+        // Don't allocate anything for a buffer the host bus can't handle,
+        // the request being failed.
+        let buf_descs = &descs[1..descs.len() - 1];
+        let len: u64 = buf_descs.iter().map(|desc| desc.len() as u64).sum();
+        if len > I2C_MAX_MSG_LEN as u64 {
+            return Ok(Request {
+                head_index,
+                addr: u16::from_le(hdr.addr) >> 1,
+                flags,
+                data: Vec::new(),
+                read_buffers: Vec::new(),
+                status_addr,
+                too_long: true,
+            });
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        let mut read_buffers = Vec::new();
+        for desc in buf_descs {
+            let addr = desc
+                .addr()
+                .translate_gva(access_platform, desc.len() as usize);
+            let offset = data.len();
+            data.resize(offset + desc.len() as usize, 0);
+            if flags & VIRTIO_I2C_FLAGS_M_RD != 0 {
+                read_buffers.push((addr, desc.len() as usize));
+            } else {
+                mem.read_slice(&mut data[offset..], addr)
+                    .map_err(Error::GuestMemoryRead)?;
+            }
+        }
+
+        Ok(Request {
+            head_index,
+            addr: u16::from_le(hdr.addr) >> 1,
+            flags,
+            data,
+            read_buffers,
+            status_addr,
+            too_long: false,
+        })
+    }
+}
+
+struct I2cEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queue: Queue,
+    bus: File,
+    allowed_addresses: Vec<u16>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl I2cEpollHandler {
+    // Run a group of requests as a single combined transfer on the host bus,
+    // so that the messages are separated by repeated START conditions.
+    fn transfer(&self, requests: &mut [Request]) -> io::Result<()> {
+        if requests.len() > I2C_RDWR_IOCTL_MAX_MSGS {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut msgs = Vec::with_capacity(requests.len());
+        for request in requests.iter_mut() {
+            if !self.allowed_addresses.contains(&request.addr) {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+            if request.too_long {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            msgs.push(I2cMsg {
+                addr: request.addr,
+                flags: if request.flags & VIRTIO_I2C_FLAGS_M_RD != 0 {
+                    I2C_M_RD
+                } else {
+                    0
+                },
+                len: request.data.len() as u16,
+                buf: request.data.as_mut_ptr(),
+            });
+        }
+
+        let mut rdwr = I2cRdwrIoctlData {
+            msgs: msgs.as_mut_ptr(),
+            nmsgs: msgs.len() as u32,
+        };
+        // SAFETY: the messages point to buffers of the advertised length,
+        // which outlive the ioctl.
+        let ret = unsafe { ioctl_with_mut_ref(&self.bus, I2C_RDWR(), &mut rdwr) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self, request: &Request, status: u8) -> result::Result<(), Error> {
+        let mem = self.mem.memory();
+
+        let mut len = 0;
+        if status == VIRTIO_I2C_MSG_OK {
+            let mut offset = 0;
+            for (addr, size) in request.read_buffers.iter() {
+                mem.write_slice(&request.data[offset..offset + size], *addr)
+                    .map_err(Error::GuestMemoryWrite)?;
+                offset += size;
+            }
+            len = offset as u32;
+        }
+
+        mem.write_obj(status, request.status_addr)
+            .map_err(Error::GuestMemoryWrite)?;
+        self.queue
+            .add_used(mem.deref(), request.head_index, len + 1)
+            .map_err(Error::QueueAddUsed)
+    }
+
+    fn process_queue(&mut self) -> result::Result<bool, Error> {
+        let mut requests = Vec::new();
+        while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(self.mem.memory()) {
+            match Request::parse(&mut desc_chain, self.access_platform.as_ref()) {
+                Ok(request) => requests.push(request),
+                Err(e) => {
+                    error!("Invalid virtio-i2c request: {}", e);
+                    self.queue
+                        .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                        .map_err(Error::QueueAddUsed)?;
+                }
+            }
+        }
+
+        let used_descs = !requests.is_empty();
+        let mut requests = requests.as_mut_slice();
+        while !requests.is_empty() {
+            // A group ends with the first request not failing the next one.
+            let len = requests
+                .iter()
+                .position(|r| r.flags & VIRTIO_I2C_FLAGS_FAIL_NEXT == 0)
+                .map_or(requests.len(), |i| i + 1);
+            let (group, rest) = requests.split_at_mut(len);
+
+            let status = match self.transfer(group) {
+                Ok(()) => VIRTIO_I2C_MSG_OK,
+                Err(e) => {
+                    warn!("virtio-i2c transfer failed: {}", e);
+                    VIRTIO_I2C_MSG_ERR
+                }
+            };
+            for request in group.iter() {
+                self.complete(request, status)?;
+            }
+
+            requests = rest;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(0))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for I2cEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        match ev_type {
+            QUEUE_AVAIL_EVENT => {
+                self.queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process queue : {:?}", e))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to signal used queue: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+            }
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {}",
+                    ev_type
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Virtio device giving the guest access to some devices of a host I2C bus.
+pub struct I2c {
+    common: VirtioCommon,
+    id: String,
+    bus: File,
+    allowed_addresses: Vec<u16>,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+impl I2c {
+    /// Create a new virtio-i2c device forwarding the transfers addressed to
+    /// `allowed_addresses` to the host i2c-dev bus at `path`.
+    pub fn new(
+        id: String,
+        path: &Path,
+        allowed_addresses: Vec<u16>,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+    ) -> io::Result<I2c> {
+        let bus = File::options().read(true).write(true).open(path)?;
+
+        // Adapters only supporting SMBus can't carry raw I2C messages.
+        let mut funcs: u64 = 0;
+        // SAFETY: the file descriptor is a valid i2c-dev bus and funcs is
+        // large enough to receive the functionality mask.
+        let ret = unsafe { ioctl_with_mut_ref(&bus, I2C_FUNCS(), &mut funcs) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if funcs & I2C_FUNC_I2C == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "host I2C adapter does not support plain I2C transfers",
+            ));
+        }
+
+        let mut avail_features =
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_I2C_F_ZERO_LENGTH_REQUEST);
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        Ok(I2c {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::I2c as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                min_queues: 1,
+                ..Default::default()
+            },
+            id,
+            bus,
+            allowed_addresses,
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl Drop for I2c {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for I2c {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let bus = self.bus.try_clone().map_err(|e| {
+            error!("failed cloning I2C bus file: {}", e);
+            ActivateError::BadActivate
+        })?;
+
+        let (_, queue, queue_evt) = queues.remove(0);
+
+        let mut handler = I2cEpollHandler {
+            mem,
+            queue,
+            bus,
+            allowed_addresses: self.allowed_addresses.clone(),
+            interrupt_cb,
+            queue_evt,
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioI2c,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for I2c {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for I2c {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Transportable for I2c {}
+impl Migratable for I2c {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio_bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    const HDR_ADDR: u64 = 0x4_0000;
+    const BUF_ADDR: u64 = 0x4_1000;
+    const STATUS_ADDR: u64 = 0x4_4000;
+    const HDR_LEN: u32 = std::mem::size_of::<VirtioI2cOutHdr>() as u32;
+
+    // Parse the request made of the given (address, length, write-only)
+    // descriptors.
+    fn parse(mem: &GuestMemoryMmap, descs: &[(u64, u32, bool)]) -> result::Result<Request, Error> {
+        let guest_queue = GuestQ::new(GuestAddress(0x1_0000), mem, 16);
+        for (i, (addr, len, write)) in descs.iter().enumerate() {
+            let mut flags = if *write { VRING_DESC_F_WRITE } else { 0 };
+            if i + 1 < descs.len() {
+                flags |= VRING_DESC_F_NEXT;
+            }
+            guest_queue.dtable[i].set(*addr, *len, flags as u16, i as u16 + 1);
+        }
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.idx.set(1);
+
+        let mut queue = guest_queue.create_queue();
+        let mem = GuestMemoryAtomic::new(mem.clone());
+        let mut desc_chain = queue.pop_descriptor_chain(mem.memory()).unwrap();
+        Request::parse(&mut desc_chain, None)
+    }
+
+    fn create_memory(flags: u32) -> GuestMemoryMmap {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let hdr = VirtioI2cOutHdr {
+            addr: (0x50u16 << 1).to_le(),
+            padding: 0,
+            flags: flags.to_le(),
+        };
+        mem.write_obj(hdr, GuestAddress(HDR_ADDR)).unwrap();
+        mem.write_slice(&[1, 2, 3, 4, 5, 6], GuestAddress(BUF_ADDR))
+            .unwrap();
+        mem
+    }
+
+    #[test]
+    fn test_parse_write_request() {
+        let mem = create_memory(VIRTIO_I2C_FLAGS_FAIL_NEXT);
+        let request = parse(
+            &mem,
+            &[
+                (HDR_ADDR, HDR_LEN, false),
+                (BUF_ADDR, 4, false),
+                (BUF_ADDR + 4, 2, false),
+                (STATUS_ADDR, 1, true),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(request.head_index, 0);
+        assert_eq!(request.addr, 0x50);
+        assert_eq!(request.flags, VIRTIO_I2C_FLAGS_FAIL_NEXT);
+        assert_eq!(request.data, vec![1, 2, 3, 4, 5, 6]);
+        assert!(request.read_buffers.is_empty());
+        assert_eq!(request.status_addr, GuestAddress(STATUS_ADDR));
+        assert!(!request.too_long);
+    }
+
+    #[test]
+    fn test_parse_read_request() {
+        let mem = create_memory(VIRTIO_I2C_FLAGS_M_RD);
+        let request = parse(
+            &mem,
+            &[
+                (HDR_ADDR, HDR_LEN, false),
+                (BUF_ADDR, 2, true),
+                (BUF_ADDR + 0x100, 3, true),
+                (STATUS_ADDR, 1, true),
+            ],
+        )
+        .unwrap();
+
+        // The data is only read from the I2C device, not from the guest.
+        assert_eq!(request.data, vec![0; 5]);
+        assert_eq!(
+            request.read_buffers,
+            vec![
+                (GuestAddress(BUF_ADDR), 2),
+                (GuestAddress(BUF_ADDR + 0x100), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_zero_length_request() {
+        let mem = create_memory(0);
+        let request = parse(&mem, &[(HDR_ADDR, HDR_LEN, false), (STATUS_ADDR, 1, true)]).unwrap();
+
+        assert!(request.data.is_empty());
+        assert!(request.read_buffers.is_empty());
+        assert_eq!(request.status_addr, GuestAddress(STATUS_ADDR));
+    }
+
+    #[test]
+    fn test_parse_too_long_request() {
+        let mem = create_memory(0);
+        let request = parse(
+            &mem,
+            &[
+                (HDR_ADDR, HDR_LEN, false),
+                (BUF_ADDR, I2C_MAX_MSG_LEN as u32, false),
+                (BUF_ADDR + I2C_MAX_MSG_LEN as u64, 1, false),
+                (STATUS_ADDR, 1, true),
+            ],
+        )
+        .unwrap();
+
+        // The request is kept so that it can be failed.
+        assert!(request.too_long);
+        assert!(request.data.is_empty());
+        assert_eq!(request.addr, 0x50);
+    }
+
+    #[test]
+    fn test_parse_invalid_descriptors() {
+        let mem = create_memory(0);
+
+        // Missing status.
+        assert!(matches!(
+            parse(&mem, &[(HDR_ADDR, HDR_LEN, false)]),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        // Header too short.
+        assert!(matches!(
+            parse(
+                &mem,
+                &[(HDR_ADDR, HDR_LEN - 1, false), (STATUS_ADDR, 1, true)]
+            ),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        // Write-only header.
+        assert!(matches!(
+            parse(&mem, &[(HDR_ADDR, HDR_LEN, true), (STATUS_ADDR, 1, true)]),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        // Read-only status.
+        assert!(matches!(
+            parse(
+                &mem,
+                &[
+                    (HDR_ADDR, HDR_LEN, false),
+                    (BUF_ADDR, 4, false),
+                    (STATUS_ADDR, 1, false)
+                ]
+            ),
+            Err(Error::DescriptorChainTooShort)
+        ));
+        // Buffer outside of guest memory.
+        assert!(matches!(
+            parse(
+                &mem,
+                &[
+                    (HDR_ADDR, HDR_LEN, false),
+                    (0x10_0000, 4, false),
+                    (STATUS_ADDR, 1, true)
+                ]
+            ),
+            Err(Error::GuestMemoryRead(_))
+        ));
+    }
+}
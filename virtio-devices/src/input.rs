@@ -0,0 +1,772 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! virtio-input device, emulating either a keyboard or an absolute pointing
+//! device. The events are produced by the VMM through an [`InputEventSender`]
+//! and queued until the guest provides buffers to receive them.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccompiler::SeccompAction;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 64;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New descriptors are pending on the status queue.
+const STATUS_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// New input events have been queued by the VMM.
+const INPUT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+
+// Events kept while the guest doesn't provide buffers, newer events being
+// dropped beyond it.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// Configuration selectors
+const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// Event types and codes, see include/uapi/linux/input-event-codes.h in the
+// kernel code.
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const SYN_REPORT: u16 = 0x00;
+pub const REL_WHEEL: u16 = 0x08;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+// Largest key code reported by the keyboard.
+const KEY_MAX_CODE: u16 = 0xff;
+
+/// Largest coordinate reported by the tablet, on both axes.
+pub const INPUT_ABS_MAX: u32 = 0x7fff;
+
+const BUS_VIRTUAL: u16 = 0x06;
+const INPUT_VENDOR: u16 = 0x0627;
+const INPUT_VERSION: u16 = 0x0001;
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Failed to write to guest memory: {0}")]
+    GuestMemoryWrite(vm_memory::guest_memory::Error),
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+/// Input event, in the evdev format.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for InputEvent {}
+
+impl InputEvent {
+    pub fn new(type_: u16, code: u16, value: u32) -> Self {
+        InputEvent {
+            type_: type_.to_le(),
+            code: code.to_le(),
+            value: value.to_le(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioInputAbsInfo {
+    min: u32,
+    max: u32,
+    fuzz: u32,
+    flat: u32,
+    res: u32,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputAbsInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioInputDevIds {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputDevIds {}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct VirtioInputConfig {
+    select: u8,
+    subsel: u8,
+    size: u8,
+    reserved: [u8; 5],
+    u: [u8; 128],
+}
+
+impl Default for VirtioInputConfig {
+    fn default() -> Self {
+        VirtioInputConfig {
+            select: 0,
+            subsel: 0,
+            size: 0,
+            reserved: [0; 5],
+            u: [0; 128],
+        }
+    }
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputConfig {}
+
+/// Kind of input device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    Keyboard,
+    /// Pointing device reporting absolute coordinates, from 0 to
+    /// [`INPUT_ABS_MAX`], along with a wheel and three buttons.
+    Tablet,
+}
+
+impl InputKind {
+    fn name(&self) -> &'static str {
+        match self {
+            InputKind::Keyboard => "Cloud Hypervisor Keyboard",
+            InputKind::Tablet => "Cloud Hypervisor Tablet",
+        }
+    }
+
+    fn product(&self) -> u16 {
+        match self {
+            InputKind::Keyboard => 0x0001,
+            InputKind::Tablet => 0x0003,
+        }
+    }
+
+    // Codes of the given event type supported by the device.
+    fn codes(&self, type_: u16) -> Vec<u16> {
+        match (self, type_) {
+            (InputKind::Keyboard, EV_KEY) => (1..=KEY_MAX_CODE).collect(),
+            (InputKind::Tablet, EV_KEY) => vec![BTN_LEFT, BTN_RIGHT, BTN_MIDDLE],
+            (InputKind::Tablet, EV_REL) => vec![REL_WHEEL],
+            (InputKind::Tablet, EV_ABS) => vec![ABS_X, ABS_Y],
+            _ => Vec::new(),
+        }
+    }
+
+    // Fill the configuration union for the given selector, returning the
+    // size of the data.
+    fn config(&self, select: u8, subsel: u8, data: &mut [u8; 128]) -> usize {
+        match select {
+            VIRTIO_INPUT_CFG_ID_NAME if subsel == 0 => {
+                let name = self.name().as_bytes();
+                data[..name.len()].copy_from_slice(name);
+                name.len()
+            }
+            VIRTIO_INPUT_CFG_ID_DEVIDS if subsel == 0 => {
+                let ids = VirtioInputDevIds {
+                    bustype: BUS_VIRTUAL.to_le(),
+                    vendor: INPUT_VENDOR.to_le(),
+                    product: self.product().to_le(),
+                    version: INPUT_VERSION.to_le(),
+                };
+                data[..size_of::<VirtioInputDevIds>()].copy_from_slice(ids.as_slice());
+                size_of::<VirtioInputDevIds>()
+            }
+            VIRTIO_INPUT_CFG_EV_BITS => {
+                let mut size = 0;
+                for code in self.codes(subsel as u16) {
+                    let byte = code as usize / 8;
+                    data[byte] |= 1 << (code % 8);
+                    size = size.max(byte + 1);
+                }
+                size
+            }
+            VIRTIO_INPUT_CFG_ABS_INFO
+                if *self == InputKind::Tablet && self.codes(EV_ABS).contains(&(subsel as u16)) =>
+            {
+                let info = VirtioInputAbsInfo {
+                    min: 0,
+                    max: INPUT_ABS_MAX.to_le(),
+                    ..Default::default()
+                };
+                data[..size_of::<VirtioInputAbsInfo>()].copy_from_slice(info.as_slice());
+                size_of::<VirtioInputAbsInfo>()
+            }
+            // No serial number and no property.
+            VIRTIO_INPUT_CFG_UNSET | VIRTIO_INPUT_CFG_ID_SERIAL | VIRTIO_INPUT_CFG_PROP_BITS => 0,
+            _ => 0,
+        }
+    }
+}
+
+/// Handle used by the VMM to send events to an input device.
+#[derive(Clone)]
+pub struct InputEventSender {
+    events: Arc<Mutex<VecDeque<InputEvent>>>,
+    evt: Arc<EventFd>,
+}
+
+impl InputEventSender {
+    /// Send a group of events, followed by a synchronization event. The group
+    /// is dropped if the guest doesn't consume the events fast enough.
+    pub fn send(&self, events: &[InputEvent]) {
+        let mut pending = self.events.lock().unwrap();
+        if pending.len() + events.len() + 1 > MAX_PENDING_EVENTS {
+            debug!("Dropping input events, the guest is not reading them");
+            return;
+        }
+        pending.extend(events);
+        pending.push_back(InputEvent::new(EV_SYN, SYN_REPORT, 0));
+        drop(pending);
+
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed to signal input events: {:?}", e);
+        }
+    }
+}
+
+struct InputEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    event_queue: Queue,
+    status_queue: Option<Queue>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    event_queue_evt: EventFd,
+    status_queue_evt: Option<EventFd>,
+    input_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    events: Arc<Mutex<VecDeque<InputEvent>>>,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl InputEpollHandler {
+    // Move the pending events to the buffers provided by the guest, one
+    // event per buffer.
+    fn process_event_queue(&mut self) -> result::Result<bool, Error> {
+        let mut events = self.events.lock().unwrap();
+        let mut used_descs = false;
+        while !events.is_empty() {
+            let Some(mut desc_chain) = self.event_queue.pop_descriptor_chain(self.mem.memory())
+            else {
+                break;
+            };
+
+            let mut len = 0;
+            match desc_chain.next() {
+                Some(desc)
+                    if desc.is_write_only() && desc.len() as usize >= size_of::<InputEvent>() =>
+                {
+                    let event = events.pop_front().unwrap();
+                    desc_chain
+                        .memory()
+                        .write_obj(
+                            event,
+                            desc.addr()
+                                .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                        )
+                        .map_err(Error::GuestMemoryWrite)?;
+                    len = size_of::<InputEvent>() as u32;
+                }
+                _ => error!("Invalid virtio-input event buffer"),
+            }
+
+            self.event_queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    // Status updates, such as the LEDs of the keyboard, are ignored.
+    fn process_status_queue(&mut self) -> result::Result<bool, Error> {
+        let Some(queue) = self.status_queue.as_mut() else {
+            return Ok(false);
+        };
+        let mut used_descs = false;
+        while let Some(desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(queue_index))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.event_queue_evt.as_raw_fd(), EVENT_QUEUE_EVENT)?;
+        if let Some(status_queue_evt) = self.status_queue_evt.as_ref() {
+            helper.add_event(status_queue_evt.as_raw_fd(), STATUS_QUEUE_EVENT)?;
+        }
+        helper.add_event(self.input_evt.as_raw_fd(), INPUT_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for InputEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        let evt = match ev_type {
+            EVENT_QUEUE_EVENT => Some(&self.event_queue_evt),
+            STATUS_QUEUE_EVENT => self.status_queue_evt.as_ref(),
+            INPUT_EVENT => Some(&self.input_evt),
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {}",
+                    ev_type
+                )));
+            }
+        };
+        if let Some(evt) = evt {
+            evt.read().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to get event: {:?}", e))
+            })?;
+        }
+
+        let (queue_index, needs_notification) = if ev_type == STATUS_QUEUE_EVENT {
+            let needs_notification = self.process_status_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to process queue: {:?}", e))
+            })?;
+            (STATUS_QUEUE, needs_notification)
+        } else {
+            let needs_notification = self.process_event_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to process queue: {:?}", e))
+            })?;
+            (EVENT_QUEUE, needs_notification)
+        };
+        if needs_notification {
+            self.signal_used_queue(queue_index as u16).map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct InputState {
+    avail_features: u64,
+    acked_features: u64,
+}
+
+/// Virtio input device, fed with the events of an [`InputEventSender`].
+pub struct Input {
+    common: VirtioCommon,
+    id: String,
+    kind: InputKind,
+    config: VirtioInputConfig,
+    events: Arc<Mutex<VecDeque<InputEvent>>>,
+    input_evt: Arc<EventFd>,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+impl Input {
+    pub fn new(
+        id: String,
+        kind: InputKind,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+        state: Option<InputState>,
+    ) -> io::Result<Input> {
+        let (avail_features, acked_features, paused) = if let Some(state) = state {
+            info!("Restoring virtio-input {}", id);
+            (state.avail_features, state.acked_features, true)
+        } else {
+            let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+            if iommu {
+                avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+            }
+
+            (avail_features, 0, false)
+        };
+
+        Ok(Input {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Input as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                acked_features,
+                min_queues: 1,
+                paused: Arc::new(AtomicBool::new(paused)),
+                ..Default::default()
+            },
+            id,
+            kind,
+            config: VirtioInputConfig::default(),
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            input_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    /// Handle sending events to the device.
+    pub fn event_sender(&self) -> InputEventSender {
+        InputEventSender {
+            events: self.events.clone(),
+            evt: self.input_evt.clone(),
+        }
+    }
+
+    fn state(&self) -> InputState {
+        InputState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for Input {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only select and subsel are writable, selecting the data exposed
+        // through the rest of the configuration.
+        match (offset, data) {
+            (0, [select]) => self.config.select = *select,
+            (1, [subsel]) => self.config.subsel = *subsel,
+            (0, [select, subsel]) => {
+                self.config.select = *select;
+                self.config.subsel = *subsel;
+            }
+            _ => {
+                warn!("Invalid virtio-input config write: offset {offset}");
+                return;
+            }
+        }
+
+        self.config.u = [0; 128];
+        self.config.size =
+            self.kind
+                .config(self.config.select, self.config.subsel, &mut self.config.u)
+                as u8;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let (_, event_queue, event_queue_evt) = queues.remove(0);
+        let (status_queue, status_queue_evt) = match queues.pop() {
+            Some((_, queue, queue_evt)) => (Some(queue), Some(queue_evt)),
+            None => (None, None),
+        };
+
+        let mut handler = InputEpollHandler {
+            mem,
+            event_queue,
+            status_queue,
+            interrupt_cb,
+            event_queue_evt,
+            status_queue_evt,
+            input_evt: self.input_evt.try_clone().map_err(|e| {
+                error!("failed cloning input event fd: {}", e);
+                ActivateError::BadActivate
+            })?,
+            kill_evt,
+            pause_evt,
+            events: self.events.clone(),
+            access_platform: self.common.access_platform.clone(),
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioInput,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        // Events queued for the previous driver are meaningless.
+        self.events.lock().unwrap().clear();
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for Input {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Input {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+
+impl Transportable for Input {}
+impl Migratable for Input {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio_bindings::virtio_ring::VRING_DESC_F_WRITE;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    const BUF_ADDR: u64 = 0x4_0000;
+
+    struct NoopInterrupt {}
+
+    impl VirtioInterrupt for NoopInterrupt {
+        fn trigger(&self, _int_type: VirtioInterruptType) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn create_device(kind: InputKind) -> Input {
+        Input::new(
+            "input0".to_string(),
+            kind,
+            false,
+            SeccompAction::Allow,
+            EventFd::new(0).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    // Select the given configuration, returning its data.
+    fn read_config(input: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        input.write_config(0, &[select, subsel]);
+        let mut size = [0u8];
+        input.read_config(2, &mut size);
+        let mut data = vec![0u8; size[0] as usize];
+        input.read_config(8, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_config() {
+        let mut keyboard = create_device(InputKind::Keyboard);
+        assert_eq!(
+            read_config(&mut keyboard, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"Cloud Hypervisor Keyboard"
+        );
+        assert!(read_config(&mut keyboard, VIRTIO_INPUT_CFG_ID_SERIAL, 0).is_empty());
+
+        let ids = read_config(&mut keyboard, VIRTIO_INPUT_CFG_ID_DEVIDS, 0);
+        assert_eq!(ids, [0x06, 0, 0x27, 0x06, 0x01, 0, 0x01, 0]);
+
+        // All the keys but KEY_RESERVED are reported.
+        let keys = read_config(&mut keyboard, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(keys.len(), 32);
+        assert_eq!(keys[0], 0xfe);
+        assert!(keys[1..].iter().all(|b| *b == 0xff));
+        assert!(read_config(&mut keyboard, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8).is_empty());
+        assert!(read_config(&mut keyboard, VIRTIO_INPUT_CFG_ABS_INFO, ABS_X as u8).is_empty());
+
+        let mut tablet = create_device(InputKind::Tablet);
+        let buttons = read_config(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(buttons.len(), 35);
+        assert_eq!(buttons[34], 0x07);
+        assert_eq!(
+            read_config(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8),
+            [0, 0x01]
+        );
+        assert_eq!(
+            read_config(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            [0x03]
+        );
+        let abs_info = read_config(&mut tablet, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(abs_info.len(), size_of::<VirtioInputAbsInfo>());
+        assert_eq!(abs_info[4..8], INPUT_ABS_MAX.to_le_bytes());
+
+        // Selecting an unknown configuration clears the data.
+        assert!(read_config(&mut tablet, 0x42, 0).is_empty());
+        let mut data = [0xffu8; 4];
+        tablet.read_config(8, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+
+    #[test]
+    fn test_events() {
+        let input = create_device(InputKind::Tablet);
+        let sender = input.event_sender();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0x1_0000), &mem, 16);
+        for i in 0..4 {
+            guest_queue.dtable[i].set(
+                BUF_ADDR + i as u64 * 0x100,
+                size_of::<InputEvent>() as u32,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            );
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        // Only two buffers are available at first.
+        guest_queue.avail.idx.set(2);
+
+        let mut handler = InputEpollHandler {
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            event_queue: guest_queue.create_queue(),
+            status_queue: None,
+            interrupt_cb: Arc::new(NoopInterrupt {}),
+            event_queue_evt: EventFd::new(0).unwrap(),
+            status_queue_evt: None,
+            input_evt: input.input_evt.try_clone().unwrap(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+            events: input.events.clone(),
+            access_platform: None,
+        };
+
+        assert!(!handler.process_event_queue().unwrap());
+
+        let events = [
+            InputEvent::new(EV_ABS, ABS_X, 100),
+            InputEvent::new(EV_ABS, ABS_Y, 200),
+        ];
+        sender.send(&events);
+        assert_eq!(handler.input_evt.read().unwrap(), 1);
+        assert!(handler.process_event_queue().unwrap());
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        let read_event =
+            |i: u64| -> InputEvent { mem.read_obj(GuestAddress(BUF_ADDR + i * 0x100)).unwrap() };
+        assert_eq!(read_event(0), events[0]);
+        assert_eq!(read_event(1), events[1]);
+
+        // The synchronization event waits for the next buffer.
+        assert_eq!(input.events.lock().unwrap().len(), 1);
+        guest_queue.avail.idx.set(3);
+        assert!(handler.process_event_queue().unwrap());
+        assert_eq!(read_event(2), InputEvent::new(EV_SYN, SYN_REPORT, 0));
+        assert!(input.events.lock().unwrap().is_empty());
+
+        // Events are dropped once too many are pending.
+        let events = vec![InputEvent::new(EV_REL, REL_WHEEL, 1); MAX_PENDING_EVENTS - 1];
+        sender.send(&events);
+        assert_eq!(input.events.lock().unwrap().len(), MAX_PENDING_EVENTS);
+        sender.send(&events[..1]);
+        assert_eq!(input.events.lock().unwrap().len(), MAX_PENDING_EVENTS);
+    }
+}
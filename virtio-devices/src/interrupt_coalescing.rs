@@ -0,0 +1,175 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Interrupt coalescing of the used buffer notifications.
+//!
+//! Once a queue needs a used buffer notification, as decided by the
+//! VIRTIO_RING_F_EVENT_IDX suppression if negotiated, the interrupt can be
+//! deferred so that the buffers used in the meantime get reported along with
+//! a single interrupt.
+//!
+//! Parameters set by the driver, which only virtio-net allows through the
+//! VIRTIO_NET_CTRL_NOTF_COAL commands, are applied as is: the interrupt is
+//! deferred until the given number of buffers got used, or until the given
+//! delay expired. Otherwise, the coalescing adapts to the load of the queue:
+//! the interrupt is triggered right away if the previous notification was
+//! needed more than the maximum delay ago, keeping the latency low on an idle
+//! queue, and deferred for the maximum delay otherwise, preventing interrupt
+//! storms on a busy queue.
+
+use net_util::NotificationCoalescing;
+use std::io;
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
+
+pub(crate) struct InterruptCoalescer {
+    // Maximum delay of the adaptive coalescing.
+    max_delay: Option<Duration>,
+    // Parameters set by the driver, taking precedence when set.
+    driver_params: Option<Arc<NotificationCoalescing>>,
+    timer: TimerFd,
+    pending: bool,
+    pending_used: u32,
+    last_used: Wrapping<u16>,
+    last_notification: Option<Instant>,
+}
+
+impl InterruptCoalescer {
+    pub fn new(
+        max_delay: Option<Duration>,
+        driver_params: Option<Arc<NotificationCoalescing>>,
+    ) -> io::Result<Self> {
+        let timer = TimerFd::new()?;
+        // The timer might have been disarmed after it expired but before its
+        // event got handled, make sure reading it does not block then.
+        // SAFETY: FFI calls with a valid file descriptor.
+        let ret = unsafe {
+            let fd = timer.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(InterruptCoalescer {
+            max_delay,
+            driver_params,
+            timer,
+            pending: false,
+            pending_used: 0,
+            last_used: Wrapping(0),
+            last_notification: None,
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending {
+            self.timer.clear()?;
+            self.pending = false;
+        }
+        self.pending_used = 0;
+        Ok(())
+    }
+
+    /// Account for a needed used buffer notification, `next_used` being the
+    /// next index of the used ring. Returns whether the interrupt must be
+    /// triggered right away, or is left pending until the timer expires.
+    pub fn notify(&mut self, next_used: u16) -> io::Result<bool> {
+        let now = Instant::now();
+        let (max_used, delay) = match self.driver_params.as_ref().and_then(|p| p.get()) {
+            Some((max_packets, usecs)) => (max_packets, Duration::from_micros(usecs.into())),
+            None => match (self.max_delay, self.last_notification) {
+                (Some(max_delay), Some(last)) if now - last < max_delay => (0, max_delay),
+                _ => (0, Duration::ZERO),
+            },
+        };
+        self.last_notification = Some(now);
+
+        self.pending_used += u32::from((Wrapping(next_used) - self.last_used).0);
+        self.last_used = Wrapping(next_used);
+
+        if delay.is_zero() || (max_used != 0 && self.pending_used >= max_used) {
+            self.flush()?;
+            return Ok(true);
+        }
+
+        if !self.pending {
+            self.timer.reset(delay, None)?;
+            self.pending = true;
+        }
+
+        Ok(false)
+    }
+
+    /// Handle the expiration of the timer, returning whether the pending
+    /// interrupt must be triggered.
+    pub fn expired(&mut self) -> io::Result<bool> {
+        if let Err(e) = self.timer.wait() {
+            let e = io::Error::from(e);
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+
+        let pending = self.pending;
+        self.pending = false;
+        self.pending_used = 0;
+        Ok(pending)
+    }
+}
+
+impl AsRawFd for InterruptCoalescer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_adaptive_coalescing() {
+        let mut coalescer = InterruptCoalescer::new(Some(Duration::from_millis(50)), None).unwrap();
+
+        // An idle queue gets notified right away.
+        assert!(coalescer.notify(1).unwrap());
+        // A busy one has its notifications deferred.
+        assert!(!coalescer.notify(2).unwrap());
+        assert!(!coalescer.notify(3).unwrap());
+        sleep(Duration::from_millis(60));
+        assert!(coalescer.expired().unwrap());
+        // Spurious expirations are ignored.
+        assert!(!coalescer.expired().unwrap());
+
+        // The queue is idle again.
+        sleep(Duration::from_millis(60));
+        assert!(coalescer.notify(4).unwrap());
+    }
+
+    #[test]
+    fn test_driver_coalescing() {
+        let params = Arc::new(NotificationCoalescing::default());
+        let mut coalescer = InterruptCoalescer::new(None, Some(params.clone())).unwrap();
+
+        // No coalescing until the driver sets the parameters.
+        assert!(coalescer.notify(1).unwrap());
+        assert!(coalescer.notify(2).unwrap());
+
+        params.set(4, 1_000_000);
+        assert!(!coalescer.notify(4).unwrap());
+        assert!(coalescer.notify(6).unwrap());
+
+        // Disabled by the driver.
+        params.set(0, 0);
+        assert!(coalescer.notify(7).unwrap());
+        assert!(!coalescer.expired().unwrap());
+    }
+}
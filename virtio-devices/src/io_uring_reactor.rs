@@ -0,0 +1,257 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! io_uring based backend of the EpollHelper.
+//!
+//! Every file descriptor registered on the helper is polled through a
+//! one-shot IORING_OP_POLL_ADD request, which is re-armed once the event has
+//! been handled. Re-arming the polls and waiting for the next events is then
+//! achieved with a single io_uring_enter() call, instead of the epoll_wait()
+//! plus the epoll_ctl() calls needed by the epoll backend, and the timeout is
+//! passed along with the wait itself. Re-arming a one-shot poll checks the
+//! readiness of the file descriptor, which provides the same level-triggered
+//! semantics as epoll. Edge-triggered registrations are not supported, and
+//! are left to the epoll file descriptor of the helper, which the reactor
+//! polls as any other file descriptor.
+//!
+//! Each reactor belongs to the EpollHelper of a single virtio-block or
+//! virtio-net queue thread. There are no iothreads shared between devices
+//! to share it with.
+
+use io_uring::{opcode, types, IoUring, Probe};
+use std::collections::BTreeMap;
+use std::io;
+use std::os::unix::io::RawFd;
+
+// Tokens are allocated from 1, leaving 0 to the internal requests whose
+// completion must be ignored.
+const INTERNAL_TOKEN: u64 = 0;
+
+const RING_ENTRIES: u32 = 64;
+
+struct Registration {
+    fd: RawFd,
+    id: u16,
+    events: epoll::Events,
+    armed: bool,
+}
+
+pub(crate) struct IoUringReactor {
+    io_uring: IoUring,
+    registrations: BTreeMap<u64, Registration>,
+    next_token: u64,
+}
+
+impl IoUringReactor {
+    /// Create the reactor, failing if the host does not support the
+    /// io_uring operations and features it relies on.
+    pub fn new() -> io::Result<Self> {
+        let io_uring = IoUring::new(RING_ENTRIES)?;
+
+        if !io_uring.params().is_feature_ext_arg() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "missing IORING_FEAT_EXT_ARG",
+            ));
+        }
+
+        let mut probe = Probe::new();
+        io_uring.submitter().register_probe(&mut probe)?;
+        if !probe.is_supported(opcode::PollAdd::CODE)
+            || !probe.is_supported(opcode::PollRemove::CODE)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "missing IORING_OP_POLL_ADD or IORING_OP_POLL_REMOVE",
+            ));
+        }
+
+        Ok(IoUringReactor {
+            io_uring,
+            registrations: BTreeMap::new(),
+            next_token: INTERNAL_TOKEN + 1,
+        })
+    }
+
+    fn push(&mut self, sqe: &io_uring::squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: the polled file descriptors are owned by the device
+            // threads, which are expected to unregister them before they
+            // get closed.
+            if unsafe { self.io_uring.submission().push(sqe) }.is_ok() {
+                return Ok(());
+            }
+            // The submission queue is full, flush it to the kernel.
+            self.io_uring.submit()?;
+        }
+    }
+
+    fn cancel(&mut self, token: u64) -> io::Result<()> {
+        let sqe = opcode::PollRemove::new(token)
+            .build()
+            .user_data(INTERNAL_TOKEN);
+        self.push(&sqe)
+    }
+
+    pub fn add(&mut self, fd: RawFd, id: u16, events: epoll::Events) {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.registrations.insert(
+            token,
+            Registration {
+                fd,
+                id,
+                events,
+                armed: false,
+            },
+        );
+    }
+
+    pub fn contains(&self, fd: RawFd) -> bool {
+        self.registrations.values().any(|r| r.fd == fd)
+    }
+
+    /// Whether some completions are waiting to be reaped by `wait()`.
+    pub fn has_completions(&mut self) -> bool {
+        !self.io_uring.completion().is_empty()
+    }
+
+    pub fn del(&mut self, fd: RawFd) -> io::Result<()> {
+        let tokens: Vec<u64> = self
+            .registrations
+            .iter()
+            .filter(|(_, r)| r.fd == fd)
+            .map(|(token, _)| *token)
+            .collect();
+        if tokens.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        for token in tokens {
+            if self.registrations.remove(&token).unwrap().armed {
+                self.cancel(token)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn modify(&mut self, fd: RawFd, id: u16, events: epoll::Events) -> io::Result<()> {
+        self.del(fd)?;
+        self.add(fd, id, events);
+        Ok(())
+    }
+
+    /// Arm the pending polls and wait for events, filling `events` with the
+    /// ones which triggered. A negative timeout means waiting forever, and
+    /// no event is returned if the timeout expires.
+    pub fn wait(&mut self, timeout: i32, events: &mut [epoll::Event]) -> io::Result<usize> {
+        let mut to_arm = Vec::new();
+        for (token, registration) in self.registrations.iter_mut() {
+            if !registration.armed {
+                registration.armed = true;
+                to_arm.push(
+                    opcode::PollAdd::new(
+                        types::Fd(registration.fd),
+                        // Only keep the poll mask, EPOLLONESHOT is handled
+                        // by the reactor.
+                        registration.events.bits() & 0xffff,
+                    )
+                    .build()
+                    .user_data(*token),
+                );
+            }
+        }
+        for sqe in to_arm {
+            self.push(&sqe)?;
+        }
+
+        let submitter = self.io_uring.submitter();
+        let res = if timeout < 0 {
+            submitter.submit_and_wait(1)
+        } else {
+            let ts = types::Timespec::new()
+                .sec(timeout as u64 / 1000)
+                .nsec((timeout as u32 % 1000) * 1_000_000);
+            let args = types::SubmitArgs::new().timespec(&ts);
+            submitter.submit_with_args(1, &args)
+        };
+        match res {
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ETIME) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut count = 0;
+        let mut completion = self.io_uring.completion();
+        while count < events.len() {
+            let Some(cqe) = completion.next() else {
+                break;
+            };
+            let Some(registration) = self.registrations.get_mut(&cqe.user_data()) else {
+                // Either an internal request, or a poll which got removed.
+                continue;
+            };
+
+            if cqe.result() < 0 {
+                return Err(io::Error::from_raw_os_error(-cqe.result()));
+            }
+
+            // Honor EPOLLONESHOT by leaving the poll disarmed until it gets
+            // modified.
+            if !registration.events.contains(epoll::Events::EPOLLONESHOT) {
+                registration.armed = false;
+            }
+            events[count] = epoll::Event::new(
+                epoll::Events::from_bits_truncate(cqe.result() as u32),
+                registration.id as u64,
+            );
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::eventfd::EventFd;
+
+    #[test]
+    fn test_io_uring_reactor() {
+        let Ok(mut reactor) = IoUringReactor::new() else {
+            // io_uring is not available on this host
+            return;
+        };
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 8];
+
+        let evt0 = EventFd::new(0).unwrap();
+        let evt1 = EventFd::new(0).unwrap();
+        reactor.add(evt0.as_raw_fd(), 3, epoll::Events::EPOLLIN);
+        reactor.add(evt1.as_raw_fd(), 4, epoll::Events::EPOLLIN);
+
+        // Nothing is ready, the timeout expires.
+        assert_eq!(reactor.wait(10, &mut events).unwrap(), 0);
+
+        evt1.write(1).unwrap();
+        assert_eq!(reactor.wait(-1, &mut events).unwrap(), 1);
+        assert_eq!(events[0].data, 4);
+
+        // Level-triggered: the event is reported until it gets consumed.
+        assert_eq!(reactor.wait(10, &mut events).unwrap(), 1);
+        assert_eq!(events[0].data, 4);
+        evt1.read().unwrap();
+        assert_eq!(reactor.wait(10, &mut events).unwrap(), 0);
+
+        // Removed file descriptors are not reported anymore.
+        reactor.del(evt0.as_raw_fd()).unwrap();
+        evt0.write(1).unwrap();
+        assert_eq!(reactor.wait(10, &mut events).unwrap(), 0);
+        assert!(!reactor.contains(evt0.as_raw_fd()));
+        assert!(reactor.del(evt0.as_raw_fd()).is_err());
+    }
+}
@@ -23,8 +23,16 @@ use thiserror::Error;
 mod device;
 pub mod balloon;
 pub mod block;
+mod completion_polling;
 mod console;
 pub mod epoll_helper;
+mod fs9p;
+mod gpu;
+mod i2c;
+mod input;
+mod interrupt_coalescing;
+#[cfg(feature = "io_uring")]
+mod io_uring_reactor;
 mod iommu;
 pub mod mem;
 pub mod net;
@@ -38,7 +46,7 @@ pub mod vhost_user;
 pub mod vsock;
 pub mod watchdog;
 
-pub use self::balloon::Balloon;
+pub use self::balloon::{Balloon, BalloonPolicy};
 pub use self::block::{Block, BlockState};
 pub use self::console::{Console, ConsoleResizer, Endpoint};
 pub use self::device::{
@@ -48,9 +56,16 @@ pub use self::device::{
 pub use self::epoll_helper::{
     EpollHelper, EpollHelperError, EpollHelperHandler, EPOLL_HELPER_EVENT_LAST,
 };
+pub use self::fs9p::Fs9p;
+pub use self::gpu::{Framebuffer, Gpu, GpuDisplay, GpuState, Rect, GPU_MAX_DISPLAY_SIZE};
+pub use self::i2c::I2c;
+pub use self::input::{
+    Input, InputEvent, InputEventSender, InputKind, InputState, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE,
+    BTN_RIGHT, EV_ABS, EV_KEY, EV_REL, INPUT_ABS_MAX, REL_WHEEL,
+};
 pub use self::iommu::{AccessPlatformMapping, Iommu, IommuMapping};
 pub use self::mem::{BlocksState, Mem, VirtioMemMappingSource, VIRTIO_MEM_ALIGN_SIZE};
-pub use self::net::{Net, NetCtrlEpollHandler};
+pub use self::net::{Net, NetCtrlEpollHandler, NetIoUring};
 pub use self::pmem::Pmem;
 pub use self::rng::Rng;
 pub use self::vdpa::{Vdpa, VdpaDmaMapping};
@@ -74,6 +89,7 @@ const VIRTIO_F_RING_INDIRECT_DESC: u32 = 28;
 const VIRTIO_F_RING_EVENT_IDX: u32 = 29;
 const VIRTIO_F_VERSION_1: u32 = 32;
 const VIRTIO_F_IOMMU_PLATFORM: u32 = 33;
+const VIRTIO_F_RING_PACKED: u32 = 34;
 const VIRTIO_F_IN_ORDER: u32 = 35;
 const VIRTIO_F_ORDER_PLATFORM: u32 = 36;
 #[allow(dead_code)]
@@ -98,6 +114,14 @@ pub enum ActivateError {
     CreateRateLimiter(std::io::Error),
     #[error("Failed to activate the vDPA device: {0}")]
     ActivateVdpa(vdpa::Error),
+    #[error("Failed to setup the vhost-net backend: {0}")]
+    VhostNetSetup(vhost::Error),
+    #[error("Failed to setup the packed control queue: {0:?}")]
+    CreatePackedQueue(vm_virtio::packed::Error),
+    #[error("Failed to create interrupt coalescer: {0}")]
+    CreateInterruptCoalescer(std::io::Error),
+    #[error("Failed to create balloon policy timer: {0}")]
+    CreatePolicyTimer(std::io::Error),
 }
 
 pub type ActivateResult = std::result::Result<(), ActivateError>;
@@ -114,6 +138,8 @@ pub enum Error {
     VhostUserUpdateMemory(vhost_user::Error),
     #[error("Failed to add memory region vhost-user: {0}")]
     VhostUserAddMemoryRegion(vhost_user::Error),
+    #[error("Failed to update memory vhost-net: {0}")]
+    VhostNetUpdateMemory(vhost::Error),
     #[error("Failed to set shared memory region")]
     SetShmRegionsNotSupported,
     #[error("Failed to process net queue: {0}")]
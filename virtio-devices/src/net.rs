@@ -11,20 +11,25 @@ use super::{
     RateLimiterConfig, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
     EPOLL_HELPER_EVENT_LAST,
 };
+use crate::interrupt_coalescing::InterruptCoalescer;
 use crate::seccomp_filters::Thread;
-use crate::thread_helper::spawn_virtio_thread;
-use crate::GuestMemoryMmap;
+use crate::thread_helper::{set_thread_affinity, spawn_virtio_thread};
 use crate::VirtioInterrupt;
+use crate::{GuestMemoryMmap, GuestRegionMmap};
 use anyhow::anyhow;
 #[cfg(not(fuzzing))]
 use net_util::virtio_features_to_tap_offload;
 use net_util::CtrlQueue;
+#[cfg(feature = "io_uring")]
+use net_util::TapIoUring;
 use net_util::{
-    build_net_config_space, build_net_config_space_with_mq, open_tap, MacAddr, NetCounters,
-    NetQueuePair, OpenTapError, RxVirtio, Tap, TapError, TxVirtio, VirtioNetConfig,
+    build_net_config_space, build_net_config_space_with_mq, open_tap, open_xdp, MacAddr,
+    NetCounters, NetQueuePair, NetQueuePairError, NotificationCoalescing, OpenTapError, RxVirtio,
+    Tap, TapError, TxVirtio, VirtioNetConfig, XdpError, XdpSocket, VIRTIO_NET_F_NOTF_COAL,
 };
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::num::Wrapping;
@@ -32,15 +37,22 @@ use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use thiserror::Error;
+use vhost::net::VhostNet;
+use vhost::vhost_kern::net::Net as VhostKernNet;
+use vhost::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
 use virtio_bindings::virtio_config::*;
 use virtio_bindings::virtio_net::*;
 use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use virtio_queue::{Queue, QueueT};
-use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic};
+use vm_memory::{
+    Address, ByteValued, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryRegion,
+};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::packed::PackedQueue;
 use vm_virtio::AccessPlatform;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -58,6 +70,9 @@ pub struct NetCtrlEpollHandler {
     pub ctrl_q: CtrlQueue,
     pub queue_evt: EventFd,
     pub queue: Queue,
+    // Control queue processed in place of `queue` when VIRTIO_F_RING_PACKED
+    // is negotiated.
+    pub packed_queue: Option<PackedQueue>,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub queue_index: u16,
@@ -102,15 +117,32 @@ impl EpollHelperHandler for NetCtrlEpollHandler {
                         e
                     ))
                 })?;
-                self.ctrl_q
-                    .process(mem.deref(), &mut self.queue, self.access_platform.as_ref())
-                    .map_err(|e| {
-                        EpollHelperError::HandleEvent(anyhow!(
-                            "Failed to process control queue: {:?}",
-                            e
-                        ))
-                    })?;
-                match self.queue.needs_notification(mem.deref()) {
+                let needs_notification = if let Some(queue) = self.packed_queue.as_mut() {
+                    self.ctrl_q
+                        .process_packed(mem.deref(), queue, self.access_platform.as_ref())
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to process control queue: {:?}",
+                                e
+                            ))
+                        })?;
+                    queue
+                        .needs_notification(mem.deref())
+                        .map_err(|e| anyhow!("{:?}", e))
+                } else {
+                    self.ctrl_q
+                        .process(mem.deref(), &mut self.queue, self.access_platform.as_ref())
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to process control queue: {:?}",
+                                e
+                            ))
+                        })?;
+                    self.queue
+                        .needs_notification(mem.deref())
+                        .map_err(|e| anyhow!("{}", e))
+                };
+                match needs_notification {
                     Ok(true) => {
                         self.signal_used_queue(self.queue_index).map_err(|e| {
                             EpollHelperError::HandleEvent(anyhow!(
@@ -152,6 +184,10 @@ pub const TX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 pub const RX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 // New 'wake up' event from the tx rate limiter
 pub const TX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// Deferred used buffer notification of the rx queue
+pub const RX_COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// Deferred used buffer notification of the tx queue
+pub const TX_COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -161,12 +197,89 @@ pub enum Error {
     TapError(TapError),
     #[error("Error calling dup() on tap fd: {0}")]
     DuplicateTapFd(std::io::Error),
+    #[error("Failed to open AF_XDP sockets: {0}")]
+    OpenXdp(XdpError),
+    #[error("Failed to open the vhost-net device: {0}")]
+    OpenVhostNet(vhost::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+type VhostNetBackend = VhostKernNet<GuestMemoryAtomic<GuestMemoryMmap>>;
+
+// Module parameter letting vhost-net transmit the frames without copying
+// them.
+const VHOST_NET_ZCOPYTX_PARAM: &str = "/sys/module/vhost_net/parameters/experimental_zcopytx";
+
+// The kernel accesses the guest memory through the VMM mapping, which is all
+// the memory table of a vhost-net backend describes.
+fn vhost_net_memory_region(region: &GuestRegionMmap) -> VhostUserMemoryRegionInfo {
+    VhostUserMemoryRegionInfo {
+        guest_phys_addr: region.start_addr().raw_value(),
+        memory_size: region.len(),
+        userspace_addr: region.as_ptr() as u64,
+        mmap_offset: 0,
+        mmap_handle: -1,
+    }
+}
+
+// Hand a queue pair over to a vhost-net backend bound to the TAP queue. The
+// kernel then transmits the frames straight from the guest memory, copying
+// only the small ones, when zero-copy transmit is enabled on the host.
+fn activate_vhost_net(
+    mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+    mem_table: &[VhostUserMemoryRegionInfo],
+    acked_features: u64,
+    tap: &Tap,
+    vrings: &[(Queue, EventFd, EventFd)],
+) -> vhost::Result<VhostNetBackend> {
+    let backend = VhostNetBackend::new(mem.clone())?;
+    backend.set_owner()?;
+    // The offloads are programmed on the TAP interface, vhost-net only
+    // handles the transport features.
+    backend.set_features(acked_features & backend.get_features()?)?;
+    backend.set_mem_table(mem_table)?;
+
+    for (queue_index, (queue, kick_evt, call_evt)) in vrings.iter().enumerate() {
+        let queue_size = queue.size();
+        backend.set_vring_num(queue_index, queue_size)?;
+        let config_data = VringConfigData {
+            queue_max_size: queue.max_size(),
+            queue_size,
+            flags: 0u32,
+            desc_table_addr: queue.desc_table(),
+            used_ring_addr: queue.used_ring(),
+            avail_ring_addr: queue.avail_ring(),
+            log_addr: None,
+        };
+        backend.set_vring_addr(queue_index, &config_data)?;
+        backend.set_vring_base(queue_index, queue.next_avail())?;
+        backend.set_vring_call(queue_index, call_evt)?;
+        backend.set_vring_kick(queue_index, kick_evt)?;
+        backend.set_backend(queue_index, Some(tap.as_file()))?;
+    }
+
+    Ok(backend)
+}
+
+/// How the frames are exchanged with the TAP interfaces
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NetIoUring {
+    /// One `readv()`/`writev()` system call per frame
+    #[default]
+    Disabled,
+    /// Frames submitted in batches through io_uring
+    Enabled,
+    /// Frames submitted in batches through io_uring, the guest memory being
+    /// registered as fixed buffers
+    RegisteredBuffers,
+}
+
 struct NetEpollHandler {
     net: NetQueuePair,
+    // Batches the TAP I/O when set
+    #[cfg(feature = "io_uring")]
+    io_uring: Option<TapIoUring>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     kill_evt: EventFd,
@@ -174,6 +287,11 @@ struct NetEpollHandler {
     queue_index_base: u16,
     queue_pair: (Queue, Queue),
     queue_evt_pair: (EventFd, EventFd),
+    host_cpus: Option<Vec<usize>>,
+    busy_poll: Option<Duration>,
+    io_uring_event_loop: bool,
+    rx_coalescer: Option<InterruptCoalescer>,
+    tx_coalescer: Option<InterruptCoalescer>,
     // Always generate interrupts until the driver has signalled to the device.
     // This mitigates a problem with interrupts from tap events being "lost" upon
     // a restore as the vCPU thread isn't ready to handle the interrupt. This causes
@@ -181,6 +299,20 @@ struct NetEpollHandler {
     driver_awake: bool,
 }
 
+// Whether the needed used buffer notification of the queue must be sent
+// right away, or got deferred by the interrupt coalescing.
+fn coalesce_notification(
+    coalescer: &mut Option<InterruptCoalescer>,
+    queue: &Queue,
+) -> result::Result<bool, DeviceError> {
+    match coalescer {
+        Some(coalescer) => coalescer
+            .notify(queue.next_used())
+            .map_err(DeviceError::IoError),
+        None => Ok(true),
+    }
+}
+
 impl NetEpollHandler {
     fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
         self.interrupt_cb
@@ -191,6 +323,20 @@ impl NetEpollHandler {
             })
     }
 
+    fn handle_coalescing_event(&mut self, queue_index: u16) -> result::Result<(), DeviceError> {
+        let coalescer = if queue_index == self.queue_index_base {
+            &mut self.rx_coalescer
+        } else {
+            &mut self.tx_coalescer
+        };
+        if let Some(coalescer) = coalescer {
+            if coalescer.expired().map_err(DeviceError::IoError)? {
+                self.signal_used_queue(queue_index)?;
+            }
+        }
+        Ok(())
+    }
+
     fn handle_rx_event(&mut self) -> result::Result<(), DeviceError> {
         let queue_evt = &self.queue_evt_pair.0;
         if let Err(e) = queue_evt.read() {
@@ -220,11 +366,37 @@ impl NetEpollHandler {
         Ok(())
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
-        if self
-            .net
+    fn net_process_tx(&mut self) -> result::Result<bool, NetQueuePairError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(io_uring) = &mut self.io_uring {
+            return self.net.process_tx_io_uring(
+                io_uring,
+                &self.mem.memory(),
+                &mut self.queue_pair.1,
+            );
+        }
+
+        self.net
             .process_tx(&self.mem.memory(), &mut self.queue_pair.1)
-            .map_err(DeviceError::NetQueuePair)?
+    }
+
+    fn net_process_rx(&mut self) -> result::Result<bool, NetQueuePairError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(io_uring) = &mut self.io_uring {
+            return self.net.process_rx_io_uring(
+                io_uring,
+                &self.mem.memory(),
+                &mut self.queue_pair.0,
+            );
+        }
+
+        self.net
+            .process_rx(&self.mem.memory(), &mut self.queue_pair.0)
+    }
+
+    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+        if (self.net_process_tx().map_err(DeviceError::NetQueuePair)?
+            && coalesce_notification(&mut self.tx_coalescer, &self.queue_pair.1)?)
             || !self.driver_awake
         {
             self.signal_used_queue(self.queue_index_base + 1)?;
@@ -250,10 +422,8 @@ impl NetEpollHandler {
     }
 
     fn handle_rx_tap_event(&mut self) -> result::Result<(), DeviceError> {
-        if self
-            .net
-            .process_rx(&self.mem.memory(), &mut self.queue_pair.0)
-            .map_err(DeviceError::NetQueuePair)?
+        if (self.net_process_rx().map_err(DeviceError::NetQueuePair)?
+            && coalesce_notification(&mut self.rx_coalescer, &self.queue_pair.0)?)
             || !self.driver_awake
         {
             self.signal_used_queue(self.queue_index_base)?;
@@ -270,6 +440,9 @@ impl NetEpollHandler {
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        if self.io_uring_event_loop {
+            helper.use_io_uring();
+        }
         helper.add_event(self.queue_evt_pair.0.as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evt_pair.1.as_raw_fd(), TX_QUEUE_EVENT)?;
         if let Some(rate_limiter) = &self.net.rx_rate_limiter {
@@ -278,6 +451,27 @@ impl NetEpollHandler {
         if let Some(rate_limiter) = &self.net.tx_rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), TX_RATE_LIMITER_EVENT)?;
         }
+        if let Some(coalescer) = &self.rx_coalescer {
+            helper.add_event(coalescer.as_raw_fd(), RX_COALESCING_EVENT)?;
+        }
+        if let Some(coalescer) = &self.tx_coalescer {
+            helper.add_event(coalescer.as_raw_fd(), TX_COALESCING_EVENT)?;
+        }
+
+        if let Some(budget) = self.busy_poll {
+            helper.set_busy_poll(budget);
+        }
+
+        // Schedule the thread to run on the expected CPU set
+        if let Some(host_cpus) = self.host_cpus.as_ref() {
+            if let Err(e) = set_thread_affinity(host_cpus) {
+                error!(
+                    "Failed scheduling the queue pair thread {} on the expected CPU set: {}",
+                    self.queue_index_base / 2,
+                    e
+                )
+            }
+        }
 
         let mem = self.mem.memory();
         // If there are some already available descriptors on the RX queue,
@@ -398,6 +592,24 @@ impl EpollHelperHandler for NetEpollHandler {
                     )));
                 }
             }
+            RX_COALESCING_EVENT => {
+                self.handle_coalescing_event(self.queue_index_base)
+                    .map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Error signalling RX queue (coalescing event): {:?}",
+                            e
+                        ))
+                    })?;
+            }
+            TX_COALESCING_EVENT => {
+                self.handle_coalescing_event(self.queue_index_base + 1)
+                    .map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Error signalling TX queue (coalescing event): {:?}",
+                            e
+                        ))
+                    })?;
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unexpected event: {}",
@@ -407,6 +619,30 @@ impl EpollHelperHandler for NetEpollHandler {
         }
         Ok(())
     }
+
+    fn poll(&mut self, _helper: &mut EpollHelper) -> result::Result<bool, EpollHelperError> {
+        // Only the TX queue is polled, the frames received on the TAP
+        // interface being reported through RX_TAP_EVENT already.
+        let rate_limit_reached = self
+            .net
+            .tx_rate_limiter
+            .as_ref()
+            .map_or(false, |r| r.is_blocked());
+        let tx_avail = !rate_limit_reached
+            && self
+                .queue_pair
+                .1
+                .avail_idx(self.mem.memory().deref(), Ordering::Acquire)
+                .map_err(EpollHelperError::QueueRingIndex)?
+                != Wrapping(self.queue_pair.1.next_avail());
+        if tx_avail {
+            self.process_tx().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Error processing TX queue: {:?}", e))
+            })?;
+        }
+
+        Ok(tx_avail)
+    }
 }
 
 pub struct Net {
@@ -419,6 +655,28 @@ pub struct Net {
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
     exit_evt: EventFd,
+    io_uring: NetIoUring,
+    queue_affinity: BTreeMap<u16, Vec<usize>>,
+    busy_poll: Option<Duration>,
+    coalescing: Option<Duration>,
+    io_uring_event_loop: bool,
+    // Coalescing parameters set by the driver, shared with the queue threads
+    // and kept across snapshots and migrations.
+    rx_coalescing: Arc<NotificationCoalescing>,
+    tx_coalescing: Arc<NotificationCoalescing>,
+    // AF_XDP sockets backing the queue pairs instead of the taps, which are
+    // then only used to poll them.
+    xdp_sockets: Vec<Arc<Mutex<XdpSocket>>>,
+    // Set while the guest is asked to announce itself on the network
+    announce: Arc<AtomicBool>,
+    // Whether the guest must be asked to announce itself once resumed, the
+    // device having been restored or migrated
+    announce_on_resume: bool,
+    // Whether the queue pairs are handed over to vhost-net backends, one per
+    // TAP queue, rather than processed by the VMM.
+    vhost_net: bool,
+    vhost_net_backends: Vec<VhostNetBackend>,
+    vhost_net_mem_table: Vec<VhostUserMemoryRegionInfo>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -427,6 +685,21 @@ pub struct NetState {
     pub acked_features: u64,
     pub config: VirtioNetConfig,
     pub queue_size: Vec<u16>,
+    /// Maximum number of packets and delay in microseconds set by the driver
+    /// for the RX and TX queues notifications.
+    #[serde(default)]
+    pub rx_coalescing: Option<(u32, u32)>,
+    #[serde(default)]
+    pub tx_coalescing: Option<(u32, u32)>,
+}
+
+// Create the coalescing parameters of a queue, as optionally restored.
+fn new_notification_coalescing(params: Option<(u32, u32)>) -> Arc<NotificationCoalescing> {
+    let coalescing = NotificationCoalescing::default();
+    if let Some((max_packets, max_usecs)) = params {
+        coalescing.set(max_packets, max_usecs);
+    }
+    Arc::new(coalescing)
 }
 
 impl Net {
@@ -446,11 +719,21 @@ impl Net {
         offload_tso: bool,
         offload_ufo: bool,
         offload_csum: bool,
+        io_uring: NetIoUring,
+        queue_affinity: BTreeMap<u16, Vec<usize>>,
+        busy_poll: Option<Duration>,
+        coalescing: Option<Duration>,
+        io_uring_event_loop: bool,
     ) -> Result<Self> {
         assert!(!taps.is_empty());
 
         let mtu = taps[0].mtu().map_err(Error::TapError)? as u16;
 
+        let restoring = state.is_some();
+        let (rx_coalescing, tx_coalescing) = state
+            .as_ref()
+            .map(|state| (state.rx_coalescing, state.tx_coalescing))
+            .unwrap_or_default();
         let (avail_features, acked_features, config, queue_sizes, paused) =
             if let Some(state) = state {
                 info!("Restoring virtio-net {}", id);
@@ -490,6 +773,13 @@ impl Net {
                 }
 
                 avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
+                // Let the guest announce itself on the network after a
+                // restore or a migration, which relies on the status field.
+                avail_features |= 1 << VIRTIO_NET_F_STATUS | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
+                // Let the driver set its own coalescing parameters.
+                if coalescing.is_some() {
+                    avail_features |= 1 << VIRTIO_NET_F_NOTF_COAL;
+                }
                 let queue_num = num_queues + 1;
 
                 let mut config = VirtioNetConfig::default();
@@ -509,6 +799,7 @@ impl Net {
                         &mut avail_features,
                     );
                 }
+                config.status = VIRTIO_NET_S_LINK_UP as u16;
 
                 (
                     avail_features,
@@ -538,9 +829,43 @@ impl Net {
             seccomp_action,
             rate_limiter_config,
             exit_evt,
+            io_uring,
+            queue_affinity,
+            busy_poll,
+            coalescing,
+            io_uring_event_loop,
+            rx_coalescing: new_notification_coalescing(rx_coalescing),
+            tx_coalescing: new_notification_coalescing(tx_coalescing),
+            xdp_sockets: Vec::new(),
+            announce: Arc::new(AtomicBool::new(false)),
+            announce_on_resume: restoring,
+            vhost_net: false,
+            vhost_net_backends: Vec::new(),
+            vhost_net_mem_table: Vec::new(),
         })
     }
 
+    /// Process the queue pairs through vhost-net, which transmits the frames
+    /// without copying them if the host enabled the zero-copy transmit of
+    /// the vhost_net module.
+    pub fn enable_vhost_net(&mut self) -> Result<()> {
+        // Check the host provides vhost-net before the guest drives the
+        // device.
+        VhostNetBackend::new(GuestMemoryAtomic::new(GuestMemoryMmap::new()))
+            .map_err(Error::OpenVhostNet)?;
+
+        let zcopytx = std::fs::read_to_string(VHOST_NET_ZCOPYTX_PARAM).unwrap_or_default();
+        if zcopytx.trim() != "1" {
+            warn!(
+                "{}: Zero-copy transmit is disabled in the vhost_net module, frames are copied",
+                self.id
+            );
+        }
+
+        self.vhost_net = true;
+        Ok(())
+    }
+
     /// Create a new virtio network device with the given IP address and
     /// netmask.
     #[allow(clippy::too_many_arguments)]
@@ -562,6 +887,11 @@ impl Net {
         offload_tso: bool,
         offload_ufo: bool,
         offload_csum: bool,
+        io_uring: NetIoUring,
+        queue_affinity: BTreeMap<u16, Vec<usize>>,
+        busy_poll: Option<Duration>,
+        coalescing: Option<Duration>,
+        io_uring_event_loop: bool,
     ) -> Result<Self> {
         let taps = open_tap(
             if_name,
@@ -588,6 +918,11 @@ impl Net {
             offload_tso,
             offload_ufo,
             offload_csum,
+            io_uring,
+            queue_affinity,
+            busy_poll,
+            coalescing,
+            io_uring_event_loop,
         )
     }
 
@@ -606,6 +941,11 @@ impl Net {
         offload_tso: bool,
         offload_ufo: bool,
         offload_csum: bool,
+        io_uring: NetIoUring,
+        queue_affinity: BTreeMap<u16, Vec<usize>>,
+        busy_poll: Option<Duration>,
+        coalescing: Option<Duration>,
+        io_uring_event_loop: bool,
     ) -> Result<Self> {
         let mut taps: Vec<Tap> = Vec::new();
         let num_queue_pairs = fds.len();
@@ -641,18 +981,114 @@ impl Net {
             offload_tso,
             offload_ufo,
             offload_csum,
+            io_uring,
+            queue_affinity,
+            busy_poll,
+            coalescing,
+            io_uring_event_loop,
         )
     }
 
+    /// Create a new virtio network device exchanging frames through AF_XDP
+    /// sockets bound to the queues of `if_name` starting from `queue`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_xdp(
+        id: String,
+        if_name: &str,
+        queue: u32,
+        guest_mac: Option<MacAddr>,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        exit_evt: EventFd,
+        state: Option<NetState>,
+        queue_affinity: BTreeMap<u16, Vec<usize>>,
+        busy_poll: Option<Duration>,
+        coalescing: Option<Duration>,
+        io_uring_event_loop: bool,
+    ) -> Result<Self> {
+        let sockets = open_xdp(if_name, queue, num_queues / 2).map_err(Error::OpenXdp)?;
+        let taps = sockets
+            .iter()
+            .map(XdpSocket::tap)
+            .collect::<std::result::Result<Vec<Tap>, XdpError>>()
+            .map_err(Error::OpenXdp)?;
+
+        // The frames are handed over to the NIC as they are, which rules
+        // out any offload.
+        let mut net = Self::new_with_tap(
+            id,
+            taps,
+            guest_mac,
+            iommu,
+            num_queues,
+            queue_size,
+            seccomp_action,
+            rate_limiter_config,
+            exit_evt,
+            state,
+            false,
+            false,
+            false,
+            false,
+            queue_affinity,
+            busy_poll,
+            coalescing,
+            io_uring_event_loop,
+        )?;
+        net.xdp_sockets = sockets
+            .into_iter()
+            .map(|socket| Arc::new(Mutex::new(socket)))
+            .collect();
+
+        Ok(net)
+    }
+
     fn state(&self) -> NetState {
         NetState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
             config: self.config,
             queue_size: self.common.queue_sizes.clone(),
+            rx_coalescing: self.rx_coalescing.get(),
+            tx_coalescing: self.tx_coalescing.get(),
+        }
+    }
+
+    // Asks the guest to announce itself on the network, so that the switches
+    // learn its new location right away after a restore or a migration.
+    fn announce(&self) {
+        if !self
+            .common
+            .feature_acked(VIRTIO_NET_F_GUEST_ANNOUNCE.into())
+        {
+            return;
+        }
+        let Some(interrupt_cb) = self.common.interrupt_cb.as_ref() else {
+            return;
+        };
+
+        info!("{}: Requesting guest announcement", self.id);
+        self.announce.store(true, Ordering::Release);
+        if let Err(e) = interrupt_cb.trigger(VirtioInterruptType::Config) {
+            error!("{}: Failed signaling the config change: {:?}", self.id, e);
         }
     }
 
+    // Detach the vhost-net backends from the TAP queues, or attach them
+    // back, the vrings being kept as they are in the meantime.
+    fn set_vhost_net_backends(&self, attach: bool) -> result::Result<(), vhost::Error> {
+        for (backend, tap) in self.vhost_net_backends.iter().zip(self.taps.iter()) {
+            for queue_index in 0..2 {
+                backend.set_backend(queue_index, attach.then(|| tap.as_file()))?;
+            }
+        }
+
+        Ok(())
+    }
+
     #[cfg(fuzzing)]
     pub fn wait_for_epoll_threads(&mut self) {
         self.common.wait_for_epoll_threads();
@@ -693,7 +1129,11 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        let mut config = self.config;
+        if self.announce.load(Ordering::Acquire) {
+            config.status |= VIRTIO_NET_S_ANNOUNCE as u16;
+        }
+        self.read_config_from_slice(config.as_slice(), offset, data);
     }
 
     fn activate(
@@ -706,6 +1146,26 @@ impl VirtioDevice for Net {
 
         let num_queues = queues.len();
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
+        // A legacy driver which did not negotiate mergeable RX buffers uses
+        // a header without the num_buffers field.
+        let legacy_hdr = !self.common.feature_acked(VIRTIO_F_VERSION_1.into())
+            && !self.common.feature_acked(VIRTIO_NET_F_MRG_RXBUF.into());
+        // Coalescing parameters set by the driver, shared across the queues.
+        let (rx_coalescing, tx_coalescing) =
+            if self.common.feature_acked(VIRTIO_NET_F_NOTF_COAL.into()) {
+                (
+                    Some(self.rx_coalescing.clone()),
+                    Some(self.tx_coalescing.clone()),
+                )
+            } else {
+                (None, None)
+            };
+        // No thread processes the queue pairs handed over to vhost-net.
+        let data_threads = if self.vhost_net { 0 } else { self.taps.len() };
+        if self.vhost_net {
+            self.common.paused_sync = Some(Arc::new(Barrier::new(1)));
+            self.vhost_net_mem_table = mem.memory().iter().map(vhost_net_memory_region).collect();
+        }
         if self.common.feature_acked(VIRTIO_NET_F_CTRL_VQ.into()) && num_queues % 2 != 0 {
             let ctrl_queue_index = num_queues - 1;
             let (_, mut ctrl_queue, ctrl_queue_evt) = queues.remove(ctrl_queue_index);
@@ -717,9 +1177,15 @@ impl VirtioDevice for Net {
                 mem: mem.clone(),
                 kill_evt,
                 pause_evt,
-                ctrl_q: CtrlQueue::new(self.taps.clone()),
+                ctrl_q: CtrlQueue {
+                    rx_coalescing: rx_coalescing.clone(),
+                    tx_coalescing: tx_coalescing.clone(),
+                    announce: Some(self.announce.clone()),
+                    ..CtrlQueue::new(self.taps.clone())
+                },
                 queue: ctrl_queue,
                 queue_evt: ctrl_queue_evt,
+                packed_queue: None,
                 access_platform: self.common.access_platform.clone(),
                 queue_index: ctrl_queue_index as u16,
                 interrupt_cb: interrupt_cb.clone(),
@@ -729,7 +1195,7 @@ impl VirtioDevice for Net {
             // Let's update the barrier as we need 1 for each RX/TX pair +
             // 1 for the control queue + 1 for the main thread signalling
             // the pause.
-            self.common.paused_sync = Some(Arc::new(Barrier::new(self.taps.len() + 2)));
+            self.common.paused_sync = Some(Arc::new(Barrier::new(data_threads + 2)));
             let paused_sync = self.common.paused_sync.clone();
 
             let mut epoll_threads = Vec::new();
@@ -747,7 +1213,10 @@ impl VirtioDevice for Net {
         let mut epoll_threads = Vec::new();
         let mut taps = self.taps.clone();
         for i in 0..queues.len() / 2 {
-            let rx = RxVirtio::new();
+            let rx = RxVirtio {
+                legacy_hdr,
+                ..RxVirtio::new()
+            };
             let tx = TxVirtio::new();
             let rx_tap_listening = false;
 
@@ -773,13 +1242,92 @@ impl VirtioDevice for Net {
                 .transpose()
                 .map_err(ActivateError::CreateRateLimiter)?;
 
+            let xsk = self.xdp_sockets.get(i).cloned();
+
+            // The io_uring path only handles the modern header, and can't
+            // account for rate limiting, which is done frame by frame.
+            #[cfg(feature = "io_uring")]
+            let io_uring = if self.io_uring != NetIoUring::Disabled
+                && !self.vhost_net
+                && !legacy_hdr
+                && xsk.is_none()
+                && self.rate_limiter_config.is_none()
+            {
+                TapIoUring::new(
+                    &mem.memory(),
+                    self.io_uring == NetIoUring::RegisteredBuffers,
+                )
+                .map_err(|e| warn!("Failed to create TAP io_uring, not using it: {}", e))
+                .ok()
+            } else {
+                None
+            };
+
+            let new_coalescer = |driver_params: &Option<Arc<NotificationCoalescing>>| {
+                if self.coalescing.is_some() || driver_params.is_some() {
+                    InterruptCoalescer::new(self.coalescing, driver_params.clone())
+                        .map(Some)
+                        .map_err(ActivateError::CreateInterruptCoalescer)
+                } else {
+                    Ok(None)
+                }
+            };
+
             let tap = taps.remove(0);
             #[cfg(not(fuzzing))]
-            tap.set_offload(virtio_features_to_tap_offload(self.common.acked_features))
-                .map_err(|e| {
-                    error!("Error programming tap offload: {:?}", e);
+            if xsk.is_none() {
+                tap.set_offload(virtio_features_to_tap_offload(self.common.acked_features))
+                    .map_err(|e| {
+                        error!("Error programming tap offload: {:?}", e);
+                        ActivateError::BadActivate
+                    })?;
+
+                let vnet_hdr_size = if legacy_hdr {
+                    std::mem::size_of::<virtio_net_hdr>()
+                } else {
+                    std::mem::size_of::<virtio_net_hdr_v1>()
+                };
+                tap.set_vnet_hdr_size(vnet_hdr_size as i32).map_err(|e| {
+                    error!("Error setting tap vnet header size: {:?}", e);
                     ActivateError::BadActivate
                 })?;
+            }
+
+            if self.vhost_net {
+                // The kernel signals the used buffers straight through the
+                // interrupt eventfds.
+                let mut vrings = Vec::new();
+                for (queue_index, (queue, queue_evt)) in [
+                    (queue_pair.0, queue_evt_pair.0),
+                    (queue_pair.1, queue_evt_pair.1),
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    let queue_index = (i * 2 + queue_index) as u16;
+                    let call_evt = interrupt_cb
+                        .notifier(VirtioInterruptType::Queue(queue_index))
+                        .ok_or_else(|| {
+                            error!(
+                                "{}: No interrupt eventfd for queue {}",
+                                self.id, queue_index
+                            );
+                            ActivateError::BadActivate
+                        })?;
+                    vrings.push((queue, queue_evt, call_evt));
+                }
+
+                let backend = activate_vhost_net(
+                    &mem,
+                    &self.vhost_net_mem_table,
+                    self.common.acked_features,
+                    &tap,
+                    &vrings,
+                )
+                .map_err(ActivateError::VhostNetSetup)?;
+                self.vhost_net_backends.push(backend);
+                continue;
+            }
 
             let mut handler = NetEpollHandler {
                 net: NetQueuePair {
@@ -797,11 +1345,19 @@ impl VirtioDevice for Net {
                     rx_rate_limiter,
                     tx_rate_limiter,
                     access_platform: self.common.access_platform.clone(),
+                    xsk,
                 },
+                #[cfg(feature = "io_uring")]
+                io_uring,
                 mem: mem.clone(),
                 queue_index_base: (i * 2) as u16,
                 queue_pair,
                 queue_evt_pair,
+                host_cpus: self.queue_affinity.get(&(i as u16)).cloned(),
+                busy_poll: self.busy_poll,
+                io_uring_event_loop: self.io_uring_event_loop,
+                rx_coalescer: new_coalescer(&rx_coalescing)?,
+                tx_coalescer: new_coalescer(&tx_coalescing)?,
                 interrupt_cb: interrupt_cb.clone(),
                 kill_evt,
                 pause_evt,
@@ -829,6 +1385,11 @@ impl VirtioDevice for Net {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        // Closing the vhost-net devices stops the backends.
+        self.vhost_net_backends.clear();
+        // The driver must set its coalescing parameters again.
+        self.rx_coalescing = new_notification_coalescing(None);
+        self.tx_coalescing = new_notification_coalescing(None);
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
@@ -859,19 +1420,48 @@ impl VirtioDevice for Net {
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
+
+    fn add_memory_region(
+        &mut self,
+        region: &Arc<GuestRegionMmap>,
+    ) -> std::result::Result<(), crate::Error> {
+        if self.vhost_net_backends.is_empty() {
+            return Ok(());
+        }
+
+        self.vhost_net_mem_table
+            .push(vhost_net_memory_region(region));
+        for backend in self.vhost_net_backends.iter() {
+            backend
+                .set_mem_table(&self.vhost_net_mem_table)
+                .map_err(crate::Error::VhostNetUpdateMemory)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Pausable for Net {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+        self.common.pause()?;
+        self.set_vhost_net_backends(false)
+            .map_err(|e| MigratableError::Pause(e.into()))
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.set_vhost_net_backends(true)
+            .map_err(|e| MigratableError::Resume(e.into()))?;
         self.common.resume()?;
 
         if let Some(ctrl_queue_epoll_thread) = &self.ctrl_queue_epoll_thread {
             ctrl_queue_epoll_thread.thread().unpark();
         }
+
+        if self.announce_on_resume {
+            self.announce_on_resume = false;
+            self.announce();
+        }
+
         Ok(())
     }
 }
@@ -882,8 +1472,26 @@ impl Snapshottable for Net {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        // The vrings state is held by the kernel.
+        if self.vhost_net {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Snapshotting vhost-net devices is not supported"
+            )));
+        }
+
         Snapshot::new_from_state(&self.state())
     }
 }
 impl Transportable for Net {}
-impl Migratable for Net {}
+impl Migratable for Net {
+    fn start_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        // The pages written by the kernel are not logged.
+        if self.vhost_net {
+            return Err(MigratableError::StartDirtyLog(anyhow!(
+                "Migrating vhost-net devices is not supported"
+            )));
+        }
+
+        Ok(())
+    }
+}
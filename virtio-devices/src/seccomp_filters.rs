@@ -13,6 +13,10 @@ pub enum Thread {
     VirtioBalloon,
     VirtioBlock,
     VirtioConsole,
+    VirtioFs9p,
+    VirtioGpu,
+    VirtioI2c,
+    VirtioInput,
     VirtioIommu,
     VirtioMem,
     VirtioNet,
@@ -49,6 +53,9 @@ macro_rules! or {
 const TIOCGWINSZ: u64 = 0x5413;
 const FIONBIO: u64 = 0x5421;
 
+// See include/uapi/linux/i2c-dev.h in the kernel code.
+const I2C_RDWR: u64 = 0x0707;
+
 // See include/uapi/linux/vfio.h in the kernel code.
 const VFIO_IOMMU_MAP_DMA: u64 = 0x3b71;
 const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
@@ -80,6 +87,10 @@ fn create_virtio_console_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     ]
 }
 
+fn create_virtio_i2c_ioctl_seccomp_rule() -> Vec<SeccompRule> {
+    or![and![Cond::new(1, ArgLen::Dword, Eq, I2C_RDWR).unwrap()]]
+}
+
 fn create_virtio_iommu_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     or![
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_IOMMU_MAP_DMA).unwrap()],
@@ -95,7 +106,7 @@ fn create_virtio_mem_ioctl_seccomp_rule() -> Vec<SeccompRule> {
 }
 
 fn virtio_balloon_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
-    vec![(libc::SYS_fallocate, vec![])]
+    vec![(libc::SYS_fallocate, vec![]), (libc::SYS_fstatfs, vec![])]
 }
 
 fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
@@ -111,7 +122,13 @@ fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_io_getevents, vec![]),
         (libc::SYS_io_submit, vec![]),
         (libc::SYS_io_uring_enter, vec![]),
+        (libc::SYS_io_uring_register, vec![]),
+        (libc::SYS_io_uring_setup, vec![]),
         (libc::SYS_lseek, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (libc::SYS_ppoll, vec![]),
         (libc::SYS_pread64, vec![]),
         (libc::SYS_preadv, vec![]),
         (libc::SYS_pwritev, vec![]),
@@ -131,6 +148,57 @@ fn virtio_console_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     ]
 }
 
+fn virtio_fs9p_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_fchmod, vec![]),
+        (libc::SYS_fchown, vec![]),
+        (libc::SYS_fcntl, vec![]),
+        (libc::SYS_fdatasync, vec![]),
+        (libc::SYS_fstatfs, vec![]),
+        (libc::SYS_fsync, vec![]),
+        (libc::SYS_ftruncate, vec![]),
+        (libc::SYS_getdents64, vec![]),
+        (libc::SYS_linkat, vec![]),
+        (libc::SYS_lseek, vec![]),
+        (libc::SYS_mkdirat, vec![]),
+        (libc::SYS_mknodat, vec![]),
+        (libc::SYS_newfstatat, vec![]),
+        (libc::SYS_pread64, vec![]),
+        (libc::SYS_pwrite64, vec![]),
+        (libc::SYS_readlinkat, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_renameat, vec![]),
+        (libc::SYS_renameat2, vec![]),
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+        (libc::SYS_symlinkat, vec![]),
+        (libc::SYS_unlinkat, vec![]),
+        (libc::SYS_utimensat, vec![]),
+    ]
+}
+
+fn virtio_gpu_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+    ]
+}
+
+fn virtio_i2c_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_ioctl, create_virtio_i2c_ioctl_seccomp_rule()),
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+    ]
+}
+
+fn virtio_input_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+    ]
+}
+
 fn virtio_iommu_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![(libc::SYS_ioctl, create_virtio_iommu_ioctl_seccomp_rule())]
 }
@@ -146,7 +214,17 @@ fn virtio_mem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 
 fn virtio_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
+        (libc::SYS_io_uring_enter, vec![]),
+        (libc::SYS_io_uring_register, vec![]),
+        (libc::SYS_io_uring_setup, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (libc::SYS_ppoll, vec![]),
         (libc::SYS_readv, vec![]),
+        (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_sched_setaffinity, vec![]),
+        (libc::SYS_sendto, vec![]),
         (libc::SYS_timerfd_settime, vec![]),
         (libc::SYS_writev, vec![]),
         #[cfg(feature = "sev_snp")]
@@ -263,6 +341,10 @@ fn get_seccomp_rules(thread_type: Thread) -> Vec<(i64, Vec<SeccompRule>)> {
         Thread::VirtioBalloon => virtio_balloon_thread_rules(),
         Thread::VirtioBlock => virtio_block_thread_rules(),
         Thread::VirtioConsole => virtio_console_thread_rules(),
+        Thread::VirtioFs9p => virtio_fs9p_thread_rules(),
+        Thread::VirtioGpu => virtio_gpu_thread_rules(),
+        Thread::VirtioI2c => virtio_i2c_thread_rules(),
+        Thread::VirtioInput => virtio_input_thread_rules(),
         Thread::VirtioIommu => virtio_iommu_thread_rules(),
         Thread::VirtioMem => virtio_mem_thread_rules(),
         Thread::VirtioNet => virtio_net_thread_rules(),
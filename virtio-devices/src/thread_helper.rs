@@ -10,11 +10,38 @@ use crate::{
 };
 use seccompiler::{apply_filter, SeccompAction};
 use std::{
+    io,
     panic::AssertUnwindSafe,
     thread::{self, JoinHandle},
 };
 use vmm_sys_util::eventfd::EventFd;
 
+/// Schedule the current thread to run on the given host CPUs.
+pub(crate) fn set_thread_affinity(host_cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: all zeros is a valid pattern
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call, trivially safe
+    unsafe { libc::CPU_ZERO(&mut cpuset) };
+    for host_cpu in host_cpus {
+        // SAFETY: FFI call, trivially safe
+        unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
+    }
+
+    // SAFETY: FFI call with correct arguments
+    let ret = unsafe {
+        libc::sched_setaffinity(
+            0,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &cpuset as *const libc::cpu_set_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 pub(crate) fn spawn_virtio_thread<F>(
     name: &str,
     seccomp_action: &SeccompAction,
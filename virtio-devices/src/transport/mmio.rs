@@ -0,0 +1,836 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! virtio-mmio transport, see section 4.2 of the virtio 1.2 specification.
+//! Only the modern (version 2) register layout is exposed, and the device
+//! interrupt is signaled through a single legacy IRQ.
+
+use crate::transport::VirtioPciDeviceActivator;
+use crate::GuestMemoryMmap;
+use crate::{
+    ActivateResult, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE,
+    DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
+};
+use anyhow::anyhow;
+use libc::EFD_NONBLOCK;
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use virtio_queue::{Queue, QueueT};
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::BusDevice;
+use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Size of the MMIO region of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976; // "virt"
+const VIRTIO_MMIO_VERSION: u32 = 2;
+const VIRTIO_MMIO_VENDOR_ID: u32 = 0x1af4;
+
+// Device registers.
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+const VIRTIO_MMIO_VERSION_REG: u64 = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+const VIRTIO_MMIO_VENDOR_ID_REG: u64 = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+/// Offset of the queue notification register, written with the queue index.
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+const VIRTIO_MMIO_STATUS: u64 = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+const VIRTIO_MMIO_QUEUE_AVAIL_LOW: u64 = 0x090;
+const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: u64 = 0x094;
+const VIRTIO_MMIO_QUEUE_USED_LOW: u64 = 0x0a0;
+const VIRTIO_MMIO_QUEUE_USED_HIGH: u64 = 0x0a4;
+const VIRTIO_MMIO_SHM_SEL: u64 = 0x0ac;
+const VIRTIO_MMIO_SHM_LEN_LOW: u64 = 0x0b0;
+const VIRTIO_MMIO_SHM_BASE_HIGH: u64 = 0x0bc;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+// Interrupt status bits.
+const VIRTIO_MMIO_INT_VRING: usize = 0x1;
+const VIRTIO_MMIO_INT_CONFIG: usize = 0x2;
+
+#[derive(Serialize, Deserialize)]
+struct QueueState {
+    size: u16,
+    ready: bool,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VirtioMmioDeviceState {
+    device_activated: bool,
+    interrupt_status: usize,
+    driver_status: u8,
+    config_generation: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u32,
+    queues: Vec<QueueState>,
+}
+
+#[derive(Error, Debug)]
+pub enum VirtioMmioDeviceError {
+    #[error("Failed creating VirtioMmioDevice: {0}")]
+    CreateVirtioMmioDevice(#[source] anyhow::Error),
+}
+pub type Result<T> = std::result::Result<T, VirtioMmioDeviceError>;
+
+pub struct VirtioInterruptIntx {
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+}
+
+impl VirtioInterrupt for VirtioInterruptIntx {
+    fn trigger(&self, int_type: VirtioInterruptType) -> std::result::Result<(), std::io::Error> {
+        let status = match int_type {
+            VirtioInterruptType::Config => VIRTIO_MMIO_INT_CONFIG,
+            VirtioInterruptType::Queue(_) => VIRTIO_MMIO_INT_VRING,
+        };
+        self.interrupt_status.fetch_or(status, Ordering::SeqCst);
+
+        self.interrupt_source_group.trigger(0)
+    }
+
+    // No notifier is provided, as the interrupt status must be updated
+    // before the interrupt is delivered.
+}
+
+pub struct VirtioMmioDevice {
+    id: String,
+
+    // Virtio device reference and status
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    device_activated: Arc<AtomicBool>,
+
+    // Interrupt status and legacy interrupt
+    interrupt_status: Arc<AtomicUsize>,
+    virtio_interrupt: Option<Arc<dyn VirtioInterrupt>>,
+
+    // Transport registers
+    driver_status: u8,
+    config_generation: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u32,
+
+    // virtio queues
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+
+    // Guest memory
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+
+    // EventFd to signal on to request activation
+    activate_evt: EventFd,
+
+    // Pending activations
+    pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+}
+
+impl VirtioMmioDevice {
+    /// Constructs a new MMIO transport for the given virtio device.
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+        activate_evt: EventFd,
+        pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self> {
+        let locked_device = device.lock().unwrap();
+        let mut queue_evts = Vec::new();
+        for _ in locked_device.queue_max_sizes().iter() {
+            queue_evts.push(EventFd::new(EFD_NONBLOCK).map_err(|e| {
+                VirtioMmioDeviceError::CreateVirtioMmioDevice(anyhow!(
+                    "Failed creating eventfd: {}",
+                    e
+                ))
+            })?)
+        }
+
+        let mut queues: Vec<Queue> = locked_device
+            .queue_max_sizes()
+            .iter()
+            .map(|&s| Queue::new(s).unwrap())
+            .collect();
+        drop(locked_device);
+
+        let state: Option<VirtioMmioDeviceState> = snapshot
+            .as_ref()
+            .map(|s| s.to_state())
+            .transpose()
+            .map_err(|e| {
+                VirtioMmioDeviceError::CreateVirtioMmioDevice(anyhow!(
+                    "Failed to get VirtioMmioDeviceState from Snapshot: {}",
+                    e
+                ))
+            })?;
+
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let mut virtio_mmio_device = VirtioMmioDevice {
+            id,
+            device,
+            device_activated: Arc::new(AtomicBool::new(false)),
+            interrupt_status: interrupt_status.clone(),
+            virtio_interrupt: Some(Arc::new(VirtioInterruptIntx {
+                interrupt_status,
+                interrupt_source_group,
+            })),
+            driver_status: DEVICE_INIT as u8,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            queues: Vec::new(),
+            queue_evts,
+            memory,
+            activate_evt,
+            pending_activations,
+        };
+
+        if let Some(state) = state {
+            let mem = virtio_mmio_device.memory.memory();
+            for (queue, state) in queues.iter_mut().zip(state.queues.iter()) {
+                queue.set_size(state.size);
+                queue.set_ready(state.ready);
+                queue
+                    .try_set_desc_table_address(GuestAddress(state.desc_table))
+                    .unwrap();
+                queue
+                    .try_set_avail_ring_address(GuestAddress(state.avail_ring))
+                    .unwrap();
+                queue
+                    .try_set_used_ring_address(GuestAddress(state.used_ring))
+                    .unwrap();
+                let used_idx = queue.used_idx(mem.deref(), Ordering::Acquire).unwrap().0;
+                queue.set_next_avail(used_idx);
+                queue.set_next_used(used_idx);
+            }
+            drop(mem);
+
+            virtio_mmio_device
+                .device_activated
+                .store(state.device_activated, Ordering::SeqCst);
+            virtio_mmio_device
+                .interrupt_status
+                .store(state.interrupt_status, Ordering::SeqCst);
+            virtio_mmio_device.driver_status = state.driver_status;
+            virtio_mmio_device.config_generation = state.config_generation;
+            virtio_mmio_device.device_feature_select = state.device_feature_select;
+            virtio_mmio_device.driver_feature_select = state.driver_feature_select;
+            virtio_mmio_device.queue_select = state.queue_select;
+        }
+        virtio_mmio_device.queues = queues;
+
+        // In case of a restore, we can activate the device, as we know at
+        // this point the virtqueues are in the right state and the device is
+        // ready to be activated, which will spawn each virtio worker thread.
+        if virtio_mmio_device.device_activated.load(Ordering::SeqCst)
+            && virtio_mmio_device.is_driver_ready()
+        {
+            virtio_mmio_device.activate().map_err(|e| {
+                VirtioMmioDeviceError::CreateVirtioMmioDevice(anyhow!(
+                    "Failed activating the device: {}",
+                    e
+                ))
+            })?;
+        }
+
+        Ok(virtio_mmio_device)
+    }
+
+    fn state(&self) -> VirtioMmioDeviceState {
+        VirtioMmioDeviceState {
+            device_activated: self.device_activated.load(Ordering::Acquire),
+            interrupt_status: self.interrupt_status.load(Ordering::Acquire),
+            driver_status: self.driver_status,
+            config_generation: self.config_generation,
+            device_feature_select: self.device_feature_select,
+            driver_feature_select: self.driver_feature_select,
+            queue_select: self.queue_select,
+            queues: self
+                .queues
+                .iter()
+                .map(|q| QueueState {
+                    size: q.size(),
+                    ready: q.ready(),
+                    desc_table: q.desc_table(),
+                    avail_ring: q.avail_ring(),
+                    used_ring: q.used_ring(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Gets the list of queue events that must be triggered whenever the
+    /// guest writes the index of the matching queue to the
+    /// `VIRTIO_MMIO_QUEUE_NOTIFY` register.
+    pub fn queue_evts(&self) -> &[EventFd] {
+        self.queue_evts.as_slice()
+    }
+
+    pub fn virtio_device(&self) -> Arc<Mutex<dyn VirtioDevice>> {
+        self.device.clone()
+    }
+
+    fn is_driver_ready(&self) -> bool {
+        let ready_bits =
+            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
+        self.driver_status == ready_bits && self.driver_status & DEVICE_FAILED as u8 == 0
+    }
+
+    /// Determines if the driver has requested the device (re)init / reset itself
+    fn is_driver_init(&self) -> bool {
+        self.driver_status == DEVICE_INIT as u8
+    }
+
+    fn needs_activation(&self) -> bool {
+        !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
+    }
+
+    fn prepare_activator(&mut self, barrier: Option<Arc<Barrier>>) -> VirtioPciDeviceActivator {
+        let mut queues = Vec::new();
+
+        for (queue_index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready() {
+                continue;
+            }
+
+            if !queue.is_valid(self.memory.memory().deref()) {
+                error!("Queue {} is not valid", queue_index);
+            }
+
+            queues.push((
+                queue_index,
+                vm_virtio::clone_queue(queue),
+                self.queue_evts[queue_index].try_clone().unwrap(),
+            ));
+        }
+
+        VirtioPciDeviceActivator::new(
+            self.id.clone(),
+            self.virtio_interrupt.take(),
+            self.memory.clone(),
+            self.device.clone(),
+            self.device_activated.clone(),
+            queues,
+            barrier,
+        )
+    }
+
+    fn activate(&mut self) -> ActivateResult {
+        self.prepare_activator(None).activate()
+    }
+
+    fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) {
+        // Queues can't be reconfigured once the device is running.
+        if self.device_activated.load(Ordering::SeqCst) {
+            warn!("{}: Ignoring queue update on an active device", self.id);
+            return;
+        }
+
+        if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
+            f(queue);
+        } else {
+            error!("{}: Invalid queue selected: {}", self.id, self.queue_select);
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let queue = self.queues.get(self.queue_select as usize);
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION_REG => VIRTIO_MMIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.lock().unwrap().device_type(),
+            VIRTIO_MMIO_VENDOR_ID_REG => VIRTIO_MMIO_VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                // Only 64 bits of features (2 pages) are defined for now, so limit
+                // device_feature_select to avoid shifting by 64 or more bits.
+                if self.device_feature_select < 2 {
+                    (self.device.lock().unwrap().features() >> (self.device_feature_select * 32))
+                        as u32
+                } else {
+                    0
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue.map_or(0, |q| u32::from(q.max_size())),
+            VIRTIO_MMIO_QUEUE_READY => queue.map_or(0, |q| u32::from(q.ready())),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::SeqCst) as u32,
+            VIRTIO_MMIO_STATUS => u32::from(self.driver_status),
+            // Shared memory regions are not supported, which is reported
+            // through an all ones length.
+            VIRTIO_MMIO_SHM_LEN_LOW..=VIRTIO_MMIO_SHM_BASE_HIGH => u32::MAX,
+            VIRTIO_MMIO_CONFIG_GENERATION => self.config_generation,
+            _ => {
+                warn!(
+                    "{}: invalid virtio-mmio register read: 0x{:x}",
+                    self.id, offset
+                );
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_feature_select = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                if self.driver_feature_select < 2 {
+                    self.device
+                        .lock()
+                        .unwrap()
+                        .ack_features(u64::from(value) << (self.driver_feature_select * 32));
+                } else {
+                    warn!(
+                        "{}: invalid ack_features (page {}, value 0x{:x})",
+                        self.id, self.driver_feature_select, value
+                    );
+                }
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_feature_select = value,
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_select = value,
+            VIRTIO_MMIO_QUEUE_NUM => self.with_queue_mut(|q| q.set_size(value as u16)),
+            VIRTIO_MMIO_QUEUE_READY => self.with_queue_mut(|q| q.set_ready(value == 1)),
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                // Only reached when no ioeventfd is registered for the queue.
+                if let Some(queue_evt) = self.queue_evts.get(value as usize) {
+                    queue_evt.write(1).ok();
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status
+                    .fetch_and(!(value as usize), Ordering::SeqCst);
+            }
+            VIRTIO_MMIO_STATUS => self.driver_status = value as u8,
+            VIRTIO_MMIO_QUEUE_DESC_LOW => {
+                self.with_queue_mut(|q| q.set_desc_table_address(Some(value), None))
+            }
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                self.with_queue_mut(|q| q.set_desc_table_address(None, Some(value)))
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => {
+                self.with_queue_mut(|q| q.set_avail_ring_address(Some(value), None))
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => {
+                self.with_queue_mut(|q| q.set_avail_ring_address(None, Some(value)))
+            }
+            VIRTIO_MMIO_QUEUE_USED_LOW => {
+                self.with_queue_mut(|q| q.set_used_ring_address(Some(value), None))
+            }
+            VIRTIO_MMIO_QUEUE_USED_HIGH => {
+                self.with_queue_mut(|q| q.set_used_ring_address(None, Some(value)))
+            }
+            VIRTIO_MMIO_SHM_SEL => {}
+            _ => warn!(
+                "{}: invalid virtio-mmio register write: 0x{:x}",
+                self.id, offset
+            ),
+        }
+    }
+}
+
+impl BusDevice for VirtioMmioDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.device
+                .lock()
+                .unwrap()
+                .read_config(offset - VIRTIO_MMIO_CONFIG, data);
+            return;
+        }
+
+        // All the transport registers are 32 bits wide.
+        if data.len() != 4 {
+            warn!(
+                "{}: invalid virtio-mmio register read: 0x{:x}, len {}",
+                self.id,
+                offset,
+                data.len()
+            );
+            data.fill(0);
+            return;
+        }
+
+        data.copy_from_slice(&self.read_register(offset).to_le_bytes());
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.device
+                .lock()
+                .unwrap()
+                .write_config(offset - VIRTIO_MMIO_CONFIG, data);
+            return None;
+        }
+
+        let Ok(value) = <[u8; 4]>::try_from(data).map(u32::from_le_bytes) else {
+            warn!(
+                "{}: invalid virtio-mmio register write: 0x{:x}, len {}",
+                self.id,
+                offset,
+                data.len()
+            );
+            return None;
+        };
+        self.write_register(offset, value);
+
+        // Try and activate the device if the driver status has changed
+        if self.needs_activation() {
+            let barrier = Arc::new(Barrier::new(2));
+            let activator = self.prepare_activator(Some(barrier.clone()));
+            self.pending_activations.lock().unwrap().push(activator);
+            info!(
+                "{}: Needs activation; writing to activate event fd",
+                self.id
+            );
+            self.activate_evt.write(1).ok();
+            info!("{}: Needs activation; returning barrier", self.id);
+            return Some(barrier);
+        }
+
+        // Device has been reset by the driver
+        if self.device_activated.load(Ordering::SeqCst) && self.is_driver_init() {
+            let mut device = self.device.lock().unwrap();
+            if let Some(virtio_interrupt) = device.reset() {
+                // Upon reset the device returns its interrupt EventFD
+                self.virtio_interrupt = Some(virtio_interrupt);
+                self.device_activated.store(false, Ordering::SeqCst);
+
+                // Reset queue readiness, queue sizes, selected queue and
+                // pending interrupts as per spec for reset
+                self.queues.iter_mut().for_each(Queue::reset);
+                self.queue_select = 0;
+                self.interrupt_status.store(0, Ordering::SeqCst);
+            } else {
+                error!("Attempt to reset device when not implemented in underlying device");
+                self.driver_status = DEVICE_FAILED as u8;
+            }
+        }
+
+        None
+    }
+}
+
+impl Pausable for VirtioMmioDevice {}
+
+impl Snapshottable for VirtioMmioDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+impl Transportable for VirtioMmioDevice {}
+impl Migratable for VirtioMmioDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::result;
+    use std::thread;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    const QUEUE_SIZES: &[u16] = &[256, 128];
+    const DUMMY_FEATURES: u64 = 0x1_5555_aaaa;
+    const DUMMY_DEVICE_TYPE: u32 = 4;
+
+    #[derive(Default)]
+    struct DummyDevice {
+        acked_features: u64,
+        activated_queues: Vec<usize>,
+        interrupt: Option<Arc<dyn VirtioInterrupt>>,
+    }
+
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            DUMMY_DEVICE_TYPE
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn features(&self) -> u64 {
+            DUMMY_FEATURES
+        }
+
+        fn ack_features(&mut self, value: u64) {
+            self.acked_features |= value;
+        }
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            interrupt: Arc<dyn VirtioInterrupt>,
+            queues: Vec<(usize, Queue, EventFd)>,
+        ) -> ActivateResult {
+            self.activated_queues = queues.iter().map(|(index, _, _)| *index).collect();
+            self.interrupt = Some(interrupt);
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+            self.interrupt.take()
+        }
+    }
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> result::Result<(), std::io::Error> {
+            self.event_fd.write(1)
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+        fn set_gsi(&self) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            Some(self.event_fd.try_clone().unwrap())
+        }
+    }
+
+    fn create_device(
+        device: Arc<Mutex<DummyDevice>>,
+    ) -> (VirtioMmioDevice, Arc<Mutex<Vec<VirtioPciDeviceActivator>>>) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let pending_activations = Arc::new(Mutex::new(Vec::new()));
+        let mmio = VirtioMmioDevice::new(
+            "virtio-mmio0".to_owned(),
+            GuestMemoryAtomic::new(mem),
+            device,
+            Arc::new(TestInterrupt {
+                event_fd: EventFd::new(EFD_NONBLOCK).unwrap(),
+            }),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            pending_activations.clone(),
+            None,
+        )
+        .unwrap();
+        (mmio, pending_activations)
+    }
+
+    fn read_reg(mmio: &mut VirtioMmioDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        mmio.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_reg(mmio: &mut VirtioMmioDevice, offset: u64, value: u32) -> Option<Arc<Barrier>> {
+        mmio.write(0, offset, &value.to_le_bytes())
+    }
+
+    // Go through the driver initialization sequence, setting up the first
+    // queue only.
+    fn setup_device(mmio: &mut VirtioMmioDevice, pending: &Mutex<Vec<VirtioPciDeviceActivator>>) {
+        write_reg(mmio, VIRTIO_MMIO_STATUS, DEVICE_ACKNOWLEDGE);
+        write_reg(mmio, VIRTIO_MMIO_STATUS, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        write_reg(mmio, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        write_reg(mmio, VIRTIO_MMIO_DRIVER_FEATURES, 0x1);
+        write_reg(
+            mmio,
+            VIRTIO_MMIO_STATUS,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK,
+        );
+
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_SEL, 0);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_NUM, 16);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x1000);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_DESC_HIGH, 0);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_AVAIL_LOW, 0x2000);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_AVAIL_HIGH, 0);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_USED_LOW, 0x3000);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_USED_HIGH, 0);
+        write_reg(mmio, VIRTIO_MMIO_QUEUE_READY, 1);
+
+        // The device is activated by the VMM while the vCPU waits on the
+        // returned barrier.
+        let barrier = write_reg(
+            mmio,
+            VIRTIO_MMIO_STATUS,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK,
+        )
+        .unwrap();
+        let mut activator = pending.lock().unwrap().pop().unwrap();
+        let activation = thread::spawn(move || activator.activate());
+        barrier.wait();
+        activation.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_identification_registers() {
+        let (mut mmio, _) = create_device(Arc::new(Mutex::new(DummyDevice::default())));
+
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_MAGIC_VALUE), 0x7472_6976);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_VERSION_REG), 2);
+        assert_eq!(
+            read_reg(&mut mmio, VIRTIO_MMIO_DEVICE_ID),
+            DUMMY_DEVICE_TYPE
+        );
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_VENDOR_ID_REG), 0x1af4);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_SHM_LEN_LOW), u32::MAX);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_STATUS), DEVICE_INIT);
+
+        // Registers are only accessed 32 bits at a time.
+        let mut data = [0xffu8; 2];
+        mmio.read(0, VIRTIO_MMIO_MAGIC_VALUE, &mut data);
+        assert_eq!(data, [0u8; 2]);
+        mmio.write(0, VIRTIO_MMIO_STATUS, &[DEVICE_ACKNOWLEDGE as u8]);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_STATUS), DEVICE_INIT);
+
+        // Unknown registers read as zero.
+        assert_eq!(read_reg(&mut mmio, 0x0f0), 0);
+    }
+
+    #[test]
+    fn test_feature_selection() {
+        let device = Arc::new(Mutex::new(DummyDevice::default()));
+        let (mut mmio, _) = create_device(device.clone());
+
+        write_reg(&mut mmio, VIRTIO_MMIO_DEVICE_FEATURES_SEL, 0);
+        assert_eq!(
+            read_reg(&mut mmio, VIRTIO_MMIO_DEVICE_FEATURES),
+            DUMMY_FEATURES as u32
+        );
+        write_reg(&mut mmio, VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+        assert_eq!(
+            read_reg(&mut mmio, VIRTIO_MMIO_DEVICE_FEATURES),
+            (DUMMY_FEATURES >> 32) as u32
+        );
+        write_reg(&mut mmio, VIRTIO_MMIO_DEVICE_FEATURES_SEL, 2);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_DEVICE_FEATURES), 0);
+
+        write_reg(&mut mmio, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0);
+        write_reg(&mut mmio, VIRTIO_MMIO_DRIVER_FEATURES, 0x5555_0000);
+        write_reg(&mut mmio, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        write_reg(&mut mmio, VIRTIO_MMIO_DRIVER_FEATURES, 0x1);
+        // Pages past the second one are ignored.
+        write_reg(&mut mmio, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 2);
+        write_reg(&mut mmio, VIRTIO_MMIO_DRIVER_FEATURES, 0xffff_ffff);
+        assert_eq!(device.lock().unwrap().acked_features, 0x1_5555_0000);
+    }
+
+    #[test]
+    fn test_queue_setup() {
+        let device = Arc::new(Mutex::new(DummyDevice::default()));
+        let (mut mmio, pending) = create_device(device.clone());
+
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_SEL, 1);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_QUEUE_NUM_MAX), 128);
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_SEL, 2);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_QUEUE_NUM_MAX), 0);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_QUEUE_READY), 0);
+
+        setup_device(&mut mmio, &pending);
+
+        assert!(mmio.device_activated.load(Ordering::SeqCst));
+        assert_eq!(device.lock().unwrap().activated_queues, vec![0]);
+        assert_eq!(device.lock().unwrap().acked_features, 1 << 32);
+        let queue = &mmio.queues[0];
+        assert_eq!(queue.size(), 16);
+        assert!(queue.ready());
+        assert_eq!(queue.desc_table(), 0x1000);
+        assert_eq!(queue.avail_ring(), 0x2000);
+        assert_eq!(queue.used_ring(), 0x3000);
+        assert!(!mmio.queues[1].ready());
+
+        // Queues can't be changed once the device is running.
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_SEL, 0);
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_NUM, 32);
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x4000);
+        assert_eq!(mmio.queues[0].size(), 16);
+        assert_eq!(mmio.queues[0].desc_table(), 0x1000);
+
+        // Notifications without ioeventfd are forwarded to the queue event.
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+        assert_eq!(mmio.queue_evts()[0].read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_interrupt_status() {
+        let device = Arc::new(Mutex::new(DummyDevice::default()));
+        let (mut mmio, pending) = create_device(device.clone());
+        setup_device(&mut mmio, &pending);
+
+        let interrupt = device.lock().unwrap().interrupt.clone().unwrap();
+        interrupt.trigger(VirtioInterruptType::Queue(0)).unwrap();
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(
+            read_reg(&mut mmio, VIRTIO_MMIO_INTERRUPT_STATUS),
+            (VIRTIO_MMIO_INT_VRING | VIRTIO_MMIO_INT_CONFIG) as u32
+        );
+
+        write_reg(
+            &mut mmio,
+            VIRTIO_MMIO_INTERRUPT_ACK,
+            VIRTIO_MMIO_INT_VRING as u32,
+        );
+        assert_eq!(
+            read_reg(&mut mmio, VIRTIO_MMIO_INTERRUPT_STATUS),
+            VIRTIO_MMIO_INT_CONFIG as u32
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let device = Arc::new(Mutex::new(DummyDevice::default()));
+        let (mut mmio, pending) = create_device(device.clone());
+        setup_device(&mut mmio, &pending);
+        device
+            .lock()
+            .unwrap()
+            .interrupt
+            .as_ref()
+            .unwrap()
+            .trigger(VirtioInterruptType::Config)
+            .unwrap();
+        write_reg(&mut mmio, VIRTIO_MMIO_QUEUE_SEL, 1);
+
+        // Writing zero to the status register resets the device.
+        assert!(write_reg(&mut mmio, VIRTIO_MMIO_STATUS, DEVICE_INIT).is_none());
+        assert!(!mmio.device_activated.load(Ordering::SeqCst));
+        assert!(device.lock().unwrap().interrupt.is_none());
+        assert!(mmio.virtio_interrupt.is_some());
+        assert!(!mmio.queues[0].ready());
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+        assert_eq!(mmio.queue_select, 0);
+        assert_eq!(read_reg(&mut mmio, VIRTIO_MMIO_STATUS), DEVICE_INIT);
+
+        // The device can be set up again after the reset.
+        setup_device(&mut mmio, &pending);
+        assert!(mmio.device_activated.load(Ordering::SeqCst));
+    }
+}
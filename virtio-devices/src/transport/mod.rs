@@ -3,8 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use vmm_sys_util::eventfd::EventFd;
+mod mmio;
 mod pci_common_config;
 mod pci_device;
+pub use mmio::{
+    VirtioMmioDevice, VirtioMmioDeviceError, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_SIZE,
+};
 pub use pci_common_config::{VirtioPciCommonConfig, VIRTIO_PCI_COMMON_CONFIG_ID};
 pub use pci_device::{VirtioPciDevice, VirtioPciDeviceActivator, VirtioPciDeviceError};
 
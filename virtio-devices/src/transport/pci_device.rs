@@ -18,7 +18,8 @@ use libc::EFD_NONBLOCK;
 use pci::{
     BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
     PciCapability, PciCapabilityId, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
+    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciPmCap, PciPowerState,
+    PciSubclass,
 };
 use serde::{Deserialize, Serialize};
 use std::any::Any;
@@ -40,7 +41,7 @@ use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottabl
 use vm_virtio::AccessPlatform;
 use vmm_sys_util::eventfd::EventFd;
 
-use super::pci_common_config::VirtioPciCommonConfigState;
+use super::pci_common_config::{get_vring_size, VirtioPciCommonConfigState, VringType};
 
 /// Vector value used to disable MSI for a queue.
 const VIRTQ_MSI_NO_VECTOR: u16 = 0xffff;
@@ -260,6 +261,43 @@ const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
+// Transitional devices expose the legacy interface through an I/O BAR 0, as
+// expected by legacy drivers, the modern capabilities being moved to BAR 4.
+const VIRTIO_LEGACY_BAR_INDEX: usize = 0;
+const VIRTIO_TRANSITIONAL_COMMON_BAR_INDEX: usize = 4;
+const VIRTIO_LEGACY_BAR_SIZE: u64 = 0x100;
+
+// Legacy interface registers, see section 4.1.4.8 of the virtio 1.2 spec.
+const VIRTIO_LEGACY_HOST_FEATURES: u64 = 0;
+const VIRTIO_LEGACY_GUEST_FEATURES: u64 = 4;
+const VIRTIO_LEGACY_QUEUE_PFN: u64 = 8;
+const VIRTIO_LEGACY_QUEUE_NUM: u64 = 12;
+const VIRTIO_LEGACY_QUEUE_SEL: u64 = 14;
+const VIRTIO_LEGACY_QUEUE_NOTIFY: u64 = 16;
+const VIRTIO_LEGACY_STATUS: u64 = 18;
+const VIRTIO_LEGACY_ISR: u64 = 19;
+const VIRTIO_LEGACY_MSI_CONFIG_VECTOR: u64 = 20;
+const VIRTIO_LEGACY_MSI_QUEUE_VECTOR: u64 = 22;
+// The device configuration follows the MSI-X vectors only when MSI-X is
+// enabled.
+const VIRTIO_LEGACY_CONFIG: u64 = 20;
+const VIRTIO_LEGACY_CONFIG_MSIX: u64 = 24;
+const VIRTIO_LEGACY_QUEUE_ADDR_SHIFT: u64 = 12;
+const VIRTIO_LEGACY_VRING_ALIGN: u64 = 4096;
+
+/// PCI device ID of the transitional flavor of a device, if it has one.
+fn transitional_device_id(device_type: u32) -> Option<u16> {
+    match VirtioDeviceType::from(device_type) {
+        VirtioDeviceType::Net => Some(0x1000),
+        VirtioDeviceType::Block => Some(0x1001),
+        VirtioDeviceType::Balloon => Some(0x1002),
+        VirtioDeviceType::Console => Some(0x1003),
+        VirtioDeviceType::Rng => Some(0x1005),
+        VirtioDeviceType::Fs9P => Some(0x1009),
+        _ => None,
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct QueueState {
     max_size: u16,
@@ -277,6 +315,8 @@ pub struct VirtioPciDeviceState {
     interrupt_status: usize,
     cap_pci_cfg_offset: usize,
     cap_pci_cfg: Vec<u8>,
+    #[serde(default)]
+    legacy: bool,
 }
 
 pub struct VirtioPciDeviceActivator {
@@ -290,6 +330,26 @@ pub struct VirtioPciDeviceActivator {
 }
 
 impl VirtioPciDeviceActivator {
+    pub(crate) fn new(
+        id: String,
+        interrupt: Option<Arc<dyn VirtioInterrupt>>,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        device_activated: Arc<AtomicBool>,
+        queues: Vec<(usize, Queue, EventFd)>,
+        barrier: Option<Arc<Barrier>>,
+    ) -> Self {
+        VirtioPciDeviceActivator {
+            interrupt,
+            memory: Some(memory),
+            device,
+            device_activated,
+            queues: Some(queues),
+            barrier,
+            id,
+        }
+    }
+
     pub fn activate(&mut self) -> ActivateResult {
         self.device.lock().unwrap().activate(
             self.memory.take().unwrap(),
@@ -352,6 +412,12 @@ pub struct VirtioPciDevice {
     // Whether to use 64-bit bar location or 32-bit
     use_64bit_bar: bool,
 
+    // Whether the legacy interface is exposed along with the modern one
+    transitional: bool,
+
+    // Whether the driver is using the legacy interface
+    legacy: bool,
+
     // Add a dedicated structure to hold information about the very specific
     // virtio-pci capability VIRTIO_PCI_CAP_PCI_CFG. This is needed to support
     // the legacy/backward compatible mechanism of letting the guest access the
@@ -371,6 +437,9 @@ pub struct VirtioPciDevice {
 
     // Pending activations
     pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+
+    // Handle to pause the virtio device while the driver keeps it in D3hot
+    device_pausable: Option<Arc<Mutex<dyn Migratable>>>,
 }
 
 impl VirtioPciDevice {
@@ -386,8 +455,10 @@ impl VirtioPciDevice {
         pci_device_bdf: u32,
         activate_evt: EventFd,
         use_64bit_bar: bool,
+        transitional: bool,
         dma_handler: Option<Arc<dyn ExternalDmaMapping>>,
         pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+        device_pausable: Option<Arc<Mutex<dyn Migratable>>>,
         snapshot: Option<Snapshot>,
     ) -> Result<Self> {
         let mut locked_device = device.lock().unwrap();
@@ -412,7 +483,18 @@ impl VirtioPciDevice {
             .map(|&s| Queue::new(s).unwrap())
             .collect();
 
-        let pci_device_id = VIRTIO_PCI_DEVICE_ID_BASE + locked_device.device_type() as u16;
+        let device_type = locked_device.device_type();
+        let (pci_device_id, revision_id) = if transitional {
+            let pci_device_id = transitional_device_id(device_type).ok_or_else(|| {
+                VirtioPciDeviceError::CreateVirtioPciDevice(anyhow!(
+                    "No transitional PCI device ID for virtio device type {}",
+                    device_type
+                ))
+            })?;
+            (pci_device_id, 0x0)
+        } else {
+            (VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16, 0x1)
+        };
 
         let interrupt_source_group = interrupt_manager
             .create_group(MsiIrqGroupConfig {
@@ -478,13 +560,18 @@ impl VirtioPciDevice {
         let configuration = PciConfiguration::new(
             VIRTIO_PCI_VENDOR_ID,
             pci_device_id,
-            0x1, // For modern virtio-PCI devices
+            revision_id, // 0x1 for modern virtio-PCI devices, 0x0 for transitional ones
             class,
             subclass,
             None,
             PciHeaderType::Device,
             VIRTIO_PCI_VENDOR_ID,
-            pci_device_id,
+            // Legacy drivers identify the device type from the subsystem ID.
+            if transitional {
+                device_type as u16
+            } else {
+                pci_device_id
+            },
             msix_config_clone,
             pci_configuration_state,
         );
@@ -527,45 +614,47 @@ impl VirtioPciDevice {
                 ))
             })?;
 
-        let (device_activated, interrupt_status, cap_pci_cfg_info) = if let Some(state) = state {
-            // Update virtqueues indexes for both available and used rings.
-            for (i, queue) in queues.iter_mut().enumerate() {
-                queue.set_size(state.queues[i].size);
-                queue.set_ready(state.queues[i].ready);
-                queue
-                    .try_set_desc_table_address(GuestAddress(state.queues[i].desc_table))
-                    .unwrap();
-                queue
-                    .try_set_avail_ring_address(GuestAddress(state.queues[i].avail_ring))
-                    .unwrap();
-                queue
-                    .try_set_used_ring_address(GuestAddress(state.queues[i].used_ring))
-                    .unwrap();
-                queue.set_next_avail(
+        let (device_activated, interrupt_status, cap_pci_cfg_info, legacy) =
+            if let Some(state) = state {
+                // Update virtqueues indexes for both available and used rings.
+                for (i, queue) in queues.iter_mut().enumerate() {
+                    queue.set_size(state.queues[i].size);
+                    queue.set_ready(state.queues[i].ready);
                     queue
-                        .used_idx(memory.memory().deref(), Ordering::Acquire)
-                        .unwrap()
-                        .0,
-                );
-                queue.set_next_used(
+                        .try_set_desc_table_address(GuestAddress(state.queues[i].desc_table))
+                        .unwrap();
                     queue
-                        .used_idx(memory.memory().deref(), Ordering::Acquire)
-                        .unwrap()
-                        .0,
-                );
-            }
+                        .try_set_avail_ring_address(GuestAddress(state.queues[i].avail_ring))
+                        .unwrap();
+                    queue
+                        .try_set_used_ring_address(GuestAddress(state.queues[i].used_ring))
+                        .unwrap();
+                    queue.set_next_avail(
+                        queue
+                            .used_idx(memory.memory().deref(), Ordering::Acquire)
+                            .unwrap()
+                            .0,
+                    );
+                    queue.set_next_used(
+                        queue
+                            .used_idx(memory.memory().deref(), Ordering::Acquire)
+                            .unwrap()
+                            .0,
+                    );
+                }
 
-            (
-                state.device_activated,
-                state.interrupt_status,
-                VirtioPciCfgCapInfo {
-                    offset: state.cap_pci_cfg_offset,
-                    cap: *VirtioPciCfgCap::from_slice(&state.cap_pci_cfg).unwrap(),
-                },
-            )
-        } else {
-            (false, 0, VirtioPciCfgCapInfo::default())
-        };
+                (
+                    state.device_activated,
+                    state.interrupt_status,
+                    VirtioPciCfgCapInfo {
+                        offset: state.cap_pci_cfg_offset,
+                        cap: *VirtioPciCfgCap::from_slice(&state.cap_pci_cfg).unwrap(),
+                    },
+                    state.legacy,
+                )
+            } else {
+                (false, 0, VirtioPciCfgCapInfo::default(), false)
+            };
 
         // Dropping the MutexGuard to unlock the VirtioDevice. This is required
         // in the context of a restore given the device might require some
@@ -586,14 +675,21 @@ impl VirtioPciDevice {
             queues,
             queue_evts,
             memory,
-            settings_bar: 0,
+            settings_bar: if transitional {
+                VIRTIO_TRANSITIONAL_COMMON_BAR_INDEX as u8
+            } else {
+                VIRTIO_COMMON_BAR_INDEX as u8
+            },
             use_64bit_bar,
+            transitional,
+            legacy,
             interrupt_source_group,
             cap_pci_cfg_info,
             bar_regions: vec![],
             activate_evt,
             dma_handler,
             pending_activations,
+            device_pausable,
         };
 
         if let Some(msix_config) = &virtio_pci_device.msix_config {
@@ -640,6 +736,7 @@ impl VirtioPciDevice {
                 .collect(),
             cap_pci_cfg_offset: self.cap_pci_cfg_info.offset,
             cap_pci_cfg: self.cap_pci_cfg_info.cap.bytes().to_vec(),
+            legacy: self.legacy,
         }
     }
 
@@ -651,8 +748,12 @@ impl VirtioPciDevice {
     }
 
     fn is_driver_ready(&self) -> bool {
-        let ready_bits =
-            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
+        // Legacy drivers don't go through the FEATURES_OK step.
+        let ready_bits = if self.legacy {
+            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK) as u8
+        } else {
+            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8
+        };
         self.common_config.driver_status == ready_bits
             && self.common_config.driver_status & DEVICE_FAILED as u8 == 0
     }
@@ -734,6 +835,10 @@ impl VirtioPciDevice {
                 .map_err(PciDeviceError::CapabilitiesSetup)?;
         }
 
+        self.configuration
+            .add_capability(&PciPmCap::new())
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+
         self.settings_bar = settings_bar;
         Ok(())
     }
@@ -757,7 +862,7 @@ impl VirtioPciDevice {
             let bar_offset: u32 =
                 // SAFETY: we know self.cap_pci_cfg_info.cap.cap.offset is 32bits long.
                 unsafe { std::mem::transmute(self.cap_pci_cfg_info.cap.cap.offset) };
-            self.read_bar(0, bar_offset as u64, data)
+            self.read_bar(self.config_bar_addr(), bar_offset as u64, data)
         }
     }
 
@@ -778,7 +883,7 @@ impl VirtioPciDevice {
             let bar_offset: u32 =
                 // SAFETY: we know self.cap_pci_cfg_info.cap.cap.offset is 32bits long.
                 unsafe { std::mem::transmute(self.cap_pci_cfg_info.cap.cap.offset) };
-            self.write_bar(0, bar_offset as u64, data)
+            self.write_bar(self.config_bar_addr(), bar_offset as u64, data)
         }
     }
 
@@ -805,15 +910,15 @@ impl VirtioPciDevice {
             ));
         }
 
-        VirtioPciDeviceActivator {
-            interrupt: self.virtio_interrupt.take(),
-            memory: Some(self.memory.clone()),
-            device: self.device.clone(),
-            queues: Some(queues),
-            device_activated: self.device_activated.clone(),
+        VirtioPciDeviceActivator::new(
+            self.id.clone(),
+            self.virtio_interrupt.take(),
+            self.memory.clone(),
+            self.device.clone(),
+            self.device_activated.clone(),
+            queues,
             barrier,
-            id: self.id.clone(),
-        }
+        )
     }
 
     fn activate(&mut self) -> ActivateResult {
@@ -824,9 +929,190 @@ impl VirtioPciDevice {
         !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
     }
 
+    /// Moves the device to the given power state, as if the driver did.
+    pub fn set_power_state(&mut self, power_state: PciPowerState) {
+        if let Some(old_power_state) = self.configuration.power_state() {
+            self.configuration.set_power_state(power_state);
+            self.update_power_state(old_power_state);
+        }
+    }
+
+    // The virtio device is paused in D3hot, for its queues not to be
+    // processed until the driver brings it back to D0.
+    fn update_power_state(&self, old_power_state: PciPowerState) {
+        let Some(device_pausable) = &self.device_pausable else {
+            return;
+        };
+
+        match (old_power_state, self.configuration.power_state()) {
+            (PciPowerState::D0, Some(PciPowerState::D3Hot)) => {
+                info!("{}: Entering D3hot", self.id);
+                if let Err(e) = device_pausable.lock().unwrap().pause() {
+                    warn!("{}: Failed pausing the device in D3hot: {}", self.id, e);
+                }
+            }
+            (PciPowerState::D3Hot, Some(PciPowerState::D0)) => {
+                info!("{}: Leaving D3hot", self.id);
+                if let Err(e) = device_pausable.lock().unwrap().resume() {
+                    error!("{}: Failed resuming the device in D0: {}", self.id, e);
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub fn dma_handler(&self) -> Option<&Arc<dyn ExternalDmaMapping>> {
         self.dma_handler.as_ref()
     }
+
+    fn is_legacy_bar(&self, base: u64) -> bool {
+        self.transitional
+            && self.bar_regions.iter().any(|bar| {
+                bar.idx() == VIRTIO_LEGACY_BAR_INDEX
+                    && bar.region_type() == PciBarRegionType::IoRegion
+                    && bar.addr() == base
+            })
+    }
+
+    fn legacy_config_offset(&self) -> u64 {
+        if self
+            .msix_config
+            .as_ref()
+            .is_some_and(|msix_config| msix_config.lock().unwrap().enabled())
+        {
+            VIRTIO_LEGACY_CONFIG_MSIX
+        } else {
+            VIRTIO_LEGACY_CONFIG
+        }
+    }
+
+    // With the legacy interface, the driver only provides the guest page
+    // frame of the vring, laid out with a 4KiB alignment and the maximum
+    // queue size.
+    fn set_legacy_queue_pfn(&mut self, pfn: u32) {
+        let Some(queue) = self
+            .queues
+            .get_mut(self.common_config.queue_select as usize)
+        else {
+            return;
+        };
+
+        if pfn == 0 {
+            queue.set_ready(false);
+            return;
+        }
+
+        let size = queue.max_size();
+        let desc_table = u64::from(pfn) << VIRTIO_LEGACY_QUEUE_ADDR_SHIFT;
+        let avail_ring = desc_table + get_vring_size(VringType::Desc, size);
+        let used_ring = (avail_ring + get_vring_size(VringType::Avail, size))
+            .next_multiple_of(VIRTIO_LEGACY_VRING_ALIGN);
+
+        queue.set_size(size);
+        queue.set_desc_table_address(Some(desc_table as u32), Some((desc_table >> 32) as u32));
+        queue.set_avail_ring_address(Some(avail_ring as u32), Some((avail_ring >> 32) as u32));
+        queue.set_used_ring_address(Some(used_ring as u32), Some((used_ring >> 32) as u32));
+        queue.set_ready(true);
+    }
+
+    fn read_legacy_bar(&mut self, offset: u64, data: &mut [u8]) {
+        let config_offset = self.legacy_config_offset();
+        if offset >= config_offset {
+            let device = self.device.lock().unwrap();
+            device.read_config(offset - config_offset, data);
+            return;
+        }
+
+        let queue_select = self.common_config.queue_select as usize;
+        let queue = self.queues.get(queue_select);
+        let value: u32 = match (offset, data.len()) {
+            (VIRTIO_LEGACY_HOST_FEATURES, 4) => self.device.lock().unwrap().features() as u32,
+            (VIRTIO_LEGACY_QUEUE_PFN, 4) => queue.filter(|q| q.ready()).map_or(0, |q| {
+                (q.desc_table() >> VIRTIO_LEGACY_QUEUE_ADDR_SHIFT) as u32
+            }),
+            (VIRTIO_LEGACY_QUEUE_NUM, 2) => queue.map_or(0, |q| u32::from(q.max_size())),
+            (VIRTIO_LEGACY_QUEUE_SEL, 2) => u32::from(self.common_config.queue_select),
+            (VIRTIO_LEGACY_STATUS, 1) => u32::from(self.common_config.driver_status),
+            // Reading this register resets it to 0.
+            (VIRTIO_LEGACY_ISR, 1) => self.interrupt_status.swap(0, Ordering::AcqRel) as u32,
+            (VIRTIO_LEGACY_MSI_CONFIG_VECTOR, 2) => {
+                u32::from(self.common_config.msix_config.load(Ordering::Acquire))
+            }
+            (VIRTIO_LEGACY_MSI_QUEUE_VECTOR, 2) => u32::from(
+                self.common_config
+                    .msix_queues
+                    .lock()
+                    .unwrap()
+                    .get(queue_select)
+                    .copied()
+                    .unwrap_or(VIRTQ_MSI_NO_VECTOR),
+            ),
+            _ => {
+                warn!(
+                    "invalid legacy virtio register read: offset 0x{:x}, len {}",
+                    offset,
+                    data.len()
+                );
+                data.fill(0);
+                return;
+            }
+        };
+        data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+    }
+
+    fn write_legacy_bar(&mut self, offset: u64, data: &[u8]) {
+        // Only legacy drivers access this BAR.
+        self.legacy = true;
+
+        let config_offset = self.legacy_config_offset();
+        if offset >= config_offset {
+            let mut device = self.device.lock().unwrap();
+            device.write_config(offset - config_offset, data);
+            return;
+        }
+
+        let value = match *data {
+            [b0] => u32::from(b0),
+            [b0, b1] => u32::from(u16::from_le_bytes([b0, b1])),
+            [b0, b1, b2, b3] => u32::from_le_bytes([b0, b1, b2, b3]),
+            _ => 0,
+        };
+        match (offset, data.len()) {
+            (VIRTIO_LEGACY_GUEST_FEATURES, 4) => {
+                // Only the first 32 feature bits are known to legacy drivers.
+                self.device.lock().unwrap().ack_features(u64::from(value));
+            }
+            (VIRTIO_LEGACY_QUEUE_PFN, 4) => self.set_legacy_queue_pfn(value),
+            (VIRTIO_LEGACY_QUEUE_SEL, 2) => self.common_config.queue_select = value as u16,
+            (VIRTIO_LEGACY_QUEUE_NOTIFY, 2) => {
+                if let Some(queue_evt) = self.queue_evts.get(value as usize) {
+                    queue_evt.write(1).ok();
+                }
+            }
+            (VIRTIO_LEGACY_STATUS, 1) => self.common_config.driver_status = value as u8,
+            (VIRTIO_LEGACY_MSI_CONFIG_VECTOR, 2) => self
+                .common_config
+                .msix_config
+                .store(value as u16, Ordering::Release),
+            (VIRTIO_LEGACY_MSI_QUEUE_VECTOR, 2) => {
+                let queue_select = self.common_config.queue_select as usize;
+                if let Some(vector) = self
+                    .common_config
+                    .msix_queues
+                    .lock()
+                    .unwrap()
+                    .get_mut(queue_select)
+                {
+                    *vector = value as u16;
+                }
+            }
+            _ => warn!(
+                "invalid legacy virtio register write: offset 0x{:x}, len {}",
+                offset,
+                data.len()
+            ),
+        }
+    }
 }
 
 impl VirtioTransport for VirtioPciDevice {
@@ -928,8 +1214,12 @@ impl PciDevice for VirtioPciDevice {
             let offset = base + offset as usize - self.cap_pci_cfg_info.offset;
             self.write_cap_pci_cfg(offset, data)
         } else {
+            let power_state = self.configuration.power_state();
             self.configuration
                 .write_config_register(reg_idx, offset, data);
+            if let Some(power_state) = power_state {
+                self.update_power_state(power_state);
+            }
             None
         }
     }
@@ -970,7 +1260,9 @@ impl PciDevice for VirtioPciDevice {
         let device_clone = self.device.clone();
         let device = device_clone.lock().unwrap();
 
+        let settings_bar_index = self.settings_bar as usize;
         let mut settings_bar_addr = None;
+        let mut legacy_bar_addr = None;
         let mut use_64bit_bar = self.use_64bit_bar;
         let restoring = resources.is_some();
         if let Some(resources) = resources {
@@ -979,7 +1271,7 @@ impl PciDevice for VirtioPciDevice {
                     index, base, type_, ..
                 } = resource
                 {
-                    if index == VIRTIO_COMMON_BAR_INDEX {
+                    if index == settings_bar_index {
                         settings_bar_addr = Some(GuestAddress(base));
                         use_64bit_bar = match type_ {
                             PciBarType::Io => {
@@ -988,12 +1280,16 @@ impl PciDevice for VirtioPciDevice {
                             PciBarType::Mmio32 => false,
                             PciBarType::Mmio64 => true,
                         };
-                        break;
+                    } else if self.transitional && index == VIRTIO_LEGACY_BAR_INDEX {
+                        if !matches!(type_, PciBarType::Io) {
+                            return Err(PciDeviceError::InvalidResource(resource));
+                        }
+                        legacy_bar_addr = Some(GuestAddress(base));
                     }
                 }
             }
             // Error out if no resource was matching the BAR id.
-            if settings_bar_addr.is_none() {
+            if settings_bar_addr.is_none() || (self.transitional && legacy_bar_addr.is_none()) {
                 return Err(PciDeviceError::MissingResource);
             }
         }
@@ -1023,7 +1319,7 @@ impl PciDevice for VirtioPciDevice {
         };
 
         let bar = PciBarConfiguration::default()
-            .set_index(VIRTIO_COMMON_BAR_INDEX)
+            .set_index(settings_bar_index)
             .set_address(virtio_pci_bar_addr.raw_value())
             .set_size(CAPABILITY_BAR_SIZE)
             .set_region_type(region_type);
@@ -1038,11 +1334,44 @@ impl PciDevice for VirtioPciDevice {
             })?;
 
             // Once the BARs are allocated, the capabilities can be added to the PCI configuration.
-            self.add_pci_capabilities(VIRTIO_COMMON_BAR_INDEX as u8)?;
+            self.add_pci_capabilities(settings_bar_index as u8)?;
         }
 
         bars.push(bar);
 
+        // Allocate the I/O BAR holding the legacy interface.
+        if self.transitional {
+            #[cfg(target_arch = "x86_64")]
+            {
+                let addr = _allocator
+                    .lock()
+                    .unwrap()
+                    .allocate_io_addresses(
+                        legacy_bar_addr,
+                        VIRTIO_LEGACY_BAR_SIZE,
+                        Some(VIRTIO_LEGACY_BAR_SIZE),
+                    )
+                    .ok_or(PciDeviceError::IoAllocationFailed(VIRTIO_LEGACY_BAR_SIZE))?;
+
+                let bar = PciBarConfiguration::default()
+                    .set_index(VIRTIO_LEGACY_BAR_INDEX)
+                    .set_address(addr.raw_value())
+                    .set_size(VIRTIO_LEGACY_BAR_SIZE)
+                    .set_region_type(PciBarRegionType::IoRegion);
+
+                if !restoring {
+                    self.configuration
+                        .add_pci_bar(&bar)
+                        .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?;
+                }
+
+                bars.push(bar);
+            }
+            // There is no I/O port space for the legacy interface.
+            #[cfg(not(target_arch = "x86_64"))]
+            return Err(PciDeviceError::IoAllocationFailed(VIRTIO_LEGACY_BAR_SIZE));
+        }
+
         // Allocate a dedicated BAR if there are some shared memory regions.
         if let Some(shm_list) = device.get_shm_regions() {
             let bar = PciBarConfiguration::default()
@@ -1095,6 +1424,10 @@ impl PciDevice for VirtioPciDevice {
                 PciBarRegionType::Memory64BitRegion => {
                     mmio64_allocator.free(GuestAddress(bar.addr()), bar.size());
                 }
+                #[cfg(target_arch = "x86_64")]
+                PciBarRegionType::IoRegion if self.transitional => {
+                    _allocator.free_io_addresses(GuestAddress(bar.addr()), bar.size());
+                }
                 _ => error!("Unexpected PCI bar type"),
             }
         }
@@ -1117,7 +1450,12 @@ impl PciDevice for VirtioPciDevice {
         Ok(())
     }
 
-    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        if self.is_legacy_bar(base) {
+            self.read_legacy_bar(offset, data);
+            return;
+        }
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.read(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -1162,14 +1500,18 @@ impl PciDevice for VirtioPciDevice {
         }
     }
 
-    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
         match offset {
-            o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.write(
-                o - COMMON_CONFIG_BAR_OFFSET,
-                data,
-                &mut self.queues,
-                self.device.clone(),
-            ),
+            _ if self.is_legacy_bar(base) => self.write_legacy_bar(offset, data),
+            o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => {
+                self.legacy = false;
+                self.common_config.write(
+                    o - COMMON_CONFIG_BAR_OFFSET,
+                    data,
+                    &mut self.queues,
+                    self.device.clone(),
+                )
+            }
             o if (ISR_CONFIG_BAR_OFFSET..ISR_CONFIG_BAR_OFFSET + ISR_CONFIG_SIZE).contains(&o) => {
                 if let Some(v) = data.first() {
                     self.interrupt_status
@@ -1186,8 +1528,8 @@ impl PciDevice for VirtioPciDevice {
                 .contains(&o) =>
             {
                 #[cfg(feature = "sev_snp")]
-                for (_event, _addr) in self.ioeventfds(_base) {
-                    if _addr == _base + offset {
+                for (_event, _addr) in self.ioeventfds(base) {
+                    if _addr == base + offset {
                         _event.write(1).unwrap();
                     }
                 }
@@ -1260,10 +1602,20 @@ impl PciDevice for VirtioPciDevice {
 
 impl BusDevice for VirtioPciDevice {
     fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        // Only the configuration space is accessible in D3hot.
+        if self.configuration.power_state() == Some(PciPowerState::D3Hot) {
+            data.fill(0xff);
+            return;
+        }
+
         self.read_bar(base, offset, data)
     }
 
     fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if self.configuration.power_state() == Some(PciPowerState::D3Hot) {
+            return None;
+        }
+
         self.write_bar(base, offset, data)
     }
 }
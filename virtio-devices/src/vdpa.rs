@@ -6,17 +6,18 @@
 use crate::{
     ActivateError, ActivateResult, GuestMemoryMmap, VirtioCommon, VirtioDevice, VirtioInterrupt,
     VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FEATURES_OK,
-    VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_PACKED,
 };
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
-    io, result,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
+    fs::File,
+    io,
+    os::{raw::c_uint, unix::fs::FileTypeExt},
+    path::Path,
+    result,
+    sync::{atomic::Ordering, Arc, Mutex},
 };
 use thiserror::Error;
 use vhost::{
@@ -31,6 +32,34 @@ use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic}
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vm_virtio::{AccessPlatform, Translatable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr};
+
+// See include/uapi/linux/vhost.h and vhost_types.h in the kernel code.
+const VHOST_VIRTIO: c_uint = 0xaf;
+const VHOST_BACKEND_F_RESUME: u64 = 0x5;
+ioctl_io_nr!(VHOST_VDPA_RESUME, VHOST_VIRTIO, 0x7e);
+ioctl_ior_nr!(VHOST_VDPA_GET_VQS_COUNT, VHOST_VIRTIO, 0x80, u32);
+
+/// Whether the given path is a device node, which can only be a vhost-vdpa
+/// device when hot-added as a generic device, VFIO devices being identified
+/// through their sysfs directory.
+pub fn is_vdpa_device_path(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_char_device())
+}
+
+/// Number of virtqueues exposed by the vhost-vdpa device at the given path.
+pub fn vdpa_num_queues(path: &Path) -> io::Result<usize> {
+    let file = File::options().read(true).write(true).open(path)?;
+    let mut count: u32 = 0;
+    // SAFETY: the file descriptor is a valid vhost-vdpa device and the ioctl
+    // only writes to the given u32.
+    let ret = unsafe { ioctl_with_mut_ref(&file, VHOST_VDPA_GET_VQS_COUNT(), &mut count) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(count as usize)
+}
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -56,12 +85,16 @@ pub enum Error {
     GetIovaRange(vhost::Error),
     #[error("Failed to get queue size: {0}")]
     GetVringNum(vhost::Error),
+    #[error("Failed to get vring base: {0}")]
+    GetVringBase(vhost::Error),
     #[error("Invalid IOVA range: {0}-{1}")]
     InvalidIovaRange(u64, u64),
     #[error("Missing VIRTIO_F_ACCESS_PLATFORM feature")]
     MissingAccessPlatformVirtioFeature,
     #[error("Failed to reset owner: {0}")]
     ResetOwner(vhost::Error),
+    #[error("Failed to resume the device: {0}")]
+    Resume(io::Error),
     #[error("Failed to set backend specific features: {0}")]
     SetBackendFeatures(vhost::Error),
     #[error("Failed to set backend configuration: {0}")]
@@ -88,6 +121,8 @@ pub enum Error {
     SetVringKick(vhost::Error),
     #[error("Failed to set vring size: {0}")]
     SetVringNum(vhost::Error),
+    #[error("Failed to suspend the device: {0}")]
+    Suspend(vhost::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -102,6 +137,8 @@ pub struct VdpaState {
     pub config: Vec<u8>,
     pub queue_sizes: Vec<u16>,
     pub backend_features: u64,
+    #[serde(default)]
+    pub vring_bases: Vec<(usize, u16)>,
 }
 
 pub struct Vdpa {
@@ -112,6 +149,12 @@ pub struct Vdpa {
     enabled_queues: BTreeMap<usize, bool>,
     backend_features: u64,
     migrating: bool,
+    // Indexes of the activated vrings.
+    vrings: Vec<usize>,
+    suspended: bool,
+    // Next available index of each vring, saved when the device is
+    // suspended or restored from a snapshot.
+    vring_bases: Vec<(usize, u16)>,
 }
 
 impl Vdpa {
@@ -132,7 +175,7 @@ impl Vdpa {
             queue_sizes,
             iova_range,
             backend_features,
-            paused,
+            vring_bases,
         ) = if let Some(state) = state {
             info!("Restoring vDPA {}", id);
 
@@ -151,12 +194,15 @@ impl Vdpa {
                     last: state.iova_range_last,
                 },
                 state.backend_features,
-                false,
+                state.vring_bases,
             )
         } else {
             let device_type = vhost.get_device_id().map_err(Error::GetDeviceId)?;
             let queue_size = vhost.get_vring_num().map_err(Error::GetVringNum)?;
-            let avail_features = vhost.get_features().map_err(Error::GetFeatures)?;
+            // The vring bases saved for migration only hold the split ring
+            // available index, the packed layout must not be negotiated.
+            let avail_features =
+                vhost.get_features().map_err(Error::GetFeatures)? & !(1u64 << VIRTIO_F_RING_PACKED);
             let backend_features = vhost
                 .get_backend_features()
                 .map_err(Error::GetBackendFeatures)?;
@@ -175,7 +221,7 @@ impl Vdpa {
                 vec![queue_size; num_queues as usize],
                 iova_range,
                 backend_features,
-                false,
+                Vec::new(),
             )
         };
 
@@ -186,7 +232,6 @@ impl Vdpa {
                 avail_features,
                 acked_features,
                 min_queues: num_queues,
-                paused: Arc::new(AtomicBool::new(paused)),
                 ..Default::default()
             },
             id,
@@ -195,6 +240,9 @@ impl Vdpa {
             enabled_queues: BTreeMap::new(),
             backend_features,
             migrating: false,
+            vrings: Vec::new(),
+            suspended: false,
+            vring_bases,
         })
     }
 
@@ -266,16 +314,22 @@ impl Vdpa {
                 .unwrap()
                 .set_vring_addr(*queue_index, &config_data)
                 .map_err(Error::SetVringAddr)?;
-            self.vhost
-                .as_ref()
-                .unwrap()
-                .set_vring_base(
-                    *queue_index,
+            // A vring restored from a snapshot resumes where the device
+            // stopped, which may be behind the available index if some
+            // descriptors were not processed yet.
+            let base = match self.vring_bases.iter().find(|(i, _)| i == queue_index) {
+                Some((_, base)) => *base,
+                None => {
                     queue
                         .avail_idx(mem, Ordering::Acquire)
                         .map_err(Error::GetAvailableIndex)?
-                        .0,
-                )
+                        .0
+                }
+            };
+            self.vhost
+                .as_ref()
+                .unwrap()
+                .set_vring_base(*queue_index, base)
                 .map_err(Error::SetVringBase)?;
 
             if let Some(eventfd) =
@@ -295,7 +349,9 @@ impl Vdpa {
                 .map_err(Error::SetVringKick)?;
 
             self.enabled_queues.insert(*queue_index, false);
+            self.vrings.push(*queue_index);
         }
+        self.vring_bases.clear();
 
         // Setup the config eventfd if there is one
         if let Some(eventfd) = virtio_interrupt.notifier(VirtioInterruptType::Config) {
@@ -319,6 +375,7 @@ impl Vdpa {
 
     fn reset_vdpa(&mut self) -> Result<()> {
         self.enable_vrings(false)?;
+        self.vrings.clear();
 
         assert!(self.vhost.is_some());
         self.vhost
@@ -362,6 +419,45 @@ impl Vdpa {
             .map_err(Error::DmaUnmap)
     }
 
+    fn suspend_vdpa(&mut self) -> Result<()> {
+        // A device which hasn't been started isn't processing anything.
+        if self.vrings.is_empty() {
+            return Ok(());
+        }
+
+        assert!(self.vhost.is_some());
+        let vhost = self.vhost.as_ref().unwrap();
+        vhost.suspend().map_err(Error::Suspend)?;
+
+        // Once suspended, the device doesn't process any descriptor anymore
+        // and the vring state can be retrieved.
+        let mut vring_bases = Vec::new();
+        for index in self.vrings.iter() {
+            let base = vhost.get_vring_base(*index).map_err(Error::GetVringBase)?;
+            vring_bases.push((*index, base as u16));
+        }
+        self.vring_bases = vring_bases;
+
+        Ok(())
+    }
+
+    fn resume_vdpa(&mut self) -> Result<()> {
+        if self.vrings.is_empty() {
+            return Ok(());
+        }
+
+        assert!(self.vhost.is_some());
+        // SAFETY: the file descriptor is a valid vhost-vdpa device and the
+        // ioctl doesn't take any argument.
+        let ret = unsafe { ioctl(self.vhost.as_ref().unwrap(), VHOST_VDPA_RESUME()) };
+        if ret < 0 {
+            return Err(Error::Resume(io::Error::last_os_error()));
+        }
+        self.vring_bases.clear();
+
+        Ok(())
+    }
+
     fn state(&self) -> Result<VdpaState> {
         assert!(self.vhost.is_some());
         let config_size = self
@@ -382,6 +478,7 @@ impl Vdpa {
             iova_range_last: self.iova_range.last,
             config,
             backend_features: self.backend_features,
+            vring_bases: self.vring_bases.clone(),
         })
     }
 }
@@ -452,27 +549,40 @@ impl VirtioDevice for Vdpa {
 
 impl Pausable for Vdpa {
     fn pause(&mut self) -> std::result::Result<(), MigratableError> {
-        if !self.migrating {
-            Err(MigratableError::Pause(anyhow!(
-                "Can't pause a vDPA device outside live migration"
-            )))
-        } else {
-            Ok(())
+        if self.suspended {
+            return Ok(());
+        }
+
+        if self.backend_features & (1 << VHOST_BACKEND_F_SUSPEND) == 0 {
+            return Err(MigratableError::Pause(anyhow!(
+                "vDPA device can't be suspended"
+            )));
         }
+
+        self.suspend_vdpa().map_err(|e| {
+            MigratableError::Pause(anyhow!("Error suspending vDPA device: {:?}", e))
+        })?;
+        self.suspended = true;
+
+        Ok(())
     }
 
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
-        if !self.common.paused.load(Ordering::SeqCst) {
+        if !self.suspended {
             return Ok(());
         }
 
-        if !self.migrating {
-            Err(MigratableError::Resume(anyhow!(
-                "Can't resume a vDPA device outside live migration"
-            )))
-        } else {
-            Ok(())
+        if self.backend_features & (1 << VHOST_BACKEND_F_RESUME) == 0 || self.vhost.is_none() {
+            return Err(MigratableError::Resume(anyhow!(
+                "vDPA device can't be resumed"
+            )));
         }
+
+        self.resume_vdpa()
+            .map_err(|e| MigratableError::Resume(anyhow!("Error resuming vDPA device: {:?}", e)))?;
+        self.suspended = false;
+
+        Ok(())
     }
 }
 
@@ -504,20 +614,26 @@ impl Snapshottable for Vdpa {
 impl Transportable for Vdpa {}
 
 impl Migratable for Vdpa {
+    // vhost-vdpa provides no way to log the guest memory written by the
+    // device, which must therefore be suspended as soon as the migration
+    // starts. It then doesn't write to guest memory anymore, and has no
+    // dirty pages to report.
     fn start_migration(&mut self) -> std::result::Result<(), MigratableError> {
-        self.migrating = true;
-        // Given there's no way to track dirty pages, we must suspend the
-        // device as soon as the migration process starts.
-        if self.backend_features & (1 << VHOST_BACKEND_F_SUSPEND) != 0 {
-            assert!(self.vhost.is_some());
-            self.vhost.as_ref().unwrap().suspend().map_err(|e| {
-                MigratableError::StartMigration(anyhow!("Error suspending vDPA device: {:?}", e))
-            })
-        } else {
-            Err(MigratableError::StartMigration(anyhow!(
+        if self.backend_features & (1 << VHOST_BACKEND_F_SUSPEND) == 0 {
+            return Err(MigratableError::StartMigration(anyhow!(
                 "vDPA device can't be suspended"
-            )))
+            )));
+        }
+
+        if !self.suspended {
+            self.suspend_vdpa().map_err(|e| {
+                MigratableError::StartMigration(anyhow!("Error suspending vDPA device: {:?}", e))
+            })?;
+            self.suspended = true;
         }
+        self.migrating = true;
+
+        Ok(())
     }
 
     fn complete_migration(&mut self) -> std::result::Result<(), MigratableError> {
@@ -5,7 +5,7 @@ use crate::{
     ActivateError, EpollHelper, EpollHelperError, EpollHelperHandler, GuestMemoryMmap,
     GuestRegionMmap, VirtioInterrupt, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IN_ORDER,
     VIRTIO_F_NOTIFICATION_DATA, VIRTIO_F_ORDER_PLATFORM, VIRTIO_F_RING_EVENT_IDX,
-    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1,
 };
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
@@ -131,6 +131,8 @@ pub enum Error {
     MissingIrqFd,
     #[error("Failed getting the available index: {0}")]
     GetAvailableIndex(QueueError),
+    #[error("Failed getting the base of the packed vring: {0:?}")]
+    GetPackedVringBase(vm_virtio::packed::Error),
     #[error("Migration is not supported by this vhost-user device")]
     MigrationNotSupported,
     #[error("Failed creating memfd: {0}")]
@@ -149,6 +151,7 @@ type Result<T> = std::result::Result<T, Error>;
 pub const DEFAULT_VIRTIO_FEATURES: u64 = 1 << VIRTIO_F_RING_INDIRECT_DESC
     | 1 << VIRTIO_F_RING_EVENT_IDX
     | 1 << VIRTIO_F_VERSION_1
+    | 1 << VIRTIO_F_RING_PACKED
     | 1 << VIRTIO_F_IN_ORDER
     | 1 << VIRTIO_F_ORDER_PLATFORM
     | 1 << VIRTIO_F_NOTIFICATION_DATA
@@ -156,6 +159,7 @@ pub const DEFAULT_VIRTIO_FEATURES: u64 = 1 << VIRTIO_F_RING_INDIRECT_DESC
 
 const HUP_CONNECTION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const BACKEND_REQ_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+const LISTENER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 
 #[derive(Default)]
 pub struct Inflight {
@@ -207,7 +211,16 @@ impl<S: VhostUserFrontendReqHandler> VhostUserEpollHandler<S> {
             epoll::Events::EPOLLHUP,
         )?;
 
-        let mut vhost_user = VhostUserHandle::connect_vhost_user(
+        // In server mode, the backend connecting again is waited for from
+        // the epoll loop rather than by blocking on accept(), so that the
+        // device can still be paused or stopped in the meantime.
+        let listener = self.vu.lock().unwrap().listener();
+        if let Some(listener) = listener {
+            info!("Waiting for vhost-user backend to reconnect...");
+            return helper.add_event(listener.as_raw_fd(), LISTENER_EVENT);
+        }
+
+        let vhost_user = VhostUserHandle::connect_vhost_user(
             self.server,
             &self.socket_path,
             self.queues.len() as u64,
@@ -220,7 +233,35 @@ impl<S: VhostUserFrontendReqHandler> VhostUserEpollHandler<S> {
             ))
         })?;
 
-        // Initialize the backend
+        self.reinitialize(helper, vhost_user)
+    }
+
+    fn accept(&mut self, helper: &mut EpollHelper) -> std::result::Result<(), EpollHelperError> {
+        let listener =
+            self.vu.lock().unwrap().listener().ok_or_else(|| {
+                EpollHelperError::HandleEvent(anyhow!("Missing vhost-user listener"))
+            })?;
+        helper.del_event_custom(listener.as_raw_fd(), LISTENER_EVENT, epoll::Events::EPOLLIN)?;
+
+        let vhost_user = VhostUserHandle::accept_vhost_user(listener, self.queues.len() as u64)
+            .map_err(|e| {
+                EpollHelperError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("failed accepting vhost-user backend {e:?}"),
+                ))
+            })?;
+
+        self.reinitialize(helper, vhost_user)
+    }
+
+    fn reinitialize(
+        &mut self,
+        helper: &mut EpollHelper,
+        mut vhost_user: VhostUserHandle,
+    ) -> std::result::Result<(), EpollHelperError> {
+        // Initialize the backend, handing over the inflight region so that
+        // the descriptors in flight when the previous backend went away are
+        // resubmitted.
         vhost_user
             .reinitialize_vhost_user(
                 self.mem.memory().deref(),
@@ -271,6 +312,14 @@ impl<S: VhostUserFrontendReqHandler> EpollHelperHandler for VhostUserEpollHandle
                     ))
                 })?;
             }
+            LISTENER_EVENT => {
+                self.accept(helper).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "failed to accept vhost-user backend: {:?}",
+                        e
+                    ))
+                })?;
+            }
             BACKEND_REQ_EVENT => {
                 if let Some(backend_req_handler) = self.backend_req_handler.as_mut() {
                     backend_req_handler.handle_request().map_err(|e| {
@@ -6,13 +6,15 @@ use crate::thread_helper::spawn_virtio_thread;
 use crate::vhost_user::vu_common_ctrl::{VhostUserConfig, VhostUserHandle};
 use crate::vhost_user::{Error, Result, VhostUserCommon};
 use crate::{
-    ActivateResult, NetCtrlEpollHandler, VirtioCommon, VirtioDevice, VirtioDeviceType,
-    VirtioInterrupt, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
+    ActivateError, ActivateResult, NetCtrlEpollHandler, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, VirtioInterrupt, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1,
 };
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 use net_util::{build_net_config_space, CtrlQueue, MacAddr, VirtioNetConfig};
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
+use std::ops::Deref;
 use std::result;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier, Mutex};
@@ -27,11 +29,12 @@ use virtio_bindings::virtio_net::{
 };
 use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use virtio_queue::{Queue, QueueT};
-use vm_memory::{ByteValued, GuestMemoryAtomic};
+use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic};
 use vm_migration::{
     protocol::MemoryRangeTable, Migratable, MigratableError, Pausable, Snapshot, Snapshottable,
     Transportable,
 };
+use vm_virtio::packed::PackedQueue;
 use vmm_sys_util::eventfd::EventFd;
 
 const DEFAULT_QUEUE_NUMBER: usize = 2;
@@ -123,6 +126,7 @@ impl Net {
                 | 1 << VIRTIO_NET_F_CTRL_VQ
                 | 1 << VIRTIO_F_RING_EVENT_IDX
                 | 1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_F_RING_PACKED
                 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
             if mtu.is_some() {
@@ -304,6 +308,16 @@ impl VirtioDevice for Net {
             let (_, mut ctrl_queue, ctrl_queue_evt) = queues.remove(ctrl_queue_index);
 
             ctrl_queue.set_event_idx(event_idx);
+            // The control queue is processed by the VMM, with the layout
+            // negotiated with the backend for the other queues.
+            let packed_queue = if self.common.feature_acked(VIRTIO_F_RING_PACKED.into()) {
+                Some(
+                    PackedQueue::new(mem.memory().deref(), &ctrl_queue)
+                        .map_err(ActivateError::CreatePackedQueue)?,
+                )
+            } else {
+                None
+            };
 
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
@@ -314,6 +328,7 @@ impl VirtioDevice for Net {
                 ctrl_q: CtrlQueue::new(Vec::new()),
                 queue: ctrl_queue,
                 queue_evt: ctrl_queue_evt,
+                packed_queue,
                 access_platform: None,
                 interrupt_cb: interrupt_cb.clone(),
                 queue_index: ctrl_queue_index as u16,
@@ -5,7 +5,7 @@ use super::{Error, Result};
 use crate::vhost_user::Inflight;
 use crate::{
     get_host_address_range, GuestMemoryMmap, GuestRegionMmap, MmapRegion, VirtioInterrupt,
-    VirtioInterruptType,
+    VirtioInterruptType, VIRTIO_F_RING_PACKED,
 };
 use std::ffi;
 use std::fs::File;
@@ -28,6 +28,7 @@ use vm_memory::{
     Address, Error as MmapError, FileOffset, GuestAddress, GuestMemory, GuestMemoryRegion,
 };
 use vm_migration::protocol::MemoryRangeTable;
+use vm_virtio::packed::packed_vring_base;
 use vmm_sys_util::eventfd::EventFd;
 
 // Size of a dirty page for vhost-user.
@@ -55,9 +56,24 @@ pub struct VhostUserHandle {
     acked_features: u64,
     vrings_info: Option<Vec<VringInfo>>,
     queue_indexes: Vec<usize>,
+    // Kept around in server mode so that the backend can reconnect.
+    listener: Option<Arc<UnixListener>>,
 }
 
 impl VhostUserHandle {
+    fn new(vu: Frontend, listener: Option<Arc<UnixListener>>) -> Self {
+        VhostUserHandle {
+            vu,
+            ready: false,
+            supports_migration: false,
+            shm_log: None,
+            acked_features: 0,
+            vrings_info: None,
+            queue_indexes: Vec::new(),
+            listener,
+        }
+    }
+
     pub fn update_mem_table(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
         let mut regions: Vec<VhostUserMemoryRegionInfo> = Vec::new();
         for region in mem.iter() {
@@ -199,9 +215,17 @@ impl VhostUserHandle {
                 .map_err(Error::VhostUserSetInflight)?;
         }
 
+        let packed = acked_features & (1 << VIRTIO_F_RING_PACKED) != 0;
         let mut vrings_info = Vec::new();
         for (queue_index, queue, queue_evt) in queues.iter() {
             let actual_size: usize = queue.size().into();
+            // The driver and device areas of a packed ring only hold their
+            // event suppression structure {off_wrap: u16; flags: u16}.
+            let (used_ring_size, avail_ring_size) = if packed {
+                (4, 4)
+            } else {
+                (4 + actual_size * 8, 4 + actual_size * 2)
+            };
 
             let config_data = VringConfigData {
                 queue_max_size: queue.max_size(),
@@ -218,7 +242,7 @@ impl VhostUserHandle {
                 used_ring_addr: get_host_address_range(
                     mem,
                     GuestAddress(queue.used_ring()),
-                    4 + actual_size * 8,
+                    used_ring_size,
                 )
                 .ok_or(Error::UsedAddress)? as u64,
                 // The used ring is {flags: u16; idx: u16; elem [u16; actual_size]},
@@ -226,7 +250,7 @@ impl VhostUserHandle {
                 avail_ring_addr: get_host_address_range(
                     mem,
                     GuestAddress(queue.avail_ring()),
-                    4 + actual_size * 2,
+                    avail_ring_size,
                 )
                 .ok_or(Error::AvailAddress)? as u64,
                 log_addr: None,
@@ -240,14 +264,19 @@ impl VhostUserHandle {
             self.vu
                 .set_vring_addr(*queue_index, &config_data)
                 .map_err(Error::VhostUserSetVringAddr)?;
+            // The base of a packed ring holds the wrap counter along with
+            // the index.
+            let vring_base = if packed {
+                packed_vring_base(mem, GuestAddress(queue.desc_table()), queue.size())
+                    .map_err(Error::GetPackedVringBase)?
+            } else {
+                queue
+                    .avail_idx(mem, Ordering::Acquire)
+                    .map_err(Error::GetAvailableIndex)?
+                    .0
+            };
             self.vu
-                .set_vring_base(
-                    *queue_index,
-                    queue
-                        .avail_idx(mem, Ordering::Acquire)
-                        .map_err(Error::GetAvailableIndex)?
-                        .0,
-                )
+                .set_vring_base(*queue_index, vring_base)
                 .map_err(Error::VhostUserSetVringBase)?;
 
             if let Some(eventfd) =
@@ -369,35 +398,14 @@ impl VhostUserHandle {
 
             info!("Binding vhost-user listener...");
             let listener = UnixListener::bind(socket_path).map_err(Error::BindSocket)?;
-            info!("Waiting for incoming vhost-user connection...");
-            let (stream, _) = listener.accept().map_err(Error::AcceptConnection)?;
-
-            Ok(VhostUserHandle {
-                vu: Frontend::from_stream(stream, num_queues),
-                ready: false,
-                supports_migration: false,
-                shm_log: None,
-                acked_features: 0,
-                vrings_info: None,
-                queue_indexes: Vec::new(),
-            })
+            Self::accept_vhost_user(Arc::new(listener), num_queues)
         } else {
             let now = Instant::now();
 
             // Retry connecting for a full minute
             let err = loop {
                 let err = match Frontend::connect(socket_path, num_queues) {
-                    Ok(m) => {
-                        return Ok(VhostUserHandle {
-                            vu: m,
-                            ready: false,
-                            supports_migration: false,
-                            shm_log: None,
-                            acked_features: 0,
-                            vrings_info: None,
-                            queue_indexes: Vec::new(),
-                        })
-                    }
+                    Ok(m) => return Ok(Self::new(m, None)),
                     Err(e) => e,
                 };
                 sleep(Duration::from_millis(100));
@@ -415,6 +423,20 @@ impl VhostUserHandle {
         }
     }
 
+    pub fn accept_vhost_user(listener: Arc<UnixListener>, num_queues: u64) -> Result<Self> {
+        info!("Waiting for incoming vhost-user connection...");
+        let (stream, _) = listener.accept().map_err(Error::AcceptConnection)?;
+
+        Ok(Self::new(
+            Frontend::from_stream(stream, num_queues),
+            Some(listener),
+        ))
+    }
+
+    pub fn listener(&self) -> Option<Arc<UnixListener>> {
+        self.listener.clone()
+    }
+
     pub fn socket_handle(&mut self) -> &mut Frontend {
         &mut self.vu
     }
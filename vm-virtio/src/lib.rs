@@ -15,6 +15,7 @@ use std::sync::Arc;
 use virtio_queue::{Queue, QueueT};
 use vm_memory::GuestAddress;
 
+pub mod packed;
 pub mod queue;
 pub use queue::*;
 
@@ -39,6 +40,7 @@ pub enum VirtioDeviceType {
     Mem = 24,
     Fs = 26,
     Pmem = 27,
+    I2c = 34,
     Watchdog = 35, // Temporary until official number allocated
     Unknown = 0xFF,
 }
@@ -59,6 +61,7 @@ impl From<u32> for VirtioDeviceType {
             24 => VirtioDeviceType::Mem,
             26 => VirtioDeviceType::Fs,
             27 => VirtioDeviceType::Pmem,
+            34 => VirtioDeviceType::I2c,
             35 => VirtioDeviceType::Watchdog,
             _ => VirtioDeviceType::Unknown,
         }
@@ -84,6 +87,7 @@ impl fmt::Display for VirtioDeviceType {
             VirtioDeviceType::Mem => "mem",
             VirtioDeviceType::Fs => "fs",
             VirtioDeviceType::Pmem => "pmem",
+            VirtioDeviceType::I2c => "i2c",
             VirtioDeviceType::Watchdog => "watchdog",
             VirtioDeviceType::Unknown => "UNKNOWN",
         };
@@ -0,0 +1,684 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Packed virtqueues, negotiated through VIRTIO_F_RING_PACKED.
+//!
+//! The driver makes descriptors available and the device writes them back as
+//! used in a single ring. Whether a slot is available or used is told by two
+//! flags, compared against the wrap counter of the side reading it, which
+//! flips each time the ring wraps around. The driver and device areas of the
+//! queue hold the event suppression structures of each side.
+
+use std::sync::atomic::{fence, Ordering};
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError};
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+const RING_EVENT_FLAGS_MASK: u16 = 0x3;
+
+/// Bit of a vring base or of an event offset holding the wrap counter.
+pub const PACKED_RING_WRAP_COUNTER_BIT: u16 = 1 << 15;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+// Offsets of the fields of a descriptor, and of the flags of an event
+// suppression structure.
+const DESCRIPTOR_LEN_OFFSET: u64 = 8;
+const DESCRIPTOR_ID_OFFSET: u64 = 12;
+const DESCRIPTOR_FLAGS_OFFSET: u64 = 14;
+const EVENT_FLAGS_OFFSET: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Accessing the ring or an indirect table failed.
+    GuestMemory(GuestMemoryError),
+    /// The chain doesn't fit in the ring, or its indirect table is invalid.
+    InvalidChain,
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct PackedDesc {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+// SAFETY: PackedDesc only contains a series of integers
+unsafe impl ByteValued for PackedDesc {}
+
+/// Buffer of a descriptor chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PackedDescriptor {
+    pub addr: GuestAddress,
+    pub len: u32,
+    pub flags: u16,
+}
+
+impl PackedDescriptor {
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+}
+
+/// Descriptor chain made available by the driver.
+#[derive(Debug)]
+pub struct PackedDescriptorChain {
+    /// Buffer ID, given back to the driver once the chain is used.
+    pub id: u16,
+    /// Buffers of the chain, those of an indirect table included.
+    pub descriptors: Vec<PackedDescriptor>,
+    // Number of ring slots taken by the chain.
+    slots: u16,
+}
+
+/// Packed virtqueue processed by the VMM.
+pub struct PackedQueue {
+    size: u16,
+    desc_table: GuestAddress,
+    driver_event: GuestAddress,
+    device_event: GuestAddress,
+    event_idx: bool,
+    next_avail: u16,
+    avail_wrap_counter: bool,
+    next_used: u16,
+    used_wrap_counter: bool,
+    // Number of slots used since the driver was last asked whether it must
+    // be notified, unless it never was.
+    used_since_notification: Option<u32>,
+}
+
+impl PackedQueue {
+    /// Create a packed virtqueue from the queue set up by the driver, whose
+    /// available and used rings addresses are the ones of the driver and
+    /// device areas. Processing starts at the vring base of the ring, so
+    /// that a restored queue resumes where it stopped.
+    pub fn new<M: GuestMemory>(mem: &M, queue: &Queue) -> Result<Self> {
+        let desc_table = GuestAddress(queue.desc_table());
+        let base = packed_vring_base(mem, desc_table, queue.size())?;
+        let index = base & !PACKED_RING_WRAP_COUNTER_BIT;
+        let wrap_counter = base & PACKED_RING_WRAP_COUNTER_BIT != 0;
+
+        Ok(PackedQueue {
+            size: queue.size(),
+            desc_table,
+            driver_event: GuestAddress(queue.avail_ring()),
+            device_event: GuestAddress(queue.used_ring()),
+            event_idx: queue.event_idx_enabled(),
+            next_avail: index,
+            avail_wrap_counter: wrap_counter,
+            next_used: index,
+            used_wrap_counter: wrap_counter,
+            used_since_notification: None,
+        })
+    }
+
+    fn desc_addr(&self, index: u16) -> GuestAddress {
+        self.desc_table
+            .unchecked_add(u64::from(index) * DESCRIPTOR_SIZE)
+    }
+
+    fn is_available<M: GuestMemory>(&self, mem: &M) -> Result<bool> {
+        let flags: u16 = mem
+            .load(
+                self.desc_addr(self.next_avail)
+                    .unchecked_add(DESCRIPTOR_FLAGS_OFFSET),
+                Ordering::Acquire,
+            )
+            .map_err(Error::GuestMemory)?;
+
+        Ok((flags & VIRTQ_DESC_F_AVAIL != 0) == self.avail_wrap_counter
+            && (flags & VIRTQ_DESC_F_USED != 0) != self.avail_wrap_counter)
+    }
+
+    fn read_indirect<M: GuestMemory>(
+        mem: &M,
+        desc: &PackedDesc,
+        descriptors: &mut Vec<PackedDescriptor>,
+    ) -> Result<()> {
+        let len = u64::from(desc.len);
+        if desc.flags & VIRTQ_DESC_F_NEXT != 0
+            || len == 0
+            || len % DESCRIPTOR_SIZE != 0
+            || len / DESCRIPTOR_SIZE > u64::from(u16::MAX)
+        {
+            return Err(Error::InvalidChain);
+        }
+
+        for offset in (0..len).step_by(DESCRIPTOR_SIZE as usize) {
+            let addr = GuestAddress(desc.addr)
+                .checked_add(offset)
+                .ok_or(Error::InvalidChain)?;
+            let entry: PackedDesc = mem.read_obj(addr).map_err(Error::GuestMemory)?;
+            if entry.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err(Error::InvalidChain);
+            }
+            descriptors.push(PackedDescriptor {
+                addr: GuestAddress(entry.addr),
+                len: entry.len,
+                flags: entry.flags,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Pop the next descriptor chain made available by the driver, if any.
+    pub fn pop_descriptor_chain<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+    ) -> Result<Option<PackedDescriptorChain>> {
+        if !self.is_available(mem)? {
+            return Ok(None);
+        }
+
+        let (mut index, mut wrap_counter) = (self.next_avail, self.avail_wrap_counter);
+        let mut descriptors = Vec::new();
+        let mut slots = 0;
+        // The driver marks the head of the chain available last, the other
+        // descriptors are only read once it is.
+        let id = loop {
+            if slots == self.size {
+                return Err(Error::InvalidChain);
+            }
+            let desc: PackedDesc = mem
+                .read_obj(self.desc_addr(index))
+                .map_err(Error::GuestMemory)?;
+            slots += 1;
+            index += 1;
+            if index == self.size {
+                index = 0;
+                wrap_counter = !wrap_counter;
+            }
+
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                Self::read_indirect(mem, &desc, &mut descriptors)?;
+            } else {
+                descriptors.push(PackedDescriptor {
+                    addr: GuestAddress(desc.addr),
+                    len: desc.len,
+                    flags: desc.flags,
+                });
+            }
+
+            // The buffer ID is the one of the last descriptor.
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break desc.id;
+            }
+        };
+
+        self.next_avail = index;
+        self.avail_wrap_counter = wrap_counter;
+
+        Ok(Some(PackedDescriptorChain {
+            id,
+            descriptors,
+            slots,
+        }))
+    }
+
+    /// Give a chain back to the driver, `len` bytes having been written to
+    /// its device writable buffers.
+    pub fn add_used<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        chain: &PackedDescriptorChain,
+        len: u32,
+    ) -> Result<()> {
+        let addr = self.desc_addr(self.next_used);
+        mem.write_obj(len, addr.unchecked_add(DESCRIPTOR_LEN_OFFSET))
+            .map_err(Error::GuestMemory)?;
+        mem.write_obj(chain.id, addr.unchecked_add(DESCRIPTOR_ID_OFFSET))
+            .map_err(Error::GuestMemory)?;
+
+        let mut flags = if self.used_wrap_counter {
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+        } else {
+            0
+        };
+        if len > 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        // The driver must see the ID and length before the descriptor is
+        // marked used.
+        mem.store(
+            flags,
+            addr.unchecked_add(DESCRIPTOR_FLAGS_OFFSET),
+            Ordering::Release,
+        )
+        .map_err(Error::GuestMemory)?;
+
+        // The used descriptor takes the place of the whole chain.
+        self.next_used += chain.slots;
+        if self.next_used >= self.size {
+            self.next_used -= self.size;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+        if let Some(used) = self.used_since_notification.as_mut() {
+            *used += u32::from(chain.slots);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the driver must be notified of the chains used since it was
+    /// last asked.
+    pub fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool> {
+        // The used descriptors must be visible before reading whether the
+        // driver wants to be notified.
+        fence(Ordering::SeqCst);
+        let off_wrap: u16 = mem
+            .load(self.driver_event, Ordering::Acquire)
+            .map_err(Error::GuestMemory)?;
+        let flags: u16 = mem
+            .load(
+                self.driver_event.unchecked_add(EVENT_FLAGS_OFFSET),
+                Ordering::Acquire,
+            )
+            .map_err(Error::GuestMemory)?;
+        let used_since_notification = self.used_since_notification.replace(0);
+
+        match flags & RING_EVENT_FLAGS_MASK {
+            RING_EVENT_FLAGS_ENABLE => Ok(true),
+            RING_EVENT_FLAGS_DESC if self.event_idx => {
+                let Some(used) = used_since_notification else {
+                    return Ok(true);
+                };
+                // Notify if the descriptor the driver waits for is among the
+                // ones used since, counting back from the next used one.
+                let off = off_wrap & !PACKED_RING_WRAP_COUNTER_BIT;
+                let wrap_counter = off_wrap & PACKED_RING_WRAP_COUNTER_BIT != 0;
+                let distance = if wrap_counter == self.used_wrap_counter {
+                    if off >= self.next_used {
+                        return Ok(false);
+                    }
+                    u32::from(self.next_used - off)
+                } else {
+                    u32::from(self.next_used) + u32::from(self.size) - u32::from(off)
+                };
+                Ok(distance <= used)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Let the driver notify the device of the chains made available, and
+    /// tell whether some already are.
+    pub fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool> {
+        let flags_addr = self.device_event.unchecked_add(EVENT_FLAGS_OFFSET);
+        if self.event_idx {
+            let mut off_wrap = self.next_avail;
+            if self.avail_wrap_counter {
+                off_wrap |= PACKED_RING_WRAP_COUNTER_BIT;
+            }
+            mem.store(off_wrap, self.device_event, Ordering::Relaxed)
+                .map_err(Error::GuestMemory)?;
+            mem.store(RING_EVENT_FLAGS_DESC, flags_addr, Ordering::Release)
+                .map_err(Error::GuestMemory)?;
+        } else {
+            mem.store(RING_EVENT_FLAGS_ENABLE, flags_addr, Ordering::Release)
+                .map_err(Error::GuestMemory)?;
+        }
+
+        // The driver must see the notifications enabled before checking for
+        // chains made available in the meantime.
+        fence(Ordering::SeqCst);
+        self.is_available(mem)
+    }
+
+    /// Stop the driver from notifying the device.
+    pub fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<()> {
+        mem.store(
+            RING_EVENT_FLAGS_DISABLE,
+            self.device_event.unchecked_add(EVENT_FLAGS_OFFSET),
+            Ordering::Release,
+        )
+        .map_err(Error::GuestMemory)
+    }
+}
+
+/// Vring base of a packed ring set up by the driver, with the index of the
+/// next slot the driver writes and its wrap counter in the top bit. As with
+/// the available index of a split ring, every descriptor made available is
+/// considered processed.
+pub fn packed_vring_base<M: GuestMemory>(
+    mem: &M,
+    desc_table: GuestAddress,
+    size: u16,
+) -> Result<u16> {
+    // The driver flags the descriptors it writes as available with its wrap
+    // counter, which the device keeps when marking them used.
+    let avail = |index: u16| -> Result<bool> {
+        let flags: u16 = mem
+            .load(
+                desc_table
+                    .unchecked_add(u64::from(index) * DESCRIPTOR_SIZE + DESCRIPTOR_FLAGS_OFFSET),
+                Ordering::Acquire,
+            )
+            .map_err(Error::GuestMemory)?;
+        Ok(flags & VIRTQ_DESC_F_AVAIL != 0)
+    };
+
+    let wrap_counter = avail(0)?;
+    for index in 1..size {
+        if avail(index)? != wrap_counter {
+            return Ok(if wrap_counter {
+                index | PACKED_RING_WRAP_COUNTER_BIT
+            } else {
+                index
+            });
+        }
+    }
+
+    // The whole ring was written in the last lap, the next one starts.
+    Ok(if wrap_counter {
+        0
+    } else {
+        PACKED_RING_WRAP_COUNTER_BIT
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::bitmap::AtomicBitmap;
+
+    type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+    const QUEUE_SIZE: u16 = 4;
+    const DESC_TABLE: u64 = 0x1000;
+    const DRIVER_EVENT: u64 = 0x2000;
+    const DEVICE_EVENT: u64 = 0x3000;
+
+    fn setup(event_idx: bool) -> (GuestMemoryMmap, PackedQueue) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE).unwrap();
+        queue.set_size(QUEUE_SIZE);
+        queue.set_event_idx(event_idx);
+        queue
+            .try_set_desc_table_address(GuestAddress(DESC_TABLE))
+            .unwrap();
+        queue
+            .try_set_avail_ring_address(GuestAddress(DRIVER_EVENT))
+            .unwrap();
+        queue
+            .try_set_used_ring_address(GuestAddress(DEVICE_EVENT))
+            .unwrap();
+
+        let packed_queue = PackedQueue::new(&mem, &queue).unwrap();
+        (mem, packed_queue)
+    }
+
+    // Make a descriptor available as the driver does in the lap of the given
+    // wrap counter.
+    fn make_avail(
+        mem: &GuestMemoryMmap,
+        index: u16,
+        addr: u64,
+        id: u16,
+        flags: u16,
+        wrap_counter: bool,
+    ) {
+        let flags = flags
+            | if wrap_counter {
+                VIRTQ_DESC_F_AVAIL
+            } else {
+                VIRTQ_DESC_F_USED
+            };
+        let desc = PackedDesc {
+            addr,
+            len: 0x100,
+            id,
+            flags,
+        };
+        mem.write_obj(desc, GuestAddress(DESC_TABLE + u64::from(index) * 16))
+            .unwrap();
+    }
+
+    fn read_desc(mem: &GuestMemoryMmap, index: u16) -> PackedDesc {
+        mem.read_obj(GuestAddress(DESC_TABLE + u64::from(index) * 16))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pop_and_add_used() {
+        let (mem, mut queue) = setup(false);
+        assert!(queue.pop_descriptor_chain(&mem).unwrap().is_none());
+
+        // A chain of two descriptors, whose last one holds the buffer ID.
+        make_avail(&mem, 1, 0x5000, 0, VIRTQ_DESC_F_WRITE, true);
+        make_avail(&mem, 0, 0x4000, 0, VIRTQ_DESC_F_NEXT, true);
+        mem.write_obj(7u16, GuestAddress(DESC_TABLE + 16 + 12))
+            .unwrap();
+
+        let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+        assert_eq!(chain.id, 7);
+        assert_eq!(chain.descriptors.len(), 2);
+        assert_eq!(chain.descriptors[0].addr, GuestAddress(0x4000));
+        assert!(!chain.descriptors[0].is_write_only());
+        assert_eq!(chain.descriptors[1].addr, GuestAddress(0x5000));
+        assert!(chain.descriptors[1].is_write_only());
+        assert!(queue.pop_descriptor_chain(&mem).unwrap().is_none());
+
+        // The used descriptor is written in the slot of the head, and the
+        // next one goes after the chain.
+        queue.add_used(&mem, &chain, 3).unwrap();
+        let used = read_desc(&mem, 0);
+        assert_eq!((used.id, used.len), (7, 3));
+        assert_eq!(
+            used.flags,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE
+        );
+        assert_eq!(queue.next_used, 2);
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let (mem, mut queue) = setup(false);
+
+        for index in 0..QUEUE_SIZE {
+            make_avail(&mem, index, 0x4000, index, 0, true);
+        }
+        for index in 0..QUEUE_SIZE {
+            let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+            assert_eq!(chain.id, index);
+            queue.add_used(&mem, &chain, 0).unwrap();
+        }
+        assert!(queue.pop_descriptor_chain(&mem).unwrap().is_none());
+        assert_eq!((queue.next_avail, queue.avail_wrap_counter), (0, false));
+        assert_eq!((queue.next_used, queue.used_wrap_counter), (0, false));
+
+        // The descriptors of the second lap are flagged the other way.
+        make_avail(&mem, 0, 0x4000, 9, 0, false);
+        let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+        assert_eq!(chain.id, 9);
+        queue.add_used(&mem, &chain, 0).unwrap();
+        assert_eq!(read_desc(&mem, 0).flags, 0);
+
+        // A chain wrapping around the ring, its descriptors being flagged
+        // with the wrap counter of their own lap.
+        make_avail(&mem, 1, 0x4000, 1, 0, false);
+        make_avail(&mem, 2, 0x4000, 2, 0, false);
+        make_avail(&mem, 3, 0x4000, 0, VIRTQ_DESC_F_NEXT, false);
+        make_avail(&mem, 0, 0x5000, 5, 0, true);
+        for id in 1..3 {
+            let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+            assert_eq!(chain.id, id);
+            queue.add_used(&mem, &chain, 0).unwrap();
+        }
+        let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+        assert_eq!((chain.id, chain.slots), (5, 2));
+        assert_eq!((queue.next_avail, queue.avail_wrap_counter), (1, true));
+        queue.add_used(&mem, &chain, 0).unwrap();
+        assert_eq!((queue.next_used, queue.used_wrap_counter), (1, true));
+    }
+
+    #[test]
+    fn test_indirect() {
+        let (mem, mut queue) = setup(false);
+
+        let table = [
+            PackedDesc {
+                addr: 0x6000,
+                len: 0x10,
+                ..Default::default()
+            },
+            PackedDesc {
+                addr: 0x7000,
+                len: 0x20,
+                flags: VIRTQ_DESC_F_WRITE,
+                ..Default::default()
+            },
+        ];
+        mem.write_obj(table, GuestAddress(0x8000)).unwrap();
+        make_avail(&mem, 0, 0x8000, 3, VIRTQ_DESC_F_INDIRECT, true);
+        mem.write_obj(32u32, GuestAddress(DESC_TABLE + 8)).unwrap();
+
+        let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+        assert_eq!((chain.id, chain.slots), (3, 1));
+        assert_eq!(
+            chain.descriptors,
+            vec![
+                PackedDescriptor {
+                    addr: GuestAddress(0x6000),
+                    len: 0x10,
+                    flags: 0,
+                },
+                PackedDescriptor {
+                    addr: GuestAddress(0x7000),
+                    len: 0x20,
+                    flags: VIRTQ_DESC_F_WRITE,
+                },
+            ]
+        );
+
+        // The table size must be a multiple of the descriptor size.
+        make_avail(&mem, 1, 0x8000, 4, VIRTQ_DESC_F_INDIRECT, true);
+        mem.write_obj(24u32, GuestAddress(DESC_TABLE + 16 + 8))
+            .unwrap();
+        assert!(matches!(
+            queue.pop_descriptor_chain(&mem),
+            Err(Error::InvalidChain)
+        ));
+    }
+
+    #[test]
+    fn test_notifications() {
+        let (mem, mut queue) = setup(true);
+        let driver_flags = GuestAddress(DRIVER_EVENT + 2);
+
+        for index in 0..QUEUE_SIZE {
+            make_avail(&mem, index, 0x4000, index, 0, true);
+        }
+
+        // Notifications enabled or disabled by the driver.
+        mem.write_obj(RING_EVENT_FLAGS_ENABLE, driver_flags)
+            .unwrap();
+        assert!(queue.needs_notification(&mem).unwrap());
+        mem.write_obj(RING_EVENT_FLAGS_DISABLE, driver_flags)
+            .unwrap();
+        assert!(!queue.needs_notification(&mem).unwrap());
+
+        // The driver waits for the descriptor in slot 1 to be used.
+        mem.write_obj(RING_EVENT_FLAGS_DESC, driver_flags).unwrap();
+        mem.write_obj(1 | PACKED_RING_WRAP_COUNTER_BIT, GuestAddress(DRIVER_EVENT))
+            .unwrap();
+        let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+        queue.add_used(&mem, &chain, 0).unwrap();
+        assert!(!queue.needs_notification(&mem).unwrap());
+        let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+        queue.add_used(&mem, &chain, 0).unwrap();
+        assert!(queue.needs_notification(&mem).unwrap());
+        // Already notified.
+        assert!(!queue.needs_notification(&mem).unwrap());
+
+        // The event is in the lap before the next used descriptor.
+        mem.write_obj(3 | PACKED_RING_WRAP_COUNTER_BIT, GuestAddress(DRIVER_EVENT))
+            .unwrap();
+        for _ in 2..QUEUE_SIZE {
+            let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+            queue.add_used(&mem, &chain, 0).unwrap();
+        }
+        assert_eq!((queue.next_used, queue.used_wrap_counter), (0, false));
+        assert!(queue.needs_notification(&mem).unwrap());
+
+        // The device asks to be notified once the next slot is available.
+        assert!(!queue.enable_notification(&mem).unwrap());
+        let device_event: u16 = mem.read_obj(GuestAddress(DEVICE_EVENT)).unwrap();
+        let device_flags: u16 = mem.read_obj(GuestAddress(DEVICE_EVENT + 2)).unwrap();
+        assert_eq!((device_event, device_flags), (0, RING_EVENT_FLAGS_DESC));
+        make_avail(&mem, 0, 0x4000, 0, 0, false);
+        assert!(queue.enable_notification(&mem).unwrap());
+
+        queue.disable_notification(&mem).unwrap();
+        let device_flags: u16 = mem.read_obj(GuestAddress(DEVICE_EVENT + 2)).unwrap();
+        assert_eq!(device_flags, RING_EVENT_FLAGS_DISABLE);
+    }
+
+    #[test]
+    fn test_restore() {
+        let (mem, mut queue) = setup(false);
+        for index in 0..3 {
+            make_avail(&mem, index, 0x4000, index, 0, true);
+            let chain = queue.pop_descriptor_chain(&mem).unwrap().unwrap();
+            queue.add_used(&mem, &chain, 0).unwrap();
+        }
+
+        // A queue created from the same ring starts after the descriptors
+        // processed.
+        let mut virtio_queue = Queue::new(QUEUE_SIZE).unwrap();
+        virtio_queue.set_size(QUEUE_SIZE);
+        virtio_queue
+            .try_set_desc_table_address(GuestAddress(DESC_TABLE))
+            .unwrap();
+        let mut restored = PackedQueue::new(&mem, &virtio_queue).unwrap();
+        assert_eq!(
+            (restored.next_avail, restored.avail_wrap_counter),
+            (3, true)
+        );
+        assert_eq!((restored.next_used, restored.used_wrap_counter), (3, true));
+        assert!(restored.pop_descriptor_chain(&mem).unwrap().is_none());
+        make_avail(&mem, 3, 0x4000, 3, 0, true);
+        assert_eq!(restored.pop_descriptor_chain(&mem).unwrap().unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_packed_vring_base() {
+        let (mem, _) = setup(false);
+        let base = |mem: &GuestMemoryMmap| {
+            packed_vring_base(mem, GuestAddress(DESC_TABLE), QUEUE_SIZE).unwrap()
+        };
+
+        // Nothing written yet, the first lap starts.
+        assert_eq!(base(&mem), PACKED_RING_WRAP_COUNTER_BIT);
+
+        make_avail(&mem, 0, 0x4000, 0, 0, true);
+        make_avail(&mem, 1, 0x4000, 1, 0, true);
+        assert_eq!(base(&mem), 2 | PACKED_RING_WRAP_COUNTER_BIT);
+
+        // The used descriptors keep the wrap counter of their lap.
+        make_avail(&mem, 2, 0x4000, 2, 0, true);
+        mem.write_obj(
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED,
+            GuestAddress(DESC_TABLE + 14),
+        )
+        .unwrap();
+        make_avail(&mem, 3, 0x4000, 3, 0, true);
+        assert_eq!(base(&mem), 0);
+
+        make_avail(&mem, 0, 0x4000, 0, 0, false);
+        assert_eq!(base(&mem), 1);
+    }
+}
@@ -93,6 +93,71 @@ struct ProcessorGiccAffinity {
     pub clock_domain: u32,
 }
 
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct NfitSpaRange {
+    pub type_: u16,
+    pub length: u16,
+    pub range_index: u16,
+    pub flags: u16,
+    _reserved: u32,
+    pub proximity_domain: u32,
+    pub range_type_guid: [u8; 16],
+    pub base: u64,
+    pub size: u64,
+    pub memory_mapping_attributes: u64,
+}
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct NfitMemoryDeviceMap {
+    pub type_: u16,
+    pub length: u16,
+    pub device_handle: u32,
+    pub physical_id: u16,
+    pub region_id: u16,
+    pub range_index: u16,
+    pub control_region_index: u16,
+    pub region_size: u64,
+    pub region_offset: u64,
+    pub region_physical_address: u64,
+    pub interleave_index: u16,
+    pub interleave_ways: u16,
+    pub state_flags: u16,
+    _reserved: u16,
+}
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct NfitControlRegion {
+    pub type_: u16,
+    pub length: u16,
+    pub control_region_index: u16,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_device_id: u16,
+    pub subsystem_revision_id: u16,
+    pub valid_fields: u8,
+    pub manufacturing_location: u8,
+    pub manufacturing_date: u16,
+    _reserved1: u16,
+    pub serial_number: u32,
+    pub format_interface_code: u16,
+    pub block_control_windows: u16,
+    _block_control_window_size: u64,
+    _command_register_offset: u64,
+    _command_register_size: u64,
+    _status_register_offset: u64,
+    _status_register_size: u64,
+    pub flags: u16,
+    _reserved2: [u8; 6],
+}
+
 bitflags! {
     pub struct MemAffinityFlags: u32 {
         const NOFLAGS = 0;
@@ -141,6 +206,51 @@ impl MemoryAffinity {
     }
 }
 
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct HmatMemoryProximityDomain {
+    pub type_: u16,
+    _reserved: u16,
+    pub length: u32,
+    pub flags: u16,
+    _reserved2: u16,
+    pub initiator_proximity_domain: u32,
+    pub memory_proximity_domain: u32,
+    _reserved3: [u8; 20],
+}
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct HmatLocality {
+    pub type_: u16,
+    _reserved: u16,
+    pub length: u32,
+    pub flags: u8,
+    pub data_type: u8,
+    pub min_transfer_size: u8,
+    _reserved2: u8,
+    pub num_initiators: u32,
+    pub num_targets: u32,
+    _reserved3: u32,
+    pub entry_base_unit: u64,
+}
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct CedtHostBridge {
+    pub type_: u8,
+    _reserved: u8,
+    pub length: u16,
+    pub uid: u32,
+    pub cxl_version: u32,
+    _reserved2: u32,
+    pub base: u64,
+    pub base_length: u64,
+}
+
 #[allow(dead_code)]
 #[repr(packed)]
 #[derive(Default, AsBytes)]
@@ -188,7 +298,64 @@ pub fn create_dsdt_table(
     dsdt
 }
 
-fn create_facp_table(dsdt_offset: GuestAddress, device_manager: &Arc<Mutex<DeviceManager>>) -> Sdt {
+// The FACS isn't a regular SDT, it has neither checksum nor OEM fields.
+#[cfg(target_arch = "x86_64")]
+fn create_facs_table(hardware_signature: u32) -> Vec<u8> {
+    const FACS_LEN: usize = 64;
+
+    let mut facs = vec![0u8; FACS_LEN];
+    facs[0..4].copy_from_slice(b"FACS");
+    facs[4..8].copy_from_slice(&(FACS_LEN as u32).to_le_bytes());
+    // Hardware Signature
+    facs[8..12].copy_from_slice(&hardware_signature.to_le_bytes());
+    // Version
+    facs[32] = 2;
+    facs
+}
+
+// The guest compares the hardware signature from before hibernating with the
+// one found when resuming, in order to detect configuration changes. It is
+// derived from the DSDT, describing the devices, vCPUs and memory, using
+// FNV-1a which remains stable across builds.
+#[cfg(target_arch = "x86_64")]
+fn hardware_signature(dsdt: &Sdt) -> u32 {
+    dsdt.as_slice().iter().fold(0x811c_9dc5, |hash: u32, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Returns the waking vector the guest stored in the FACS before entering the
+/// S3 sleep state, if any. The FACS is looked up from the RSDP through the
+/// XSDT and the FADT, so that this also works for a restored VM.
+#[cfg(target_arch = "x86_64")]
+pub fn waking_vector(guest_mem: &GuestMemoryMmap) -> Option<u32> {
+    let xsdt: u64 = guest_mem
+        .read_obj(arch::layout::RSDP_POINTER.unchecked_add(24))
+        .ok()?;
+    let xsdt_len: u32 = guest_mem.read_obj(GuestAddress(xsdt + 4)).ok()?;
+    let facp = (36..u64::from(xsdt_len))
+        .step_by(8)
+        .filter_map(|entry| guest_mem.read_obj::<u64>(GuestAddress(xsdt + entry)).ok())
+        .find(|table| {
+            guest_mem
+                .read_obj::<[u8; 4]>(GuestAddress(*table))
+                .is_ok_and(|signature| &signature == b"FACP")
+        })?;
+    // X_FIRMWARE_CTRL
+    let facs: u64 = guest_mem.read_obj(GuestAddress(facp + 132)).ok()?;
+    if facs == 0 {
+        return None;
+    }
+    // Firmware Waking Vector
+    let waking_vector: u32 = guest_mem.read_obj(GuestAddress(facs + 12)).ok()?;
+    (waking_vector != 0).then_some(waking_vector)
+}
+
+fn create_facp_table(
+    dsdt_offset: GuestAddress,
+    facs_offset: Option<GuestAddress>,
+    device_manager: &Arc<Mutex<DeviceManager>>,
+) -> Sdt {
     trace_scoped!("create_facp_table");
 
     // Revision 6 of the ACPI FADT table is 276 bytes long
@@ -236,6 +403,10 @@ fn create_facp_table(dsdt_offset: GuestAddress, device_manager: &Arc<Mutex<Devic
     facp.write(112, fadt_flags);
     // FADT minor version
     facp.write(131, 3u8);
+    // X_FIRMWARE_CTRL
+    if let Some(facs_offset) = facs_offset {
+        facp.write(132, facs_offset.0);
+    }
     // X_DSDT
     facp.write(140, dsdt_offset.0);
     // Hypervisor Vendor Identity
@@ -353,6 +524,192 @@ fn create_srat_table(
     srat
 }
 
+fn create_nfit_table(nvdimms: &[devices::Nvdimm]) -> Sdt {
+    // Persistent memory region GUID 66F0D379-B4F3-4074-AC43-0D3318B78CDB,
+    // in its mixed endian encoding.
+    const PERSISTENT_MEMORY_GUID: [u8; 16] = [
+        0x79, 0xd3, 0xf0, 0x66, 0xf3, 0xb4, 0x74, 0x40, 0xac, 0x43, 0x0d, 0x33, 0x18, 0xb7, 0x8c,
+        0xdb,
+    ];
+    // EFI_MEMORY_WB | EFI_MEMORY_NV
+    const MEMORY_MAPPING_ATTRIBUTES: u64 = 0x8 | 0x8000;
+    // Byte addressable, energy backed
+    const FORMAT_INTERFACE_CODE: u16 = 0x301;
+
+    let mut nfit = Sdt::new(*b"NFIT", 36, 1, *b"CLOUDH", *b"CHNFIT  ", 1);
+    // NFIT reserved 4 bytes
+    nfit.append_slice(&[0u8; 4]);
+
+    assert_eq!(std::mem::size_of::<NfitSpaRange>(), 56);
+    assert_eq!(std::mem::size_of::<NfitMemoryDeviceMap>(), 48);
+    assert_eq!(std::mem::size_of::<NfitControlRegion>(), 80);
+
+    for (i, nvdimm) in nvdimms.iter().enumerate() {
+        // Structures are referenced through 1-based indexes
+        let index = i as u16 + 1;
+        let handle = devices::Nvdimm::handle(i);
+
+        nfit.append(NfitSpaRange {
+            type_: 0,
+            length: 56,
+            range_index: index,
+            range_type_guid: PERSISTENT_MEMORY_GUID,
+            base: nvdimm.base.raw_value(),
+            size: nvdimm.size,
+            memory_mapping_attributes: MEMORY_MAPPING_ATTRIBUTES,
+            ..Default::default()
+        });
+
+        nfit.append(NfitMemoryDeviceMap {
+            type_: 1,
+            length: 48,
+            device_handle: handle,
+            physical_id: index,
+            range_index: index,
+            control_region_index: index,
+            region_size: nvdimm.size,
+            interleave_ways: 1,
+            ..Default::default()
+        });
+
+        nfit.append(NfitControlRegion {
+            type_: 4,
+            length: 80,
+            control_region_index: index,
+            vendor_id: 0x8086,
+            device_id: 0x1,
+            revision_id: 0x1,
+            serial_number: handle,
+            format_interface_code: FORMAT_INTERFACE_CODE,
+            ..Default::default()
+        });
+    }
+
+    nfit
+}
+
+fn create_cedt_table(host_bridges: &[(u16, u64)]) -> Sdt {
+    let mut cedt = Sdt::new(*b"CEDT", 36, 1, *b"CLOUDH", *b"CHCEDT  ", 1);
+
+    assert_eq!(std::mem::size_of::<CedtHostBridge>(), 32);
+
+    for (uid, rcrb) in host_bridges {
+        // CXL 1.1 host bridge, pointing to its downstream and upstream port
+        // RCRBs.
+        cedt.append(CedtHostBridge {
+            type_: 0,
+            length: 32,
+            uid: *uid as u32,
+            cxl_version: 0,
+            base: *rcrb,
+            base_length: devices::cxl::CXL_RCRB_SIZE,
+            ..Default::default()
+        });
+    }
+
+    cedt
+}
+
+// The HMAT is only created when latencies or bandwidths are provided.
+fn has_numa_performance(numa_nodes: &NumaNodes) -> bool {
+    numa_nodes
+        .values()
+        .any(|node| !node.latencies.is_empty() || !node.bandwidths.is_empty())
+}
+
+fn create_hmat_table(numa_nodes: &NumaNodes) -> Sdt {
+    // Access latency, in picoseconds, and access bandwidth, in MB/s
+    const ACCESS_LATENCY: u8 = 0;
+    const ACCESS_BANDWIDTH: u8 = 3;
+    const PS_PER_NS: u64 = 1000;
+
+    let mut hmat = Sdt::new(*b"HMAT", 36, 2, *b"CLOUDH", *b"CHHMAT  ", 1);
+    // HMAT reserved 4 bytes
+    hmat.append_slice(&[0u8; 4]);
+
+    assert_eq!(std::mem::size_of::<HmatMemoryProximityDomain>(), 40);
+    assert_eq!(std::mem::size_of::<HmatLocality>(), 32);
+
+    let initiators: Vec<u32> = numa_nodes
+        .iter()
+        .filter(|(_, node)| !node.cpus.is_empty())
+        .map(|(id, _)| *id)
+        .collect();
+    let targets: Vec<u32> = numa_nodes
+        .iter()
+        .filter(|(_, node)| !node.memory_regions.is_empty() || !node.hotplug_regions.is_empty())
+        .map(|(id, _)| *id)
+        .collect();
+
+    for target in targets.iter() {
+        // Nodes with CPUs are the initiators attached to their own memory.
+        let attached_initiator = initiators.contains(target);
+        hmat.append(HmatMemoryProximityDomain {
+            type_: 0,
+            length: 40,
+            flags: attached_initiator as u16,
+            initiator_proximity_domain: if attached_initiator { *target } else { 0 },
+            memory_proximity_domain: *target,
+            ..Default::default()
+        });
+    }
+
+    for (data_type, unit, values) in [
+        (
+            ACCESS_LATENCY,
+            PS_PER_NS,
+            initiators
+                .iter()
+                .map(|i| &numa_nodes[i].latencies)
+                .collect::<Vec<_>>(),
+        ),
+        (
+            ACCESS_BANDWIDTH,
+            1,
+            initiators
+                .iter()
+                .map(|i| &numa_nodes[i].bandwidths)
+                .collect(),
+        ),
+    ] {
+        let Some(max) = values.iter().flat_map(|v| v.values()).max() else {
+            continue;
+        };
+
+        // Entries are 16 bits wide, 0xffff meaning unreachable, so the base
+        // unit is scaled up to fit the largest value.
+        let scale = max.div_ceil(0xfffe);
+        let num_entries = initiators.len() * targets.len();
+        hmat.append(HmatLocality {
+            type_: 1,
+            length: (32 + 4 * (initiators.len() + targets.len()) + 2 * num_entries) as u32,
+            data_type,
+            num_initiators: initiators.len() as u32,
+            num_targets: targets.len() as u32,
+            entry_base_unit: unit * scale,
+            ..Default::default()
+        });
+        for initiator in initiators.iter() {
+            hmat.append(*initiator);
+        }
+        for target in targets.iter() {
+            hmat.append(*target);
+        }
+        for initiator_values in values.iter() {
+            for target in targets.iter() {
+                // A missing value is reported as 0, meaning not provided.
+                let entry = initiator_values
+                    .get(target)
+                    .map(|value| value.div_ceil(scale) as u16)
+                    .unwrap_or(0);
+                hmat.append(entry);
+            }
+        }
+    }
+
+    hmat
+}
+
 fn create_slit_table(numa_nodes: &NumaNodes) -> Sdt {
     let mut slit = Sdt::new(*b"SLIT", 36, 1, *b"CLOUDH", *b"CHSLIT  ", 1);
     // Number of System Localities on 8 bytes.
@@ -416,8 +773,9 @@ fn create_spcr_table(base_address: u64, gsi: u32) -> Sdt {
     let mut spcr = Sdt::new(*b"SPCR", 80, 2, *b"CLOUDH", *b"CHSPCR  ", 1);
     // Interface Type
     spcr.write(36, 3u8);
-    // Base Address in format ACPI Generic Address Structure
-    spcr.write(40, GenericAddress::mmio_address::<u8>(base_address));
+    // Base Address in format ACPI Generic Address Structure. The PL011
+    // registers must be accessed 32 bits at a time.
+    spcr.write(40, GenericAddress::mmio_address::<u32>(base_address));
     // Interrupt Type: Bit[3] ARMH GIC interrupt
     spcr.write(52, (1 << 3) as u8);
     // Global System Interrupt used by the UART
@@ -426,8 +784,8 @@ fn create_spcr_table(base_address: u64, gsi: u32) -> Sdt {
     spcr.write(58, 3u8);
     // Stop Bits: 1 Stop bit
     spcr.write(60, 1u8);
-    // Flow Control: Bit[1] = RTS/CTS hardware flow control
-    spcr.write(61, (1 << 1) as u8);
+    // Flow Control: None, the emulated UART doesn't implement any
+    spcr.write(61, 0u8);
     // PCI Device ID: Not a PCI device
     spcr.write(64, 0xffff_u16);
     // PCI Vendor ID: Not a PCI device
@@ -486,7 +844,7 @@ fn create_dbg2_table(base_address: u64) -> Sdt {
     /* BaseAddressRegister */
     dbg2.write(
         debug_device_info_offset + base_address_register_offset as usize,
-        GenericAddress::mmio_address::<u8>(base_address),
+        GenericAddress::mmio_address::<u32>(base_address),
     );
     /* AddressSize */
     dbg2.write_u32(
@@ -643,9 +1001,32 @@ pub fn create_acpi_tables(
         .write_slice(dsdt.as_slice(), dsdt_offset)
         .expect("Error writing DSDT table");
 
+    // FACS, needed for the hardware signature checked when resuming from
+    // hibernation, and which must be 64 bytes aligned
+    #[cfg(target_arch = "x86_64")]
+    let (facs_offset, facs_end) = {
+        let facs = create_facs_table(hardware_signature(&dsdt));
+        let facs_offset = GuestAddress(
+            dsdt_offset
+                .checked_add(dsdt.len() as u64)
+                .unwrap()
+                .0
+                .next_multiple_of(64),
+        );
+        guest_mem
+            .write_slice(&facs, facs_offset)
+            .expect("Error writing FACS table");
+        (
+            Some(facs_offset),
+            facs_offset.checked_add(facs.len() as u64).unwrap(),
+        )
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let (facs_offset, facs_end) = (None, dsdt_offset.checked_add(dsdt.len() as u64).unwrap());
+
     // FACP aka FADT
-    let facp = create_facp_table(dsdt_offset, device_manager);
-    let facp_offset = dsdt_offset.checked_add(dsdt.len() as u64).unwrap();
+    let facp = create_facp_table(dsdt_offset, facs_offset, device_manager);
+    let facp_offset = facs_end;
     guest_mem
         .write_slice(facp.as_slice(), facp_offset)
         .expect("Error writing FACP table");
@@ -697,29 +1078,18 @@ pub fn create_acpi_tables(
     prev_tbl_len = mcfg.len() as u64;
     prev_tbl_off = mcfg_offset;
 
-    // SPCR and DBG2
+    // SPCR and DBG2, only describing the serial device when there is one,
+    // as DBG2 refers to its node in the DSDT.
     #[cfg(target_arch = "aarch64")]
-    {
-        let is_serial_on = device_manager
-            .lock()
-            .unwrap()
-            .get_device_info()
-            .clone()
-            .contains_key(&(DeviceType::Serial, DeviceType::Serial.to_string()));
+    let serial_device_irq = device_manager
+        .lock()
+        .unwrap()
+        .get_device_info()
+        .get(&(DeviceType::Serial, DeviceType::Serial.to_string()))
+        .map(|info| info.irq());
+    #[cfg(target_arch = "aarch64")]
+    if let Some(serial_device_irq) = serial_device_irq {
         let serial_device_addr = arch::layout::LEGACY_SERIAL_MAPPED_IO_START.raw_value();
-        let serial_device_irq = if is_serial_on {
-            device_manager
-                .lock()
-                .unwrap()
-                .get_device_info()
-                .clone()
-                .get(&(DeviceType::Serial, DeviceType::Serial.to_string()))
-                .unwrap()
-                .irq()
-        } else {
-            // If serial is turned off, add a fake device with invalid irq.
-            31
-        };
 
         // SPCR
         let spcr = create_spcr_table(serial_device_addr, serial_device_irq);
@@ -781,8 +1151,46 @@ pub fn create_acpi_tables(
 
         prev_tbl_len = slit.len() as u64;
         prev_tbl_off = slit_offset;
+
+        // HMAT
+        if has_numa_performance(numa_nodes) {
+            let hmat = create_hmat_table(numa_nodes);
+            let hmat_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+            guest_mem
+                .write_slice(hmat.as_slice(), hmat_offset)
+                .expect("Error writing HMAT table");
+            tables.push(hmat_offset.0);
+
+            prev_tbl_len = hmat.len() as u64;
+            prev_tbl_off = hmat_offset;
+        }
     };
 
+    // NFIT
+    if let Some(nvdimm_device) = device_manager.lock().unwrap().nvdimm_device() {
+        let nfit = create_nfit_table(nvdimm_device.lock().unwrap().nvdimms());
+        let nfit_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(nfit.as_slice(), nfit_offset)
+            .expect("Error writing NFIT table");
+        tables.push(nfit_offset.0);
+        prev_tbl_len = nfit.len() as u64;
+        prev_tbl_off = nfit_offset;
+    }
+
+    // CEDT
+    let cxl_host_bridges = device_manager.lock().unwrap().cxl_host_bridges();
+    if !cxl_host_bridges.is_empty() {
+        let cedt = create_cedt_table(&cxl_host_bridges);
+        let cedt_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(cedt.as_slice(), cedt_offset)
+            .expect("Error writing CEDT table");
+        tables.push(cedt_offset.0);
+        prev_tbl_len = cedt.len() as u64;
+        prev_tbl_off = cedt_offset;
+    }
+
     #[cfg(target_arch = "aarch64")]
     {
         let iort = create_iort_table(device_manager.lock().unwrap().pci_segments());
@@ -849,7 +1257,7 @@ pub fn create_acpi_tables_tdx(
     )];
 
     // FACP aka FADT
-    tables.push(create_facp_table(GuestAddress(0), device_manager));
+    tables.push(create_facp_table(GuestAddress(0), None, device_manager));
 
     // MADT
     tables.push(cpu_manager.lock().unwrap().create_madt());
@@ -874,6 +1282,11 @@ pub fn create_acpi_tables_tdx(
 
         // SLIT
         tables.push(create_slit_table(numa_nodes));
+
+        // HMAT
+        if has_numa_performance(numa_nodes) {
+            tables.push(create_hmat_table(numa_nodes));
+        }
     };
 
     // VIOT
@@ -6,10 +6,11 @@ use super::{ApiAction, ApiRequest};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
 use crate::api::{
-    AddDisk, Body, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice, VmAddVdpa,
-    VmAddVsock, VmBoot, VmCounters, VmCreate, VmDelete, VmInfo, VmPause, VmPowerButton, VmReboot,
-    VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot, VmmPing, VmmShutdown,
+    AddDisk, Body, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUsbDevice, VmAddUserDevice,
+    VmAddVdpa, VmAddVsock, VmBoot, VmClock, VmCounters, VmCreate, VmDelete, VmInfo, VmPause,
+    VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore,
+    VmResume, VmSendMigration, VmSerialCapture, VmShutdown, VmSnapshot, VmSnapshotDelete,
+    VmSnapshotList, VmUpdateCgroup, VmmAddVm, VmmListVms, VmmPing, VmmRemoveVm, VmmShutdown,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error as VmmError, Result as VmmResult};
@@ -126,6 +127,22 @@ impl DBusApi {
             .map_err(api_error)
     }
 
+    async fn vmm_add_vm(&self, vmm_add_vm_data: String) -> Result<()> {
+        let vmm_add_vm_data = serde_json::from_str(&vmm_add_vm_data).map_err(api_error)?;
+        self.vm_action(&VmmAddVm, vmm_add_vm_data).await.map(|_| ())
+    }
+
+    async fn vmm_list_vms(&self) -> Result<Optional<String>> {
+        self.vm_action(&VmmListVms, ()).await
+    }
+
+    async fn vmm_remove_vm(&self, vmm_remove_vm_data: String) -> Result<()> {
+        let vmm_remove_vm_data = serde_json::from_str(&vmm_remove_vm_data).map_err(api_error)?;
+        self.vm_action(&VmmRemoveVm, vmm_remove_vm_data)
+            .await
+            .map(|_| ())
+    }
+
     async fn vm_add_device(&self, device_config: String) -> Result<Optional<String>> {
         let device_config = serde_json::from_str(&device_config).map_err(api_error)?;
         self.vm_action(&VmAddDevice, device_config).await
@@ -155,6 +172,13 @@ impl DBusApi {
         self.vm_action(&VmAddPmem, pmem_config).await
     }
 
+    async fn vm_add_usb_device(&self, usb_device_config: String) -> Result<()> {
+        let usb_device_config = serde_json::from_str(&usb_device_config).map_err(api_error)?;
+        self.vm_action(&VmAddUsbDevice, usb_device_config)
+            .await
+            .map(|_| ())
+    }
+
     async fn vm_add_user_device(&self, vm_add_user_device: String) -> Result<Optional<String>> {
         let vm_add_user_device = serde_json::from_str(&vm_add_user_device).map_err(api_error)?;
         self.vm_action(&VmAddUserDevice, vm_add_user_device).await
@@ -193,6 +217,10 @@ impl DBusApi {
         ))
     }
 
+    async fn vm_clock(&self) -> Result<Optional<String>> {
+        self.vm_action(&VmClock, ()).await
+    }
+
     async fn vm_counters(&self) -> Result<Optional<String>> {
         self.vm_action(&VmCounters, ()).await
     }
@@ -290,6 +318,10 @@ impl DBusApi {
         self.vm_action(&VmResume, ()).await.map(|_| ())
     }
 
+    async fn vm_serial_capture(&self) -> Result<Optional<String>> {
+        self.vm_action(&VmSerialCapture, ()).await
+    }
+
     async fn vm_shutdown(&self) -> Result<()> {
         self.vm_action(&VmShutdown, ()).await.map(|_| ())
     }
@@ -301,6 +333,27 @@ impl DBusApi {
             .map(|_| ())
     }
 
+    async fn vm_snapshot_delete(&self, vm_snapshot_delete_data: String) -> Result<()> {
+        let vm_snapshot_delete_data =
+            serde_json::from_str(&vm_snapshot_delete_data).map_err(api_error)?;
+        self.vm_action(&VmSnapshotDelete, vm_snapshot_delete_data)
+            .await
+            .map(|_| ())
+    }
+
+    async fn vm_snapshot_list(&self, vm_snapshot_list_data: String) -> Result<Optional<String>> {
+        let vm_snapshot_list_data =
+            serde_json::from_str(&vm_snapshot_list_data).map_err(api_error)?;
+        self.vm_action(&VmSnapshotList, vm_snapshot_list_data).await
+    }
+
+    async fn vm_update_cgroup(&self, vm_update_cgroup: String) -> Result<()> {
+        let vm_update_cgroup = serde_json::from_str(&vm_update_cgroup).map_err(api_error)?;
+        self.vm_action(&VmUpdateCgroup, vm_update_cgroup)
+            .await
+            .map(|_| ())
+    }
+
     // implementation of this function is provided by the `#[zbus(signal)]` macro call
     #[zbus(signal)]
     async fn event(ctxt: &zbus::SignalContext<'_>, event: Arc<String>) -> zbus::Result<()>;
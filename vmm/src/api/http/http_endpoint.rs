@@ -8,10 +8,11 @@ use crate::api::http::{error_response, EndpointHandler, HttpError};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
 use crate::api::{
-    AddDisk, ApiAction, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice,
-    VmAddVdpa, VmAddVsock, VmBoot, VmConfig, VmCounters, VmDelete, VmNmi, VmPause, VmPowerButton,
-    VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    AddDisk, ApiAction, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUsbDevice,
+    VmAddUserDevice, VmAddVdpa, VmAddVsock, VmBoot, VmClock, VmConfig, VmCounters, VmDelete, VmNmi,
+    VmPause, VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone,
+    VmRestore, VmResume, VmSendMigration, VmSerialCapture, VmShutdown, VmSnapshot,
+    VmSnapshotDelete, VmSnapshotList, VmUpdateCgroup, VmmAddVm, VmmListVms, VmmRemoveVm,
 };
 use crate::config::{NetConfig, RestoreConfig};
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
@@ -163,7 +164,10 @@ macro_rules! vm_action_put_handler_body {
     };
 }
 
+vm_action_get_handler!(VmClock);
 vm_action_get_handler!(VmCounters);
+vm_action_get_handler!(VmSerialCapture);
+vm_action_get_handler!(VmmListVms);
 
 vm_action_put_handler!(VmBoot);
 vm_action_put_handler!(VmDelete);
@@ -180,17 +184,61 @@ vm_action_put_handler_body!(VmAddFs);
 vm_action_put_handler_body!(VmAddPmem);
 vm_action_put_handler_body!(VmAddVdpa);
 vm_action_put_handler_body!(VmAddVsock);
+vm_action_put_handler_body!(VmAddUsbDevice);
 vm_action_put_handler_body!(VmAddUserDevice);
 vm_action_put_handler_body!(VmRemoveDevice);
 vm_action_put_handler_body!(VmResize);
 vm_action_put_handler_body!(VmResizeZone);
 vm_action_put_handler_body!(VmSnapshot);
-vm_action_put_handler_body!(VmReceiveMigration);
-vm_action_put_handler_body!(VmSendMigration);
+vm_action_put_handler_body!(VmSnapshotList);
+vm_action_put_handler_body!(VmSnapshotDelete);
+vm_action_put_handler_body!(VmUpdateCgroup);
+vm_action_put_handler_body!(VmmAddVm);
+vm_action_put_handler_body!(VmmRemoveVm);
 
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 vm_action_put_handler_body!(VmCoredump);
 
+// The file descriptor of a `fd:` migration URL is the one passed along the
+// request, the number in the URL being only meaningful to the client. The
+// files are closed when the request is rejected.
+fn migration_fd(url: &str, mut files: Vec<File>) -> std::result::Result<Option<File>, HttpError> {
+    match files.len() {
+        0 => Ok(None),
+        1 if url.starts_with("fd:") => Ok(files.pop()),
+        _ => Err(HttpError::BadRequest),
+    }
+}
+
+macro_rules! vm_migration_put_handler {
+    ($action:ty, $url:ident) => {
+        impl PutHandler for $action {
+            fn handle_request(
+                &'static self,
+                api_notifier: EventFd,
+                api_sender: Sender<ApiRequest>,
+                body: &Option<Body>,
+                files: Vec<File>,
+            ) -> std::result::Result<Option<Body>, HttpError> {
+                if let Some(body) = body {
+                    let mut migration_data: <$action as ApiAction>::RequestBody =
+                        serde_json::from_slice(body.raw())?;
+                    migration_data.fd = migration_fd(&migration_data.$url, files)?;
+                    self.send(api_notifier, api_sender, migration_data)
+                        .map_err(HttpError::ApiError)
+                } else {
+                    Err(HttpError::BadRequest)
+                }
+            }
+        }
+
+        impl GetHandler for $action {}
+    };
+}
+
+vm_migration_put_handler!(VmReceiveMigration, receiver_url);
+vm_migration_put_handler!(VmSendMigration, destination_url);
+
 impl PutHandler for VmAddNet {
     fn handle_request(
         &'static self,
@@ -7,10 +7,11 @@ use self::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdow
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
 use crate::api::{
-    AddDisk, ApiError, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice,
-    VmAddVdpa, VmAddVsock, VmBoot, VmCounters, VmDelete, VmNmi, VmPause, VmPowerButton, VmReboot,
-    VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    AddDisk, ApiError, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUsbDevice,
+    VmAddUserDevice, VmAddVdpa, VmAddVsock, VmApiChannels, VmBoot, VmClock, VmCounters, VmDelete,
+    VmNmi, VmPause, VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize,
+    VmResizeZone, VmRestore, VmResume, VmSendMigration, VmSerialCapture, VmShutdown, VmSnapshot,
+    VmSnapshotDelete, VmSnapshotList, VmUpdateCgroup, VmmAddVm, VmmListVms, VmmRemoveVm,
 };
 use crate::landlock::Landlock;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
@@ -77,6 +78,7 @@ impl From<serde_json::Error> for HttpError {
 }
 
 const HTTP_ROOT: &str = "/api/v1";
+const HTTP_VMS_ROOT: &str = "/api/v1/vms/";
 
 pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     let mut response = Response::new(Version::Http11, status);
@@ -189,6 +191,10 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.add-pmem"),
         Box::new(VmActionHandler::new(&VmAddPmem)),
     );
+    r.routes.insert(
+        endpoint!("/vm.add-usb-device"),
+        Box::new(VmActionHandler::new(&VmAddUsbDevice)),
+    );
     r.routes.insert(
         endpoint!("/vm.add-vdpa"),
         Box::new(VmActionHandler::new(&VmAddVdpa)),
@@ -201,6 +207,10 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.boot"),
         Box::new(VmActionHandler::new(&VmBoot)),
     );
+    r.routes.insert(
+        endpoint!("/vm.clock"),
+        Box::new(VmActionHandler::new(&VmClock)),
+    );
     r.routes.insert(
         endpoint!("/vm.counters"),
         Box::new(VmActionHandler::new(&VmCounters)),
@@ -252,6 +262,10 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.send-migration"),
         Box::new(VmActionHandler::new(&VmSendMigration)),
     );
+    r.routes.insert(
+        endpoint!("/vm.serial-capture"),
+        Box::new(VmActionHandler::new(&VmSerialCapture)),
+    );
     r.routes.insert(
         endpoint!("/vm.shutdown"),
         Box::new(VmActionHandler::new(&VmShutdown)),
@@ -260,6 +274,18 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.snapshot"),
         Box::new(VmActionHandler::new(&VmSnapshot)),
     );
+    r.routes.insert(
+        endpoint!("/vm.snapshot-delete"),
+        Box::new(VmActionHandler::new(&VmSnapshotDelete)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.snapshot-list"),
+        Box::new(VmActionHandler::new(&VmSnapshotList)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.update-cgroup"),
+        Box::new(VmActionHandler::new(&VmUpdateCgroup)),
+    );
     #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
     r.routes.insert(
         endpoint!("/vm.coredump"),
@@ -267,6 +293,18 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
     );
     r.routes
         .insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+    r.routes.insert(
+        endpoint!("/vmm.add-vm"),
+        Box::new(VmActionHandler::new(&VmmAddVm)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.list-vms"),
+        Box::new(VmActionHandler::new(&VmmListVms)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.remove-vm"),
+        Box::new(VmActionHandler::new(&VmmRemoveVm)),
+    );
     r.routes
         .insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
     r.routes
@@ -275,21 +313,43 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
     r
 });
 
+// Splits the path of a request targeting one of the VMs added in multi-VM
+// mode, i.e. `/api/v1/vms/{id}/vm.*`, into the identifier of the VM and the
+// path of the same request targeting the VM of the VMM.
+fn split_vm_path(path: &str) -> Option<(&str, String)> {
+    let (id, action) = path.strip_prefix(HTTP_VMS_ROOT)?.split_once('/')?;
+    action
+        .starts_with("vm.")
+        .then(|| (id, endpoint!(format!("/{action}"))))
+}
+
 fn handle_http_request(
     request: &Request,
     api_notifier: &EventFd,
     api_sender: &Sender<ApiRequest>,
+    vm_api_channels: &VmApiChannels,
 ) -> Response {
     let path = request.uri().get_abs_path().to_string();
-    let mut response = match HTTP_ROUTES.routes.get(&path) {
-        Some(route) => match api_notifier.try_clone() {
-            Ok(notifier) => route.handle_request(request, notifier, api_sender.clone()),
-            Err(_) => error_response(
-                HttpError::InternalServerError,
-                StatusCode::InternalServerError,
-            ),
-        },
-        None => error_response(HttpError::NotFound, StatusCode::NotFound),
+    let (path, api_channel) = match split_vm_path(&path) {
+        Some((id, vm_path)) => (
+            vm_path,
+            vm_api_channels
+                .lock()
+                .unwrap()
+                .get(id)
+                .map(|(notifier, sender)| (notifier.try_clone(), sender.clone())),
+        ),
+        None => (path, Some((api_notifier.try_clone(), api_sender.clone()))),
+    };
+    let mut response = match (HTTP_ROUTES.routes.get(&path), api_channel) {
+        (Some(route), Some((Ok(notifier), sender))) => {
+            route.handle_request(request, notifier, sender)
+        }
+        (Some(_), Some((Err(_), _))) => error_response(
+            HttpError::InternalServerError,
+            StatusCode::InternalServerError,
+        ),
+        _ => error_response(HttpError::NotFound, StatusCode::NotFound),
     };
 
     response.set_server("Cloud Hypervisor API");
@@ -297,10 +357,12 @@ fn handle_http_request(
     response
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_http_thread(
     mut server: HttpServer,
     api_notifier: EventFd,
     api_sender: Sender<ApiRequest>,
+    vm_api_channels: VmApiChannels,
     seccomp_action: &SeccompAction,
     exit_evt: EventFd,
     hypervisor_type: HypervisorType,
@@ -350,7 +412,12 @@ fn start_http_thread(
                         Ok(request_vec) => {
                             for server_request in request_vec {
                                 if let Err(e) = server.respond(server_request.process(|request| {
-                                    handle_http_request(request, &api_notifier, &api_sender)
+                                    handle_http_request(
+                                        request,
+                                        &api_notifier,
+                                        &api_sender,
+                                        &vm_api_channels,
+                                    )
                                 })) {
                                     error!("HTTP server error on response: {}", e);
                                 }
@@ -382,10 +449,12 @@ fn start_http_thread(
     Ok((thread, api_shutdown_fd))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn start_http_path_thread(
     path: &str,
     api_notifier: EventFd,
     api_sender: Sender<ApiRequest>,
+    vm_api_channels: VmApiChannels,
     seccomp_action: &SeccompAction,
     exit_evt: EventFd,
     hypervisor_type: HypervisorType,
@@ -401,6 +470,7 @@ pub fn start_http_path_thread(
         server,
         api_notifier,
         api_sender,
+        vm_api_channels,
         seccomp_action,
         exit_evt,
         hypervisor_type,
@@ -408,10 +478,12 @@ pub fn start_http_path_thread(
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn start_http_fd_thread(
     fd: RawFd,
     api_notifier: EventFd,
     api_sender: Sender<ApiRequest>,
+    vm_api_channels: VmApiChannels,
     seccomp_action: &SeccompAction,
     exit_evt: EventFd,
     hypervisor_type: HypervisorType,
@@ -423,6 +495,7 @@ pub fn start_http_fd_thread(
         server,
         api_notifier,
         api_sender,
+        vm_api_channels,
         seccomp_action,
         exit_evt,
         hypervisor_type,
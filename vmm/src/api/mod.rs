@@ -39,16 +39,19 @@ pub use self::http::start_http_fd_thread;
 pub use self::http::start_http_path_thread;
 
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, UserDeviceConfig,
-    VdpaConfig, VmConfig, VsockConfig,
+    CgroupResources, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
+    UsbDeviceConfig, UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
 };
 use crate::device_tree::DeviceTree;
+use crate::guest_agent::GuestNetworkInterface;
 use crate::vm::{Error as VmError, VmState};
 use crate::Error as VmmError;
 use core::fmt;
 use micro_http::Body;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::fs::File;
 use std::io;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
@@ -103,6 +106,12 @@ pub enum ApiError {
     /// The VM could not be snapshotted.
     VmSnapshot(VmError),
 
+    /// The snapshots could not be listed.
+    VmSnapshotList(VmError),
+
+    /// The snapshot could not be deleted.
+    VmSnapshotDelete(VmError),
+
     /// The VM could not restored.
     VmRestore(VmError),
 
@@ -151,6 +160,9 @@ pub enum ApiError {
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
+    /// The USB device could not be added to the VM.
+    VmAddUsbDevice(VmError),
+
     /// Error starting migration receiver
     VmReceiveMigration(MigratableError),
 
@@ -162,6 +174,18 @@ pub enum ApiError {
 
     /// Error triggering NMI
     VmNmi(VmError),
+
+    /// The cgroup resources could not be updated
+    VmUpdateCgroup(VmError),
+
+    /// The VM could not be added to the VMM.
+    VmmAddVm(VmError),
+
+    /// The VM could not be removed from the VMM.
+    VmmRemoveVm(VmError),
+
+    /// The VMs of the VMM could not be listed.
+    VmmListVms(VmError),
 }
 pub type ApiResult<T> = Result<T, ApiError>;
 
@@ -184,6 +208,8 @@ impl Display for ApiError {
             VmShutdown(vm_error) => write!(f, "{}", vm_error),
             VmReboot(vm_error) => write!(f, "{}", vm_error),
             VmSnapshot(vm_error) => write!(f, "{}", vm_error),
+            VmSnapshotList(vm_error) => write!(f, "{}", vm_error),
+            VmSnapshotDelete(vm_error) => write!(f, "{}", vm_error),
             VmRestore(vm_error) => write!(f, "{}", vm_error),
             VmCoredump(vm_error) => write!(f, "{}", vm_error),
             VmmShutdown(vm_error) => write!(f, "{}", vm_error),
@@ -200,10 +226,15 @@ impl Display for ApiError {
             VmAddNet(vm_error) => write!(f, "{}", vm_error),
             VmAddVdpa(vm_error) => write!(f, "{}", vm_error),
             VmAddVsock(vm_error) => write!(f, "{}", vm_error),
+            VmAddUsbDevice(vm_error) => write!(f, "{}", vm_error),
             VmReceiveMigration(migratable_error) => write!(f, "{}", migratable_error),
             VmSendMigration(migratable_error) => write!(f, "{}", migratable_error),
             VmPowerButton(vm_error) => write!(f, "{}", vm_error),
             VmNmi(vm_error) => write!(f, "{}", vm_error),
+            VmUpdateCgroup(vm_error) => write!(f, "{}", vm_error),
+            VmmAddVm(vm_error) => write!(f, "{}", vm_error),
+            VmmRemoveVm(vm_error) => write!(f, "{}", vm_error),
+            VmmListVms(vm_error) => write!(f, "{}", vm_error),
         }
     }
 }
@@ -214,6 +245,8 @@ pub struct VmInfoResponse {
     pub state: VmState,
     pub memory_actual_size: u64,
     pub device_tree: Option<Arc<Mutex<DeviceTree>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_network_interfaces: Option<Vec<GuestNetworkInterface>>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -224,9 +257,22 @@ pub struct VmmPingResponse {
     pub features: Vec<String>,
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmClockResponse {
+    pub guest_ns: u64,
+    pub host_realtime_ns: u64,
+    pub offset_ns: i64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSerialCaptureResponse {
+    pub output: String,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmResizeData {
     pub desired_vcpus: Option<u8>,
+    pub desired_max_vcpus: Option<u8>,
     pub desired_ram: Option<u64>,
     pub desired_balloon: Option<u64>,
 }
@@ -248,25 +294,60 @@ pub struct VmSnapshotConfig {
     pub destination_url: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSnapshotListData {
+    /// The snapshot tree URL
+    pub tree_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSnapshotDeleteData {
+    /// The snapshot tree URL
+    pub tree_url: String,
+    /// The identifier of the snapshot in the tree
+    pub id: String,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmCoredumpData {
     /// The coredump destination file
     pub destination_url: String,
 }
 
-#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Debug)]
 pub struct VmReceiveMigrationData {
     /// URL for the reception of migration state
     pub receiver_url: String,
+    /// Move the guest clock forward by the downtime of the migration
+    #[serde(default)]
+    pub resync_clock: bool,
+    /// File descriptor of a `fd:` URL, received along the request
+    #[serde(skip)]
+    pub fd: Option<File>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Debug)]
 pub struct VmSendMigrationData {
     /// URL to migrate the VM to
     pub destination_url: String,
     /// Send memory across socket without copying
     #[serde(default)]
     pub local: bool,
+    /// File descriptor of a `fd:` URL, received along the request
+    #[serde(skip)]
+    pub fd: Option<File>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmAddVmData {
+    /// Identifier of the VM, routing the requests targeting it
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmRemoveVmData {
+    /// Identifier of the VM
+    pub id: String,
 }
 
 pub enum ApiResponsePayload {
@@ -299,6 +380,10 @@ pub trait RequestHandler {
 
     fn vm_restore(&mut self, restore_cfg: RestoreConfig) -> Result<(), VmError>;
 
+    fn vm_snapshot_list(&self, tree_url: &str) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_snapshot_delete(&mut self, tree_url: &str, id: &str) -> Result<(), VmError>;
+
     #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
     fn vm_coredump(&mut self, destination_url: &str) -> Result<(), VmError>;
 
@@ -314,9 +399,16 @@ pub trait RequestHandler {
 
     fn vmm_shutdown(&mut self) -> Result<(), VmError>;
 
+    fn vmm_add_vm(&mut self, id: String) -> Result<(), VmError>;
+
+    fn vmm_remove_vm(&mut self, id: String) -> Result<(), VmError>;
+
+    fn vmm_list_vms(&self) -> Result<Option<Vec<u8>>, VmError>;
+
     fn vm_resize(
         &mut self,
         desired_vcpus: Option<u8>,
+        desired_max_vcpus: Option<u8>,
         desired_ram: Option<u64>,
         desired_balloon: Option<u64>,
     ) -> Result<(), VmError>;
@@ -344,8 +436,14 @@ pub trait RequestHandler {
 
     fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> Result<Option<Vec<u8>>, VmError>;
 
+    fn vm_add_usb_device(&mut self, usb_cfg: UsbDeviceConfig) -> Result<(), VmError>;
+
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError>;
 
+    fn vm_clock(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_serial_capture(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
     fn vm_power_button(&mut self) -> Result<(), VmError>;
 
     fn vm_receive_migration(
@@ -359,6 +457,8 @@ pub trait RequestHandler {
     ) -> Result<(), MigratableError>;
 
     fn vm_nmi(&mut self) -> Result<(), VmError>;
+
+    fn vm_update_cgroup(&mut self, resources: CgroupResources) -> Result<(), VmError>;
 }
 
 /// It would be nice if we could pass around an object like this:
@@ -377,6 +477,11 @@ pub trait RequestHandler {
 pub type ApiRequest =
     Box<dyn FnOnce(&mut dyn RequestHandler) -> Result<bool, VmmError> + Send + 'static>;
 
+/// API channels of the VMs added to the VMM in multi-VM mode, by identifier.
+/// They are shared with the HTTP server thread, which routes the requests
+/// targeting these VMs to their own channel.
+pub type VmApiChannels = Arc<Mutex<BTreeMap<String, (EventFd, Sender<ApiRequest>)>>>;
+
 fn get_response<Action: ApiAction>(
     action: &Action,
     api_evt: EventFd,
@@ -682,6 +787,43 @@ impl ApiAction for VmAddVsock {
     }
 }
 
+pub struct VmAddUsbDevice;
+
+impl ApiAction for VmAddUsbDevice {
+    type RequestBody = UsbDeviceConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmAddUsbDevice {:?}", config);
+
+            let response = vmm
+                .vm_add_usb_device(config)
+                .map_err(ApiError::VmAddUsbDevice)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmAddUserDevice;
 
 impl ApiAction for VmAddUserDevice {
@@ -752,6 +894,39 @@ impl ApiAction for VmBoot {
     }
 }
 
+pub struct VmClock;
+
+impl ApiAction for VmClock {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmClock");
+
+            let response = vmm
+                .vm_clock()
+                .map_err(ApiError::VmInfo)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 pub struct VmCoredump;
 
@@ -1120,6 +1295,7 @@ impl ApiAction for VmResize {
             let response = vmm
                 .vm_resize(
                     resize_data.desired_vcpus,
+                    resize_data.desired_max_vcpus,
                     resize_data.desired_ram,
                     resize_data.desired_balloon,
                 )
@@ -1181,6 +1357,43 @@ impl ApiAction for VmResizeZone {
     }
 }
 
+pub struct VmUpdateCgroup;
+
+impl ApiAction for VmUpdateCgroup {
+    type RequestBody = CgroupResources;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        resources: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmUpdateCgroup {:?}", resources);
+
+            let response = vmm
+                .vm_update_cgroup(resources)
+                .map_err(ApiError::VmUpdateCgroup)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmRestore;
 
 impl ApiAction for VmRestore {
@@ -1284,6 +1497,39 @@ impl ApiAction for VmSendMigration {
     }
 }
 
+pub struct VmSerialCapture;
+
+impl ApiAction for VmSerialCapture {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSerialCapture");
+
+            let response = vmm
+                .vm_serial_capture()
+                .map_err(ApiError::VmInfo)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmShutdown;
 
 impl ApiAction for VmShutdown {
@@ -1358,6 +1604,80 @@ impl ApiAction for VmSnapshot {
     }
 }
 
+pub struct VmSnapshotList;
+
+impl ApiAction for VmSnapshotList {
+    type RequestBody = VmSnapshotListData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        list_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSnapshotList {:?}", list_data);
+
+            let response = vmm
+                .vm_snapshot_list(&list_data.tree_url)
+                .map_err(ApiError::VmSnapshotList)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmSnapshotDelete;
+
+impl ApiAction for VmSnapshotDelete {
+    type RequestBody = VmSnapshotDeleteData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        delete_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSnapshotDelete {:?}", delete_data);
+
+            let response = vmm
+                .vm_snapshot_delete(&delete_data.tree_url, &delete_data.id)
+                .map_err(ApiError::VmSnapshotDelete)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmmPing;
 
 impl ApiAction for VmmPing {
@@ -1423,6 +1743,113 @@ impl ApiAction for VmmShutdown {
     }
 }
 
+pub struct VmmAddVm;
+
+impl ApiAction for VmmAddVm {
+    type RequestBody = VmmAddVmData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        add_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmAddVm {:?}", add_data);
+
+            let response = vmm
+                .vmm_add_vm(add_data.id)
+                .map_err(ApiError::VmmAddVm)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmRemoveVm;
+
+impl ApiAction for VmmRemoveVm {
+    type RequestBody = VmmRemoveVmData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        remove_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmRemoveVm {:?}", remove_data);
+
+            let response = vmm
+                .vmm_remove_vm(remove_data.id)
+                .map_err(ApiError::VmmRemoveVm)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmListVms;
+
+impl ApiAction for VmmListVms {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmListVms");
+
+            let response = vmm
+                .vmm_list_vms()
+                .map_err(ApiError::VmmListVms)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmNmi;
 
 impl ApiAction for VmNmi {
@@ -0,0 +1,315 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Native cgroup v2 resource control.
+//!
+//! The VMM process is moved into a dedicated cgroup (the "VM cgroup"), which
+//! carries the domain controllers (`memory.high` and `io.max`) for the whole
+//! VM. Below it, threaded sub-hierarchies are created for the VMM control
+//! threads, the vCPU threads and the virtio device (I/O) threads, so each of
+//! them can be given its own `cpu.weight`.
+
+use crate::vm_config::{CgroupConfig, CgroupResources};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const CGROUP_PROCS: &str = "cgroup.procs";
+const CGROUP_THREADS: &str = "cgroup.threads";
+const CGROUP_TYPE: &str = "cgroup.type";
+const CGROUP_SUBTREE_CONTROL: &str = "cgroup.subtree_control";
+const CPU_WEIGHT: &str = "cpu.weight";
+const IO_MAX: &str = "io.max";
+const MEMORY_HIGH: &str = "memory.high";
+
+pub const CGROUP_CPU_WEIGHT_MIN: u64 = 1;
+pub const CGROUP_CPU_WEIGHT_MAX: u64 = 10000;
+
+#[derive(Debug, Error)]
+pub enum CgroupError {
+    #[error("Error creating cgroup {0:?}: {1}")]
+    Create(PathBuf, #[source] io::Error),
+
+    #[error("Error enabling controllers on cgroup {0:?}: {1}")]
+    EnableControllers(PathBuf, #[source] io::Error),
+
+    #[error("Error writing {1} to {0:?}: {2}")]
+    Write(PathBuf, String, #[source] io::Error),
+
+    #[error("Error listing threads of the VMM process: {0}")]
+    ListThreads(#[source] io::Error),
+}
+
+type Result<T> = std::result::Result<T, CgroupError>;
+
+/// Threaded sub-hierarchies created below the VM cgroup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgroupThreadGroup {
+    /// VMM control threads (main, API, signal handling, ...)
+    Vmm,
+    /// vCPU threads
+    Vcpus,
+    /// Virtio device worker threads
+    Iothreads,
+}
+
+impl CgroupThreadGroup {
+    const ALL: [CgroupThreadGroup; 3] = [
+        CgroupThreadGroup::Vmm,
+        CgroupThreadGroup::Vcpus,
+        CgroupThreadGroup::Iothreads,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CgroupThreadGroup::Vmm => "vmm",
+            CgroupThreadGroup::Vcpus => "vcpus",
+            CgroupThreadGroup::Iothreads => "iothreads",
+        }
+    }
+
+    fn cpu_weight(&self, resources: &CgroupResources) -> Option<u64> {
+        match self {
+            CgroupThreadGroup::Vmm => resources.vmm_cpu_weight,
+            CgroupThreadGroup::Vcpus => resources.vcpu_cpu_weight,
+            CgroupThreadGroup::Iothreads => resources.iothread_cpu_weight,
+        }
+    }
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| CgroupError::Write(path.to_path_buf(), value.to_string(), e))
+}
+
+pub(crate) fn gettid() -> libc::pid_t {
+    // SAFETY: FFI call, trivially safe
+    unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+}
+
+pub struct CgroupManager {
+    path: PathBuf,
+}
+
+impl CgroupManager {
+    /// Create the VM cgroup and its threaded children, and move every thread
+    /// of the current process into it.
+    ///
+    /// Memory that has already been faulted in (e.g. prefaulted guest RAM)
+    /// stays charged to the cgroup the process was started in.
+    pub fn new(config: &CgroupConfig) -> Result<Self> {
+        let path = config.path.clone();
+
+        fs::create_dir_all(&path).map_err(|e| CgroupError::Create(path.clone(), e))?;
+
+        // Enabling the controllers on the parent is best effort, as they may
+        // have already been delegated by whoever created the hierarchy.
+        if let Some(parent) = path.parent() {
+            for controller in ["+cpu", "+io", "+memory"] {
+                if let Err(e) = fs::write(parent.join(CGROUP_SUBTREE_CONTROL), controller) {
+                    warn!(
+                        "Could not enable {} controller on cgroup {:?}: {}",
+                        &controller[1..],
+                        parent,
+                        e
+                    );
+                }
+            }
+        }
+
+        // SAFETY: FFI call, trivially safe
+        let pid = unsafe { libc::getpid() };
+        write_file(&path.join(CGROUP_PROCS), &pid.to_string())?;
+
+        // The children must be switched to threaded mode before the cpu
+        // controller is enabled, otherwise the kernel would reject it as the
+        // VM cgroup still contains the process.
+        for group in CgroupThreadGroup::ALL {
+            let group_path = path.join(group.name());
+            fs::create_dir_all(&group_path)
+                .map_err(|e| CgroupError::Create(group_path.clone(), e))?;
+            write_file(&group_path.join(CGROUP_TYPE), "threaded")?;
+        }
+
+        fs::write(path.join(CGROUP_SUBTREE_CONTROL), "+cpu")
+            .map_err(|e| CgroupError::EnableControllers(path.clone(), e))?;
+
+        let cgroup_manager = CgroupManager { path };
+
+        for entry in fs::read_dir("/proc/self/task").map_err(CgroupError::ListThreads)? {
+            let entry = entry.map_err(CgroupError::ListThreads)?;
+            cgroup_manager
+                .attach_thread(CgroupThreadGroup::Vmm, &entry.file_name().to_string_lossy())?;
+        }
+
+        cgroup_manager.update(&config.resources)?;
+
+        info!("VMM placed in cgroup {:?}", cgroup_manager.path);
+
+        Ok(cgroup_manager)
+    }
+
+    fn attach_thread(&self, group: CgroupThreadGroup, tid: &str) -> Result<()> {
+        write_file(&self.path.join(group.name()).join(CGROUP_THREADS), tid)
+    }
+
+    /// Move the thread `tid` of the current process into the given threaded
+    /// sub-hierarchy.
+    pub fn attach_thread_id(&self, group: CgroupThreadGroup, tid: libc::pid_t) -> Result<()> {
+        self.attach_thread(group, &tid.to_string())
+    }
+
+    /// Move the calling thread into the given threaded sub-hierarchy. Threads
+    /// spawned afterwards by the caller will inherit it.
+    pub fn attach_current_thread(&self, group: CgroupThreadGroup) -> Result<()> {
+        self.attach_thread_id(group, gettid())
+    }
+
+    /// Apply the resource settings that are set in `resources`, leaving the
+    /// other ones untouched.
+    pub fn update(&self, resources: &CgroupResources) -> Result<()> {
+        if let Some(memory_high) = resources.memory_high {
+            write_file(&self.path.join(MEMORY_HIGH), &memory_high.to_string())?;
+        }
+
+        if let Some(io_max) = &resources.io_max {
+            // Each line of io.max has to be written separately.
+            for limit in io_max {
+                write_file(&self.path.join(IO_MAX), limit)?;
+            }
+        }
+
+        for group in CgroupThreadGroup::ALL {
+            if let Some(weight) = group.cpu_weight(resources) {
+                write_file(
+                    &self.path.join(group.name()).join(CPU_WEIGHT),
+                    &weight.to_string(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn read_file(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    // A plain directory stands in for the cgroup filesystem, each write
+    // leaving the last value written in the file.
+    fn create_cgroup_manager(dir: &TempDir, resources: CgroupResources) -> CgroupManager {
+        let config = CgroupConfig {
+            path: dir.as_path().join("vm0"),
+            resources,
+        };
+        CgroupManager::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_cgroup_new() {
+        let dir = TempDir::new().unwrap();
+        let cgroup_manager = create_cgroup_manager(&dir, CgroupResources::default());
+        let path = dir.as_path().join("vm0");
+        assert_eq!(cgroup_manager.path, path);
+
+        assert_eq!(
+            read_file(&path.join(CGROUP_PROCS)),
+            // SAFETY: FFI call, trivially safe
+            unsafe { libc::getpid() }.to_string()
+        );
+        assert_eq!(read_file(&path.join(CGROUP_SUBTREE_CONTROL)), "+cpu");
+        assert_eq!(
+            read_file(&dir.as_path().join(CGROUP_SUBTREE_CONTROL)),
+            "+memory"
+        );
+        for group in CgroupThreadGroup::ALL {
+            assert_eq!(
+                read_file(&path.join(group.name()).join(CGROUP_TYPE)),
+                "threaded"
+            );
+        }
+        // All the threads land in the VMM group, the last one moved being
+        // left in the file.
+        assert!(read_file(&path.join("vmm").join(CGROUP_THREADS))
+            .parse::<libc::pid_t>()
+            .is_ok());
+        assert!(!path.join("vcpus").join(CGROUP_THREADS).exists());
+        assert!(!path.join(MEMORY_HIGH).exists());
+    }
+
+    #[test]
+    fn test_cgroup_attach_thread() {
+        let dir = TempDir::new().unwrap();
+        let cgroup_manager = create_cgroup_manager(&dir, CgroupResources::default());
+        let path = dir.as_path().join("vm0");
+
+        cgroup_manager
+            .attach_thread_id(CgroupThreadGroup::Vcpus, 1234)
+            .unwrap();
+        assert_eq!(read_file(&path.join("vcpus").join(CGROUP_THREADS)), "1234");
+
+        cgroup_manager
+            .attach_current_thread(CgroupThreadGroup::Iothreads)
+            .unwrap();
+        assert_eq!(
+            read_file(&path.join("iothreads").join(CGROUP_THREADS)),
+            gettid().to_string()
+        );
+    }
+
+    #[test]
+    fn test_cgroup_update() {
+        let dir = TempDir::new().unwrap();
+        let cgroup_manager = create_cgroup_manager(
+            &dir,
+            CgroupResources {
+                memory_high: Some(1 << 30),
+                vcpu_cpu_weight: Some(200),
+                ..Default::default()
+            },
+        );
+        let path = dir.as_path().join("vm0");
+        assert_eq!(read_file(&path.join(MEMORY_HIGH)), "1073741824");
+        assert_eq!(read_file(&path.join("vcpus").join(CPU_WEIGHT)), "200");
+        assert!(!path.join("vmm").join(CPU_WEIGHT).exists());
+
+        // Only the settings which are set get updated.
+        cgroup_manager
+            .update(&CgroupResources {
+                io_max: Some(vec![
+                    "8:0 rbps=1048576".to_string(),
+                    "8:16 wiops=100".to_string(),
+                ]),
+                vmm_cpu_weight: Some(50),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(read_file(&path.join(MEMORY_HIGH)), "1073741824");
+        assert_eq!(read_file(&path.join(IO_MAX)), "8:16 wiops=100");
+        assert_eq!(read_file(&path.join("vmm").join(CPU_WEIGHT)), "50");
+        assert_eq!(read_file(&path.join("vcpus").join(CPU_WEIGHT)), "200");
+    }
+
+    #[test]
+    fn test_cgroup_new_error() {
+        let dir = TempDir::new().unwrap();
+        // The VM cgroup can't be created below a regular file.
+        fs::write(dir.as_path().join("file"), "").unwrap();
+        let config = CgroupConfig {
+            path: dir.as_path().join("file").join("vm0"),
+            resources: CgroupResources::default(),
+        };
+        assert!(matches!(
+            CgroupManager::new(&config),
+            Err(CgroupError::Create(p, _)) if p == config.path
+        ));
+    }
+}
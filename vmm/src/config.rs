@@ -3,16 +3,20 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::cgroup::{CGROUP_CPU_WEIGHT_MAX, CGROUP_CPU_WEIGHT_MIN};
 use crate::landlock::LandlockAccess;
+use crate::memory_manager::MemoryRestoreMode;
 pub use crate::vm_config::*;
+use clap::parser::ValueSource;
 use clap::ArgMatches;
 use option_parser::{
     ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, Tuple,
 };
+use pci::PciBdf;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet, HashMap};
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
 use thiserror::Error;
@@ -29,12 +33,22 @@ pub enum Error {
     ParseFsTagTooLong,
     /// Filesystem socket is missing
     ParseFsSockMissing,
+    /// 9P filesystem tag is missing
+    ParseFs9pTagMissing,
+    /// 9P filesystem exported path is missing
+    ParseFs9pPathMissing,
+    /// Missing I2C bus path parameter.
+    ParseI2cPathMissing,
+    /// Missing allowed I2C device addresses parameter.
+    ParseI2cAddressesMissing,
     /// Missing persistent memory file parameter.
     ParsePmemFileMissing,
     /// Missing vsock socket path parameter.
     ParseVsockSockMissing,
     /// Missing vsock cid parameter.
     ParseVsockCidMissing,
+    /// Missing guest agent vsock port parameter.
+    ParseGuestAgentPortMissing,
     /// Missing restore source_url parameter.
     ParseRestoreSourceUrlMissing,
     /// Error parsing CPU options
@@ -49,6 +63,8 @@ pub enum Error {
     ParseMemoryZoneIdMissing,
     /// Error parsing rate-limiter group options
     ParseRateLimiterGroup(OptionParserError),
+    /// Error parsing I/O affinity options
+    ParseIoAffinity(OptionParserError),
     /// Error parsing disk options
     ParseDisk(OptionParserError),
     /// Error parsing network options
@@ -59,6 +75,10 @@ pub enum Error {
     ParseBalloon(OptionParserError),
     /// Error parsing filesystem parameters
     ParseFileSystem(OptionParserError),
+    /// Error parsing 9P filesystem parameters
+    ParseFs9p(OptionParserError),
+    /// Error parsing I2C adapter parameters
+    ParseI2c(OptionParserError),
     /// Error parsing persistent memory parameters
     ParsePersistentMemory(OptionParserError),
     /// Failed parsing console
@@ -74,6 +94,8 @@ pub enum Error {
     ParseDevicePathMissing,
     /// Failed parsing vsock parameters
     ParseVsock(OptionParserError),
+    /// Failed parsing guest agent parameters
+    ParseGuestAgent(OptionParserError),
     /// Failed parsing restore parameters
     ParseRestore(OptionParserError),
     /// Failed parsing SGX EPC parameters
@@ -107,6 +129,24 @@ pub enum Error {
     ParseVdpa(OptionParserError),
     /// Missing path for vDPA device
     ParseVdpaPathMissing,
+    /// Failed parsing ivshmem device
+    ParseIvshmem(OptionParserError),
+    /// Missing path for ivshmem device
+    ParseIvshmemPathMissing,
+    /// Failed parsing NVMe controller
+    ParseNvme(OptionParserError),
+    /// Missing identifier for NVMe controller
+    ParseNvmeIdMissing,
+    /// Failed parsing CXL memory device
+    ParseCxl(OptionParserError),
+    /// Missing memory zone for CXL memory device
+    ParseCxlMemoryZoneMissing,
+    /// Failed parsing xHCI controller
+    ParseXhci(OptionParserError),
+    /// Failed parsing USB device
+    ParseUsbDevice(OptionParserError),
+    /// Failed parsing VNC server
+    ParseVnc(OptionParserError),
     /// Failed parsing TPM device
     ParseTpm(OptionParserError),
     /// Missing path for TPM device
@@ -115,6 +155,28 @@ pub enum Error {
     ParseLandlockRules(OptionParserError),
     /// Missing fields in Landlock rules
     ParseLandlockMissingFields,
+    /// Failed parsing cgroup parameters
+    ParseCgroup(OptionParserError),
+    /// Missing path for cgroup
+    ParseCgroupPathMissing,
+    /// Failed parsing security label parameters
+    ParseSecurityLabel(OptionParserError),
+    /// Failed parsing RTC parameters
+    ParseRtc(OptionParserError),
+    /// Failed parsing guest panic parameters
+    ParsePanic(OptionParserError),
+    /// Failed parsing fw_cfg parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseFwCfg(OptionParserError),
+    /// Missing name for fw_cfg file
+    #[cfg(target_arch = "x86_64")]
+    ParseFwCfgNameMissing,
+    /// Failed reading the VM configuration file
+    ReadConfigFile(PathBuf, std::io::Error),
+    /// Failed parsing the VM configuration file
+    ParseConfigFile(PathBuf, String),
+    /// Unknown VM configuration file format
+    UnknownConfigFileFormat(PathBuf),
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
@@ -125,6 +187,8 @@ pub enum ValidationError {
     ConsoleFileMissing,
     /// Missing socket path for console
     ConsoleSocketPathMissing,
+    /// Output log and capture only supported by an enabled serial port
+    ConsoleLogUnsupported,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
     /// Missing file value for debug-console
@@ -159,6 +223,14 @@ pub enum ValidationError {
     HugePageSizeWithoutHugePages,
     /// Huge page size is not power of 2
     InvalidHugePageSize(u64),
+    /// Memory boot size requires the virtio-mem hotplug method
+    MemoryBootSizeWithoutVirtioMem,
+    /// Memory boot size can't be combined with hotplugged size
+    MemoryBootSizeWithHotpluggedSize,
+    /// Memory boot size is zero, too large or used with memory zones
+    InvalidMemoryBootSize(u64),
+    /// guest_memfd is only supported on x86_64 for non confidential guests
+    GuestMemfdUnsupported,
     /// CPU Hotplug is not permitted with TDX
     #[cfg(feature = "tdx")]
     TdxNoCpuHotplug,
@@ -171,22 +243,40 @@ pub enum ValidationError {
     UserDevicesRequireSharedMemory,
     /// VSOCK Context Identifier has a special meaning, unsuitable for a VM.
     VsockSpecialCid(u32),
+    /// Guest agent requires a vsock device
+    GuestAgentRequiresVsock,
     /// Memory zone is reused across NUMA nodes
     MemoryZoneReused(String, u32, u32),
     /// Invalid number of PCI segments
     InvalidNumPciSegments(u16),
     /// Invalid PCI segment id
     InvalidPciSegment(u16),
+    /// More PCI slots reserved for IOMMU hotplug than a segment has
+    InvalidIommuHotplugSlots(u8),
+    /// More OEM strings than SMBIOS can describe
+    TooManyOemStrings(usize),
+    /// Device or option not supported along with the virtio-mmio transport
+    VirtioMmioUnsupported(String),
+    /// The VMBus relay is only supported on x86_64
+    VmbusRelayUnsupported,
     /// Invalid PCI segment aperture weight
     InvalidPciSegmentApertureWeight(u32),
     /// Balloon too big
     BalloonLargerThanRam(u64, u64),
+    /// Balloon policy minimum guest RAM above its maximum
+    BalloonPolicyMinAboveMax(u64, u64),
+    /// Balloon policy evaluated with a zero interval
+    InvalidBalloonPolicyInterval,
     /// On a IOMMU segment but not behind IOMMU
     OnIommuSegment(u16),
     // On a IOMMU segment but IOMMU not supported
     IommuNotSupportedOnSegment(u16),
     // Identifier is not unique
     IdentifierNotUnique(String),
+    /// Invalid PCI address requested for a device
+    InvalidPciBdf(PciBdf),
+    /// PCI address requested by more than one device
+    PciBdfReused(PciBdf),
     /// Invalid identifier
     InvalidIdentifier(String),
     /// Placing the device behind a virtual IOMMU is not supported
@@ -201,6 +291,34 @@ pub enum ValidationError {
     DefaultPciSegmentInvalidNode(u32),
     /// Invalid rate-limiter group
     InvalidRateLimiterGroup,
+    /// Invalid I/O affinity
+    InvalidIoAffinity(String),
+    /// Device assigned to an unknown I/O affinity
+    UnknownIoAffinity(String),
+    /// I/O affinity not supported by the device configuration
+    IoAffinityUnsupported,
+    /// Busy polling not supported by the device configuration
+    BusyPollUnsupported,
+    /// io_uring event loop not supported by the device configuration
+    IoUringEventLoopUnsupported,
+    /// Interrupt coalescing not supported by the device configuration
+    CoalescingUnsupported,
+    /// Completion polling not supported by the device configuration
+    CompletionPollUnsupported,
+    /// Invalid disk thread pool
+    InvalidDiskThreadPool,
+    /// Disk thread pool not supported by the device configuration
+    DiskThreadPoolUnsupported,
+    /// Transitional virtio device not supported by the device configuration
+    TransitionalUnsupported,
+    /// Disk overlay not supported by the device configuration
+    DiskOverlayUnsupported,
+    /// Disk encryption key not supported by the device configuration
+    DiskKeyUnsupported,
+    /// NVDIMM not supported by the device configuration
+    NvdimmUnsupported,
+    /// Invalid persistent memory label storage area size
+    InvalidPmemLabelSize(u64),
     /// The specified I/O port was invalid. It should be provided in hex, such as `0xe9`.
     #[cfg(target_arch = "x86_64")]
     InvalidIoPortHex(String),
@@ -210,10 +328,94 @@ pub enum ValidationError {
     RestoreMissingRequiredNetId(String),
     /// Number of FDs passed during Restore are incorrect to the NetConfig
     RestoreNetFdCountMismatch(String, usize, usize),
+    /// Lazy restore cannot be combined with clone or prefault
+    RestoreLazyIncompatible,
     /// Path provided in landlock-rules doesn't exist
     LandlockPathDoesNotExist(PathBuf),
     /// Access provided in landlock-rules in invalid
     InvalidLandlockAccess(String),
+    /// cgroup cpu.weight out of range
+    InvalidCgroupCpuWeight(u64),
+    /// cgroup path not absolute
+    InvalidCgroupPath(PathBuf),
+    /// Both out_of_process and vhost_user specified
+    OutOfProcessAndVhostUser,
+    /// Missing disk path for out-of-process backend
+    OutOfProcessDiskPathMissing,
+    /// Option not supported by out-of-process backends
+    OutOfProcessUnsupported(String),
+    /// Option not supported along with a macvtap interface
+    MacvtapUnsupported(String),
+    /// Option not supported by the passt networking backend
+    PasstUnsupported(String),
+    /// Option not supported by the AF_XDP networking backend
+    XdpUnsupported(String),
+    /// Option not supported by the vhost-net networking backend
+    VhostNetUnsupported(String),
+    /// Port forwarding rules provided without the passt backend
+    PortForwardRequiresPasst,
+    /// Option not supported along with io_uring registered buffers
+    IoUringRegisteredBuffersUnsupported(String),
+    /// Backend command provided without vhost_user
+    ExecRequiresVhostUser,
+    /// Both backend command and shared directory provided for virtio-fs
+    FsExecAndSharedDir,
+    /// virtio-9p maximum message size too small
+    InvalidFs9pMsize(u32),
+    /// No I2C device address allowed
+    I2cAddressesMissing,
+    /// Invalid 7-bit I2C device address
+    InvalidI2cAddress(u16),
+    /// Both SELinux and AppArmor labels provided
+    SecurityLabelSelinuxAndApparmor,
+    /// NUMA placement requested without any guest NUMA node
+    NumaPlacementWithoutNuma,
+    /// ivshmem size not a power of two of at least 4 KiB
+    InvalidIvshmemSize(u64),
+    /// NVMe controller number of queues out of range
+    InvalidNvmeNumQueues(u16),
+    /// NVMe serial number not made of up to 20 ASCII characters
+    InvalidNvmeSerial(String),
+    /// Disk attached to a missing NVMe controller
+    NvmeControllerMissing(String),
+    /// NVMe namespace not supported by the disk configuration
+    DiskNvmeUnsupported,
+    /// CXL memory device backed by a missing memory zone
+    CxlMemoryZoneMissing(String),
+    /// Memory zone backing more than one CXL memory device
+    CxlMemoryZoneReused(String),
+    /// Memory zone not suitable for backing a CXL memory device
+    InvalidCxlMemoryZone(String),
+    /// CXL host bridge on the default or an already used PCI segment
+    InvalidCxlPciSegment(u16),
+    /// CXL memory devices not supported on this architecture
+    #[cfg(not(target_arch = "x86_64"))]
+    CxlUnsupported,
+    /// xHCI controller number of ports out of range
+    InvalidXhciNumPorts(u8),
+    /// USB device passthrough requires an xHCI controller
+    UsbDeviceRequiresXhci,
+    /// USB device not identified by exactly one of bus/address or vendor/product
+    InvalidUsbDeviceAddress,
+    /// VNC display size out of range
+    InvalidVncDisplaySize(u32, u32),
+    /// RTC running along with the VM not supported on this architecture
+    #[cfg(not(target_arch = "x86_64"))]
+    RtcClockVmUnsupported,
+    /// fw_cfg file name empty or too long
+    #[cfg(target_arch = "x86_64")]
+    InvalidFwCfgName(String),
+    /// fw_cfg file not provided by exactly one of file or string
+    #[cfg(target_arch = "x86_64")]
+    InvalidFwCfgContent(String),
+    /// fw_cfg file name used more than once
+    #[cfg(target_arch = "x86_64")]
+    DuplicateFwCfgName(String),
+    /// Coredump on guest panic requested without a coredump file
+    PanicCoredumpFileMissing,
+    /// Coredump on guest panic not supported by this build
+    #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+    PanicCoredumpUnsupported,
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -225,6 +427,10 @@ impl fmt::Display for ValidationError {
             KernelMissing => write!(f, "No kernel specified"),
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
             ConsoleSocketPathMissing => write!(f, "Path missing when using socket console mode"),
+            ConsoleLogUnsupported => write!(
+                f,
+                "log_file and capture_size are only supported by an enabled serial port"
+            ),
             CpusMaxLowerThanBoot => write!(f, "Max CPUs lower than boot CPUs"),
             #[cfg(target_arch = "x86_64")]
             DebugconFileMissing => write!(f, "Path missing when using file mode for debug console"),
@@ -261,6 +467,21 @@ impl fmt::Display for ValidationError {
             InvalidHugePageSize(s) => {
                 write!(f, "Huge page size is not power of 2: {s}")
             }
+            MemoryBootSizeWithoutVirtioMem => {
+                write!(f, "Memory boot size requires hotplug_method=virtio-mem")
+            }
+            MemoryBootSizeWithHotpluggedSize => {
+                write!(f, "Memory boot size can't be used with hotplugged_size")
+            }
+            InvalidMemoryBootSize(s) => write!(
+                f,
+                "Memory boot size {s} must be non-zero, not exceed the memory size \
+                and leave some memory to virtio-mem, memory zones are not supported"
+            ),
+            GuestMemfdUnsupported => write!(
+                f,
+                "guest_memfd private memory is only supported on x86_64, without TDX or SEV-SNP"
+            ),
             #[cfg(feature = "tdx")]
             TdxNoCpuHotplug => {
                 write!(f, "CPU hotplug is not permitted with TDX")
@@ -281,12 +502,21 @@ impl fmt::Display for ValidationError {
             VsockSpecialCid(cid) => {
                 write!(f, "{cid} is a special VSOCK CID")
             }
+            GuestAgentRequiresVsock => {
+                write!(f, "Guest agent requires a vsock device")
+            }
             MemoryZoneReused(s, u1, u2) => {
                 write!(
                     f,
                     "Memory zone: {s} belongs to multiple NUMA nodes {u1} and {u2}"
                 )
             }
+            VirtioMmioUnsupported(s) => {
+                write!(f, "{s} is not supported with the virtio-mmio transport")
+            }
+            VmbusRelayUnsupported => {
+                write!(f, "The VMBus relay is only supported on x86_64")
+            }
             InvalidNumPciSegments(n) => {
                 write!(
                     f,
@@ -296,6 +526,15 @@ impl fmt::Display for ValidationError {
             InvalidPciSegment(pci_segment) => {
                 write!(f, "Invalid PCI segment id: {pci_segment}")
             }
+            InvalidIommuHotplugSlots(num_slots) => {
+                write!(
+                    f,
+                    "Invalid number of PCI slots for IOMMU hotplug: {num_slots}"
+                )
+            }
+            TooManyOemStrings(n) => {
+                write!(f, "Number of OEM strings ({n}) greater than 255")
+            }
             InvalidPciSegmentApertureWeight(aperture_weight) => {
                 write!(f, "Invalid PCI segment aperture weight: {aperture_weight}")
             }
@@ -305,6 +544,15 @@ impl fmt::Display for ValidationError {
                     "Ballon size ({balloon_size}) greater than RAM ({ram_size})"
                 )
             }
+            BalloonPolicyMinAboveMax(min, max) => {
+                write!(
+                    f,
+                    "Balloon policy minimum guest RAM ({min}) greater than maximum ({max})"
+                )
+            }
+            InvalidBalloonPolicyInterval => {
+                write!(f, "Balloon policy interval must be greater than 0")
+            }
             OnIommuSegment(pci_segment) => {
                 write!(
                     f,
@@ -320,6 +568,15 @@ impl fmt::Display for ValidationError {
             IdentifierNotUnique(s) => {
                 write!(f, "Identifier {s} is not unique")
             }
+            InvalidPciBdf(bdf) => {
+                write!(
+                    f,
+                    "Invalid PCI address {bdf}: devices can only be placed on bus 0, function 0 of a non-zero slot in their PCI segment"
+                )
+            }
+            PciBdfReused(bdf) => {
+                write!(f, "PCI address {bdf} is used by more than one device")
+            }
             InvalidIdentifier(s) => {
                 write!(f, "Identifier {s} is invalid")
             }
@@ -345,6 +602,84 @@ impl fmt::Display for ValidationError {
             InvalidRateLimiterGroup => {
                 write!(f, "Invalid rate-limiter group")
             }
+            InvalidIoAffinity(s) => {
+                write!(
+                    f,
+                    "Invalid I/O affinity {s}: it needs at least one set and as many host CPUs as sets"
+                )
+            }
+            UnknownIoAffinity(s) => {
+                write!(f, "Unknown I/O affinity {s}")
+            }
+            IoAffinityUnsupported => {
+                write!(
+                    f,
+                    "io_affinity cannot be used with vhost_user, out_of_process or queue_affinity"
+                )
+            }
+            BusyPollUnsupported => {
+                write!(
+                    f,
+                    "busy_poll_us cannot be used with vhost_user or out_of_process"
+                )
+            }
+            IoUringEventLoopUnsupported => {
+                write!(
+                    f,
+                    "io_uring_event_loop cannot be used with vhost_user or out_of_process"
+                )
+            }
+            CoalescingUnsupported => {
+                write!(
+                    f,
+                    "coalesce_us cannot be used with vhost_user or out_of_process"
+                )
+            }
+            CompletionPollUnsupported => {
+                write!(
+                    f,
+                    "completion_poll_us cannot be used with vhost_user or out_of_process"
+                )
+            }
+            InvalidDiskThreadPool => {
+                write!(
+                    f,
+                    "Invalid disk thread pool: it needs at least one thread and a non-zero queue depth"
+                )
+            }
+            DiskThreadPoolUnsupported => {
+                write!(
+                    f,
+                    "thread_pool_size cannot be used with vhost_user or out_of_process"
+                )
+            }
+            TransitionalUnsupported => {
+                write!(
+                    f,
+                    "transitional is only supported on x86_64 and cannot be used with vhost_user, out_of_process or iommu"
+                )
+            }
+            DiskOverlayUnsupported => {
+                write!(
+                    f,
+                    "overlay requires a disk path and cannot be used with readonly, vhost_user or out_of_process"
+                )
+            }
+            DiskKeyUnsupported => {
+                write!(
+                    f,
+                    "key and key_file are mutually exclusive and cannot be used with overlay, vhost_user or out_of_process"
+                )
+            }
+            NvdimmUnsupported => {
+                write!(f, "nvdimm cannot be used with iommu")
+            }
+            InvalidPmemLabelSize(s) => {
+                write!(
+                    f,
+                    "Invalid label_size {s}: requires nvdimm=on and must be a multiple of 4 KiB of at least 128 KiB"
+                )
+            }
             #[cfg(target_arch = "x86_64")]
             InvalidIoPortHex(s) => {
                 write!(
@@ -365,6 +700,9 @@ impl fmt::Display for ValidationError {
                     "Number of Net FDs passed for '{s}' during Restore: {u1}. Expected: {u2}"
                 )
             }
+            RestoreLazyIncompatible => {
+                write!(f, "Lazy restore cannot be combined with clone or prefault")
+            }
             LandlockPathDoesNotExist(s) => {
                 write!(
                     f,
@@ -375,6 +713,178 @@ impl fmt::Display for ValidationError {
             InvalidLandlockAccess(s) => {
                 write!(f, "{s}")
             }
+            OutOfProcessAndVhostUser => {
+                write!(f, "Both out_of_process and vhost_user are specified")
+            }
+            OutOfProcessDiskPathMissing => {
+                write!(f, "Disk path is required when using out_of_process")
+            }
+            OutOfProcessUnsupported(o) => {
+                write!(f, "Option {o} is not supported with out_of_process")
+            }
+            MacvtapUnsupported(o) => {
+                write!(f, "Option {o} is not supported with macvtap")
+            }
+            PasstUnsupported(o) => {
+                write!(f, "Option {o} is not supported with passt")
+            }
+            XdpUnsupported(o) => {
+                write!(f, "Option {o} is not supported with xdp")
+            }
+            VhostNetUnsupported(o) => {
+                write!(f, "Option {o} is not supported with vhost_net")
+            }
+            PortForwardRequiresPasst => {
+                write!(f, "Port forwarding requires the passt backend")
+            }
+            IoUringRegisteredBuffersUnsupported(o) => {
+                write!(
+                    f,
+                    "Option {o} is not supported with io_uring registered buffers"
+                )
+            }
+            ExecRequiresVhostUser => {
+                write!(f, "Backend command provided but vhost_user is not enabled")
+            }
+            FsExecAndSharedDir => {
+                write!(f, "Both backend command and shared directory provided")
+            }
+            InvalidFs9pMsize(s) => {
+                write!(
+                    f,
+                    "virtio-9p maximum message size {s} is below the minimum of {}",
+                    Fs9pConfig::MIN_MSIZE
+                )
+            }
+            I2cAddressesMissing => {
+                write!(f, "At least one I2C device address must be allowed")
+            }
+            InvalidI2cAddress(a) => {
+                write!(
+                    f,
+                    "Invalid I2C device address {a:#x}: must be a 7-bit address"
+                )
+            }
+            SecurityLabelSelinuxAndApparmor => {
+                write!(f, "Both SELinux and AppArmor labels provided")
+            }
+            NumaPlacementWithoutNuma => {
+                write!(f, "NUMA placement requires guest NUMA nodes to be defined")
+            }
+            InvalidIvshmemSize(s) => {
+                write!(
+                    f,
+                    "ivshmem size {s} must be a power of two of at least 4 KiB"
+                )
+            }
+            InvalidNvmeNumQueues(n) => {
+                write!(
+                    f,
+                    "NVMe number of queues {n} not in range of 1 to {}",
+                    devices::nvme::NVME_MAX_IO_QUEUES
+                )
+            }
+            InvalidNvmeSerial(s) => {
+                write!(
+                    f,
+                    "NVMe serial number {s} must be made of up to 20 ASCII characters"
+                )
+            }
+            NvmeControllerMissing(id) => {
+                write!(f, "Disk attached to missing NVMe controller {id}")
+            }
+            DiskNvmeUnsupported => {
+                write!(
+                    f,
+                    "nvme cannot be used with vhost_user, out_of_process, iommu or transitional"
+                )
+            }
+            CxlMemoryZoneMissing(id) => {
+                write!(f, "CXL memory device backed by missing memory zone {id}")
+            }
+            CxlMemoryZoneReused(id) => {
+                write!(f, "Memory zone {id} backs more than one CXL memory device")
+            }
+            InvalidCxlMemoryZone(id) => {
+                write!(
+                    f,
+                    "Memory zone {id} backing a CXL memory device must have a size multiple of {} MiB and no hotplug_size",
+                    devices::cxl::CXL_HDM_ALIGNMENT >> 20
+                )
+            }
+            InvalidCxlPciSegment(s) => {
+                write!(
+                    f,
+                    "CXL memory device requires its own PCI segment other than 0, got {s}"
+                )
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            CxlUnsupported => {
+                write!(f, "CXL memory devices are only supported on x86_64")
+            }
+            InvalidXhciNumPorts(n) => {
+                write!(
+                    f,
+                    "xHCI number of ports {n} not in range of 1 to {}",
+                    devices::usb::XHCI_MAX_PORTS
+                )
+            }
+            UsbDeviceRequiresXhci => {
+                write!(f, "USB device passthrough requires an xHCI controller")
+            }
+            InvalidUsbDeviceAddress => {
+                write!(
+                    f,
+                    "USB device must be identified by either hostbus/hostaddr or vendor_id/product_id"
+                )
+            }
+            InvalidVncDisplaySize(width, height) => {
+                write!(
+                    f,
+                    "VNC display size {width}x{height} not in range of 1x1 to {0}x{0}",
+                    virtio_devices::GPU_MAX_DISPLAY_SIZE
+                )
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            RtcClockVmUnsupported => {
+                write!(f, "RTC clock=vm is only supported on x86_64")
+            }
+            #[cfg(target_arch = "x86_64")]
+            InvalidFwCfgName(n) => {
+                write!(
+                    f,
+                    "fw_cfg name {n:?} must be between 1 and {} bytes long",
+                    devices::legacy::FW_CFG_MAX_FILE_NAME - 1
+                )
+            }
+            #[cfg(target_arch = "x86_64")]
+            InvalidFwCfgContent(n) => {
+                write!(
+                    f,
+                    "fw_cfg {n:?} must be provided by exactly one of file or string"
+                )
+            }
+            #[cfg(target_arch = "x86_64")]
+            DuplicateFwCfgName(n) => write!(f, "fw_cfg name {n:?} used more than once"),
+            PanicCoredumpFileMissing => {
+                write!(f, "Guest panic action coredump requires a coredump_file")
+            }
+            #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+            PanicCoredumpUnsupported => {
+                write!(
+                    f,
+                    "Guest panic action coredump requires x86_64 and the guest_debug feature"
+                )
+            }
+            InvalidCgroupCpuWeight(w) => {
+                write!(
+                    f,
+                    "cgroup CPU weight {w} not in range of {CGROUP_CPU_WEIGHT_MIN} to {CGROUP_CPU_WEIGHT_MAX}"
+                )
+            }
+            InvalidCgroupPath(p) => {
+                write!(f, "cgroup path {p:?} is not absolute")
+            }
         }
     }
 }
@@ -396,6 +906,12 @@ impl fmt::Display for Error {
             ParseFileSystem(o) => write!(f, "Error parsing --fs: {o}"),
             ParseFsSockMissing => write!(f, "Error parsing --fs: socket missing"),
             ParseFsTagMissing => write!(f, "Error parsing --fs: tag missing"),
+            ParseFs9p(o) => write!(f, "Error parsing --fs9p: {o}"),
+            ParseFs9pPathMissing => write!(f, "Error parsing --fs9p: path missing"),
+            ParseFs9pTagMissing => write!(f, "Error parsing --fs9p: tag missing"),
+            ParseI2c(o) => write!(f, "Error parsing --i2c: {o}"),
+            ParseI2cPathMissing => write!(f, "Error parsing --i2c: path missing"),
+            ParseI2cAddressesMissing => write!(f, "Error parsing --i2c: addresses missing"),
             ParseFsTagTooLong => write!(
                 f,
                 "Error parsing --fs: max tag length is {}",
@@ -406,11 +922,14 @@ impl fmt::Display for Error {
             ParseVsock(o) => write!(f, "Error parsing --vsock: {o}"),
             ParseVsockCidMissing => write!(f, "Error parsing --vsock: cid missing"),
             ParseVsockSockMissing => write!(f, "Error parsing --vsock: socket missing"),
+            ParseGuestAgent(o) => write!(f, "Error parsing --guest-agent: {o}"),
+            ParseGuestAgentPortMissing => write!(f, "Error parsing --guest-agent: port missing"),
             ParseMemory(o) => write!(f, "Error parsing --memory: {o}"),
             ParseMemoryZone(o) => write!(f, "Error parsing --memory-zone: {o}"),
             ParseMemoryZoneIdMissing => write!(f, "Error parsing --memory-zone: id missing"),
             ParseNetwork(o) => write!(f, "Error parsing --net: {o}"),
             ParseRateLimiterGroup(o) => write!(f, "Error parsing --rate-limit-group: {o}"),
+            ParseIoAffinity(o) => write!(f, "Error parsing --io-affinity: {o}"),
             ParseDisk(o) => write!(f, "Error parsing --disk: {o}"),
             ParseRng(o) => write!(f, "Error parsing --rng: {o}"),
             ParseBalloon(o) => write!(f, "Error parsing --balloon: {o}"),
@@ -438,6 +957,15 @@ impl fmt::Display for Error {
             ParsePlatform(o) => write!(f, "Error parsing --platform: {o}"),
             ParseVdpa(o) => write!(f, "Error parsing --vdpa: {o}"),
             ParseVdpaPathMissing => write!(f, "Error parsing --vdpa: path missing"),
+            ParseIvshmem(o) => write!(f, "Error parsing --ivshmem: {o}"),
+            ParseIvshmemPathMissing => write!(f, "Error parsing --ivshmem: path missing"),
+            ParseNvme(o) => write!(f, "Error parsing --nvme: {o}"),
+            ParseNvmeIdMissing => write!(f, "Error parsing --nvme: id missing"),
+            ParseCxl(o) => write!(f, "Error parsing --cxl: {o}"),
+            ParseCxlMemoryZoneMissing => write!(f, "Error parsing --cxl: memory_zone missing"),
+            ParseXhci(o) => write!(f, "Error parsing --xhci: {o}"),
+            ParseUsbDevice(o) => write!(f, "Error parsing --usb: {o}"),
+            ParseVnc(o) => write!(f, "Error parsing --vnc: {o}"),
             ParseTpm(o) => write!(f, "Error parsing --tpm: {o}"),
             ParseTpmPathMissing => write!(f, "Error parsing --tpm: path missing"),
             ParseLandlockRules(o) => write!(f, "Error parsing --landlock-rules: {o}"),
@@ -445,6 +973,21 @@ impl fmt::Display for Error {
                 f,
                 "Error parsing --landlock-rules: path/access field missing"
             ),
+            ParseCgroup(o) => write!(f, "Error parsing --cgroup: {o}"),
+            ParseCgroupPathMissing => write!(f, "Error parsing --cgroup: path missing"),
+            ParseSecurityLabel(o) => write!(f, "Error parsing --security-label: {o}"),
+            ParseRtc(o) => write!(f, "Error parsing --rtc: {o}"),
+            ParsePanic(o) => write!(f, "Error parsing --panic: {o}"),
+            #[cfg(target_arch = "x86_64")]
+            ParseFwCfg(o) => write!(f, "Error parsing --fw-cfg: {o}"),
+            #[cfg(target_arch = "x86_64")]
+            ParseFwCfgNameMissing => write!(f, "Error parsing --fw-cfg: name missing"),
+            ReadConfigFile(p, e) => write!(f, "Error reading --config file {p:?}: {e}"),
+            ParseConfigFile(p, e) => write!(f, "Error parsing --config file {p:?}: {e}"),
+            UnknownConfigFileFormat(p) => write!(
+                f,
+                "Error parsing --config file {p:?}: unknown format, expected .toml or .json"
+            ),
         }
     }
 }
@@ -468,11 +1011,14 @@ pub struct VmParams<'a> {
     pub initramfs: Option<&'a str>,
     pub cmdline: Option<&'a str>,
     pub rate_limit_groups: Option<Vec<&'a str>>,
+    pub io_affinities: Option<Vec<&'a str>>,
     pub disks: Option<Vec<&'a str>>,
     pub net: Option<Vec<&'a str>>,
     pub rng: &'a str,
     pub balloon: Option<&'a str>,
     pub fs: Option<Vec<&'a str>>,
+    pub fs9p: Option<Vec<&'a str>>,
+    pub i2c: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
@@ -481,13 +1027,23 @@ pub struct VmParams<'a> {
     pub devices: Option<Vec<&'a str>>,
     pub user_devices: Option<Vec<&'a str>>,
     pub vdpa: Option<Vec<&'a str>>,
+    pub ivshmem: Option<Vec<&'a str>>,
+    pub nvme: Option<Vec<&'a str>>,
+    pub cxl: Option<Vec<&'a str>>,
+    pub xhci: Option<&'a str>,
+    pub usb_devices: Option<Vec<&'a str>>,
+    pub vnc: Option<&'a str>,
     pub vsock: Option<&'a str>,
+    pub guest_agent: Option<&'a str>,
     #[cfg(feature = "pvmemcontrol")]
     pub pvmemcontrol: bool,
     pub pvpanic: bool,
+    pub vmclock: bool,
+    pub panic: Option<&'a str>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
     pub numa: Option<Vec<&'a str>>,
+    pub numa_placement: bool,
     pub watchdog: bool,
     #[cfg(feature = "guest_debug")]
     pub gdb: bool,
@@ -500,6 +1056,15 @@ pub struct VmParams<'a> {
     pub host_data: Option<&'a str>,
     pub landlock_enable: bool,
     pub landlock_rules: Option<Vec<&'a str>>,
+    pub cgroup: Option<&'a str>,
+    pub security_label: Option<&'a str>,
+    pub rtc: Option<&'a str>,
+    #[cfg(target_arch = "x86_64")]
+    pub fw_cfg: Option<Vec<&'a str>>,
+    pub config_file: Option<&'a str>,
+    // Options left to their default value, which must not override the
+    // ones from the configuration file.
+    pub defaulted: Vec<&'static str>,
 }
 
 impl<'a> VmParams<'a> {
@@ -519,6 +1084,9 @@ impl<'a> VmParams<'a> {
         let rate_limit_groups: Option<Vec<&str>> = args
             .get_many::<String>("rate-limit-group")
             .map(|x| x.map(|y| y as &str).collect());
+        let io_affinities: Option<Vec<&str>> = args
+            .get_many::<String>("io-affinity")
+            .map(|x| x.map(|y| y as &str).collect());
         let disks: Option<Vec<&str>> = args
             .get_many::<String>("disk")
             .map(|x| x.map(|y| y as &str).collect());
@@ -532,6 +1100,12 @@ impl<'a> VmParams<'a> {
         let fs: Option<Vec<&str>> = args
             .get_many::<String>("fs")
             .map(|x| x.map(|y| y as &str).collect());
+        let fs9p: Option<Vec<&str>> = args
+            .get_many::<String>("fs9p")
+            .map(|x| x.map(|y| y as &str).collect());
+        let i2c: Option<Vec<&str>> = args
+            .get_many::<String>("i2c")
+            .map(|x| x.map(|y| y as &str).collect());
         let pmem: Option<Vec<&str>> = args
             .get_many::<String>("pmem")
             .map(|x| x.map(|y| y as &str).collect());
@@ -544,10 +1118,27 @@ impl<'a> VmParams<'a> {
         let vdpa: Option<Vec<&str>> = args
             .get_many::<String>("vdpa")
             .map(|x| x.map(|y| y as &str).collect());
+        let ivshmem: Option<Vec<&str>> = args
+            .get_many::<String>("ivshmem")
+            .map(|x| x.map(|y| y as &str).collect());
+        let nvme: Option<Vec<&str>> = args
+            .get_many::<String>("nvme")
+            .map(|x| x.map(|y| y as &str).collect());
+        let cxl: Option<Vec<&str>> = args
+            .get_many::<String>("cxl")
+            .map(|x| x.map(|y| y as &str).collect());
+        let xhci: Option<&str> = args.get_one::<String>("xhci").map(|x| x as &str);
+        let usb_devices: Option<Vec<&str>> = args
+            .get_many::<String>("usb")
+            .map(|x| x.map(|y| y as &str).collect());
+        let vnc: Option<&str> = args.get_one::<String>("vnc").map(|x| x as &str);
         let vsock: Option<&str> = args.get_one::<String>("vsock").map(|x| x as &str);
+        let guest_agent: Option<&str> = args.get_one::<String>("guest-agent").map(|x| x as &str);
         #[cfg(feature = "pvmemcontrol")]
         let pvmemcontrol = args.get_flag("pvmemcontrol");
         let pvpanic = args.get_flag("pvpanic");
+        let vmclock = args.get_flag("vmclock");
+        let panic: Option<&str> = args.get_one::<String>("panic").map(|x| x as &str);
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args
             .get_many::<String>("sgx-epc")
@@ -555,6 +1146,7 @@ impl<'a> VmParams<'a> {
         let numa: Option<Vec<&str>> = args
             .get_many::<String>("numa")
             .map(|x| x.map(|y| y as &str).collect());
+        let numa_placement = args.get_flag("numa-placement");
         let watchdog = args.get_flag("watchdog");
         let pci_segments: Option<Vec<&str>> = args
             .get_many::<String>("pci-segment")
@@ -571,6 +1163,27 @@ impl<'a> VmParams<'a> {
         let landlock_rules: Option<Vec<&str>> = args
             .get_many::<String>("landlock-rules")
             .map(|x| x.map(|y| y as &str).collect());
+        let cgroup: Option<&str> = args.get_one::<String>("cgroup").map(|x| x as &str);
+        let security_label: Option<&str> =
+            args.get_one::<String>("security-label").map(|x| x as &str);
+        let rtc: Option<&str> = args.get_one::<String>("rtc").map(|x| x as &str);
+        #[cfg(target_arch = "x86_64")]
+        let fw_cfg: Option<Vec<&str>> = args
+            .get_many::<String>("fw-cfg")
+            .map(|x| x.map(|y| y as &str).collect());
+        let config_file = args.get_one::<String>("config").map(|x| x as &str);
+        let defaulted = [
+            "cpus",
+            "memory",
+            "rng",
+            "serial",
+            "console",
+            #[cfg(target_arch = "x86_64")]
+            "debug-console",
+        ]
+        .into_iter()
+        .filter(|arg| args.value_source(arg) == Some(ValueSource::DefaultValue))
+        .collect();
 
         VmParams {
             cpus,
@@ -581,11 +1194,14 @@ impl<'a> VmParams<'a> {
             initramfs,
             cmdline,
             rate_limit_groups,
+            io_affinities,
             disks,
             net,
             rng,
             balloon,
             fs,
+            fs9p,
+            i2c,
             pmem,
             serial,
             console,
@@ -594,13 +1210,23 @@ impl<'a> VmParams<'a> {
             devices,
             user_devices,
             vdpa,
+            ivshmem,
+            nvme,
+            cxl,
+            xhci,
+            usb_devices,
+            vnc,
             vsock,
+            guest_agent,
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol,
             pvpanic,
+            vmclock,
+            panic,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
             numa,
+            numa_placement,
             watchdog,
             #[cfg(feature = "guest_debug")]
             gdb,
@@ -613,6 +1239,13 @@ impl<'a> VmParams<'a> {
             host_data,
             landlock_enable,
             landlock_rules,
+            cgroup,
+            security_label,
+            rtc,
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg,
+            config_file,
+            defaulted,
         }
     }
 }
@@ -677,6 +1310,7 @@ impl CpusConfig {
             .add("kvm_hyperv")
             .add("max_phys_bits")
             .add("affinity")
+            .add("affinity_fallback")
             .add("features");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
@@ -709,6 +1343,10 @@ impl CpusConfig {
                     })
                     .collect()
             });
+        let affinity_fallback = parser
+            .convert("affinity_fallback")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or_default();
         let features_list = parser
             .convert::<StringList>("features")
             .map_err(Error::ParseCpus)?
@@ -738,11 +1376,29 @@ impl CpusConfig {
             kvm_hyperv,
             max_phys_bits,
             affinity,
+            affinity_fallback,
             features,
         })
     }
 }
 
+#[derive(Debug)]
+pub enum ParseCpuAffinityFallbackError {
+    InvalidValue(String),
+}
+
+impl FromStr for CpuAffinityFallback {
+    type Err = ParseCpuAffinityFallbackError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(CpuAffinityFallback::Any),
+            "numa" => Ok(CpuAffinityFallback::Numa),
+            _ => Err(ParseCpuAffinityFallbackError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 impl PciSegmentConfig {
     pub const SYNTAX: &'static str = "PCI Segment parameters \
          \"pci_segment=<segment_id>,mmio32_aperture_weight=<scale>,mmio64_aperture_weight=<scale>\"";
@@ -807,9 +1463,13 @@ impl PlatformConfig {
         parser
             .add("num_pci_segments")
             .add("iommu_segments")
+            .add("iommu_hotplug_slots")
             .add("serial_number")
             .add("uuid")
-            .add("oem_strings");
+            .add("oem_strings")
+            .add("oem_string_files")
+            .add("virtio_mmio")
+            .add("vmbus_relay");
         #[cfg(feature = "tdx")]
         parser.add("tdx");
         #[cfg(feature = "sev_snp")]
@@ -824,6 +1484,10 @@ impl PlatformConfig {
             .convert::<IntegerList>("iommu_segments")
             .map_err(Error::ParsePlatform)?
             .map(|v| v.0.iter().map(|e| *e as u16).collect());
+        let iommu_hotplug_slots = parser
+            .convert("iommu_hotplug_slots")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_default();
         let serial_number = parser
             .convert("serial_number")
             .map_err(Error::ParsePlatform)?;
@@ -832,6 +1496,16 @@ impl PlatformConfig {
             .convert::<StringList>("oem_strings")
             .map_err(Error::ParsePlatform)?
             .map(|v| v.0);
+        let oem_string_files = parser
+            .convert::<StringList>("oem_string_files")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| v.0.into_iter().map(PathBuf::from).collect());
+        let virtio_mmio = parser
+            .convert::<Toggle>("virtio_mmio")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let vmbus_relay = parser.get("vmbus_relay").map(PathBuf::from);
         #[cfg(feature = "tdx")]
         let tdx = parser
             .convert::<Toggle>("tdx")
@@ -847,9 +1521,13 @@ impl PlatformConfig {
         Ok(PlatformConfig {
             num_pci_segments,
             iommu_segments,
+            iommu_hotplug_slots,
             serial_number,
             uuid,
             oem_strings,
+            oem_string_files,
+            virtio_mmio,
+            vmbus_relay,
             #[cfg(feature = "tdx")]
             tdx,
             #[cfg(feature = "sev_snp")]
@@ -872,6 +1550,24 @@ impl PlatformConfig {
             }
         }
 
+        // Slot 0 of each segment is taken by its host bridge.
+        if self.iommu_hotplug_slots > 31 {
+            return Err(ValidationError::InvalidIommuHotplugSlots(
+                self.iommu_hotplug_slots,
+            ));
+        }
+
+        let oem_strings_count = self.oem_strings.as_ref().map_or(0, |s| s.len())
+            + self.oem_string_files.as_ref().map_or(0, |f| f.len());
+        if oem_strings_count > u8::MAX as usize {
+            return Err(ValidationError::TooManyOemStrings(oem_strings_count));
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        if self.vmbus_relay.is_some() {
+            return Err(ValidationError::VmbusRelayUnsupported);
+        }
+
         Ok(())
     }
 }
@@ -886,11 +1582,15 @@ impl MemoryConfig {
             .add("hotplug_method")
             .add("hotplug_size")
             .add("hotplugged_size")
+            .add("boot_size")
             .add("shared")
             .add("hugepages")
             .add("hugepage_size")
             .add("prefault")
-            .add("thp");
+            .add("mlock")
+            .add("thp")
+            .add("dirty_ring")
+            .add("guest_memfd");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -915,6 +1615,10 @@ impl MemoryConfig {
             .convert::<ByteSized>("hotplugged_size")
             .map_err(Error::ParseMemory)?
             .map(|v| v.0);
+        let boot_size = parser
+            .convert::<ByteSized>("boot_size")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
         let shared = parser
             .convert::<Toggle>("shared")
             .map_err(Error::ParseMemory)?
@@ -934,11 +1638,26 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let mlock = parser
+            .convert::<Toggle>("mlock")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
         let thp = parser
             .convert::<Toggle>("thp")
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(true))
             .0;
+        let dirty_ring = parser
+            .convert::<Toggle>("dirty_ring")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let guest_memfd = parser
+            .convert::<Toggle>("guest_memfd")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         let zones: Option<Vec<MemoryZoneConfig>> = if let Some(memory_zones) = &memory_zones {
             let mut zones = Vec::new();
@@ -954,7 +1673,8 @@ impl MemoryConfig {
                     .add("host_numa_node")
                     .add("hotplug_size")
                     .add("hotplugged_size")
-                    .add("prefault");
+                    .add("prefault")
+                    .add("mlock");
                 parser.parse(memory_zone).map_err(Error::ParseMemoryZone)?;
 
                 let id = parser.get("id").ok_or(Error::ParseMemoryZoneIdMissing)?;
@@ -995,6 +1715,11 @@ impl MemoryConfig {
                     .map_err(Error::ParseMemoryZone)?
                     .unwrap_or(Toggle(false))
                     .0;
+                let mlock = parser
+                    .convert::<Toggle>("mlock")
+                    .map_err(Error::ParseMemoryZone)?
+                    .unwrap_or(Toggle(false))
+                    .0;
 
                 zones.push(MemoryZoneConfig {
                     id,
@@ -1007,6 +1732,7 @@ impl MemoryConfig {
                     hotplug_size,
                     hotplugged_size,
                     prefault,
+                    mlock,
                 });
             }
             Some(zones)
@@ -1020,12 +1746,16 @@ impl MemoryConfig {
             hotplug_method,
             hotplug_size,
             hotplugged_size,
+            boot_size,
             shared,
             hugepages,
             hugepage_size,
             prefault,
+            mlock,
             zones,
             thp,
+            dirty_ring,
+            guest_memfd,
         })
     }
 
@@ -1135,15 +1865,55 @@ impl RateLimiterGroupConfig {
     }
 }
 
+impl IoAffinityConfig {
+    pub const SYNTAX: &'static str = "I/O affinity parameters \
+        \"id=<io_affinity_id>,num_sets=<number_of_cpu_sets>,host_cpus=<list_of_host_cpus>\"";
+
+    pub fn parse(io_affinity: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("id").add("num_sets").add("host_cpus");
+        parser.parse(io_affinity).map_err(Error::ParseIoAffinity)?;
+
+        let id = parser.get("id").unwrap_or_default();
+        let num_sets = parser
+            .convert("num_sets")
+            .map_err(Error::ParseIoAffinity)?
+            .unwrap_or_else(default_ioaffinityconfig_num_sets);
+        let host_cpus = parser
+            .convert::<IntegerList>("host_cpus")
+            .map_err(Error::ParseIoAffinity)?
+            .map(|v| v.0.iter().map(|e| *e as usize).collect())
+            .unwrap_or_default();
+
+        Ok(IoAffinityConfig {
+            id,
+            num_sets,
+            host_cpus,
+        })
+    }
+
+    pub fn validate(&self, _vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.id.is_empty() || self.num_sets == 0 || self.host_cpus.len() < self.num_sets {
+            return Err(ValidationError::InvalidIoAffinity(self.id.clone()));
+        }
+
+        Ok(())
+    }
+}
+
 impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
          \"path=<disk_image_path>,readonly=on|off,direct=on|off,iommu=on|off,\
          num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-         vhost_user=on|off,socket=<vhost_user_socket_path>,\
-         bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+         vhost_user=on|off,socket=<vhost_user_socket_path>,out_of_process=on|off,\
+         exec=<list_of_backend_command_arguments>,bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
          ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
-         id=<device_id>,pci_segment=<segment_id>,rate_limit_group=<group_id>,\
-         queue_affinity=<list_of_queue_indices_with_their_associated_cpuset>";
+         id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>,rate_limit_group=<group_id>,\
+         queue_affinity=<list_of_queue_indices_with_their_associated_cpuset>,\
+         io_affinity=<io_affinity_id>,busy_poll_us=<microseconds>,io_uring_event_loop=on|off,\
+         coalesce_us=<microseconds>,completion_poll_us=<microseconds>,thread_pool_size=<number_of_threads>,\
+         thread_pool_queue_depth=<number_of_requests>,transitional=on|off,overlay=on|off,key_file=<luks_key_file_path>,\
+         nvme=<nvme_controller_id>";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1156,6 +1926,8 @@ impl DiskConfig {
             .add("num_queues")
             .add("vhost_user")
             .add("socket")
+            .add("out_of_process")
+            .add("exec")
             .add("bw_size")
             .add("bw_one_time_burst")
             .add("bw_refill_time")
@@ -1166,9 +1938,21 @@ impl DiskConfig {
             .add("_disable_io_uring")
             .add("_disable_aio")
             .add("pci_segment")
+            .add("pci_bdf")
             .add("serial")
             .add("rate_limit_group")
-            .add("queue_affinity");
+            .add("queue_affinity")
+            .add("io_affinity")
+            .add("busy_poll_us")
+            .add("io_uring_event_loop")
+            .add("coalesce_us")
+            .add("completion_poll_us")
+            .add("thread_pool_size")
+            .add("thread_pool_queue_depth")
+            .add("transitional")
+            .add("overlay")
+            .add("key_file")
+            .add("nvme");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -1201,6 +1985,15 @@ impl DiskConfig {
             .unwrap_or(Toggle(false))
             .0;
         let vhost_socket = parser.get("socket");
+        let out_of_process = parser
+            .convert::<Toggle>("out_of_process")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let exec = parser
+            .convert::<StringList>("exec")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
         let id = parser.get("id");
         let disable_io_uring = parser
             .convert::<Toggle>("_disable_io_uring")
@@ -1212,19 +2005,52 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or(Toggle(false))
             .0;
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseDisk)?;
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParseDisk)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
         let rate_limit_group = parser.get("rate_limit_group");
-        let bw_size = parser
-            .convert("bw_size")
+        let io_affinity = parser.get("io_affinity");
+        let busy_poll_us = parser.convert("busy_poll_us").map_err(Error::ParseDisk)?;
+        let io_uring_event_loop = parser
+            .convert::<Toggle>("io_uring_event_loop")
             .map_err(Error::ParseDisk)?
-            .unwrap_or_default();
-        let bw_one_time_burst = parser
-            .convert("bw_one_time_burst")
+            .unwrap_or(Toggle(false))
+            .0;
+        let coalesce_us = parser.convert("coalesce_us").map_err(Error::ParseDisk)?;
+        let completion_poll_us = parser
+            .convert("completion_poll_us")
+            .map_err(Error::ParseDisk)?;
+        let thread_pool_size = parser
+            .convert("thread_pool_size")
+            .map_err(Error::ParseDisk)?;
+        let thread_pool_queue_depth = parser
+            .convert("thread_pool_queue_depth")
+            .map_err(Error::ParseDisk)?;
+        let transitional = parser
+            .convert::<Toggle>("transitional")
             .map_err(Error::ParseDisk)?
-            .unwrap_or_default();
+            .unwrap_or(Toggle(false))
+            .0;
+        let overlay = parser
+            .convert::<Toggle>("overlay")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let key_file = parser.get("key_file").map(PathBuf::from);
+        let nvme = parser.get("nvme");
+        let bw_size = parser
+            .convert("bw_size")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let bw_one_time_burst = parser
+            .convert("bw_one_time_burst")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
         let bw_refill_time = parser
             .convert("bw_refill_time")
             .map_err(Error::ParseDisk)?
@@ -1289,14 +2115,29 @@ impl DiskConfig {
             queue_size,
             vhost_user,
             vhost_socket,
+            out_of_process,
+            exec,
             rate_limit_group,
             rate_limiter_config,
             id,
             disable_io_uring,
             disable_aio,
             pci_segment,
+            pci_bdf,
             serial,
             queue_affinity,
+            io_affinity,
+            busy_poll_us,
+            io_uring_event_loop,
+            coalesce_us,
+            completion_poll_us,
+            thread_pool_size,
+            thread_pool_queue_depth,
+            transitional,
+            overlay,
+            key_file,
+            key: None,
+            nvme,
         })
     }
 
@@ -1305,10 +2146,28 @@ impl DiskConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
-        if self.vhost_user && self.iommu {
+        if (self.vhost_user || self.out_of_process) && self.iommu {
             return Err(ValidationError::IommuNotSupported);
         }
 
+        if self.exec.is_some() && !self.vhost_user {
+            return Err(ValidationError::ExecRequiresVhostUser);
+        }
+
+        if self.out_of_process {
+            if self.vhost_user {
+                return Err(ValidationError::OutOfProcessAndVhostUser);
+            }
+            if self.path.is_none() {
+                return Err(ValidationError::OutOfProcessDiskPathMissing);
+            }
+            if self.rate_limiter_config.is_some() || self.rate_limit_group.is_some() {
+                return Err(ValidationError::OutOfProcessUnsupported(
+                    "rate limiting".to_owned(),
+                ));
+            }
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1325,6 +2184,73 @@ impl DiskConfig {
             return Err(ValidationError::InvalidRateLimiterGroup);
         }
 
+        if let Some(io_affinity) = &self.io_affinity {
+            if self.vhost_user || self.out_of_process || self.queue_affinity.is_some() {
+                return Err(ValidationError::IoAffinityUnsupported);
+            }
+            vm_config.validate_io_affinity(io_affinity)?;
+        }
+
+        if self.busy_poll_us.is_some() && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::BusyPollUnsupported);
+        }
+
+        if self.io_uring_event_loop && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::IoUringEventLoopUnsupported);
+        }
+
+        if self.coalesce_us.is_some() && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::CoalescingUnsupported);
+        }
+
+        if self.completion_poll_us.is_some() && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::CompletionPollUnsupported);
+        }
+
+        if self.thread_pool_size == Some(0)
+            || self.thread_pool_queue_depth == Some(0)
+            || (self.thread_pool_queue_depth.is_some() && self.thread_pool_size.is_none())
+        {
+            return Err(ValidationError::InvalidDiskThreadPool);
+        }
+
+        if self.thread_pool_size.is_some() && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::DiskThreadPoolUnsupported);
+        }
+
+        if self.transitional
+            && (!cfg!(target_arch = "x86_64")
+                || self.vhost_user
+                || self.out_of_process
+                || self.iommu)
+        {
+            return Err(ValidationError::TransitionalUnsupported);
+        }
+
+        if self.overlay
+            && (self.path.is_none() || self.readonly || self.vhost_user || self.out_of_process)
+        {
+            return Err(ValidationError::DiskOverlayUnsupported);
+        }
+
+        if (self.key_file.is_some() || self.key.is_some())
+            && ((self.key_file.is_some() && self.key.is_some())
+                || self.overlay
+                || self.vhost_user
+                || self.out_of_process)
+        {
+            return Err(ValidationError::DiskKeyUnsupported);
+        }
+
+        if let Some(nvme) = &self.nvme {
+            if !vm_config.nvme.iter().flatten().any(|n| &n.id == nvme) {
+                return Err(ValidationError::NvmeControllerMissing(nvme.clone()));
+            }
+            if self.vhost_user || self.out_of_process || self.iommu || self.transitional {
+                return Err(ValidationError::DiskNvmeUnsupported);
+            }
+        }
+
         Ok(())
     }
 }
@@ -1348,18 +2274,25 @@ impl FromStr for VhostMode {
 
 impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
-    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,fd=<fd1,fd2...>,iommu=on|off,\
+    \"tap=<if_name>,macvtap=<if_name>,xdp=<if_name>,xdp_queue=<first_queue>,ip=<ip_addr>,\
+    mask=<net_mask>,mac=<mac_addr>,fd=<fd1,fd2...>,iommu=on|off,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,id=<device_id>,\
     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,vhost_mode=client|server,\
-    bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
-    ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,pci_segment=<segment_id>\
-    offload_tso=on|off,offload_ufo=on|off,offload_csum=on|off\"";
+    out_of_process=on|off,exec=<list_of_backend_command_arguments>,bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+    ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\
+    offload_tso=on|off,offload_ufo=on|off,offload_csum=on|off,io_affinity=<io_affinity_id>,\
+    busy_poll_us=<microseconds>,io_uring_event_loop=on|off,coalesce_us=<microseconds>,\
+    transitional=on|off,passt=on|off,io_uring_registered_buffers=on|off,port_forward=<[tcp|udp:<host_port>:<guest_port>,...]>,\
+    vhost_net=on|off\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
 
         parser
             .add("tap")
+            .add("macvtap")
+            .add("xdp")
+            .add("xdp_queue")
             .add("ip")
             .add("mask")
             .add("mac")
@@ -1374,6 +2307,8 @@ impl NetConfig {
             .add("vhost_user")
             .add("socket")
             .add("vhost_mode")
+            .add("out_of_process")
+            .add("exec")
             .add("id")
             .add("fd")
             .add("bw_size")
@@ -1382,10 +2317,27 @@ impl NetConfig {
             .add("ops_size")
             .add("ops_one_time_burst")
             .add("ops_refill_time")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("pci_bdf")
+            .add("_disable_io_uring")
+            .add("io_affinity")
+            .add("busy_poll_us")
+            .add("io_uring_event_loop")
+            .add("coalesce_us")
+            .add("transitional")
+            .add("passt")
+            .add("io_uring_registered_buffers")
+            .add("port_forward")
+            .add("vhost_net");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
+        let macvtap = parser.get("macvtap");
+        let xdp = parser.get("xdp");
+        let xdp_queue = parser
+            .convert("xdp_queue")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
         let ip = parser
             .convert("ip")
             .map_err(Error::ParseNetwork)?
@@ -1438,15 +2390,80 @@ impl NetConfig {
             .convert("vhost_mode")
             .map_err(Error::ParseNetwork)?
             .unwrap_or_default();
+        let out_of_process = parser
+            .convert::<Toggle>("out_of_process")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let exec = parser
+            .convert::<StringList>("exec")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| v.0);
         let id = parser.get("id");
         let fds = parser
             .convert::<IntegerList>("fd")
             .map_err(Error::ParseNetwork)?
             .map(|v| v.0.iter().map(|e| *e as i32).collect());
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseNetwork)?;
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParseNetwork)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
+        let disable_io_uring = parser
+            .convert::<Toggle>("_disable_io_uring")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let io_uring_registered_buffers = parser
+            .convert::<Toggle>("io_uring_registered_buffers")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let io_affinity = parser.get("io_affinity");
+        let busy_poll_us = parser
+            .convert("busy_poll_us")
+            .map_err(Error::ParseNetwork)?;
+        let io_uring_event_loop = parser
+            .convert::<Toggle>("io_uring_event_loop")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let coalesce_us = parser.convert("coalesce_us").map_err(Error::ParseNetwork)?;
+        let transitional = parser
+            .convert::<Toggle>("transitional")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let passt = parser
+            .convert::<Toggle>("passt")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let vhost_net = parser
+            .convert::<Toggle>("vhost_net")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let port_forward = parser
+            .convert::<StringList>("port_forward")
+            .map_err(Error::ParseNetwork)?
+            .map(|list| {
+                list.0
+                    .iter()
+                    .map(|rule| {
+                        rule.parse().map_err(|_| {
+                            Error::ParseNetwork(OptionParserError::Conversion(
+                                "port_forward".to_owned(),
+                                rule.to_owned(),
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<PortForwardConfig>>>()
+            })
+            .transpose()?;
         let bw_size = parser
             .convert("bw_size")
             .map_err(Error::ParseNetwork)?
@@ -1500,6 +2517,9 @@ impl NetConfig {
 
         let config = NetConfig {
             tap,
+            macvtap,
+            xdp,
+            xdp_queue,
             ip,
             mask,
             mac,
@@ -1511,13 +2531,26 @@ impl NetConfig {
             vhost_user,
             vhost_socket,
             vhost_mode,
+            out_of_process,
+            exec,
             id,
             fds,
             rate_limiter_config,
             pci_segment,
+            pci_bdf,
             offload_tso,
             offload_ufo,
             offload_csum,
+            disable_io_uring,
+            io_uring_registered_buffers,
+            io_affinity,
+            busy_poll_us,
+            io_uring_event_loop,
+            coalesce_us,
+            transitional,
+            passt,
+            port_forward,
+            vhost_net,
         };
         Ok(config)
     }
@@ -1527,6 +2560,83 @@ impl NetConfig {
             return Err(ValidationError::VnetQueueLowerThan2);
         }
 
+        if self.passt {
+            let unsupported = [
+                ("tap", self.tap.is_some()),
+                ("macvtap", self.macvtap.is_some()),
+                ("fd", self.fds.is_some()),
+                ("vhost_user", self.vhost_user),
+                ("out_of_process", self.out_of_process),
+                ("iommu", self.iommu),
+                ("rate limiting", self.rate_limiter_config.is_some()),
+                ("io_affinity", self.io_affinity.is_some()),
+                ("busy_poll_us", self.busy_poll_us.is_some()),
+                ("io_uring_event_loop", self.io_uring_event_loop),
+                ("coalesce_us", self.coalesce_us.is_some()),
+                ("transitional", self.transitional),
+            ];
+            if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+                return Err(ValidationError::PasstUnsupported((*option).to_owned()));
+            }
+        } else if self.port_forward.is_some() {
+            return Err(ValidationError::PortForwardRequiresPasst);
+        }
+
+        // Registered buffers pin the whole guest memory, which defeats
+        // giving memory back to the host.
+        if self.io_uring_registered_buffers {
+            if vm_config.balloon.is_some() {
+                return Err(ValidationError::IoUringRegisteredBuffersUnsupported(
+                    "balloon".to_owned(),
+                ));
+            }
+            if vm_config.memory.hotplug_method == HotplugMethod::VirtioMem
+                && vm_config.memory.hotplug_size.is_some()
+            {
+                return Err(ValidationError::IoUringRegisteredBuffersUnsupported(
+                    "virtio-mem".to_owned(),
+                ));
+            }
+        }
+
+        if self.xdp.is_some() {
+            let unsupported = [
+                ("tap", self.tap.is_some()),
+                ("macvtap", self.macvtap.is_some()),
+                ("fd", self.fds.is_some()),
+                ("vhost_user", self.vhost_user),
+                ("out_of_process", self.out_of_process),
+                ("passt", self.passt),
+            ];
+            if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+                return Err(ValidationError::XdpUnsupported((*option).to_owned()));
+            }
+        }
+
+        // The queue pairs are processed by the kernel, leaving out all the
+        // options applied by the VMM threads.
+        if self.vhost_net {
+            let unsupported = [
+                ("vhost_user", self.vhost_user),
+                ("out_of_process", self.out_of_process),
+                ("passt", self.passt),
+                ("xdp", self.xdp.is_some()),
+                ("iommu", self.iommu),
+                ("rate limiting", self.rate_limiter_config.is_some()),
+                ("io_affinity", self.io_affinity.is_some()),
+                ("busy_poll_us", self.busy_poll_us.is_some()),
+                ("io_uring_event_loop", self.io_uring_event_loop),
+                (
+                    "io_uring_registered_buffers",
+                    self.io_uring_registered_buffers,
+                ),
+                ("coalesce_us", self.coalesce_us.is_some()),
+            ];
+            if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+                return Err(ValidationError::VhostNetUnsupported((*option).to_owned()));
+            }
+        }
+
         if self.fds.is_some() && self.fds.as_ref().unwrap().len() * 2 != self.num_queues {
             return Err(ValidationError::VnetQueueFdMismatch);
         }
@@ -1543,10 +2653,45 @@ impl NetConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
-        if self.vhost_user && self.iommu {
+        if self.macvtap.is_some() {
+            if self.tap.is_some() {
+                return Err(ValidationError::MacvtapUnsupported("tap".to_owned()));
+            }
+            if self.fds.is_some() {
+                return Err(ValidationError::MacvtapUnsupported("fd".to_owned()));
+            }
+            if self.vhost_user {
+                return Err(ValidationError::MacvtapUnsupported("vhost_user".to_owned()));
+            }
+            if self.out_of_process {
+                return Err(ValidationError::MacvtapUnsupported(
+                    "out_of_process".to_owned(),
+                ));
+            }
+        }
+
+        if (self.vhost_user || self.out_of_process) && self.iommu {
             return Err(ValidationError::IommuNotSupported);
         }
 
+        if self.exec.is_some() && !self.vhost_user {
+            return Err(ValidationError::ExecRequiresVhostUser);
+        }
+
+        if self.out_of_process {
+            if self.vhost_user {
+                return Err(ValidationError::OutOfProcessAndVhostUser);
+            }
+            if self.fds.is_some() {
+                return Err(ValidationError::OutOfProcessUnsupported("fd".to_owned()));
+            }
+            if self.rate_limiter_config.is_some() {
+                return Err(ValidationError::OutOfProcessUnsupported(
+                    "rate limiting".to_owned(),
+                ));
+            }
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1569,6 +2714,34 @@ impl NetConfig {
             return Err(ValidationError::NoHardwareChecksumOffload);
         }
 
+        if let Some(io_affinity) = &self.io_affinity {
+            if self.vhost_user || self.out_of_process {
+                return Err(ValidationError::IoAffinityUnsupported);
+            }
+            vm_config.validate_io_affinity(io_affinity)?;
+        }
+
+        if self.busy_poll_us.is_some() && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::BusyPollUnsupported);
+        }
+
+        if self.io_uring_event_loop && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::IoUringEventLoopUnsupported);
+        }
+
+        if self.coalesce_us.is_some() && (self.vhost_user || self.out_of_process) {
+            return Err(ValidationError::CoalescingUnsupported);
+        }
+
+        if self.transitional
+            && (!cfg!(target_arch = "x86_64")
+                || self.vhost_user
+                || self.out_of_process
+                || self.iommu)
+        {
+            return Err(ValidationError::TransitionalUnsupported);
+        }
+
         Ok(())
     }
 }
@@ -1597,13 +2770,22 @@ impl RngConfig {
 impl BalloonConfig {
     pub const SYNTAX: &'static str =
         "Balloon parameters \"size=<balloon_size>,deflate_on_oom=on|off,\
-        free_page_reporting=on|off\"";
+        free_page_reporting=on|off,auto=on|off,auto_min=<min_guest_ram>,\
+        auto_max=<max_guest_ram>,auto_reserve=<free_memory_reserve>,\
+        auto_hysteresis=<min_balloon_change>,auto_interval_ms=<policy_interval>\"";
 
     pub fn parse(balloon: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser.add("size");
         parser.add("deflate_on_oom");
         parser.add("free_page_reporting");
+        parser
+            .add("auto")
+            .add("auto_min")
+            .add("auto_max")
+            .add("auto_reserve")
+            .add("auto_hysteresis")
+            .add("auto_interval_ms");
         parser.parse(balloon).map_err(Error::ParseBalloon)?;
 
         let size = parser
@@ -1624,18 +2806,62 @@ impl BalloonConfig {
             .unwrap_or(Toggle(false))
             .0;
 
+        let auto = parser
+            .convert::<Toggle>("auto")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        let policy = if auto {
+            let min_guest_ram = parser
+                .convert::<ByteSized>("auto_min")
+                .map_err(Error::ParseBalloon)?
+                .map(|v| v.0)
+                .unwrap_or(0);
+            let max_guest_ram = parser
+                .convert::<ByteSized>("auto_max")
+                .map_err(Error::ParseBalloon)?
+                .map(|v| v.0);
+            let free_reserve = parser
+                .convert::<ByteSized>("auto_reserve")
+                .map_err(Error::ParseBalloon)?
+                .map(|v| v.0)
+                .unwrap_or_else(default_balloonpolicyconfig_free_reserve);
+            let hysteresis = parser
+                .convert::<ByteSized>("auto_hysteresis")
+                .map_err(Error::ParseBalloon)?
+                .map(|v| v.0)
+                .unwrap_or_else(default_balloonpolicyconfig_hysteresis);
+            let interval_ms = parser
+                .convert("auto_interval_ms")
+                .map_err(Error::ParseBalloon)?
+                .unwrap_or_else(default_balloonpolicyconfig_interval_ms);
+
+            Some(BalloonPolicyConfig {
+                min_guest_ram,
+                max_guest_ram,
+                free_reserve,
+                hysteresis,
+                interval_ms,
+            })
+        } else {
+            None
+        };
+
         Ok(BalloonConfig {
             size,
             deflate_on_oom,
             free_page_reporting,
+            policy,
         })
     }
 }
 
 impl FsConfig {
     pub const SYNTAX: &'static str = "virtio-fs parameters \
-    \"tag=<tag_name>,socket=<socket_path>,num_queues=<number_of_queues>,\
-    queue_size=<size_of_each_queue>,id=<device_id>,pci_segment=<segment_id>\"";
+    \"tag=<tag_name>,socket=<socket_path>,shared_dir=<shared_directory_path>,\
+    exec=<list_of_backend_command_arguments>,num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,id=<device_id>,\
+    pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
 
     pub fn parse(fs: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1644,8 +2870,11 @@ impl FsConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket")
+            .add("shared_dir")
+            .add("exec")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("pci_bdf");
         parser.parse(fs).map_err(Error::ParseFileSystem)?;
 
         let tag = parser.get("tag").ok_or(Error::ParseFsTagMissing)?;
@@ -1663,11 +2892,21 @@ impl FsConfig {
             .map_err(Error::ParseFileSystem)?
             .unwrap_or_else(default_fsconfig_num_queues);
 
+        let shared_dir = parser.get("shared_dir").map(PathBuf::from);
+        let exec = parser
+            .convert::<StringList>("exec")
+            .map_err(Error::ParseFileSystem)?
+            .map(|v| v.0);
+
         let id = parser.get("id");
 
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseFileSystem)?;
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParseFileSystem)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
 
         Ok(FsConfig {
@@ -1675,8 +2914,11 @@ impl FsConfig {
             socket,
             num_queues,
             queue_size,
+            shared_dir,
+            exec,
             id,
             pci_segment,
+            pci_bdf,
         })
     }
 
@@ -1685,6 +2927,163 @@ impl FsConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
+        if self.exec.is_some() && self.shared_dir.is_some() {
+            return Err(ValidationError::FsExecAndSharedDir);
+        }
+
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+
+            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
+                if iommu_segments.contains(&self.pci_segment) {
+                    return Err(ValidationError::IommuNotSupportedOnSegment(
+                        self.pci_segment,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Fs9pConfig {
+    // Room for the largest message header along with some payload.
+    pub const MIN_MSIZE: u32 = 4096;
+
+    pub const SYNTAX: &'static str = "virtio-9p parameters \
+    \"tag=<tag_name>,path=<exported_directory_path>,msize=<max_message_size>,\
+    id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
+
+    pub fn parse(fs9p: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("tag")
+            .add("path")
+            .add("msize")
+            .add("id")
+            .add("pci_segment")
+            .add("pci_bdf");
+        parser.parse(fs9p).map_err(Error::ParseFs9p)?;
+
+        let tag = parser.get("tag").ok_or(Error::ParseFs9pTagMissing)?;
+        let path = PathBuf::from(parser.get("path").ok_or(Error::ParseFs9pPathMissing)?);
+        let msize = parser
+            .convert::<ByteSized>("msize")
+            .map_err(Error::ParseFs9p)?
+            .map(|v| {
+                u32::try_from(v.0).map_err(|_| {
+                    Error::ParseFs9p(OptionParserError::InvalidValue(format!(
+                        "msize {} exceeds {}",
+                        v.0,
+                        u32::MAX
+                    )))
+                })
+            })
+            .transpose()?
+            .unwrap_or_else(default_fs9pconfig_msize);
+        let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseFs9p)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseFs9p)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
+            .unwrap_or_default();
+
+        Ok(Fs9pConfig {
+            tag,
+            path,
+            msize,
+            id,
+            pci_segment,
+            pci_bdf,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.msize < Self::MIN_MSIZE {
+            return Err(ValidationError::InvalidFs9pMsize(self.msize));
+        }
+
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+
+            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
+                if iommu_segments.contains(&self.pci_segment) {
+                    return Err(ValidationError::IommuNotSupportedOnSegment(
+                        self.pci_segment,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl I2cConfig {
+    pub const SYNTAX: &'static str = "I2C adapter parameters \
+    \"path=<host_i2c_bus_path>,addresses=<list_of_allowed_device_addresses>,\
+    id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
+
+    pub fn parse(i2c: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("addresses")
+            .add("id")
+            .add("pci_segment")
+            .add("pci_bdf");
+        parser.parse(i2c).map_err(Error::ParseI2c)?;
+
+        let path = PathBuf::from(parser.get("path").ok_or(Error::ParseI2cPathMissing)?);
+        let addresses = parser
+            .convert::<StringList>("addresses")
+            .map_err(Error::ParseI2c)?
+            .ok_or(Error::ParseI2cAddressesMissing)?
+            .0
+            .iter()
+            .map(|v| {
+                u16::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| {
+                    Error::ParseI2c(OptionParserError::InvalidValue(format!("addresses={v}")))
+                })
+            })
+            .collect::<Result<Vec<u16>>>()?;
+        let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseI2c)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseI2c)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
+            .unwrap_or_default();
+
+        Ok(I2cConfig {
+            path,
+            addresses,
+            id,
+            pci_segment,
+            pci_bdf,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.addresses.is_empty() {
+            return Err(ValidationError::I2cAddressesMissing);
+        }
+
+        // 10-bit addressing is not supported by the virtio-i2c protocol.
+        if let Some(addr) = self.addresses.iter().find(|a| **a > 0x7f) {
+            return Err(ValidationError::InvalidI2cAddress(*addr));
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1706,7 +3105,8 @@ impl FsConfig {
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-    discard_writes=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+    discard_writes=on|off,id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>,nvdimm=on|off,\
+    label_size=<label_storage_size>\"";
 
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1716,7 +3116,10 @@ impl PmemConfig {
             .add("iommu")
             .add("discard_writes")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("pci_bdf")
+            .add("nvdimm")
+            .add("label_size");
         parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
 
         let file = PathBuf::from(parser.get("file").ok_or(Error::ParsePmemFileMissing)?);
@@ -1735,9 +3138,23 @@ impl PmemConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParsePersistentMemory)?;
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParsePersistentMemory)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
+            .unwrap_or_default();
+        let nvdimm = parser
+            .convert::<Toggle>("nvdimm")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let label_size = parser
+            .convert::<ByteSized>("label_size")
+            .map_err(Error::ParsePersistentMemory)?
+            .map(|v| v.0)
             .unwrap_or_default();
 
         Ok(PmemConfig {
@@ -1747,10 +3164,25 @@ impl PmemConfig {
             discard_writes,
             id,
             pci_segment,
+            pci_bdf,
+            nvdimm,
+            label_size,
         })
     }
 
     pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.nvdimm && self.iommu {
+            return Err(ValidationError::NvdimmUnsupported);
+        }
+
+        if self.label_size != 0
+            && (!self.nvdimm
+                || self.label_size < devices::nvdimm::NVDIMM_LABEL_MIN_SIZE
+                || self.label_size % 0x1000 != 0)
+        {
+            return Err(ValidationError::InvalidPmemLabelSize(self.label_size));
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1777,7 +3209,9 @@ impl ConsoleConfig {
             .add_valueless("null")
             .add("file")
             .add("iommu")
-            .add("socket");
+            .add("socket")
+            .add("log_file")
+            .add("capture_size");
         parser.parse(console).map_err(Error::ParseConsole)?;
 
         let mut file: Option<PathBuf> = default_consoleconfig_file();
@@ -1810,12 +3244,19 @@ impl ConsoleConfig {
             .map_err(Error::ParseConsole)?
             .unwrap_or(Toggle(false))
             .0;
+        let log_file = parser.get("log_file").map(PathBuf::from);
+        let capture_size = parser
+            .convert::<ByteSized>("capture_size")
+            .map_err(Error::ParseConsole)?
+            .map(|v| v.0);
 
         Ok(Self {
             file,
             mode,
             iommu,
             socket,
+            log_file,
+            capture_size,
         })
     }
 }
@@ -1875,7 +3316,7 @@ impl DebugConsoleConfig {
 
 impl DeviceConfig {
     pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
 
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1884,6 +3325,7 @@ impl DeviceConfig {
             .add("id")
             .add("iommu")
             .add("pci_segment")
+            .add("pci_bdf")
             .add("x_nv_gpudirect_clique");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
@@ -1897,9 +3339,13 @@ impl DeviceConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseDevice)?;
         let pci_segment = parser
             .convert::<u16>("pci_segment")
             .map_err(Error::ParseDevice)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
         let x_nv_gpudirect_clique = parser
             .convert::<u8>("x_nv_gpudirect_clique")
@@ -1909,6 +3355,7 @@ impl DeviceConfig {
             iommu,
             id,
             pci_segment,
+            pci_bdf,
             x_nv_gpudirect_clique,
         })
     }
@@ -1932,11 +3379,15 @@ impl DeviceConfig {
 
 impl UserDeviceConfig {
     pub const SYNTAX: &'static str =
-        "Userspace device socket=<socket_path>,id=<device_id>,pci_segment=<segment_id>\"";
+        "Userspace device socket=<socket_path>,id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
 
     pub fn parse(user_device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("socket").add("id").add("pci_segment");
+        parser
+            .add("socket")
+            .add("id")
+            .add("pci_segment")
+            .add("pci_bdf");
         parser.parse(user_device).map_err(Error::ParseUserDevice)?;
 
         let socket = parser
@@ -1944,15 +3395,20 @@ impl UserDeviceConfig {
             .map(PathBuf::from)
             .ok_or(Error::ParseUserDeviceSocketMissing)?;
         let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseUserDevice)?;
         let pci_segment = parser
             .convert::<u16>("pci_segment")
             .map_err(Error::ParseUserDevice)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
 
         Ok(UserDeviceConfig {
             socket,
             id,
             pci_segment,
+            pci_bdf,
         })
     }
 
@@ -1978,7 +3434,7 @@ impl UserDeviceConfig {
 impl VdpaConfig {
     pub const SYNTAX: &'static str = "vDPA device \
         \"path=<device_path>,num_queues=<number_of_queues>,iommu=on|off,\
-        id=<device_id>,pci_segment=<segment_id>\"";
+        id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
 
     pub fn parse(vdpa: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1987,7 +3443,8 @@ impl VdpaConfig {
             .add("num_queues")
             .add("iommu")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("pci_bdf");
         parser.parse(vdpa).map_err(Error::ParseVdpa)?;
 
         let path = parser
@@ -2004,9 +3461,13 @@ impl VdpaConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseVdpa)?;
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParseVdpa)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
 
         Ok(VdpaConfig {
@@ -2015,6 +3476,7 @@ impl VdpaConfig {
             iommu,
             id,
             pci_segment,
+            pci_bdf,
         })
     }
 
@@ -2035,18 +3497,345 @@ impl VdpaConfig {
     }
 }
 
-impl VsockConfig {
-    pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
-        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+impl IvshmemConfig {
+    pub const SYNTAX: &'static str = "ivshmem-plain shared memory device \
+        \"path=<shared_memory_file>,size=<shared_memory_size>,id=<device_id>,\
+        pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
 
-    pub fn parse(vsock: &str) -> Result<Self> {
+    pub fn parse(ivshmem: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("size")
+            .add("id")
+            .add("pci_segment")
+            .add("pci_bdf");
+        parser.parse(ivshmem).map_err(Error::ParseIvshmem)?;
+
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseIvshmemPathMissing)?;
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseIvshmem)?
+            .map(|v| v.0);
+        let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseIvshmem)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseIvshmem)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
+            .unwrap_or_default();
+
+        Ok(IvshmemConfig {
+            path,
+            size,
+            id,
+            pci_segment,
+            pci_bdf,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+        }
+
+        if let Some(size) = self.size {
+            if !size.is_power_of_two() || size < 0x1000 {
+                return Err(ValidationError::InvalidIvshmemSize(size));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl NvmeConfig {
+    pub const SYNTAX: &'static str = "NVMe controller, to which disks are attached as \
+        namespaces with their nvme option \
+        \"id=<controller_id>,serial=<serial_number>,num_queues=<number_of_io_queues>,\
+        pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
+
+    pub fn parse(nvme: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("id")
+            .add("serial")
+            .add("num_queues")
+            .add("pci_segment")
+            .add("pci_bdf");
+        parser.parse(nvme).map_err(Error::ParseNvme)?;
+
+        let id = parser.get("id").ok_or(Error::ParseNvmeIdMissing)?;
+        let serial = parser.get("serial");
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::ParseNvme)?
+            .unwrap_or_else(default_nvmeconfig_num_queues);
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseNvme)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseNvme)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
+            .unwrap_or_default();
+
+        Ok(NvmeConfig {
+            id,
+            serial,
+            num_queues,
+            pci_segment,
+            pci_bdf,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+        }
+
+        if !(1..=devices::nvme::NVME_MAX_IO_QUEUES).contains(&self.num_queues) {
+            return Err(ValidationError::InvalidNvmeNumQueues(self.num_queues));
+        }
+
+        if let Some(serial) = &self.serial {
+            if serial.len() > 20 || !serial.is_ascii() {
+                return Err(ValidationError::InvalidNvmeSerial(serial.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CxlConfig {
+    pub const SYNTAX: &'static str = "CXL Type-3 memory device, exposing a memory zone as its \
+        host-managed device memory behind a dedicated CXL host bridge \
+        \"memory_zone=<memory_zone_id>,id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
+
+    pub fn parse(cxl: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("memory_zone")
+            .add("id")
+            .add("pci_segment")
+            .add("pci_bdf");
+        parser.parse(cxl).map_err(Error::ParseCxl)?;
+
+        let memory_zone = parser
+            .get("memory_zone")
+            .ok_or(Error::ParseCxlMemoryZoneMissing)?;
+        let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseCxl)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseCxl)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
+            .unwrap_or_default();
+
+        Ok(CxlConfig {
+            memory_zone,
+            id,
+            pci_segment,
+            pci_bdf,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        // The PCI segment becomes the CXL host bridge, which can't be the
+        // default one.
+        let num_pci_segments = vm_config
+            .platform
+            .as_ref()
+            .map(|p| p.num_pci_segments)
+            .unwrap_or(1);
+        if self.pci_segment == 0 || self.pci_segment >= num_pci_segments {
+            return Err(ValidationError::InvalidCxlPciSegment(self.pci_segment));
+        }
+
+        let zone = vm_config
+            .memory
+            .zones
+            .iter()
+            .flatten()
+            .find(|z| z.id == self.memory_zone)
+            .ok_or_else(|| ValidationError::CxlMemoryZoneMissing(self.memory_zone.clone()))?;
+        if zone.size == 0
+            || zone.size % devices::cxl::CXL_HDM_ALIGNMENT != 0
+            || zone.hotplug_size.is_some()
+        {
+            return Err(ValidationError::InvalidCxlMemoryZone(zone.id.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl XhciConfig {
+    pub const SYNTAX: &'static str = "xHCI controller \"num_ports=<number_of_ports>\"";
+
+    pub fn parse(xhci: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("num_ports");
+        parser.parse(xhci).map_err(Error::ParseXhci)?;
+
+        let num_ports = parser
+            .convert("num_ports")
+            .map_err(Error::ParseXhci)?
+            .unwrap_or_else(default_xhciconfig_num_ports);
+
+        Ok(XhciConfig { num_ports })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.num_ports == 0 || self.num_ports > devices::usb::XHCI_MAX_PORTS {
+            return Err(ValidationError::InvalidXhciNumPorts(self.num_ports));
+        }
+
+        Ok(())
+    }
+}
+
+impl VncConfig {
+    pub const SYNTAX: &'static str = "VNC server for a virtio-gpu display \
+        \"listen=<address:port>,width=<pixels>,height=<pixels>\"";
+
+    pub fn parse(vnc: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("listen").add("width").add("height");
+        parser.parse(vnc).map_err(Error::ParseVnc)?;
+
+        let listen = parser
+            .convert("listen")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or_else(default_vncconfig_listen);
+        let width = parser
+            .convert("width")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or_else(default_vncconfig_width);
+        let height = parser
+            .convert("height")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or_else(default_vncconfig_height);
+
+        Ok(VncConfig {
+            listen,
+            width,
+            height,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        let size_range = 1..=virtio_devices::GPU_MAX_DISPLAY_SIZE;
+        if !size_range.contains(&self.width) || !size_range.contains(&self.height) {
+            return Err(ValidationError::InvalidVncDisplaySize(
+                self.width,
+                self.height,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl UsbDeviceConfig {
+    pub const SYNTAX: &'static str = "USB host device passthrough \
+        \"hostbus=<bus_number>,hostaddr=<device_address>,vendor_id=<hex_id>,\
+        product_id=<hex_id>,id=<device_id>\"";
+
+    fn parse_hex_id(parser: &OptionParser, option: &str) -> Result<Option<u16>> {
+        parser
+            .get(option)
+            .map(|v| {
+                u16::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| {
+                    Error::ParseUsbDevice(OptionParserError::InvalidValue(format!("{option}={v}")))
+                })
+            })
+            .transpose()
+    }
+
+    pub fn parse(usb_device: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("hostbus")
+            .add("hostaddr")
+            .add("vendor_id")
+            .add("product_id")
+            .add("id");
+        parser.parse(usb_device).map_err(Error::ParseUsbDevice)?;
+
+        let hostbus = parser.convert("hostbus").map_err(Error::ParseUsbDevice)?;
+        let hostaddr = parser.convert("hostaddr").map_err(Error::ParseUsbDevice)?;
+        let vendor_id = Self::parse_hex_id(&parser, "vendor_id")?;
+        let product_id = Self::parse_hex_id(&parser, "product_id")?;
+        let id = parser.get("id");
+
+        Ok(UsbDeviceConfig {
+            hostbus,
+            hostaddr,
+            vendor_id,
+            product_id,
+            id,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if vm_config.xhci.is_none() {
+            return Err(ValidationError::UsbDeviceRequiresXhci);
+        }
+
+        let bus_addr = self.hostbus.is_some() && self.hostaddr.is_some();
+        let vendor_product = self.vendor_id.is_some() && self.product_id.is_some();
+        let any_bus_addr = self.hostbus.is_some() || self.hostaddr.is_some();
+        let any_vendor_product = self.vendor_id.is_some() || self.product_id.is_some();
+        if !((bus_addr && !any_vendor_product) || (vendor_product && !any_bus_addr)) {
+            return Err(ValidationError::InvalidUsbDeviceAddress);
+        }
+
+        Ok(())
+    }
+
+    /// Host address of the device to pass through.
+    pub fn host_address(&self) -> Option<devices::usb::UsbHostAddress> {
+        match (self.hostbus, self.hostaddr, self.vendor_id, self.product_id) {
+            (Some(bus), Some(addr), None, None) => {
+                Some(devices::usb::UsbHostAddress::BusAddr { bus, addr })
+            }
+            (None, None, Some(vendor_id), Some(product_id)) => {
+                Some(devices::usb::UsbHostAddress::VendorProduct {
+                    vendor_id,
+                    product_id,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl VsockConfig {
+    pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
+        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,pci_bdf=<segment:bus:device.function>\"";
+
+    pub fn parse(vsock: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
             .add("socket")
             .add("cid")
             .add("iommu")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("pci_bdf");
         parser.parse(vsock).map_err(Error::ParseVsock)?;
 
         let socket = parser
@@ -2063,9 +3852,13 @@ impl VsockConfig {
             .map_err(Error::ParseVsock)?
             .ok_or(Error::ParseVsockCidMissing)?;
         let id = parser.get("id");
+        let pci_bdf = parser
+            .convert::<PciBdf>("pci_bdf")
+            .map_err(Error::ParseVsock)?;
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParseVsock)?
+            .or(pci_bdf.map(|bdf| bdf.segment()))
             .unwrap_or_default();
 
         Ok(VsockConfig {
@@ -2074,6 +3867,7 @@ impl VsockConfig {
             iommu,
             id,
             pci_segment,
+            pci_bdf,
         })
     }
 
@@ -2094,6 +3888,29 @@ impl VsockConfig {
     }
 }
 
+impl GuestAgentConfig {
+    pub const SYNTAX: &'static str = "Guest agent parameters, reached through the vsock device \
+        \"port=<agent_vsock_port>,fs_freeze=on|off\"";
+
+    pub fn parse(guest_agent: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("port").add("fs_freeze");
+        parser.parse(guest_agent).map_err(Error::ParseGuestAgent)?;
+
+        let port = parser
+            .convert("port")
+            .map_err(Error::ParseGuestAgent)?
+            .ok_or(Error::ParseGuestAgentPortMissing)?;
+        let fs_freeze = parser
+            .convert::<Toggle>("fs_freeze")
+            .map_err(Error::ParseGuestAgent)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(GuestAgentConfig { port, fs_freeze })
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 impl SgxEpcConfig {
     pub const SYNTAX: &'static str = "SGX EPC parameters \
@@ -2123,7 +3940,8 @@ impl SgxEpcConfig {
 impl NumaConfig {
     pub const SYNTAX: &'static str = "Settings related to a given NUMA node \
         \"guest_numa_id=<node_id>,cpus=<cpus_id>,distances=<list_of_distances_to_destination_nodes>,\
-        memory_zones=<list_of_memory_zones>,sgx_epc_sections=<list_of_sgx_epc_sections>,\
+        latencies=<list_of_latencies_in_ns_to_destination_nodes>,\
+        bandwidths=<list_of_bandwidths_in_mbps_to_destination_nodes>,memory_zones=<list_of_memory_zones>,sgx_epc_sections=<list_of_sgx_epc_sections>,\
         pci_segments=<list_of_pci_segments>\"";
 
     pub fn parse(numa: &str) -> Result<Self> {
@@ -2132,6 +3950,8 @@ impl NumaConfig {
             .add("guest_numa_id")
             .add("cpus")
             .add("distances")
+            .add("latencies")
+            .add("bandwidths")
             .add("memory_zones")
             .add("sgx_epc_sections")
             .add("pci_segments");
@@ -2157,6 +3977,28 @@ impl NumaConfig {
                     })
                     .collect()
             });
+        let latencies = parser
+            .convert::<Tuple<u64, u64>>("latencies")
+            .map_err(Error::ParseNuma)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|(e1, e2)| NumaLatency {
+                        destination: *e1 as u32,
+                        latency: *e2,
+                    })
+                    .collect()
+            });
+        let bandwidths = parser
+            .convert::<Tuple<u64, u64>>("bandwidths")
+            .map_err(Error::ParseNuma)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|(e1, e2)| NumaBandwidth {
+                        destination: *e1 as u32,
+                        bandwidth: *e2,
+                    })
+                    .collect()
+            });
         let memory_zones = parser
             .convert::<StringList>("memory_zones")
             .map_err(Error::ParseNuma)?
@@ -2174,6 +4016,8 @@ impl NumaConfig {
             guest_numa_id,
             cpus,
             distances,
+            latencies,
+            bandwidths,
             memory_zones,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_sections,
@@ -2233,20 +4077,43 @@ pub struct RestoreConfig {
     pub prefault: bool,
     #[serde(default)]
     pub net_fds: Option<Vec<RestoredNetConfig>>,
+    #[serde(default)]
+    pub resync_clock: bool,
+    #[serde(default)]
+    pub clone: bool,
+    #[serde(default)]
+    pub lazy: bool,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 impl RestoreConfig {
     pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
         \nRestore parameters \"source_url=<source_url>,prefault=on|off,\
-        net_fds=<list_of_net_ids_with_their_associated_fds>\" \
+        net_fds=<list_of_net_ids_with_their_associated_fds>,resync_clock=on|off,clone=on|off,lazy=on|off,id=<snapshot_id>\" \
         \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
         \n`prefault` brings memory pages in when enabled (disabled by default) \
         \n`net_fds` is a list of net ids with new file descriptors. \
-        Only net devices backed by FDs directly are needed as input.";
+        Only net devices backed by FDs directly are needed as input. \
+        \n`resync_clock` moves the guest clock forward by the time elapsed since \
+        the snapshot was taken (disabled by default) \
+        \n`clone` maps the memory of the snapshot copy-on-write instead of copying it, \
+        for the snapshot to be used as a template shared by many VMs (disabled by default) \
+        \n`lazy` resumes the VM right away, each memory page being read from the snapshot \
+        when first accessed (disabled by default) \
+        \n`id` restores the snapshot with this identifier from the snapshot tree \
+        found at `source_url`";
 
     pub fn parse(restore: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault").add("net_fds");
+        parser
+            .add("source_url")
+            .add("prefault")
+            .add("net_fds")
+            .add("resync_clock")
+            .add("clone")
+            .add("lazy")
+            .add("id");
         parser.parse(restore).map_err(Error::ParseRestore)?;
 
         let source_url = parser
@@ -2270,18 +4137,52 @@ impl RestoreConfig {
                     })
                     .collect()
             });
+        let resync_clock = parser
+            .convert::<Toggle>("resync_clock")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let clone = parser
+            .convert::<Toggle>("clone")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let lazy = parser
+            .convert::<Toggle>("lazy")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
 
         Ok(RestoreConfig {
             source_url,
             prefault,
             net_fds,
+            resync_clock,
+            clone,
+            lazy,
+            id,
         })
     }
 
+    pub fn memory_restore_mode(&self) -> MemoryRestoreMode {
+        if self.lazy {
+            MemoryRestoreMode::Lazy
+        } else if self.clone {
+            MemoryRestoreMode::Clone
+        } else {
+            MemoryRestoreMode::Copy
+        }
+    }
+
     // Ensure all net devices from 'VmConfig' backed by FDs have a
     // corresponding 'RestoreNetConfig' with a matched 'id' and expected
     // number of FDs.
     pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.lazy && (self.clone || self.prefault) {
+            return Err(ValidationError::RestoreLazyIncompatible);
+        }
+
         let mut restored_net_with_fds = HashMap::new();
         for n in self.net_fds.iter().flatten() {
             assert_eq!(
@@ -2379,44 +4280,527 @@ impl LandlockConfig {
     }
 }
 
-impl VmConfig {
-    fn validate_identifier(
-        id_list: &mut BTreeSet<String>,
-        id: &Option<String>,
-    ) -> ValidationResult<()> {
-        if let Some(id) = id.as_ref() {
-            if id.starts_with("__") {
-                return Err(ValidationError::InvalidIdentifier(id.clone()));
-            }
+impl CgroupConfig {
+    pub const SYNTAX: &'static str = "cgroup v2 resource control parameters \
+        \"path=</path/to/vm/cgroup>,memory_high=<bytes>,io_max=<list_of_io_max_lines>,\
+        vmm_cpu_weight=<weight>,vcpu_cpu_weight=<weight>,iothread_cpu_weight=<weight>\"";
 
-            if !id_list.insert(id.clone()) {
-                return Err(ValidationError::IdentifierNotUnique(id.clone()));
-            }
-        }
+    pub fn parse(cgroup: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("memory_high")
+            .add("io_max")
+            .add("vmm_cpu_weight")
+            .add("vcpu_cpu_weight")
+            .add("iothread_cpu_weight");
+        parser.parse(cgroup).map_err(Error::ParseCgroup)?;
 
-        Ok(())
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseCgroupPathMissing)?;
+        let memory_high = parser
+            .convert::<ByteSized>("memory_high")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| v.0);
+        let io_max = parser
+            .convert::<StringList>("io_max")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| v.0);
+        let vmm_cpu_weight = parser
+            .convert("vmm_cpu_weight")
+            .map_err(Error::ParseCgroup)?;
+        let vcpu_cpu_weight = parser
+            .convert("vcpu_cpu_weight")
+            .map_err(Error::ParseCgroup)?;
+        let iothread_cpu_weight = parser
+            .convert("iothread_cpu_weight")
+            .map_err(Error::ParseCgroup)?;
+
+        Ok(CgroupConfig {
+            path,
+            resources: CgroupResources {
+                memory_high,
+                io_max,
+                vmm_cpu_weight,
+                vcpu_cpu_weight,
+                iothread_cpu_weight,
+            },
+        })
     }
 
-    pub fn backed_by_shared_memory(&self) -> bool {
-        if self.memory.shared || self.memory.hugepages {
-            return true;
+    pub fn validate(&self) -> ValidationResult<()> {
+        // The path is resolved from the working directory of the VMM
+        // otherwise, which the user has no reason to expect.
+        if !self.path.is_absolute() {
+            return Err(ValidationError::InvalidCgroupPath(self.path.clone()));
         }
 
-        if self.memory.size == 0 {
-            for zone in self.memory.zones.as_ref().unwrap() {
-                if !zone.shared && !zone.hugepages {
-                    return false;
-                }
-            }
-            true
-        } else {
-            false
-        }
+        self.resources.validate()
     }
+}
 
-    // Also enables virtio-iommu if the config needs it
-    // Returns the list of unique identifiers provided through the
-    // configuration.
+pub enum ParsePortForwardError {
+    InvalidValue(String),
+}
+
+impl FromStr for PortForwardConfig {
+    type Err = ParsePortForwardError;
+
+    // Parse "[tcp|udp:]<host_port>:<guest_port>", TCP being the default.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || ParsePortForwardError::InvalidValue(s.to_owned());
+        let fields: Vec<&str> = s.split(':').collect();
+        let (protocol, ports) = match fields.as_slice() {
+            [protocol, ports @ ..] if ports.len() == 2 => {
+                let protocol = match protocol.to_lowercase().as_str() {
+                    "tcp" => PortForwardProtocol::Tcp,
+                    "udp" => PortForwardProtocol::Udp,
+                    _ => return Err(invalid()),
+                };
+                (protocol, ports)
+            }
+            ports if ports.len() == 2 => (PortForwardProtocol::Tcp, ports),
+            _ => return Err(invalid()),
+        };
+
+        Ok(PortForwardConfig {
+            protocol,
+            host_port: ports[0].parse().map_err(|_| invalid())?,
+            guest_port: ports[1].parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+pub enum ParseRtcBaseError {
+    InvalidValue(String),
+}
+
+impl FromStr for RtcBase {
+    type Err = ParseRtcBaseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "utc" => Ok(RtcBase::Utc),
+            "localtime" => Ok(RtcBase::Localtime),
+            _ => rtc_datetime_to_unix(s)
+                .map(|_| RtcBase::Datetime(s.to_owned()))
+                .ok_or_else(|| ParseRtcBaseError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+pub enum ParseRtcClockError {
+    InvalidValue(String),
+}
+
+impl FromStr for RtcClock {
+    type Err = ParseRtcClockError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "host" => Ok(RtcClock::Host),
+            "vm" => Ok(RtcClock::Vm),
+            _ => Err(ParseRtcClockError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+// Converts a `YYYY-MM-DDTHH:MM:SS` UTC date and time into seconds since the
+// epoch.
+fn rtc_datetime_to_unix(datetime: &str) -> Option<i64> {
+    let (date, time) = datetime.split_once('T')?;
+    let date = date
+        .split('-')
+        .map(|v| v.parse::<i32>().ok())
+        .collect::<Option<Vec<i32>>>()?;
+    let time = time
+        .split(':')
+        .map(|v| v.parse::<i32>().ok())
+        .collect::<Option<Vec<i32>>>()?;
+    if date.len() != 3
+        || time.len() != 3
+        || date[0] < 1970
+        || !(1..=12).contains(&date[1])
+        || !(1..=31).contains(&date[2])
+        || !(0..=23).contains(&time[0])
+        || !(0..=59).contains(&time[1])
+        || !(0..=59).contains(&time[2])
+    {
+        return None;
+    }
+
+    // SAFETY: tm only contains plain data.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = date[0] - 1900;
+    tm.tm_mon = date[1] - 1;
+    tm.tm_mday = date[2];
+    tm.tm_hour = time[0];
+    tm.tm_min = time[1];
+    tm.tm_sec = time[2];
+    // SAFETY: tm is a valid and initialized struct.
+    let secs = unsafe { libc::timegm(&mut tm) };
+    (secs != -1).then_some(secs as i64)
+}
+
+impl RtcBase {
+    /// Offset of the RTC from the host wall clock, in nanoseconds.
+    pub fn offset_ns(&self) -> i64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        match self {
+            RtcBase::Utc => 0,
+            RtcBase::Localtime => {
+                // https://github.com/rust-lang/libc/issues/1848
+                #[cfg_attr(target_env = "musl", allow(deprecated))]
+                let now = now.as_secs() as libc::time_t;
+                // SAFETY: tm only contains plain data.
+                let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+                // SAFETY: both pointers are valid for the duration of the call.
+                unsafe { libc::localtime_r(&now, &mut tm) };
+                tm.tm_gmtoff * 1_000_000_000
+            }
+            RtcBase::Datetime(datetime) => rtc_datetime_to_unix(datetime)
+                .map(|secs| secs * 1_000_000_000 - now.as_nanos() as i64)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl RtcConfig {
+    pub const SYNTAX: &'static str = "Real time clock parameters \
+        \"base=utc|localtime|<YYYY-MM-DDTHH:MM:SS>,clock=host|vm\"";
+
+    pub fn parse(rtc: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("base").add("clock");
+        parser.parse(rtc).map_err(Error::ParseRtc)?;
+
+        let base = parser
+            .convert("base")
+            .map_err(Error::ParseRtc)?
+            .unwrap_or_default();
+        let clock = parser
+            .convert("clock")
+            .map_err(Error::ParseRtc)?
+            .unwrap_or_default();
+
+        Ok(RtcConfig { base, clock })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        #[cfg(not(target_arch = "x86_64"))]
+        if self.clock == RtcClock::Vm {
+            return Err(ValidationError::RtcClockVmUnsupported);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ParsePanicActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for PanicAction {
+    type Err = ParsePanicActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PanicAction::None),
+            "pause" => Ok(PanicAction::Pause),
+            "coredump" => Ok(PanicAction::Coredump),
+            "restart" => Ok(PanicAction::Restart),
+            "shutdown" => Ok(PanicAction::Shutdown),
+            _ => Err(ParsePanicActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+impl PanicConfig {
+    pub const SYNTAX: &'static str = "Action taken when the guest panics \
+        \"action=none|pause|coredump|restart|shutdown,coredump_file=</path/to/coredump>\"";
+
+    pub fn parse(panic: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("action").add("coredump_file");
+        parser.parse(panic).map_err(Error::ParsePanic)?;
+
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParsePanic)?
+            .unwrap_or_default();
+        let coredump_file = parser.get("coredump_file").map(PathBuf::from);
+
+        Ok(PanicConfig {
+            action,
+            coredump_file,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.action == PanicAction::Coredump {
+            #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+            return Err(ValidationError::PanicCoredumpUnsupported);
+
+            #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+            if self.coredump_file.is_none() {
+                return Err(ValidationError::PanicCoredumpFileMissing);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl FwCfgConfig {
+    pub const SYNTAX: &'static str = "fw_cfg file exposed to the firmware and the guest \
+        \"name=<fw_cfg_file_name>,file=</path/to/content>,string=<content>\"";
+
+    pub fn parse(fw_cfg: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("name").add("file").add("string");
+        parser.parse(fw_cfg).map_err(Error::ParseFwCfg)?;
+
+        let name = parser.get("name").ok_or(Error::ParseFwCfgNameMissing)?;
+        let file = parser.get("file").map(PathBuf::from);
+        let string = parser.get("string");
+
+        Ok(FwCfgConfig { name, file, string })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.name.is_empty() || self.name.len() >= devices::legacy::FW_CFG_MAX_FILE_NAME {
+            return Err(ValidationError::InvalidFwCfgName(self.name.clone()));
+        }
+
+        if self.file.is_some() == self.string.is_some() {
+            return Err(ValidationError::InvalidFwCfgContent(self.name.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl SecurityLabelConfig {
+    pub const SYNTAX: &'static str = "Security labels of the VMM and of the spawned backends \
+        \"selinux_context=<vmm_selinux_context>,selinux_file_context=<created_resources_selinux_context>,\
+        selinux_backend_context=<backends_selinux_context>,apparmor_profile=<vmm_apparmor_profile>,\
+        apparmor_backend_profile=<backends_apparmor_profile>\"";
+
+    pub fn parse(security_label: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("selinux_context")
+            .add("selinux_file_context")
+            .add("selinux_backend_context")
+            .add("apparmor_profile")
+            .add("apparmor_backend_profile");
+        parser
+            .parse(security_label)
+            .map_err(Error::ParseSecurityLabel)?;
+
+        Ok(SecurityLabelConfig {
+            selinux_context: parser.get("selinux_context"),
+            selinux_file_context: parser.get("selinux_file_context"),
+            selinux_backend_context: parser.get("selinux_backend_context"),
+            apparmor_profile: parser.get("apparmor_profile"),
+            apparmor_backend_profile: parser.get("apparmor_backend_profile"),
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        let selinux = self.selinux_context.is_some()
+            || self.selinux_file_context.is_some()
+            || self.selinux_backend_context.is_some();
+        let apparmor = self.apparmor_profile.is_some() || self.apparmor_backend_profile.is_some();
+        if selinux && apparmor {
+            return Err(ValidationError::SecurityLabelSelinuxAndApparmor);
+        }
+
+        Ok(())
+    }
+}
+
+impl CgroupResources {
+    pub fn validate(&self) -> ValidationResult<()> {
+        for weight in [
+            self.vmm_cpu_weight,
+            self.vcpu_cpu_weight,
+            self.iothread_cpu_weight,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !(CGROUP_CPU_WEIGHT_MIN..=CGROUP_CPU_WEIGHT_MAX).contains(&weight) {
+                return Err(ValidationError::InvalidCgroupCpuWeight(weight));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    fn validate_identifier(
+        id_list: &mut BTreeSet<String>,
+        id: &Option<String>,
+    ) -> ValidationResult<()> {
+        if let Some(id) = id.as_ref() {
+            if id.starts_with("__") {
+                return Err(ValidationError::InvalidIdentifier(id.clone()));
+            }
+
+            if !id_list.insert(id.clone()) {
+                return Err(ValidationError::IdentifierNotUnique(id.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_io_affinity(&self, io_affinity: &str) -> ValidationResult<()> {
+        if !self
+            .io_affinities
+            .iter()
+            .flatten()
+            .any(|cfg| cfg.id == io_affinity)
+        {
+            return Err(ValidationError::UnknownIoAffinity(io_affinity.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    /// PCI addresses pinned through the configuration, each paired with the
+    /// PCI segment the device has been placed on.
+    pub fn pinned_pci_bdfs(&self) -> Vec<(PciBdf, u16)> {
+        let pinned = |bdf: Option<PciBdf>, pci_segment: u16| bdf.map(|bdf| (bdf, pci_segment));
+
+        std::iter::empty()
+            .chain(
+                self.disks
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.net
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.fs
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.fs9p
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.i2c
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.pmem
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.devices
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.user_devices
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.vdpa
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.ivshmem
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.nvme
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.cxl
+                    .iter()
+                    .flatten()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .chain(
+                self.vsock
+                    .iter()
+                    .filter_map(|d| pinned(d.pci_bdf, d.pci_segment)),
+            )
+            .collect()
+    }
+
+    fn validate_pci_bdfs(&self) -> ValidationResult<()> {
+        let mut used_bdfs = BTreeSet::new();
+        for (bdf, pci_segment) in self.pinned_pci_bdfs() {
+            if bdf.segment() != pci_segment
+                || bdf.bus() != 0
+                || bdf.device() == 0
+                || bdf.function() != 0
+            {
+                return Err(ValidationError::InvalidPciBdf(bdf));
+            }
+
+            if !used_bdfs.insert(bdf) {
+                return Err(ValidationError::PciBdfReused(bdf));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn backed_by_shared_memory(&self) -> bool {
+        if self.memory.shared || self.memory.hugepages {
+            return true;
+        }
+
+        if self.memory.size == 0 {
+            for zone in self.memory.zones.as_ref().unwrap() {
+                if !zone.shared && !zone.hugepages {
+                    return false;
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    // Also enables virtio-iommu if the config needs it
+    // Returns the list of unique identifiers provided through the
+    // configuration.
     pub fn validate(&mut self) -> ValidationResult<BTreeSet<String>> {
         let mut id_list = BTreeSet::new();
 
@@ -2479,6 +4863,15 @@ impl VmConfig {
             return Err(ValidationError::ConsoleFileMissing);
         }
 
+        // The output of the virtio-console device is not multiplexed.
+        if self.console.log_file.is_some()
+            || self.console.capture_size.is_some()
+            || (self.serial.mode == ConsoleOutputMode::Off
+                && (self.serial.log_file.is_some() || self.serial.capture_size.is_some()))
+        {
+            return Err(ValidationError::ConsoleLogUnsupported);
+        }
+
         if self.cpus.max_vcpus < self.cpus.boot_vcpus {
             return Err(ValidationError::CpusMaxLowerThanBoot);
         }
@@ -2491,12 +4884,22 @@ impl VmConfig {
             }
         }
 
+        if let Some(io_affinities) = &self.io_affinities {
+            for io_affinity in io_affinities {
+                io_affinity.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &Some(io_affinity.id.clone()))?;
+            }
+        }
+
         if let Some(disks) = &self.disks {
             for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
+                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some()
+                    && !disk.out_of_process
+                {
                     return Err(ValidationError::DiskSocketAndPath);
                 }
-                if disk.vhost_user && !self.backed_by_shared_memory() {
+                if (disk.vhost_user || disk.out_of_process) && !self.backed_by_shared_memory() {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
                 if disk.vhost_user && disk.vhost_socket.is_none() {
@@ -2524,7 +4927,9 @@ impl VmConfig {
 
         if let Some(nets) = &self.net {
             for net in nets {
-                if net.vhost_user && !self.backed_by_shared_memory() {
+                if (net.vhost_user || net.out_of_process || net.passt)
+                    && !self.backed_by_shared_memory()
+                {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
                 net.validate(self)?;
@@ -2545,6 +4950,22 @@ impl VmConfig {
             }
         }
 
+        if let Some(fs9ps) = &self.fs9p {
+            for fs9p in fs9ps {
+                fs9p.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &fs9p.id)?;
+            }
+        }
+
+        if let Some(i2cs) = &self.i2c {
+            for i2c in i2cs {
+                i2c.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &i2c.id)?;
+            }
+        }
+
         if let Some(pmems) = &self.pmem {
             for pmem in pmems {
                 pmem.validate(self)?;
@@ -2589,6 +5010,37 @@ impl VmConfig {
             }
         }
 
+        if let Some(boot_size) = self.memory.boot_size {
+            if self.memory.hotplug_method != HotplugMethod::VirtioMem {
+                return Err(ValidationError::MemoryBootSizeWithoutVirtioMem);
+            }
+            if self.memory.hotplugged_size.is_some() {
+                return Err(ValidationError::MemoryBootSizeWithHotpluggedSize);
+            }
+            let max_size = self.memory.size + self.memory.hotplug_size.unwrap_or(0);
+            if boot_size == 0
+                || boot_size > self.memory.size
+                || boot_size >= max_size
+                || self.memory.zones.is_some()
+            {
+                return Err(ValidationError::InvalidMemoryBootSize(boot_size));
+            }
+        }
+
+        if self.memory.guest_memfd {
+            if cfg!(not(target_arch = "x86_64")) {
+                return Err(ValidationError::GuestMemfdUnsupported);
+            }
+            #[cfg(feature = "tdx")]
+            if self.is_tdx_enabled() {
+                return Err(ValidationError::GuestMemfdUnsupported);
+            }
+            #[cfg(feature = "sev_snp")]
+            if self.is_sev_snp_enabled() {
+                return Err(ValidationError::GuestMemfdUnsupported);
+            }
+        }
+
         if let Some(user_devices) = &self.user_devices {
             if !user_devices.is_empty() && !self.backed_by_shared_memory() {
                 return Err(ValidationError::UserDevicesRequireSharedMemory);
@@ -2610,12 +5062,74 @@ impl VmConfig {
             }
         }
 
+        if let Some(ivshmem_devices) = &self.ivshmem {
+            for ivshmem_device in ivshmem_devices {
+                ivshmem_device.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &ivshmem_device.id)?;
+            }
+        }
+
+        if let Some(nvme_controllers) = &self.nvme {
+            for nvme_controller in nvme_controllers {
+                nvme_controller.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &Some(nvme_controller.id.clone()))?;
+            }
+        }
+
+        if let Some(cxl_devices) = &self.cxl {
+            #[cfg(not(target_arch = "x86_64"))]
+            if !cxl_devices.is_empty() {
+                return Err(ValidationError::CxlUnsupported);
+            }
+
+            let mut memory_zones = BTreeSet::new();
+            let mut pci_segments = BTreeSet::new();
+            for cxl_device in cxl_devices {
+                cxl_device.validate(self)?;
+
+                if !memory_zones.insert(&cxl_device.memory_zone) {
+                    return Err(ValidationError::CxlMemoryZoneReused(
+                        cxl_device.memory_zone.clone(),
+                    ));
+                }
+                if !pci_segments.insert(cxl_device.pci_segment) {
+                    return Err(ValidationError::InvalidCxlPciSegment(
+                        cxl_device.pci_segment,
+                    ));
+                }
+
+                Self::validate_identifier(&mut id_list, &cxl_device.id)?;
+            }
+        }
+
+        if let Some(xhci) = &self.xhci {
+            xhci.validate()?;
+        }
+
+        if let Some(usb_devices) = &self.usb_devices {
+            for usb_device in usb_devices {
+                usb_device.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &usb_device.id)?;
+            }
+        }
+
+        if let Some(vnc) = &self.vnc {
+            vnc.validate()?;
+        }
+
         if let Some(vsock) = &self.vsock {
             if [!0, 0, 1, 2].contains(&vsock.cid) {
                 return Err(ValidationError::VsockSpecialCid(vsock.cid));
             }
         }
 
+        if self.guest_agent.is_some() && self.vsock.is_none() {
+            return Err(ValidationError::GuestAgentRequiresVsock);
+        }
+
         if let Some(balloon) = &self.balloon {
             let mut ram_size = self.memory.size;
 
@@ -2631,6 +5145,21 @@ impl VmConfig {
                     ram_size,
                 ));
             }
+
+            if let Some(policy) = &balloon.policy {
+                if let Some(max_guest_ram) = policy.max_guest_ram {
+                    if policy.min_guest_ram > max_guest_ram {
+                        return Err(ValidationError::BalloonPolicyMinAboveMax(
+                            policy.min_guest_ram,
+                            max_guest_ram,
+                        ));
+                    }
+                }
+
+                if policy.interval_ms == 0 {
+                    return Err(ValidationError::InvalidBalloonPolicyInterval);
+                }
+            }
         }
 
         if let Some(devices) = &self.devices {
@@ -2703,6 +5232,10 @@ impl VmConfig {
             }
         }
 
+        if self.numa_placement && self.numa.is_none() {
+            return Err(ValidationError::NumaPlacementWithoutNuma);
+        }
+
         if let Some(zones) = &self.memory.zones {
             for zone in zones.iter() {
                 let id = zone.id.clone();
@@ -2724,19 +5257,44 @@ impl VmConfig {
             }
         }
 
+        self.validate_pci_bdfs()?;
+
         self.platform.as_ref().map(|p| p.validate()).transpose()?;
         self.iommu |= self
             .platform
             .as_ref()
-            .map(|p| p.iommu_segments.is_some())
+            .map(|p| p.iommu_segments.is_some() || p.iommu_hotplug_slots > 0)
             .unwrap_or_default();
 
+        if self.is_virtio_mmio_enabled() {
+            self.validate_virtio_mmio()?;
+        }
+
         if let Some(landlock_rules) = &self.landlock_rules {
             for landlock_rule in landlock_rules {
                 landlock_rule.validate()?;
             }
         }
 
+        self.cgroup.as_ref().map(|c| c.validate()).transpose()?;
+        self.security_label
+            .as_ref()
+            .map(|s| s.validate())
+            .transpose()?;
+        self.rtc.as_ref().map(|r| r.validate()).transpose()?;
+        self.panic.as_ref().map(|p| p.validate()).transpose()?;
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(fw_cfgs) = &self.fw_cfg {
+            let mut names = BTreeSet::new();
+            for fw_cfg in fw_cfgs {
+                fw_cfg.validate()?;
+                if !names.insert(fw_cfg.name.as_str()) {
+                    return Err(ValidationError::DuplicateFwCfgName(fw_cfg.name.clone()));
+                }
+            }
+        }
+
         Ok(id_list)
     }
 
@@ -2751,6 +5309,16 @@ impl VmConfig {
             rate_limit_groups = Some(rate_limit_group_config_list);
         }
 
+        let mut io_affinities: Option<Vec<IoAffinityConfig>> = None;
+        if let Some(io_affinity_list) = &vm_params.io_affinities {
+            let mut io_affinity_config_list = Vec::new();
+            for item in io_affinity_list.iter() {
+                let io_affinity_config = IoAffinityConfig::parse(item)?;
+                io_affinity_config_list.push(io_affinity_config);
+            }
+            io_affinities = Some(io_affinity_config_list);
+        }
+
         let mut disks: Option<Vec<DiskConfig>> = None;
         if let Some(disk_list) = &vm_params.disks {
             let mut disk_config_list = Vec::new();
@@ -2792,6 +5360,24 @@ impl VmConfig {
             fs = Some(fs_config_list);
         }
 
+        let mut fs9p: Option<Vec<Fs9pConfig>> = None;
+        if let Some(fs9p_list) = &vm_params.fs9p {
+            let mut fs9p_config_list = Vec::new();
+            for item in fs9p_list.iter() {
+                fs9p_config_list.push(Fs9pConfig::parse(item)?);
+            }
+            fs9p = Some(fs9p_config_list);
+        }
+
+        let mut i2c: Option<Vec<I2cConfig>> = None;
+        if let Some(i2c_list) = &vm_params.i2c {
+            let mut i2c_config_list = Vec::new();
+            for item in i2c_list.iter() {
+                i2c_config_list.push(I2cConfig::parse(item)?);
+            }
+            i2c = Some(i2c_config_list);
+        }
+
         let mut pmem: Option<Vec<PmemConfig>> = None;
         if let Some(pmem_list) = &vm_params.pmem {
             let mut pmem_config_list = Vec::new();
@@ -2837,12 +5423,67 @@ impl VmConfig {
             vdpa = Some(vdpa_config_list);
         }
 
+        let mut ivshmem: Option<Vec<IvshmemConfig>> = None;
+        if let Some(ivshmem_list) = &vm_params.ivshmem {
+            let mut ivshmem_config_list = Vec::new();
+            for item in ivshmem_list.iter() {
+                let ivshmem_config = IvshmemConfig::parse(item)?;
+                ivshmem_config_list.push(ivshmem_config);
+            }
+            ivshmem = Some(ivshmem_config_list);
+        }
+
+        let mut nvme: Option<Vec<NvmeConfig>> = None;
+        if let Some(nvme_list) = &vm_params.nvme {
+            let mut nvme_config_list = Vec::new();
+            for item in nvme_list.iter() {
+                let nvme_config = NvmeConfig::parse(item)?;
+                nvme_config_list.push(nvme_config);
+            }
+            nvme = Some(nvme_config_list);
+        }
+
+        let mut cxl: Option<Vec<CxlConfig>> = None;
+        if let Some(cxl_list) = &vm_params.cxl {
+            let mut cxl_config_list = Vec::new();
+            for item in cxl_list.iter() {
+                let cxl_config = CxlConfig::parse(item)?;
+                cxl_config_list.push(cxl_config);
+            }
+            cxl = Some(cxl_config_list);
+        }
+
+        let mut xhci: Option<XhciConfig> = None;
+        if let Some(xhci_params) = &vm_params.xhci {
+            xhci = Some(XhciConfig::parse(xhci_params)?);
+        }
+
+        let mut usb_devices: Option<Vec<UsbDeviceConfig>> = None;
+        if let Some(usb_list) = &vm_params.usb_devices {
+            let mut usb_config_list = Vec::new();
+            for item in usb_list.iter() {
+                let usb_config = UsbDeviceConfig::parse(item)?;
+                usb_config_list.push(usb_config);
+            }
+            usb_devices = Some(usb_config_list);
+        }
+
+        let mut vnc: Option<VncConfig> = None;
+        if let Some(vnc_params) = &vm_params.vnc {
+            vnc = Some(VncConfig::parse(vnc_params)?);
+        }
+
         let mut vsock: Option<VsockConfig> = None;
         if let Some(vs) = &vm_params.vsock {
             let vsock_config = VsockConfig::parse(vs)?;
             vsock = Some(vsock_config);
         }
 
+        let guest_agent = vm_params
+            .guest_agent
+            .map(GuestAgentConfig::parse)
+            .transpose()?;
+
         let mut pci_segments: Option<Vec<PciSegmentConfig>> = None;
         if let Some(pci_segment_list) = &vm_params.pci_segments {
             let mut pci_segment_config_list = Vec::new();
@@ -2886,7 +5527,8 @@ impl VmConfig {
         let payload_present =
             vm_params.kernel.is_some() || vm_params.firmware.is_some() || vm_params.igvm.is_some();
 
-        let payload = if payload_present {
+        // The payload may be partially described by the configuration file.
+        let payload = if payload_present || vm_params.config_file.is_some() {
             Some(PayloadConfig {
                 kernel: vm_params.kernel.map(PathBuf::from),
                 initramfs: vm_params.initramfs.map(PathBuf::from),
@@ -2922,16 +5564,38 @@ impl VmConfig {
             );
         }
 
+        let cgroup = vm_params.cgroup.map(CgroupConfig::parse).transpose()?;
+        let security_label = vm_params
+            .security_label
+            .map(SecurityLabelConfig::parse)
+            .transpose()?;
+        let rtc = vm_params.rtc.map(RtcConfig::parse).transpose()?;
+        let panic = vm_params.panic.map(PanicConfig::parse).transpose()?;
+
+        #[cfg(target_arch = "x86_64")]
+        let fw_cfg = vm_params
+            .fw_cfg
+            .map(|fw_cfg_list| {
+                fw_cfg_list
+                    .iter()
+                    .map(|item| FwCfgConfig::parse(item))
+                    .collect::<Result<Vec<FwCfgConfig>>>()
+            })
+            .transpose()?;
+
         let mut config = VmConfig {
             cpus: CpusConfig::parse(vm_params.cpus)?,
             memory: MemoryConfig::parse(vm_params.memory, vm_params.memory_zones)?,
             payload,
             rate_limit_groups,
+            io_affinities,
             disks,
             net,
             rng,
             balloon,
             fs,
+            fs9p,
+            i2c,
             pmem,
             serial,
             console,
@@ -2940,28 +5604,159 @@ impl VmConfig {
             devices,
             user_devices,
             vdpa,
+            ivshmem,
+            nvme,
+            cxl,
+            xhci,
+            usb_devices,
+            vnc,
             vsock,
+            guest_agent,
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol,
             pvpanic: vm_params.pvpanic,
+            vmclock: vm_params.vmclock,
+            panic,
             iommu: false, // updated in VmConfig::validate()
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
             numa,
+            numa_placement: vm_params.numa_placement,
+            numa_placed: None,
             watchdog: vm_params.watchdog,
             #[cfg(feature = "guest_debug")]
             gdb,
             pci_segments,
             platform,
             tpm,
+            rtc,
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg,
+            cgroup,
+            security_label,
             preserved_fds: None,
             landlock_enable: vm_params.landlock_enable,
             landlock_rules,
         };
+        if let Some(config_file) = vm_params.config_file {
+            config =
+                VmConfig::from_file(Path::new(config_file))?.merge(config, &vm_params.defaulted);
+        }
         config.validate().map_err(Error::Validation)?;
         Ok(config)
     }
 
+    /// Loads a complete VM configuration from a TOML or JSON file,
+    /// following the same schema as the `vm.create` API.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::ReadConfigFile(path.to_path_buf(), e))?;
+        let parse_error = |e: String| Error::ParseConfigFile(path.to_path_buf(), e);
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| parse_error(e.to_string())),
+            Some("json") => serde_json::from_str(&content).map_err(|e| parse_error(e.to_string())),
+            _ => Err(Error::UnknownConfigFileFormat(path.to_path_buf())),
+        }
+    }
+
+    // Overrides the configuration with the one built from the command line,
+    // each option provided replacing the matching part of the configuration.
+    fn merge(mut self, cli: VmConfig, defaulted: &[&str]) -> Self {
+        let provided = |arg| !defaulted.contains(&arg);
+
+        if provided("cpus") {
+            self.cpus = cli.cpus;
+        }
+        let zones = self.memory.zones.take();
+        if provided("memory") {
+            self.memory = cli.memory;
+        } else {
+            self.memory.zones = cli.memory.zones;
+        }
+        self.memory.zones = self.memory.zones.take().or(zones);
+        if provided("rng") {
+            self.rng = cli.rng;
+        }
+        if provided("serial") {
+            self.serial = cli.serial;
+        }
+        if provided("console") {
+            self.console = cli.console;
+        }
+        #[cfg(target_arch = "x86_64")]
+        if provided("debug-console") {
+            self.debug_console = cli.debug_console;
+        }
+
+        self.payload = match (self.payload.take(), cli.payload) {
+            (Some(payload), Some(cli_payload)) => Some(PayloadConfig {
+                firmware: cli_payload.firmware.or(payload.firmware),
+                kernel: cli_payload.kernel.or(payload.kernel),
+                cmdline: cli_payload.cmdline.or(payload.cmdline),
+                initramfs: cli_payload.initramfs.or(payload.initramfs),
+                #[cfg(feature = "igvm")]
+                igvm: cli_payload.igvm.or(payload.igvm),
+                #[cfg(feature = "sev_snp")]
+                host_data: cli_payload.host_data.or(payload.host_data),
+            }),
+            (payload, cli_payload) => cli_payload.or(payload),
+        };
+
+        self.rate_limit_groups = cli.rate_limit_groups.or(self.rate_limit_groups.take());
+        self.io_affinities = cli.io_affinities.or(self.io_affinities.take());
+        self.disks = cli.disks.or(self.disks.take());
+        self.net = cli.net.or(self.net.take());
+        self.balloon = cli.balloon.or(self.balloon.take());
+        self.fs = cli.fs.or(self.fs.take());
+        self.fs9p = cli.fs9p.or(self.fs9p.take());
+        self.i2c = cli.i2c.or(self.i2c.take());
+        self.pmem = cli.pmem.or(self.pmem.take());
+        self.devices = cli.devices.or(self.devices.take());
+        self.user_devices = cli.user_devices.or(self.user_devices.take());
+        self.vdpa = cli.vdpa.or(self.vdpa.take());
+        self.ivshmem = cli.ivshmem.or(self.ivshmem.take());
+        self.nvme = cli.nvme.or(self.nvme.take());
+        self.cxl = cli.cxl.or(self.cxl.take());
+        self.xhci = cli.xhci.or(self.xhci.take());
+        self.usb_devices = cli.usb_devices.or(self.usb_devices.take());
+        self.vnc = cli.vnc.or(self.vnc.take());
+        self.vsock = cli.vsock.or(self.vsock.take());
+        self.guest_agent = cli.guest_agent.or(self.guest_agent.take());
+        #[cfg(feature = "pvmemcontrol")]
+        {
+            self.pvmemcontrol = cli.pvmemcontrol.or(self.pvmemcontrol.take());
+        }
+        self.pvpanic |= cli.pvpanic;
+        self.vmclock |= cli.vmclock;
+        self.panic = cli.panic.or(self.panic.take());
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.sgx_epc = cli.sgx_epc.or(self.sgx_epc.take());
+        }
+        self.numa = cli.numa.or(self.numa.take());
+        self.numa_placement |= cli.numa_placement;
+        self.watchdog |= cli.watchdog;
+        #[cfg(feature = "guest_debug")]
+        {
+            self.gdb |= cli.gdb;
+        }
+        self.pci_segments = cli.pci_segments.or(self.pci_segments.take());
+        self.platform = cli.platform.or(self.platform.take());
+        self.tpm = cli.tpm.or(self.tpm.take());
+        self.rtc = cli.rtc.or(self.rtc.take());
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.fw_cfg = cli.fw_cfg.or(self.fw_cfg.take());
+        }
+        self.cgroup = cli.cgroup.or(self.cgroup.take());
+        self.security_label = cli.security_label.or(self.security_label.take());
+        self.landlock_enable |= cli.landlock_enable;
+        self.landlock_rules = cli.landlock_rules.or(self.landlock_rules.take());
+
+        self
+    }
+
     pub fn remove_device(&mut self, id: &str) -> bool {
         let mut removed = false;
 
@@ -2993,6 +5788,20 @@ impl VmConfig {
             removed |= fs.len() != len;
         }
 
+        // Remove if 9P fs device
+        if let Some(fs9p) = self.fs9p.as_mut() {
+            let len = fs9p.len();
+            fs9p.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= fs9p.len() != len;
+        }
+
+        // Remove if I2C adapter
+        if let Some(i2c) = self.i2c.as_mut() {
+            let len = i2c.len();
+            i2c.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= i2c.len() != len;
+        }
+
         // Remove if net device
         if let Some(net) = self.net.as_mut() {
             let len = net.len();
@@ -3014,6 +5823,13 @@ impl VmConfig {
             removed |= vdpa.len() != len;
         }
 
+        // Remove if USB device
+        if let Some(usb_devices) = self.usb_devices.as_mut() {
+            let len = usb_devices.len();
+            usb_devices.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= usb_devices.len() != len;
+        }
+
         // Remove if vsock device
         if let Some(vsock) = self.vsock.as_ref() {
             if vsock.id.as_ref().map(|id| id.as_ref()) == Some(id) {
@@ -3040,6 +5856,62 @@ impl VmConfig {
         self.preserved_fds = Some(fds);
     }
 
+    pub fn is_vmbus_relay_enabled(&self) -> bool {
+        self.platform
+            .as_ref()
+            .is_some_and(|p| p.vmbus_relay.is_some())
+    }
+
+    pub fn is_virtio_mmio_enabled(&self) -> bool {
+        self.platform
+            .as_ref()
+            .map(|p| p.virtio_mmio)
+            .unwrap_or(false)
+    }
+
+    // The virtio-mmio devices can't be placed behind the virtual IOMMU, and
+    // only offer a single interrupt which rules out the devices relying on
+    // the interrupt notifiers (vhost-user and vDPA).
+    fn validate_virtio_mmio(&self) -> ValidationResult<()> {
+        let unsupported = |s: &str| Err(ValidationError::VirtioMmioUnsupported(s.to_owned()));
+
+        if self.iommu {
+            return unsupported("iommu");
+        }
+
+        if let Some(disks) = &self.disks {
+            for disk in disks {
+                if disk.vhost_user || disk.out_of_process {
+                    return unsupported("vhost-user disk");
+                }
+                if disk.transitional {
+                    return unsupported("transitional disk");
+                }
+            }
+        }
+
+        if let Some(nets) = &self.net {
+            for net in nets {
+                if net.vhost_user || net.out_of_process {
+                    return unsupported("vhost-user net");
+                }
+                if net.transitional {
+                    return unsupported("transitional net");
+                }
+            }
+        }
+
+        if self.fs.is_some() {
+            return unsupported("virtio-fs");
+        }
+
+        if self.vdpa.is_some() {
+            return unsupported("vDPA");
+        }
+
+        Ok(())
+    }
+
     #[cfg(feature = "tdx")]
     pub fn is_tdx_enabled(&self) -> bool {
         self.platform.as_ref().map(|p| p.tdx).unwrap_or(false)
@@ -3058,6 +5930,7 @@ impl Clone for VmConfig {
             memory: self.memory.clone(),
             payload: self.payload.clone(),
             rate_limit_groups: self.rate_limit_groups.clone(),
+            io_affinities: self.io_affinities.clone(),
             disks: self.disks.clone(),
             net: self.net.clone(),
             rng: self.rng.clone(),
@@ -3065,6 +5938,8 @@ impl Clone for VmConfig {
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol: self.pvmemcontrol.clone(),
             fs: self.fs.clone(),
+            fs9p: self.fs9p.clone(),
+            i2c: self.i2c.clone(),
             pmem: self.pmem.clone(),
             serial: self.serial.clone(),
             console: self.console.clone(),
@@ -3073,13 +5948,26 @@ impl Clone for VmConfig {
             devices: self.devices.clone(),
             user_devices: self.user_devices.clone(),
             vdpa: self.vdpa.clone(),
+            ivshmem: self.ivshmem.clone(),
+            nvme: self.nvme.clone(),
+            cxl: self.cxl.clone(),
+            xhci: self.xhci.clone(),
+            usb_devices: self.usb_devices.clone(),
+            vnc: self.vnc.clone(),
             vsock: self.vsock.clone(),
+            guest_agent: self.guest_agent.clone(),
+            panic: self.panic.clone(),
             #[cfg(target_arch = "x86_64")]
             sgx_epc: self.sgx_epc.clone(),
             numa: self.numa.clone(),
             pci_segments: self.pci_segments.clone(),
             platform: self.platform.clone(),
             tpm: self.tpm.clone(),
+            rtc: self.rtc.clone(),
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg: self.fw_cfg.clone(),
+            cgroup: self.cgroup.clone(),
+            security_label: self.security_label.clone(),
             preserved_fds: self
                 .preserved_fds
                 .as_ref()
@@ -3174,6 +6062,20 @@ mod tests {
                 ..Default::default()
             },
         );
+        assert_eq!(
+            CpusConfig::parse("boot=1,affinity=[0@[2]],affinity_fallback=numa")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                affinity: Some(vec![CpuAffinity {
+                    vcpu: 0,
+                    host_cpus: vec![2],
+                }]),
+                affinity_fallback: CpuAffinityFallback::Numa,
+                ..Default::default()
+            },
+        );
+        assert!(CpusConfig::parse("boot=1,affinity_fallback=sibling").is_err());
 
         Ok(())
     }
@@ -3209,6 +6111,21 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,dirty_ring=on", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                dirty_ring: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("guest_memfd=on", None)?,
+            MemoryConfig {
+                guest_memfd: true,
+                ..Default::default()
+            }
+        );
         assert_eq!(
             MemoryConfig::parse("hotplug_method=acpi", None)?,
             MemoryConfig {
@@ -3230,6 +6147,19 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse(
+                "size=8G,hotplug_method=virtio-mem,hotplug_size=8G,boot_size=512M",
+                None
+            )?,
+            MemoryConfig {
+                size: 8 << 30,
+                hotplug_size: Some(8 << 30),
+                boot_size: Some(512 << 20),
+                hotplug_method: HotplugMethod::VirtioMem,
+                ..Default::default()
+            }
+        );
         assert_eq!(
             MemoryConfig::parse("hugepages=on,size=1G,hugepage_size=2M", None)?,
             MemoryConfig {
@@ -3239,6 +6169,26 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("size=0", Some(vec!["id=mem0,size=1G,mlock=on"]))?,
+            MemoryConfig {
+                size: 0,
+                zones: Some(vec![MemoryZoneConfig {
+                    id: "mem0".to_string(),
+                    size: 1 << 30,
+                    file: None,
+                    shared: false,
+                    hugepages: false,
+                    hugepage_size: None,
+                    host_numa_node: None,
+                    hotplug_size: None,
+                    hotplugged_size: None,
+                    prefault: false,
+                    mlock: true,
+                }]),
+                ..Default::default()
+            }
+        );
         Ok(())
     }
 
@@ -3275,6 +6225,27 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_io_affinity_parsing() -> Result<()> {
+        assert_eq!(
+            IoAffinityConfig::parse("id=io0,host_cpus=[2-3]")?,
+            IoAffinityConfig {
+                id: "io0".to_string(),
+                num_sets: 1,
+                host_cpus: vec![2, 3],
+            }
+        );
+        assert_eq!(
+            IoAffinityConfig::parse("id=io0,num_sets=2,host_cpus=[2,4-5]")?,
+            IoAffinityConfig {
+                id: "io0".to_string(),
+                num_sets: 2,
+                host_cpus: vec![2, 4, 5],
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_pci_segment_parsing() -> Result<()> {
         assert_eq!(
@@ -3315,6 +6286,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_platform_parsing() -> Result<()> {
+        assert_eq!(
+            PlatformConfig::parse("vmbus_relay=/tmp/vmbus.sock")?,
+            PlatformConfig {
+                num_pci_segments: DEFAULT_NUM_PCI_SEGMENTS,
+                vmbus_relay: Some(PathBuf::from("/tmp/vmbus.sock")),
+                ..platform_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_bdf_parsing() -> Result<()> {
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,pci_bdf=0000:00:05.0")?,
+            DiskConfig {
+                pci_bdf: Some(PciBdf::new(0, 0, 5, 0)),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,pci_bdf=0001:00:1f.0")?,
+            DiskConfig {
+                pci_segment: 1,
+                pci_bdf: Some(PciBdf::new(1, 0, 0x1f, 0)),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,pci_segment=1,pci_bdf=0001:00:03.0")?,
+            DeviceConfig {
+                pci_segment: 1,
+                pci_bdf: Some(PciBdf::new(1, 0, 3, 0)),
+                ..device_fixture()
+            }
+        );
+        DiskConfig::parse("path=/path/to_file,pci_bdf=0000:00:05").unwrap_err();
+        DiskConfig::parse("path=/path/to_file,pci_bdf=00:05.0").unwrap_err();
+        DiskConfig::parse("path=/path/to_file,pci_bdf=0000:00:20.0").unwrap_err();
+        DiskConfig::parse("path=/path/to_file,pci_bdf=0000:00:05.8").unwrap_err();
+
+        Ok(())
+    }
+
     fn disk_fixture() -> DiskConfig {
         DiskConfig {
             path: Some(PathBuf::from("/path/to_file")),
@@ -3325,14 +6343,29 @@ mod tests {
             queue_size: 128,
             vhost_user: false,
             vhost_socket: None,
+            out_of_process: false,
+            exec: None,
             id: None,
             disable_io_uring: false,
             disable_aio: false,
             rate_limit_group: None,
             rate_limiter_config: None,
             pci_segment: 0,
+            pci_bdf: None,
             serial: None,
             queue_affinity: None,
+            io_affinity: None,
+            busy_poll_us: None,
+            io_uring_event_loop: false,
+            coalesce_us: None,
+            completion_poll_us: None,
+            thread_pool_size: None,
+            thread_pool_queue_depth: None,
+            transitional: false,
+            overlay: false,
+            key_file: None,
+            key: None,
+            nvme: None,
         }
     }
 
@@ -3342,6 +6375,56 @@ mod tests {
             DiskConfig::parse("path=/path/to_file")?,
             DiskConfig { ..disk_fixture() }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,busy_poll_us=50")?,
+            DiskConfig {
+                busy_poll_us: Some(50),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_uring_event_loop=on")?,
+            DiskConfig {
+                io_uring_event_loop: true,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,completion_poll_us=20")?,
+            DiskConfig {
+                completion_poll_us: Some(20),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,thread_pool_size=4,thread_pool_queue_depth=64")?,
+            DiskConfig {
+                thread_pool_size: Some(4),
+                thread_pool_queue_depth: Some(64),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,transitional=on")?,
+            DiskConfig {
+                transitional: true,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,overlay=on")?,
+            DiskConfig {
+                overlay: true,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_file=/path/to_key")?,
+            DiskConfig {
+                key_file: Some(PathBuf::from("/path/to_key")),
+                ..disk_fixture()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,id=mydisk0")?,
             DiskConfig {
@@ -3358,6 +6441,13 @@ mod tests {
                 ..disk_fixture()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,out_of_process=on")?,
+            DiskConfig {
+                out_of_process: true,
+                ..disk_fixture()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,iommu=on")?,
             DiskConfig {
@@ -3433,6 +6523,9 @@ mod tests {
     fn net_fixture() -> NetConfig {
         NetConfig {
             tap: None,
+            macvtap: None,
+            xdp: None,
+            xdp_queue: 0,
             ip: Ipv4Addr::new(192, 168, 249, 1),
             mask: Ipv4Addr::new(255, 255, 255, 0),
             mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
@@ -3444,13 +6537,26 @@ mod tests {
             vhost_user: false,
             vhost_socket: None,
             vhost_mode: VhostMode::Client,
+            out_of_process: false,
+            exec: None,
             id: None,
             fds: None,
             rate_limiter_config: None,
             pci_segment: 0,
+            pci_bdf: None,
             offload_tso: true,
             offload_ufo: true,
             offload_csum: true,
+            disable_io_uring: false,
+            io_uring_registered_buffers: false,
+            io_affinity: None,
+            busy_poll_us: None,
+            io_uring_event_loop: false,
+            coalesce_us: None,
+            transitional: false,
+            passt: false,
+            port_forward: None,
+            vhost_net: false,
         }
     }
 
@@ -3470,6 +6576,22 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,coalesce_us=100")?,
+            NetConfig {
+                coalesce_us: Some(100),
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,transitional=on")?,
+            NetConfig {
+                transitional: true,
+                ..net_fixture()
+            }
+        );
+
         assert_eq!(
             NetConfig::parse(
                 "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
@@ -3493,6 +6615,14 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,out_of_process=on")?,
+            NetConfig {
+                out_of_process: true,
+                ..net_fixture()
+            }
+        );
+
         assert_eq!(
             NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
             NetConfig {
@@ -3504,26 +6634,78 @@ mod tests {
         );
 
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=[3,7],num_queues=4")?,
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,macvtap=macvtap0")?,
             NetConfig {
-                host_mac: None,
-                fds: Some(vec![3, 7]),
-                num_queues: 4,
+                macvtap: Some("macvtap0".to_owned()),
                 ..net_fixture()
             }
         );
 
-        Ok(())
-    }
-
-    #[test]
-    fn test_parse_rng() -> Result<()> {
-        assert_eq!(RngConfig::parse("")?, RngConfig::default());
         assert_eq!(
-            RngConfig::parse("src=/dev/random")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
-                ..Default::default()
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,xdp=eth1,xdp_queue=4,num_queues=4"
+            )?,
+            NetConfig {
+                xdp: Some("eth1".to_owned()),
+                xdp_queue: 4,
+                num_queues: 4,
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,passt=on,port_forward=[2222:22,udp:5353:53]"
+            )?,
+            NetConfig {
+                passt: true,
+                port_forward: Some(vec![
+                    PortForwardConfig {
+                        protocol: PortForwardProtocol::Tcp,
+                        host_port: 2222,
+                        guest_port: 22,
+                    },
+                    PortForwardConfig {
+                        protocol: PortForwardProtocol::Udp,
+                        host_port: 5353,
+                        guest_port: 53,
+                    },
+                ]),
+                ..net_fixture()
+            }
+        );
+        assert!(NetConfig::parse("passt=on,port_forward=[sctp:1:2]").is_err());
+        assert!(NetConfig::parse("passt=on,port_forward=[tcp:70000:22]").is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=[3,7],num_queues=4")?,
+            NetConfig {
+                host_mac: None,
+                fds: Some(vec![3, 7]),
+                num_queues: 4,
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_net=on")?,
+            NetConfig {
+                vhost_net: true,
+                ..net_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rng() -> Result<()> {
+        assert_eq!(RngConfig::parse("")?, RngConfig::default());
+        assert_eq!(
+            RngConfig::parse("src=/dev/random")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -3543,14 +6725,58 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_balloon() -> Result<()> {
+        assert_eq!(
+            BalloonConfig::parse("size=1G")?,
+            BalloonConfig {
+                size: 1 << 30,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+                policy: None,
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse("size=0,auto=on")?,
+            BalloonConfig {
+                size: 0,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+                policy: Some(BalloonPolicyConfig::default()),
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse(
+                "size=0,auto=on,auto_min=1G,auto_max=4G,auto_reserve=512M,auto_hysteresis=128M,auto_interval_ms=1000"
+            )?,
+            BalloonConfig {
+                size: 0,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+                policy: Some(BalloonPolicyConfig {
+                    min_guest_ram: 1 << 30,
+                    max_guest_ram: Some(4 << 30),
+                    free_reserve: 512 << 20,
+                    hysteresis: 128 << 20,
+                    interval_ms: 1000,
+                }),
+            }
+        );
+        assert_eq!(BalloonConfig::parse("size=0,auto_min=1G")?.policy, None);
+        Ok(())
+    }
+
     fn fs_fixture() -> FsConfig {
         FsConfig {
             socket: PathBuf::from("/tmp/sock"),
             tag: "mytag".to_owned(),
             num_queues: 1,
             queue_size: 1024,
+            shared_dir: None,
+            exec: None,
             id: None,
             pci_segment: 0,
+            pci_bdf: None,
         }
     }
 
@@ -3569,6 +6795,88 @@ mod tests {
                 ..fs_fixture()
             }
         );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,exec=[/usr/bin/virtiofsd,--socket-path=/tmp/sock,--shared-dir=/tmp/shared dir]")?,
+            FsConfig {
+                exec: Some(vec![
+                    "/usr/bin/virtiofsd".to_owned(),
+                    "--socket-path=/tmp/sock".to_owned(),
+                    "--shared-dir=/tmp/shared dir".to_owned(),
+                ]),
+                ..fs_fixture()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,shared_dir=/tmp/shared")?,
+            FsConfig {
+                shared_dir: Some(PathBuf::from("/tmp/shared")),
+                ..fs_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs9p_parsing() -> Result<()> {
+        // "tag" and "path" are mandatory
+        assert!(Fs9pConfig::parse("").is_err());
+        assert!(Fs9pConfig::parse("tag=mytag").is_err());
+        assert!(Fs9pConfig::parse("path=/tmp/shared").is_err());
+        assert_eq!(
+            Fs9pConfig::parse("tag=mytag,path=/tmp/shared")?,
+            Fs9pConfig {
+                tag: "mytag".to_owned(),
+                path: PathBuf::from("/tmp/shared"),
+                msize: 512 << 10,
+                id: None,
+                pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        assert_eq!(
+            Fs9pConfig::parse("tag=mytag,path=/tmp/shared,msize=64K,id=myfs9p0")?,
+            Fs9pConfig {
+                tag: "mytag".to_owned(),
+                path: PathBuf::from("/tmp/shared"),
+                msize: 64 << 10,
+                id: Some("myfs9p0".to_owned()),
+                pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        assert!(Fs9pConfig::parse("tag=mytag,path=/tmp/shared,msize=8G").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_i2c_parsing() -> Result<()> {
+        // "path" and "addresses" are required
+        assert!(I2cConfig::parse("").is_err());
+        assert!(I2cConfig::parse("path=/dev/i2c-1").is_err());
+        assert!(I2cConfig::parse("addresses=[0x48]").is_err());
+        assert_eq!(
+            I2cConfig::parse("path=/dev/i2c-1,addresses=[0x48,0x50]")?,
+            I2cConfig {
+                path: PathBuf::from("/dev/i2c-1"),
+                addresses: vec![0x48, 0x50],
+                id: None,
+                pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        assert_eq!(
+            I2cConfig::parse("path=/dev/i2c-1,addresses=[1d],id=myi2c0,pci_segment=1")?,
+            I2cConfig {
+                path: PathBuf::from("/dev/i2c-1"),
+                addresses: vec![0x1d],
+                id: Some("myi2c0".to_owned()),
+                pci_segment: 1,
+                pci_bdf: None,
+            }
+        );
+        assert!(I2cConfig::parse("path=/dev/i2c-1,addresses=[0xzz]").is_err());
 
         Ok(())
     }
@@ -3581,6 +6889,9 @@ mod tests {
             discard_writes: false,
             id: None,
             pci_segment: 0,
+            pci_bdf: None,
+            nvdimm: false,
+            label_size: 0,
         }
     }
 
@@ -3608,6 +6919,14 @@ mod tests {
                 ..pmem_fixture()
             }
         );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,nvdimm=on,label_size=128K")?,
+            PmemConfig {
+                nvdimm: true,
+                label_size: 128 << 10,
+                ..pmem_fixture()
+            }
+        );
 
         Ok(())
     }
@@ -3623,6 +6942,8 @@ mod tests {
                 iommu: false,
                 file: None,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             }
         );
         assert_eq!(
@@ -3632,6 +6953,8 @@ mod tests {
                 iommu: false,
                 file: None,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             }
         );
         assert_eq!(
@@ -3641,6 +6964,8 @@ mod tests {
                 iommu: false,
                 file: None,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             }
         );
         assert_eq!(
@@ -3650,6 +6975,8 @@ mod tests {
                 iommu: false,
                 file: None,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             }
         );
         assert_eq!(
@@ -3659,6 +6986,8 @@ mod tests {
                 iommu: false,
                 file: Some(PathBuf::from("/tmp/console")),
                 socket: None,
+                log_file: None,
+                capture_size: None,
             }
         );
         assert_eq!(
@@ -3668,6 +6997,8 @@ mod tests {
                 iommu: true,
                 file: None,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             }
         );
         assert_eq!(
@@ -3677,6 +7008,19 @@ mod tests {
                 iommu: true,
                 file: Some(PathBuf::from("/tmp/console")),
                 socket: None,
+                log_file: None,
+                capture_size: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty,log_file=/tmp/serial.log,capture_size=64K")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                socket: None,
+                log_file: Some(PathBuf::from("/tmp/serial.log")),
+                capture_size: Some(64 << 10),
             }
         );
         assert_eq!(
@@ -3686,6 +7030,8 @@ mod tests {
                 iommu: true,
                 file: None,
                 socket: Some(PathBuf::from("/tmp/serial.sock")),
+                log_file: None,
+                capture_size: None,
             }
         );
         Ok(())
@@ -3697,6 +7043,7 @@ mod tests {
             id: None,
             iommu: false,
             pci_segment: 0,
+            pci_bdf: None,
             x_nv_gpudirect_clique: None,
         }
     }
@@ -3737,6 +7084,7 @@ mod tests {
             iommu: false,
             id: None,
             pci_segment: 0,
+            pci_bdf: None,
         }
     }
 
@@ -3756,6 +7104,189 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_ivshmem_parsing() -> Result<()> {
+        // path is required
+        assert!(IvshmemConfig::parse("").is_err());
+        assert!(IvshmemConfig::parse("size=1M").is_err());
+        assert_eq!(
+            IvshmemConfig::parse("path=/dev/shm/ivshmem")?,
+            IvshmemConfig {
+                path: PathBuf::from("/dev/shm/ivshmem"),
+                size: None,
+                id: None,
+                pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        assert_eq!(
+            IvshmemConfig::parse("path=/dev/shm/ivshmem,size=16M,id=shm0,pci_segment=1")?,
+            IvshmemConfig {
+                path: PathBuf::from("/dev/shm/ivshmem"),
+                size: Some(16 << 20),
+                id: Some("shm0".to_owned()),
+                pci_segment: 1,
+                pci_bdf: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_nvme_parsing() -> Result<()> {
+        // id is required
+        assert!(NvmeConfig::parse("").is_err());
+        assert!(NvmeConfig::parse("num_queues=4").is_err());
+        assert_eq!(
+            NvmeConfig::parse("id=nvme0")?,
+            NvmeConfig {
+                id: "nvme0".to_owned(),
+                serial: None,
+                num_queues: 1,
+                pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        assert_eq!(
+            NvmeConfig::parse("id=nvme0,serial=ch0001,num_queues=4,pci_segment=1")?,
+            NvmeConfig {
+                id: "nvme0".to_owned(),
+                serial: Some("ch0001".to_owned()),
+                num_queues: 4,
+                pci_segment: 1,
+                pci_bdf: None,
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,nvme=nvme0")?,
+            DiskConfig {
+                nvme: Some("nvme0".to_owned()),
+                ..disk_fixture()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_numa_parsing() -> Result<()> {
+        assert_eq!(
+            NumaConfig::parse(
+                "guest_numa_id=1,latencies=[0@120,1@80],bandwidths=[0@10000,1@20000]"
+            )?,
+            NumaConfig {
+                guest_numa_id: 1,
+                latencies: Some(vec![
+                    NumaLatency {
+                        destination: 0,
+                        latency: 120,
+                    },
+                    NumaLatency {
+                        destination: 1,
+                        latency: 80,
+                    },
+                ]),
+                bandwidths: Some(vec![
+                    NumaBandwidth {
+                        destination: 0,
+                        bandwidth: 10000,
+                    },
+                    NumaBandwidth {
+                        destination: 1,
+                        bandwidth: 20000,
+                    },
+                ]),
+                ..numa_fixture()
+            }
+        );
+        assert!(NumaConfig::parse("latencies=[0@fast]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cxl_parsing() -> Result<()> {
+        // memory_zone is required
+        assert!(CxlConfig::parse("").is_err());
+        assert!(CxlConfig::parse("pci_segment=1").is_err());
+        assert_eq!(
+            CxlConfig::parse("memory_zone=mem1")?,
+            CxlConfig {
+                memory_zone: "mem1".to_owned(),
+                id: None,
+                pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        assert_eq!(
+            CxlConfig::parse("memory_zone=mem1,id=cxl0,pci_segment=1")?,
+            CxlConfig {
+                memory_zone: "mem1".to_owned(),
+                id: Some("cxl0".to_owned()),
+                pci_segment: 1,
+                pci_bdf: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_usb_parsing() -> Result<()> {
+        assert_eq!(XhciConfig::parse("")?, XhciConfig { num_ports: 4 });
+        assert_eq!(
+            XhciConfig::parse("num_ports=8")?,
+            XhciConfig { num_ports: 8 }
+        );
+        assert!(XhciConfig::parse("num_ports=256").is_err());
+
+        assert_eq!(
+            UsbDeviceConfig::parse("hostbus=3,hostaddr=12")?,
+            UsbDeviceConfig {
+                hostbus: Some(3),
+                hostaddr: Some(12),
+                vendor_id: None,
+                product_id: None,
+                id: None,
+            }
+        );
+        assert_eq!(
+            UsbDeviceConfig::parse("vendor_id=0x0483,product_id=df11,id=dfu0")?,
+            UsbDeviceConfig {
+                hostbus: None,
+                hostaddr: None,
+                vendor_id: Some(0x0483),
+                product_id: Some(0xdf11),
+                id: Some("dfu0".to_owned()),
+            }
+        );
+        assert!(UsbDeviceConfig::parse("vendor_id=0xg483").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_vnc_parsing() -> Result<()> {
+        assert_eq!(VncConfig::parse("")?, VncConfig::default());
+        assert_eq!(
+            VncConfig::parse("listen=0.0.0.0:5901,width=1920,height=1080")?,
+            VncConfig {
+                listen: "0.0.0.0:5901".parse().unwrap(),
+                width: 1920,
+                height: 1080,
+            }
+        );
+        assert!(VncConfig::parse("listen=localhost").is_err());
+        assert!(VncConfig::parse("depth=24").is_err());
+
+        assert!(VncConfig::default().validate().is_ok());
+        assert_eq!(
+            VncConfig {
+                width: 0,
+                ..Default::default()
+            }
+            .validate(),
+            Err(ValidationError::InvalidVncDisplaySize(0, 768))
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_tpm_parsing() -> Result<()> {
         // path is required
@@ -3781,6 +7312,7 @@ mod tests {
                 iommu: false,
                 id: None,
                 pci_segment: 0,
+                pci_bdf: None,
             }
         );
         assert_eq!(
@@ -3791,6 +7323,29 @@ mod tests {
                 iommu: true,
                 id: None,
                 pci_segment: 0,
+                pci_bdf: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_guest_agent_parsing() -> Result<()> {
+        // port is required
+        assert!(GuestAgentConfig::parse("").is_err());
+        assert!(GuestAgentConfig::parse("fs_freeze=on").is_err());
+        assert_eq!(
+            GuestAgentConfig::parse("port=1234")?,
+            GuestAgentConfig {
+                port: 1234,
+                fs_freeze: false,
+            }
+        );
+        assert_eq!(
+            GuestAgentConfig::parse("port=1234,fs_freeze=on")?,
+            GuestAgentConfig {
+                port: 1234,
+                fs_freeze: true,
             }
         );
         Ok(())
@@ -3804,6 +7359,22 @@ mod tests {
                 source_url: PathBuf::from("/path/to/snapshot"),
                 prefault: false,
                 net_fds: None,
+                resync_clock: false,
+                clone: false,
+                lazy: false,
+                id: None,
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,resync_clock=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                prefault: false,
+                net_fds: None,
+                resync_clock: true,
+                clone: false,
+                lazy: false,
+                id: None,
             }
         );
         assert_eq!(
@@ -3825,6 +7396,46 @@ mod tests {
                         fds: Some(vec![5, 6, 7, 8]),
                     }
                 ]),
+                resync_clock: false,
+                clone: false,
+                lazy: false,
+                id: None,
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,clone=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                prefault: false,
+                net_fds: None,
+                resync_clock: false,
+                clone: true,
+                lazy: false,
+                id: None,
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,lazy=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                prefault: false,
+                net_fds: None,
+                resync_clock: false,
+                clone: false,
+                lazy: true,
+                id: None,
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/tree,id=snapshot0")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/tree"),
+                prefault: false,
+                net_fds: None,
+                resync_clock: false,
+                clone: false,
+                lazy: false,
+                id: Some("snapshot0".to_string()),
             }
         );
         // Parsing should fail as source_url is a required field
@@ -3840,10 +7451,13 @@ mod tests {
             memory: MemoryConfig::default(),
             payload: None,
             rate_limit_groups: None,
+            io_affinities: None,
             disks: None,
             rng: RngConfig::default(),
             balloon: None,
             fs: None,
+            fs9p: None,
+            i2c: None,
             pmem: None,
             serial: default_serial(),
             console: default_console(),
@@ -3852,20 +7466,36 @@ mod tests {
             devices: None,
             user_devices: None,
             vdpa: None,
+            ivshmem: None,
+            nvme: None,
+            cxl: None,
+            xhci: None,
+            usb_devices: None,
+            vnc: None,
             vsock: None,
+            guest_agent: None,
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol: None,
             pvpanic: false,
+            vmclock: false,
+            panic: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
             numa: None,
+            numa_placement: false,
+            numa_placed: None,
             watchdog: false,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             pci_segments: None,
             platform: None,
             tpm: None,
+            rtc: None,
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg: None,
+            cgroup: None,
+            security_label: None,
             preserved_fds: None,
             net: Some(vec![
                 NetConfig {
@@ -3905,6 +7535,10 @@ mod tests {
                     fds: Some(vec![7, 8]),
                 },
             ]),
+            resync_clock: false,
+            clone: false,
+            lazy: false,
+            id: None,
         };
         assert!(valid_config.validate(&snapshot_vm_config).is_ok());
 
@@ -3964,10 +7598,28 @@ mod tests {
             ))
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.lazy = true;
+        invalid_config.clone = true;
+        assert_eq!(
+            invalid_config.validate(&snapshot_vm_config),
+            Err(ValidationError::RestoreLazyIncompatible)
+        );
+        invalid_config.clone = false;
+        invalid_config.prefault = true;
+        assert_eq!(
+            invalid_config.validate(&snapshot_vm_config),
+            Err(ValidationError::RestoreLazyIncompatible)
+        );
+
         let another_valid_config = RestoreConfig {
             source_url: PathBuf::from("/path/to/snapshot"),
             prefault: false,
             net_fds: None,
+            resync_clock: false,
+            clone: false,
+            lazy: false,
+            id: None,
         };
         snapshot_vm_config.net = Some(vec![NetConfig {
             id: Some("net2".to_owned()),
@@ -3981,9 +7633,13 @@ mod tests {
         PlatformConfig {
             num_pci_segments: MAX_NUM_PCI_SEGMENTS,
             iommu_segments: None,
+            iommu_hotplug_slots: 0,
             serial_number: None,
             uuid: None,
             oem_strings: None,
+            oem_string_files: None,
+            virtio_mmio: false,
+            vmbus_relay: None,
             #[cfg(feature = "tdx")]
             tdx: false,
             #[cfg(feature = "sev_snp")]
@@ -3996,6 +7652,8 @@ mod tests {
             guest_numa_id: 0,
             cpus: None,
             distances: None,
+            latencies: None,
+            bandwidths: None,
             memory_zones: None,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_sections: None,
@@ -4017,12 +7675,16 @@ mod tests {
                 hotplug_method: HotplugMethod::Acpi,
                 hotplug_size: None,
                 hotplugged_size: None,
+                boot_size: None,
                 shared: false,
                 hugepages: false,
                 hugepage_size: None,
                 prefault: false,
+                mlock: false,
                 zones: None,
                 thp: true,
+                dirty_ring: false,
+                guest_memfd: false,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -4037,6 +7699,7 @@ mod tests {
                 ),
             }),
             rate_limit_groups: None,
+            io_affinities: None,
             disks: None,
             net: None,
             rng: RngConfig {
@@ -4045,38 +7708,60 @@ mod tests {
             },
             balloon: None,
             fs: None,
+            fs9p: None,
+            i2c: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             },
             #[cfg(target_arch = "x86_64")]
             debug_console: DebugConsoleConfig::default(),
             devices: None,
             user_devices: None,
             vdpa: None,
+            ivshmem: None,
+            nvme: None,
+            cxl: None,
+            xhci: None,
+            usb_devices: None,
+            vnc: None,
             vsock: None,
+            guest_agent: None,
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol: None,
             pvpanic: false,
+            vmclock: false,
+            panic: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
             numa: None,
+            numa_placement: false,
+            numa_placed: None,
             watchdog: false,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             pci_segments: None,
             platform: None,
             tpm: None,
+            rtc: None,
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg: None,
+            cgroup: None,
+            security_label: None,
             preserved_fds: None,
             landlock_enable: false,
             landlock_rules: None,
@@ -4105,16 +7790,23 @@ mod tests {
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 32;
+        invalid_config.console.capture_size = Some(4096);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::CpusMaxLowerThanBoot)
+            Err(ValidationError::ConsoleLogUnsupported)
         );
 
         let mut invalid_config = valid_config.clone();
         invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 32;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CpusMaxLowerThanBoot)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 16;
         invalid_config.cpus.topology = Some(CpuTopology {
             threads_per_core: 2,
             cores_per_die: 8,
@@ -4137,6 +7829,16 @@ mod tests {
             Err(ValidationError::DiskSocketAndPath)
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            thread_pool_queue_depth: Some(64),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidDiskThreadPool)
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.memory.shared = true;
         invalid_config.disks = Some(vec![DiskConfig {
@@ -4171,50 +7873,449 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            vhost_user: true,
-            ..net_fixture()
-        }]);
-        assert_eq!(
-            invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
-        );
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            iommu: true,
+            transitional: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::TransitionalUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            readonly: true,
+            overlay: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskOverlayUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            key_file: Some(PathBuf::from("/path/to_key")),
+            key: Some("passphrase".to_string()),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            key_file: Some(PathBuf::from("/path/to_key")),
+            overlay: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            out_of_process: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            out_of_process: true,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OutOfProcessAndVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            out_of_process: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OutOfProcessDiskPathMissing)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.shared = true;
+        still_valid_config.disks = Some(vec![DiskConfig {
+            out_of_process: true,
+            ..disk_fixture()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            exec: Some(vec!["/usr/bin/backend".to_owned()]),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ExecRequiresVhostUser)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.shared = true;
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            exec: Some(vec![
+                "/usr/bin/backend".to_owned(),
+                "--socket".to_owned(),
+                "/path/to/sock".to_owned(),
+            ]),
+            ..disk_fixture()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.net = Some(vec![NetConfig {
+            out_of_process: true,
+            fds: Some(vec![3]),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OutOfProcessUnsupported("fd".to_owned()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            policy: Some(BalloonPolicyConfig {
+                min_guest_ram: 2 << 30,
+                max_guest_ram: Some(1 << 30),
+                ..Default::default()
+            }),
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::BalloonPolicyMinAboveMax(2 << 30, 1 << 30))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            policy: Some(BalloonPolicyConfig {
+                interval_ms: 0,
+                ..Default::default()
+            }),
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidBalloonPolicyInterval)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..net_fixture()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            fds: Some(vec![0]),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VnetReservedFd)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            tap: Some("tap0".to_owned()),
+            macvtap: Some("macvtap0".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::MacvtapUnsupported("tap".to_owned()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            tap: Some("tap0".to_owned()),
+            xdp: Some("eth1".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::XdpUnsupported("tap".to_owned()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_net: true,
+            coalesce_us: Some(50),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostNetUnsupported(
+                "coalesce_us".to_owned()
+            ))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            port_forward: Some(vec![PortForwardConfig {
+                protocol: PortForwardProtocol::Tcp,
+                host_port: 2222,
+                guest_port: 22,
+            }]),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::PortForwardRequiresPasst)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            io_uring_registered_buffers: true,
+            ..net_fixture()
+        }]);
+        invalid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            policy: None,
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::IoUringRegisteredBuffersUnsupported(
+                "balloon".to_owned()
+            ))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            offload_csum: false,
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NoHardwareChecksumOffload)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs = Some(vec![fs_fixture()]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs9p = Some(vec![Fs9pConfig {
+            tag: "mytag".to_owned(),
+            path: PathBuf::from("/tmp/shared"),
+            msize: 1024,
+            id: None,
+            pci_segment: 0,
+            pci_bdf: None,
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidFs9pMsize(1024))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.i2c = Some(vec![I2cConfig {
+            path: PathBuf::from("/dev/i2c-1"),
+            addresses: Vec::new(),
+            id: None,
+            pci_segment: 0,
+            pci_bdf: None,
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::I2cAddressesMissing)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.i2c = Some(vec![I2cConfig {
+            path: PathBuf::from("/dev/i2c-1"),
+            addresses: vec![0x48, 0x80],
+            id: None,
+            pci_segment: 0,
+            pci_bdf: None,
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidI2cAddress(0x80))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.guest_agent = Some(GuestAgentConfig {
+            port: 1234,
+            fs_freeze: true,
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::GuestAgentRequiresVsock)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.ivshmem = Some(vec![IvshmemConfig::parse("path=/dev/shm/ivshmem,size=3M")?]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIvshmemSize(3 << 20))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.nvme = Some(vec![NvmeConfig::parse("id=nvme0,num_queues=0")?]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNvmeNumQueues(0))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.nvme = Some(vec![NvmeConfig::parse(
+            "id=nvme0,serial=0123456789abcdefghijk",
+        )?]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNvmeSerial(
+                "0123456789abcdefghijk".to_owned()
+            ))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig::parse("path=/path/to_file,nvme=nvme0")?]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NvmeControllerMissing("nvme0".to_owned()))
+        );
+
+        invalid_config.nvme = Some(vec![NvmeConfig::parse("id=nvme0")?]);
+        assert!(invalid_config.validate().is_ok());
+
+        invalid_config.disks = Some(vec![DiskConfig::parse(
+            "path=/path/to_file,nvme=nvme0,iommu=on",
+        )?]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskNvmeUnsupported)
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut cxl_config = valid_config.clone();
+            cxl_config.memory =
+                MemoryConfig::parse("size=0", Some(vec!["id=mem0,size=1G", "id=mem1,size=1G"]))?;
+            cxl_config.platform = Some(PlatformConfig {
+                num_pci_segments: 3,
+                ..platform_fixture()
+            });
+
+            let mut invalid_config = cxl_config.clone();
+            invalid_config.cxl = Some(vec![CxlConfig::parse("memory_zone=mem2,pci_segment=1")?]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::CxlMemoryZoneMissing("mem2".to_owned()))
+            );
+
+            invalid_config.cxl = Some(vec![CxlConfig::parse("memory_zone=mem1")?]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidCxlPciSegment(0))
+            );
+
+            invalid_config.cxl = Some(vec![
+                CxlConfig::parse("memory_zone=mem0,pci_segment=1")?,
+                CxlConfig::parse("memory_zone=mem1,pci_segment=1")?,
+            ]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidCxlPciSegment(1))
+            );
+
+            invalid_config.cxl = Some(vec![
+                CxlConfig::parse("memory_zone=mem1,pci_segment=1")?,
+                CxlConfig::parse("memory_zone=mem1,pci_segment=2")?,
+            ]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::CxlMemoryZoneReused("mem1".to_owned()))
+            );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.net = Some(vec![NetConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
-            ..net_fixture()
-        }]);
-        still_valid_config.memory.shared = true;
-        assert!(still_valid_config.validate().is_ok());
+            invalid_config.memory = MemoryConfig::parse(
+                "size=0",
+                Some(vec!["id=mem0,size=1G", "id=mem1,size=1G,hotplug_size=1G"]),
+            )?;
+            invalid_config.cxl = Some(vec![CxlConfig::parse("memory_zone=mem1,pci_segment=1")?]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidCxlMemoryZone("mem1".to_owned()))
+            );
+
+            let mut still_valid_config = cxl_config.clone();
+            still_valid_config.cxl = Some(vec![CxlConfig::parse(
+                "memory_zone=mem1,id=cxl0,pci_segment=1",
+            )?]);
+            assert!(still_valid_config.validate().is_ok());
+        }
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            fds: Some(vec![0]),
-            ..net_fixture()
-        }]);
+        invalid_config.usb_devices = Some(vec![UsbDeviceConfig::parse("hostbus=1,hostaddr=2")?]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VnetReservedFd)
+            Err(ValidationError::UsbDeviceRequiresXhci)
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            offload_csum: false,
-            ..net_fixture()
-        }]);
+        invalid_config.xhci = Some(XhciConfig::default());
+        assert!(invalid_config.validate().is_ok());
+
+        invalid_config.usb_devices = Some(vec![UsbDeviceConfig::parse(
+            "hostbus=1,vendor_id=1d6b,product_id=2",
+        )?]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::NoHardwareChecksumOffload)
+            Err(ValidationError::InvalidUsbDeviceAddress)
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.fs = Some(vec![fs_fixture()]);
+        invalid_config.usb_devices = None;
+        invalid_config.xhci = Some(XhciConfig { num_ports: 0 });
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::InvalidXhciNumPorts(0))
         );
 
         let mut still_valid_config = valid_config.clone();
@@ -4246,6 +8347,46 @@ mod tests {
             Err(ValidationError::InvalidHugePageSize(3 << 20))
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hotplug_method = HotplugMethod::VirtioMem;
+        still_valid_config.memory.boot_size = Some(128 << 20);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.boot_size = Some(128 << 20);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::MemoryBootSizeWithoutVirtioMem)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hotplug_method = HotplugMethod::VirtioMem;
+        invalid_config.memory.hotplug_size = Some(512 << 20);
+        invalid_config.memory.hotplugged_size = Some(256 << 20);
+        invalid_config.memory.boot_size = Some(128 << 20);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::MemoryBootSizeWithHotpluggedSize)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hotplug_method = HotplugMethod::VirtioMem;
+        invalid_config.memory.boot_size = Some(512 << 20);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMemoryBootSize(512 << 20))
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.guest_memfd = true;
+        #[cfg(target_arch = "x86_64")]
+        assert!(still_valid_config.validate().is_ok());
+        #[cfg(not(target_arch = "x86_64"))]
+        assert_eq!(
+            still_valid_config.validate(),
+            Err(ValidationError::GuestMemfdUnsupported)
+        );
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.platform = Some(platform_fixture());
         assert!(still_valid_config.validate().is_ok());
@@ -4262,6 +8403,35 @@ mod tests {
             ))
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            oem_strings: Some(vec!["io.systemd.credential:foo=bar".to_owned(); 200]),
+            oem_string_files: Some(vec![PathBuf::from("/tmp/provisioning"); 56]),
+            ..platform_fixture()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::TooManyOemStrings(256))
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            virtio_mmio: true,
+            ..platform_fixture()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            virtio_mmio: true,
+            ..platform_fixture()
+        });
+        invalid_config.iommu = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VirtioMmioUnsupported("iommu".to_owned()))
+        );
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.platform = Some(PlatformConfig {
             iommu_segments: Some(vec![1, 2, 3]),
@@ -4269,6 +8439,24 @@ mod tests {
         });
         assert!(still_valid_config.validate().is_ok());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            iommu_hotplug_slots: 4,
+            ..platform_fixture()
+        });
+        assert!(still_valid_config.validate().is_ok());
+        assert!(still_valid_config.iommu);
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            iommu_hotplug_slots: 32,
+            ..platform_fixture()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIommuHotplugSlots(32))
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.platform = Some(PlatformConfig {
             iommu_segments: Some(vec![MAX_NUM_PCI_SEGMENTS + 1, MAX_NUM_PCI_SEGMENTS + 2]),
@@ -4338,6 +8526,7 @@ mod tests {
             id: None,
             iommu: true,
             pci_segment: 1,
+            pci_bdf: None,
         });
         assert!(still_valid_config.validate().is_ok());
 
@@ -4387,6 +8576,35 @@ mod tests {
             Err(ValidationError::OnIommuSegment(1))
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.pmem = Some(vec![PmemConfig {
+            nvdimm: true,
+            label_size: 128 << 10,
+            ..pmem_fixture()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![PmemConfig {
+            label_size: 128 << 10,
+            ..pmem_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPmemLabelSize(128 << 10))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![PmemConfig {
+            nvdimm: true,
+            label_size: 4 << 10,
+            ..pmem_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPmemLabelSize(4 << 10))
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.platform = Some(PlatformConfig {
             num_pci_segments: MAX_NUM_PCI_SEGMENTS,
@@ -4414,6 +8632,7 @@ mod tests {
             id: None,
             iommu: false,
             pci_segment: 1,
+            pci_bdf: None,
         });
         assert_eq!(
             invalid_config.validate(),
@@ -4428,6 +8647,7 @@ mod tests {
         });
         invalid_config.user_devices = Some(vec![UserDeviceConfig {
             pci_segment: 1,
+            pci_bdf: None,
             socket: PathBuf::new(),
             id: None,
         }]);
@@ -4544,6 +8764,48 @@ mod tests {
             Err(ValidationError::InvalidPciSegment(1))
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            pci_bdf: Some(PciBdf::new(0, 0, 5, 0)),
+            ..disk_fixture()
+        }]);
+        still_valid_config.devices = Some(vec![DeviceConfig {
+            pci_bdf: Some(PciBdf::new(0, 0, 6, 0)),
+            ..device_fixture()
+        }]);
+        still_valid_config.validate().unwrap();
+
+        for bdf in [
+            PciBdf::new(0, 1, 5, 0),
+            PciBdf::new(0, 0, 0, 0),
+            PciBdf::new(0, 0, 5, 1),
+            PciBdf::new(1, 0, 5, 0),
+        ] {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.disks = Some(vec![DiskConfig {
+                pci_bdf: Some(bdf),
+                ..disk_fixture()
+            }]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidPciBdf(bdf))
+            );
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            pci_bdf: Some(PciBdf::new(0, 0, 5, 0)),
+            ..disk_fixture()
+        }]);
+        invalid_config.net = Some(vec![NetConfig {
+            pci_bdf: Some(PciBdf::new(0, 0, 5, 0)),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::PciBdfReused(PciBdf::new(0, 0, 5, 0)))
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
             rate_limit_group: Some("foo".into()),
@@ -4554,6 +8816,43 @@ mod tests {
             Err(ValidationError::InvalidRateLimiterGroup)
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.io_affinities = Some(vec![IoAffinityConfig {
+            id: "io0".to_string(),
+            num_sets: 2,
+            host_cpus: vec![2],
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIoAffinity("io0".to_string()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            io_affinity: Some("io0".into()),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::UnknownIoAffinity("io0".to_string()))
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.io_affinities = Some(vec![IoAffinityConfig {
+            id: "io0".to_string(),
+            num_sets: 2,
+            host_cpus: vec![2, 3],
+        }]);
+        still_valid_config.disks = Some(vec![DiskConfig {
+            io_affinity: Some("io0".into()),
+            ..disk_fixture()
+        }]);
+        still_valid_config.net = Some(vec![NetConfig {
+            io_affinity: Some("io0".into()),
+            ..net_fixture()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.devices = Some(vec![
             DeviceConfig {
@@ -4637,6 +8936,201 @@ mod tests {
         }
         let _still_valid_config = still_valid_config.clone();
     }
+
+    #[test]
+    fn test_cgroup_parsing() -> Result<()> {
+        assert!(CgroupConfig::parse("").is_err());
+        assert!(CgroupConfig::parse("memory_high=1G").is_err());
+        assert!(CgroupConfig::parse("path=/sys/fs/cgroup/vm0,memory_high=foo").is_err());
+        assert!(CgroupConfig::parse("path=/sys/fs/cgroup/vm0,vcpu_cpu_weight=-1").is_err());
+        assert!(CgroupConfig::parse("path=/sys/fs/cgroup/vm0,cpu_weight=100").is_err());
+        assert_eq!(
+            CgroupConfig::parse("path=/sys/fs/cgroup/vm0")?,
+            CgroupConfig {
+                path: PathBuf::from("/sys/fs/cgroup/vm0"),
+                resources: CgroupResources::default(),
+            }
+        );
+        assert_eq!(
+            CgroupConfig::parse(
+                "path=/sys/fs/cgroup/vm0,memory_high=1G,io_max=[8:0 rbps=1048576,8:16 wiops=100],\
+                 vmm_cpu_weight=50,vcpu_cpu_weight=200,iothread_cpu_weight=100"
+            )?,
+            CgroupConfig {
+                path: PathBuf::from("/sys/fs/cgroup/vm0"),
+                resources: CgroupResources {
+                    memory_high: Some(1 << 30),
+                    io_max: Some(vec![
+                        "8:0 rbps=1048576".to_string(),
+                        "8:16 wiops=100".to_string()
+                    ]),
+                    vmm_cpu_weight: Some(50),
+                    vcpu_cpu_weight: Some(200),
+                    iothread_cpu_weight: Some(100),
+                },
+            }
+        );
+
+        assert!(
+            CgroupConfig::parse("path=/sys/fs/cgroup/vm0,vcpu_cpu_weight=100")?
+                .validate()
+                .is_ok()
+        );
+        assert_eq!(
+            CgroupConfig::parse("path=/sys/fs/cgroup/vm0,vcpu_cpu_weight=0")?.validate(),
+            Err(ValidationError::InvalidCgroupCpuWeight(0))
+        );
+        assert_eq!(
+            CgroupConfig::parse("path=/sys/fs/cgroup/vm0,iothread_cpu_weight=10001")?.validate(),
+            Err(ValidationError::InvalidCgroupCpuWeight(10001))
+        );
+        assert_eq!(
+            CgroupConfig::parse("path=vm0")?.validate(),
+            Err(ValidationError::InvalidCgroupPath(PathBuf::from("vm0")))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rtc_parsing() -> Result<()> {
+        assert_eq!(RtcConfig::parse("")?, RtcConfig::default());
+        assert_eq!(
+            RtcConfig::parse("base=localtime,clock=vm")?,
+            RtcConfig {
+                base: RtcBase::Localtime,
+                clock: RtcClock::Vm,
+            }
+        );
+        assert_eq!(
+            RtcConfig::parse("base=2024-02-29T12:30:00")?,
+            RtcConfig {
+                base: RtcBase::Datetime("2024-02-29T12:30:00".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            rtc_datetime_to_unix("2024-02-29T12:30:00"),
+            Some(1709209800)
+        );
+        assert!(RtcConfig::parse("base=2024-13-01T00:00:00").is_err());
+        assert!(RtcConfig::parse("base=yesterday").is_err());
+        assert!(RtcConfig::parse("clock=guest").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_panic_parsing() -> Result<()> {
+        assert_eq!(PanicConfig::parse("")?, PanicConfig::default());
+        assert_eq!(
+            PanicConfig::parse("action=restart")?,
+            PanicConfig {
+                action: PanicAction::Restart,
+                coredump_file: None,
+            }
+        );
+        assert_eq!(
+            PanicConfig::parse("action=coredump,coredump_file=/tmp/core")?,
+            PanicConfig {
+                action: PanicAction::Coredump,
+                coredump_file: Some(PathBuf::from("/tmp/core")),
+            }
+        );
+        assert!(PanicConfig::parse("action=reset").is_err());
+
+        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+        assert_eq!(
+            PanicConfig::parse("action=coredump")?.validate(),
+            Err(ValidationError::PanicCoredumpFileMissing)
+        );
+        #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+        assert_eq!(
+            PanicConfig::parse("action=coredump,coredump_file=/tmp/core")?.validate(),
+            Err(ValidationError::PanicCoredumpUnsupported)
+        );
+        assert!(PanicConfig::parse("action=pause")?.validate().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_fw_cfg_parsing() -> Result<()> {
+        assert_eq!(
+            FwCfgConfig::parse("name=opt/org.example/blob,file=/tmp/blob")?,
+            FwCfgConfig {
+                name: "opt/org.example/blob".to_owned(),
+                file: Some(PathBuf::from("/tmp/blob")),
+                string: None,
+            }
+        );
+        assert_eq!(
+            FwCfgConfig::parse("name=opt/org.example/token,string=secret")?,
+            FwCfgConfig {
+                name: "opt/org.example/token".to_owned(),
+                file: None,
+                string: Some("secret".to_owned()),
+            }
+        );
+        assert!(FwCfgConfig::parse("file=/tmp/blob").is_err());
+
+        let mut config = FwCfgConfig::parse("name=opt/org.example/token")?;
+        assert_eq!(
+            config.validate(),
+            Err(ValidationError::InvalidFwCfgContent(
+                "opt/org.example/token".to_owned()
+            ))
+        );
+        config.string = Some("secret".to_owned());
+        assert!(config.validate().is_ok());
+        config.file = Some(PathBuf::from("/tmp/blob"));
+        assert!(config.validate().is_err());
+
+        let config = FwCfgConfig::parse(&format!("name=opt/{},string=secret", "a".repeat(52)))?;
+        assert_eq!(
+            config.validate(),
+            Err(ValidationError::InvalidFwCfgName(config.name.clone()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_security_label_parsing() -> Result<()> {
+        assert_eq!(
+            SecurityLabelConfig::parse(
+                "selinux_context=\"system_u:system_r:svirt_t:s0:c1,c2\",\
+                 selinux_file_context=\"system_u:object_r:svirt_image_t:s0:c1,c2\""
+            )?,
+            SecurityLabelConfig {
+                selinux_context: Some("system_u:system_r:svirt_t:s0:c1,c2".to_owned()),
+                selinux_file_context: Some("system_u:object_r:svirt_image_t:s0:c1,c2".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            SecurityLabelConfig::parse(
+                "apparmor_profile=ch-vm0,apparmor_backend_profile=ch-vm0-backend"
+            )?,
+            SecurityLabelConfig {
+                apparmor_profile: Some("ch-vm0".to_owned()),
+                apparmor_backend_profile: Some("ch-vm0-backend".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(SecurityLabelConfig::parse("selinux=foo").is_err());
+        assert_eq!(
+            SecurityLabelConfig::parse(
+                "selinux_context=system_u:system_r:svirt_t:s0,apparmor_profile=ch-vm0"
+            )?
+            .validate(),
+            Err(ValidationError::SecurityLabelSelinuxAndApparmor)
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_landlock_parsing() -> Result<()> {
         // should not be empty
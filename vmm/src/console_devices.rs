@@ -253,17 +253,10 @@ pub(crate) fn pre_create_console_devices(vmm: &mut Vmm) -> ConsoleDeviceResult<C
             // SAFETY: stdout is valid and owned solely by us.
             let stdout = unsafe { File::from_raw_fd(stdout) };
 
-            // SAFETY: FFI call. Trivially safe.
-            if unsafe { libc::isatty(stdout.as_raw_fd()) } == 1 {
-                vmm.console_resize_pipe = Some(Arc::new(
-                    listen_for_sigwinch_on_tty(
-                        stdout.try_clone().unwrap(),
-                        &vmm.seccomp_action,
-                        vmm.hypervisor.hypervisor_type(),
-                    )
-                    .map_err(ConsoleDeviceError::StartSigwinchListener)?,
-                ));
-            }
+            // The serial port has no notion of a terminal size, so there is
+            // no SIGWINCH listener to start here. Starting one would replace
+            // the listener of the virtio-console, which could be attached to
+            // a different terminal.
 
             // Make sure stdout is in raw mode, if it's a terminal.
             set_raw_mode(&stdout, vmm.original_termios_opt.clone())?;
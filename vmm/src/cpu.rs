@@ -11,7 +11,8 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-use crate::config::CpusConfig;
+use crate::cgroup::{gettid, CgroupError, CgroupManager, CgroupThreadGroup};
+use crate::config::{CpuAffinityFallback, CpusConfig};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::{
     CpuElf64Writable, CpuSegment, CpuState as DumpCpusState, DumpState, Elf64Writable,
@@ -22,6 +23,7 @@ use crate::coredump::{
 use crate::gdb::{get_raw_tid, Debuggable, DebuggableError};
 #[cfg(target_arch = "x86_64")]
 use crate::memory_manager::MemoryManager;
+use crate::numa_placement::{self, NumaPlacementError};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 #[cfg(target_arch = "x86_64")]
 use crate::vm::physical_bits;
@@ -46,10 +48,12 @@ use gdbstub_arch::x86::reg::{X86SegmentRegs, X86_64CoreRegs as CoreRegs};
 use hypervisor::arch::x86::msr_index;
 #[cfg(target_arch = "x86_64")]
 use hypervisor::arch::x86::CpuIdEntry;
-#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+#[cfg(target_arch = "x86_64")]
 use hypervisor::arch::x86::MsrEntry;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use hypervisor::arch::x86::SpecialRegisters;
+#[cfg(target_arch = "x86_64")]
+use hypervisor::arch::x86::{HV_X64_MSR_CRASH_P0, HV_X64_MSR_CRASH_PARAMS};
 #[cfg(target_arch = "aarch64")]
 use hypervisor::kvm::kvm_bindings;
 #[cfg(all(target_arch = "aarch64", feature = "kvm"))]
@@ -62,19 +66,20 @@ use hypervisor::CpuVendor;
 use hypervisor::HypervisorType;
 #[cfg(feature = "guest_debug")]
 use hypervisor::StandardRegisters;
-use hypervisor::{CpuState, HypervisorCpuError, VmExit, VmOps};
+use hypervisor::{CpuState, HypervisorCpuError, VcpuExitCounters, VmExit, VmOps};
 use libc::{c_void, siginfo_t};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use linux_loader::elf::Elf64_Nhdr;
 use seccompiler::{apply_filter, SeccompAction};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::io::Write;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::mem::size_of;
+use std::num::Wrapping;
 use std::os::unix::thread::JoinHandleExt;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use std::{cmp, io, result, thread};
 use thiserror::Error;
 use tracer::trace_scoped;
@@ -110,7 +115,7 @@ macro_rules! extract_bits_64_without_offset {
     };
 }
 
-pub const CPU_MANAGER_ACPI_SIZE: usize = 0xc;
+pub const CPU_MANAGER_ACPI_SIZE: usize = 0x8000;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -129,6 +134,10 @@ pub enum Error {
     #[error("Error configuring vCPU: {0}")]
     VcpuConfiguration(#[source] arch::Error),
 
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error setting up vCPU for waking up: {0}")]
+    VcpuWakeup(#[source] arch::regs::Error),
+
     #[error("Still pending removed vcpu")]
     VcpuPendingRemovedVcpu,
 
@@ -191,6 +200,27 @@ pub enum Error {
     #[error("Maximum number of vCPUs exceeds host limit")]
     MaximumVcpusExceeded,
 
+    #[error("Error moving the vCPU thread into its cgroup: {0}")]
+    VcpuCgroup(#[source] CgroupError),
+
+    #[error("vCPU thread exited before reporting its thread ID")]
+    VcpuCgroupTid,
+
+    #[error("Error reading the online host CPUs: {0}")]
+    OnlineHostCpus(#[source] NumaPlacementError),
+
+    #[error("Error getting the host CPU set of the VMM: {0}")]
+    GetAffinity(#[source] io::Error),
+
+    #[error("Changing the maximum number of vCPUs is not supported")]
+    MaxVcpusResizeUnsupported,
+
+    #[error("Maximum number of vCPUs can't be decreased")]
+    MaxVcpusDecrease,
+
+    #[error("SSDT describing the new vCPUs is too large: {0} bytes")]
+    HotplugSsdtTooLarge(usize),
+
     #[cfg(feature = "sev_snp")]
     #[error("Failed to set sev control register: {0}")]
     SetSevControlRegister(#[source] hypervisor::HypervisorCpuError),
@@ -198,6 +228,10 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     #[error("Failed to inject NMI")]
     NmiError(hypervisor::HypervisorCpuError),
+
+    #[cfg(target_arch = "aarch64")]
+    #[error("Error moving the guest counter forward: {0}")]
+    AdvanceClock(#[source] hypervisor::HypervisorCpuError),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -404,6 +438,46 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Reports the crash notified by the guest through the Hyper-V crash
+    /// MSRs, along with the crash code and parameters stored in them.
+    #[cfg(target_arch = "x86_64")]
+    fn report_hyperv_crash(&self) {
+        let mut msrs: Vec<MsrEntry> = (0..HV_X64_MSR_CRASH_PARAMS)
+            .map(|i| MsrEntry {
+                index: HV_X64_MSR_CRASH_P0 + i,
+                ..Default::default()
+            })
+            .collect();
+        if let Err(e) = self.vcpu.get_msrs(&mut msrs) {
+            error!("Failed to read the Hyper-V crash MSRs: {:?}", e);
+        }
+
+        let code = format!("{:#x}", msrs[0].data);
+        let parameters = msrs[1..]
+            .iter()
+            .map(|msr| format!("{:#x}", msr.data))
+            .collect::<Vec<String>>()
+            .join(",");
+        info!(
+            "vCPU {} got guest crash: code = {}, parameters = {}",
+            self.id, code, parameters
+        );
+        event!(
+            "guest",
+            "panic",
+            "source",
+            "hyperv",
+            "event",
+            "panic",
+            "cpu_id",
+            self.id.to_string(),
+            "code",
+            code,
+            "parameters",
+            parameters
+        );
+    }
+
     /// Gets the MPIDR register value.
     #[cfg(target_arch = "aarch64")]
     pub fn get_mpidr(&self) -> u64 {
@@ -511,21 +585,34 @@ pub struct CpuManager {
     exit_evt: EventFd,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     reset_evt: EventFd,
+    #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+    panic_evt: EventFd,
     #[cfg(feature = "guest_debug")]
     vm_debug_evt: EventFd,
     vcpu_states: Vec<VcpuState>,
     selected_cpu: u8,
     vcpus: Vec<Arc<Mutex<Vcpu>>>,
+    // Exit counters of the vCPUs, which remain reachable while they run
+    vcpus_exit_counters: Vec<Arc<VcpuExitCounters>>,
     seccomp_action: SeccompAction,
     vm_ops: Arc<dyn VmOps>,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     acpi_address: Option<GuestAddress>,
     proximity_domain_per_cpu: BTreeMap<u8, u32>,
     affinity: BTreeMap<u8, Vec<usize>>,
+    // Host CPUs each pinned vCPU falls back onto when none of its host CPUs
+    // is available anymore, before falling back onto any host CPU
+    fallback_affinity: BTreeMap<u8, Vec<usize>>,
     dynamic: bool,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     #[cfg(feature = "sev_snp")]
     sev_snp_enabled: bool,
+    cgroup_manager: Option<Arc<CgroupManager>>,
+    // Number of vCPUs described by the ACPI tables known to the guest
+    acpi_max_vcpus: u8,
+    // SSDT describing the vCPUs above acpi_max_vcpus, along with the maximum
+    // number of vCPUs it accounts for, until the guest acknowledges loading it
+    hotplug_ssdt: Option<(u8, Vec<u8>)>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -535,6 +622,13 @@ const CPU_EJECT_FLAG: usize = 3;
 
 const CPU_STATUS_OFFSET: u64 = 4;
 const CPU_SELECTION_OFFSET: u64 = 0;
+const CPU_MAX_VCPUS_OFFSET: u64 = 0xc;
+const CPU_SSDT_PENDING_OFFSET: u64 = 0x10;
+
+// Window exposing the SSDT that describes the vCPUs made available after boot
+// by raising the maximum number of vCPUs.
+const CPU_SSDT_OFFSET: u64 = 0x100;
+const CPU_SSDT_SIZE: usize = CPU_MANAGER_ACPI_SIZE - CPU_SSDT_OFFSET as usize;
 
 impl BusDevice for CpuManager {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
@@ -561,6 +655,23 @@ impl BusDevice for CpuManager {
                     warn!("Out of range vCPU id: {}", self.selected_cpu);
                 }
             }
+            CPU_MAX_VCPUS_OFFSET => {
+                data[0] = self.max_vcpus();
+            }
+            CPU_SSDT_PENDING_OFFSET => {
+                if self.latch_hotplug_ssdt() {
+                    data[0] = 1;
+                }
+            }
+            o if o >= CPU_SSDT_OFFSET => {
+                let offset = (o - CPU_SSDT_OFFSET) as usize;
+                if let Some((_, ssdt)) = &self.hotplug_ssdt {
+                    if offset < ssdt.len() {
+                        let len = data.len().min(ssdt.len() - offset);
+                        data[..len].copy_from_slice(&ssdt[offset..offset + len]);
+                    }
+                }
+            }
             _ => {
                 warn!(
                     "Unexpected offset for accessing CPU manager device: {:#}",
@@ -600,6 +711,14 @@ impl BusDevice for CpuManager {
                     warn!("Out of range vCPU id: {}", self.selected_cpu);
                 }
             }
+            CPU_SSDT_PENDING_OFFSET => {
+                // The ACPI code writes back a 1 once the SSDT has been loaded
+                if data[0] & 1 == 1 {
+                    if let Some((max_vcpus, _)) = self.hotplug_ssdt.take() {
+                        self.acpi_max_vcpus = max_vcpus;
+                    }
+                }
+            }
             _ => {
                 warn!(
                     "Unexpected offset for accessing CPU manager device: {:#}",
@@ -620,6 +739,8 @@ struct VcpuState {
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
     paused: Arc<AtomicBool>,
+    // Host CPUs the vCPU thread is currently pinned onto
+    host_cpus: Vec<usize>,
 }
 
 impl VcpuState {
@@ -660,6 +781,71 @@ impl VcpuState {
     }
 }
 
+// Host CPUs the VMM can currently run onto, meaning the online ones out of
+// the CPU set of the calling thread, which follows its cpuset.
+// Frequency of the host system counter, which the guest counter runs at.
+#[cfg(target_arch = "aarch64")]
+fn host_counter_frequency() -> u64 {
+    let frequency: u64;
+    // SAFETY: CNTFRQ_EL0 is readable from EL0 and reading it has no side
+    // effect.
+    unsafe { std::arch::asm!("mrs {}, cntfrq_el0", out(reg) frequency) };
+    frequency
+}
+
+fn available_host_cpus() -> Result<BTreeSet<usize>> {
+    let online = numa_placement::online_host_cpus().map_err(Error::OnlineHostCpus)?;
+
+    // SAFETY: all zeros is a valid pattern
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call with correct arguments
+    let ret =
+        unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut cpuset) };
+    if ret != 0 {
+        return Err(Error::GetAffinity(io::Error::last_os_error()));
+    }
+
+    Ok(online
+        .into_iter()
+        // SAFETY: FFI call, trivially safe
+        .filter(|cpu| {
+            *cpu < libc::CPU_SETSIZE as usize && unsafe { libc::CPU_ISSET(*cpu, &cpuset) }
+        })
+        .collect())
+}
+
+// Host CPUs a vCPU pinned onto `host_cpus` can run onto out of the available
+// ones: its remaining host CPUs, or else the remaining fallback ones, or else
+// any available host CPU.
+fn effective_host_cpus(
+    host_cpus: &[usize],
+    fallback_cpus: &[usize],
+    available: &BTreeSet<usize>,
+) -> Vec<usize> {
+    [host_cpus, fallback_cpus]
+        .into_iter()
+        .map(|cpus| {
+            cpus.iter()
+                .copied()
+                .filter(|cpu| available.contains(cpu))
+                .collect::<Vec<usize>>()
+        })
+        .find(|cpus| !cpus.is_empty())
+        .unwrap_or_else(|| available.iter().copied().collect())
+}
+
+fn host_cpuset(host_cpus: &[usize]) -> libc::cpu_set_t {
+    // SAFETY: all zeros is a valid pattern
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call, trivially safe
+    unsafe { libc::CPU_ZERO(&mut cpuset) };
+    for host_cpu in host_cpus {
+        // SAFETY: FFI call, trivially safe
+        unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
+    }
+    cpuset
+}
+
 impl CpuManager {
     #[allow(unused_variables)]
     #[allow(clippy::too_many_arguments)]
@@ -668,6 +854,7 @@ impl CpuManager {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        panic_evt: EventFd,
         #[cfg(feature = "guest_debug")] vm_debug_evt: EventFd,
         hypervisor: &Arc<dyn hypervisor::Hypervisor>,
         seccomp_action: SeccompAction,
@@ -739,6 +926,24 @@ impl CpuManager {
             BTreeMap::new()
         };
 
+        // The host NUMA nodes are looked up while the pinned host CPUs are
+        // still online.
+        let fallback_affinity = match config.affinity_fallback {
+            CpuAffinityFallback::Any => BTreeMap::new(),
+            CpuAffinityFallback::Numa => affinity
+                .iter()
+                .filter_map(
+                    |(vcpu, host_cpus)| match numa_placement::host_node_cpus(host_cpus) {
+                        Ok(node_cpus) => Some((*vcpu, node_cpus)),
+                        Err(e) => {
+                            warn!("Failed looking up the host NUMA node of vCPU {vcpu}: {e}");
+                            None
+                        }
+                    },
+                )
+                .collect(),
+        };
+
         #[cfg(feature = "tdx")]
         let dynamic = !tdx_enabled;
         #[cfg(not(feature = "tdx"))]
@@ -756,19 +961,25 @@ impl CpuManager {
             vcpu_states,
             exit_evt,
             reset_evt,
+            panic_evt,
             #[cfg(feature = "guest_debug")]
             vm_debug_evt,
             selected_cpu: 0,
             vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
+            vcpus_exit_counters: Vec::with_capacity(usize::from(config.max_vcpus)),
             seccomp_action,
             vm_ops,
             acpi_address: None,
             proximity_domain_per_cpu,
             affinity,
+            fallback_affinity,
             dynamic,
             hypervisor: hypervisor.clone(),
             #[cfg(feature = "sev_snp")]
             sev_snp_enabled,
+            cgroup_manager: None,
+            acpi_max_vcpus: config.max_vcpus,
+            hotplug_ssdt: None,
         })))
     }
 
@@ -839,6 +1050,7 @@ impl CpuManager {
             vcpu.saved_state = Some(state);
         }
 
+        self.vcpus_exit_counters.push(vcpu.vcpu.exit_counters());
         let vcpu = Arc::new(Mutex::new(vcpu));
 
         // Adding vCPU to the CpuManager's vCPU list.
@@ -953,6 +1165,8 @@ impl CpuManager {
     ) -> Result<()> {
         let reset_evt = self.reset_evt.try_clone().unwrap();
         let exit_evt = self.exit_evt.try_clone().unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let guest_panic_evt = self.panic_evt.try_clone().unwrap();
         #[cfg(feature = "kvm")]
         let hypervisor_type = self.hypervisor.hypervisor_type();
         #[cfg(feature = "guest_debug")]
@@ -969,18 +1183,32 @@ impl CpuManager {
         let panic_vcpu_run_interrupted = vcpu_run_interrupted.clone();
         let vcpu_paused = self.vcpu_states[usize::from(vcpu_id)].paused.clone();
 
-        // Prepare the CPU set the current vCPU is expected to run onto.
-        let cpuset = self.affinity.get(&vcpu_id).map(|host_cpus| {
-            // SAFETY: all zeros is a valid pattern
-            let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
-            // SAFETY: FFI call, trivially safe
-            unsafe { libc::CPU_ZERO(&mut cpuset) };
-            for host_cpu in host_cpus {
-                // SAFETY: FFI call, trivially safe
-                unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
+        // Prepare the CPU set the current vCPU is expected to run onto,
+        // some of its host CPUs possibly being unavailable already.
+        let host_cpus = if let Some(host_cpus) = self.affinity.get(&vcpu_id) {
+            let fallback_cpus = self.fallback_affinity.get(&vcpu_id);
+            let host_cpus = match available_host_cpus() {
+                Ok(available) => effective_host_cpus(
+                    host_cpus,
+                    fallback_cpus.map_or(&[], |cpus| cpus.as_slice()),
+                    &available,
+                ),
+                Err(e) => {
+                    warn!("Failed getting the available host CPUs: {}", e);
+                    host_cpus.clone()
+                }
+            };
+            if Some(&host_cpus) != self.affinity.get(&vcpu_id) {
+                warn!(
+                    "vCPU {} host CPUs unavailable, pinning it onto host CPUs {:?}",
+                    vcpu_id, host_cpus
+                );
             }
-            cpuset
-        });
+            host_cpus
+        } else {
+            Vec::new()
+        };
+        let cpuset = (!host_cpus.is_empty()).then(|| host_cpuset(&host_cpus));
 
         // Retrieve seccomp filter for vcpu thread
         let vcpu_seccomp_filter = get_seccomp_filter(
@@ -993,12 +1221,32 @@ impl CpuManager {
         #[cfg(target_arch = "x86_64")]
         let interrupt_controller_clone = self.interrupt_controller.as_ref().cloned();
 
+        // Channels over which the vCPU thread reports its thread ID, so that
+        // it can be moved into the vCPU cgroup, and then learns whether it
+        // was moved.
+        let (tid_tx, tid_rx) = if self.cgroup_manager.is_some() {
+            let (tid_tx, tid_rx) = mpsc::channel();
+            let (attached_tx, attached_rx) = mpsc::channel();
+            (Some((tid_tx, attached_rx)), Some((tid_rx, attached_tx)))
+        } else {
+            (None, None)
+        };
+
         info!("Starting vCPU: cpu_id = {}", vcpu_id);
 
         let handle = Some(
             thread::Builder::new()
                 .name(format!("vcpu{vcpu_id}"))
                 .spawn(move || {
+                    // Report the thread ID so that the thread can be moved into
+                    // the vCPU cgroup before it starts running the guest.
+                    if let Some((tid_tx, attached_rx)) = tid_tx {
+                        tid_tx.send(gettid()).ok();
+                        if !attached_rx.recv().unwrap_or(false) {
+                            return;
+                        }
+                    }
+
                     // Schedule the thread to run on the expected CPU set
                     if let Some(cpuset) = cpuset.as_ref() {
                         // SAFETY: FFI call with correct arguments
@@ -1135,6 +1383,11 @@ impl CpuManager {
                                     }
                                     VmExit::Ignore => {}
                                     VmExit::Hyperv => {}
+                                    #[cfg(target_arch = "x86_64")]
+                                    VmExit::HypervCrash => {
+                                        vcpu.report_hyperv_crash();
+                                        guest_panic_evt.write(1).unwrap();
+                                    }
                                     VmExit::Reset => {
                                         info!("VmExit::Reset");
                                         vcpu_run_interrupted.store(true, Ordering::SeqCst);
@@ -1196,10 +1449,36 @@ impl CpuManager {
                 .map_err(Error::VcpuSpawn)?,
         );
 
+        // Moving the thread from here rather than from the thread itself lets
+        // the failure be reported to the caller, instead of leaving the VM
+        // waiting for a vCPU that will never start.
+        if let (Some(cgroup_manager), Some((tid_rx, attached_tx))) =
+            (self.cgroup_manager.as_ref(), tid_rx)
+        {
+            let attached = tid_rx
+                .recv()
+                .map_err(|_| Error::VcpuCgroupTid)
+                .and_then(|tid| {
+                    cgroup_manager
+                        .attach_thread_id(CgroupThreadGroup::Vcpus, tid)
+                        .map_err(Error::VcpuCgroup)
+                });
+            // The thread waits for the outcome before running the guest, so
+            // that on failure it exits and can be joined.
+            attached_tx.send(attached.is_ok()).ok();
+            if let Err(e) = attached {
+                if let Some(handle) = handle {
+                    handle.join().ok();
+                }
+                return Err(e);
+            }
+        }
+
         // On hot plug calls into this function entry_point is None. It is for
         // those hotplug CPU additions that we need to set the inserting flag.
         self.vcpu_states[usize::from(vcpu_id)].handle = handle;
         self.vcpu_states[usize::from(vcpu_id)].inserting = inserting;
+        self.vcpu_states[usize::from(vcpu_id)].host_cpus = host_cpus;
 
         Ok(())
     }
@@ -1299,6 +1578,95 @@ impl CpuManager {
         Ok(())
     }
 
+    /// Move the virtual counter of the restored vCPUs forward by `ns`
+    /// nanoseconds. The same value is written to all the vCPUs, as KVM may
+    /// share the counter offset across the whole VM.
+    #[cfg(target_arch = "aarch64")]
+    pub fn advance_clock(&self, ns: u64) -> Result<()> {
+        let Some(first_vcpu) = self.vcpus.first() else {
+            return Ok(());
+        };
+        let counter = first_vcpu
+            .lock()
+            .unwrap()
+            .vcpu
+            .get_sys_reg(arch::aarch64::regs::KVM_REG_ARM_TIMER_CNT)
+            .map_err(Error::AdvanceClock)?;
+        let ticks = u128::from(ns) * u128::from(host_counter_frequency()) / 1_000_000_000;
+        let counter = counter.wrapping_add(ticks as u64);
+
+        for vcpu in self.vcpus.iter() {
+            vcpu.lock()
+                .unwrap()
+                .vcpu
+                .set_sys_reg(arch::aarch64::regs::KVM_REG_ARM_TIMER_CNT, counter)
+                .map_err(Error::AdvanceClock)?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-pin the vCPU threads whose host CPUs went offline or left the VMM
+    /// cpuset according to the affinity fallback policy, and back onto their
+    /// host CPUs once available again.
+    pub fn update_vcpus_affinity(&mut self) -> Result<()> {
+        if self.affinity.is_empty() {
+            return Ok(());
+        }
+
+        let available = available_host_cpus()?;
+        for (vcpu_id, host_cpus) in self.affinity.iter() {
+            let Some(state) = self.vcpu_states.get_mut(usize::from(*vcpu_id)) else {
+                continue;
+            };
+            let Some(handle) = state.handle.as_ref() else {
+                continue;
+            };
+
+            let fallback_cpus = self.fallback_affinity.get(vcpu_id);
+            let effective = effective_host_cpus(
+                host_cpus,
+                fallback_cpus.map_or(&[], |cpus| cpus.as_slice()),
+                &available,
+            );
+            if effective.is_empty() || effective == state.host_cpus {
+                continue;
+            }
+
+            let cpuset = host_cpuset(&effective);
+            // SAFETY: FFI call with correct arguments
+            let ret = unsafe {
+                libc::pthread_setaffinity_np(
+                    handle.as_pthread_t() as _,
+                    std::mem::size_of::<libc::cpu_set_t>(),
+                    &cpuset,
+                )
+            };
+            if ret != 0 {
+                error!(
+                    "Failed re-pinning vCPU {} onto host CPUs {:?}: {}",
+                    vcpu_id,
+                    effective,
+                    io::Error::from_raw_os_error(ret)
+                );
+                continue;
+            }
+
+            warn!("vCPU {} re-pinned onto host CPUs {:?}", vcpu_id, effective);
+            event!(
+                "cpu_manager",
+                "vcpu-repinned",
+                "cpu_id",
+                vcpu_id.to_string(),
+                "host_cpus",
+                format!("{effective:?}")
+            );
+            state.host_cpus = effective;
+        }
+
+        Ok(())
+    }
+
     pub fn resize(&mut self, desired_vcpus: u8) -> Result<bool> {
         if desired_vcpus.cmp(&self.present_vcpus()) == cmp::Ordering::Equal {
             return Ok(false);
@@ -1329,6 +1697,40 @@ impl CpuManager {
         }
     }
 
+    pub fn resize_max_vcpus(&mut self, desired_max_vcpus: u8) -> Result<bool> {
+        if desired_max_vcpus == self.config.max_vcpus {
+            return Ok(false);
+        }
+
+        // The vCPUs added after boot are described to the guest through an
+        // SSDT loaded by the CPU hotplug controller, which only exists on
+        // x86_64. Their APIC ids must not depend on a user defined topology.
+        if !self.dynamic || cfg!(not(target_arch = "x86_64")) || self.config.topology.is_some() {
+            return Err(Error::MaxVcpusResizeUnsupported);
+        }
+
+        if desired_max_vcpus < self.config.max_vcpus {
+            return Err(Error::MaxVcpusDecrease);
+        }
+
+        if u32::from(desired_max_vcpus) > self.hypervisor.get_max_vcpus() {
+            return Err(Error::MaximumVcpusExceeded);
+        }
+
+        let ssdt_len = self
+            .create_hotplug_ssdt(self.acpi_max_vcpus, desired_max_vcpus)
+            .len();
+        if ssdt_len > CPU_SSDT_SIZE {
+            return Err(Error::HotplugSsdtTooLarge(ssdt_len));
+        }
+
+        self.vcpu_states
+            .resize_with(usize::from(desired_max_vcpus), VcpuState::default);
+        self.config.max_vcpus = desired_max_vcpus;
+
+        Ok(true)
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         // Tell the vCPUs to stop themselves next time they go through the loop
         self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
@@ -1376,6 +1778,16 @@ impl CpuManager {
         self.config.max_vcpus
     }
 
+    /// Exit counters of the present vCPUs, named after their id.
+    pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
+        self.vcpus_exit_counters
+            .iter()
+            .enumerate()
+            .filter(|(cpu_id, _)| self.vcpu_states[*cpu_id].active())
+            .map(|(cpu_id, counters)| (format!("__vcpu{cpu_id}"), counters.counters()))
+            .collect()
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn common_cpuid(&self) -> Vec<CpuIdEntry> {
         assert!(!self.cpuid.is_empty());
@@ -1411,6 +1823,52 @@ impl CpuManager {
             .map(|t| (t.threads_per_core, t.cores_per_die, t.packages))
     }
 
+    // Highest number of vCPUs the guest could be given by raising the maximum
+    // number of vCPUs at runtime.
+    fn max_vcpus_limit(&self) -> u8 {
+        if cfg!(target_arch = "x86_64") && self.dynamic {
+            u8::try_from(self.hypervisor.get_max_vcpus()).unwrap_or(u8::MAX)
+        } else {
+            self.config.max_vcpus
+        }
+    }
+
+    fn create_hotplug_ssdt(&self, first_vcpu: u8, max_vcpus: u8) -> Sdt {
+        let mut ssdt = Sdt::new(*b"SSDT", 36, 2, *b"CLOUDH", *b"CHCPUSDT", 1);
+
+        #[cfg(target_arch = "x86_64")]
+        let topology = self.get_vcpu_topology();
+        let cpu_devices: Vec<Cpu> = (first_vcpu..max_vcpus)
+            .map(|cpu_id| Cpu {
+                cpu_id,
+                proximity_domain: *self.proximity_domain_per_cpu.get(&cpu_id).unwrap_or(&0),
+                dynamic: self.dynamic,
+                #[cfg(target_arch = "x86_64")]
+                topology,
+            })
+            .collect();
+        let cpu_devices_refs: Vec<&dyn Aml> =
+            cpu_devices.iter().map(|cpu| cpu as &dyn Aml).collect();
+
+        let mut bytes = Vec::new();
+        aml::Scope::new("_SB_.CPUS".into(), cpu_devices_refs).to_aml_bytes(&mut bytes);
+        ssdt.append_slice(&bytes);
+
+        ssdt
+    }
+
+    // Generate the SSDT for the vCPUs the guest doesn't know about yet. It
+    // stays the same until the guest acknowledges loading it, even if the
+    // maximum number of vCPUs is raised again in the meantime.
+    fn latch_hotplug_ssdt(&mut self) -> bool {
+        if self.hotplug_ssdt.is_none() && self.acpi_max_vcpus < self.config.max_vcpus {
+            let ssdt = self.create_hotplug_ssdt(self.acpi_max_vcpus, self.config.max_vcpus);
+            self.hotplug_ssdt = Some((self.config.max_vcpus, ssdt.as_slice().to_vec()));
+        }
+
+        self.hotplug_ssdt.is_some()
+    }
+
     pub fn create_madt(&self) -> Sdt {
         use crate::acpi;
         // This is also checked in the commandline parsing.
@@ -1485,7 +1943,12 @@ impl CpuManager {
                     uid: cpu as u32,
                     flags: 1,
                     parking_version: 0,
-                    performance_interrupt: 0,
+                    // The PMU is otherwise only described in the FDT.
+                    performance_interrupt: if vcpu.lock().unwrap().vcpu.has_pmu_support() {
+                        arch::aarch64::fdt::AARCH64_PMU_IRQ + 16
+                    } else {
+                        0
+                    },
                     parked_address: 0,
                     base_address: 0,
                     gicv_base_address: 0,
@@ -1861,6 +2324,10 @@ impl CpuManager {
         self.interrupt_controller = Some(interrupt_controller);
     }
 
+    pub(crate) fn set_cgroup_manager(&mut self, cgroup_manager: Arc<CgroupManager>) {
+        self.cgroup_manager = Some(cgroup_manager);
+    }
+
     pub(crate) fn vcpus_kill_signalled(&self) -> &Arc<AtomicBool> {
         &self.vcpus_kill_signalled
     }
@@ -1888,6 +2355,14 @@ impl CpuManager {
         self.sev_snp_enabled
     }
 
+    // Only the boot vCPU runs the waking vector, the guest brings the other
+    // ones back online by itself.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn wakeup(&self, waking_vector: u32) -> Result<()> {
+        let vcpu = self.vcpus[0].lock().unwrap();
+        arch::regs::setup_wakeup_regs(&vcpu.vcpu, waking_vector).map_err(Error::VcpuWakeup)
+    }
+
     pub(crate) fn nmi(&self) -> Result<()> {
         self.vcpus_kick_signalled.store(true, Ordering::SeqCst);
 
@@ -2039,6 +2514,21 @@ impl Aml for CpuNotify {
     }
 }
 
+// Load the definition block found in the operation region, storing the
+// resulting DDB handle into Local1.
+struct LoadTable {
+    region: &'static str,
+}
+
+impl Aml for LoadTable {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
+        sink.byte(0x5b); // ExtOpPrefix
+        sink.byte(0x20); // LoadOp
+        aml::Path::new(self.region).to_aml_bytes(sink);
+        aml::Local(1).to_aml_bytes(sink);
+    }
+}
+
 struct CpuMethods {
     max_vcpus: u8,
     dynamic: bool,
@@ -2105,9 +2595,20 @@ impl Aml for CpuMethods {
                 vec![
                     // Take lock defined above
                     &aml::Acquire::new("\\_SB_.PRES.CPLK".into(), 0xffff),
+                    // Load the SSDT describing the vCPUs added by raising the
+                    // maximum number of vCPUs, and acknowledge it
+                    &aml::If::new(
+                        &aml::Equal::new(&aml::Path::new("\\_SB_.PRES.CSSD"), &aml::ONE),
+                        vec![
+                            &LoadTable {
+                                region: "\\_SB_.PRES.CSDT",
+                            },
+                            &aml::Store::new(&aml::Path::new("\\_SB_.PRES.CSSD"), &aml::ONE),
+                        ],
+                    ),
                     &aml::Store::new(&aml::Local(0), &aml::ZERO),
                     &aml::While::new(
-                        &aml::LessThan::new(&aml::Local(0), &self.max_vcpus),
+                        &aml::LessThan::new(&aml::Local(0), &aml::Path::new("\\_SB_.PRES.CMAX")),
                         vec![
                             // Write CPU number (in first argument) to I/O port via field
                             &aml::Store::new(&aml::Path::new("\\_SB_.PRES.CSEL"), &aml::Local(0)),
@@ -2184,7 +2685,14 @@ impl Aml for CpuManager {
                         "PRST".into(),
                         aml::OpRegionSpace::SystemMemory,
                         &(acpi_address.0 as usize),
-                        &CPU_MANAGER_ACPI_SIZE,
+                        &(CPU_SSDT_OFFSET as usize),
+                    ),
+                    // SSDT describing the vCPUs added after boot
+                    &aml::OpRegion::new(
+                        "CSDT".into(),
+                        aml::OpRegionSpace::SystemMemory,
+                        &((acpi_address.0 + CPU_SSDT_OFFSET) as usize),
+                        &CPU_SSDT_SIZE,
                     ),
                     &aml::Field::new(
                         "PRST".into(),
@@ -2210,6 +2718,8 @@ impl Aml for CpuManager {
                             aml::FieldEntry::Named(*b"CSEL", 32),
                             aml::FieldEntry::Reserved(32),
                             aml::FieldEntry::Named(*b"CDAT", 32),
+                            aml::FieldEntry::Named(*b"CMAX", 32),
+                            aml::FieldEntry::Named(*b"CSSD", 32),
                         ],
                     ),
                 ],
@@ -2221,8 +2731,10 @@ impl Aml for CpuManager {
         let hid = aml::Name::new("_HID".into(), &"ACPI0010");
         let uid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A05"));
         // Bundle methods together under a common object
+        // Notifying vCPUs above the current maximum must be possible, as
+        // their devices can be added later through the hotplug SSDT.
         let methods = CpuMethods {
-            max_vcpus: self.config.max_vcpus,
+            max_vcpus: self.max_vcpus_limit(),
             dynamic: self.dynamic,
         };
         let mut cpu_data_inner: Vec<&dyn Aml> = vec![&hid, &uid, &methods];
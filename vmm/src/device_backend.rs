@@ -0,0 +1,907 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Out-of-process virtio device backends.
+//!
+//! Devices configured with `out_of_process=on` are not emulated by the VMM
+//! itself. Instead, a vhost-user backend process is spawned for each of them
+//! and the VMM connects to it as a regular vhost-user frontend. A compromise
+//! of the device emulation is then confined to the backend process, which
+//! only gets access to the guest memory regions shared over vhost-user.
+//!
+//! Alternatively, vhost-user devices configured with `exec=[<binary>,<args>...]` get
+//! the given command spawned as their backend, and network devices
+//! configured with `passt=on` get a passt instance spawned as their
+//! unprivileged userspace networking backend.
+//!
+//! In both cases the backend process is supervised: if it exits while the
+//! device is still in use, it is restarted and the vhost-user device
+//! reconnects to it, restoring the in-flight requests through the inflight
+//! shared memory if the backend supports it.
+//!
+//! The backends are not executed by the VMM itself, which is not allowed to
+//! call `execve()`. They are spawned by a launcher process forked at startup,
+//! before any thread is started and before the seccomp filters and Landlock
+//! rules are applied, so the backends don't inherit them either. The
+//! launcher is always started, so that the VMs created through the API and
+//! the hotplugged devices can get spawned backends too. It only executes the
+//! backends shipped with the VMM, passt, and the `exec` binaries of the VM
+//! given on the command line.
+
+use crate::config::{DiskConfig, FsConfig, NetConfig, PortForwardProtocol, VmConfig};
+use crate::security_label::SecurityLabel;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+use vmm_sys_util::tempdir::TempDir;
+
+const BLOCK_BACKEND_BINARY: &str = "vhost_user_block";
+const NET_BACKEND_BINARY: &str = "vhost_user_net";
+const FS_BACKEND_BINARY: &str = "virtiofsd";
+const PASST_BINARY: &str = "passt";
+
+// Give up restarting a backend which keeps on exiting: it is only restarted
+// if it ran for at least BACKEND_MIN_UPTIME, or if it did not fail more than
+// BACKEND_MAX_QUICK_RESTARTS times in a row.
+const BACKEND_MIN_UPTIME: Duration = Duration::from_secs(10);
+const BACKEND_MAX_QUICK_RESTARTS: u32 = 5;
+const BACKEND_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+// Maximum size of the messages exchanged with the launcher process.
+const LAUNCHER_MSG_SIZE: usize = 64 << 10;
+
+// The launcher process, set once at startup.
+static LAUNCHER: OnceLock<Launcher> = OnceLock::new();
+
+struct Launcher {
+    socket: Mutex<UnixStream>,
+    // Binaries the launcher is allowed to execute
+    binaries: Vec<PathBuf>,
+}
+
+#[derive(Debug, Error)]
+pub enum DeviceBackendError {
+    #[error("Error spawning backend {0:?}: {1}")]
+    Spawn(PathBuf, #[source] io::Error),
+
+    #[error("Error spawning backend supervisor thread: {0}")]
+    SpawnSupervisor(#[source] io::Error),
+
+    #[error("Missing disk path for out-of-process backend")]
+    MissingDiskPath,
+
+    #[error("Missing shared directory for virtio-fs backend")]
+    MissingSharedDir,
+
+    #[error("Empty backend command")]
+    EmptyCommand,
+
+    #[error("Error starting the backend launcher process: {0}")]
+    StartLauncher(#[source] io::Error),
+
+    #[error("No backend launcher")]
+    NoLauncher,
+
+    #[error("Backend {0:?} is not allowed, exec binaries must be used by the VM given on the command line")]
+    BinaryNotAllowed(PathBuf),
+
+    #[error("Value {1:?} of backend option {0} can't be passed to the backend")]
+    InvalidOptionValue(&'static str, String),
+
+    #[error("Error communicating with the backend launcher process: {0}")]
+    Launcher(#[source] io::Error),
+
+    #[error("Error creating the backend socket directory: {0}")]
+    SocketDir(#[source] vmm_sys_util::errno::Error),
+}
+
+type Result<T> = std::result::Result<T, DeviceBackendError>;
+
+/// Look for the backend binary next to the VMM binary first, falling back
+/// to a lookup through `PATH`.
+fn backend_binary(name: &str) -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(name)))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// Create a socket path for a backend in a private directory, which is only
+/// accessible to the VMM user and whose name can't be guessed by others.
+fn default_socket_path(id: &str) -> Result<(TempDir, String)> {
+    let dir = TempDir::new_with_prefix(std::env::temp_dir().join("cloud-hypervisor-"))
+        .map_err(DeviceBackendError::SocketDir)?;
+    let socket = dir
+        .as_path()
+        .join(format!("{id}.sock"))
+        .to_string_lossy()
+        .into_owned();
+    Ok((dir, socket))
+}
+
+// The backends parse their options the way the VMM does, as a comma separated
+// list in which the values holding commas are quoted. Quotes can't be
+// escaped, and brackets are matched even when quoted.
+fn backend_option(name: &'static str, value: &str) -> Result<String> {
+    if value.contains(['"', '[', ']']) || value.trim() != value {
+        return Err(DeviceBackendError::InvalidOptionValue(
+            name,
+            value.to_string(),
+        ));
+    }
+
+    Ok(if value.contains(',') {
+        format!("{name}=\"{value}\"")
+    } else {
+        format!("{name}={value}")
+    })
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackendCommand {
+    binary: PathBuf,
+    args: Vec<String>,
+    label: Option<SecurityLabel>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum LauncherReply {
+    // The pidfd of the backend process comes along with the message.
+    Spawned(u32),
+    Error(String),
+}
+
+fn disk_backend_binary(disk: &DiskConfig) -> Option<PathBuf> {
+    if disk.out_of_process {
+        Some(backend_binary(BLOCK_BACKEND_BINARY))
+    } else {
+        disk.exec.as_ref()?.first().map(PathBuf::from)
+    }
+}
+
+fn net_backend_binary(net: &NetConfig) -> Option<PathBuf> {
+    if net.out_of_process {
+        Some(backend_binary(NET_BACKEND_BINARY))
+    } else if net.passt {
+        Some(PathBuf::from(PASST_BINARY))
+    } else {
+        net.exec.as_ref()?.first().map(PathBuf::from)
+    }
+}
+
+fn fs_backend_binary(fs: &FsConfig) -> Option<PathBuf> {
+    if fs.shared_dir.is_some() {
+        Some(backend_binary(FS_BACKEND_BINARY))
+    } else {
+        fs.exec.as_ref()?.first().map(PathBuf::from)
+    }
+}
+
+/// Binaries of the spawned backends needed by the devices of `config`.
+fn backend_binaries(config: &VmConfig) -> Vec<PathBuf> {
+    let mut binaries: Vec<PathBuf> = config
+        .disks
+        .iter()
+        .flatten()
+        .filter_map(disk_backend_binary)
+        .chain(config.net.iter().flatten().filter_map(net_backend_binary))
+        .chain(config.fs.iter().flatten().filter_map(fs_backend_binary))
+        .collect();
+
+    binaries.sort();
+    binaries.dedup();
+    binaries
+}
+
+/// Binaries the launcher executes, whichever VM needs them: the backends
+/// shipped with the VMM, passt, and the binaries needed by `config`, the VM
+/// given on the command line.
+pub fn launcher_binaries(config: Option<&VmConfig>) -> Vec<PathBuf> {
+    let mut binaries = vec![
+        backend_binary(BLOCK_BACKEND_BINARY),
+        backend_binary(NET_BACKEND_BINARY),
+        backend_binary(FS_BACKEND_BINARY),
+        PathBuf::from(PASST_BINARY),
+    ];
+    binaries.extend(config.map(backend_binaries).unwrap_or_default());
+
+    binaries.sort();
+    binaries.dedup();
+    binaries
+}
+
+fn check_binaries(binaries: &[PathBuf], allowed: &[PathBuf]) -> Result<()> {
+    for binary in binaries {
+        if !allowed.contains(binary) {
+            return Err(DeviceBackendError::BinaryNotAllowed(binary.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the launcher can spawn the backends needed by the devices of
+/// `config`, so that a VM or a device needing other binaries is rejected
+/// before being created.
+pub fn check_backends(config: &VmConfig) -> Result<()> {
+    let binaries = backend_binaries(config);
+    if binaries.is_empty() {
+        return Ok(());
+    }
+
+    let launcher = LAUNCHER.get().ok_or(DeviceBackendError::NoLauncher)?;
+    check_binaries(&binaries, &launcher.binaries)
+}
+
+/// Fork the launcher process spawning the backends on behalf of the VMM,
+/// limited to executing `binaries`. It must be called before any thread is
+/// started, as the forked process keeps on running Rust code.
+pub fn start_launcher(binaries: Vec<PathBuf>) -> Result<()> {
+    let mut fds = [0; 2];
+    // SAFETY: FFI call with a valid array of two fds
+    if unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    } != 0
+    {
+        return Err(DeviceBackendError::StartLauncher(io::Error::last_os_error()));
+    }
+    // SAFETY: the fds have just been created and are owned here
+    let (vmm_socket, launcher_socket) = unsafe {
+        (
+            UnixStream::from_raw_fd(fds[0]),
+            UnixStream::from_raw_fd(fds[1]),
+        )
+    };
+
+    // SAFETY: FFI call, the process is still single threaded
+    match unsafe { libc::fork() } {
+        -1 => Err(DeviceBackendError::StartLauncher(io::Error::last_os_error())),
+        0 => {
+            drop(vmm_socket);
+            run_launcher(launcher_socket, binaries)
+        }
+        _ => {
+            drop(launcher_socket);
+            LAUNCHER
+                .set(Launcher {
+                    socket: Mutex::new(vmm_socket),
+                    binaries,
+                })
+                .ok();
+            Ok(())
+        }
+    }
+}
+
+fn run_launcher(mut socket: UnixStream, binaries: Vec<PathBuf>) -> ! {
+    // Don't outlive the VMM, which is the only one able to stop the
+    // backends.
+    // SAFETY: FFI calls with valid arguments
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        libc::prctl(libc::PR_SET_NAME, c"ch-launcher".as_ptr());
+    }
+
+    let mut buf = vec![0u8; LAUNCHER_MSG_SIZE];
+    loop {
+        // The VMM closing its end of the socket is the signal to exit.
+        let len = match socket.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+
+        let reply = match serde_json::from_slice::<BackendCommand>(&buf[..len]) {
+            Ok(command) if binaries.contains(&command.binary) => command.exec(),
+            Ok(command) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} is not a configured backend", command.binary),
+            )),
+            Err(e) => Err(e.into()),
+        };
+
+        let sent = match reply {
+            Ok((pid, pidfd)) => {
+                let reply = serde_json::to_vec(&LauncherReply::Spawned(pid)).unwrap();
+                socket
+                    .send_with_fd(&reply[..], pidfd.as_raw_fd())
+                    .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+            }
+            Err(e) => {
+                let reply = serde_json::to_vec(&LauncherReply::Error(e.to_string())).unwrap();
+                socket.write_all(&reply)
+            }
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+
+    std::process::exit(0)
+}
+
+impl BackendCommand {
+    // Run by the launcher process.
+    fn exec(&self) -> io::Result<(u32, OwnedFd)> {
+        // The backend should not outlive the launcher, and must not be able
+        // to gain more privileges than it was started with.
+        let mut command = Command::new(&self.binary);
+        command.args(&self.args).stdin(Stdio::null());
+        let label = self.label.clone();
+        // SAFETY: only async-signal-safe functions are called between fork
+        // and exec.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0
+                    || libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                // SIGCHLD is ignored by the VMM, which would be inherited.
+                libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+                if let Some(label) = &label {
+                    label.apply()?;
+                }
+                Ok(())
+            })
+        };
+
+        // The child is reaped automatically as SIGCHLD is ignored, the VMM
+        // watches it through its pidfd.
+        let child = command.spawn()?;
+        // SAFETY: FFI call with valid arguments
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, child.id(), 0) };
+        if pidfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the pidfd has just been created and is owned here
+        Ok((child.id(), unsafe { OwnedFd::from_raw_fd(pidfd as i32) }))
+    }
+
+    // Run by the VMM, asking the launcher to spawn the backend.
+    fn spawn(&self) -> Result<BackendProcess> {
+        let launcher = LAUNCHER
+            .get()
+            .ok_or(DeviceBackendError::NoLauncher)?
+            .socket
+            .lock()
+            .unwrap();
+
+        let request =
+            serde_json::to_vec(self).map_err(|e| DeviceBackendError::Launcher(e.into()))?;
+        (&*launcher)
+            .write_all(&request)
+            .map_err(DeviceBackendError::Launcher)?;
+
+        let mut buf = vec![0u8; LAUNCHER_MSG_SIZE];
+        let (len, pidfd) = launcher
+            .recv_with_fd(&mut buf)
+            .map_err(|e| DeviceBackendError::Launcher(io::Error::from_raw_os_error(e.errno())))?;
+        let reply = serde_json::from_slice::<LauncherReply>(&buf[..len])
+            .map_err(|e| DeviceBackendError::Launcher(e.into()))?;
+
+        match (reply, pidfd) {
+            (LauncherReply::Spawned(pid), Some(pidfd)) => Ok(BackendProcess { pid, pidfd }),
+            (LauncherReply::Spawned(_), None) => Err(DeviceBackendError::Launcher(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing backend pidfd",
+            ))),
+            (LauncherReply::Error(e), _) => Err(DeviceBackendError::Spawn(
+                self.binary.clone(),
+                io::Error::other(e),
+            )),
+        }
+    }
+}
+
+/// A backend process, referred to through its pidfd as it is a child of the
+/// launcher rather than of the VMM.
+struct BackendProcess {
+    pid: u32,
+    pidfd: File,
+}
+
+impl BackendProcess {
+    // Block until the process referred to by `pidfd` exits.
+    fn wait_pidfd(pidfd: &File) -> io::Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            // SAFETY: FFI call with a valid pollfd
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        // SAFETY: FFI call with a valid pidfd
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.pidfd.as_raw_fd(),
+                libc::SIGKILL,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A supervised backend process serving a single virtio device. The process
+/// is killed when this is dropped.
+pub struct DeviceBackend {
+    socket: String,
+    // Private directory holding the socket, when not provided by the user.
+    _socket_dir: Option<TempDir>,
+    stopping: Arc<AtomicBool>,
+    process: Arc<Mutex<Option<BackendProcess>>>,
+    supervisor: Option<thread::JoinHandle<()>>,
+}
+
+impl DeviceBackend {
+    fn spawn(
+        id: &str,
+        command: BackendCommand,
+        socket: String,
+        socket_dir: Option<TempDir>,
+    ) -> Result<Self> {
+        let process = command.spawn()?;
+
+        info!(
+            "Spawned device backend {:?} (pid {}) for {} on socket {}",
+            command.binary, process.pid, id, socket
+        );
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let process = Arc::new(Mutex::new(Some(process)));
+
+        let supervisor = {
+            let id = id.to_owned();
+            let stopping = stopping.clone();
+            let process = process.clone();
+            thread::Builder::new()
+                .name(format!("{id}_backend"))
+                .spawn(move || {
+                    let mut quick_restarts = 0;
+                    loop {
+                        let started = Instant::now();
+                        // Only this thread replaces the process, so it can
+                        // be waited for without holding the lock.
+                        let pidfd = process.lock().unwrap().as_ref().unwrap().pidfd.try_clone();
+                        if let Err(e) = pidfd.and_then(|pidfd| BackendProcess::wait_pidfd(&pidfd)) {
+                            error!("Error waiting for device backend for {}: {}", id, e);
+                            break;
+                        }
+                        // The process is gone, it must not be signaled
+                        // anymore.
+                        *process.lock().unwrap() = None;
+                        if stopping.load(Ordering::SeqCst) {
+                            break;
+                        }
+
+                        warn!("Device backend for {} exited", id);
+
+                        if started.elapsed() >= BACKEND_MIN_UPTIME {
+                            quick_restarts = 0;
+                        } else {
+                            quick_restarts += 1;
+                            if quick_restarts > BACKEND_MAX_QUICK_RESTARTS {
+                                error!("Device backend for {} keeps on exiting, giving up", id);
+                                event!("vm", "device-backend-failed", "id", &id);
+                                break;
+                            }
+                        }
+
+                        thread::sleep(BACKEND_RESTART_DELAY * quick_restarts);
+
+                        // Take the lock before spawning so that a concurrent
+                        // drop either sees the new process or stops the loop.
+                        let mut process = process.lock().unwrap();
+                        if stopping.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        let new_process = match command.spawn() {
+                            Ok(new_process) => new_process,
+                            Err(e) => {
+                                error!("Error restarting device backend for {}: {}", id, e);
+                                event!("vm", "device-backend-failed", "id", &id);
+                                break;
+                            }
+                        };
+
+                        event!("vm", "device-backend-restarted", "id", &id);
+                        info!(
+                            "Restarted device backend for {} (pid {})",
+                            id, new_process.pid
+                        );
+                        *process = Some(new_process);
+                    }
+                })
+                .map_err(DeviceBackendError::SpawnSupervisor)?
+        };
+
+        Ok(DeviceBackend {
+            socket,
+            _socket_dir: socket_dir,
+            stopping,
+            process,
+            supervisor: Some(supervisor),
+        })
+    }
+
+    fn block_args(disk_cfg: &DiskConfig, path: &Path, socket: &str) -> Result<Vec<String>> {
+        let backend = [
+            backend_option("path", &path.to_string_lossy())?,
+            backend_option("socket", socket)?,
+            format!("num_queues={}", disk_cfg.num_queues),
+            format!("queue_size={}", disk_cfg.queue_size),
+            format!("readonly={}", on_off(disk_cfg.readonly)),
+            format!("direct={}", on_off(disk_cfg.direct)),
+        ];
+
+        Ok(vec!["--block-backend".to_string(), backend.join(",")])
+    }
+
+    fn net_args(net_cfg: &NetConfig, socket: &str) -> Result<Vec<String>> {
+        let mut backend = vec![
+            format!("ip={}", net_cfg.ip),
+            format!("mask={}", net_cfg.mask),
+            backend_option("socket", socket)?,
+            format!("num_queues={}", net_cfg.num_queues),
+            format!("queue_size={}", net_cfg.queue_size),
+        ];
+        if let Some(tap) = &net_cfg.tap {
+            backend.push(backend_option("tap", tap)?);
+        }
+        if let Some(host_mac) = &net_cfg.host_mac {
+            backend.push(format!("host_mac={host_mac}"));
+        }
+        if let Some(mtu) = net_cfg.mtu {
+            backend.push(format!("mtu={mtu}"));
+        }
+
+        Ok(vec!["--net-backend".to_string(), backend.join(",")])
+    }
+
+    fn passt_args(net_cfg: &NetConfig, socket: &str) -> Vec<String> {
+        // Stay in the foreground so that the process can be supervised.
+        let mut args = vec![
+            "--vhost-user".to_string(),
+            "--foreground".to_string(),
+            "--socket".to_string(),
+            socket.to_string(),
+        ];
+        if let Some(mtu) = net_cfg.mtu {
+            args.push("--mtu".to_string());
+            args.push(mtu.to_string());
+        }
+        for rule in net_cfg.port_forward.iter().flatten() {
+            args.push(
+                match rule.protocol {
+                    PortForwardProtocol::Tcp => "--tcp-ports",
+                    PortForwardProtocol::Udp => "--udp-ports",
+                }
+                .to_string(),
+            );
+            args.push(format!("{}:{}", rule.host_port, rule.guest_port));
+        }
+
+        args
+    }
+
+    pub fn spawn_block(
+        id: &str,
+        disk_cfg: &DiskConfig,
+        label: Option<SecurityLabel>,
+    ) -> Result<Self> {
+        let path = disk_cfg
+            .path
+            .as_ref()
+            .ok_or(DeviceBackendError::MissingDiskPath)?;
+        let (socket_dir, socket) = match &disk_cfg.vhost_socket {
+            Some(socket) => (None, socket.clone()),
+            None => default_socket_path(id).map(|(dir, socket)| (Some(dir), socket))?,
+        };
+
+        let command = BackendCommand {
+            binary: backend_binary(BLOCK_BACKEND_BINARY),
+            args: Self::block_args(disk_cfg, path, &socket)?,
+            label,
+        };
+
+        Self::spawn(id, command, socket, socket_dir)
+    }
+
+    pub fn spawn_net(id: &str, net_cfg: &NetConfig, label: Option<SecurityLabel>) -> Result<Self> {
+        let (socket_dir, socket) = match &net_cfg.vhost_socket {
+            Some(socket) => (None, socket.clone()),
+            None => default_socket_path(id).map(|(dir, socket)| (Some(dir), socket))?,
+        };
+
+        let command = BackendCommand {
+            binary: backend_binary(NET_BACKEND_BINARY),
+            args: Self::net_args(net_cfg, &socket)?,
+            label,
+        };
+
+        Self::spawn(id, command, socket, socket_dir)
+    }
+
+    pub fn spawn_passt(
+        id: &str,
+        net_cfg: &NetConfig,
+        label: Option<SecurityLabel>,
+    ) -> Result<Self> {
+        let (socket_dir, socket) = match &net_cfg.vhost_socket {
+            Some(socket) => (None, socket.clone()),
+            None => default_socket_path(id).map(|(dir, socket)| (Some(dir), socket))?,
+        };
+
+        let command = BackendCommand {
+            binary: PathBuf::from(PASST_BINARY),
+            args: Self::passt_args(net_cfg, &socket),
+            label,
+        };
+
+        Self::spawn(id, command, socket, socket_dir)
+    }
+
+    pub fn spawn_fs(id: &str, fs_cfg: &FsConfig, label: Option<SecurityLabel>) -> Result<Self> {
+        let shared_dir = fs_cfg
+            .shared_dir
+            .as_ref()
+            .ok_or(DeviceBackendError::MissingSharedDir)?;
+        let socket = fs_cfg.socket.to_string_lossy().into_owned();
+
+        let command = BackendCommand {
+            binary: backend_binary(FS_BACKEND_BINARY),
+            args: vec![
+                format!("--socket-path={socket}"),
+                format!("--shared-dir={}", shared_dir.display()),
+            ],
+            label,
+        };
+
+        Self::spawn(id, command, socket, None)
+    }
+
+    /// Spawn a user provided backend command, given as the binary followed
+    /// by its arguments, which is expected to serve the vhost-user protocol
+    /// on `socket`.
+    pub fn spawn_exec(
+        id: &str,
+        exec: &[String],
+        socket: String,
+        label: Option<SecurityLabel>,
+    ) -> Result<Self> {
+        let (binary, args) = exec
+            .split_first()
+            .filter(|(binary, _)| !binary.is_empty())
+            .ok_or(DeviceBackendError::EmptyCommand)?;
+        let command = BackendCommand {
+            binary: PathBuf::from(binary),
+            args: args.to_vec(),
+            label,
+        };
+
+        Self::spawn(id, command, socket, None)
+    }
+
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+}
+
+impl Drop for DeviceBackend {
+    fn drop(&mut self) {
+        {
+            let process = self.process.lock().unwrap();
+            self.stopping.store(true, Ordering::SeqCst);
+            if let Some(process) = process.as_ref() {
+                if let Err(e) = process.kill() {
+                    warn!("Error killing device backend (pid {}): {}", process.pid, e);
+                }
+            }
+        }
+
+        if let Some(supervisor) = self.supervisor.take() {
+            if let Err(e) = supervisor.join() {
+                error!("Error joining device backend supervisor thread: {:?}", e);
+            }
+        }
+
+        if Path::new(&self.socket).exists() {
+            let _ = std::fs::remove_file(&self.socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use option_parser::OptionParser;
+
+    fn parse_backend(backend: &str, options: &[&str]) -> OptionParser {
+        let mut parser = OptionParser::new();
+        for option in options {
+            parser.add(option);
+        }
+        parser.parse(backend).unwrap();
+        parser
+    }
+
+    #[test]
+    fn test_backend_option() {
+        assert_eq!(
+            backend_option("path", "/tmp/disk.img").unwrap(),
+            "path=/tmp/disk.img"
+        );
+        assert_eq!(
+            backend_option("path", "/tmp/disk,1.img").unwrap(),
+            "path=\"/tmp/disk,1.img\""
+        );
+        for value in ["/tmp/\"disk\"", "/tmp/disk[1]", "/tmp/disk ", " /tmp/disk"] {
+            assert!(matches!(
+                backend_option("path", value),
+                Err(DeviceBackendError::InvalidOptionValue("path", v)) if v == value
+            ));
+        }
+    }
+
+    #[test]
+    fn test_block_args() {
+        let disk_cfg =
+            DiskConfig::parse("path=\"/tmp/disk,1.img\",out_of_process=on,readonly=on").unwrap();
+        let args = DeviceBackend::block_args(
+            &disk_cfg,
+            disk_cfg.path.as_ref().unwrap(),
+            "/tmp/disk,1.sock",
+        )
+        .unwrap();
+        assert_eq!(args[0], "--block-backend");
+
+        let parser = parse_backend(
+            &args[1],
+            &[
+                "path",
+                "socket",
+                "num_queues",
+                "queue_size",
+                "readonly",
+                "direct",
+            ],
+        );
+        assert_eq!(parser.get("path").unwrap(), "/tmp/disk,1.img");
+        assert_eq!(parser.get("socket").unwrap(), "/tmp/disk,1.sock");
+        assert_eq!(parser.get("readonly").unwrap(), "on");
+        assert_eq!(parser.get("direct").unwrap(), "off");
+
+        assert!(DeviceBackend::block_args(
+            &disk_cfg,
+            Path::new("/tmp/disk[1].img"),
+            "/tmp/disk.sock"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_net_args() {
+        let net_cfg = NetConfig::parse("tap=\"tap,0\",mtu=1400,out_of_process=on").unwrap();
+        let args = DeviceBackend::net_args(&net_cfg, "/tmp/net.sock").unwrap();
+        assert_eq!(args[0], "--net-backend");
+
+        let parser = parse_backend(
+            &args[1],
+            &[
+                "ip",
+                "mask",
+                "socket",
+                "num_queues",
+                "queue_size",
+                "tap",
+                "mtu",
+            ],
+        );
+        assert_eq!(parser.get("tap").unwrap(), "tap,0");
+        assert_eq!(parser.get("socket").unwrap(), "/tmp/net.sock");
+        assert_eq!(parser.get("mtu").unwrap(), "1400");
+    }
+
+    #[test]
+    fn test_passt_args() {
+        let net_cfg =
+            NetConfig::parse("passt=on,mtu=1400,port_forward=[2222:22,udp:5353:53]").unwrap();
+        assert_eq!(
+            DeviceBackend::passt_args(&net_cfg, "/tmp/passt,0.sock"),
+            [
+                "--vhost-user",
+                "--foreground",
+                "--socket",
+                "/tmp/passt,0.sock",
+                "--mtu",
+                "1400",
+                "--tcp-ports",
+                "2222:22",
+                "--udp-ports",
+                "5353:53",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backend_binaries() {
+        let disk_cfg = DiskConfig::parse("path=/tmp/disk.img,out_of_process=on").unwrap();
+        assert_eq!(
+            disk_backend_binary(&disk_cfg),
+            Some(backend_binary(BLOCK_BACKEND_BINARY))
+        );
+        let disk_cfg =
+            DiskConfig::parse("vhost_user=on,socket=/tmp/disk.sock,exec=[/usr/bin/backend,-v]")
+                .unwrap();
+        assert_eq!(
+            disk_backend_binary(&disk_cfg),
+            Some(PathBuf::from("/usr/bin/backend"))
+        );
+        let net_cfg = NetConfig::parse("passt=on").unwrap();
+        assert_eq!(
+            net_backend_binary(&net_cfg),
+            Some(PathBuf::from(PASST_BINARY))
+        );
+        let net_cfg = NetConfig::parse("tap=tap0").unwrap();
+        assert_eq!(net_backend_binary(&net_cfg), None);
+
+        // The launcher of a VMM started without VM executes the backends
+        // shipped with the VMM and passt, but no exec binary.
+        let allowed = launcher_binaries(None);
+        assert!(check_binaries(
+            &[
+                backend_binary(BLOCK_BACKEND_BINARY),
+                PathBuf::from(PASST_BINARY)
+            ],
+            &allowed
+        )
+        .is_ok());
+        assert!(matches!(
+            check_binaries(&[PathBuf::from("/usr/bin/backend")], &allowed),
+            Err(DeviceBackendError::BinaryNotAllowed(p)) if p == Path::new("/usr/bin/backend")
+        ));
+    }
+}
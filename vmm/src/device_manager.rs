@@ -10,17 +10,23 @@
 //
 
 use crate::config::{
-    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, UserDeviceConfig,
-    VdpaConfig, VhostMode, VmConfig, VsockConfig,
+    ConsoleOutputMode, CxlConfig, DeviceConfig, DiskConfig, Fs9pConfig, FsConfig, I2cConfig,
+    IvshmemConfig, NetConfig, NvmeConfig, PmemConfig, UsbDeviceConfig, UserDeviceConfig,
+    VdpaConfig, VhostMode, VmConfig, VsockConfig, XhciConfig,
 };
 use crate::console_devices::{ConsoleDeviceError, ConsoleInfo};
 use crate::cpu::{CpuManager, CPU_MANAGER_ACPI_SIZE};
+use crate::device_backend::{DeviceBackend, DeviceBackendError};
 use crate::device_tree::{DeviceNode, DeviceTree};
 use crate::interrupt::LegacyUserspaceInterruptManager;
 use crate::interrupt::MsiInterruptManager;
+use crate::io_affinity::IoAffinity;
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager, MEMORY_MANAGER_ACPI_SIZE};
 use crate::pci_segment::PciSegment;
-use crate::serial_manager::{Error as SerialManagerError, SerialManager};
+use crate::security_label::{self, SecurityLabel};
+use crate::serial_manager::{
+    Error as SerialManagerError, SerialCapture, SerialManager, SerialOutputCopies,
+};
 use crate::vm_config::DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT;
 use crate::GuestRegionMmap;
 use crate::PciDeviceInfo;
@@ -35,9 +41,19 @@ use arch::NumaNodes;
 #[cfg(target_arch = "aarch64")]
 use arch::{DeviceType, MmioDeviceInfo};
 use block::{
-    async_io::DiskFile, block_aio_is_supported, block_io_uring_is_supported, detect_image_type,
-    fixed_vhd_sync::FixedVhdDiskSync, qcow, qcow_sync::QcowDiskSync, raw_async_aio::RawFileDiskAio,
-    raw_sync::RawFileDiskSync, vhdx, vhdx_sync::VhdxDiskSync, ImageType,
+    async_io::DiskFile,
+    block_aio_is_supported, block_io_uring_is_supported, detect_image_type,
+    fixed_vhd_sync::FixedVhdDiskSync,
+    luks,
+    luks_sync::LuksDiskSync,
+    qcow,
+    qcow_sync::QcowDiskSync,
+    raw_async_aio::RawFileDiskAio,
+    raw_async_thread_pool::{RawFileDiskThreadPool, DEFAULT_THREAD_POOL_QUEUE_DEPTH},
+    raw_sync::RawFileDiskSync,
+    vhdx,
+    vhdx_sync::VhdxDiskSync,
+    ImageType,
 };
 #[cfg(feature = "io_uring")]
 use block::{fixed_vhd_async::FixedVhdDiskAsync, raw_async::RawFileDisk};
@@ -54,14 +70,14 @@ use devices::pvmemcontrol::{PvmemcontrolBusDevice, PvmemcontrolPciDevice};
 use devices::{
     interrupt_controller, interrupt_controller::InterruptController, AcpiNotificationFlags,
 };
-use hypervisor::IoEventAddress;
+use hypervisor::{DataMatch, IoEventAddress};
 use libc::{
     tcsetattr, termios, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE,
     TCSANOW,
 };
 use pci::{
-    DeviceRelocation, MmioRegion, PciBarRegionType, PciBdf, PciDevice, VfioDmaMapping,
-    VfioPciDevice, VfioUserDmaMapping, VfioUserPciDevice, VfioUserPciDeviceError,
+    DeviceRelocation, MmioRegion, PciBarRegionType, PciBdf, PciDevice, PciPowerState,
+    VfioDmaMapping, VfioPciDevice, VfioUserDmaMapping, VfioUserPciDevice, VfioUserPciDeviceError,
 };
 use rate_limiter::group::RateLimiterGroup;
 use seccompiler::SeccompAction;
@@ -76,16 +92,19 @@ use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tracer::trace_scoped;
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioDeviceFd};
 use virtio_devices::transport::VirtioTransport;
-use virtio_devices::transport::{VirtioPciDevice, VirtioPciDeviceActivator};
+use virtio_devices::transport::{
+    VirtioMmioDevice, VirtioPciDevice, VirtioPciDeviceActivator, VIRTIO_MMIO_QUEUE_NOTIFY,
+    VIRTIO_MMIO_SIZE,
+};
 use virtio_devices::vhost_user::VhostUserConfig;
 use virtio_devices::{
     AccessPlatformMapping, ActivateError, VdpaDmaMapping, VirtioMemMappingSource,
 };
-use virtio_devices::{Endpoint, IommuMapping};
+use virtio_devices::{Endpoint, GpuDisplay, InputEventSender, InputKind, IommuMapping};
 use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::dma_mapping::ExternalDmaMapping;
 use vm_device::interrupt::{
@@ -113,31 +132,47 @@ const MMIO_LEN: u64 = 0x1000;
 // Singleton devices / devices the user cannot name
 #[cfg(target_arch = "x86_64")]
 const IOAPIC_DEVICE_NAME: &str = "__ioapic";
+#[cfg(target_arch = "x86_64")]
+const CMOS_DEVICE_NAME: &str = "__cmos";
 const SERIAL_DEVICE_NAME: &str = "__serial";
 #[cfg(target_arch = "x86_64")]
 const DEBUGCON_DEVICE_NAME: &str = "__debug_console";
 #[cfg(target_arch = "aarch64")]
 const GPIO_DEVICE_NAME: &str = "__gpio";
+// The pin of the GPIO controller the power button is wired to, as described
+// in the device tree.
+#[cfg(target_arch = "aarch64")]
+const GPIO_PIN_POWER_BUTTON: u16 = 3;
 const RNG_DEVICE_NAME: &str = "__rng";
+const GPU_DEVICE_NAME: &str = "__gpu";
+const KEYBOARD_DEVICE_NAME: &str = "__keyboard";
+const TABLET_DEVICE_NAME: &str = "__tablet";
 const IOMMU_DEVICE_NAME: &str = "__iommu";
 #[cfg(feature = "pvmemcontrol")]
 const PVMEMCONTROL_DEVICE_NAME: &str = "__pvmemcontrol";
 const BALLOON_DEVICE_NAME: &str = "__balloon";
 const CONSOLE_DEVICE_NAME: &str = "__console";
 const PVPANIC_DEVICE_NAME: &str = "__pvpanic";
+const XHCI_DEVICE_NAME: &str = "__xhci";
 
 // Devices that the user may name and for which we generate
 // identifiers if the user doesn't give one
+const CXL_DEVICE_NAME_PREFIX: &str = "_cxl";
 const DISK_DEVICE_NAME_PREFIX: &str = "_disk";
 const FS_DEVICE_NAME_PREFIX: &str = "_fs";
+const FS9P_DEVICE_NAME_PREFIX: &str = "_fs9p";
+const I2C_DEVICE_NAME_PREFIX: &str = "_i2c";
+const IVSHMEM_DEVICE_NAME_PREFIX: &str = "_ivshmem";
 const NET_DEVICE_NAME_PREFIX: &str = "_net";
 const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
+const USB_DEVICE_NAME_PREFIX: &str = "_usb";
 const VDPA_DEVICE_NAME_PREFIX: &str = "_vdpa";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
 const WATCHDOG_DEVICE_NAME: &str = "__watchdog";
 const VFIO_DEVICE_NAME_PREFIX: &str = "_vfio";
 const VFIO_USER_DEVICE_NAME_PREFIX: &str = "_vfio_user";
 const VIRTIO_PCI_DEVICE_NAME_PREFIX: &str = "_virtio-pci";
+const VIRTIO_MMIO_DEVICE_NAME_PREFIX: &str = "_virtio-mmio";
 
 /// Errors associated with device manager
 #[derive(Debug)]
@@ -163,12 +198,27 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-rng device
     CreateVirtioRng(io::Error),
 
+    /// Cannot create virtio-gpu device
+    CreateVirtioGpu(io::Error),
+
+    /// Cannot create virtio-input device
+    CreateVirtioInput(io::Error),
+
     /// Cannot create virtio-fs device
     CreateVirtioFs(virtio_devices::vhost_user::Error),
 
     /// Virtio-fs device was created without a socket.
     NoVirtioFsSock,
 
+    /// Cannot create virtio-9p device
+    CreateVirtioFs9p(io::Error),
+
+    /// Cannot create virtio-i2c device
+    CreateVirtioI2c(io::Error),
+
+    /// Cannot spawn out-of-process device backend
+    SpawnDeviceBackend(DeviceBackendError),
+
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(virtio_devices::vhost_user::Error),
 
@@ -184,6 +234,10 @@ pub enum DeviceManagerError {
     /// Cannot create tpm device
     CreateTpmDevice(anyhow::Error),
 
+    /// Cannot read fw_cfg file content
+    #[cfg(target_arch = "x86_64")]
+    ReadFwCfgFile(PathBuf, io::Error),
+
     /// Failed to convert Path to &str for the vDPA device.
     CreateVdpaConvertPath,
 
@@ -221,6 +275,9 @@ pub enum DeviceManagerError {
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
+    /// Cannot open macvtap interface
+    OpenMacvtap(net_util::OpenTapError),
+
     /// Cannot allocate IRQ.
     AllocateIrq,
 
@@ -242,6 +299,9 @@ pub enum DeviceManagerError {
     /// Cannot create virtio device
     VirtioDevice(virtio_devices::transport::VirtioPciDeviceError),
 
+    /// Cannot create virtio-mmio device
+    VirtioMmioDevice(virtio_devices::transport::VirtioMmioDeviceError),
+
     /// Cannot add PCI device
     AddPciDevice(pci::PciRootError),
 
@@ -399,6 +459,21 @@ pub enum DeviceManagerError {
     /// Trying to use a size that is not multiple of 2MiB
     PmemSizeNotAligned,
 
+    /// Persistent memory label storage area larger than the backing file
+    PmemLabelAreaTooLarge,
+
+    /// Cannot hotplug NVDIMM
+    NvdimmHotplugUnsupported,
+
+    /// Cannot create an NVMe controller
+    CreateNvme(devices::nvme::NvmeError),
+
+    /// Cannot hotplug or unplug NVMe namespaces
+    NvmeNamespaceHotplugUnsupported,
+
+    /// Memory zone of a CXL memory device not set up as such
+    MissingCxlMemoryZone(String),
+
     /// Could not find the node in the device tree.
     MissingNode,
 
@@ -445,9 +520,27 @@ pub enum DeviceManagerError {
     /// Failed to create QcowDiskSync
     CreateQcowDiskSync(qcow::Error),
 
+    /// Failed to create RawFileDiskThreadPool
+    CreateRawFileDiskThreadPool(io::Error),
+
+    /// Failed to create the overlay of a disk image
+    CreateDiskOverlay(io::Error),
+
     /// Failed to create FixedVhdxDiskSync
     CreateFixedVhdxDiskSync(vhdx::VhdxError),
 
+    /// Failed to create LuksDiskSync
+    CreateLuksDiskSync(luks::LuksError),
+
+    /// Failed to read the LUKS key file
+    ReadLuksKeyFile(io::Error),
+
+    /// LUKS disk image requires a key
+    MissingLuksKey,
+
+    /// Key provided for a disk image that isn't LUKS encrypted
+    UnexpectedLuksKey,
+
     /// Failed to add DMA mapping handler to virtio-mem device.
     AddDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
 
@@ -484,6 +577,9 @@ pub enum DeviceManagerError {
     /// Cannot hotplug device behind vIOMMU
     InvalidIommuHotplug,
 
+    /// Cannot hotplug virtio device with the virtio-mmio transport
+    VirtioMmioHotplugUnsupported,
+
     /// Invalid identifier as it is not unique.
     IdentifierNotUnique(String),
 
@@ -499,6 +595,36 @@ pub enum DeviceManagerError {
     /// Cannot create a PvPanic device
     PvPanicCreate(devices::pvpanic::PvPanicError),
 
+    /// Cannot create the vmclock device
+    VmclockCreate(devices::vmclock::VmclockError),
+
+    /// Cannot open the ivshmem shared memory file
+    IvshmemFileOpen(io::Error),
+
+    /// Cannot set the size of the ivshmem shared memory file
+    IvshmemFileSetLen(io::Error),
+
+    /// Cannot create an ivshmem device
+    CreateIvshmem(devices::ivshmem::IvshmemError),
+
+    /// Cannot map the ivshmem shared memory into the guest
+    MapIvshmem(devices::ivshmem::IvshmemError),
+
+    /// Cannot create the xHCI controller
+    CreateXhci(devices::usb::XhciError),
+
+    /// Cannot open the host USB device
+    OpenUsbDevice(std::io::Error),
+
+    /// Cannot attach the USB device to the xHCI controller
+    AttachUsbDevice(devices::usb::XhciError),
+
+    /// USB device passthrough without an xHCI controller
+    MissingXhciController,
+
+    /// USB device not identified by a valid host address
+    InvalidUsbDeviceAddress,
+
     /// Cannot create a RateLimiterGroup
     RateLimiterGroupCreate(rate_limiter::group::Error),
 
@@ -770,7 +896,15 @@ struct MetaVirtioDevice {
     iommu: bool,
     id: String,
     pci_segment: u16,
+    pci_bdf: Option<PciBdf>,
     dma_handler: Option<Arc<dyn ExternalDmaMapping>>,
+    transitional: bool,
+}
+
+struct PmemMapping {
+    file: File,
+    mapping: virtio_devices::UserspaceMapping,
+    mmap_region: MmapRegion,
 }
 
 #[derive(Default)]
@@ -824,6 +958,9 @@ pub struct DeviceManager {
     // Serial Manager
     serial_manager: Option<Arc<SerialManager>>,
 
+    // Capture of the serial output
+    serial_capture: Option<Arc<Mutex<SerialCapture>>>,
+
     // pty foreground status,
     console_resize_pipe: Option<Arc<File>>,
 
@@ -892,6 +1029,15 @@ pub struct DeviceManager {
     // information for filling the ACPI VIOT table.
     iommu_attached_devices: Option<(PciBdf, Vec<PciBdf>)>,
 
+    // PCI slots reserved at boot for devices hot-attached to the
+    // paravirtualized IOMMU, along with the ones still available.
+    iommu_hotplug_slots: Vec<PciBdf>,
+    free_iommu_hotplug_slots: Vec<PciBdf>,
+
+    // PCI slots reserved at boot for the devices pinned to a PCI address
+    // through the configuration, and not handed out yet.
+    pinned_pci_bdfs: BTreeSet<PciBdf>,
+
     // Tree of devices, representing the dependencies between devices.
     // Useful for introspection, snapshot and restore.
     device_tree: Arc<Mutex<DeviceTree>>,
@@ -899,10 +1045,19 @@ pub struct DeviceManager {
     // Exit event
     exit_evt: EventFd,
     reset_evt: EventFd,
+    // Guest panic event
+    panic_evt: EventFd,
+    // Guest hibernation event
+    hibernate_evt: EventFd,
+    // Guest suspend to RAM event
+    suspend_evt: EventFd,
 
     #[cfg(target_arch = "aarch64")]
     id_to_dev_info: HashMap<(DeviceType, String), MmioDeviceInfo>,
 
+    // MMIO range and IRQ of the virtio-mmio devices
+    virtio_mmio_devices: Vec<(GuestAddress, u32)>,
+
     // seccomp action
     seccomp_action: SeccompAction,
 
@@ -936,6 +1091,18 @@ pub struct DeviceManager {
     // pvpanic device
     pvpanic_device: Option<Arc<Mutex<devices::PvPanicDevice>>>,
 
+    // xHCI controller for USB host device passthrough
+    xhci: Option<Arc<Mutex<devices::usb::XhciController>>>,
+
+    // Display, keyboard and tablet exposed through VNC
+    vnc_devices: Option<(GpuDisplay, InputEventSender, InputEventSender)>,
+
+    // ACPI NVDIMMs
+    nvdimm_device: Option<Arc<Mutex<devices::NvdimmDevice>>>,
+
+    // vmclock device
+    vmclock_device: Option<devices::VmclockDevice>,
+
     // Flag to force setting the iommu on virtio devices
     force_iommu: bool,
 
@@ -961,7 +1128,15 @@ pub struct DeviceManager {
 
     rate_limit_groups: HashMap<String, Arc<RateLimiterGroup>>,
 
+    io_affinities: HashMap<String, IoAffinity>,
+
     mmio_regions: Arc<Mutex<Vec<MmioRegion>>>,
+
+    // Out-of-process backends, indexed by device identifier
+    device_backends: HashMap<String, DeviceBackend>,
+
+    // Label the out-of-process backends transition to
+    backend_label: Option<SecurityLabel>,
 }
 
 fn create_mmio_allocators(
@@ -1003,6 +1178,9 @@ impl DeviceManager {
         cpu_manager: Arc<Mutex<CpuManager>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        panic_evt: EventFd,
+        hibernate_evt: EventFd,
+        suspend_evt: EventFd,
         seccomp_action: SeccompAction,
         numa_nodes: NumaNodes,
         activate_evt: &EventFd,
@@ -1127,6 +1305,19 @@ impl DeviceManager {
             )?);
         }
 
+        // Reserve the slots of the devices pinned to a PCI address before
+        // anything else is placed, so no other device can end up there.
+        let mut pinned_pci_bdfs = BTreeSet::new();
+        for (bdf, _) in config.lock().unwrap().pinned_pci_bdfs() {
+            pci_segments[bdf.segment() as usize]
+                .pci_bus
+                .lock()
+                .unwrap()
+                .get_device_id(bdf.device() as usize)
+                .map_err(DeviceManagerError::GetPciDeviceId)?;
+            pinned_pci_bdfs.insert(bdf);
+        }
+
         if dynamic {
             let acpi_address = address_manager
                 .allocator
@@ -1172,6 +1363,22 @@ impl DeviceManager {
             }
         }
 
+        let io_affinities = config
+            .lock()
+            .unwrap()
+            .io_affinities
+            .iter()
+            .flatten()
+            .map(|io_affinity_cfg| (io_affinity_cfg.id.clone(), IoAffinity::new(io_affinity_cfg)))
+            .collect();
+
+        let backend_label = config
+            .lock()
+            .unwrap()
+            .security_label
+            .as_ref()
+            .and_then(security_label::backend_label);
+
         let device_manager = DeviceManager {
             address_manager: Arc::clone(&address_manager),
             console: Arc::new(Console::default()),
@@ -1192,12 +1399,19 @@ impl DeviceManager {
             iommu_device: None,
             iommu_mapping: None,
             iommu_attached_devices: None,
+            iommu_hotplug_slots: Vec::new(),
+            free_iommu_hotplug_slots: Vec::new(),
+            pinned_pci_bdfs,
             pci_segments,
             device_tree,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
+            virtio_mmio_devices: Vec::new(),
             seccomp_action,
             numa_nodes,
             balloon: None,
@@ -1207,6 +1421,7 @@ impl DeviceManager {
             acpi_address,
             selected_segment: 0,
             serial_manager: None,
+            serial_capture: None,
             console_resize_pipe: None,
             original_termios_opt: Arc::new(Mutex::new(None)),
             virtio_mem_devices: Vec::new(),
@@ -1215,6 +1430,10 @@ impl DeviceManager {
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol_devices: None,
             pvpanic_device: None,
+            xhci: None,
+            vnc_devices: None,
+            nvdimm_device: None,
+            vmclock_device: None,
             force_iommu,
             io_uring_supported: None,
             aio_supported: None,
@@ -1224,7 +1443,10 @@ impl DeviceManager {
             acpi_platform_addresses: AcpiPlatformAddresses::default(),
             snapshot,
             rate_limit_groups,
+            io_affinities,
             mmio_regions: Arc::new(Mutex::new(Vec::new())),
+            device_backends: HashMap::new(),
+            backend_label,
         };
 
         let device_manager = Arc::new(Mutex::new(device_manager));
@@ -1302,6 +1524,12 @@ impl DeviceManager {
                 self.exit_evt
                     .try_clone()
                     .map_err(DeviceManagerError::EventFd)?,
+                self.hibernate_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                self.suspend_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
             )?;
         }
 
@@ -1321,6 +1549,8 @@ impl DeviceManager {
         }
         self.legacy_interrupt_manager = Some(legacy_interrupt_manager);
 
+        self.add_nvdimm_devices()?;
+
         virtio_devices.append(&mut self.make_virtio_devices()?);
 
         self.add_pci_devices(virtio_devices.clone())?;
@@ -1342,6 +1572,20 @@ impl DeviceManager {
             self.pvpanic_device = self.add_pvpanic_device()?;
         }
 
+        if self.config.lock().unwrap().vmclock {
+            self.vmclock_device = Some(self.add_vmclock_device()?);
+        }
+
+        self.add_ivshmem_devices()?;
+        self.add_nvme_controllers()?;
+        self.add_cxl_devices()?;
+
+        let xhci_config = self.config.lock().unwrap().xhci.clone();
+        if let Some(xhci_config) = xhci_config {
+            self.xhci = Some(self.add_xhci_controller(&xhci_config)?);
+            self.add_usb_devices()?;
+        }
+
         Ok(())
     }
 
@@ -1408,9 +1652,15 @@ impl DeviceManager {
             None
         };
 
+        let virtio_mmio = self.config.lock().unwrap().is_virtio_mmio_enabled();
         let mut iommu_attached_devices = Vec::new();
         {
             for handle in virtio_devices {
+                if virtio_mmio {
+                    self.add_virtio_mmio_device(handle.virtio_device, handle.id)?;
+                    continue;
+                }
+
                 let mapping: Option<Arc<IommuMapping>> = if handle.iommu {
                     self.iommu_mapping.clone()
                 } else {
@@ -1422,7 +1672,9 @@ impl DeviceManager {
                     &mapping,
                     handle.id,
                     handle.pci_segment,
+                    handle.pci_bdf,
                     handle.dma_handler,
+                    handle.transitional,
                 )?;
 
                 if handle.iommu {
@@ -1450,8 +1702,24 @@ impl DeviceManager {
                 }
             }
 
+            if iommu_device.is_some() {
+                for bdf in self.reserve_iommu_hotplug_slots() {
+                    if !iommu_attached_devices.contains(&bdf) {
+                        iommu_attached_devices.push(bdf);
+                    }
+                }
+            }
+
             if let Some(iommu_device) = iommu_device {
-                let dev_id = self.add_virtio_pci_device(iommu_device, &None, iommu_id, 0, None)?;
+                let dev_id = self.add_virtio_pci_device(
+                    iommu_device,
+                    &None,
+                    iommu_id,
+                    0,
+                    None,
+                    None,
+                    false,
+                )?;
                 self.iommu_attached_devices = Some((dev_id, iommu_attached_devices));
             }
         }
@@ -1567,6 +1835,8 @@ impl DeviceManager {
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
         reset_evt: EventFd,
         exit_evt: EventFd,
+        hibernate_evt: EventFd,
+        suspend_evt: EventFd,
     ) -> DeviceManagerResult<Option<Arc<Mutex<devices::AcpiGedDevice>>>> {
         let vcpus_kill_signalled = self
             .cpu_manager
@@ -1577,6 +1847,8 @@ impl DeviceManager {
         let shutdown_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
             exit_evt,
             reset_evt,
+            hibernate_evt,
+            suspend_evt,
             vcpus_kill_signalled,
         )));
 
@@ -1709,11 +1981,19 @@ impl DeviceManager {
             let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
             let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
 
+            let rtc_config = self.config.lock().unwrap().rtc.clone().unwrap_or_default();
+            let id = String::from(CMOS_DEVICE_NAME);
+
             let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
+                id.clone(),
                 mem_below_4g,
                 mem_above_4g,
                 reset_evt,
                 Some(vcpus_kill_signalled),
+                rtc_config.base.offset_ns(),
+                rtc_config.clock == crate::config::RtcClock::Host,
+                state_from_id(self.snapshot.as_ref(), id.as_str())
+                    .map_err(DeviceManagerError::RestoreGetState)?,
             )));
 
             self.bus_devices
@@ -1721,9 +2001,14 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(cmos, 0x70, 0x2)
+                .insert(cmos.clone(), 0x70, 0x2)
                 .map_err(DeviceManagerError::BusError)?;
 
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, cmos));
+
             let fwdebug = Arc::new(Mutex::new(devices::legacy::FwDebugDevice::new()));
 
             self.bus_devices
@@ -1735,6 +2020,39 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::BusError)?;
         }
 
+        let fw_cfg_configs = self.config.lock().unwrap().fw_cfg.clone();
+        if let Some(fw_cfg_configs) = fw_cfg_configs {
+            let mut files = Vec::new();
+            for fw_cfg_config in fw_cfg_configs {
+                let data = match (fw_cfg_config.file, fw_cfg_config.string) {
+                    (Some(file), _) => std::fs::read(&file)
+                        .map_err(|e| DeviceManagerError::ReadFwCfgFile(file, e))?,
+                    (None, string) => string.unwrap_or_default().into_bytes(),
+                };
+                files.push(devices::legacy::FwCfgFile {
+                    name: fw_cfg_config.name,
+                    data,
+                });
+            }
+
+            let fw_cfg = Arc::new(Mutex::new(devices::legacy::FwCfg::new(
+                files,
+                self.memory_manager.lock().unwrap().guest_memory(),
+            )));
+
+            self.bus_devices
+                .push(Arc::clone(&fw_cfg) as Arc<dyn BusDeviceSync>);
+
+            self.address_manager
+                .io_bus
+                .insert(
+                    fw_cfg,
+                    devices::legacy::FW_CFG_PORT_BASE,
+                    devices::legacy::FW_CFG_PORT_SIZE,
+                )
+                .map_err(DeviceManagerError::BusError)?;
+        }
+
         // 0x80 debug port
         let debug_port = Arc::new(Mutex::new(devices::legacy::DebugPort::new(self.timestamp)));
         self.bus_devices
@@ -1767,7 +2085,18 @@ impl DeviceManager {
             })
             .map_err(DeviceManagerError::CreateInterruptGroup)?;
 
-        let rtc_device = Arc::new(Mutex::new(devices::legacy::Rtc::new(interrupt_group)));
+        let rtc_offset_ns = self
+            .config
+            .lock()
+            .unwrap()
+            .rtc
+            .as_ref()
+            .map(|rtc| rtc.base.offset_ns())
+            .unwrap_or_default();
+        let rtc_device = Arc::new(Mutex::new(devices::legacy::Rtc::new(
+            interrupt_group,
+            rtc_offset_ns,
+        )));
 
         self.bus_devices
             .push(Arc::clone(&rtc_device) as Arc<dyn BusDeviceSync>);
@@ -2084,7 +2413,9 @@ impl DeviceManager {
             iommu: console_config.iommu,
             id: id.clone(),
             pci_segment: 0,
+            pci_bdf: None,
             dma_handler: None,
+            transitional: false,
         });
 
         // Fill the device tree with a new node. In case of restore, we
@@ -2140,7 +2471,13 @@ impl DeviceManager {
             | ConsoleOutputMode::Socket => None,
         };
         if serial_config.mode != ConsoleOutputMode::Off {
-            let serial = self.add_serial_device(interrupt_manager, serial_writer)?;
+            let copies = SerialOutputCopies::new(
+                serial_config.log_file.as_deref(),
+                serial_config.capture_size,
+            )
+            .map_err(DeviceManagerError::CreateSerialManager)?;
+            self.serial_capture = copies.capture();
+            let serial = self.add_serial_device(interrupt_manager, copies.wrap(serial_writer))?;
             self.serial_manager = match serial_config.mode {
                 ConsoleOutputMode::Pty | ConsoleOutputMode::Tty | ConsoleOutputMode::Socket => {
                     let serial_manager = SerialManager::new(
@@ -2148,6 +2485,7 @@ impl DeviceManager {
                         console_info.serial_main_fd,
                         serial_config.mode,
                         serial_config.socket,
+                        copies,
                     )
                     .map_err(DeviceManagerError::CreateSerialManager)?;
                     if let Some(mut serial_manager) = serial_manager {
@@ -2233,9 +2571,18 @@ impl DeviceManager {
         devices.append(&mut self.make_virtio_net_devices()?);
         devices.append(&mut self.make_virtio_rng_devices()?);
 
+        // Add virtio-gpu and virtio-input if VNC is enabled
+        devices.append(&mut self.make_virtio_vnc_devices()?);
+
         // Add virtio-fs if required
         devices.append(&mut self.make_virtio_fs_devices()?);
 
+        // Add virtio-9p if required
+        devices.append(&mut self.make_virtio_fs9p_devices()?);
+
+        // Add virtio-i2c if required
+        devices.append(&mut self.make_virtio_i2c_devices()?);
+
         // Add virtio-pmem if required
         devices.append(&mut self.make_virtio_pmem_devices()?);
 
@@ -2278,50 +2625,52 @@ impl DeviceManager {
         supported
     }
 
-    fn make_virtio_block_device(
+    fn io_affinity_queues(
         &mut self,
-        disk_cfg: &mut DiskConfig,
-    ) -> DeviceManagerResult<MetaVirtioDevice> {
-        let id = if let Some(id) = &disk_cfg.id {
-            id.clone()
-        } else {
-            let id = self.next_device_name(DISK_DEVICE_NAME_PREFIX)?;
-            disk_cfg.id = Some(id.clone());
-            id
-        };
-
-        info!("Creating virtio-block device: {:?}", disk_cfg);
+        io_affinity: Option<&str>,
+        num_queues: usize,
+    ) -> BTreeMap<u16, Vec<usize>> {
+        io_affinity
+            .and_then(|id| self.io_affinities.get_mut(id))
+            .map(|io_affinity| io_affinity.queue_affinity(num_queues))
+            .unwrap_or_default()
+    }
 
-        let (virtio_device, migratable_device) = if disk_cfg.vhost_user {
-            let socket = disk_cfg.vhost_socket.as_ref().unwrap().clone();
-            let vu_cfg = VhostUserConfig {
-                socket,
-                num_queues: disk_cfg.num_queues,
-                queue_size: disk_cfg.queue_size,
-            };
-            let vhost_user_block = Arc::new(Mutex::new(
-                match virtio_devices::vhost_user::Blk::new(
-                    id.clone(),
-                    vu_cfg,
-                    self.seccomp_action.clone(),
-                    self.exit_evt
-                        .try_clone()
-                        .map_err(DeviceManagerError::EventFd)?,
-                    self.force_iommu,
-                    state_from_id(self.snapshot.as_ref(), id.as_str())
-                        .map_err(DeviceManagerError::RestoreGetState)?,
-                ) {
-                    Ok(vub_device) => vub_device,
-                    Err(e) => {
-                        return Err(DeviceManagerError::CreateVhostUserBlk(e));
-                    }
-                },
-            ));
+    // Open the image of a disk, picking the backend matching its format.
+    fn open_disk_image(&mut self, disk_cfg: &DiskConfig) -> DeviceManagerResult<Box<dyn DiskFile>> {
+        let image = if disk_cfg.overlay {
+            // The image is only read, as the base of a qcow2 overlay
+            // living as long as the VM, so that it can be shared by many
+            // VMs.
+            let path = disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?
+                .canonicalize()
+                .map_err(DeviceManagerError::Disk)?;
+            let path = path.to_str().ok_or_else(|| {
+                DeviceManagerError::CreateDiskOverlay(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Disk path is not valid UTF-8",
+                ))
+            })?;
 
-            (
-                Arc::clone(&vhost_user_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
-                vhost_user_block as Arc<Mutex<dyn Migratable>>,
-            )
+            let mut options = OpenOptions::new();
+            options.read(true).write(true);
+            let mut flags = libc::O_TMPFILE;
+            if disk_cfg.direct {
+                flags |= libc::O_DIRECT;
+            }
+            options.custom_flags(flags);
+            let overlay = options
+                .open(std::env::temp_dir())
+                .map_err(DeviceManagerError::CreateDiskOverlay)?;
+
+            info!("Using synchronous QCOW overlay on top of {}", path);
+            Box::new(
+                QcowDiskSync::new_overlay(overlay, disk_cfg.direct, path)
+                    .map_err(DeviceManagerError::CreateQcowDiskSync)?,
+            ) as Box<dyn DiskFile>
         } else {
             let mut options = OpenOptions::new();
             options.read(true);
@@ -2342,7 +2691,16 @@ impl DeviceManager {
             let image_type =
                 detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
 
-            let image = match image_type {
+            let key = if let Some(key_file) = &disk_cfg.key_file {
+                Some(std::fs::read(key_file).map_err(DeviceManagerError::ReadLuksKeyFile)?)
+            } else {
+                disk_cfg.key.as_ref().map(|key| key.as_bytes().to_vec())
+            };
+            if key.is_some() && !matches!(image_type, ImageType::Luks) {
+                return Err(DeviceManagerError::UnexpectedLuksKey);
+            }
+
+            match image_type {
                 ImageType::FixedVhd => {
                     // Use asynchronous backend relying on io_uring if the
                     // syscalls are supported.
@@ -2370,9 +2728,22 @@ impl DeviceManager {
                     }
                 }
                 ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
+                    // Use the thread pool backend when explicitly requested,
+                    // otherwise the asynchronous backend relying on io_uring
+                    // if the syscalls are supported.
+                    if let Some(pool_size) = disk_cfg.thread_pool_size {
+                        info!("Using asynchronous RAW disk file (thread pool)");
+                        Box::new(
+                            RawFileDiskThreadPool::new(
+                                file,
+                                pool_size,
+                                disk_cfg
+                                    .thread_pool_queue_depth
+                                    .unwrap_or(DEFAULT_THREAD_POOL_QUEUE_DEPTH),
+                            )
+                            .map_err(DeviceManagerError::CreateRawFileDiskThreadPool)?,
+                        ) as Box<dyn DiskFile>
+                    } else if cfg!(feature = "io_uring")
                         && !disk_cfg.disable_io_uring
                         && self.io_uring_is_supported()
                     {
@@ -2406,7 +2777,79 @@ impl DeviceManager {
                             .map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
                     ) as Box<dyn DiskFile>
                 }
+                ImageType::Luks => {
+                    let key = key.ok_or(DeviceManagerError::MissingLuksKey)?;
+                    info!("Using synchronous LUKS disk file");
+                    Box::new(
+                        LuksDiskSync::new(file, disk_cfg.direct, &key)
+                            .map_err(DeviceManagerError::CreateLuksDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            }
+        };
+
+        Ok(image)
+    }
+
+    fn make_virtio_block_device(
+        &mut self,
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &disk_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(DISK_DEVICE_NAME_PREFIX)?;
+            disk_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating virtio-block device: {:?}", disk_cfg);
+
+        let (virtio_device, migratable_device) = if disk_cfg.vhost_user || disk_cfg.out_of_process {
+            let socket = if disk_cfg.out_of_process {
+                self.spawn_device_backend(
+                    &id,
+                    DeviceBackend::spawn_block(&id, disk_cfg, self.backend_label.clone()),
+                )?
+            } else if let Some(exec) = &disk_cfg.exec {
+                let socket = disk_cfg.vhost_socket.as_ref().unwrap().clone();
+                self.spawn_device_backend(
+                    &id,
+                    DeviceBackend::spawn_exec(&id, exec, socket, self.backend_label.clone()),
+                )?
+            } else {
+                disk_cfg.vhost_socket.as_ref().unwrap().clone()
+            };
+            let vu_cfg = VhostUserConfig {
+                socket,
+                num_queues: disk_cfg.num_queues,
+                queue_size: disk_cfg.queue_size,
             };
+            let vhost_user_block = Arc::new(Mutex::new(
+                match virtio_devices::vhost_user::Blk::new(
+                    id.clone(),
+                    vu_cfg,
+                    self.seccomp_action.clone(),
+                    self.exit_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
+                    self.force_iommu,
+                    state_from_id(self.snapshot.as_ref(), id.as_str())
+                        .map_err(DeviceManagerError::RestoreGetState)?,
+                ) {
+                    Ok(vub_device) => vub_device,
+                    Err(e) => {
+                        return Err(DeviceManagerError::CreateVhostUserBlk(e));
+                    }
+                },
+            ));
+
+            (
+                Arc::clone(&vhost_user_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+                vhost_user_block as Arc<Mutex<dyn Migratable>>,
+            )
+        } else {
+            let image = self.open_disk_image(disk_cfg)?;
 
             let rate_limit_group =
                 if let Some(rate_limiter_cfg) = disk_cfg.rate_limiter_config.as_ref() {
@@ -2440,13 +2883,27 @@ impl DeviceManager {
                     None
                 };
 
+            let numa_placement_cpus = self
+                .config
+                .lock()
+                .unwrap()
+                .numa_placed
+                .as_ref()
+                .and_then(|placement| placement.pci_segment_cpus(disk_cfg.pci_segment))
+                .cloned();
             let queue_affinity = if let Some(queue_affinity) = disk_cfg.queue_affinity.as_ref() {
                 queue_affinity
                     .iter()
                     .map(|a| (a.queue_index, a.host_cpus.clone()))
                     .collect()
+            } else if let (None, Some(host_cpus)) =
+                (disk_cfg.io_affinity.as_ref(), numa_placement_cpus)
+            {
+                (0..disk_cfg.num_queues as u16)
+                    .map(|queue_index| (queue_index, host_cpus.clone()))
+                    .collect()
             } else {
-                BTreeMap::new()
+                self.io_affinity_queues(disk_cfg.io_affinity.as_deref(), disk_cfg.num_queues)
             };
 
             let virtio_block = Arc::new(Mutex::new(
@@ -2471,6 +2928,10 @@ impl DeviceManager {
                     state_from_id(self.snapshot.as_ref(), id.as_str())
                         .map_err(DeviceManagerError::RestoreGetState)?,
                     queue_affinity,
+                    disk_cfg.busy_poll_us.map(Duration::from_micros),
+                    disk_cfg.coalesce_us.map(Duration::from_micros),
+                    disk_cfg.completion_poll_us.map(Duration::from_micros),
+                    disk_cfg.io_uring_event_loop,
                 )
                 .map_err(DeviceManagerError::CreateVirtioBlock)?,
             ));
@@ -2494,16 +2955,31 @@ impl DeviceManager {
             iommu: disk_cfg.iommu,
             id,
             pci_segment: disk_cfg.pci_segment,
+            pci_bdf: disk_cfg.pci_bdf,
             dma_handler: None,
+            transitional: disk_cfg.transitional,
         })
     }
 
+    fn spawn_device_backend(
+        &mut self,
+        id: &str,
+        backend: Result<DeviceBackend, DeviceBackendError>,
+    ) -> DeviceManagerResult<String> {
+        let backend = backend.map_err(DeviceManagerError::SpawnDeviceBackend)?;
+        let socket = backend.socket().to_owned();
+        self.device_backends.insert(id.to_owned(), backend);
+
+        Ok(socket)
+    }
+
     fn make_virtio_block_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         let mut block_devices = self.config.lock().unwrap().disks.clone();
         if let Some(disk_list_cfg) = &mut block_devices {
-            for disk_cfg in disk_list_cfg.iter_mut() {
+            // NVMe namespaces are created along with their controller.
+            for disk_cfg in disk_list_cfg.iter_mut().filter(|d| d.nvme.is_none()) {
                 devices.push(self.make_virtio_block_device(disk_cfg)?);
             }
         }
@@ -2525,17 +3001,37 @@ impl DeviceManager {
         };
         info!("Creating virtio-net device: {:?}", net_cfg);
 
-        let (virtio_device, migratable_device) = if net_cfg.vhost_user {
-            let socket = net_cfg.vhost_socket.as_ref().unwrap().clone();
+        // The passt backend is served over vhost-user as well.
+        let use_vhost_user = net_cfg.vhost_user || net_cfg.out_of_process || net_cfg.passt;
+        let (virtio_device, migratable_device) = if use_vhost_user {
+            let socket = if net_cfg.out_of_process {
+                self.spawn_device_backend(
+                    &id,
+                    DeviceBackend::spawn_net(&id, net_cfg, self.backend_label.clone()),
+                )?
+            } else if net_cfg.passt {
+                self.spawn_device_backend(
+                    &id,
+                    DeviceBackend::spawn_passt(&id, net_cfg, self.backend_label.clone()),
+                )?
+            } else if let Some(exec) = &net_cfg.exec {
+                let socket = net_cfg.vhost_socket.as_ref().unwrap().clone();
+                self.spawn_device_backend(
+                    &id,
+                    DeviceBackend::spawn_exec(&id, exec, socket, self.backend_label.clone()),
+                )?
+            } else {
+                net_cfg.vhost_socket.as_ref().unwrap().clone()
+            };
             let vu_cfg = VhostUserConfig {
                 socket,
                 num_queues: net_cfg.num_queues,
                 queue_size: net_cfg.queue_size,
             };
-            let server = match net_cfg.vhost_mode {
-                VhostMode::Client => false,
-                VhostMode::Server => true,
-            };
+            // A spawned backend always listens on the socket.
+            let server = !net_cfg.out_of_process
+                && !net_cfg.passt
+                && matches!(net_cfg.vhost_mode, VhostMode::Server);
             let vhost_user_net = Arc::new(Mutex::new(
                 match virtio_devices::vhost_user::Net::new(
                     id.clone(),
@@ -2568,6 +3064,22 @@ impl DeviceManager {
         } else {
             let state = state_from_id(self.snapshot.as_ref(), id.as_str())
                 .map_err(DeviceManagerError::RestoreGetState)?;
+            // Batch the TAP I/O through io_uring if the host supports it.
+            let io_uring = if !cfg!(feature = "io_uring")
+                || net_cfg.disable_io_uring
+                || !self.io_uring_is_supported()
+            {
+                virtio_devices::NetIoUring::Disabled
+            } else if net_cfg.io_uring_registered_buffers {
+                info!("Using io_uring with registered buffers for TAP I/O");
+                virtio_devices::NetIoUring::RegisteredBuffers
+            } else {
+                info!("Using io_uring for TAP I/O");
+                virtio_devices::NetIoUring::Enabled
+            };
+            // Each queue pair is processed by its own thread.
+            let queue_affinity =
+                self.io_affinity_queues(net_cfg.io_affinity.as_deref(), net_cfg.num_queues / 2);
             let virtio_net = if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
@@ -2590,26 +3102,99 @@ impl DeviceManager {
                         net_cfg.offload_tso,
                         net_cfg.offload_ufo,
                         net_cfg.offload_csum,
+                        io_uring,
+                        queue_affinity,
+                        net_cfg.busy_poll_us.map(Duration::from_micros),
+                        net_cfg.coalesce_us.map(Duration::from_micros),
+                        net_cfg.io_uring_event_loop,
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
-            } else if let Some(fds) = &net_cfg.fds {
-                let net = virtio_devices::Net::from_tap_fds(
-                    id.clone(),
-                    fds,
-                    Some(net_cfg.mac),
-                    net_cfg.mtu,
-                    self.force_iommu | net_cfg.iommu,
-                    net_cfg.queue_size,
-                    self.seccomp_action.clone(),
-                    net_cfg.rate_limiter_config,
-                    self.exit_evt
-                        .try_clone()
-                        .map_err(DeviceManagerError::EventFd)?,
-                    state,
-                    net_cfg.offload_tso,
-                    net_cfg.offload_ufo,
-                    net_cfg.offload_csum,
+            } else if let Some(ref xdp_if_name) = net_cfg.xdp {
+                info!(
+                    "Using AF_XDP on {xdp_if_name} from queue {}",
+                    net_cfg.xdp_queue
+                );
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::from_xdp(
+                        id.clone(),
+                        xdp_if_name,
+                        net_cfg.xdp_queue,
+                        Some(net_cfg.mac),
+                        self.force_iommu | net_cfg.iommu,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                        net_cfg.rate_limiter_config,
+                        self.exit_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                        state,
+                        queue_affinity,
+                        net_cfg.busy_poll_us.map(Duration::from_micros),
+                        net_cfg.coalesce_us.map(Duration::from_micros),
+                        net_cfg.io_uring_event_loop,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(macvtap) = net_cfg.macvtap.clone() {
+                let (files, mac) = net_util::open_macvtap(&macvtap, net_cfg.num_queues / 2)
+                    .map_err(DeviceManagerError::OpenMacvtap)?;
+                // The macvtap interface only delivers frames addressed to
+                // its own MAC address.
+                if mac != net_cfg.mac {
+                    info!("Using MAC address {mac} of macvtap interface {macvtap}");
+                    net_cfg.mac = mac;
+                }
+                let fds: Vec<RawFd> = files.iter().map(|f| f.as_raw_fd()).collect();
+
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::from_tap_fds(
+                        id.clone(),
+                        &fds,
+                        Some(net_cfg.mac),
+                        net_cfg.mtu,
+                        self.force_iommu | net_cfg.iommu,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                        net_cfg.rate_limiter_config,
+                        self.exit_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                        state,
+                        net_cfg.offload_tso,
+                        net_cfg.offload_ufo,
+                        net_cfg.offload_csum,
+                        io_uring,
+                        queue_affinity,
+                        net_cfg.busy_poll_us.map(Duration::from_micros),
+                        net_cfg.coalesce_us.map(Duration::from_micros),
+                        net_cfg.io_uring_event_loop,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(fds) = &net_cfg.fds {
+                let net = virtio_devices::Net::from_tap_fds(
+                    id.clone(),
+                    fds,
+                    Some(net_cfg.mac),
+                    net_cfg.mtu,
+                    self.force_iommu | net_cfg.iommu,
+                    net_cfg.queue_size,
+                    self.seccomp_action.clone(),
+                    net_cfg.rate_limiter_config,
+                    self.exit_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
+                    state,
+                    net_cfg.offload_tso,
+                    net_cfg.offload_ufo,
+                    net_cfg.offload_csum,
+                    io_uring,
+                    queue_affinity,
+                    net_cfg.busy_poll_us.map(Duration::from_micros),
+                    net_cfg.coalesce_us.map(Duration::from_micros),
+                    net_cfg.io_uring_event_loop,
                 )
                 .map_err(DeviceManagerError::CreateVirtioNet)?;
 
@@ -2641,11 +3226,25 @@ impl DeviceManager {
                         net_cfg.offload_tso,
                         net_cfg.offload_ufo,
                         net_cfg.offload_csum,
+                        io_uring,
+                        queue_affinity,
+                        net_cfg.busy_poll_us.map(Duration::from_micros),
+                        net_cfg.coalesce_us.map(Duration::from_micros),
+                        net_cfg.io_uring_event_loop,
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
             };
 
+            if net_cfg.vhost_net {
+                info!("Using vhost-net for {id}");
+                virtio_net
+                    .lock()
+                    .unwrap()
+                    .enable_vhost_net()
+                    .map_err(DeviceManagerError::CreateVirtioNet)?;
+            }
+
             (
                 Arc::clone(&virtio_net) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
                 virtio_net as Arc<Mutex<dyn Migratable>>,
@@ -2665,7 +3264,9 @@ impl DeviceManager {
             iommu: net_cfg.iommu,
             id,
             pci_segment: net_cfg.pci_segment,
+            pci_bdf: net_cfg.pci_bdf,
             dma_handler: None,
+            transitional: net_cfg.transitional,
         })
     }
 
@@ -2712,7 +3313,9 @@ impl DeviceManager {
                 iommu: rng_config.iommu,
                 id: id.clone(),
                 pci_segment: 0,
+                pci_bdf: None,
                 dma_handler: None,
+                transitional: false,
             });
 
             // Fill the device tree with a new node. In case of restore, we
@@ -2727,6 +3330,102 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    fn make_virtio_input_device(
+        &mut self,
+        id: &str,
+        kind: InputKind,
+    ) -> DeviceManagerResult<(MetaVirtioDevice, InputEventSender)> {
+        let id = String::from(id);
+        let virtio_input_device = Arc::new(Mutex::new(
+            virtio_devices::Input::new(
+                id.clone(),
+                kind,
+                self.force_iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                state_from_id(self.snapshot.as_ref(), id.as_str())
+                    .map_err(DeviceManagerError::RestoreGetState)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioInput)?,
+        ));
+        let sender = virtio_input_device.lock().unwrap().event_sender();
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, virtio_input_device));
+
+        Ok((
+            MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_input_device)
+                    as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+                iommu: false,
+                id,
+                pci_segment: 0,
+                pci_bdf: None,
+                dma_handler: None,
+                transitional: false,
+            },
+            sender,
+        ))
+    }
+
+    fn make_virtio_vnc_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let Some(vnc_config) = self.config.lock().unwrap().vnc.clone() else {
+            return Ok(devices);
+        };
+        info!(
+            "Creating virtio-gpu and virtio-input devices: {:?}",
+            vnc_config
+        );
+
+        let id = String::from(GPU_DEVICE_NAME);
+        let display = GpuDisplay::new(vnc_config.width, vnc_config.height);
+        let virtio_gpu_device = Arc::new(Mutex::new(
+            virtio_devices::Gpu::new(
+                id.clone(),
+                display.clone(),
+                self.force_iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                state_from_id(self.snapshot.as_ref(), id.as_str())
+                    .map_err(DeviceManagerError::RestoreGetState)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioGpu)?,
+        ));
+        devices.push(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_gpu_device)
+                as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: false,
+            id: id.clone(),
+            pci_segment: 0,
+            pci_bdf: None,
+            dma_handler: None,
+            transitional: false,
+        });
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, virtio_gpu_device));
+
+        let (keyboard_device, keyboard) =
+            self.make_virtio_input_device(KEYBOARD_DEVICE_NAME, InputKind::Keyboard)?;
+        devices.push(keyboard_device);
+        let (tablet_device, tablet) =
+            self.make_virtio_input_device(TABLET_DEVICE_NAME, InputKind::Tablet)?;
+        devices.push(tablet_device);
+
+        self.vnc_devices = Some((display, keyboard, tablet));
+
+        Ok(devices)
+    }
+
     fn make_virtio_fs_device(
         &mut self,
         fs_cfg: &mut FsConfig,
@@ -2741,6 +3440,19 @@ impl DeviceManager {
 
         info!("Creating virtio-fs device: {:?}", fs_cfg);
 
+        if fs_cfg.shared_dir.is_some() {
+            self.spawn_device_backend(
+                &id,
+                DeviceBackend::spawn_fs(&id, fs_cfg, self.backend_label.clone()),
+            )?;
+        } else if let Some(exec) = &fs_cfg.exec {
+            let socket = fs_cfg.socket.to_string_lossy().into_owned();
+            self.spawn_device_backend(
+                &id,
+                DeviceBackend::spawn_exec(&id, exec, socket, self.backend_label.clone()),
+            )?;
+        }
+
         let mut node = device_node!(id);
 
         if let Some(fs_socket) = fs_cfg.socket.to_str() {
@@ -2773,7 +3485,9 @@ impl DeviceManager {
                 iommu: false,
                 id,
                 pci_segment: fs_cfg.pci_segment,
+                pci_bdf: fs_cfg.pci_bdf,
                 dma_handler: None,
+                transitional: false,
             })
         } else {
             Err(DeviceManagerError::NoVirtioFsSock)
@@ -2794,26 +3508,137 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_pmem_device(
+    fn make_virtio_fs9p_device(
         &mut self,
-        pmem_cfg: &mut PmemConfig,
+        fs9p_cfg: &mut Fs9pConfig,
     ) -> DeviceManagerResult<MetaVirtioDevice> {
-        let id = if let Some(id) = &pmem_cfg.id {
+        let id = if let Some(id) = &fs9p_cfg.id {
             id.clone()
         } else {
-            let id = self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?;
-            pmem_cfg.id = Some(id.clone());
+            let id = self.next_device_name(FS9P_DEVICE_NAME_PREFIX)?;
+            fs9p_cfg.id = Some(id.clone());
             id
         };
 
-        info!("Creating virtio-pmem device: {:?}", pmem_cfg);
+        info!("Creating virtio-9p device: {:?}", fs9p_cfg);
+
+        let mut node = device_node!(id);
+
+        let virtio_fs9p_device = Arc::new(Mutex::new(
+            virtio_devices::Fs9p::new(
+                id.clone(),
+                &fs9p_cfg.tag,
+                &fs9p_cfg.path,
+                fs9p_cfg.msize,
+                self.force_iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioFs9p)?,
+        ));
+
+        // Update the device tree with the migratable device.
+        node.migratable = Some(Arc::clone(&virtio_fs9p_device) as Arc<Mutex<dyn Migratable>>);
+        self.device_tree.lock().unwrap().insert(id.clone(), node);
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_fs9p_device)
+                as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: false,
+            id,
+            pci_segment: fs9p_cfg.pci_segment,
+            pci_bdf: fs9p_cfg.pci_bdf,
+            dma_handler: None,
+            transitional: false,
+        })
+    }
+
+    fn make_virtio_fs9p_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let mut fs9p_devices = self.config.lock().unwrap().fs9p.clone();
+        if let Some(fs9p_list_cfg) = &mut fs9p_devices {
+            for fs9p_cfg in fs9p_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_fs9p_device(fs9p_cfg)?);
+            }
+        }
+        self.config.lock().unwrap().fs9p = fs9p_devices;
+
+        Ok(devices)
+    }
+
+    fn make_virtio_i2c_device(
+        &mut self,
+        i2c_cfg: &mut I2cConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &i2c_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(I2C_DEVICE_NAME_PREFIX)?;
+            i2c_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating virtio-i2c device: {:?}", i2c_cfg);
 
         let mut node = device_node!(id);
 
+        let virtio_i2c_device = Arc::new(Mutex::new(
+            virtio_devices::I2c::new(
+                id.clone(),
+                &i2c_cfg.path,
+                i2c_cfg.addresses.clone(),
+                self.force_iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioI2c)?,
+        ));
+
+        // Update the device tree with the migratable device.
+        node.migratable = Some(Arc::clone(&virtio_i2c_device) as Arc<Mutex<dyn Migratable>>);
+        self.device_tree.lock().unwrap().insert(id.clone(), node);
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_i2c_device)
+                as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: false,
+            id,
+            pci_segment: i2c_cfg.pci_segment,
+            pci_bdf: i2c_cfg.pci_bdf,
+            dma_handler: None,
+            transitional: false,
+        })
+    }
+
+    fn make_virtio_i2c_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let mut i2c_devices = self.config.lock().unwrap().i2c.clone();
+        if let Some(i2c_list_cfg) = &mut i2c_devices {
+            for i2c_cfg in i2c_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_i2c_device(i2c_cfg)?);
+            }
+        }
+        self.config.lock().unwrap().i2c = i2c_devices;
+
+        Ok(devices)
+    }
+
+    // Map the persistent memory backing file into the guest address space.
+    fn map_pmem_file(
+        &mut self,
+        id: &str,
+        pmem_cfg: &PmemConfig,
+    ) -> DeviceManagerResult<PmemMapping> {
         // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
-        let region_range = if let Some(node) = self.device_tree.lock().unwrap().get(&id) {
-            info!("Restoring virtio-pmem {} resources", id);
+        let region_range = if let Some(node) = self.device_tree.lock().unwrap().get(id) {
+            info!("Restoring pmem {} resources", id);
 
             let mut region_range: Option<(u64, u64)> = None;
             for resource in node.resources.iter() {
@@ -2867,6 +3692,13 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::PmemFileSetLen)?
         };
 
+        // The label storage area is kept at the end of the file, out of the
+        // guest mapping.
+        let size = size
+            .checked_sub(pmem_cfg.label_size)
+            .filter(|size| *size > 0)
+            .ok_or(DeviceManagerError::PmemLabelAreaTooLarge)?;
+
         if size % 0x20_0000 != 0 {
             return Err(DeviceManagerError::PmemSizeNotAligned);
         }
@@ -2929,6 +3761,37 @@ impl DeviceManager {
             mergeable: false,
         };
 
+        Ok(PmemMapping {
+            file,
+            mapping,
+            mmap_region,
+        })
+    }
+
+    fn make_virtio_pmem_device(
+        &mut self,
+        pmem_cfg: &mut PmemConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &pmem_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?;
+            pmem_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating virtio-pmem device: {:?}", pmem_cfg);
+
+        let mut node = device_node!(id);
+
+        let PmemMapping {
+            file,
+            mapping,
+            mmap_region,
+        } = self.map_pmem_file(&id, pmem_cfg)?;
+        let region_base = mapping.addr.raw_value();
+        let region_size = mapping.len;
+
         let virtio_pmem_device = Arc::new(Mutex::new(
             virtio_devices::Pmem::new(
                 id.clone(),
@@ -2962,7 +3825,9 @@ impl DeviceManager {
             iommu: pmem_cfg.iommu,
             id,
             pci_segment: pmem_cfg.pci_segment,
+            pci_bdf: pmem_cfg.pci_bdf,
             dma_handler: None,
+            transitional: false,
         })
     }
 
@@ -2971,7 +3836,7 @@ impl DeviceManager {
         // Add virtio-pmem if required
         let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
         if let Some(pmem_list_cfg) = &mut pmem_devices {
-            for pmem_cfg in pmem_list_cfg.iter_mut() {
+            for pmem_cfg in pmem_list_cfg.iter_mut().filter(|cfg| !cfg.nvdimm) {
                 devices.push(self.make_virtio_pmem_device(pmem_cfg)?);
             }
         }
@@ -2980,6 +3845,115 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    fn make_nvdimm(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<devices::Nvdimm> {
+        let id = if let Some(id) = &pmem_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?;
+            pmem_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating NVDIMM: {:?}", pmem_cfg);
+
+        let mut node = device_node!(id);
+
+        let PmemMapping {
+            file,
+            mapping,
+            mmap_region,
+        } = self.map_pmem_file(&id, pmem_cfg)?;
+
+        let label_area = (pmem_cfg.label_size != 0).then_some(devices::NvdimmLabelArea {
+            file,
+            offset: mapping.len,
+            size: pmem_cfg.label_size,
+        });
+
+        node.resources.push(Resource::MmioAddressRange {
+            base: mapping.addr.raw_value(),
+            size: mapping.len,
+        });
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(devices::Nvdimm {
+            base: mapping.addr,
+            size: mapping.len,
+            label_area,
+            mmap_region,
+        })
+    }
+
+    fn add_nvdimm_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut nvdimms = Vec::new();
+        let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
+        if let Some(pmem_list_cfg) = &mut pmem_devices {
+            for pmem_cfg in pmem_list_cfg.iter_mut().filter(|cfg| cfg.nvdimm) {
+                nvdimms.push(self.make_nvdimm(pmem_cfg)?);
+            }
+        }
+        self.config.lock().unwrap().pmem = pmem_devices;
+
+        if nvdimms.is_empty() {
+            return Ok(());
+        }
+
+        let address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_platform_mmio_addresses(None, devices::nvdimm::NVDIMM_DEVICE_MMIO_SIZE, None)
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+        let nvdimm_device = Arc::new(Mutex::new(devices::NvdimmDevice::new(address, nvdimms)));
+
+        self.address_manager
+            .mmio_bus
+            .insert(
+                nvdimm_device.clone(),
+                address.0,
+                devices::nvdimm::NVDIMM_DEVICE_MMIO_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&nvdimm_device) as Arc<dyn BusDeviceSync>);
+        self.nvdimm_device = Some(nvdimm_device);
+
+        Ok(())
+    }
+
+    fn add_vmclock_device(&mut self) -> DeviceManagerResult<devices::VmclockDevice> {
+        let address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_platform_mmio_addresses(
+                None,
+                devices::vmclock::VMCLOCK_SIZE,
+                Some(devices::vmclock::VMCLOCK_SIZE),
+            )
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+        let vmclock_device =
+            devices::VmclockDevice::new(address).map_err(DeviceManagerError::VmclockCreate)?;
+
+        // The guest only reads the page, which is never migrated.
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .create_userspace_mapping(
+                address.0,
+                devices::vmclock::VMCLOCK_SIZE,
+                vmclock_device.host_addr(),
+                false,
+                true,
+                false,
+            )
+            .map_err(DeviceManagerError::MemoryManager)?;
+
+        Ok(vmclock_device)
+    }
+
     fn make_virtio_vsock_device(
         &mut self,
         vsock_cfg: &mut VsockConfig,
@@ -3033,7 +4007,9 @@ impl DeviceManager {
             iommu: vsock_cfg.iommu,
             id,
             pci_segment: vsock_cfg.pci_segment,
+            pci_bdf: vsock_cfg.pci_bdf,
             dma_handler: None,
+            transitional: false,
         })
     }
 
@@ -3092,7 +4068,9 @@ impl DeviceManager {
                     iommu: false,
                     id: memory_zone_id.clone(),
                     pci_segment: 0,
+                    pci_bdf: None,
                     dma_handler: None,
+                    transitional: false,
                 });
 
                 // Fill the device tree with a new node. In case of restore, we
@@ -3119,7 +4097,7 @@ impl DeviceManager {
         let pci_segment_id = 0x0_u16;
 
         let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&id, pci_segment_id)?;
+            self.pci_resources(&id, pci_segment_id, None, false)?;
 
         info!("Creating pvmemcontrol device: id = {}", id);
         let (pvmemcontrol_pci_device, pvmemcontrol_bus_device) =
@@ -3163,6 +4141,16 @@ impl DeviceManager {
                     balloon_config.size,
                     balloon_config.deflate_on_oom,
                     balloon_config.free_page_reporting,
+                    balloon_config
+                        .policy
+                        .as_ref()
+                        .map(|policy| virtio_devices::BalloonPolicy {
+                            min_guest_ram: policy.min_guest_ram,
+                            max_guest_ram: policy.max_guest_ram.unwrap_or(u64::MAX),
+                            free_reserve: policy.free_reserve,
+                            hysteresis: policy.hysteresis,
+                            interval: Duration::from_millis(policy.interval_ms),
+                        }),
                     self.seccomp_action.clone(),
                     self.exit_evt
                         .try_clone()
@@ -3181,7 +4169,9 @@ impl DeviceManager {
                 iommu: false,
                 id: id.clone(),
                 pci_segment: 0,
+                pci_bdf: None,
                 dma_handler: None,
+                transitional: false,
             });
 
             self.device_tree
@@ -3222,7 +4212,9 @@ impl DeviceManager {
             iommu: false,
             id: id.clone(),
             pci_segment: 0,
+            pci_bdf: None,
             dma_handler: None,
+            transitional: false,
         });
 
         self.device_tree
@@ -3280,7 +4272,9 @@ impl DeviceManager {
             iommu: vdpa_cfg.iommu,
             id,
             pci_segment: vdpa_cfg.pci_segment,
+            pci_bdf: vdpa_cfg.pci_bdf,
             dma_handler: Some(vdpa_mapping),
+            transitional: false,
         })
     }
 
@@ -3366,8 +4360,12 @@ impl DeviceManager {
             id
         };
 
-        let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&vfio_name, device_cfg.pci_segment)?;
+        let (pci_segment_id, pci_device_bdf, resources) = self.pci_resources(
+            &vfio_name,
+            device_cfg.pci_segment,
+            device_cfg.pci_bdf,
+            device_cfg.iommu,
+        )?;
 
         let mut needs_dma_mapping = false;
 
@@ -3603,8 +4601,12 @@ impl DeviceManager {
             id
         };
 
-        let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&vfio_user_name, device_cfg.pci_segment)?;
+        let (pci_segment_id, pci_device_bdf, resources) = self.pci_resources(
+            &vfio_user_name,
+            device_cfg.pci_segment,
+            device_cfg.pci_bdf,
+            false,
+        )?;
 
         let legacy_interrupt_group =
             if let Some(legacy_interrupt_manager) = &self.legacy_interrupt_manager {
@@ -3715,7 +4717,9 @@ impl DeviceManager {
         iommu_mapping: &Option<Arc<IommuMapping>>,
         virtio_device_id: String,
         pci_segment_id: u16,
+        pci_bdf: Option<PciBdf>,
         dma_handler: Option<Arc<dyn ExternalDmaMapping>>,
+        transitional: bool,
     ) -> DeviceManagerResult<PciBdf> {
         let id = format!("{VIRTIO_PCI_DEVICE_NAME_PREFIX}-{virtio_device_id}");
 
@@ -3724,7 +4728,7 @@ impl DeviceManager {
         node.children = vec![virtio_device_id.clone()];
 
         let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&id, pci_segment_id)?;
+            self.pci_resources(&id, pci_segment_id, pci_bdf, iommu_mapping.is_some())?;
 
         // Update the existing virtio node by setting the parent.
         if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
@@ -3801,6 +4805,12 @@ impl DeviceManager {
         }
 
         let device_type = virtio_device.lock().unwrap().device_type();
+        let device_pausable = self
+            .device_tree
+            .lock()
+            .unwrap()
+            .get(&virtio_device_id)
+            .and_then(|node| node.migratable.clone());
         let virtio_pci_device = Arc::new(Mutex::new(
             VirtioPciDevice::new(
                 id.clone(),
@@ -3818,67 +4828,507 @@ impl DeviceManager {
                 // to firmware without requiring excessive identity mapping.
                 // The exception being if not on the default PCI segment.
                 pci_segment_id > 0 || device_type != VirtioDeviceType::Block as u32,
+                transitional,
                 dma_handler,
                 self.pending_activations.clone(),
+                device_pausable,
                 vm_migration::snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
             )
-            .map_err(DeviceManagerError::VirtioDevice)?,
-        ));
+            .map_err(DeviceManagerError::VirtioDevice)?,
+        ));
+
+        let new_resources = self.add_pci_device(
+            virtio_pci_device.clone(),
+            virtio_pci_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let bar_addr = virtio_pci_device.lock().unwrap().config_bar_addr();
+        for (event, addr) in virtio_pci_device.lock().unwrap().ioeventfds(bar_addr) {
+            let io_addr = IoEventAddress::Mmio(addr);
+            self.address_manager
+                .vm
+                .register_ioevent(event, &io_addr, None)
+                .map_err(|e| DeviceManagerError::RegisterIoevent(e.into()))?;
+        }
+
+        // Update the device tree with correct resource information.
+        node.resources = new_resources;
+        node.migratable = Some(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = Some(PciDeviceHandle::Virtio(virtio_pci_device));
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(pci_device_bdf)
+    }
+
+    fn add_virtio_mmio_device(
+        &mut self,
+        virtio_device: Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+        virtio_device_id: String,
+    ) -> DeviceManagerResult<()> {
+        let id = format!("{VIRTIO_MMIO_DEVICE_NAME_PREFIX}-{virtio_device_id}");
+
+        // Look for the id in the device tree. If it can be found, that means
+        // the device is being restored, otherwise it's created from scratch.
+        let restored_addr = self.device_tree.lock().unwrap().get(&id).and_then(|node| {
+            node.resources.iter().find_map(|resource| match resource {
+                Resource::MmioAddressRange { base, .. } => Some(GuestAddress(*base)),
+                _ => None,
+            })
+        });
+
+        // Add the new virtio-mmio node to the device tree.
+        let mut node = device_node!(id);
+        node.children = vec![virtio_device_id.clone()];
+
+        // Update the existing virtio node by setting the parent.
+        if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
+            node.parent = Some(id.clone());
+        } else {
+            return Err(DeviceManagerError::MissingNode);
+        }
+
+        let addr = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_platform_mmio_addresses(
+                restored_addr,
+                VIRTIO_MMIO_SIZE,
+                Some(VIRTIO_MMIO_SIZE),
+            )
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+
+        let irq = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_irq()
+            .ok_or(DeviceManagerError::AllocateIrq)?;
+
+        let interrupt_group = self
+            .legacy_interrupt_manager
+            .as_ref()
+            .unwrap()
+            .create_group(LegacyIrqGroupConfig {
+                irq: irq as InterruptIndex,
+            })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+        #[cfg(target_arch = "aarch64")]
+        let device_type = virtio_device.lock().unwrap().device_type();
+        let virtio_mmio_device = Arc::new(Mutex::new(
+            VirtioMmioDevice::new(
+                id.clone(),
+                self.memory_manager.lock().unwrap().guest_memory(),
+                virtio_device,
+                interrupt_group,
+                self.activate_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                self.pending_activations.clone(),
+                snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+            )
+            .map_err(DeviceManagerError::VirtioMmioDevice)?,
+        ));
+
+        self.address_manager
+            .mmio_bus
+            .insert(virtio_mmio_device.clone(), addr.0, VIRTIO_MMIO_SIZE)
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&virtio_mmio_device) as Arc<dyn BusDeviceSync>);
+
+        // All the queues are notified through the same register, the queue
+        // index being the value written.
+        for (index, event) in virtio_mmio_device
+            .lock()
+            .unwrap()
+            .queue_evts()
+            .iter()
+            .enumerate()
+        {
+            let io_addr = IoEventAddress::Mmio(addr.0 + VIRTIO_MMIO_QUEUE_NOTIFY);
+            self.address_manager
+                .vm
+                .register_ioevent(event, &io_addr, Some(DataMatch::DataMatch32(index as u32)))
+                .map_err(|e| DeviceManagerError::RegisterIoevent(e.into()))?;
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        self.id_to_dev_info.insert(
+            (DeviceType::Virtio(device_type), id.clone()),
+            MmioDeviceInfo {
+                addr: addr.0,
+                len: VIRTIO_MMIO_SIZE,
+                irq,
+            },
+        );
+        self.virtio_mmio_devices.push((addr, irq));
+
+        // Update the device tree with correct resource information.
+        node.resources = vec![
+            Resource::MmioAddressRange {
+                base: addr.0,
+                size: VIRTIO_MMIO_SIZE,
+            },
+            Resource::LegacyIrq(irq),
+        ];
+        node.migratable = Some(Arc::clone(&virtio_mmio_device) as Arc<Mutex<dyn Migratable>>);
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    fn add_pvpanic_device(
+        &mut self,
+    ) -> DeviceManagerResult<Option<Arc<Mutex<devices::PvPanicDevice>>>> {
+        let id = String::from(PVPANIC_DEVICE_NAME);
+        let pci_segment_id = 0x0_u16;
+
+        info!("Creating pvpanic device {}", id);
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, pci_segment_id, None, false)?;
+
+        let snapshot = snapshot_from_id(self.snapshot.as_ref(), id.as_str());
+
+        let pvpanic_device = devices::PvPanicDevice::new(
+            id.clone(),
+            self.panic_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+            snapshot,
+        )
+        .map_err(DeviceManagerError::PvPanicCreate)?;
+
+        let pvpanic_device = Arc::new(Mutex::new(pvpanic_device));
+
+        let new_resources = self.add_pci_device(
+            pvpanic_device.clone(),
+            pvpanic_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let mut node = device_node!(id, pvpanic_device);
+
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(Some(pvpanic_device))
+    }
+
+    fn add_ivshmem_device(&mut self, ivshmem_cfg: &mut IvshmemConfig) -> DeviceManagerResult<()> {
+        let id = if let Some(id) = &ivshmem_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(IVSHMEM_DEVICE_NAME_PREFIX)?;
+            ivshmem_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating ivshmem device: {:?}", ivshmem_cfg);
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, ivshmem_cfg.pci_segment, ivshmem_cfg.pci_bdf, false)?;
+
+        // The file is created if a size is given, so that peers can be
+        // started in any order.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(ivshmem_cfg.size.is_some())
+            .truncate(false)
+            .open(&ivshmem_cfg.path)
+            .map_err(DeviceManagerError::IvshmemFileOpen)?;
+
+        let file_size = file
+            .seek(SeekFrom::End(0))
+            .map_err(DeviceManagerError::IvshmemFileSetLen)?;
+        let size = match ivshmem_cfg.size {
+            Some(size) if size > file_size => {
+                file.set_len(size)
+                    .map_err(DeviceManagerError::IvshmemFileSetLen)?;
+                size
+            }
+            Some(size) => size,
+            None => file_size,
+        };
+
+        let memory_slot = self.memory_manager.lock().unwrap().allocate_memory_slot();
+        let ivshmem_device = devices::ivshmem::IvshmemDevice::new(
+            id.clone(),
+            &file,
+            size,
+            self.address_manager.vm.clone(),
+            memory_slot,
+            snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+        )
+        .map_err(DeviceManagerError::CreateIvshmem)?;
+
+        let ivshmem_device = Arc::new(Mutex::new(ivshmem_device));
+
+        let new_resources = self.add_pci_device(
+            ivshmem_device.clone(),
+            ivshmem_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        ivshmem_device
+            .lock()
+            .unwrap()
+            .map_shared_memory()
+            .map_err(DeviceManagerError::MapIvshmem)?;
+
+        let mut node = device_node!(id, ivshmem_device);
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    fn add_ivshmem_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut ivshmem_devices = self.config.lock().unwrap().ivshmem.clone();
+        if let Some(ivshmem_list_cfg) = &mut ivshmem_devices {
+            for ivshmem_cfg in ivshmem_list_cfg.iter_mut() {
+                self.add_ivshmem_device(ivshmem_cfg)?;
+            }
+        }
+        self.config.lock().unwrap().ivshmem = ivshmem_devices;
+
+        Ok(())
+    }
+
+    fn add_nvme_controller(
+        &mut self,
+        nvme_cfg: &NvmeConfig,
+        disks: &mut [DiskConfig],
+    ) -> DeviceManagerResult<()> {
+        let id = nvme_cfg.id.clone();
+
+        info!("Creating NVMe controller {}: {:?}", id, nvme_cfg);
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, nvme_cfg.pci_segment, nvme_cfg.pci_bdf, false)?;
+
+        // Namespaces are numbered in the order of the disks.
+        let mut namespaces = Vec::new();
+        for disk_cfg in disks
+            .iter_mut()
+            .filter(|d| d.nvme.as_ref() == Some(&nvme_cfg.id))
+        {
+            let disk_id = if let Some(disk_id) = &disk_cfg.id {
+                disk_id.clone()
+            } else {
+                let disk_id = self.next_device_name(DISK_DEVICE_NAME_PREFIX)?;
+                disk_cfg.id = Some(disk_id.clone());
+                disk_id
+            };
+            info!("Creating NVMe namespace {}: {:?}", disk_id, disk_cfg);
+
+            namespaces.push(devices::nvme::NvmeNamespace {
+                id: disk_id,
+                disk: self.open_disk_image(disk_cfg)?,
+                readonly: disk_cfg.readonly,
+            });
+        }
+        let namespace_ids: Vec<String> = namespaces.iter().map(|n| n.id.clone()).collect();
+
+        let nvme = devices::nvme::NvmeController::new(
+            id.clone(),
+            nvme_cfg.serial.clone().unwrap_or_else(|| id.clone()),
+            nvme_cfg.num_queues,
+            namespaces,
+            self.memory_manager.lock().unwrap().guest_memory(),
+            self.msi_interrupt_manager.as_ref(),
+            pci_device_bdf.into(),
+        )
+        .map_err(DeviceManagerError::CreateNvme)?;
+
+        let nvme = Arc::new(Mutex::new(nvme));
+
+        let new_resources = self.add_pci_device(
+            nvme.clone(),
+            nvme.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let mut node = device_node!(id, nvme);
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+        node.children.clone_from(&namespace_ids);
+
+        // Namespaces hang off the controller in the device tree, which keeps
+        // their identifiers unique among all the other devices.
+        let mut device_tree = self.device_tree.lock().unwrap();
+        device_tree.insert(id.clone(), node);
+        for namespace_id in namespace_ids {
+            let mut node = device_node!(namespace_id);
+            node.parent = Some(id.clone());
+            device_tree.insert(namespace_id, node);
+        }
+
+        Ok(())
+    }
+
+    fn add_nvme_controllers(&mut self) -> DeviceManagerResult<()> {
+        let nvme_controllers = self.config.lock().unwrap().nvme.clone();
+        let mut disks = self.config.lock().unwrap().disks.clone();
+        if let Some(nvme_list_cfg) = &nvme_controllers {
+            for nvme_cfg in nvme_list_cfg.iter() {
+                self.add_nvme_controller(nvme_cfg, disks.as_deref_mut().unwrap_or_default())?;
+            }
+        }
+        self.config.lock().unwrap().disks = disks;
+
+        Ok(())
+    }
+
+    fn add_cxl_device(&mut self, cxl_cfg: &mut CxlConfig) -> DeviceManagerResult<()> {
+        let id = if let Some(id) = &cxl_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(CXL_DEVICE_NAME_PREFIX)?;
+            cxl_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating CXL memory device: {:?}", cxl_cfg);
+
+        let region = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .memory_zones()
+            .get(&cxl_cfg.memory_zone)
+            .and_then(|zone| zone.cxl_region().clone())
+            .ok_or_else(|| DeviceManagerError::MissingCxlMemoryZone(cxl_cfg.memory_zone.clone()))?;
+
+        // The host bridge registers are shared by the guest and the CEDT,
+        // independently from the PCI segment device memory.
+        let rcrb_address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_platform_mmio_addresses(
+                None,
+                devices::cxl::CXL_HOST_BRIDGE_MMIO_SIZE,
+                Some(devices::cxl::CXL_HOST_BRIDGE_MMIO_ALIGNMENT),
+            )
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+        let host_bridge = Arc::new(Mutex::new(devices::cxl::CxlHostBridge::new(rcrb_address)));
+        self.address_manager
+            .mmio_bus
+            .insert(
+                host_bridge.clone(),
+                rcrb_address.0,
+                devices::cxl::CXL_HOST_BRIDGE_MMIO_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&host_bridge) as Arc<dyn BusDeviceSync>);
+        self.pci_segments[cxl_cfg.pci_segment as usize].cxl_rcrb_address = Some(rcrb_address.0);
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, cxl_cfg.pci_segment, cxl_cfg.pci_bdf, false)?;
+
+        let cxl_device = Arc::new(Mutex::new(devices::cxl::CxlType3Device::new(
+            id.clone(),
+            region.start_addr(),
+            region.len(),
+            u32::from(pci_device_bdf) as u64,
+        )));
 
         let new_resources = self.add_pci_device(
-            virtio_pci_device.clone(),
-            virtio_pci_device.clone(),
+            cxl_device.clone(),
+            cxl_device.clone(),
             pci_segment_id,
             pci_device_bdf,
             resources,
         )?;
 
-        let bar_addr = virtio_pci_device.lock().unwrap().config_bar_addr();
-        for (event, addr) in virtio_pci_device.lock().unwrap().ioeventfds(bar_addr) {
-            let io_addr = IoEventAddress::Mmio(addr);
-            self.address_manager
-                .vm
-                .register_ioevent(event, &io_addr, None)
-                .map_err(|e| DeviceManagerError::RegisterIoevent(e.into()))?;
-        }
-
-        // Update the device tree with correct resource information.
+        let mut node = device_node!(id, cxl_device);
         node.resources = new_resources;
-        node.migratable = Some(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
         node.pci_bdf = Some(pci_device_bdf);
-        node.pci_device_handle = Some(PciDeviceHandle::Virtio(virtio_pci_device));
+        node.pci_device_handle = None;
+
         self.device_tree.lock().unwrap().insert(id, node);
 
-        Ok(pci_device_bdf)
+        Ok(())
     }
 
-    fn add_pvpanic_device(
+    fn add_cxl_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut cxl_devices = self.config.lock().unwrap().cxl.clone();
+        if let Some(cxl_list_cfg) = &mut cxl_devices {
+            for cxl_cfg in cxl_list_cfg.iter_mut() {
+                self.add_cxl_device(cxl_cfg)?;
+            }
+        }
+        self.config.lock().unwrap().cxl = cxl_devices;
+
+        Ok(())
+    }
+
+    /// UID and RCRB address of the CXL host bridges, for the CEDT.
+    pub fn cxl_host_bridges(&self) -> Vec<(u16, u64)> {
+        self.pci_segments
+            .iter()
+            .filter_map(|segment| segment.cxl_rcrb_address.map(|rcrb| (segment.id, rcrb)))
+            .collect()
+    }
+
+    fn add_xhci_controller(
         &mut self,
-    ) -> DeviceManagerResult<Option<Arc<Mutex<devices::PvPanicDevice>>>> {
-        let id = String::from(PVPANIC_DEVICE_NAME);
+        xhci_cfg: &XhciConfig,
+    ) -> DeviceManagerResult<Arc<Mutex<devices::usb::XhciController>>> {
+        let id = String::from(XHCI_DEVICE_NAME);
         let pci_segment_id = 0x0_u16;
 
-        info!("Creating pvpanic device {}", id);
+        info!("Creating xHCI controller {}: {:?}", id, xhci_cfg);
 
         let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&id, pci_segment_id)?;
-
-        let snapshot = snapshot_from_id(self.snapshot.as_ref(), id.as_str());
+            self.pci_resources(&id, pci_segment_id, None, false)?;
 
-        let pvpanic_device = devices::PvPanicDevice::new(id.clone(), snapshot)
-            .map_err(DeviceManagerError::PvPanicCreate)?;
+        let xhci = devices::usb::XhciController::new(
+            id.clone(),
+            xhci_cfg.num_ports,
+            self.memory_manager.lock().unwrap().guest_memory(),
+            self.msi_interrupt_manager.as_ref(),
+            pci_device_bdf.into(),
+            snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+        )
+        .map_err(DeviceManagerError::CreateXhci)?;
 
-        let pvpanic_device = Arc::new(Mutex::new(pvpanic_device));
+        let xhci = Arc::new(Mutex::new(xhci));
 
         let new_resources = self.add_pci_device(
-            pvpanic_device.clone(),
-            pvpanic_device.clone(),
+            xhci.clone(),
+            xhci.clone(),
             pci_segment_id,
             pci_device_bdf,
             resources,
         )?;
 
-        let mut node = device_node!(id, pvpanic_device);
+        let mut node = device_node!(id, xhci);
 
         node.resources = new_resources;
         node.pci_bdf = Some(pci_device_bdf);
@@ -3886,13 +5336,83 @@ impl DeviceManager {
 
         self.device_tree.lock().unwrap().insert(id, node);
 
-        Ok(Some(pvpanic_device))
+        Ok(xhci)
+    }
+
+    fn add_usb_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut usb_devices = self.config.lock().unwrap().usb_devices.clone();
+        if let Some(usb_list_cfg) = &mut usb_devices {
+            for usb_cfg in usb_list_cfg.iter_mut() {
+                self.attach_usb_device(usb_cfg)?;
+            }
+        }
+        self.config.lock().unwrap().usb_devices = usb_devices;
+
+        Ok(())
+    }
+
+    fn attach_usb_device(&mut self, usb_cfg: &mut UsbDeviceConfig) -> DeviceManagerResult<()> {
+        let xhci = self
+            .xhci
+            .clone()
+            .ok_or(DeviceManagerError::MissingXhciController)?;
+        let address = usb_cfg
+            .host_address()
+            .ok_or(DeviceManagerError::InvalidUsbDeviceAddress)?;
+
+        let id = if let Some(id) = &usb_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(USB_DEVICE_NAME_PREFIX)?;
+            usb_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Attaching USB device {}: {:?}", id, usb_cfg);
+
+        let device = devices::usb::UsbHostDevice::open(address)
+            .map_err(DeviceManagerError::OpenUsbDevice)?;
+        xhci.lock()
+            .unwrap()
+            .attach_device(id.clone(), device)
+            .map_err(DeviceManagerError::AttachUsbDevice)?;
+
+        // USB devices hang off the controller in the device tree, which
+        // keeps their identifiers unique among all the other devices.
+        let mut device_tree = self.device_tree.lock().unwrap();
+        let mut node = device_node!(id);
+        node.parent = Some(XHCI_DEVICE_NAME.to_owned());
+        device_tree.insert(id.clone(), node);
+        if let Some(xhci_node) = device_tree.get_mut(XHCI_DEVICE_NAME) {
+            xhci_node.children.push(id);
+        }
+
+        Ok(())
+    }
+
+    fn detach_usb_device(&mut self, id: &str) -> DeviceManagerResult<()> {
+        if let Some(xhci) = &self.xhci {
+            // The device may already be gone if it was unplugged from the host.
+            if !xhci.lock().unwrap().detach_device(id) {
+                warn!("USB device {} was no longer attached", id);
+            }
+        }
+
+        let mut device_tree = self.device_tree.lock().unwrap();
+        device_tree.remove(id);
+        if let Some(xhci_node) = device_tree.get_mut(XHCI_DEVICE_NAME) {
+            xhci_node.children.retain(|child| child != id);
+        }
+
+        Ok(())
     }
 
     fn pci_resources(
-        &self,
+        &mut self,
         id: &str,
         pci_segment_id: u16,
+        pci_bdf: Option<PciBdf>,
+        iommu: bool,
     ) -> DeviceManagerResult<(u16, PciBdf, Option<Vec<Resource>>)> {
         // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
@@ -3904,14 +5424,31 @@ impl DeviceManager {
                     .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
                 let pci_segment_id = pci_device_bdf.segment();
 
-                self.pci_segments[pci_segment_id as usize]
-                    .pci_bus
-                    .lock()
-                    .unwrap()
-                    .get_device_id(pci_device_bdf.device() as usize)
-                    .map_err(DeviceManagerError::GetPciDeviceId)?;
+                Self::reserve_pci_bdf(
+                    &mut self.pinned_pci_bdfs,
+                    &self.pci_segments,
+                    pci_device_bdf,
+                )?;
 
                 (pci_segment_id, pci_device_bdf, Some(node.resources.clone()))
+            } else if let Some(pci_device_bdf) = pci_bdf {
+                Self::reserve_pci_bdf(
+                    &mut self.pinned_pci_bdfs,
+                    &self.pci_segments,
+                    pci_device_bdf,
+                )?;
+
+                (pci_device_bdf.segment(), pci_device_bdf, None)
+            } else if let Some(pci_device_bdf) = iommu
+                .then(|| {
+                    Self::take_iommu_hotplug_slot(
+                        &mut self.free_iommu_hotplug_slots,
+                        pci_segment_id,
+                    )
+                })
+                .flatten()
+            {
+                (pci_segment_id, pci_device_bdf, None)
             } else {
                 let pci_device_bdf =
                     self.pci_segments[pci_segment_id as usize].next_device_bdf()?;
@@ -3948,6 +5485,17 @@ impl DeviceManager {
         &self.console
     }
 
+    /// Display, keyboard and tablet to expose through VNC, if enabled.
+    pub fn vnc_devices(&self) -> Option<(GpuDisplay, InputEventSender, InputEventSender)> {
+        self.vnc_devices.clone()
+    }
+
+    pub fn serial_capture(&self) -> Option<Vec<u8>> {
+        self.serial_capture
+            .as_ref()
+            .map(|capture| capture.lock().unwrap().contents())
+    }
+
     #[cfg(target_arch = "aarch64")]
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
@@ -4034,7 +5582,7 @@ impl DeviceManager {
     ) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&device_cfg.id)?;
 
-        if device_cfg.iommu && !self.is_iommu_segment(device_cfg.pci_segment) {
+        if device_cfg.iommu && !self.is_iommu_hotplug_allowed(device_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
@@ -4074,7 +5622,25 @@ impl DeviceManager {
         let device_tree = self.device_tree.lock().unwrap();
         let node = device_tree
             .get(&id)
-            .ok_or(DeviceManagerError::UnknownDeviceId(id))?;
+            .ok_or(DeviceManagerError::UnknownDeviceId(id.clone()))?;
+
+        // USB devices are detached from the xHCI controller right away, as
+        // there is no PCI device to eject.
+        if node.parent.as_deref() == Some(XHCI_DEVICE_NAME) {
+            drop(device_tree);
+            return self.detach_usb_device(&id);
+        }
+
+        let is_nvme_namespace = self
+            .config
+            .lock()
+            .unwrap()
+            .nvme
+            .as_ref()
+            .is_some_and(|nvme| nvme.iter().any(|n| node.parent.as_ref() == Some(&n.id)));
+        if is_nvme_namespace {
+            return Err(DeviceManagerError::NvmeNamespaceHotplugUnsupported);
+        }
 
         let pci_device_node = if node.pci_bdf.is_some() && node.pci_device_handle.is_some() {
             node
@@ -4097,6 +5663,15 @@ impl DeviceManager {
             .pci_device_handle
             .as_ref()
             .ok_or(DeviceManagerError::MissingPciDevice)?;
+        // vDPA devices can be removed whatever the type of device they expose.
+        let is_vdpa = self
+            .config
+            .lock()
+            .unwrap()
+            .vdpa
+            .as_ref()
+            .is_some_and(|vdpa| vdpa.iter().any(|dev| dev.id.as_ref() == Some(&id)));
+
         #[allow(irrefutable_let_patterns)]
         if let PciDeviceHandle::Virtio(virtio_pci_device) = pci_device_handle {
             let device_type = VirtioDeviceType::from(
@@ -4114,6 +5689,7 @@ impl DeviceManager {
                 | VirtioDeviceType::Pmem
                 | VirtioDeviceType::Fs
                 | VirtioDeviceType::Vsock => {}
+                _ if is_vdpa => {}
                 _ => return Err(DeviceManagerError::RemovalNotAllowed(device_type)),
             }
         }
@@ -4133,13 +5709,18 @@ impl DeviceManager {
         // Convert the device ID into the corresponding b/d/f.
         let pci_device_bdf = PciBdf::new(pci_segment_id, 0, device_id, 0);
 
-        // Give the PCI device ID back to the PCI bus.
-        self.pci_segments[pci_segment_id as usize]
-            .pci_bus
-            .lock()
-            .unwrap()
-            .put_device_id(device_id as usize)
-            .map_err(DeviceManagerError::PutPciDeviceId)?;
+        // Give the PCI device ID back to the PCI bus, unless the slot is
+        // reserved for devices hot-attached to the virtual IOMMU.
+        if self.iommu_hotplug_slots.contains(&pci_device_bdf) {
+            self.free_iommu_hotplug_slots.push(pci_device_bdf);
+        } else {
+            self.pci_segments[pci_segment_id as usize]
+                .pci_bus
+                .lock()
+                .unwrap()
+                .put_device_id(device_id as usize)
+                .map_err(DeviceManagerError::PutPciDeviceId)?;
+        }
 
         // Remove the device from the device tree along with its children.
         let mut device_tree = self.device_tree.lock().unwrap();
@@ -4191,7 +5772,10 @@ impl DeviceManager {
                 )
             }
             PciDeviceHandle::Virtio(virtio_pci_device) => {
-                let dev = virtio_pci_device.lock().unwrap();
+                let mut dev = virtio_pci_device.lock().unwrap();
+                // The virtio device can't be shut down while paused in D3hot.
+                dev.set_power_state(PciPowerState::D0);
+
                 let bar_addr = dev.config_bar_addr();
                 for (event, addr) in dev.ioeventfds(bar_addr) {
                     let io_addr = IoEventAddress::Mmio(addr);
@@ -4307,6 +5891,14 @@ impl DeviceManager {
                         mapping.mem_slot,
                     )
                     .map_err(DeviceManagerError::MemoryManager)?;
+
+                // Give the guest address range back to the PCI segment it was
+                // allocated from, for later hotplugged devices to reuse it.
+                self.pci_segments[pci_segment_id as usize]
+                    .mem64_allocator
+                    .lock()
+                    .unwrap()
+                    .free(mapping.addr, mapping.len);
             }
 
             virtio_device.lock().unwrap().shutdown();
@@ -4315,6 +5907,9 @@ impl DeviceManager {
                 .retain(|handler| !Arc::ptr_eq(&handler.virtio_device, &virtio_device));
         }
 
+        // Terminate the out-of-process backend if there is one
+        self.device_backends.remove(&id);
+
         event!(
             "vm",
             "device-removed",
@@ -4351,7 +5946,9 @@ impl DeviceManager {
             &mapping,
             handle.id.clone(),
             handle.pci_segment,
+            handle.pci_bdf,
             handle.dma_handler,
+            handle.transitional,
         )?;
 
         // Update the PCIU bitmap
@@ -4360,6 +5957,103 @@ impl DeviceManager {
         Ok(PciDeviceInfo { id: handle.id, bdf })
     }
 
+    fn validate_virtio_hotplug(&self) -> DeviceManagerResult<()> {
+        // virtio-mmio devices are discovered once at boot
+        if self.config.lock().unwrap().is_virtio_mmio_enabled() {
+            return Err(DeviceManagerError::VirtioMmioHotplugUnsupported);
+        }
+
+        Ok(())
+    }
+
+    // Reserve the highest free device slots of each PCI segment which is not
+    // entirely behind the virtual IOMMU, so that devices can be hot-attached
+    // to it later on. They are described as such to the guest from boot.
+    fn reserve_iommu_hotplug_slots(&mut self) -> Vec<PciBdf> {
+        let num_slots = self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map(|pc| pc.iommu_hotplug_slots)
+            .unwrap_or_default();
+
+        for segment in self.pci_segments.iter() {
+            if self.is_iommu_segment(segment.id) {
+                continue;
+            }
+
+            let mut pci_bus = segment.pci_bus.lock().unwrap();
+            let mut reserved = 0;
+            for device in (1..32).rev() {
+                if reserved == num_slots {
+                    break;
+                }
+                if pci_bus.get_device_id(device as usize).is_ok() {
+                    self.iommu_hotplug_slots
+                        .push(PciBdf::new(segment.id, 0, device, 0));
+                    reserved += 1;
+                }
+            }
+            if reserved < num_slots {
+                warn!(
+                    "Only {} PCI slots reserved for IOMMU hotplug on segment {}",
+                    reserved, segment.id
+                );
+            }
+        }
+        self.free_iommu_hotplug_slots
+            .clone_from(&self.iommu_hotplug_slots);
+
+        self.iommu_hotplug_slots.clone()
+    }
+
+    // Claim the given PCI address, either from the slots reserved at boot for
+    // pinned devices or directly from the PCI bus.
+    fn reserve_pci_bdf(
+        pinned_pci_bdfs: &mut BTreeSet<PciBdf>,
+        pci_segments: &[PciSegment],
+        pci_device_bdf: PciBdf,
+    ) -> DeviceManagerResult<()> {
+        if pinned_pci_bdfs.remove(&pci_device_bdf) {
+            return Ok(());
+        }
+
+        pci_segments[pci_device_bdf.segment() as usize]
+            .pci_bus
+            .lock()
+            .unwrap()
+            .get_device_id(pci_device_bdf.device() as usize)
+            .map_err(DeviceManagerError::GetPciDeviceId)
+    }
+
+    // Pick the highest of the slots reserved for the devices hot-attached to
+    // the virtual IOMMU on the given PCI segment, if any is left.
+    fn take_iommu_hotplug_slot(
+        free_slots: &mut Vec<PciBdf>,
+        pci_segment_id: u16,
+    ) -> Option<PciBdf> {
+        let index = free_slots
+            .iter()
+            .enumerate()
+            .filter(|(_, bdf)| bdf.segment() == pci_segment_id)
+            .max_by_key(|(_, bdf)| bdf.device())
+            .map(|(index, _)| index)?;
+
+        Some(free_slots.remove(index))
+    }
+
+    // Whether a device can be hot-attached to the virtual IOMMU on the given
+    // PCI segment.
+    fn is_iommu_hotplug_allowed(&self, pci_segment_id: u16) -> bool {
+        self.is_iommu_segment(pci_segment_id)
+            || self
+                .free_iommu_hotplug_slots
+                .iter()
+                .any(|bdf| bdf.segment() == pci_segment_id)
+    }
+
     fn is_iommu_segment(&self, pci_segment_id: u16) -> bool {
         self.config
             .lock()
@@ -4379,7 +6073,12 @@ impl DeviceManager {
     pub fn add_disk(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&disk_cfg.id)?;
 
-        if disk_cfg.iommu && !self.is_iommu_segment(disk_cfg.pci_segment) {
+        if disk_cfg.nvme.is_some() {
+            return Err(DeviceManagerError::NvmeNamespaceHotplugUnsupported);
+        }
+        self.validate_virtio_hotplug()?;
+
+        if disk_cfg.iommu && !self.is_iommu_hotplug_allowed(disk_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
@@ -4389,6 +6088,7 @@ impl DeviceManager {
 
     pub fn add_fs(&mut self, fs_cfg: &mut FsConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&fs_cfg.id)?;
+        self.validate_virtio_hotplug()?;
 
         let device = self.make_virtio_fs_device(fs_cfg)?;
         self.hotplug_virtio_pci_device(device)
@@ -4397,7 +6097,13 @@ impl DeviceManager {
     pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&pmem_cfg.id)?;
 
-        if pmem_cfg.iommu && !self.is_iommu_segment(pmem_cfg.pci_segment) {
+        if pmem_cfg.nvdimm {
+            return Err(DeviceManagerError::NvdimmHotplugUnsupported);
+        }
+
+        self.validate_virtio_hotplug()?;
+
+        if pmem_cfg.iommu && !self.is_iommu_hotplug_allowed(pmem_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
@@ -4407,8 +6113,9 @@ impl DeviceManager {
 
     pub fn add_net(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&net_cfg.id)?;
+        self.validate_virtio_hotplug()?;
 
-        if net_cfg.iommu && !self.is_iommu_segment(net_cfg.pci_segment) {
+        if net_cfg.iommu && !self.is_iommu_hotplug_allowed(net_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
@@ -4418,8 +6125,9 @@ impl DeviceManager {
 
     pub fn add_vdpa(&mut self, vdpa_cfg: &mut VdpaConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&vdpa_cfg.id)?;
+        self.validate_virtio_hotplug()?;
 
-        if vdpa_cfg.iommu && !self.is_iommu_segment(vdpa_cfg.pci_segment) {
+        if vdpa_cfg.iommu && !self.is_iommu_hotplug_allowed(vdpa_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
@@ -4427,10 +6135,16 @@ impl DeviceManager {
         self.hotplug_virtio_pci_device(device)
     }
 
+    pub fn add_usb_device(&mut self, usb_cfg: &mut UsbDeviceConfig) -> DeviceManagerResult<()> {
+        self.validate_identifier(&usb_cfg.id)?;
+        self.attach_usb_device(usb_cfg)
+    }
+
     pub fn add_vsock(&mut self, vsock_cfg: &mut VsockConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&vsock_cfg.id)?;
+        self.validate_virtio_hotplug()?;
 
-        if vsock_cfg.iommu && !self.is_iommu_segment(vsock_cfg.pci_segment) {
+        if vsock_cfg.iommu && !self.is_iommu_hotplug_allowed(vsock_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
@@ -4489,33 +6203,27 @@ impl DeviceManager {
 
     #[cfg(target_arch = "aarch64")]
     pub fn notify_power_button(&self) -> DeviceManagerResult<()> {
-        // There are two use cases:
-        // 1. Users will use direct kernel boot with device tree.
-        // 2. Users will use ACPI+UEFI boot.
-
-        // Trigger a GPIO pin 3 event to satisfy use case 1.
+        // The power button is wired to a GPIO pin, which is described both
+        // in the device tree and in the DSDT. This covers the direct kernel
+        // boot with device tree as well as the ACPI+UEFI boot, without
+        // relying on the GED which isn't supported by every ACPI guest.
         self.gpio_device
             .as_ref()
             .unwrap()
             .lock()
             .unwrap()
-            .trigger_key(3)
-            .map_err(DeviceManagerError::AArch64PowerButtonNotification)?;
-        // Trigger a GED power button event to satisfy use case 2.
-        return self
-            .ged_notification_device
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .notify(AcpiNotificationFlags::POWER_BUTTON_CHANGED)
-            .map_err(DeviceManagerError::PowerButtonNotification);
+            .trigger_key(GPIO_PIN_POWER_BUTTON.into())
+            .map_err(DeviceManagerError::AArch64PowerButtonNotification)
     }
 
     pub fn iommu_attached_devices(&self) -> &Option<(PciBdf, Vec<PciBdf>)> {
         &self.iommu_attached_devices
     }
 
+    pub fn nvdimm_device(&self) -> Option<&Arc<Mutex<devices::NvdimmDevice>>> {
+        self.nvdimm_device.as_ref()
+    }
+
     fn validate_identifier(&self, id: &Option<String>) -> DeviceManagerResult<()> {
         if let Some(id) = id {
             if id.starts_with("__") {
@@ -4555,6 +6263,93 @@ fn numa_node_id_from_pci_segment_id(numa_nodes: &NumaNodes, pci_segment_id: u16)
     0
 }
 
+// GPIO interrupt connection descriptor, see section 6.4.3.8.1 of the ACPI
+// specification.
+#[cfg(target_arch = "aarch64")]
+struct GpioInt {
+    pin: u16,
+    source: &'static str,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Aml for GpioInt {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
+        // The fixed part of the descriptor is followed by the pin table and
+        // the name of the GPIO controller.
+        const PIN_TABLE_OFFSET: u16 = 23;
+        let source_offset = PIN_TABLE_OFFSET + 2;
+        let vendor_data_offset = source_offset + self.source.len() as u16 + 1;
+
+        let mut bytes = vec![0x8c]; // GPIO Connection Descriptor
+        bytes.extend_from_slice(&(vendor_data_offset - 3).to_le_bytes()); // Length
+        bytes.push(1); // Revision ID
+        bytes.push(0); // GPIO Connection Type: Interrupt
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // General Flags: Consumer
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // Interrupt Flags: Edge, ActiveHigh, Exclusive
+        bytes.push(0); // Pin Configuration: Default
+        bytes.extend_from_slice(&0u16.to_le_bytes()); // Output Drive Strength
+        bytes.extend_from_slice(&0u16.to_le_bytes()); // Debounce Timeout
+        bytes.extend_from_slice(&PIN_TABLE_OFFSET.to_le_bytes());
+        bytes.push(0); // Resource Source Index
+        bytes.extend_from_slice(&source_offset.to_le_bytes());
+        bytes.extend_from_slice(&vendor_data_offset.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes()); // Vendor Data Length
+        bytes.extend_from_slice(&self.pin.to_le_bytes());
+        bytes.extend_from_slice(self.source.as_bytes());
+        bytes.push(0);
+
+        for byte in bytes {
+            sink.byte(byte);
+        }
+    }
+}
+
+// PL061 GPIO controller, through which ACPI guests get the power button
+// events, as there is no embedded controller.
+#[cfg(target_arch = "aarch64")]
+struct GpioDevice {
+    addr: u64,
+    irq: u32,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Aml for GpioDevice {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
+        aml::Device::new(
+            "_SB_.GPO0".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ARMH0061"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![
+                        &aml::Memory32Fixed::new(true, self.addr as u32, MMIO_LEN as u32),
+                        &aml::Interrupt::new(true, true, false, false, self.irq),
+                    ]),
+                ),
+                &aml::Name::new(
+                    "_AEI".into(),
+                    &aml::ResourceTemplate::new(vec![&GpioInt {
+                        pin: GPIO_PIN_POWER_BUTTON,
+                        source: "\\_SB_.GPO0",
+                    }]),
+                ),
+                // Event handler of the power button pin.
+                &aml::Method::new(
+                    "_E03".into(),
+                    0,
+                    false,
+                    vec![&aml::Notify::new(
+                        &aml::Path::new("\\_SB_.PWRB"),
+                        &0x80usize,
+                    )],
+                ),
+            ],
+        )
+        .to_aml_bytes(sink)
+    }
+}
+
 struct TpmDevice {}
 
 impl Aml for TpmDevice {
@@ -4658,6 +6453,22 @@ impl Aml for DeviceManager {
             segment.to_aml_bytes(sink);
         }
 
+        // CXL root device, under which the host bridges are enumerated.
+        if self
+            .pci_segments
+            .iter()
+            .any(|s| s.cxl_rcrb_address.is_some())
+        {
+            aml::Device::new(
+                "_SB_.CXLM".into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &"ACPI0017"),
+                    &aml::Name::new("_UID".into(), &aml::ZERO),
+                ],
+            )
+            .to_aml_bytes(sink);
+        }
+
         let mut mbrd_memory = Vec::new();
 
         for segment in &self.pci_segments {
@@ -4730,6 +6541,25 @@ impl Aml for DeviceManager {
             .to_aml_bytes(sink);
         }
 
+        #[cfg(target_arch = "aarch64")]
+        if let Some(gpio_info) = self
+            .get_device_info()
+            .get(&(DeviceType::Gpio, "gpio".to_string()))
+        {
+            GpioDevice {
+                addr: gpio_info.addr(),
+                irq: gpio_info.irq(),
+            }
+            .to_aml_bytes(sink);
+        }
+
+        // Suspend to RAM and hibernation are only reported through the sleep
+        // control register, which only exists on x86_64.
+        #[cfg(target_arch = "x86_64")]
+        {
+            aml::Name::new("_S3_".into(), &aml::Package::new(vec![&3u8])).to_aml_bytes(sink);
+            aml::Name::new("_S4_".into(), &aml::Package::new(vec![&4u8])).to_aml_bytes(sink);
+        }
         aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes(sink);
 
         aml::Device::new(
@@ -4741,11 +6571,65 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes(sink);
 
+        // virtio-mmio devices
+        for (i, (addr, irq)) in self.virtio_mmio_devices.iter().enumerate() {
+            aml::Device::new(
+                format!("_SB_.VR{i:02X}").as_str().into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &"LNRO0005"),
+                    &aml::Name::new("_UID".into(), &(i as u32)),
+                    &aml::Name::new(
+                        "_CRS".into(),
+                        &aml::ResourceTemplate::new(vec![
+                            &aml::AddressSpace::new_memory(
+                                aml::AddressSpaceCacheable::NotCacheable,
+                                true,
+                                addr.0,
+                                addr.0 + VIRTIO_MMIO_SIZE - 1,
+                                None,
+                            ),
+                            &aml::Interrupt::new(true, true, false, false, *irq),
+                        ]),
+                    ),
+                ],
+            )
+            .to_aml_bytes(sink);
+        }
+
         if self.config.lock().unwrap().tpm.is_some() {
             // Add tpm device
             TpmDevice {}.to_aml_bytes(sink);
         }
 
+        #[cfg(target_arch = "x86_64")]
+        if self.config.lock().unwrap().fw_cfg.is_some() {
+            aml::Device::new(
+                "_SB_.FWCF".into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &"QEMU0002"),
+                    &aml::Name::new("_STA".into(), &(0xB_usize)),
+                    &aml::Name::new(
+                        "_CRS".into(),
+                        &aml::ResourceTemplate::new(vec![&aml::IO::new(
+                            devices::legacy::FW_CFG_PORT_BASE as u16,
+                            devices::legacy::FW_CFG_PORT_BASE as u16,
+                            1,
+                            devices::legacy::FW_CFG_PORT_SIZE as u8,
+                        )]),
+                    ),
+                ],
+            )
+            .to_aml_bytes(sink);
+        }
+
+        if let Some(nvdimm_device) = &self.nvdimm_device {
+            nvdimm_device.lock().unwrap().to_aml_bytes(sink);
+        }
+
+        if let Some(vmclock_device) = &self.vmclock_device {
+            vmclock_device.to_aml_bytes(sink);
+        }
+
         self.ged_notification_device
             .as_ref()
             .unwrap()
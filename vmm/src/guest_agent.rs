@@ -0,0 +1,188 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Client for a qemu-guest-agent compatible agent running in the guest.
+//!
+//! The agent listens on a vsock port of the guest, reached through the UNIX
+//! socket of the virtio-vsock device. Each command opens a new connection,
+//! sends a single JSON request and waits for its response, the protocol
+//! being line oriented.
+
+use crate::vm_config::VmConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+// Upper bound on the time spent waiting for the agent, so that an
+// unresponsive guest does not stall the VMM.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Error)]
+pub enum GuestAgentError {
+    #[error("Error connecting to the guest agent: {0}")]
+    Connect(#[source] io::Error),
+
+    #[error("Guest agent vsock port {0} could not be reached: {1:?}")]
+    PortUnreachable(u32, String),
+
+    #[error("Error communicating with the guest agent: {0}")]
+    Io(#[source] io::Error),
+
+    #[error("Invalid guest agent response: {0}")]
+    InvalidResponse(#[source] serde_json::Error),
+
+    #[error("Guest agent command {0} failed: {1}")]
+    Command(&'static str, String),
+}
+
+type Result<T> = std::result::Result<T, GuestAgentError>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GuestIpAddress {
+    pub ip_address_type: String,
+    pub ip_address: String,
+    pub prefix: u8,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GuestNetworkInterface {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardware_address: Option<String>,
+    #[serde(default)]
+    pub ip_addresses: Vec<GuestIpAddress>,
+}
+
+#[derive(Deserialize)]
+struct AgentErrorDesc {
+    desc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AgentResponse {
+    Return(Value),
+    Error(AgentErrorDesc),
+}
+
+pub struct GuestAgent {
+    socket: PathBuf,
+    port: u32,
+}
+
+impl GuestAgent {
+    /// Returns the agent described by the VM configuration, if any.
+    pub fn new(config: &VmConfig) -> Option<Self> {
+        let port = config.guest_agent.as_ref()?.port;
+        let socket = config.vsock.as_ref()?.socket.clone();
+
+        Some(GuestAgent { socket, port })
+    }
+
+    fn connect(&self) -> Result<BufReader<UnixStream>> {
+        let mut stream = UnixStream::connect(&self.socket).map_err(GuestAgentError::Connect)?;
+        stream
+            .set_read_timeout(Some(AGENT_TIMEOUT))
+            .map_err(GuestAgentError::Connect)?;
+        stream
+            .set_write_timeout(Some(AGENT_TIMEOUT))
+            .map_err(GuestAgentError::Connect)?;
+
+        // Ask the vsock device to forward the connection to the agent port.
+        stream
+            .write_all(format!("CONNECT {}\n", self.port).as_bytes())
+            .map_err(GuestAgentError::Io)?;
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line).map_err(GuestAgentError::Io)?;
+        if !line.starts_with("OK ") {
+            return Err(GuestAgentError::PortUnreachable(
+                self.port,
+                line.trim_end().to_string(),
+            ));
+        }
+
+        Ok(stream)
+    }
+
+    fn execute(&self, command: &'static str, arguments: Option<Value>) -> Result<Value> {
+        let mut stream = self.connect()?;
+
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut request = request.to_string();
+        request.push('\n');
+        stream
+            .get_mut()
+            .write_all(request.as_bytes())
+            .map_err(GuestAgentError::Io)?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line).map_err(GuestAgentError::Io)?;
+        match serde_json::from_str(&line).map_err(GuestAgentError::InvalidResponse)? {
+            AgentResponse::Return(value) => Ok(value),
+            AgentResponse::Error(e) => Err(GuestAgentError::Command(command, e.desc)),
+        }
+    }
+
+    /// Freezes the guest filesystems, returning how many were frozen.
+    pub fn fs_freeze(&self) -> Result<u64> {
+        let frozen = self.execute("guest-fsfreeze-freeze", None)?;
+        serde_json::from_value(frozen).map_err(GuestAgentError::InvalidResponse)
+    }
+
+    /// Thaws the guest filesystems, returning how many were thawed.
+    pub fn fs_thaw(&self) -> Result<u64> {
+        let thawed = self.execute("guest-fsfreeze-thaw", None)?;
+        serde_json::from_value(thawed).map_err(GuestAgentError::InvalidResponse)
+    }
+
+    pub fn network_interfaces(&self) -> Result<Vec<GuestNetworkInterface>> {
+        let interfaces = self.execute("guest-network-get-interfaces", None)?;
+        serde_json::from_value(interfaces).map_err(GuestAgentError::InvalidResponse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_agent_response() {
+        let response: AgentResponse = serde_json::from_str(
+            r#"{"return": [{"name": "eth0", "hardware-address": "12:34:56:78:9a:bc",
+                "ip-addresses": [{"ip-address-type": "ipv4", "ip-address": "192.168.249.2",
+                "prefix": 24}], "statistics": {"rx-bytes": 0}}, {"name": "lo"}]}"#,
+        )
+        .unwrap();
+        let AgentResponse::Return(value) = response else {
+            panic!("Expected a return value");
+        };
+        let interfaces: Vec<GuestNetworkInterface> = serde_json::from_value(value).unwrap();
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].name, "eth0");
+        assert_eq!(
+            interfaces[0].hardware_address.as_deref(),
+            Some("12:34:56:78:9a:bc")
+        );
+        assert_eq!(interfaces[0].ip_addresses[0].ip_address, "192.168.249.2");
+        assert_eq!(interfaces[0].ip_addresses[0].prefix, 24);
+        assert!(interfaces[1].ip_addresses.is_empty());
+
+        let response: AgentResponse = serde_json::from_str(
+            r#"{"error": {"class": "GenericError", "desc": "Command is disabled"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(response, AgentResponse::Error(e) if e.desc == "Command is disabled"));
+    }
+}
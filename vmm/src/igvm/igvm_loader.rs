@@ -48,6 +48,12 @@ pub enum Error {
     CompleteIsolatedImport(#[source] hypervisor::HypervisorVmError),
     #[error("Error decoding host data: {0}")]
     FailedToDecodeHostData(#[source] hex::FromHexError),
+    #[error("IGVM file requests VTL{0} for a paravisor, VSM is not supported and only VTL0 guests can run")]
+    UnsupportedVtl(u8),
+    #[error("IGVM file sets the VBS context of a VTL{0} vCPU, VBS isolation is not supported")]
+    UnsupportedVbsVpContext(u8),
+    #[error("IGVM file requires a VBS measurement, VBS isolation is not supported")]
+    UnsupportedVbsMeasurement,
 }
 
 #[allow(dead_code)]
@@ -162,6 +168,13 @@ pub fn load_igvm(
     let mask = match &igvm_file.platforms()[0] {
         IgvmPlatformHeader::SupportedPlatform(info) => {
             debug_assert!(info.platform_type == IgvmPlatformType::SEV_SNP);
+            // Running a paravisor requires VSM support (per-VTL vCPU state,
+            // VTL switches and memory protections), which is not available
+            // through the MSHV interface used here. Reject such files early
+            // rather than failing in the middle of the load.
+            if info.highest_vtl != 0 {
+                return Err(Error::UnsupportedVtl(info.highest_vtl));
+            }
             info.compatibility_mask
         }
     };
@@ -372,14 +385,14 @@ pub fn load_igvm(
                 loaded_info.snp_id_block.author_public_key = **author_public_key;
             }
             IgvmDirectiveHeader::X64VbsVpContext {
-                vtl: _,
+                vtl,
                 registers: _,
                 compatibility_mask: _,
             } => {
-                todo!("VbsVpContext not supported");
+                return Err(Error::UnsupportedVbsVpContext(*vtl as u8));
             }
             IgvmDirectiveHeader::VbsMeasurement { .. } => {
-                todo!("VbsMeasurement not supported")
+                return Err(Error::UnsupportedVbsMeasurement);
             }
             IgvmDirectiveHeader::ParameterInsert(IGVM_VHS_PARAMETER_INSERT {
                 gpa,
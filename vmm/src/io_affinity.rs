@@ -0,0 +1,77 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! I/O affinities.
+//!
+//! An I/O affinity gathers the host CPUs dedicated to the processing of the
+//! virtio queues of the devices assigned to it, split evenly into CPU sets.
+//! The queues of these devices are distributed across the CPU sets in a
+//! round-robin fashion, the thread processing each queue being pinned to the
+//! CPU set it got assigned to. Each device keeps its own queue threads, only
+//! their placement is shared.
+
+use crate::vm_config::IoAffinityConfig;
+use std::collections::BTreeMap;
+
+pub struct IoAffinity {
+    // Host CPUs of each CPU set
+    cpu_sets: Vec<Vec<usize>>,
+    next_cpu_set: usize,
+}
+
+impl IoAffinity {
+    pub fn new(config: &IoAffinityConfig) -> Self {
+        let num_cpus = config.host_cpus.len();
+        let cpu_sets = (0..config.num_sets)
+            .map(|i| {
+                config.host_cpus
+                    [i * num_cpus / config.num_sets..(i + 1) * num_cpus / config.num_sets]
+                    .to_vec()
+            })
+            .collect();
+
+        IoAffinity {
+            cpu_sets,
+            next_cpu_set: 0,
+        }
+    }
+
+    /// Assign each of the `num_queues` queues of a device to a CPU set,
+    /// returning the host CPUs its processing must run on.
+    pub fn queue_affinity(&mut self, num_queues: usize) -> BTreeMap<u16, Vec<usize>> {
+        (0..num_queues as u16)
+            .map(|queue_index| {
+                let host_cpus = self.cpu_sets[self.next_cpu_set].clone();
+                self.next_cpu_set = (self.next_cpu_set + 1) % self.cpu_sets.len();
+                (queue_index, host_cpus)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_affinity() {
+        let mut io_affinity = IoAffinity::new(&IoAffinityConfig {
+            id: "io0".to_string(),
+            num_sets: 2,
+            host_cpus: vec![2, 3, 4, 5, 6],
+        });
+        assert_eq!(io_affinity.cpu_sets, vec![vec![2, 3], vec![4, 5, 6]]);
+
+        assert_eq!(
+            io_affinity.queue_affinity(3),
+            BTreeMap::from([(0, vec![2, 3]), (1, vec![4, 5, 6]), (2, vec![2, 3])])
+        );
+        // Queues of the next device keep on being spread across the CPU sets.
+        assert_eq!(
+            io_affinity.queue_affinity(1),
+            BTreeMap::from([(0, vec![4, 5, 6])])
+        );
+    }
+}
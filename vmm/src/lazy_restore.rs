@@ -0,0 +1,390 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! On-demand restore of the guest memory from a snapshot.
+//!
+//! Rather than reading the whole memory content before the VM is resumed,
+//! the saved ranges are registered with userfaultfd and each page is copied
+//! from the snapshot file the first time it is accessed, either by a vCPU or
+//! by the VMM itself. A dedicated thread serves the page faults until every
+//! page has been populated.
+
+use seccompiler::{apply_filter, BpfProgram};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+use thiserror::Error;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iowr_nr};
+
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFD_API: u64 = 0xaa;
+const UFFDIO: u32 = 0xaa;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+// Only the page fault events are enabled, whose layout is described here.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u64,
+}
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+ioctl_iowr_nr!(UFFDIO_ZEROPAGE, UFFDIO, 0x04, UffdioZeropage);
+
+const EPOLL_EVENT_UFFD: u64 = 0;
+const EPOLL_EVENT_KILL: u64 = 1;
+
+#[derive(Debug, Error)]
+pub enum LazyRestoreError {
+    #[error("Error creating userfaultfd: {0}")]
+    CreateUffd(#[source] io::Error),
+
+    #[error("Error enabling the userfaultfd API: {0}")]
+    UffdApi(#[source] io::Error),
+
+    #[error("Error registering memory range with userfaultfd: {0}")]
+    UffdRegister(#[source] io::Error),
+
+    #[error("Error creating kill EventFd: {0}")]
+    EventFd(#[source] io::Error),
+
+    #[error("Error spawning lazy restore thread: {0}")]
+    SpawnThread(#[source] io::Error),
+}
+
+type Result<T> = std::result::Result<T, LazyRestoreError>;
+
+/// Range of guest memory to be populated from the snapshot file.
+pub struct LazyRange {
+    pub host_addr: u64,
+    pub len: u64,
+    pub file_offset: u64,
+}
+
+struct PopulatedRange {
+    range: LazyRange,
+    // One bit per page, set once the page has been populated.
+    populated: Vec<u64>,
+}
+
+impl PopulatedRange {
+    fn new(range: LazyRange, page_size: u64) -> Self {
+        let pages = range.len.div_ceil(page_size) as usize;
+        PopulatedRange {
+            range,
+            populated: vec![0; pages.div_ceil(64)],
+        }
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.range.host_addr && addr < self.range.host_addr + self.range.len
+    }
+
+    // Marks the page as populated, returning whether it already was.
+    fn test_and_set(&mut self, page: usize) -> bool {
+        let (word, bit) = (page / 64, page % 64);
+        let populated = self.populated[word] & (1 << bit) != 0;
+        self.populated[word] |= 1 << bit;
+        populated
+    }
+}
+
+/// Guest memory registered with userfaultfd, waiting for the page faults to
+/// be served.
+pub struct LazyRestore {
+    uffd: File,
+    memory_file: File,
+    ranges: Vec<PopulatedRange>,
+    page_size: u64,
+    remaining_pages: u64,
+}
+
+impl LazyRestore {
+    pub fn new(memory_file: File, ranges: Vec<LazyRange>, page_size: u64) -> Result<Self> {
+        // SAFETY: FFI call with valid flags, the returned file descriptor is
+        // checked below.
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(LazyRestoreError::CreateUffd(io::Error::last_os_error()));
+        }
+        // SAFETY: fd was just created and is owned by nobody else.
+        let uffd = unsafe { File::from_raw_fd(fd as i32) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // SAFETY: the file descriptor is a valid userfaultfd and api is a
+        // properly initialized uffdio_api structure.
+        let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(LazyRestoreError::UffdApi(io::Error::last_os_error()));
+        }
+
+        let mut remaining_pages = 0;
+        let mut populated_ranges = Vec::new();
+        for range in ranges {
+            let mut register = UffdioRegister {
+                range: UffdioRange {
+                    start: range.host_addr,
+                    len: range.len,
+                },
+                mode: UFFDIO_REGISTER_MODE_MISSING,
+                ioctls: 0,
+            };
+            // SAFETY: the range is guest RAM mapped by the VMM, and register
+            // is a properly initialized uffdio_register structure.
+            let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_REGISTER(), &mut register) };
+            if ret < 0 {
+                return Err(LazyRestoreError::UffdRegister(io::Error::last_os_error()));
+            }
+
+            remaining_pages += range.len.div_ceil(page_size);
+            populated_ranges.push(PopulatedRange::new(range, page_size));
+        }
+
+        Ok(LazyRestore {
+            uffd,
+            memory_file,
+            ranges: populated_ranges,
+            page_size,
+            remaining_pages,
+        })
+    }
+
+    /// Starts serving the page faults from a dedicated thread.
+    pub fn start(self, seccomp_filter: BpfProgram, exit_evt: EventFd) -> Result<LazyRestoreHandle> {
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(LazyRestoreError::EventFd)?;
+        let thread_kill_evt = kill_evt.try_clone().map_err(LazyRestoreError::EventFd)?;
+
+        let thread = thread::Builder::new()
+            .name("lazy-restore".to_string())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        return;
+                    }
+                }
+
+                if let Err(e) = self.run(thread_kill_evt) {
+                    // The faulting threads would wait forever otherwise.
+                    error!("Error serving guest memory page faults: {}", e);
+                    exit_evt.write(1).ok();
+                }
+            })
+            .map_err(LazyRestoreError::SpawnThread)?;
+
+        Ok(LazyRestoreHandle {
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(mut self, kill_evt: EventFd) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        // SAFETY: epoll_fd is a valid file descriptor owned by nobody else.
+        let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.uffd.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, EPOLL_EVENT_UFFD),
+        )?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, EPOLL_EVENT_KILL),
+        )?;
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+        while self.remaining_pages > 0 {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    EPOLL_EVENT_UFFD => self.handle_faults()?,
+                    EPOLL_EVENT_KILL => return Ok(()),
+                    _ => unreachable!(),
+                }
+            }
+        }
+
+        info!("Guest memory fully restored from snapshot");
+        for range in self.ranges.iter() {
+            let unregister = UffdioRange {
+                start: range.range.host_addr,
+                len: range.range.len,
+            };
+            // SAFETY: the range has been registered with this userfaultfd.
+            let ret = unsafe { ioctl_with_ref(&self.uffd, UFFDIO_UNREGISTER(), &unregister) };
+            if ret < 0 {
+                warn!(
+                    "Failed unregistering guest memory from userfaultfd: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_faults(&mut self) -> io::Result<()> {
+        let mut msgs = [UffdMsg::default(); 16];
+        loop {
+            // SAFETY: the buffer is large enough for the requested size.
+            let ret = unsafe {
+                libc::read(
+                    self.uffd.as_raw_fd(),
+                    msgs.as_mut_ptr() as *mut libc::c_void,
+                    std::mem::size_of_val(&msgs),
+                )
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+
+            let count = ret as usize / std::mem::size_of::<UffdMsg>();
+            for msg in msgs.iter().take(count) {
+                if msg.event == UFFD_EVENT_PAGEFAULT {
+                    self.populate(msg.address & !(self.page_size - 1))?;
+                }
+            }
+        }
+    }
+
+    fn populate(&mut self, addr: u64) -> io::Result<()> {
+        let page_size = self.page_size;
+        let Some(range) = self.ranges.iter_mut().find(|r| r.contains(addr)) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page fault outside of guest memory at {addr:#x}"),
+            ));
+        };
+        let page = ((addr - range.range.host_addr) / page_size) as usize;
+
+        let ret = if range.test_and_set(page) {
+            // The page has been populated already, and then discarded, e.g.
+            // by the balloon, so its content must not come back.
+            let mut zeropage = UffdioZeropage {
+                range: UffdioRange {
+                    start: addr,
+                    len: page_size,
+                },
+                ..Default::default()
+            };
+            // SAFETY: the range is part of the registered guest memory.
+            unsafe { ioctl_with_mut_ref(&self.uffd, UFFDIO_ZEROPAGE(), &mut zeropage) }
+        } else {
+            self.remaining_pages -= 1;
+
+            let mut buf = vec![0u8; page_size as usize];
+            let offset = range.range.file_offset + addr - range.range.host_addr;
+            self.memory_file.read_exact_at(&mut buf, offset)?;
+            let mut copy = UffdioCopy {
+                dst: addr,
+                src: buf.as_ptr() as u64,
+                len: page_size,
+                ..Default::default()
+            };
+            // SAFETY: the destination is part of the registered guest memory
+            // and the source buffer is one page long.
+            unsafe { ioctl_with_mut_ref(&self.uffd, UFFDIO_COPY(), &mut copy) }
+        };
+
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // Another fault on the same page raced with this one.
+            if e.raw_os_error() != Some(libc::EEXIST) {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Thread serving the guest memory page faults, stopped when dropped.
+pub struct LazyRestoreHandle {
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for LazyRestoreHandle {
+    fn drop(&mut self) {
+        self.kill_evt.write(1).ok();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Lazy restore thread panicked");
+            }
+        }
+    }
+}
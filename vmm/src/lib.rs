@@ -9,21 +9,27 @@ extern crate event_monitor;
 extern crate log;
 
 use crate::api::{
-    ApiRequest, ApiResponse, RequestHandler, VmInfoResponse, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiRequest, ApiResponse, RequestHandler, VmApiChannels, VmInfoResponse, VmReceiveMigrationData,
+    VmSendMigrationData, VmSerialCaptureResponse, VmmPingResponse,
 };
 use crate::config::{
-    add_to_config, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
+    add_to_config, CgroupResources, ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig,
+    NetConfig, PanicAction, PmemConfig, RestoreConfig, SecurityLabelConfig, UsbDeviceConfig,
     UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
 };
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::GuestDebuggable;
+use crate::guest_agent::GuestAgent;
 use crate::landlock::Landlock;
-use crate::memory_manager::MemoryManager;
+use crate::memory_manager::{MemoryManager, MemoryRestoreMode};
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 use crate::migration::get_vm_snapshot;
-use crate::migration::{recv_vm_config, recv_vm_state};
+use crate::migration::{
+    delete_snapshot, list_snapshots, recv_vm_config, recv_vm_state, send_snapshot_metadata,
+    snapshot_url, url_to_path, SnapshotMetadata,
+};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::security_label;
 use crate::vm::{Error as VmError, Vm, VmState};
 use anyhow::anyhow;
 #[cfg(feature = "dbus_api")]
@@ -38,54 +44,67 @@ use seccompiler::{apply_filter, SeccompAction};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
 use signal_hook::iterator::{Handle, Signals};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io;
 use std::io::{stdout, Read, Write};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{result, thread};
 use thiserror::Error;
 use tracer::trace_scoped;
 use vm_memory::bitmap::AtomicBitmap;
-use vm_memory::{ReadVolatile, WriteVolatile};
+use vm_memory::bitmap::BitmapSlice;
+use vm_memory::{ReadVolatile, VolatileMemoryError, VolatileSlice, WriteVolatile};
 use vm_migration::{protocol::*, Migratable};
 use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::signal::unblock_signal;
 use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+use vmm_sys_util::timerfd::TimerFd;
 
 mod acpi;
 pub mod api;
+pub mod cgroup;
 mod clone3;
 pub mod config;
 pub mod console_devices;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 mod coredump;
 pub mod cpu;
+pub mod device_backend;
 pub mod device_manager;
 pub mod device_tree;
 #[cfg(feature = "guest_debug")]
 mod gdb;
+pub mod guest_agent;
 #[cfg(feature = "igvm")]
 mod igvm;
 pub mod interrupt;
+mod io_affinity;
 pub mod landlock;
+pub mod lazy_restore;
 pub mod memory_manager;
 pub mod migration;
+pub mod numa_placement;
 mod pci_segment;
+pub mod seccomp_audit;
 pub mod seccomp_filters;
+pub mod security_label;
 mod serial_manager;
 mod sigwinch_listener;
 pub mod vm;
 pub mod vm_config;
+#[cfg(target_arch = "x86_64")]
+pub mod vmbus_relay;
+pub mod vnc;
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 type GuestRegionMmap = vm_memory::GuestRegionMmap<AtomicBitmap>;
@@ -117,6 +136,14 @@ pub enum Error {
     #[error("Error reading from EventFd: {0}")]
     EventFdRead(#[source] io::Error),
 
+    /// Cannot create TimerFd.
+    #[error("Error creating TimerFd: {0}")]
+    TimerFdCreate(#[source] io::Error),
+
+    /// Cannot read from TimerFd.
+    #[error("Error reading from TimerFd: {0}")]
+    TimerFdRead(#[source] io::Error),
+
     /// Cannot create epoll context.
     #[error("Error creating epoll context: {0}")]
     Epoll(#[source] io::Error),
@@ -217,6 +244,10 @@ pub enum EpollDispatch {
     Api = 2,
     ActivateVirtioDevices = 3,
     Debug = 4,
+    GuestPanic = 5,
+    HostCpus = 6,
+    Hibernate = 7,
+    Suspend = 8,
     Unknown,
 }
 
@@ -229,6 +260,10 @@ impl From<u64> for EpollDispatch {
             2 => Api,
             3 => ActivateVirtioDevices,
             4 => Debug,
+            5 => GuestPanic,
+            6 => HostCpus,
+            7 => Hibernate,
+            8 => Suspend,
             _ => Unknown,
         }
     }
@@ -411,8 +446,11 @@ pub fn start_vmm_thread(
     #[cfg(feature = "guest_debug")] vm_debug_event: EventFd,
     exit_event: EventFd,
     seccomp_action: &SeccompAction,
+    seccomp_audit_enable: bool,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     landlock_enable: bool,
+    multi_vm: bool,
+    xdp: bool,
 ) -> Result<VmmThreadHandle> {
     #[cfg(feature = "guest_debug")]
     let gdb_hw_breakpoints = hypervisor.get_guest_debug_hw_bps();
@@ -426,18 +464,31 @@ pub fn start_vmm_thread(
     let api_event_clone = api_event.try_clone().map_err(Error::EventFdClone)?;
     let hypervisor_type = hypervisor.hypervisor_type();
 
-    // Retrieve seccomp filter
-    let vmm_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::Vmm, hypervisor_type)
-        .map_err(Error::CreateSeccompFilter)?;
+    // Retrieve seccomp filter. In audit mode, the system calls denied to the
+    // VMM thread are reported instead of killing the VMM, while the other
+    // threads have theirs logged through the action they are given.
+    let vmm_seccomp_filter = if seccomp_audit_enable {
+        get_seccomp_filter(&SeccompAction::Trap, Thread::Vmm { xdp }, hypervisor_type)
+            .map(seccomp_audit::notify_on_mismatch)
+    } else {
+        get_seccomp_filter(seccomp_action, Thread::Vmm { xdp }, hypervisor_type)
+    }
+    .map_err(Error::CreateSeccompFilter)?;
 
     let vmm_seccomp_action = seccomp_action.clone();
+    // Only used in multi-VM mode, the map remains empty otherwise.
+    let vm_api_channels = VmApiChannels::default();
     let thread = {
         let exit_event = exit_event.try_clone().map_err(Error::EventFdClone)?;
+        let vm_api_channels = multi_vm.then(|| vm_api_channels.clone());
         thread::Builder::new()
             .name("vmm".to_string())
             .spawn(move || {
                 // Apply seccomp filter for VMM thread.
-                if !vmm_seccomp_filter.is_empty() {
+                if seccomp_audit_enable {
+                    seccomp_audit::apply_filter(&vmm_seccomp_filter)
+                        .map_err(Error::ApplySeccompFilter)?;
+                } else if !vmm_seccomp_filter.is_empty() {
                     apply_filter(&vmm_seccomp_filter).map_err(Error::ApplySeccompFilter)?;
                 }
 
@@ -452,6 +503,9 @@ pub fn start_vmm_thread(
                     hypervisor,
                     exit_event,
                 )?;
+                vmm.cgroup = vm_api_channels.is_none();
+                vmm.vm_api_channels = vm_api_channels;
+                vmm.xdp = xdp;
 
                 vmm.setup_signal_handler(landlock_enable)?;
 
@@ -487,6 +541,7 @@ pub fn start_vmm_thread(
             http_path,
             api_event_clone,
             api_sender,
+            vm_api_channels,
             seccomp_action,
             exit_event,
             hypervisor_type,
@@ -497,6 +552,7 @@ pub fn start_vmm_thread(
             http_fd,
             api_event_clone,
             api_sender,
+            vm_api_channels,
             seccomp_action,
             exit_event,
             hypervisor_type,
@@ -536,6 +592,219 @@ struct VmMigrationConfig {
     memory_manager_data: MemoryManagerSnapshotData,
 }
 
+/// Stream a migration goes through, as described by the migration URL:
+/// `unix:<path>`, `vsock:<cid>:<port>` (`vsock:<port>` when receiving) or
+/// `fd:<fd>` for a file descriptor already connected to the other end, passed
+/// along the API request.
+enum SocketStream {
+    Unix(UnixStream),
+    // vsock sockets and file descriptors other than UNIX sockets, which are
+    // only read from and written to.
+    Other(File),
+}
+
+impl SocketStream {
+    fn connect(url: &str, fd: Option<File>) -> result::Result<Self, MigratableError> {
+        if let Some(path) = url.strip_prefix("unix:") {
+            UnixStream::connect(path)
+                .map(SocketStream::Unix)
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!("Error connecting to UNIX socket: {}", e))
+                })
+        } else if let Some(address) = url.strip_prefix("vsock:") {
+            let (cid, port) = address
+                .split_once(':')
+                .and_then(|(cid, port)| Some((cid.parse().ok()?, port.parse().ok()?)))
+                .ok_or_else(|| {
+                    MigratableError::MigrateSend(anyhow!("Invalid vsock address: {}", address))
+                })?;
+            let socket = Self::vsock_socket().map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error creating vsock socket: {}", e))
+            })?;
+            let addr = Self::vsock_addr(cid, port);
+            // SAFETY: FFI call with a valid socket and address
+            let ret = unsafe {
+                libc::connect(
+                    socket.as_raw_fd(),
+                    &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                    std::mem::size_of_val(&addr) as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Error connecting to vsock socket: {}",
+                    io::Error::last_os_error()
+                )));
+            }
+            Ok(SocketStream::Other(socket))
+        } else if url.starts_with("fd:") {
+            fd.map(Self::from_file).ok_or_else(|| {
+                MigratableError::MigrateSend(anyhow!(
+                    "No file descriptor passed along the request for {}",
+                    url
+                ))
+            })
+        } else {
+            Err(MigratableError::MigrateSend(anyhow!(
+                "Unsupported migration URL: {}",
+                url
+            )))
+        }
+    }
+
+    fn accept(url: &str, fd: Option<File>) -> result::Result<Self, MigratableError> {
+        if let Some(path) = url.strip_prefix("unix:") {
+            let listener = UnixListener::bind(path).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error binding to UNIX socket: {}", e))
+            })?;
+            let (socket, _addr) = listener.accept().map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error accepting on UNIX socket: {}", e))
+            })?;
+            std::fs::remove_file(path).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error unlinking UNIX socket: {}", e))
+            })?;
+            Ok(SocketStream::Unix(socket))
+        } else if let Some(port) = url.strip_prefix("vsock:") {
+            let port = port.parse().map_err(|_| {
+                MigratableError::MigrateReceive(anyhow!("Invalid vsock port: {}", port))
+            })?;
+            let listener = Self::vsock_socket().map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error creating vsock socket: {}", e))
+            })?;
+            let addr = Self::vsock_addr(libc::VMADDR_CID_ANY, port);
+            // SAFETY: FFI calls with a valid socket and address
+            let fd = unsafe {
+                if libc::bind(
+                    listener.as_raw_fd(),
+                    &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                    std::mem::size_of_val(&addr) as libc::socklen_t,
+                ) < 0
+                    || libc::listen(listener.as_raw_fd(), 1) < 0
+                {
+                    -1
+                } else {
+                    libc::accept4(
+                        listener.as_raw_fd(),
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        libc::SOCK_CLOEXEC,
+                    )
+                }
+            };
+            if fd < 0 {
+                return Err(MigratableError::MigrateReceive(anyhow!(
+                    "Error accepting on vsock socket: {}",
+                    io::Error::last_os_error()
+                )));
+            }
+            // SAFETY: fd is a newly accepted connection owned by nobody else
+            Ok(SocketStream::Other(unsafe { File::from_raw_fd(fd) }))
+        } else if url.starts_with("fd:") {
+            fd.map(Self::from_file).ok_or_else(|| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "No file descriptor passed along the request for {}",
+                    url
+                ))
+            })
+        } else {
+            Err(MigratableError::MigrateReceive(anyhow!(
+                "Unsupported migration URL: {}",
+                url
+            )))
+        }
+    }
+
+    // Only a file descriptor received along the API request is used, so that
+    // none of the VMM's own file descriptors can be taken over by a request.
+    fn from_file(file: File) -> Self {
+        let mut domain: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&domain) as libc::socklen_t;
+        // SAFETY: FFI call with valid arguments
+        let ret = unsafe {
+            libc::getsockopt(
+                file.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_DOMAIN,
+                &mut domain as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret == 0 && domain == libc::AF_UNIX {
+            SocketStream::Unix(UnixStream::from(OwnedFd::from(file)))
+        } else {
+            SocketStream::Other(file)
+        }
+    }
+
+    fn vsock_socket() -> io::Result<File> {
+        // SAFETY: FFI call with valid arguments
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a newly created socket owned by nobody else
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+        // SAFETY: sockaddr_vm is a plain C structure, for which zero is valid
+        let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = cid;
+        addr.svm_port = port;
+        addr
+    }
+}
+
+impl Read for SocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SocketStream::Unix(stream) => stream.read(buf),
+            SocketStream::Other(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SocketStream::Unix(stream) => stream.write(buf),
+            SocketStream::Other(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SocketStream::Unix(stream) => stream.flush(),
+            SocketStream::Other(stream) => stream.flush(),
+        }
+    }
+}
+
+impl ReadVolatile for SocketStream {
+    fn read_volatile<B: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<B>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        match self {
+            SocketStream::Unix(stream) => stream.read_volatile(buf),
+            SocketStream::Other(stream) => stream.read_volatile(buf),
+        }
+    }
+}
+
+impl WriteVolatile for SocketStream {
+    fn write_volatile<B: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<B>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        match self {
+            SocketStream::Unix(stream) => stream.write_volatile(buf),
+            SocketStream::Other(stream) => stream.write_volatile(buf),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VmmVersionInfo {
     pub build_version: String,
@@ -562,6 +831,10 @@ pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    panic_evt: EventFd,
+    hibernate_evt: EventFd,
+    suspend_evt: EventFd,
+    host_cpus_timer: TimerFd,
     api_evt: EventFd,
     #[cfg(feature = "guest_debug")]
     debug_evt: EventFd,
@@ -578,6 +851,30 @@ pub struct Vmm {
     original_termios_opt: Arc<Mutex<Option<termios>>>,
     console_resize_pipe: Option<Arc<File>>,
     console_info: Option<ConsoleInfo>,
+    // Snapshot the VM was last restored from or snapshotted to
+    last_snapshot: Option<PathBuf>,
+    // Security labels applied to the VMM thread for the VM
+    security_label: Option<SecurityLabelConfig>,
+    // VMs added in multi-VM mode, by identifier
+    vms: BTreeMap<String, VmThread>,
+    // API channels of these VMs, only set in multi-VM mode
+    vm_api_channels: Option<VmApiChannels>,
+    // Whether the seccomp filter of the VMM thread allows XDP network devices
+    xdp: bool,
+    // Whether the VM can use the terminal of the process, which in multi-VM
+    // mode is left to the VM of the VMM
+    tty: bool,
+    // Whether the VM can be placed in cgroups, which move all the threads of
+    // the process and hence cannot be used in multi-VM mode
+    cgroup: bool,
+}
+
+// A VM added in multi-VM mode. It is managed by a VMM of its own, without
+// signal handler, running on a dedicated thread spawned from the VMM thread,
+// whose seccomp filter it inherits.
+struct VmThread {
+    exit_evt: EventFd,
+    thread: thread::JoinHandle<Result<()>>,
 }
 
 impl Vmm {
@@ -687,6 +984,21 @@ impl Vmm {
     ) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let panic_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let hibernate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let suspend_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let host_cpus_timer = TimerFd::new().map_err(|e| Error::TimerFdCreate(e.into()))?;
+        // The timer might be re-armed after it expired but before its event
+        // got handled, make sure reading it does not block then.
+        // SAFETY: FFI calls with a valid file descriptor.
+        let ret = unsafe {
+            let fd = host_cpus_timer.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+        };
+        if ret < 0 {
+            return Err(Error::TimerFdCreate(io::Error::last_os_error()));
+        }
         let activate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         epoll
@@ -697,6 +1009,22 @@ impl Vmm {
             .add_event(&reset_evt, EpollDispatch::Reset)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&panic_evt, EpollDispatch::GuestPanic)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&hibernate_evt, EpollDispatch::Hibernate)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&suspend_evt, EpollDispatch::Suspend)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&host_cpus_timer, EpollDispatch::HostCpus)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&activate_evt, EpollDispatch::ActivateVirtioDevices)
             .map_err(Error::Epoll)?;
@@ -714,6 +1042,10 @@ impl Vmm {
             epoll,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
+            host_cpus_timer,
             api_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
@@ -730,9 +1062,61 @@ impl Vmm {
             original_termios_opt: Arc::new(Mutex::new(None)),
             console_resize_pipe: None,
             console_info: None,
+            last_snapshot: None,
+            security_label: None,
+            vms: BTreeMap::new(),
+            vm_api_channels: None,
+            xdp: false,
+            tty: true,
+            cgroup: true,
         })
     }
 
+    // The seccomp filter of the VMM thread is built when the VMM is started,
+    // XDP network devices can only be created if it allowed them then.
+    fn check_xdp_allowed<'a>(
+        &self,
+        mut net: impl Iterator<Item = &'a NetConfig>,
+    ) -> result::Result<(), VmError> {
+        if !self.xdp
+            && !matches!(
+                self.seccomp_action,
+                SeccompAction::Allow | SeccompAction::Log
+            )
+            && net.any(|net| net.xdp.is_some())
+        {
+            return Err(VmError::XdpNotAllowed);
+        }
+
+        Ok(())
+    }
+
+    // The terminal settings and the SIGWINCH handler belong to the process,
+    // only one VM can use its terminal.
+    fn check_tty_allowed(&self, config: &VmConfig) -> result::Result<(), VmError> {
+        #[cfg(target_arch = "x86_64")]
+        let debug_console_tty = config.debug_console.mode == ConsoleOutputMode::Tty;
+        #[cfg(not(target_arch = "x86_64"))]
+        let debug_console_tty = false;
+        if !self.tty
+            && (config.console.mode == ConsoleOutputMode::Tty
+                || config.serial.mode == ConsoleOutputMode::Tty
+                || debug_console_tty)
+        {
+            return Err(VmError::TtyNotAllowed);
+        }
+
+        Ok(())
+    }
+
+    fn check_cgroup_allowed(&self, config: &VmConfig) -> result::Result<(), VmError> {
+        if !self.cgroup && config.cgroup.is_some() {
+            return Err(VmError::CgroupNotAllowed);
+        }
+
+        Ok(())
+    }
+
     fn vm_receive_config<T>(
         &mut self,
         req: &Request,
@@ -760,8 +1144,28 @@ impl Vmm {
             &vm_migration_config.common_cpuid,
         )?;
 
+        self.check_xdp_allowed(
+            vm_migration_config
+                .vm_config
+                .lock()
+                .unwrap()
+                .net
+                .iter()
+                .flatten(),
+        )
+        .map_err(|e| MigratableError::MigrateReceive(anyhow!("{}", e)))?;
+        self.check_tty_allowed(&vm_migration_config.vm_config.lock().unwrap())
+            .map_err(|e| MigratableError::MigrateReceive(anyhow!("{}", e)))?;
+        self.check_cgroup_allowed(&vm_migration_config.vm_config.lock().unwrap())
+            .map_err(|e| MigratableError::MigrateReceive(anyhow!("{}", e)))?;
+        device_backend::check_backends(&vm_migration_config.vm_config.lock().unwrap())
+            .map_err(|e| MigratableError::MigrateReceive(anyhow!("{}", e)))?;
+
         let config = vm_migration_config.vm_config.clone();
         self.vm_config = Some(vm_migration_config.vm_config);
+        self.apply_security_labels().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error applying security labels: {:?}", e))
+        })?;
         self.console_info = Some(pre_create_console_devices(self).map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error creating console devices: {:?}", e))
         })?);
@@ -779,12 +1183,25 @@ impl Vmm {
             })?;
         }
 
+        // The NUMA placement of the source host is not migrated, it must be
+        // resolved before allocating the guest memory.
+        if config.lock().unwrap().numa_placement {
+            numa_placement::apply(&mut config.lock().unwrap()).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Error placing the guest NUMA nodes: {:?}",
+                    e
+                ))
+            })?;
+        }
+
         let vm = Vm::create_hypervisor_vm(
             &self.hypervisor,
             #[cfg(feature = "tdx")]
             false,
             #[cfg(feature = "sev_snp")]
             false,
+            #[cfg(target_arch = "x86_64")]
+            &config.lock().unwrap().memory,
         )
         .map_err(|e| {
             MigratableError::MigrateReceive(anyhow!(
@@ -805,6 +1222,7 @@ impl Vmm {
             false,
             Some(&vm_migration_config.memory_manager_data),
             existing_memory_files,
+            &[],
             #[cfg(target_arch = "x86_64")]
             None,
         )
@@ -825,6 +1243,7 @@ impl Vmm {
         req: &Request,
         socket: &mut T,
         mm: Arc<Mutex<MemoryManager>>,
+        resync_clock: bool,
     ) -> std::result::Result<(), MigratableError>
     where
         T: Read + Write,
@@ -845,6 +1264,15 @@ impl Vmm {
         let reset_evt = self.reset_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning reset EventFd: {}", e))
         })?;
+        let panic_evt = self.panic_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning panic EventFd: {}", e))
+        })?;
+        let hibernate_evt = self.hibernate_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning hibernate EventFd: {}", e))
+        })?;
+        let suspend_evt = self.suspend_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning suspend EventFd: {}", e))
+        })?;
         #[cfg(feature = "guest_debug")]
         let debug_evt = self.vm_debug_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning debug EventFd: {}", e))
@@ -861,6 +1289,9 @@ impl Vmm {
             hypervisor_vm,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
             &self.seccomp_action,
@@ -876,12 +1307,21 @@ impl Vmm {
             MigratableError::MigrateReceive(anyhow!("Error creating VM from snapshot: {:?}", e))
         })?;
 
+        // Must be done before restoring, as the clock is set on resume.
+        if resync_clock {
+            vm.resync_clock().map_err(|e| {
+                Response::error().write_to(socket).ok();
+                MigratableError::MigrateReceive(anyhow!("Failed resyncing the clock: {}", e))
+            })?;
+        }
+
         // Create VM
         vm.restore().map_err(|e| {
             Response::error().write_to(socket).ok();
             MigratableError::MigrateReceive(anyhow!("Failed restoring the Vm: {}", e))
         })?;
         self.vm = Some(vm);
+        self.arm_host_cpus_timer();
 
         Response::ok().write_to(socket)?;
 
@@ -910,14 +1350,6 @@ impl Vmm {
         Ok(())
     }
 
-    fn socket_url_to_path(url: &str) -> result::Result<PathBuf, MigratableError> {
-        url.strip_prefix("unix:")
-            .ok_or_else(|| {
-                MigratableError::MigrateSend(anyhow!("Could not extract path from URL: {}", url))
-            })
-            .map(|s| s.into())
-    }
-
     // Returns true if there were dirty pages to send
     fn vm_maybe_send_dirty_pages<T>(
         vm: &mut Vm,
@@ -951,12 +1383,12 @@ impl Vmm {
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))] hypervisor: Arc<
             dyn hypervisor::Hypervisor,
         >,
-        send_data_migration: VmSendMigrationData,
+        mut send_data_migration: VmSendMigrationData,
     ) -> result::Result<(), MigratableError> {
-        let path = Self::socket_url_to_path(&send_data_migration.destination_url)?;
-        let mut socket = UnixStream::connect(path).map_err(|e| {
-            MigratableError::MigrateSend(anyhow!("Error connecting to UNIX socket: {}", e))
-        })?;
+        let mut socket = SocketStream::connect(
+            &send_data_migration.destination_url,
+            send_data_migration.fd.take(),
+        )?;
 
         // Start the migration
         Request::start().write_to(&mut socket)?;
@@ -967,6 +1399,22 @@ impl Vmm {
 
         // Send config
         let vm_config = vm.get_config();
+        #[cfg(feature = "sev_snp")]
+        if vm_config.lock().unwrap().is_sev_snp_enabled() {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Live Migration is not supported when SEV-SNP is enabled"
+            )));
+        }
+        if vm_config.lock().unwrap().memory.guest_memfd {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Live Migration is not supported with guest_memfd private memory"
+            )));
+        }
+        if vm_config.lock().unwrap().is_vmbus_relay_enabled() {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Live Migration is not supported with the VMBus relay"
+            )));
+        }
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let common_cpuid = {
             #[cfg(feature = "tdx")]
@@ -996,11 +1444,21 @@ impl Vmm {
         };
 
         if send_data_migration.local {
-            vm.send_memory_fds(&mut socket)?;
+            match &mut socket {
+                SocketStream::Unix(unix_socket) => vm.send_memory_fds(unix_socket)?,
+                SocketStream::Other(_) => {
+                    return Err(MigratableError::MigrateSend(anyhow!(
+                        "Local migration requires a UNIX socket"
+                    )))
+                }
+            }
         }
 
+        // The NUMA placement is resolved again on the destination host.
         let vm_migration_config = VmMigrationConfig {
-            vm_config,
+            vm_config: Arc::new(Mutex::new(numa_placement::strip(
+                &vm_config.lock().unwrap(),
+            ))),
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             common_cpuid,
             memory_manager_data: vm.memory_manager_data(),
@@ -1171,6 +1629,45 @@ impl Vmm {
                         self.reset_evt.read().map_err(Error::EventFdRead)?;
                         self.vm_reboot().map_err(Error::VmReboot)?;
                     }
+                    EpollDispatch::GuestPanic => {
+                        info!("VM panic event");
+                        // Consume the event.
+                        self.panic_evt.read().map_err(Error::EventFdRead)?;
+                        self.vm_handle_panic();
+                    }
+                    EpollDispatch::Hibernate => {
+                        info!("VM hibernation event");
+                        // Consume the event.
+                        self.hibernate_evt.read().map_err(Error::EventFdRead)?;
+                        if let Err(e) = self.vm_hibernated() {
+                            error!("Failed powering off the hibernated VM: {}", e);
+                        }
+                    }
+                    EpollDispatch::Suspend => {
+                        info!("VM suspend event");
+                        // Consume the event.
+                        self.suspend_evt.read().map_err(Error::EventFdRead)?;
+                        if let Err(e) = self.vm_suspended() {
+                            error!("Failed pausing the suspended VM: {}", e);
+                        }
+                    }
+                    EpollDispatch::HostCpus => {
+                        // Consume the event, unless the timer got re-armed
+                        // since it expired.
+                        if let Err(e) = self.host_cpus_timer.wait() {
+                            if e.errno() != libc::EAGAIN {
+                                return Err(Error::TimerFdRead(e.into()));
+                            }
+                            continue;
+                        }
+                        if let Some(ref vm) = self.vm {
+                            if let Err(e) = vm.update_vcpus_affinity() {
+                                warn!("Failed updating the vCPUs affinity: {}", e);
+                            }
+                        } else {
+                            self.arm_host_cpus_timer();
+                        }
+                    }
                     EpollDispatch::ActivateVirtioDevices => {
                         if let Some(ref vm) = self.vm {
                             let count = self.activate_evt.read().map_err(Error::EventFdRead)?;
@@ -1231,6 +1728,146 @@ impl Vmm {
 
         Ok(())
     }
+
+    // Waits for the VMM thread of a VM added in multi-VM mode to exit.
+    fn join_vm_thread(id: &str, vm_thread: VmThread) {
+        match vm_thread.thread.join() {
+            Ok(Ok(())) => info!("VM {} exited", id),
+            Ok(Err(e)) => error!("Error running VM {}: {}", id, e),
+            Err(_) => error!("VMM thread of VM {} panicked", id),
+        }
+    }
+
+    // Forgets about the VMs added in multi-VM mode whose VMM already exited,
+    // after their guest powered off or failed.
+    fn reap_vm_threads(&mut self) {
+        let ids: Vec<String> = self
+            .vms
+            .iter()
+            .filter(|(_, vm_thread)| vm_thread.thread.is_finished())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            if let Some(vm_thread) = self.vms.remove(&id) {
+                Self::join_vm_thread(&id, vm_thread);
+            }
+        }
+    }
+
+    fn guest_agent(&self) -> Option<GuestAgent> {
+        GuestAgent::new(&self.vm_config.as_ref()?.lock().unwrap())
+    }
+
+    // Guest agent used to freeze the guest filesystems while the VM is
+    // paused, so that the snapshots taken meanwhile are consistent.
+    fn fs_freeze_agent(&self) -> Option<GuestAgent> {
+        let fs_freeze = self
+            .vm_config
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .guest_agent
+            .as_ref()?
+            .fs_freeze;
+        fs_freeze.then(|| self.guest_agent()).flatten()
+    }
+
+    // Label the VMM thread before any guest resource gets created, so that
+    // the console devices, memory files, sockets, TAP devices and threads
+    // created for the VM all inherit the labels. The thread keeps its labels
+    // across VM reboots, and may not be allowed to transition again once
+    // confined.
+    fn apply_security_labels(&mut self) -> result::Result<(), VmError> {
+        let Some(security_label) = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().security_label.clone())
+        else {
+            return Ok(());
+        };
+        if self.security_label.as_ref() == Some(&security_label) {
+            return Ok(());
+        }
+
+        security_label::apply_vmm_labels(&security_label).map_err(VmError::SecurityLabel)?;
+        self.security_label = Some(security_label);
+
+        Ok(())
+    }
+
+    // Check periodically that the host CPUs the vCPUs are pinned onto are
+    // still available, as long as a VM pins some of its vCPUs.
+    fn arm_host_cpus_timer(&mut self) {
+        let pinned = self.vm.is_some()
+            && self
+                .vm_config
+                .as_ref()
+                .is_some_and(|config| config.lock().unwrap().cpus.affinity.is_some());
+        let r = if pinned {
+            self.host_cpus_timer
+                .reset(HOST_CPUS_CHECK_INTERVAL, Some(HOST_CPUS_CHECK_INTERVAL))
+        } else {
+            self.host_cpus_timer.clear()
+        };
+        if let Err(e) = r {
+            warn!("Failed arming the host CPUs timer: {}", e);
+        }
+    }
+
+    // Apply the action configured for guest panics. Failures are only
+    // logged as the guest is already in a bad state.
+    fn vm_handle_panic(&mut self) {
+        let Some(panic_config) = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().panic.clone())
+        else {
+            return;
+        };
+
+        let r = match panic_config.action {
+            PanicAction::None => Ok(()),
+            PanicAction::Pause => self.vm_pause(),
+            #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+            PanicAction::Coredump => {
+                let path = panic_config.coredump_file.unwrap_or_default();
+                self.vm_pause()
+                    .and_then(|_| self.vm_coredump(&format!("file://{}", path.display())))
+            }
+            #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+            PanicAction::Coredump => {
+                warn!("Coredump not supported, pausing the VM instead");
+                self.vm_pause()
+            }
+            PanicAction::Restart => self.vm_reboot(),
+            PanicAction::Shutdown => self.vm_shutdown(),
+        };
+
+        if let Err(e) = r {
+            error!(
+                "Failed handling guest panic with action {:?}: {}",
+                panic_config.action, e
+            );
+        }
+    }
+
+    // The guest saved its hibernation image and expects to be powered off.
+    // Booting the VM again lets the guest resume from that image.
+    fn vm_hibernated(&mut self) -> result::Result<(), VmError> {
+        self.vm_shutdown()?;
+        event!("vm", "hibernated");
+        Ok(())
+    }
+
+    fn vm_suspended(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.suspend().map_err(VmError::Pause)?;
+            event!("vm", "suspended");
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
 }
 
 fn apply_landlock(vm_config: Arc<Mutex<VmConfig>>) -> result::Result<(), LandlockError> {
@@ -1243,6 +1880,11 @@ impl RequestHandler for Vmm {
         // We only store the passed VM config.
         // The VM will be created when being asked to boot it.
         if self.vm_config.is_none() {
+            self.check_xdp_allowed(config.lock().unwrap().net.iter().flatten())?;
+            self.check_tty_allowed(&config.lock().unwrap())?;
+            self.check_cgroup_allowed(&config.lock().unwrap())?;
+            device_backend::check_backends(&config.lock().unwrap())
+                .map_err(VmError::DeviceBackend)?;
             self.vm_config = Some(config);
             self.console_info =
                 Some(pre_create_console_devices(self).map_err(VmError::CreateConsoleDevices)?);
@@ -1275,6 +1917,8 @@ impl RequestHandler for Vmm {
                 return Err(VmError::VmMissingConfig);
             };
 
+            self.apply_security_labels()?;
+
             // console_info is set to None in vm_shutdown. re-populate here if empty
             if self.console_info.is_none() {
                 self.console_info =
@@ -1285,6 +1929,15 @@ impl RequestHandler for Vmm {
             if self.vm.is_none() {
                 let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
                 let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+                let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+                let hibernate_evt = self
+                    .hibernate_evt
+                    .try_clone()
+                    .map_err(VmError::EventFdClone)?;
+                let suspend_evt = self
+                    .suspend_evt
+                    .try_clone()
+                    .map_err(VmError::EventFdClone)?;
                 #[cfg(feature = "guest_debug")]
                 let vm_debug_evt = self
                     .vm_debug_evt
@@ -1300,6 +1953,9 @@ impl RequestHandler for Vmm {
                         Arc::clone(vm_config),
                         exit_evt,
                         reset_evt,
+                        panic_evt,
+                        hibernate_evt,
+                        suspend_evt,
                         #[cfg(feature = "guest_debug")]
                         vm_debug_evt,
                         &self.seccomp_action,
@@ -1311,9 +1967,11 @@ impl RequestHandler for Vmm {
                         None,
                         None,
                         None,
+                        MemoryRestoreMode::Copy,
                     )?;
 
                     self.vm = Some(vm);
+                    self.arm_host_cpus_timer();
                 }
             }
 
@@ -1332,7 +1990,15 @@ impl RequestHandler for Vmm {
     }
 
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
+        let agent = self.fs_freeze_agent();
         if let Some(ref mut vm) = self.vm {
+            if let Some(agent) = agent.filter(|_| matches!(vm.get_state(), Ok(VmState::Running))) {
+                // Pausing must not depend on the cooperation of the guest.
+                match agent.fs_freeze() {
+                    Ok(frozen) => info!("Froze {} guest filesystems", frozen),
+                    Err(e) => warn!("Failed freezing guest filesystems: {}", e),
+                }
+            }
             vm.pause().map_err(VmError::Pause)
         } else {
             Err(VmError::VmNotRunning)
@@ -1340,8 +2006,18 @@ impl RequestHandler for Vmm {
     }
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
+        let agent = self.fs_freeze_agent();
         if let Some(ref mut vm) = self.vm {
-            vm.resume().map_err(VmError::Resume)
+            vm.resume().map_err(VmError::Resume)?;
+            // This also covers the VMs restored from a snapshot taken while
+            // their filesystems were frozen.
+            if let Some(agent) = agent {
+                match agent.fs_thaw() {
+                    Ok(thawed) => info!("Thawed {} guest filesystems", thawed),
+                    Err(e) => warn!("Failed thawing guest filesystems: {}", e),
+                }
+            }
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -1356,7 +2032,18 @@ impl RequestHandler for Vmm {
                 .and_then(|snapshot| {
                     vm.send(&snapshot, destination_url)
                         .map_err(VmError::SnapshotSend)
-                })
+                })?;
+
+            let destination = url_to_path(destination_url).map_err(VmError::SnapshotSend)?;
+            let metadata = SnapshotMetadata::new(
+                &destination,
+                self.last_snapshot.as_deref(),
+                &self.vm_config.as_ref().unwrap().lock().unwrap(),
+            );
+            send_snapshot_metadata(destination_url, &metadata).map_err(VmError::SnapshotSend)?;
+            self.last_snapshot = Some(destination);
+
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -1372,7 +2059,11 @@ impl RequestHandler for Vmm {
             return Err(VmError::InvalidRestoreSourceUrl);
         }
         // Safe to unwrap as we checked it was Some(&str).
-        let source_url = source_url.unwrap();
+        let source_url = match &restore_cfg.id {
+            Some(id) => snapshot_url(source_url.unwrap(), id).map_err(VmError::Restore)?,
+            None => source_url.unwrap().to_string(),
+        };
+        let source_url = source_url.as_str();
 
         let vm_config = Arc::new(Mutex::new(
             recv_vm_config(source_url).map_err(VmError::Restore)?,
@@ -1380,6 +2071,11 @@ impl RequestHandler for Vmm {
         restore_cfg
             .validate(&vm_config.lock().unwrap().clone())
             .map_err(VmError::ConfigValidation)?;
+        self.check_xdp_allowed(vm_config.lock().unwrap().net.iter().flatten())?;
+        self.check_tty_allowed(&vm_config.lock().unwrap())?;
+        self.check_cgroup_allowed(&vm_config.lock().unwrap())?;
+        device_backend::check_backends(&vm_config.lock().unwrap())
+            .map_err(VmError::DeviceBackend)?;
 
         // Update VM's net configurations with new fds received for restore operation
         if let (Some(restored_nets), Some(vm_net_configs)) =
@@ -1404,6 +2100,7 @@ impl RequestHandler for Vmm {
             .map_err(VmError::Restore)?;
 
         self.vm_config = Some(Arc::clone(&vm_config));
+        self.apply_security_labels()?;
 
         // console_info is set to None in vm_snapshot. re-populate here if empty
         if self.console_info.is_none() {
@@ -1413,6 +2110,15 @@ impl RequestHandler for Vmm {
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let hibernate_evt = self
+            .hibernate_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let suspend_evt = self
+            .suspend_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
         #[cfg(feature = "guest_debug")]
         let debug_evt = self
             .vm_debug_evt
@@ -1423,10 +2129,13 @@ impl RequestHandler for Vmm {
             .try_clone()
             .map_err(VmError::EventFdClone)?;
 
-        let vm = Vm::new(
+        let mut vm = Vm::new(
             vm_config,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
             &self.seccomp_action,
@@ -1438,8 +2147,15 @@ impl RequestHandler for Vmm {
             Some(snapshot),
             Some(source_url),
             Some(restore_cfg.prefault),
+            restore_cfg.memory_restore_mode(),
         )?;
+        // Must be done before restoring, as the clock is set on resume.
+        if restore_cfg.resync_clock {
+            vm.resync_clock()?;
+        }
         self.vm = Some(vm);
+        self.arm_host_cpus_timer();
+        self.last_snapshot = url_to_path(source_url).ok();
 
         if self
             .vm_config
@@ -1505,6 +2221,15 @@ impl RequestHandler for Vmm {
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let hibernate_evt = self
+            .hibernate_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let suspend_evt = self
+            .suspend_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
         #[cfg(feature = "guest_debug")]
         let debug_evt = self
             .vm_debug_evt
@@ -1530,6 +2255,9 @@ impl RequestHandler for Vmm {
             config,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
             &self.seccomp_action,
@@ -1541,12 +2269,14 @@ impl RequestHandler for Vmm {
             None,
             None,
             None,
+            MemoryRestoreMode::Copy,
         )?;
 
         // And we boot it
         vm.boot()?;
 
         self.vm = Some(vm);
+        self.arm_host_cpus_timer();
 
         event!("vm", "rebooted");
 
@@ -1570,11 +2300,25 @@ impl RequestHandler for Vmm {
 
                 let device_tree = self.vm.as_ref().map(|vm| vm.device_tree());
 
+                let guest_network_interfaces = if state == VmState::Running {
+                    self.guest_agent()
+                        .and_then(|agent| match agent.network_interfaces() {
+                            Ok(interfaces) => Some(interfaces),
+                            Err(e) => {
+                                debug!("Failed querying guest network interfaces: {}", e);
+                                None
+                            }
+                        })
+                } else {
+                    None
+                };
+
                 Ok(VmInfoResponse {
                     config,
                     state,
                     memory_actual_size,
                     device_tree,
+                    guest_network_interfaces,
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -1606,6 +2350,7 @@ impl RequestHandler for Vmm {
         }
 
         self.vm_config = None;
+        self.last_snapshot = None;
 
         event!("vm", "deleted");
 
@@ -1613,21 +2358,154 @@ impl RequestHandler for Vmm {
     }
 
     fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
+        // Shut all the VMs added in multi-VM mode down at once, before
+        // waiting for them.
+        let vms = std::mem::take(&mut self.vms);
+        for (id, vm_thread) in vms.iter() {
+            if let Err(e) = vm_thread.exit_evt.write(1) {
+                error!("Error shutting down VM {}: {}", id, e);
+            }
+        }
+        for (id, vm_thread) in vms {
+            Self::join_vm_thread(&id, vm_thread);
+        }
+
         self.vm_delete()?;
         event!("vmm", "shutdown");
         Ok(())
     }
 
+    fn vmm_add_vm(&mut self, id: String) -> result::Result<(), VmError> {
+        let Some(vm_api_channels) = self.vm_api_channels.clone() else {
+            return Err(VmError::MultiVmDisabled);
+        };
+        // The identifier is part of the path of the requests targeting the VM.
+        if id.is_empty() || id.contains('/') {
+            return Err(VmError::InvalidVmId(id));
+        }
+        self.reap_vm_threads();
+        if self.vms.contains_key(&id) {
+            return Err(VmError::VmIdInUse(id));
+        }
+
+        let api_evt = EventFd::new(EFD_NONBLOCK).map_err(VmError::CreateVmInstance)?;
+        let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(VmError::CreateVmInstance)?;
+        let (api_sender, api_receiver) = channel();
+
+        let vmm_version = self.version.clone();
+        let vmm_api_evt = api_evt.try_clone().map_err(VmError::EventFdClone)?;
+        #[cfg(feature = "guest_debug")]
+        let vmm_debug_evt = EventFd::new(EFD_NONBLOCK).map_err(VmError::CreateVmInstance)?;
+        #[cfg(feature = "guest_debug")]
+        let vmm_vm_debug_evt = EventFd::new(EFD_NONBLOCK).map_err(VmError::CreateVmInstance)?;
+        let vmm_seccomp_action = self.seccomp_action.clone();
+        let vmm_hypervisor = self.hypervisor.clone();
+        let vmm_exit_evt = exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let xdp = self.xdp;
+        let thread_id = id.clone();
+        let thread_vm_api_channels = vm_api_channels.clone();
+
+        vm_api_channels
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (api_evt, api_sender));
+
+        let thread = thread::Builder::new()
+            .name(format!("vmm-{id}"))
+            .spawn(move || {
+                let r = Vmm::new(
+                    vmm_version,
+                    vmm_api_evt,
+                    #[cfg(feature = "guest_debug")]
+                    vmm_debug_evt,
+                    #[cfg(feature = "guest_debug")]
+                    vmm_vm_debug_evt,
+                    vmm_seccomp_action,
+                    vmm_hypervisor,
+                    vmm_exit_evt,
+                )
+                .and_then(|mut vmm| {
+                    vmm.xdp = xdp;
+                    vmm.tty = false;
+                    vmm.cgroup = false;
+                    let r = vmm.control_loop(
+                        Rc::new(api_receiver),
+                        // Only the VMM thread serves the GDB requests.
+                        #[cfg(feature = "guest_debug")]
+                        Rc::new(channel().1),
+                    );
+                    // The process keeps running, make sure the VM is gone.
+                    if r.is_err() {
+                        if let Err(e) = vmm.vmm_shutdown() {
+                            error!("Error shutting down VM {}: {}", thread_id, e);
+                        }
+                    }
+                    r
+                });
+
+                thread_vm_api_channels.lock().unwrap().remove(&thread_id);
+                event!("vmm", "vm-removed", "id", &thread_id);
+
+                r
+            });
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                vm_api_channels.lock().unwrap().remove(&id);
+                return Err(VmError::CreateVmInstance(e));
+            }
+        };
+        self.vms.insert(id.clone(), VmThread { exit_evt, thread });
+
+        event!("vmm", "vm-added", "id", &id);
+
+        Ok(())
+    }
+
+    fn vmm_remove_vm(&mut self, id: String) -> result::Result<(), VmError> {
+        self.reap_vm_threads();
+        let vm_thread = self
+            .vms
+            .remove(&id)
+            .ok_or_else(|| VmError::UnknownVm(id.clone()))?;
+        vm_thread.exit_evt.write(1).map_err(VmError::EventfdError)?;
+        Self::join_vm_thread(&id, vm_thread);
+
+        Ok(())
+    }
+
+    fn vmm_list_vms(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if self.vm_api_channels.is_none() {
+            return Err(VmError::MultiVmDisabled);
+        }
+
+        let ids: Vec<&String> = self
+            .vms
+            .iter()
+            .filter(|(_, vm_thread)| !vm_thread.thread.is_finished())
+            .map(|(id, _)| id)
+            .collect();
+        serde_json::to_vec(&ids)
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
     fn vm_resize(
         &mut self,
         desired_vcpus: Option<u8>,
+        desired_max_vcpus: Option<u8>,
         desired_ram: Option<u64>,
         desired_balloon: Option<u64>,
     ) -> result::Result<(), VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
         if let Some(ref mut vm) = self.vm {
-            if let Err(e) = vm.resize(desired_vcpus, desired_ram, desired_balloon) {
+            if let Err(e) = vm.resize(
+                desired_vcpus,
+                desired_max_vcpus,
+                desired_ram,
+                desired_balloon,
+            ) {
                 error!("Error when resizing VM: {:?}", e);
                 Err(e)
             } else {
@@ -1635,6 +2513,9 @@ impl RequestHandler for Vmm {
             }
         } else {
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            if let Some(desired_max_vcpus) = desired_max_vcpus {
+                config.cpus.max_vcpus = desired_max_vcpus;
+            }
             if let Some(desired_vcpus) = desired_vcpus {
                 config.cpus.boot_vcpus = desired_vcpus;
             }
@@ -1684,6 +2565,20 @@ impl RequestHandler for Vmm {
     ) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
+        // A vhost-vdpa device gets added with all the virtqueues it exposes.
+        if virtio_devices::vdpa::is_vdpa_device_path(&device_cfg.path) {
+            let num_queues = virtio_devices::vdpa::vdpa_num_queues(&device_cfg.path)
+                .map_err(VmError::VdpaNumQueues)?;
+            return self.vm_add_vdpa(VdpaConfig {
+                path: device_cfg.path,
+                num_queues,
+                iommu: device_cfg.iommu,
+                id: device_cfg.id,
+                pci_segment: device_cfg.pci_segment,
+                pci_bdf: device_cfg.pci_bdf,
+            });
+        }
+
         {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
@@ -1764,6 +2659,7 @@ impl RequestHandler for Vmm {
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
             add_to_config(&mut config.disks, disk_cfg.clone());
             config.validate().map_err(VmError::ConfigValidation)?;
+            device_backend::check_backends(&config).map_err(VmError::DeviceBackend)?;
         }
 
         if let Some(ref mut vm) = self.vm {
@@ -1790,6 +2686,7 @@ impl RequestHandler for Vmm {
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
             add_to_config(&mut config.fs, fs_cfg.clone());
             config.validate().map_err(VmError::ConfigValidation)?;
+            device_backend::check_backends(&config).map_err(VmError::DeviceBackend)?;
         }
 
         if let Some(ref mut vm) = self.vm {
@@ -1836,12 +2733,14 @@ impl RequestHandler for Vmm {
 
     fn vm_add_net(&mut self, net_cfg: NetConfig) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+        self.check_xdp_allowed(std::iter::once(&net_cfg))?;
 
         {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
             add_to_config(&mut config.net, net_cfg.clone());
             config.validate().map_err(VmError::ConfigValidation)?;
+            device_backend::check_backends(&config).map_err(VmError::DeviceBackend)?;
         }
 
         if let Some(ref mut vm) = self.vm {
@@ -1886,6 +2785,29 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_add_usb_device(&mut self, usb_cfg: UsbDeviceConfig) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.usb_devices, usb_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            vm.add_usb_device(usb_cfg).map_err(|e| {
+                error!("Error when adding new USB device to the VM: {:?}", e);
+                e
+            })
+        } else {
+            // Update VmConfig by adding the new device.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            add_to_config(&mut config.usb_devices, usb_cfg);
+            Ok(())
+        }
+    }
+
     fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
@@ -1931,6 +2853,50 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_snapshot_list(&self, tree_url: &str) -> result::Result<Option<Vec<u8>>, VmError> {
+        let snapshots = list_snapshots(tree_url).map_err(VmError::SnapshotTree)?;
+        serde_json::to_vec(&snapshots)
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    fn vm_snapshot_delete(&mut self, tree_url: &str, id: &str) -> result::Result<(), VmError> {
+        let tree_path = url_to_path(tree_url).map_err(VmError::SnapshotTree)?;
+        let snapshot = delete_snapshot(tree_url, id).map_err(VmError::SnapshotTree)?;
+
+        // The next snapshot of the VM becomes a sibling of the deleted one.
+        if self.last_snapshot.as_ref() == Some(&tree_path.join(id)) {
+            self.last_snapshot = snapshot.parent.map(|parent| tree_path.join(parent));
+        }
+
+        Ok(())
+    }
+
+    fn vm_clock(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let clock = vm.clock()?;
+            serde_json::to_vec(&clock)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_serial_capture(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let output = vm.serial_capture()?;
+            let response = VmSerialCaptureResponse {
+                output: String::from_utf8_lossy(&output).into_owned(),
+            };
+            serde_json::to_vec(&response)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_power_button(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.power_button()
@@ -1947,25 +2913,40 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_update_cgroup(&mut self, resources: CgroupResources) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        resources.validate().map_err(VmError::ConfigValidation)?;
+
+        if let Some(ref vm) = self.vm {
+            if let Err(e) = vm.update_cgroup(&resources) {
+                error!("Error when updating cgroup: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            // Update VmConfig so the settings are applied when the VM boots.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            let cgroup = config.cgroup.as_mut().ok_or(VmError::CgroupNotConfigured)?;
+            cgroup.resources.merge(&resources);
+            Ok(())
+        }
+    }
+
     fn vm_receive_migration(
         &mut self,
-        receive_data_migration: VmReceiveMigrationData,
+        mut receive_data_migration: VmReceiveMigrationData,
     ) -> result::Result<(), MigratableError> {
         info!(
             "Receiving migration: receiver_url = {}",
             receive_data_migration.receiver_url
         );
 
-        let path = Self::socket_url_to_path(&receive_data_migration.receiver_url)?;
-        let listener = UnixListener::bind(&path).map_err(|e| {
-            MigratableError::MigrateReceive(anyhow!("Error binding to UNIX socket: {}", e))
-        })?;
-        let (mut socket, _addr) = listener.accept().map_err(|e| {
-            MigratableError::MigrateReceive(anyhow!("Error accepting on UNIX socket: {}", e))
-        })?;
-        std::fs::remove_file(&path).map_err(|e| {
-            MigratableError::MigrateReceive(anyhow!("Error unlinking UNIX socket: {}", e))
-        })?;
+        let mut socket = SocketStream::accept(
+            &receive_data_migration.receiver_url,
+            receive_data_migration.fd.take(),
+        )?;
 
         let mut started = false;
         let mut memory_manager: Option<Arc<Mutex<MemoryManager>>> = None;
@@ -2003,7 +2984,12 @@ impl RequestHandler for Vmm {
                         continue;
                     }
                     if let Some(mm) = memory_manager.take() {
-                        self.vm_receive_state(&req, &mut socket, mm)?;
+                        self.vm_receive_state(
+                            &req,
+                            &mut socket,
+                            mm,
+                            receive_data_migration.resync_clock,
+                        )?;
                     } else {
                         warn!("Configuration not sent yet");
                         Response::error().write_to(&mut socket)?;
@@ -2033,8 +3019,13 @@ impl RequestHandler for Vmm {
                         continue;
                     }
 
+                    let SocketStream::Unix(unix_socket) = &mut socket else {
+                        warn!("Memory files can only be received over a UNIX socket");
+                        Response::error().write_to(&mut socket)?;
+                        continue;
+                    };
                     let mut buf = [0u8; 4];
-                    let (_, file) = socket.recv_with_fd(&mut buf).map_err(|e| {
+                    let (_, file) = unix_socket.recv_with_fd(&mut buf).map_err(|e| {
                         MigratableError::MigrateReceive(anyhow!(
                             "Error receiving slot from socket: {}",
                             e
@@ -2136,6 +3127,10 @@ impl RequestHandler for Vmm {
     }
 }
 
+// Interval at which the availability of the host CPUs the vCPUs are pinned
+// onto is checked.
+const HOST_CPUS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 const CPU_MANAGER_SNAPSHOT_ID: &str = "cpu-manager";
 const MEMORY_MANAGER_SNAPSHOT_ID: &str = "memory-manager";
 const DEVICE_MANAGER_SNAPSHOT_ID: &str = "device-manager";
@@ -2146,8 +3141,8 @@ mod unit_tests {
     #[cfg(target_arch = "x86_64")]
     use crate::config::DebugConsoleConfig;
     use config::{
-        ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, MemoryConfig, PayloadConfig,
-        RngConfig,
+        CgroupConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, MemoryConfig,
+        PayloadConfig, RngConfig, XhciConfig,
     };
 
     fn create_dummy_vmm() -> Vmm {
@@ -2174,6 +3169,7 @@ mod unit_tests {
                 kvm_hyperv: false,
                 max_phys_bits: 46,
                 affinity: None,
+                affinity_fallback: config::CpuAffinityFallback::default(),
                 features: config::CpuFeatures::default(),
             },
             memory: MemoryConfig {
@@ -2182,12 +3178,16 @@ mod unit_tests {
                 hotplug_method: HotplugMethod::Acpi,
                 hotplug_size: None,
                 hotplugged_size: None,
+                boot_size: None,
                 shared: true,
                 hugepages: false,
                 hugepage_size: None,
                 prefault: false,
+                mlock: false,
                 zones: None,
                 thp: true,
+                dirty_ring: false,
+                guest_memfd: false,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -2200,6 +3200,7 @@ mod unit_tests {
                 host_data: None,
             }),
             rate_limit_groups: None,
+            io_affinities: None,
             disks: None,
             net: None,
             rng: RngConfig {
@@ -2208,38 +3209,60 @@ mod unit_tests {
             },
             balloon: None,
             fs: None,
+            fs9p: None,
+            i2c: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 socket: None,
+                log_file: None,
+                capture_size: None,
             },
             #[cfg(target_arch = "x86_64")]
             debug_console: DebugConsoleConfig::default(),
             devices: None,
             user_devices: None,
             vdpa: None,
+            ivshmem: None,
+            nvme: None,
+            cxl: None,
+            xhci: None,
+            usb_devices: None,
+            vnc: None,
             vsock: None,
+            guest_agent: None,
             #[cfg(feature = "pvmemcontrol")]
             pvmemcontrol: None,
             pvpanic: false,
+            vmclock: false,
+            panic: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
             numa: None,
+            numa_placement: false,
+            numa_placed: None,
             watchdog: false,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             pci_segments: None,
             platform: None,
             tpm: None,
+            rtc: None,
+            #[cfg(target_arch = "x86_64")]
+            fw_cfg: None,
+            cgroup: None,
+            security_label: None,
             preserved_fds: None,
             landlock_enable: false,
             landlock_rules: None,
@@ -2258,6 +3281,38 @@ mod unit_tests {
         ));
     }
 
+    #[test]
+    fn test_vmm_vm_create_tty_not_allowed() {
+        let mut vmm = create_dummy_vmm();
+        vmm.tty = false;
+        let config = create_dummy_vm_config();
+
+        assert!(matches!(
+            vmm.vm_create(config.clone()),
+            Err(VmError::TtyNotAllowed)
+        ));
+        config.lock().unwrap().console.mode = ConsoleOutputMode::Null;
+        assert!(matches!(vmm.vm_create(config), Ok(())));
+    }
+
+    #[test]
+    fn test_vmm_vm_create_cgroup_not_allowed() {
+        let mut vmm = create_dummy_vmm();
+        vmm.cgroup = false;
+        let config = create_dummy_vm_config();
+        config.lock().unwrap().cgroup = Some(CgroupConfig {
+            path: PathBuf::from("/sys/fs/cgroup/ch"),
+            resources: CgroupResources::default(),
+        });
+
+        assert!(matches!(
+            vmm.vm_create(config.clone()),
+            Err(VmError::CgroupNotAllowed)
+        ));
+        config.lock().unwrap().cgroup = None;
+        assert!(matches!(vmm.vm_create(config), Ok(())));
+    }
+
     #[test]
     fn test_vmm_vm_cold_add_device() {
         let mut vmm = create_dummy_vmm();
@@ -2626,4 +3681,96 @@ mod unit_tests {
             vsock_config
         );
     }
+
+    #[test]
+    fn test_vmm_vm_cold_add_usb_device() {
+        let mut vmm = create_dummy_vmm();
+        let usb_config = UsbDeviceConfig::parse("hostbus=1,hostaddr=4").unwrap();
+
+        assert!(matches!(
+            vmm.vm_add_usb_device(usb_config.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(matches!(
+            vmm.vm_add_usb_device(usb_config.clone()),
+            Err(VmError::ConfigValidation(
+                config::ValidationError::UsbDeviceRequiresXhci
+            ))
+        ));
+
+        vmm.vm_config.as_ref().unwrap().lock().unwrap().xhci = Some(XhciConfig::default());
+        assert!(vmm.vm_add_usb_device(usb_config.clone()).is_ok());
+        assert_eq!(
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .usb_devices
+                .clone()
+                .unwrap(),
+            vec![usb_config]
+        );
+    }
+
+    #[test]
+    fn test_migration_socket_stream_from_fd() {
+        let (local, remote) = UnixStream::pair().unwrap();
+        let remote = File::from(OwnedFd::from(remote));
+        let mut socket = SocketStream::connect("fd:3", Some(remote)).unwrap();
+        assert!(matches!(socket, SocketStream::Unix(_)));
+        socket.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        (&local).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let socket = SocketStream::accept("fd:3", Some(file)).unwrap();
+        assert!(matches!(socket, SocketStream::Other(_)));
+
+        // Without a file descriptor passed along the request, the one named
+        // by the URL is left alone.
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let url = format!("fd:{}", file.as_raw_fd());
+        assert!(SocketStream::connect(&url, None).is_err());
+        assert!(SocketStream::accept(&url, None).is_err());
+        // SAFETY: FFI call checking the file descriptor is still open
+        assert!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) } >= 0);
+
+        assert!(SocketStream::connect("vsock:2", None).is_err());
+        assert!(SocketStream::accept("tcp:127.0.0.1:6000", None).is_err());
+    }
+
+    #[test]
+    fn test_vmm_add_remove_vm() {
+        let mut vmm = create_dummy_vmm();
+
+        assert!(matches!(
+            vmm.vmm_add_vm("vm0".to_string()),
+            Err(VmError::MultiVmDisabled)
+        ));
+
+        let vm_api_channels = VmApiChannels::default();
+        vmm.vm_api_channels = Some(vm_api_channels.clone());
+        assert!(matches!(vmm.vmm_add_vm("vm0".to_string()), Ok(())));
+        assert!(matches!(
+            vmm.vmm_add_vm("vm0".to_string()),
+            Err(VmError::VmIdInUse(_))
+        ));
+        assert!(matches!(
+            vmm.vmm_add_vm("vm/1".to_string()),
+            Err(VmError::InvalidVmId(_))
+        ));
+        assert!(vm_api_channels.lock().unwrap().contains_key("vm0"));
+        assert_eq!(vmm.vmm_list_vms().unwrap().unwrap(), b"[\"vm0\"]".to_vec());
+
+        assert!(matches!(vmm.vmm_remove_vm("vm0".to_string()), Ok(())));
+        assert!(matches!(
+            vmm.vmm_remove_vm("vm0".to_string()),
+            Err(VmError::UnknownVm(_))
+        ));
+        assert!(vm_api_channels.lock().unwrap().is_empty());
+    }
 }
@@ -9,6 +9,7 @@ use crate::config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
 use crate::coredump::{
     CoredumpMemoryRegion, CoredumpMemoryRegions, DumpState, GuestDebuggableError,
 };
+use crate::lazy_restore::{LazyRange, LazyRestore, LazyRestoreError, LazyRestoreHandle};
 use crate::migration::url_to_path;
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 use crate::{GuestMemoryMmap, GuestRegionMmap};
@@ -24,6 +25,7 @@ use hypervisor::HypervisorVmError;
 use libc::_SC_NPROCESSORS_ONLN;
 #[cfg(target_arch = "x86_64")]
 use libc::{MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use seccompiler::BpfProgram;
 use serde::{Deserialize, Serialize};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::collections::BTreeMap;
@@ -55,6 +57,7 @@ use vm_migration::{
     protocol::MemoryRange, protocol::MemoryRangeTable, Migratable, MigratableError, Pausable,
     Snapshot, SnapshotData, Snapshottable, Transportable,
 };
+use vmm_sys_util::eventfd::EventFd;
 
 pub const MEMORY_MANAGER_ACPI_SIZE: usize = 0x18;
 
@@ -125,6 +128,7 @@ impl VirtioMemZone {
 pub struct MemoryZone {
     regions: Vec<Arc<GuestRegionMmap>>,
     virtio_mem_zone: Option<VirtioMemZone>,
+    cxl_region: Option<Arc<GuestRegionMmap>>,
 }
 
 impl MemoryZone {
@@ -137,6 +141,9 @@ impl MemoryZone {
     pub fn virtio_mem_zone_mut(&mut self) -> Option<&mut VirtioMemZone> {
         self.virtio_mem_zone.as_mut()
     }
+    pub fn cxl_region(&self) -> &Option<Arc<GuestRegionMmap>> {
+        &self.cxl_region
+    }
 }
 
 pub type MemoryZones = HashMap<String, MemoryZone>;
@@ -178,6 +185,7 @@ pub struct MemoryManager {
     hugepages: bool,
     hugepage_size: Option<u64>,
     prefault: bool,
+    mlock: bool,
     thp: bool,
     #[cfg(target_arch = "x86_64")]
     sgx_epc_region: Option<SgxEpcRegion>,
@@ -197,6 +205,23 @@ pub struct MemoryManager {
     pub acpi_address: Option<GuestAddress>,
     #[cfg(target_arch = "aarch64")]
     uefi_flash: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+
+    // Guest memory waiting to be populated from the snapshot, and the thread
+    // populating it once started.
+    lazy_restore: Option<LazyRestore>,
+    lazy_restore_handle: Option<LazyRestoreHandle>,
+}
+
+/// How the guest memory content is restored from a snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryRestoreMode {
+    /// Read the whole memory content before the VM is resumed.
+    #[default]
+    Copy,
+    /// Map the snapshot file copy-on-write.
+    Clone,
+    /// Populate each page from the snapshot file when first accessed.
+    Lazy,
 }
 
 #[derive(Debug)]
@@ -294,6 +319,9 @@ pub enum Error {
     /// Failed applying NUMA memory policy.
     ApplyNumaPolicy(io::Error),
 
+    /// Failed locking guest RAM.
+    LockMemory(io::Error),
+
     /// Memory zone identifier is not unique.
     DuplicateZoneId,
 
@@ -324,6 +352,18 @@ pub enum Error {
     // Error copying snapshot into region
     SnapshotCopy(GuestMemoryError),
 
+    /// Saved memory range not part of guest RAM
+    SnapshotRange(GuestMemoryError),
+
+    /// Error mapping snapshot into region
+    SnapshotMap(io::Error),
+
+    /// Cloning and lazy restore require private memory, not backed by huge pages
+    RestoreModeUnsupported(MemoryRestoreMode),
+
+    /// Error setting up the lazy restore of the guest memory
+    LazyRestore(LazyRestoreError),
+
     /// Failed to allocate MMIO address
     AllocateMmioAddress,
 
@@ -534,7 +574,11 @@ impl MemoryManager {
         prefault: Option<bool>,
         thp: bool,
     ) -> Result<(Vec<Arc<GuestRegionMmap>>, MemoryZones), Error> {
-        let mut zone_iter = zones.iter();
+        // Zones without any memory at boot, only made of hotpluggable memory,
+        // don't take any space in the RAM regions.
+        let (empty_zones, zones): (Vec<&MemoryZoneConfig>, Vec<&MemoryZoneConfig>) =
+            zones.iter().partition(|zone| zone.size == 0);
+        let mut zone_iter = zones.into_iter();
         let mut mem_regions = Vec::new();
         let mut zone = zone_iter.next().ok_or(Error::MissingMemoryZones)?;
         let mut zone_align_size = memory_zone_get_align_size(zone)?;
@@ -650,6 +694,20 @@ impl MemoryManager {
             }
         }
 
+        for zone in empty_zones {
+            if memory_zones
+                .insert(zone.id.clone(), MemoryZone::default())
+                .is_some()
+            {
+                error!(
+                    "Memory zone identifier '{}' found more than once. \
+                    It must be unique",
+                    zone.id,
+                );
+                return Err(Error::DuplicateZoneId);
+            }
+        }
+
         Ok((mem_regions, memory_zones))
     }
 
@@ -755,6 +813,120 @@ impl MemoryManager {
         Ok(())
     }
 
+    // Map the saved ranges copy-on-write from the snapshot file, in place of
+    // the anonymous memory backing them. The VMs cloned from the same
+    // snapshot share its unmodified pages through the host page cache, and
+    // the pages are only read from the file when first accessed.
+    fn map_saved_regions(
+        &mut self,
+        file_path: PathBuf,
+        saved_regions: MemoryRangeTable,
+        prefault: bool,
+    ) -> Result<(), Error> {
+        if saved_regions.is_empty() {
+            return Ok(());
+        }
+
+        let memory_file = OpenOptions::new()
+            .read(true)
+            .open(file_path)
+            .map_err(Error::SnapshotOpen)?;
+
+        let mut flags = libc::MAP_PRIVATE | libc::MAP_FIXED;
+        if prefault {
+            flags |= MAP_POPULATE;
+        }
+
+        let guest_memory = self.guest_memory.memory();
+        // The ranges are stored one after the other in the snapshot file,
+        // each of them being a multiple of the page size.
+        let mut file_offset: u64 = 0;
+        for range in saved_regions.regions() {
+            let slice = guest_memory
+                .get_slice(GuestAddress(range.gpa), range.length as usize)
+                .map_err(Error::SnapshotRange)?;
+            // SAFETY: the mapping replaces guest RAM owned by the memory
+            // manager, which is not accessed until the VM is resumed. The
+            // file descriptor is valid, and the mapping holds its own
+            // reference on the file.
+            let ret = unsafe {
+                libc::mmap(
+                    slice.ptr_guard_mut().as_ptr() as *mut libc::c_void,
+                    range.length as usize,
+                    PROT_READ | PROT_WRITE,
+                    flags,
+                    memory_file.as_raw_fd(),
+                    file_offset as libc::off_t,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(Error::SnapshotMap(io::Error::last_os_error()));
+            }
+            file_offset += range.length;
+        }
+
+        Ok(())
+    }
+
+    // Register the saved ranges with userfaultfd, so that their content is
+    // only read from the snapshot file when first accessed. The page faults
+    // are not served until start_lazy_restore() is called.
+    fn register_saved_regions(
+        &mut self,
+        file_path: PathBuf,
+        saved_regions: MemoryRangeTable,
+    ) -> Result<(), Error> {
+        if saved_regions.is_empty() {
+            return Ok(());
+        }
+
+        let memory_file = OpenOptions::new()
+            .read(true)
+            .open(file_path)
+            .map_err(Error::SnapshotOpen)?;
+
+        let guest_memory = self.guest_memory.memory();
+        let mut ranges = Vec::new();
+        // The ranges are stored one after the other in the snapshot file.
+        let mut file_offset: u64 = 0;
+        for range in saved_regions.regions() {
+            let slice = guest_memory
+                .get_slice(GuestAddress(range.gpa), range.length as usize)
+                .map_err(Error::SnapshotRange)?;
+            ranges.push(LazyRange {
+                host_addr: slice.ptr_guard_mut().as_ptr() as u64,
+                len: range.length,
+                file_offset,
+            });
+            file_offset += range.length;
+        }
+
+        // SAFETY: FFI call. Trivially safe.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        self.lazy_restore =
+            Some(LazyRestore::new(memory_file, ranges, page_size).map_err(Error::LazyRestore)?);
+
+        Ok(())
+    }
+
+    /// Starts populating the guest memory registered for a lazy restore, if
+    /// any. It must be called before anything accesses the guest memory.
+    pub fn start_lazy_restore(
+        &mut self,
+        seccomp_filter: BpfProgram,
+        exit_evt: EventFd,
+    ) -> Result<(), Error> {
+        if let Some(lazy_restore) = self.lazy_restore.take() {
+            self.lazy_restore_handle = Some(
+                lazy_restore
+                    .start(seccomp_filter, exit_evt)
+                    .map_err(Error::LazyRestore)?,
+            );
+        }
+
+        Ok(())
+    }
+
     fn validate_memory_config(
         config: &MemoryConfig,
         user_provided_zones: bool,
@@ -800,22 +972,50 @@ impl MemoryManager {
                 }
             }
 
+            // When a boot size is provided, only that amount of memory is
+            // static RAM. The rest of 'size' is backed by the virtio-mem region
+            // and plugged at boot, so that it can be unplugged later on.
+            let (size, hotplug_size, hotplugged_size) = if let Some(boot_size) = config.boot_size {
+                if config.hotplug_method != HotplugMethod::VirtioMem
+                    || config.hotplugged_size.is_some()
+                    || boot_size == 0
+                    || boot_size > config.size
+                {
+                    error!(
+                        "Invalid 'boot_size' {} for memory size {}",
+                        boot_size, config.size
+                    );
+                    return Err(Error::InvalidMemoryParameters);
+                }
+
+                allow_mem_hotplug = true;
+                let plugged_size = config.size - boot_size;
+                (
+                    boot_size,
+                    Some(plugged_size + config.hotplug_size.unwrap_or(0)),
+                    Some(plugged_size),
+                )
+            } else {
+                (config.size, config.hotplug_size, config.hotplugged_size)
+            };
+
             // Create a single zone from the global memory config. This lets
             // us reuse the codepath for user defined memory zones.
             let zones = vec![MemoryZoneConfig {
                 id: String::from(DEFAULT_MEMORY_ZONE),
-                size: config.size,
+                size,
                 file: None,
                 shared: config.shared,
                 hugepages: config.hugepages,
                 hugepage_size: config.hugepage_size,
                 host_numa_node: None,
-                hotplug_size: config.hotplug_size,
-                hotplugged_size: config.hotplugged_size,
+                hotplug_size,
+                hotplugged_size,
                 prefault: config.prefault,
+                mlock: config.mlock,
             }];
 
-            Ok((config.size, zones, allow_mem_hotplug))
+            Ok((size, zones, allow_mem_hotplug))
         } else {
             if config.zones.is_none() {
                 error!(
@@ -836,6 +1036,15 @@ impl MemoryManager {
             for zone in zones.iter() {
                 total_ram_size += zone.size;
 
+                if zone.size == 0 && zone.hotplug_size.is_none() {
+                    error!(
+                        "Memory zone '{}' without any memory at boot must \
+                        define a 'hotplug_size'",
+                        zone.id
+                    );
+                    return Err(Error::InvalidMemoryParameters);
+                }
+
                 if zone.shared && zone.file.is_some() && zone.host_numa_node.is_some() {
                     error!(
                         "Invalid to set host NUMA policy for a memory zone \
@@ -987,6 +1196,7 @@ impl MemoryManager {
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         restore_data: Option<&MemoryManagerSnapshotData>,
         existing_memory_files: Option<HashMap<u32, File>>,
+        cxl_memory_zones: &[String],
         #[cfg(target_arch = "x86_64")] sgx_epc_config: Option<Vec<SgxEpcConfig>>,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
         trace_scoped!("MemoryManager::new");
@@ -1005,6 +1215,13 @@ impl MemoryManager {
         let (ram_size, zones, allow_mem_hotplug) =
             Self::validate_memory_config(config, user_provided_zones)?;
 
+        // Memory zones backing CXL memory devices aren't part of the boot
+        // RAM, they are placed at the start of the device area instead.
+        let (cxl_zones, zones): (Vec<MemoryZoneConfig>, Vec<MemoryZoneConfig>) = zones
+            .into_iter()
+            .partition(|zone| cxl_memory_zones.contains(&zone.id));
+        let ram_size = ram_size - cxl_zones.iter().map(|zone| zone.size).sum::<u64>();
+
         let (
             start_of_device_area,
             boot_ram,
@@ -1134,6 +1351,45 @@ impl MemoryManager {
                 }
             }
 
+            for zone in cxl_zones.iter() {
+                let start_addr = GuestAddress(
+                    (start_of_device_area.0 + devices::cxl::CXL_HDM_ALIGNMENT - 1)
+                        / devices::cxl::CXL_HDM_ALIGNMENT
+                        * devices::cxl::CXL_HDM_ALIGNMENT,
+                );
+
+                let region = MemoryManager::create_ram_region(
+                    &zone.file,
+                    0,
+                    start_addr,
+                    zone.size as usize,
+                    prefault.unwrap_or(zone.prefault),
+                    zone.shared,
+                    zone.hugepages,
+                    zone.hugepage_size,
+                    zone.host_numa_node,
+                    None,
+                    config.thp,
+                )?;
+
+                guest_memory = guest_memory
+                    .insert_region(Arc::clone(&region))
+                    .map_err(Error::GuestMemory)?;
+
+                memory_zones.insert(
+                    zone.id.clone(),
+                    MemoryZone {
+                        regions: vec![Arc::clone(&region)],
+                        virtio_mem_zone: None,
+                        cxl_region: Some(region),
+                    },
+                );
+
+                start_of_device_area = start_addr
+                    .checked_add(zone.size)
+                    .ok_or(Error::GuestAddressOverFlow)?;
+            }
+
             let mut hotplug_slots = Vec::with_capacity(HOTPLUG_COUNT);
             hotplug_slots.resize_with(HOTPLUG_COUNT, HotPlugState::default);
 
@@ -1221,6 +1477,7 @@ impl MemoryManager {
             hugepages: config.hugepages,
             hugepage_size: config.hugepage_size,
             prefault: config.prefault,
+            mlock: config.mlock,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_region: None,
             user_provided_zones,
@@ -1235,6 +1492,8 @@ impl MemoryManager {
             #[cfg(target_arch = "aarch64")]
             uefi_flash: None,
             thp: config.thp,
+            lazy_restore: None,
+            lazy_restore_handle: None,
         };
 
         #[cfg(target_arch = "aarch64")]
@@ -1254,6 +1513,14 @@ impl MemoryManager {
             memory_manager.setup_sgx(sgx_epc_config)?;
         }
 
+        for zone in zones.iter().filter(|zone| config.mlock || zone.mlock) {
+            if let Some(memory_zone) = memory_manager.memory_zones.get(&zone.id) {
+                for region in memory_zone.regions() {
+                    Self::lock_region(region)?;
+                }
+            }
+        }
+
         Ok(Arc::new(Mutex::new(memory_manager)))
     }
 
@@ -1263,8 +1530,25 @@ impl MemoryManager {
         config: &MemoryConfig,
         source_url: Option<&str>,
         prefault: bool,
+        restore_mode: MemoryRestoreMode,
         phys_bits: u8,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
+        // Private anonymous memory is the only one that can be replaced by a
+        // private mapping of the snapshot file. It is also the only one whose
+        // page faults are all reported to userfaultfd, as shared memory could
+        // be accessed by vhost-user backends.
+        if restore_mode != MemoryRestoreMode::Copy
+            && (config.shared
+                || config.hugepages
+                || config
+                    .zones
+                    .iter()
+                    .flatten()
+                    .any(|zone| zone.shared || zone.hugepages || zone.file.is_some()))
+        {
+            return Err(Error::RestoreModeUnsupported(restore_mode));
+        }
+
         if let Some(source_url) = source_url {
             let mut memory_file_path = url_to_path(source_url).map_err(Error::Restore)?;
             memory_file_path.push(String::from(SNAPSHOT_FILENAME));
@@ -1272,22 +1556,37 @@ impl MemoryManager {
             let mem_snapshot: MemoryManagerSnapshotData =
                 snapshot.to_state().map_err(Error::Restore)?;
 
+            // Cloned memory is prefaulted from the snapshot file instead,
+            // while lazily restored memory must not be populated at all.
             let mm = MemoryManager::new(
                 vm,
                 config,
-                Some(prefault),
+                Some(prefault && restore_mode == MemoryRestoreMode::Copy),
                 phys_bits,
                 #[cfg(feature = "tdx")]
                 false,
                 Some(&mem_snapshot),
                 None,
+                &[],
                 #[cfg(target_arch = "x86_64")]
                 None,
             )?;
 
-            mm.lock()
-                .unwrap()
-                .fill_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?;
+            match restore_mode {
+                MemoryRestoreMode::Copy => mm
+                    .lock()
+                    .unwrap()
+                    .fill_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?,
+                MemoryRestoreMode::Clone => mm.lock().unwrap().map_saved_regions(
+                    memory_file_path,
+                    mem_snapshot.memory_ranges,
+                    prefault,
+                )?,
+                MemoryRestoreMode::Lazy => mm
+                    .lock()
+                    .unwrap()
+                    .register_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?,
+            }
 
             Ok(mm)
         } else {
@@ -1334,6 +1633,26 @@ impl MemoryManager {
         }
     }
 
+    // Locks the pages of the region in RAM as they get faulted in, so that
+    // they never get swapped out, without populating them all right away.
+    fn lock_region(region: &GuestRegionMmap) -> Result<(), Error> {
+        // SAFETY: FFI call with correct arguments
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_mlock2,
+                region.as_ptr() as *mut libc::c_void,
+                region.len(),
+                libc::MLOCK_ONFAULT,
+            )
+        };
+
+        if res < 0 {
+            Err(Error::LockMemory(io::Error::last_os_error()))
+        } else {
+            Ok(())
+        }
+    }
+
     fn create_anonymous_file(
         size: usize,
         hugepages: bool,
@@ -1636,6 +1955,9 @@ impl MemoryManager {
             None,
             self.thp,
         )?;
+        if self.mlock {
+            Self::lock_region(&region)?;
+        }
 
         // Map it into the guest
         let slot = self.create_userspace_mapping(
@@ -2062,6 +2384,15 @@ impl MemoryManager {
         &self.memory_zones
     }
 
+    /// Ranges of the memory zones backing CXL memory devices.
+    pub fn cxl_memory_ranges(&self) -> Vec<(GuestAddress, u64)> {
+        self.memory_zones
+            .values()
+            .filter_map(|zone| zone.cxl_region.as_ref())
+            .map(|region| (region.start_addr(), region.len()))
+            .collect()
+    }
+
     pub fn memory_zones_mut(&mut self) -> &mut MemoryZones {
         &mut self.memory_zones
     }
@@ -6,13 +6,65 @@
 use crate::coredump::GuestDebuggableError;
 use crate::{config::VmConfig, vm::VmSnapshot};
 use anyhow::anyhow;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use vm_migration::{MigratableError, Snapshot};
 
 pub const SNAPSHOT_STATE_FILE: &str = "state.json";
 pub const SNAPSHOT_CONFIG_FILE: &str = "config.json";
+pub const SNAPSHOT_METADATA_FILE: &str = "metadata.json";
+
+/// Metadata of a snapshot, locating it in a snapshot tree.
+///
+/// A snapshot tree is a directory holding snapshots in subdirectories, named
+/// after the identifiers of the snapshots. A snapshot taken from a VM that was
+/// itself restored from, or last snapshotted to, another snapshot of the same
+/// tree is a child of that snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotMetadata {
+    pub id: String,
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Creation time, in seconds since the epoch
+    pub timestamp: u64,
+    /// Hash of the VM configuration, telling apart the snapshots of
+    /// differently configured VMs
+    pub config_hash: String,
+}
+
+impl SnapshotMetadata {
+    /// Builds the metadata of a snapshot taken to `destination`, `previous`
+    /// being the snapshot the VM was last restored from or snapshotted to.
+    pub fn new(destination: &Path, previous: Option<&Path>, vm_config: &VmConfig) -> Self {
+        let file_name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        };
+        let parent = previous
+            .filter(|previous| previous.parent() == destination.parent())
+            .and_then(file_name);
+
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(vm_config)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+
+        SnapshotMetadata {
+            id: file_name(destination).unwrap_or_default(),
+            parent,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            config_hash: format!("{:016x}", hasher.finish()),
+        }
+    }
+}
 
 pub fn url_to_path(url: &str) -> std::result::Result<PathBuf, MigratableError> {
     let path: PathBuf = url
@@ -75,6 +127,94 @@ pub fn recv_vm_state(source_url: &str) -> std::result::Result<Snapshot, Migratab
     serde_json::from_slice(&bytes).map_err(|e| MigratableError::MigrateReceive(e.into()))
 }
 
+fn write_snapshot_metadata(
+    path: &Path,
+    metadata: &SnapshotMetadata,
+) -> std::result::Result<(), MigratableError> {
+    let metadata =
+        serde_json::to_vec(metadata).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path.join(SNAPSHOT_METADATA_FILE))
+        .and_then(|mut file| file.write_all(&metadata))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+pub fn send_snapshot_metadata(
+    destination_url: &str,
+    metadata: &SnapshotMetadata,
+) -> std::result::Result<(), MigratableError> {
+    write_snapshot_metadata(&url_to_path(destination_url)?, metadata)
+}
+
+fn read_snapshot_metadata(path: &Path) -> std::result::Result<SnapshotMetadata, MigratableError> {
+    let bytes = fs::read(path.join(SNAPSHOT_METADATA_FILE))
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    serde_json::from_slice(&bytes).map_err(|e| MigratableError::MigrateReceive(e.into()))
+}
+
+/// Returns the URL of the snapshot `id` from the snapshot tree at `tree_url`.
+pub fn snapshot_url(tree_url: &str, id: &str) -> std::result::Result<String, MigratableError> {
+    if id.is_empty() || id == "." || id == ".." || id.contains('/') {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Invalid snapshot identifier: {}",
+            id
+        )));
+    }
+
+    Ok(format!("{}/{}", tree_url.trim_end_matches('/'), id))
+}
+
+/// Lists the snapshots of the snapshot tree at `tree_url`, from the oldest to
+/// the newest. The subdirectories without metadata are ignored.
+pub fn list_snapshots(
+    tree_url: &str,
+) -> std::result::Result<Vec<SnapshotMetadata>, MigratableError> {
+    let tree_path = url_to_path(tree_url)?;
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(tree_path).map_err(|e| MigratableError::MigrateReceive(e.into()))? {
+        let path = entry
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))?
+            .path();
+        if path.join(SNAPSHOT_METADATA_FILE).is_file() {
+            snapshots.push(read_snapshot_metadata(&path)?);
+        }
+    }
+    snapshots.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+
+    Ok(snapshots)
+}
+
+/// Deletes the snapshot `id` from the snapshot tree at `tree_url`. As each
+/// snapshot is complete on its own, the children of the snapshot are kept
+/// and become children of its parent. Returns the metadata of the deleted
+/// snapshot.
+pub fn delete_snapshot(
+    tree_url: &str,
+    id: &str,
+) -> std::result::Result<SnapshotMetadata, MigratableError> {
+    let snapshot_path = url_to_path(&snapshot_url(tree_url, id)?)?;
+    let snapshot = read_snapshot_metadata(&snapshot_path)?;
+    let tree_path = url_to_path(tree_url)?;
+
+    for mut child in list_snapshots(tree_url)?
+        .into_iter()
+        .filter(|child| child.parent.as_deref() == Some(id))
+    {
+        child.parent.clone_from(&snapshot.parent);
+        write_snapshot_metadata(&tree_path.join(&child.id), &child)?;
+    }
+
+    fs::remove_dir_all(snapshot_path).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    Ok(snapshot)
+}
+
 pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, MigratableError> {
     if let Some(snapshot_data) = snapshot.snapshot_data.as_ref() {
         return snapshot_data.to_state();
@@ -84,3 +224,41 @@ pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, M
         "Could not find VM config snapshot section"
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_snapshot_tree() {
+        let tree = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let tree_url = format!("file://{}", tree.as_path().display());
+
+        let mut parent = None;
+        for (timestamp, id) in ["a", "b", "c"].into_iter().enumerate() {
+            let path = tree.as_path().join(id);
+            fs::create_dir(&path).unwrap();
+            let metadata = SnapshotMetadata {
+                id: id.to_string(),
+                parent: parent.replace(id.to_string()),
+                timestamp: timestamp as u64,
+                config_hash: String::new(),
+            };
+            write_snapshot_metadata(&path, &metadata).unwrap();
+        }
+
+        let snapshots = list_snapshots(&tree_url).unwrap();
+        assert_eq!(snapshots.len(), 3);
+        assert_eq!(snapshots[2].parent.as_deref(), Some("b"));
+
+        delete_snapshot(&tree_url, "b").unwrap();
+        let snapshots = list_snapshots(&tree_url).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].id, "a");
+        assert_eq!(snapshots[1].parent.as_deref(), Some("a"));
+
+        assert!(delete_snapshot(&tree_url, "b").is_err());
+        assert!(delete_snapshot(&tree_url, "..").is_err());
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Automatic placement of the guest NUMA nodes onto the host ones.
+//!
+//! Each guest NUMA node is assigned a host NUMA node: the one backing its
+//! memory zones if explicitly provided, or otherwise the next host node with
+//! CPUs, in a round-robin fashion. Unless already configured, the vCPUs of
+//! the guest node are then pinned to the CPUs of the host node, its memory
+//! zones are allocated from the host node, and the queues of the virtio-block
+//! devices on its PCI segments are served from the host node CPUs.
+//!
+//! The placement depends on the host, hence it is recorded alongside the
+//! configuration but left out of the configuration sent to a snapshot or to
+//! a migration destination, where it gets resolved again.
+
+use crate::vm_config::{CpuAffinity, VmConfig};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const SYSFS_NODE_PATH: &str = "/sys/devices/system/node";
+const SYSFS_CPU_ONLINE_PATH: &str = "/sys/devices/system/cpu/online";
+
+#[derive(Debug, Error)]
+pub enum NumaPlacementError {
+    #[error("Error reading host NUMA topology from {0:?}: {1}")]
+    ReadTopology(PathBuf, #[source] io::Error),
+
+    #[error("Invalid host NUMA topology list {0:?}")]
+    ParseList(String),
+
+    #[error("No host NUMA node with CPUs found")]
+    NoHostNode,
+}
+
+type Result<T> = std::result::Result<T, NumaPlacementError>;
+
+/// Settings filled in by the placement, on top of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumaPlacement {
+    // vCPUs whose affinity was filled in
+    vcpus: Vec<u32>,
+    // Memory zones whose host NUMA node was filled in
+    memory_zones: Vec<String>,
+    // Host CPUs serving the virtio-block queues of each PCI segment
+    pci_segments: BTreeMap<u16, Vec<usize>>,
+}
+
+impl NumaPlacement {
+    /// Host CPUs serving the virtio-block queues of the given PCI segment.
+    pub fn pci_segment_cpus(&self, pci_segment: u16) -> Option<&Vec<usize>> {
+        self.pci_segments.get(&pci_segment)
+    }
+}
+
+struct HostNode {
+    id: u32,
+    cpus: Vec<usize>,
+}
+
+// Parse a list formatted as exposed through sysfs, such as "0-3,8,10-11".
+fn parse_list(list: &str) -> Result<Vec<usize>> {
+    let list = list.trim();
+    let error = || NumaPlacementError::ParseList(list.to_string());
+
+    let mut values = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start: usize = start.parse().map_err(|_| error())?;
+        let end: usize = end.parse().map_err(|_| error())?;
+        if start > end {
+            return Err(error());
+        }
+        values.extend(start..=end);
+    }
+
+    Ok(values)
+}
+
+fn read_list(path: PathBuf) -> Result<Vec<usize>> {
+    let list = fs::read_to_string(&path).map_err(|e| NumaPlacementError::ReadTopology(path, e))?;
+    parse_list(&list)
+}
+
+// Online host NUMA nodes having CPUs, memory-only nodes being skipped.
+fn host_nodes() -> Result<Vec<HostNode>> {
+    let sysfs = Path::new(SYSFS_NODE_PATH);
+
+    let mut nodes = Vec::new();
+    for id in read_list(sysfs.join("online"))? {
+        let cpus = read_list(sysfs.join(format!("node{id}")).join("cpulist"))?;
+        if !cpus.is_empty() {
+            nodes.push(HostNode {
+                id: id as u32,
+                cpus,
+            });
+        }
+    }
+
+    if nodes.is_empty() {
+        return Err(NumaPlacementError::NoHostNode);
+    }
+
+    Ok(nodes)
+}
+
+/// Host CPUs currently online.
+pub(crate) fn online_host_cpus() -> Result<Vec<usize>> {
+    read_list(PathBuf::from(SYSFS_CPU_ONLINE_PATH))
+}
+
+/// CPUs of the host NUMA nodes the given host CPUs belong to.
+pub(crate) fn host_node_cpus(host_cpus: &[usize]) -> Result<Vec<usize>> {
+    Ok(host_nodes()?
+        .into_iter()
+        .filter(|node| node.cpus.iter().any(|cpu| host_cpus.contains(cpu)))
+        .flat_map(|node| node.cpus)
+        .collect())
+}
+
+fn place(config: &mut VmConfig, host_nodes: &[HostNode]) -> NumaPlacement {
+    let mut placement = NumaPlacement::default();
+    let Some(numa) = config.numa.clone() else {
+        return placement;
+    };
+
+    let mut next_host_node = 0;
+    for guest_node in numa.iter() {
+        let memory_zones = guest_node.memory_zones.as_deref().unwrap_or_default();
+
+        // Stick to the host node backing the guest node memory, if provided.
+        let host_node = config
+            .memory
+            .zones
+            .iter()
+            .flatten()
+            .filter(|zone| memory_zones.contains(&zone.id))
+            .find_map(|zone| zone.host_numa_node)
+            .and_then(|id| host_nodes.iter().find(|node| node.id == id))
+            .unwrap_or_else(|| {
+                next_host_node += 1;
+                &host_nodes[(next_host_node - 1) % host_nodes.len()]
+            });
+
+        info!(
+            "Placing guest NUMA node {} on host NUMA node {}",
+            guest_node.guest_numa_id, host_node.id
+        );
+
+        if let Some(vcpus) = &guest_node.cpus {
+            let affinity = config.cpus.affinity.get_or_insert_with(Vec::new);
+            for vcpu in vcpus {
+                if !affinity.iter().any(|a| a.vcpu == *vcpu) {
+                    affinity.push(CpuAffinity {
+                        vcpu: *vcpu,
+                        host_cpus: host_node.cpus.clone(),
+                    });
+                    placement.vcpus.push(*vcpu);
+                }
+            }
+        }
+
+        for zone in config.memory.zones.iter_mut().flatten() {
+            if memory_zones.contains(&zone.id) && zone.host_numa_node.is_none() {
+                zone.host_numa_node = Some(host_node.id);
+                placement.memory_zones.push(zone.id.clone());
+            }
+        }
+
+        // The default PCI segment always belongs to the guest node 0.
+        let mut pci_segments = guest_node.pci_segments.clone().unwrap_or_default();
+        if guest_node.guest_numa_id == 0 {
+            pci_segments.push(0);
+        }
+        for pci_segment in pci_segments {
+            placement
+                .pci_segments
+                .insert(pci_segment, host_node.cpus.clone());
+        }
+    }
+
+    placement
+}
+
+/// Place the guest NUMA nodes on the host NUMA nodes, filling in the vCPU
+/// affinity and memory zone host NUMA node which were not explicitly
+/// provided. The placement is only resolved once, so that it is kept across
+/// reboots.
+pub fn apply(config: &mut VmConfig) -> Result<()> {
+    if config.numa_placed.is_some() {
+        return Ok(());
+    }
+
+    let host_nodes = host_nodes()?;
+    config.numa_placed = Some(place(config, &host_nodes));
+    Ok(())
+}
+
+/// Copy of the configuration without the settings filled in by the
+/// placement, to be resolved again on another host.
+pub fn strip(config: &VmConfig) -> VmConfig {
+    let mut config = config.clone();
+    let Some(placement) = config.numa_placed.take() else {
+        return config;
+    };
+
+    if let Some(affinity) = config.cpus.affinity.as_mut() {
+        affinity.retain(|a| !placement.vcpus.contains(&a.vcpu));
+    }
+    if config.cpus.affinity.as_ref().is_some_and(|a| a.is_empty()) {
+        config.cpus.affinity = None;
+    }
+
+    for zone in config.memory.zones.iter_mut().flatten() {
+        if placement.memory_zones.contains(&zone.id) {
+            zone.host_numa_node = None;
+        }
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("0\n").unwrap(), vec![0]);
+        assert_eq!(
+            parse_list("0-3,8,10-11").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert!(parse_list("").unwrap().is_empty());
+        assert!(parse_list("3-1").is_err());
+        assert!(parse_list("a").is_err());
+    }
+
+    #[test]
+    fn test_place() {
+        let mut config: VmConfig = serde_json::from_str(
+            r#"{
+                "cpus": {"boot_vcpus": 4, "max_vcpus": 4, "affinity": [{"vcpu": 3, "host_cpus": [7]}]},
+                "memory": {"size": 0, "zones": [
+                    {"id": "mem0", "size": 1073741824},
+                    {"id": "mem1", "size": 1073741824, "host_numa_node": 1}
+                ]},
+                "numa": [
+                    {"guest_numa_id": 0, "cpus": [0, 1], "memory_zones": ["mem0"]},
+                    {"guest_numa_id": 1, "cpus": [2, 3], "memory_zones": ["mem1"], "pci_segments": [1]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let host_nodes = [
+            HostNode {
+                id: 0,
+                cpus: vec![0, 1],
+            },
+            HostNode {
+                id: 1,
+                cpus: vec![2, 3],
+            },
+        ];
+
+        let original = config.clone();
+        config.numa_placed = Some(place(&mut config, &host_nodes));
+
+        // Guest node 0 gets the next host node, guest node 1 sticks to the
+        // host node explicitly backing its memory.
+        let zones = config.memory.zones.as_ref().unwrap();
+        assert_eq!(zones[0].host_numa_node, Some(0));
+        assert_eq!(zones[1].host_numa_node, Some(1));
+
+        let affinity = config.cpus.affinity.as_ref().unwrap();
+        assert_eq!(affinity.len(), 4);
+        for a in affinity {
+            let host_cpus = match a.vcpu {
+                0 | 1 => vec![0, 1],
+                2 => vec![2, 3],
+                _ => vec![7],
+            };
+            assert_eq!(a.host_cpus, host_cpus);
+        }
+
+        let placement = config.numa_placed.as_ref().unwrap();
+        assert_eq!(placement.pci_segment_cpus(0), Some(&vec![0, 1]));
+        assert_eq!(placement.pci_segment_cpus(1), Some(&vec![2, 3]));
+        assert_eq!(placement.pci_segment_cpus(2), None);
+
+        // Only the explicit settings are left for another host.
+        assert_eq!(strip(&config), original);
+    }
+}
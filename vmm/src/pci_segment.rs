@@ -46,6 +46,9 @@ pub(crate) struct PciSegment {
 
     pub(crate) mem32_allocator: Arc<Mutex<AddressAllocator>>,
     pub(crate) mem64_allocator: Arc<Mutex<AddressAllocator>>,
+
+    // RCRB address when the segment is the host bridge of a CXL device.
+    pub(crate) cxl_rcrb_address: Option<u64>,
 }
 
 impl PciSegment {
@@ -99,6 +102,7 @@ impl PciSegment {
             start_of_mem64_area,
             end_of_mem64_area,
             pci_irq_slots: *pci_irq_slots,
+            cxl_rcrb_address: None,
         };
 
         info!(
@@ -344,15 +348,30 @@ impl Aml for PciDsmMethod {
 impl Aml for PciSegment {
     fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
         let mut pci_dsdt_inner_data: Vec<&dyn Aml> = Vec::new();
-        let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
+        // A CXL host bridge remains compatible with a PCIe one, and is
+        // matched against its CEDT host bridge structure through its UID.
+        let pcie_hid = aml::EISAName::new("PNP0A08");
+        let pci_hid = aml::EISAName::new("PNP0A03");
+        let cxl_cid = aml::Package::new(vec![&pcie_hid, &pci_hid]);
+        let (hid, cid, uid) = if self.cxl_rcrb_address.is_some() {
+            (
+                aml::Name::new("_HID".into(), &"ACPI0016"),
+                aml::Name::new("_CID".into(), &cxl_cid),
+                aml::Name::new("_UID".into(), &self.id),
+            )
+        } else {
+            (
+                aml::Name::new("_HID".into(), &pcie_hid),
+                aml::Name::new("_CID".into(), &pci_hid),
+                aml::Name::new("_UID".into(), &aml::ZERO),
+            )
+        };
         pci_dsdt_inner_data.push(&hid);
-        let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
         pci_dsdt_inner_data.push(&cid);
         let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
         pci_dsdt_inner_data.push(&adr);
         let seg = aml::Name::new("_SEG".into(), &self.id);
         pci_dsdt_inner_data.push(&seg);
-        let uid = aml::Name::new("_UID".into(), &aml::ZERO);
         pci_dsdt_inner_data.push(&uid);
         let cca = aml::Name::new("_CCA".into(), &aml::ONE);
         pci_dsdt_inner_data.push(&cca);
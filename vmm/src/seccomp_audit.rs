@@ -0,0 +1,311 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit mode of the seccomp filters.
+//!
+//! Instead of killing the VMM, the system calls denied by the filter of the
+//! VMM thread are reported to a dedicated thread through
+//! SECCOMP_RET_USER_NOTIF. The thread logs the name of the calling thread,
+//! the system call number and a hash of its arguments, then makes the system
+//! call fail with EPERM. This allows for tighter filters to be built from
+//! real workloads.
+//!
+//! The filters of the other threads are given SECCOMP_RET_LOG, for the kernel
+//! to log the system calls they deny without failing them. As the kernel
+//! applies the most restrictive action of the filters a thread is subject to,
+//! the threads spawned by the VMM thread still have the system calls denied
+//! by the filter of the VMM thread reported and failed.
+
+use seccompiler::{BpfProgram, BpfProgramRef, Error};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_iowr_nr};
+
+// See include/uapi/linux/seccomp.h and include/uapi/linux/filter.h in the
+// kernel code.
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: libc::c_ulong = 1 << 3;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+#[cfg(test)]
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_IOC_MAGIC: u32 = b'!' as u32;
+const BPF_RET_K: u16 = 0x06;
+
+#[repr(C)]
+#[derive(Default)]
+struct SeccompData {
+    nr: i32,
+    arch: u32,
+    instruction_pointer: u64,
+    args: [u64; 6],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: SeccompData,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+ioctl_iowr_nr!(SECCOMP_IOCTL_NOTIF_RECV, SECCOMP_IOC_MAGIC, 0, SeccompNotif);
+ioctl_iowr_nr!(
+    SECCOMP_IOCTL_NOTIF_SEND,
+    SECCOMP_IOC_MAGIC,
+    1,
+    SeccompNotifResp
+);
+
+static SECCOMP_AUDIT: OnceLock<SeccompAudit> = OnceLock::new();
+
+struct SeccompAudit {
+    // Listeners waiting to be handed over to the audit thread
+    pending: Mutex<Vec<File>>,
+    pending_evt: EventFd,
+}
+
+/// Starts the thread reporting the system calls denied by the filters. It
+/// must be started before any filter is applied, so that it is not subject to
+/// any of them.
+pub fn start() -> io::Result<()> {
+    let audit = SeccompAudit {
+        pending: Mutex::new(Vec::new()),
+        pending_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+    };
+    if SECCOMP_AUDIT.set(audit).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Seccomp audit already started",
+        ));
+    }
+
+    thread::Builder::new()
+        .name("seccomp-audit".to_string())
+        .spawn(|| {
+            if let Err(e) = SECCOMP_AUDIT.get().unwrap().run() {
+                // The audited threads would wait forever otherwise.
+                error!("Error auditing seccomp notifications: {}", e);
+                std::process::abort();
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Turns the denials of a filter into notifications to the audit thread.
+pub(crate) fn notify_on_mismatch(mut bpf_filter: BpfProgram) -> BpfProgram {
+    for insn in bpf_filter.iter_mut() {
+        if insn.code == BPF_RET_K && insn.k == SECCOMP_RET_TRAP {
+            insn.k = SECCOMP_RET_USER_NOTIF;
+        }
+    }
+    bpf_filter
+}
+
+/// Applies a filter turned by `notify_on_mismatch()` to the calling thread,
+/// handing its notifications over to the audit thread.
+pub fn apply_filter(bpf_filter: BpfProgramRef) -> Result<(), Error> {
+    let audit = SECCOMP_AUDIT.get().ok_or_else(|| {
+        Error::Seccomp(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Seccomp audit not started",
+        ))
+    })?;
+
+    let listener = apply_filter_with_listener(bpf_filter).map_err(Error::Seccomp)?;
+    audit.pending.lock().unwrap().push(listener);
+    audit.pending_evt.write(1).map_err(Error::Seccomp)
+}
+
+fn apply_filter_with_listener(bpf_filter: BpfProgramRef) -> io::Result<File> {
+    // SAFETY: FFI call with valid arguments
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let prog = libc::sock_fprog {
+        len: bpf_filter.len() as u16,
+        filter: bpf_filter.as_ptr() as *mut libc::sock_filter,
+    };
+    // SAFETY: prog describes a valid BPF program, which outlives the call.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &prog,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a newly created listener owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+impl SeccompAudit {
+    fn run(&self) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        // SAFETY: epoll_fd is a valid file descriptor owned by nobody else.
+        let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pending_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, self.pending_evt.as_raw_fd() as u64),
+        )?;
+
+        let mut listeners: HashMap<RawFd, File> = HashMap::new();
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 16];
+        loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                let fd = event.data as RawFd;
+                if fd == self.pending_evt.as_raw_fd() {
+                    let _ = self.pending_evt.read();
+                    for listener in self.pending.lock().unwrap().drain(..) {
+                        epoll::ctl(
+                            epoll_fd,
+                            epoll::ControlOptions::EPOLL_CTL_ADD,
+                            listener.as_raw_fd(),
+                            epoll::Event::new(epoll::Events::EPOLLIN, listener.as_raw_fd() as u64),
+                        )?;
+                        listeners.insert(listener.as_raw_fd(), listener);
+                    }
+                } else if event.events & libc::EPOLLIN as u32 != 0 {
+                    if let Some(listener) = listeners.get(&fd) {
+                        Self::audit(listener);
+                    }
+                } else {
+                    // All the threads subject to the filter are gone.
+                    listeners.remove(&fd);
+                }
+            }
+        }
+    }
+
+    fn audit(listener: &File) {
+        let mut notif = SeccompNotif::default();
+        // SAFETY: notif is a zeroed seccomp_notif structure, as expected.
+        let ret = unsafe { ioctl_with_mut_ref(listener, SECCOMP_IOCTL_NOTIF_RECV(), &mut notif) };
+        if ret < 0 {
+            // The calling thread was interrupted in the meantime.
+            return;
+        }
+
+        let thread_name = fs::read_to_string(format!("/proc/self/task/{}/comm", notif.pid))
+            .map(|name| name.trim_end().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let mut hasher = DefaultHasher::new();
+        notif.data.args.hash(&mut hasher);
+        warn!(
+            "Seccomp audit: thread {} ({}) made denied syscall {} (arguments hash {:016x})",
+            thread_name,
+            notif.pid,
+            notif.data.nr,
+            hasher.finish()
+        );
+
+        let resp = SeccompNotifResp {
+            id: notif.id,
+            val: 0,
+            error: -libc::EPERM,
+            flags: 0,
+        };
+        // SAFETY: resp is a properly initialized seccomp_notif_resp structure.
+        let ret = unsafe { ioctl_with_ref(listener, SECCOMP_IOCTL_NOTIF_SEND(), &resp) };
+        if ret < 0 {
+            debug!(
+                "Error replying to seccomp notification: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seccomp_filters::{get_seccomp_filter, Thread};
+    use seccompiler::{sock_filter, SeccompAction};
+
+    fn notifies(insn: &sock_filter) -> bool {
+        insn.code == BPF_RET_K && insn.k == SECCOMP_RET_USER_NOTIF
+    }
+
+    #[test]
+    fn test_notify_on_mismatch() {
+        let ret = |k| sock_filter {
+            code: BPF_RET_K,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let allow = 0x7fff_0000;
+
+        let bpf_filter = notify_on_mismatch(vec![ret(allow), ret(SECCOMP_RET_TRAP)]);
+        assert_eq!(bpf_filter[0].k, allow);
+        assert!(!notifies(&bpf_filter[0]));
+        assert_eq!(bpf_filter[1].k, SECCOMP_RET_USER_NOTIF);
+        assert!(notifies(&bpf_filter[1]));
+    }
+
+    #[cfg(feature = "kvm")]
+    #[test]
+    fn test_audit_filters() {
+        let returns = |bpf_filter: &BpfProgram, k| {
+            bpf_filter
+                .iter()
+                .any(|insn| insn.code == BPF_RET_K && insn.k == k)
+        };
+
+        // The VMM thread reports the system calls it denies.
+        let vmm_filter = notify_on_mismatch(
+            get_seccomp_filter(
+                &SeccompAction::Trap,
+                Thread::Vmm { xdp: false },
+                hypervisor::HypervisorType::Kvm,
+            )
+            .unwrap(),
+        );
+        assert!(returns(&vmm_filter, SECCOMP_RET_USER_NOTIF));
+        assert!(!returns(&vmm_filter, SECCOMP_RET_TRAP));
+
+        // The other threads have them logged.
+        for thread in [Thread::HttpApi, Thread::Vcpu, Thread::SignalHandler] {
+            let bpf_filter =
+                get_seccomp_filter(&SeccompAction::Log, thread, hypervisor::HypervisorType::Kvm)
+                    .unwrap();
+            assert!(returns(&bpf_filter, SECCOMP_RET_LOG));
+            assert!(!returns(&bpf_filter, SECCOMP_RET_TRAP));
+            assert!(!returns(&bpf_filter, SECCOMP_RET_USER_NOTIF));
+        }
+    }
+}
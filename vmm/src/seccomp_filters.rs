@@ -17,8 +17,14 @@ pub enum Thread {
     EventMonitor,
     SignalHandler,
     Vcpu,
-    Vmm,
+    Vmm {
+        // Whether XDP network devices can be created from the VMM thread
+        xdp: bool,
+    },
     PtyForeground,
+    LazyRestore,
+    VmbusRelay,
+    Vnc,
 }
 
 /// Shorthand for chaining `SeccompCondition`s with the `and` operator  in a `SeccompRule`.
@@ -74,6 +80,9 @@ const SIOCSIFMTU: u64 = 0x8922;
 const SIOCSIFHWADDR: u64 = 0x8924;
 const SIOCSIFNETMASK: u64 = 0x891c;
 
+// See include/linux/socket.h in the kernel code.
+const AF_XDP: u64 = 44;
+
 // See include/uapi/linux/vfio.h in the kernel code.
 const VFIO_GET_API_VERSION: u64 = 0x3b64;
 const VFIO_CHECK_EXTENSION: u64 = 0x3b65;
@@ -90,18 +99,22 @@ const VFIO_DEVICE_RESET: u64 = 0x3b6f;
 const VFIO_IOMMU_MAP_DMA: u64 = 0x3b71;
 const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 const VFIO_DEVICE_IOEVENTFD: u64 = 0x3b74;
+const VFIO_DEVICE_FEATURE: u64 = 0x3b75;
 
 // See include/uapi/linux/vhost.h in the kernel code
 const VHOST_GET_FEATURES: u64 = 0x8008af00;
 const VHOST_SET_FEATURES: u64 = 0x4008af00;
 const VHOST_SET_OWNER: u64 = 0xaf01;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008af03;
 const VHOST_SET_VRING_NUM: u64 = 0x4008af10;
 const VHOST_SET_VRING_ADDR: u64 = 0x4028af11;
 const VHOST_SET_VRING_BASE: u64 = 0x4008af12;
+const VHOST_GET_VRING_BASE: u64 = 0xc008af12;
 const VHOST_SET_VRING_KICK: u64 = 0x4008af20;
 const VHOST_SET_VRING_CALL: u64 = 0x4008af21;
 const VHOST_SET_BACKEND_FEATURES: u64 = 0x4008af25;
 const VHOST_GET_BACKEND_FEATURES: u64 = 0x8008af26;
+const VHOST_NET_SET_BACKEND: u64 = 0x4008af30;
 const VHOST_VDPA_GET_DEVICE_ID: u64 = 0x8004af70;
 const VHOST_VDPA_GET_STATUS: u64 = 0x8001af71;
 const VHOST_VDPA_SET_STATUS: u64 = 0x4001af72;
@@ -113,6 +126,32 @@ const VHOST_VDPA_SET_CONFIG_CALL: u64 = 0x4004af77;
 const VHOST_VDPA_GET_IOVA_RANGE: u64 = 0x8010af78;
 const VHOST_VDPA_GET_CONFIG_SIZE: u64 = 0x8004af79;
 const VHOST_VDPA_SUSPEND: u64 = 0xaf7d;
+const VHOST_VDPA_RESUME: u64 = 0xaf7e;
+const VHOST_VDPA_GET_VQS_COUNT: u64 = 0x8004af80;
+
+// See include/uapi/linux/usbdevice_fs.h in the kernel code
+const USBDEVFS_CONTROL: u64 = 0xc018_5500;
+const USBDEVFS_SETINTERFACE: u64 = 0x8008_5504;
+const USBDEVFS_SETCONFIGURATION: u64 = 0x8004_5505;
+const USBDEVFS_SUBMITURB: u64 = 0x8038_550a;
+const USBDEVFS_DISCARDURB: u64 = 0x550b;
+const USBDEVFS_REAPURBNDELAY: u64 = 0x4008_550d;
+const USBDEVFS_RELEASEINTERFACE: u64 = 0x8004_5510;
+const USBDEVFS_IOCTL: u64 = 0xc010_5512;
+const USBDEVFS_RESET: u64 = 0x5514;
+const USBDEVFS_CLEAR_HALT: u64 = 0x8004_5515;
+const USBDEVFS_DISCONNECT_CLAIM: u64 = 0x8108_551b;
+const USBDEVFS_GET_SPEED: u64 = 0x551f;
+
+// See include/uapi/linux/i2c-dev.h in the kernel code
+const I2C_FUNCS: u64 = 0x0705;
+
+// See include/uapi/linux/userfaultfd.h in the kernel code
+const UFFDIO_API: u64 = 0xc018_aa3f;
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
+const UFFDIO_UNREGISTER: u64 = 0x8010_aa01;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
+const UFFDIO_ZEROPAGE: u64 = 0xc020_aa04;
 
 // See include/uapi/linux/kvm.h in the kernel code.
 #[cfg(feature = "kvm")]
@@ -146,6 +185,10 @@ mod kvm {
     pub const KVM_GET_REG_LIST: u64 = 0xc008_aeb0;
     pub const KVM_MEMORY_ENCRYPT_OP: u64 = 0xc008_aeba;
     pub const KVM_NMI: u64 = 0xae9a;
+    pub const KVM_RESET_DIRTY_RINGS: u64 = 0xaec7;
+    pub const KVM_SET_USER_MEMORY_REGION2: u64 = 0x40a0_ae49;
+    pub const KVM_SET_MEMORY_ATTRIBUTES: u64 = 0x4020_aed2;
+    pub const KVM_CREATE_GUEST_MEMFD: u64 = 0xc040_aed4;
 }
 
 #[cfg(feature = "kvm")]
@@ -262,6 +305,14 @@ fn create_vmm_ioctl_seccomp_rule_common_kvm() -> Result<Vec<SeccompRule>, Backen
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_USER_MEMORY_REGION,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_VCPU_EVENTS,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_NMI)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_RESET_DIRTY_RINGS)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_CREATE_GUEST_MEMFD)?],
+        and![Cond::new(
+            1,
+            ArgLen::Dword,
+            Eq,
+            KVM_SET_USER_MEMORY_REGION2
+        )?],
     ])
 }
 
@@ -327,16 +378,20 @@ fn create_vmm_ioctl_seccomp_rule_common(
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_IOMMU_MAP_DMA)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_IOMMU_UNMAP_DMA)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_DEVICE_IOEVENTFD)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VFIO_DEVICE_FEATURE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_GET_FEATURES)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_FEATURES)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_OWNER)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_MEM_TABLE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_VRING_NUM)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_VRING_ADDR)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VHOST_GET_VRING_BASE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_VRING_KICK)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_VRING_CALL)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_SET_BACKEND_FEATURES)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_GET_BACKEND_FEATURES)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VHOST_NET_SET_BACKEND)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_DEVICE_ID)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_STATUS)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_SET_STATUS)?],
@@ -353,6 +408,23 @@ fn create_vmm_ioctl_seccomp_rule_common(
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_IOVA_RANGE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_CONFIG_SIZE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_SUSPEND)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_RESUME)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_VQS_COUNT)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_CONTROL)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SETINTERFACE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SETCONFIGURATION)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SUBMITURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCARDURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_REAPURBNDELAY)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_RELEASEINTERFACE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_IOCTL)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_RESET)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_CLEAR_HALT)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCONNECT_CLAIM)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_GET_SPEED)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, I2C_FUNCS)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, UFFDIO_API)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, UFFDIO_REGISTER)?],
     ];
 
     let hypervisor_rules = create_vmm_ioctl_seccomp_rule_hypervisor(hypervisor_type)?;
@@ -534,8 +606,14 @@ fn pty_foreground_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, Backend
 // function.
 fn vmm_thread_rules(
     hypervisor_type: HypervisorType,
+    xdp: bool,
 ) -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
-    Ok(vec![
+    let mut socket_rules = or![
+        and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_UNIX as u64)?],
+        and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_INET as u64)?],
+        and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_VSOCK as u64)?],
+    ];
+    let mut rules = vec![
         (libc::SYS_accept4, vec![]),
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_access, vec![]),
@@ -574,6 +652,7 @@ fn vmm_thread_rules(
         (libc::SYS_getpgrp, vec![]),
         (libc::SYS_getpid, vec![]),
         (libc::SYS_getrandom, vec![]),
+        (libc::SYS_getsockopt, vec![]),
         (libc::SYS_gettid, vec![]),
         (libc::SYS_gettimeofday, vec![]),
         (libc::SYS_getuid, vec![]),
@@ -598,6 +677,14 @@ fn vmm_thread_rules(
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mbind, vec![]),
         (libc::SYS_memfd_create, vec![]),
+        // Guest RAM is locked when requested, as the VM is created.
+        (libc::SYS_mlock2, vec![]),
+        // The VM cgroup hierarchy is created when the VM is, which can be
+        // long after the filter has been applied if the VM comes through
+        // the API. Landlock, when enabled, restricts where it can happen.
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_mkdir, vec![]),
+        (libc::SYS_mkdirat, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_mremap, vec![]),
@@ -607,6 +694,7 @@ fn vmm_thread_rules(
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_open, vec![]),
         (libc::SYS_openat, vec![]),
+        (libc::SYS_pidfd_send_signal, vec![]),
         (libc::SYS_pipe2, vec![]),
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_poll, vec![]),
@@ -647,13 +735,6 @@ fn vmm_thread_rules(
         (libc::SYS_setsockopt, vec![]),
         (libc::SYS_shutdown, vec![]),
         (libc::SYS_sigaltstack, vec![]),
-        (
-            libc::SYS_socket,
-            or![
-                and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_UNIX as u64)?],
-                and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_INET as u64)?],
-            ],
-        ),
         (libc::SYS_socketpair, vec![]),
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_stat, vec![]),
@@ -671,10 +752,21 @@ fn vmm_thread_rules(
         (libc::SYS_unlink, vec![]),
         #[cfg(target_arch = "aarch64")]
         (libc::SYS_unlinkat, vec![]),
+        (libc::SYS_userfaultfd, vec![]),
         (libc::SYS_wait4, vec![]),
         (libc::SYS_write, vec![]),
         (libc::SYS_writev, vec![]),
-    ])
+    ];
+
+    // Loading the XDP program and setting up the AF_XDP sockets of the XDP
+    // network devices is only allowed if such devices are expected.
+    if xdp {
+        rules.push((libc::SYS_bpf, vec![]));
+        socket_rules.push(and![Cond::new(0, ArgLen::Dword, Eq, AF_XDP)?]);
+    }
+    rules.push((libc::SYS_socket, socket_rules));
+
+    Ok(rules)
 }
 
 #[cfg(feature = "kvm")]
@@ -688,6 +780,15 @@ fn create_vcpu_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, BackendError
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_USER_MEMORY_REGION,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_RUN,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_NMI)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_RESET_DIRTY_RINGS)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_CREATE_GUEST_MEMFD)?],
+        and![Cond::new(
+            1,
+            ArgLen::Dword,
+            Eq,
+            KVM_SET_USER_MEMORY_REGION2
+        )?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_MEMORY_ATTRIBUTES)?],
     ])
 }
 
@@ -740,6 +841,7 @@ fn create_vcpu_ioctl_seccomp_rule(
 ) -> Result<Vec<SeccompRule>, BackendError> {
     let mut rules = or![
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_DEVICE_SET_IRQS)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, VFIO_DEVICE_FEATURE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_GROUP_UNSET_CONTAINER)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_IOMMU_UNMAP_DMA)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_SET_STATUS)?],
@@ -751,6 +853,15 @@ fn create_vcpu_ioctl_seccomp_rule(
             Eq,
             VHOST_VDPA_SET_VRING_ENABLE
         )?],
+        // USB transfers and requests from the guest
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_CONTROL)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SETINTERFACE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SETCONFIGURATION)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SUBMITURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCARDURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_RESET)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_CLEAR_HALT)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCONNECT_CLAIM)?],
     ];
 
     let hypervisor_rules = create_vcpu_ioctl_seccomp_rule_hypervisor(hypervisor_type)?;
@@ -836,6 +947,7 @@ fn http_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
         (libc::SYS_landlock_create_ruleset, vec![]),
         (libc::SYS_landlock_restrict_self, vec![]),
         (libc::SYS_madvise, vec![]),
+        (libc::SYS_mlock2, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_munmap, vec![]),
@@ -865,6 +977,7 @@ fn dbus_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
         (libc::SYS_futex, vec![]),
         (libc::SYS_getrandom, vec![]),
         (libc::SYS_madvise, vec![]),
+        (libc::SYS_mlock2, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_munmap, vec![]),
@@ -901,6 +1014,123 @@ fn event_monitor_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendE
     ])
 }
 
+fn create_lazy_restore_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, BackendError> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::Dword, Eq, UFFDIO_UNREGISTER)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, UFFDIO_COPY)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, UFFDIO_ZEROPAGE)?],
+    ])
+}
+
+fn lazy_restore_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_create1, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_ioctl, create_lazy_restore_ioctl_seccomp_rule()?),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_pread64, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+        #[cfg(debug_assertions)]
+        (libc::SYS_fcntl, vec![]),
+    ])
+}
+
+// The VMBus relay thread delivers the messages and events of the backend to
+// the guest, which is only possible with MSHV.
+fn vmbus_relay_thread_rules(
+    hypervisor_type: HypervisorType,
+) -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    #[allow(unused_mut)]
+    let mut rules = vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_clock_nanosleep, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_nanosleep, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+        #[cfg(debug_assertions)]
+        (libc::SYS_fcntl, vec![]),
+    ];
+
+    #[cfg(feature = "mshv")]
+    if matches!(hypervisor_type, HypervisorType::Mshv) {
+        rules.push((
+            libc::SYS_ioctl,
+            or![and![Cond::new(1, ArgLen::Dword, Eq, MSHV_ROOT_HVCALL())?]],
+        ));
+    }
+    #[cfg(not(feature = "mshv"))]
+    let _ = hypervisor_type;
+
+    Ok(rules)
+}
+
+// The VNC server thread accepts the clients, each one being served by a
+// thread spawned from it.
+fn vnc_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_accept4, vec![]),
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_clock_gettime, vec![]),
+        (libc::SYS_clone, vec![]),
+        (libc::SYS_clone3, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_create1, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_mprotect, vec![]),
+        (libc::SYS_mremap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_prctl, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_recvfrom, vec![]),
+        // musl is missing this constant
+        // (libc::SYS_rseq, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (334, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (293, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sendto, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+        (libc::SYS_setsockopt, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+        #[cfg(debug_assertions)]
+        (libc::SYS_fcntl, vec![]),
+    ])
+}
+
 fn get_seccomp_rules(
     thread_type: Thread,
     hypervisor_type: HypervisorType,
@@ -912,8 +1142,11 @@ fn get_seccomp_rules(
         Thread::EventMonitor => Ok(event_monitor_thread_rules()?),
         Thread::SignalHandler => Ok(signal_handler_thread_rules()?),
         Thread::Vcpu => Ok(vcpu_thread_rules(hypervisor_type)?),
-        Thread::Vmm => Ok(vmm_thread_rules(hypervisor_type)?),
+        Thread::Vmm { xdp } => Ok(vmm_thread_rules(hypervisor_type, xdp)?),
         Thread::PtyForeground => Ok(pty_foreground_thread_rules()?),
+        Thread::LazyRestore => Ok(lazy_restore_thread_rules()?),
+        Thread::VmbusRelay => Ok(vmbus_relay_thread_rules(hypervisor_type)?),
+        Thread::Vnc => Ok(vnc_thread_rules()?),
     }
 }
 
@@ -949,3 +1182,45 @@ pub fn get_seccomp_filter(
         .map_err(Error::Backend),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "kvm")]
+    fn allows(thread_type: Thread, hypervisor_type: HypervisorType, syscall: i64) -> bool {
+        get_seccomp_rules(thread_type, hypervisor_type)
+            .unwrap()
+            .iter()
+            .any(|(nr, _)| *nr == syscall)
+    }
+
+    #[cfg(feature = "kvm")]
+    #[test]
+    fn test_mlock2_allowed() {
+        let hypervisor_type = HypervisorType::Kvm;
+        for xdp in [false, true] {
+            assert!(allows(
+                Thread::Vmm { xdp },
+                hypervisor_type,
+                libc::SYS_mlock2
+            ));
+        }
+        assert!(allows(Thread::HttpApi, hypervisor_type, libc::SYS_mlock2));
+        #[cfg(feature = "dbus_api")]
+        assert!(allows(Thread::DBusApi, hypervisor_type, libc::SYS_mlock2));
+        // Only the threads creating VMs or serving their API need it.
+        assert!(!allows(Thread::Vcpu, hypervisor_type, libc::SYS_mlock2));
+
+        // The filters still build with the rule.
+        for seccomp_action in [SeccompAction::Trap, SeccompAction::Log] {
+            assert!(get_seccomp_filter(
+                &seccomp_action,
+                Thread::Vmm { xdp: false },
+                hypervisor_type
+            )
+            .is_ok());
+            assert!(get_seccomp_filter(&seccomp_action, Thread::HttpApi, hypervisor_type).is_ok());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Linux Security Module labeling of the VMM and of its backends.
+//!
+//! Giving each VM its own SELinux context (typically a dedicated MCS category
+//! pair) or AppArmor profile confines the VMM so that a compromised VM cannot
+//! access the resources of another one, sVirt-style.
+//!
+//! Labels are applied by writing to the `/proc/thread-self/attr` interface:
+//! - `current` transitions the calling thread, the threads it spawns later on
+//!   (vCPUs, device workers) inheriting its label;
+//! - `exec` sets the label the next `execve()` transitions to, which is used
+//!   for the spawned device backends;
+//! - `fscreate` and `sockcreate` (SELinux only) set the label of the files and
+//!   sockets created by the calling thread, such as the memory backing files,
+//!   the UNIX sockets and the TAP devices.
+
+use crate::vm_config::SecurityLabelConfig;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::io;
+use thiserror::Error;
+
+const ATTR_CURRENT: &CStr = c"/proc/thread-self/attr/current";
+const ATTR_EXEC: &CStr = c"/proc/thread-self/attr/exec";
+const ATTR_FSCREATE: &CStr = c"/proc/thread-self/attr/fscreate";
+const ATTR_SOCKCREATE: &CStr = c"/proc/thread-self/attr/sockcreate";
+
+#[derive(Debug, Error)]
+pub enum SecurityLabelError {
+    #[error("Error writing {1:?} to {0:?}: {2}")]
+    Write(&'static CStr, String, #[source] io::Error),
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum Attr {
+    Current,
+    Exec,
+    FsCreate,
+    SockCreate,
+}
+
+impl Attr {
+    fn path(&self) -> &'static CStr {
+        match self {
+            Attr::Current => ATTR_CURRENT,
+            Attr::Exec => ATTR_EXEC,
+            Attr::FsCreate => ATTR_FSCREATE,
+            Attr::SockCreate => ATTR_SOCKCREATE,
+        }
+    }
+}
+
+/// A single value to be written to one of the `/proc/thread-self/attr` files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SecurityLabel {
+    attr: Attr,
+    value: Vec<u8>,
+}
+
+impl SecurityLabel {
+    fn new(attr: Attr, value: String) -> Self {
+        SecurityLabel {
+            attr,
+            value: value.into_bytes(),
+        }
+    }
+
+    /// Apply the label to the calling thread. Only async-signal-safe
+    /// functions are called, making it usable between fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        // SAFETY: FFI call with a valid NUL terminated path
+        let fd = unsafe { libc::open(self.attr.path().as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: FFI call with a valid fd and buffer
+        let ret = unsafe { libc::write(fd, self.value.as_ptr() as *const _, self.value.len()) };
+        let ret = if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+
+        // SAFETY: fd is valid and owned here
+        unsafe { libc::close(fd) };
+
+        ret
+    }
+
+    fn error(&self, e: io::Error) -> SecurityLabelError {
+        SecurityLabelError::Write(
+            self.attr.path(),
+            String::from_utf8_lossy(&self.value).into_owned(),
+            e,
+        )
+    }
+}
+
+/// Labels applied to the VMM thread creating the VM. Resource labels are
+/// applied first, as transitioning the thread may prevent it from changing
+/// its own attributes afterwards.
+fn vmm_labels(config: &SecurityLabelConfig) -> Vec<SecurityLabel> {
+    let mut labels = Vec::new();
+
+    if let Some(context) = &config.selinux_file_context {
+        labels.push(SecurityLabel::new(Attr::FsCreate, context.clone()));
+        labels.push(SecurityLabel::new(Attr::SockCreate, context.clone()));
+    }
+    if let Some(context) = &config.selinux_context {
+        labels.push(SecurityLabel::new(Attr::Current, context.clone()));
+    }
+    if let Some(profile) = &config.apparmor_profile {
+        labels.push(SecurityLabel::new(
+            Attr::Current,
+            format!("changeprofile {profile}"),
+        ));
+    }
+
+    labels
+}
+
+/// Label the spawned device backends transition to when being executed.
+pub fn backend_label(config: &SecurityLabelConfig) -> Option<SecurityLabel> {
+    if let Some(context) = &config.selinux_backend_context {
+        Some(SecurityLabel::new(Attr::Exec, context.clone()))
+    } else {
+        config
+            .apparmor_backend_profile
+            .as_ref()
+            .map(|profile| SecurityLabel::new(Attr::Exec, format!("exec {profile}")))
+    }
+}
+
+/// Apply the VMM labels to the calling thread.
+pub fn apply_vmm_labels(config: &SecurityLabelConfig) -> Result<(), SecurityLabelError> {
+    for label in vmm_labels(config) {
+        label.apply().map_err(|e| label.error(e))?;
+    }
+
+    Ok(())
+}
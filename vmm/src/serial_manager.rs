@@ -10,14 +10,15 @@ use devices::legacy::Pl011;
 use devices::legacy::Serial;
 use libc::EFD_NONBLOCK;
 use serial_buffer::SerialBuffer;
-use std::fs::File;
-use std::io::Read;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::net::Shutdown;
 use std::os::fd::RawFd;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::panic::AssertUnwindSafe;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{io, result, thread};
@@ -81,6 +82,10 @@ pub enum Error {
     /// Cannot duplicate file descriptor
     #[error("Error duplicating file descriptor: {0}")]
     DupFd(#[source] io::Error),
+
+    /// Cannot open the serial log file
+    #[error("Error opening serial log file: {0}")]
+    OpenLogFile(#[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -105,6 +110,107 @@ impl From<u64> for EpollDispatch {
     }
 }
 
+/// Last bytes written by the guest to the serial port.
+pub struct SerialCapture {
+    buffer: VecDeque<u8>,
+    size: usize,
+}
+
+impl SerialCapture {
+    fn push(&mut self, buf: &[u8]) {
+        let buf = &buf[buf.len().saturating_sub(self.size)..];
+        let overflow = (self.buffer.len() + buf.len()).saturating_sub(self.size);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(buf);
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.iter().copied().collect()
+    }
+}
+
+/// Copies of the serial output, made whatever the serial port is attached
+/// to, including nothing at all.
+#[derive(Clone, Default)]
+pub struct SerialOutputCopies {
+    log_file: Option<Arc<File>>,
+    capture: Option<Arc<Mutex<SerialCapture>>>,
+}
+
+impl SerialOutputCopies {
+    pub fn new(log_file: Option<&Path>, capture_size: Option<u64>) -> Result<Self> {
+        let log_file = log_file
+            .map(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(Error::OpenLogFile)
+            })
+            .transpose()?
+            .map(Arc::new);
+        let capture = capture_size.map(|size| {
+            Arc::new(Mutex::new(SerialCapture {
+                buffer: VecDeque::with_capacity(size as usize),
+                size: size as usize,
+            }))
+        });
+
+        Ok(SerialOutputCopies { log_file, capture })
+    }
+
+    pub fn capture(&self) -> Option<Arc<Mutex<SerialCapture>>> {
+        self.capture.clone()
+    }
+
+    /// Wraps the output of the serial port, if any, for the copies to be
+    /// made along with it.
+    pub fn wrap(&self, out: Option<Box<dyn Write + Send>>) -> Option<Box<dyn Write + Send>> {
+        if self.log_file.is_none() && self.capture.is_none() {
+            return out;
+        }
+
+        Some(Box::new(SerialTee {
+            out,
+            copies: self.clone(),
+        }))
+    }
+}
+
+struct SerialTee {
+    out: Option<Box<dyn Write + Send>>,
+    copies: SerialOutputCopies,
+}
+
+impl Write for SerialTee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = match self.out.as_mut() {
+            Some(out) => out.write(buf)?,
+            None => buf.len(),
+        };
+
+        if let Some(log_file) = &self.copies.log_file {
+            // The guest keeps on using the serial port if the log cannot be
+            // written.
+            if let Err(e) = log_file.as_ref().write_all(&buf[..count]) {
+                warn!("Error writing serial log: {}", e);
+            }
+        }
+        if let Some(capture) = &self.copies.capture {
+            capture.lock().unwrap().push(&buf[..count]);
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.out.as_mut() {
+            Some(out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
 pub struct SerialManager {
     #[cfg(target_arch = "x86_64")]
     serial: Arc<Mutex<Serial>>,
@@ -117,6 +223,7 @@ pub struct SerialManager {
     pty_write_out: Option<Arc<AtomicBool>>,
     mode: ConsoleOutputMode,
     socket_path: Option<PathBuf>,
+    copies: SerialOutputCopies,
 }
 
 impl SerialManager {
@@ -126,6 +233,7 @@ impl SerialManager {
         main_fd: Option<RawFd>,
         mode: ConsoleOutputMode,
         socket: Option<PathBuf>,
+        copies: SerialOutputCopies,
     ) -> Result<Option<Self>> {
         let mut socket_path: Option<PathBuf> = None;
 
@@ -216,7 +324,7 @@ impl SerialManager {
                 .as_ref()
                 .lock()
                 .unwrap()
-                .set_out(Some(Box::new(buffer)));
+                .set_out(copies.wrap(Some(Box::new(buffer))));
         }
 
         // Use 'File' to enforce closing on 'epoll_fd'
@@ -232,6 +340,7 @@ impl SerialManager {
             pty_write_out,
             mode,
             socket_path,
+            copies,
         }))
     }
 
@@ -278,6 +387,7 @@ impl SerialManager {
         };
         let mut reader: Option<UnixStream> = None;
         let mode = self.mode.clone();
+        let copies = self.copies.clone();
 
         // In case of PTY, we want to be able to detect a connection on the
         // other end of the PTY. This is done by detecting there's no event
@@ -360,7 +470,10 @@ impl SerialManager {
                                         ),
                                     )
                                     .map_err(Error::Epoll)?;
-                                    serial.lock().unwrap().set_out(Some(Box::new(writer)));
+                                    serial
+                                        .lock()
+                                        .unwrap()
+                                        .set_out(copies.wrap(Some(Box::new(writer))));
                                 }
                                 EpollDispatch::File => {
                                     if event.events & libc::EPOLLIN as u32 != 0 {
@@ -381,7 +494,7 @@ impl SerialManager {
                                                             .as_ref()
                                                             .lock()
                                                             .unwrap()
-                                                            .set_out(None);
+                                                            .set_out(copies.wrap(None));
                                                     }
                                                     count
                                                 } else {
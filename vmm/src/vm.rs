@@ -11,9 +11,14 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::api::VmClockResponse;
+use crate::cgroup::{CgroupError, CgroupManager, CgroupThreadGroup};
+#[cfg(target_arch = "x86_64")]
+use crate::config::MemoryConfig;
 use crate::config::{
-    add_to_config, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig,
-    UserDeviceConfig, ValidationError, VdpaConfig, VmConfig, VsockConfig,
+    add_to_config, CgroupResources, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig,
+    PmemConfig, UsbDeviceConfig, UserDeviceConfig, ValidationError, VdpaConfig, VmConfig,
+    VsockConfig,
 };
 use crate::config::{NumaConfig, PayloadConfig};
 use crate::console_devices::{ConsoleDeviceError, ConsoleInfo};
@@ -30,13 +35,18 @@ use crate::gdb::{Debuggable, DebuggableError, GdbRequestPayload, GdbResponsePayl
 use crate::igvm::igvm_loader;
 use crate::landlock::LandlockError;
 use crate::memory_manager::{
-    Error as MemoryManagerError, MemoryManager, MemoryManagerSnapshotData,
+    Error as MemoryManagerError, MemoryManager, MemoryManagerSnapshotData, MemoryRestoreMode,
 };
-#[cfg(target_arch = "x86_64")]
 use crate::migration::get_vm_snapshot;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::migration::url_to_file;
 use crate::migration::{url_to_path, SNAPSHOT_CONFIG_FILE, SNAPSHOT_STATE_FILE};
+use crate::numa_placement::{self, NumaPlacementError};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::security_label::SecurityLabelError;
+#[cfg(target_arch = "x86_64")]
+use crate::vmbus_relay::{self, VmbusRelay};
+use crate::vnc::{self, VncServer};
 use crate::GuestMemoryMmap;
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
@@ -82,6 +92,7 @@ use std::mem::size_of;
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 use std::{result, str, thread};
@@ -126,6 +137,18 @@ pub enum Error {
     #[error("Failed to apply landlock config during vm_create: {0}")]
     ApplyLandlock(#[source] LandlockError),
 
+    #[error("Error configuring cgroup: {0}")]
+    Cgroup(#[source] CgroupError),
+
+    #[error("No cgroup configured for the VM")]
+    CgroupNotConfigured,
+
+    #[error("Error applying security labels: {0}")]
+    SecurityLabel(#[source] SecurityLabelError),
+
+    #[error("Error placing the guest NUMA nodes: {0}")]
+    NumaPlacement(#[source] NumaPlacementError),
+
     #[error("Cannot modify the kernel command line: {0}")]
     CmdLineInsertStr(#[source] linux_loader::cmdline::Error),
 
@@ -135,6 +158,9 @@ pub enum Error {
     #[error("Cannot configure system: {0}")]
     ConfigureSystem(#[source] arch::Error),
 
+    #[error("Cannot read OEM string file {0:?}: {1}")]
+    ReadOemStringFile(PathBuf, #[source] io::Error),
+
     #[cfg(target_arch = "aarch64")]
     #[error("Cannot enable interrupt controller: {0:?}")]
     EnableInterruptController(interrupt_controller::Error),
@@ -166,6 +192,24 @@ pub enum Error {
     #[error("VM is not running")]
     VmNotRunning,
 
+    #[error("Multi-VM mode is not enabled")]
+    MultiVmDisabled,
+
+    #[error("Invalid VM identifier: {0}")]
+    InvalidVmId(String),
+
+    #[error("A VM with identifier {0} already exists")]
+    VmIdInUse(String),
+
+    #[error("No VM with identifier {0}")]
+    UnknownVm(String),
+
+    #[error("Cannot create the VM: {0}")]
+    CreateVmInstance(#[source] io::Error),
+
+    #[error("Serial output capture is not enabled")]
+    SerialCaptureNotEnabled,
+
     #[error("Cannot clone EventFd: {0}")]
     EventFdClone(#[source] io::Error),
 
@@ -208,12 +252,30 @@ pub enum Error {
     #[error("Cannot send VM snapshot: {0}")]
     SnapshotSend(#[source] MigratableError),
 
+    #[error("Cannot manage snapshot tree: {0}")]
+    SnapshotTree(#[source] MigratableError),
+
     #[error("Invalid restore source URL")]
     InvalidRestoreSourceUrl,
 
     #[error("Failed to validate config: {0}")]
     ConfigValidation(#[source] ValidationError),
 
+    #[error("Cannot get the number of queues of the vDPA device: {0}")]
+    VdpaNumQueues(#[source] io::Error),
+
+    #[error("XDP network devices are not allowed by the seccomp filter of the VMM")]
+    XdpNotAllowed,
+
+    #[error("Only the VM of the VMM can use the terminal in multi-VM mode")]
+    TtyNotAllowed,
+
+    #[error("Cgroups cannot be used in multi-VM mode")]
+    CgroupNotAllowed,
+
+    #[error("Cannot spawn the device backends: {0}")]
+    DeviceBackend(#[source] crate::device_backend::DeviceBackendError),
+
     #[error("Too many virtio-vsock devices")]
     TooManyVsockDevices,
 
@@ -328,8 +390,28 @@ pub enum Error {
     #[error("Error resuming the VM: {0}")]
     ResumeVm(#[source] hypervisor::HypervisorVmError),
 
+    #[cfg(target_arch = "x86_64")]
+    #[error("Cannot create a VM with guest_memfd private memory: {0}")]
+    CreatePrivateVm(#[source] hypervisor::HypervisorError),
+
     #[error("Error creating console devices")]
     CreateConsoleDevices(ConsoleDeviceError),
+
+    #[error("Error getting the VM clock: {0}")]
+    GetClock(#[source] hypervisor::HypervisorVmError),
+
+    #[error("Reading the VM clock is not supported on this architecture")]
+    ClockUnsupported,
+
+    #[error("Resynchronizing the guest clock is not supported with this hypervisor")]
+    ClockResyncUnsupported,
+
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error setting up the VMBus relay: {0}")]
+    VmbusRelay(#[source] vmbus_relay::Error),
+
+    #[error("Error setting up the VNC server: {0}")]
+    Vnc(#[source] vnc::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -385,6 +467,8 @@ struct VmOpsHandler {
     #[cfg(target_arch = "x86_64")]
     io_bus: Arc<Bus>,
     mmio_bus: Arc<Bus>,
+    #[cfg(target_arch = "x86_64")]
+    vmbus_relay: Option<Arc<VmbusRelay>>,
 }
 
 impl VmOps for VmOpsHandler {
@@ -447,6 +531,37 @@ impl VmOps for VmOpsHandler {
         };
         Ok(())
     }
+
+    #[cfg(target_arch = "x86_64")]
+    fn synic_post_message(
+        &self,
+        connection_id: u32,
+        message_type: u32,
+        payload: &[u8],
+    ) -> result::Result<(), HypervisorVmError> {
+        let vmbus_relay = self
+            .vmbus_relay
+            .as_ref()
+            .ok_or_else(|| HypervisorVmError::PostSynicMessage(anyhow!("No VMBus relay")))?;
+        vmbus_relay
+            .post_message(connection_id, message_type, payload)
+            .map_err(|e| HypervisorVmError::PostSynicMessage(e.into()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn synic_signal_event(
+        &self,
+        connection_id: u32,
+        flag: u16,
+    ) -> result::Result<(), HypervisorVmError> {
+        let vmbus_relay = self
+            .vmbus_relay
+            .as_ref()
+            .ok_or_else(|| HypervisorVmError::SignalSynicEvent(anyhow!("No VMBus relay")))?;
+        vmbus_relay
+            .signal_event(connection_id, flag)
+            .map_err(|e| HypervisorVmError::SignalSynicEvent(e.into()))
+    }
 }
 
 pub fn physical_bits(hypervisor: &Arc<dyn hypervisor::Hypervisor>, max_phys_bits: u8) -> u8 {
@@ -455,6 +570,14 @@ pub fn physical_bits(hypervisor: &Arc<dyn hypervisor::Hypervisor>, max_phys_bits
     cmp::min(host_phys_bits, max_phys_bits)
 }
 
+// Host wall clock time, in nanoseconds since the epoch
+fn realtime_ns() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
 pub struct Vm {
     #[cfg(feature = "tdx")]
     kernel: Option<File>,
@@ -470,11 +593,20 @@ pub struct Vm {
     vm: Arc<dyn hypervisor::Vm>,
     #[cfg(target_arch = "x86_64")]
     saved_clock: Option<hypervisor::ClockData>,
+    // Host wall clock time at which the guest clock was saved
+    saved_clock_realtime: Option<u64>,
+    // The guest entered the S3 sleep state, and must be woken up on resume
+    #[cfg(target_arch = "x86_64")]
+    suspended: bool,
     numa_nodes: NumaNodes,
     #[cfg_attr(any(not(feature = "kvm"), target_arch = "aarch64"), allow(dead_code))]
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     stop_on_boot: bool,
     load_payload_handle: Option<thread::JoinHandle<Result<EntryPoint>>>,
+    cgroup_manager: Option<Arc<CgroupManager>>,
+    #[cfg(target_arch = "x86_64")]
+    vmbus_relay: Option<Arc<VmbusRelay>>,
+    vnc_server: Option<VncServer>,
 }
 
 impl Vm {
@@ -487,6 +619,9 @@ impl Vm {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        panic_evt: EventFd,
+        hibernate_evt: EventFd,
+        suspend_evt: EventFd,
         #[cfg(feature = "guest_debug")] vm_debug_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
@@ -514,6 +649,16 @@ impl Vm {
 
         info!("Booting VM from config: {:?}", &config);
 
+        let cgroup_manager = config
+            .lock()
+            .unwrap()
+            .cgroup
+            .as_ref()
+            .map(CgroupManager::new)
+            .transpose()
+            .map_err(Error::Cgroup)?
+            .map(Arc::new);
+
         // Create NUMA nodes based on NumaConfig.
         let numa_nodes =
             Self::create_numa_nodes(config.lock().unwrap().numa.clone(), &memory_manager)?;
@@ -539,11 +684,25 @@ impl Vm {
         let io_bus = Arc::new(Bus::new());
         let mmio_bus = Arc::new(Bus::new());
 
+        // The hypercalls of the guest must be relayed before any vCPU runs.
+        #[cfg(target_arch = "x86_64")]
+        let vmbus_relay = config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|p| p.vmbus_relay.as_ref())
+            .map(|socket| VmbusRelay::new(socket, &vm))
+            .transpose()
+            .map_err(Error::VmbusRelay)?;
+
         let vm_ops: Arc<dyn VmOps> = Arc::new(VmOpsHandler {
             memory,
             #[cfg(target_arch = "x86_64")]
             io_bus: io_bus.clone(),
             mmio_bus: mmio_bus.clone(),
+            #[cfg(target_arch = "x86_64")]
+            vmbus_relay: vmbus_relay.clone(),
         });
 
         let cpus_config = { &config.lock().unwrap().cpus.clone() };
@@ -552,6 +711,7 @@ impl Vm {
             vm.clone(),
             exit_evt.try_clone().map_err(Error::EventFdClone)?,
             reset_evt.try_clone().map_err(Error::EventFdClone)?,
+            panic_evt.try_clone().map_err(Error::EventFdClone)?,
             #[cfg(feature = "guest_debug")]
             vm_debug_evt,
             &hypervisor,
@@ -565,6 +725,13 @@ impl Vm {
         )
         .map_err(Error::CpuManager)?;
 
+        if let Some(cgroup_manager) = &cgroup_manager {
+            cpu_manager
+                .lock()
+                .unwrap()
+                .set_cgroup_manager(cgroup_manager.clone());
+        }
+
         #[cfg(target_arch = "x86_64")]
         cpu_manager
             .lock()
@@ -635,6 +802,9 @@ impl Vm {
             cpu_manager.clone(),
             exit_evt.try_clone().map_err(Error::EventFdClone)?,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
             seccomp_action.clone(),
             numa_nodes.clone(),
             &activate_evt,
@@ -673,13 +843,19 @@ impl Vm {
             .transpose()
             .map_err(Error::InitramfsFile)?;
 
+        let vm_snapshot = snapshot
+            .as_ref()
+            .map(get_vm_snapshot)
+            .transpose()
+            .map_err(Error::Restore)?;
+        let saved_clock_realtime = vm_snapshot
+            .as_ref()
+            .and_then(|vm_snapshot| vm_snapshot.clock_realtime);
         #[cfg(target_arch = "x86_64")]
-        let saved_clock = if let Some(snapshot) = snapshot.as_ref() {
-            let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::Restore)?;
-            vm_snapshot.clock
-        } else {
-            None
-        };
+        let (saved_clock, suspended) = vm_snapshot
+            .as_ref()
+            .map(|vm_snapshot| (vm_snapshot.clock, vm_snapshot.suspended))
+            .unwrap_or_default();
 
         let vm_state = if snapshot.is_some() {
             VmState::Paused
@@ -687,23 +863,61 @@ impl Vm {
             VmState::Created
         };
 
+        let mut threads = Vec::with_capacity(1);
+        #[cfg(target_arch = "x86_64")]
+        if let Some(vmbus_relay) = &vmbus_relay {
+            let seccomp_filter = get_seccomp_filter(
+                seccomp_action,
+                Thread::VmbusRelay,
+                hypervisor.hypervisor_type(),
+            )
+            .map_err(Error::CreateSeccompFilter)?;
+            threads.push(
+                vmbus_relay
+                    .start(vm.clone(), seccomp_filter)
+                    .map_err(Error::VmbusRelay)?,
+            );
+        }
+
+        let vnc_config = config.lock().unwrap().vnc.clone();
+        let vnc_server = vnc_config
+            .zip(device_manager.lock().unwrap().vnc_devices())
+            .map(|(vnc_config, (display, keyboard, tablet))| {
+                VncServer::new(&vnc_config, display, keyboard, tablet)
+            })
+            .transpose()
+            .map_err(Error::Vnc)?;
+        if let Some(vnc_server) = &vnc_server {
+            let seccomp_filter =
+                get_seccomp_filter(seccomp_action, Thread::Vnc, hypervisor.hypervisor_type())
+                    .map_err(Error::CreateSeccompFilter)?;
+            threads.push(vnc_server.start(seccomp_filter).map_err(Error::Vnc)?);
+        }
+
         Ok(Vm {
             #[cfg(feature = "tdx")]
             kernel,
             initramfs,
             device_manager,
             config,
-            threads: Vec::with_capacity(1),
+            threads,
             state: RwLock::new(vm_state),
             cpu_manager,
             memory_manager,
             vm,
             #[cfg(target_arch = "x86_64")]
             saved_clock,
+            saved_clock_realtime,
+            #[cfg(target_arch = "x86_64")]
+            suspended,
             numa_nodes,
             hypervisor,
             stop_on_boot,
             load_payload_handle,
+            cgroup_manager,
+            #[cfg(target_arch = "x86_64")]
+            vmbus_relay,
+            vnc_server,
         })
     }
 
@@ -766,6 +980,22 @@ impl Vm {
                     }
                 }
 
+                if let Some(latencies) = &config.latencies {
+                    node.latencies = Self::numa_performance(
+                        configs,
+                        config,
+                        latencies.iter().map(|l| (l.destination, l.latency)),
+                    )?;
+                }
+
+                if let Some(bandwidths) = &config.bandwidths {
+                    node.bandwidths = Self::numa_performance(
+                        configs,
+                        config,
+                        bandwidths.iter().map(|b| (b.destination, b.bandwidth)),
+                    )?;
+                }
+
                 #[cfg(target_arch = "x86_64")]
                 if let Some(sgx_epc_sections) = &config.sgx_epc_sections {
                     if let Some(sgx_epc_region) = mm.sgx_epc_region() {
@@ -791,11 +1021,53 @@ impl Vm {
         Ok(numa_nodes)
     }
 
+    // Performance of the accesses from the CPUs of a NUMA node to the memory
+    // of the destination nodes, as reported in the HMAT.
+    fn numa_performance(
+        configs: &[NumaConfig],
+        config: &NumaConfig,
+        values: impl Iterator<Item = (u32, u64)>,
+    ) -> Result<BTreeMap<u32, u64>> {
+        if config.cpus.is_none() {
+            error!(
+                "NUMA node {} has no CPU to access memory from",
+                config.guest_numa_id
+            );
+            return Err(Error::InvalidNumaConfig);
+        }
+
+        let mut performance = BTreeMap::new();
+        for (dest, value) in values {
+            if !configs
+                .iter()
+                .any(|cfg| cfg.guest_numa_id == dest && cfg.memory_zones.is_some())
+            {
+                error!("Unknown or memoryless destination NUMA node {}", dest);
+                return Err(Error::InvalidNumaConfig);
+            }
+
+            if value == 0 {
+                error!("Invalid latency or bandwidth to NUMA node {}", dest);
+                return Err(Error::InvalidNumaConfig);
+            }
+
+            if performance.insert(dest, value).is_some() {
+                error!("Destination NUMA node {} has been already set", dest);
+                return Err(Error::InvalidNumaConfig);
+            }
+        }
+
+        Ok(performance)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         vm_config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        panic_evt: EventFd,
+        hibernate_evt: EventFd,
+        suspend_evt: EventFd,
         #[cfg(feature = "guest_debug")] vm_debug_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
@@ -806,11 +1078,20 @@ impl Vm {
         snapshot: Option<Snapshot>,
         source_url: Option<&str>,
         prefault: Option<bool>,
+        restore_mode: MemoryRestoreMode,
     ) -> Result<Self> {
         trace_scoped!("Vm::new");
 
         let timestamp = Instant::now();
 
+        // Resolve the placement before the vCPUs, memory zones and devices
+        // get created. The result is kept along with the configuration, so
+        // that the same placement is used across reboots, but it is left out
+        // of snapshots and migrations as it depends on the host.
+        if vm_config.lock().unwrap().numa_placement {
+            numa_placement::apply(&mut vm_config.lock().unwrap()).map_err(Error::NumaPlacement)?;
+        }
+
         #[cfg(feature = "tdx")]
         let tdx_enabled = if snapshot.is_some() {
             false
@@ -831,6 +1112,8 @@ impl Vm {
             tdx_enabled,
             #[cfg(feature = "sev_snp")]
             sev_snp_enabled,
+            #[cfg(target_arch = "x86_64")]
+            &vm_config.lock().unwrap().memory,
         )?;
 
         let phys_bits = physical_bits(&hypervisor, vm_config.lock().unwrap().cpus.max_phys_bits);
@@ -838,18 +1121,48 @@ impl Vm {
         let memory_manager = if let Some(snapshot) =
             snapshot_from_id(snapshot.as_ref(), MEMORY_MANAGER_SNAPSHOT_ID)
         {
-            MemoryManager::new_from_snapshot(
+            let memory_manager = MemoryManager::new_from_snapshot(
                 &snapshot,
                 vm.clone(),
                 &vm_config.lock().unwrap().memory.clone(),
                 source_url,
                 prefault.unwrap(),
+                restore_mode,
                 phys_bits,
             )
-            .map_err(Error::MemoryManager)?
+            .map_err(Error::MemoryManager)?;
+
+            // The page faults must be served before anything, including the
+            // restore of the devices, accesses the guest memory.
+            if restore_mode == MemoryRestoreMode::Lazy {
+                let seccomp_filter = get_seccomp_filter(
+                    seccomp_action,
+                    Thread::LazyRestore,
+                    hypervisor.hypervisor_type(),
+                )
+                .map_err(Error::CreateSeccompFilter)?;
+                memory_manager
+                    .lock()
+                    .unwrap()
+                    .start_lazy_restore(
+                        seccomp_filter,
+                        exit_evt.try_clone().map_err(Error::EventFdClone)?,
+                    )
+                    .map_err(Error::MemoryManager)?;
+            }
+
+            memory_manager
         } else {
             #[cfg(target_arch = "x86_64")]
             let sgx_epc_config = vm_config.lock().unwrap().sgx_epc.clone();
+            let cxl_memory_zones: Vec<String> = vm_config
+                .lock()
+                .unwrap()
+                .cxl
+                .iter()
+                .flatten()
+                .map(|cxl| cxl.memory_zone.clone())
+                .collect();
 
             MemoryManager::new(
                 vm.clone(),
@@ -860,6 +1173,7 @@ impl Vm {
                 tdx_enabled,
                 None,
                 None,
+                &cxl_memory_zones,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc_config,
             )
@@ -872,6 +1186,9 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            suspend_evt,
             #[cfg(feature = "guest_debug")]
             vm_debug_evt,
             seccomp_action,
@@ -889,28 +1206,47 @@ impl Vm {
         hypervisor: &Arc<dyn hypervisor::Hypervisor>,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         #[cfg(feature = "sev_snp")] sev_snp_enabled: bool,
+        #[cfg(target_arch = "x86_64")] memory_config: &MemoryConfig,
     ) -> Result<Arc<dyn hypervisor::Vm>> {
         hypervisor.check_required_extensions().unwrap();
 
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "tdx")] {
-                // Passing KVM_X86_TDX_VM: 1 if tdx_enabled is true
-                // Otherwise KVM_X86_LEGACY_VM: 0
-                // value of tdx_enabled is mapped to KVM_X86_TDX_VM or KVM_X86_LEGACY_VM
-                let vm = hypervisor
-                    .create_vm_with_type(u64::from(tdx_enabled))
-                    .unwrap();
-            } else if #[cfg(feature = "sev_snp")] {
-                // Passing SEV_SNP_ENABLED: 1 if sev_snp_enabled is true
-                // Otherwise SEV_SNP_DISABLED: 0
-                // value of sev_snp_enabled is mapped to SEV_SNP_ENABLED for true or SEV_SNP_DISABLED for false
-                let vm = hypervisor
-                    .create_vm_with_type(u64::from(sev_snp_enabled))
-                    .unwrap();
-            } else {
-                let vm = hypervisor.create_vm().unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let private_vm = if memory_config.guest_memfd {
+            Some(
+                hypervisor
+                    .create_private_vm()
+                    .map_err(Error::CreatePrivateVm)?,
+            )
+        } else {
+            None
+        };
+        #[cfg(not(target_arch = "x86_64"))]
+        let private_vm = None;
+
+        let vm = if let Some(vm) = private_vm {
+            vm
+        } else {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "tdx")] {
+                    // Passing KVM_X86_TDX_VM: 1 if tdx_enabled is true
+                    // Otherwise KVM_X86_LEGACY_VM: 0
+                    // value of tdx_enabled is mapped to KVM_X86_TDX_VM or KVM_X86_LEGACY_VM
+                    let vm = hypervisor
+                        .create_vm_with_type(u64::from(tdx_enabled))
+                        .unwrap();
+                } else if #[cfg(feature = "sev_snp")] {
+                    // Passing SEV_SNP_ENABLED: 1 if sev_snp_enabled is true
+                    // Otherwise SEV_SNP_DISABLED: 0
+                    // value of sev_snp_enabled is mapped to SEV_SNP_ENABLED for true or SEV_SNP_DISABLED for false
+                    let vm = hypervisor
+                        .create_vm_with_type(u64::from(sev_snp_enabled))
+                        .unwrap();
+                } else {
+                    let vm = hypervisor.create_vm().unwrap();
+                }
             }
-        }
+            vm
+        };
 
         #[cfg(target_arch = "x86_64")]
         {
@@ -918,6 +1254,13 @@ impl Vm {
                 .unwrap();
             vm.set_tss_address(KVM_TSS_START.0 as usize).unwrap();
             vm.enable_split_irq().unwrap();
+            // Without the dirty ring, the dirty pages are tracked through
+            // the dirty bitmap.
+            if memory_config.dirty_ring {
+                if let Err(e) = vm.enable_dirty_ring() {
+                    warn!("Using the dirty bitmap: {}", e);
+                }
+            }
         }
 
         Ok(vm)
@@ -1214,6 +1557,7 @@ impl Vm {
             .sgx_epc_region()
             .as_ref()
             .cloned();
+        let cxl_memory_ranges = self.memory_manager.lock().unwrap().cxl_memory_ranges();
 
         let serial_number = self
             .config
@@ -1231,13 +1575,38 @@ impl Vm {
             .as_ref()
             .and_then(|p| p.uuid.clone());
 
-        let oem_strings = self
+        let (oem_strings, oem_string_files) = self
             .config
             .lock()
             .unwrap()
             .platform
             .as_ref()
-            .and_then(|p| p.oem_strings.clone());
+            .map(|p| (p.oem_strings.clone(), p.oem_string_files.clone()))
+            .unwrap_or_default();
+
+        // Each file provides a whole OEM string, appended after the ones
+        // given inline.
+        let oem_strings = if let Some(oem_string_files) = oem_string_files {
+            let mut oem_strings = oem_strings.unwrap_or_default();
+            for file in oem_string_files {
+                let oem_string = std::fs::read_to_string(&file)
+                    .and_then(|s| {
+                        if s.contains('\0') {
+                            Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "OEM strings cannot contain NUL characters",
+                            ))
+                        } else {
+                            Ok(s)
+                        }
+                    })
+                    .map_err(|e| Error::ReadOemStringFile(file, e))?;
+                oem_strings.push(oem_string);
+            }
+            Some(oem_strings)
+        } else {
+            oem_strings
+        };
 
         let oem_strings = oem_strings
             .as_deref()
@@ -1254,6 +1623,7 @@ impl Vm {
             entry_addr.setup_header,
             rsdp_addr,
             sgx_epc_region,
+            &cxl_memory_ranges,
             serial_number.as_deref(),
             uuid.as_deref(),
             oem_strings.as_deref(),
@@ -1377,6 +1747,15 @@ impl Vm {
             .shutdown()
             .map_err(Error::CpuManager)?;
 
+        #[cfg(target_arch = "x86_64")]
+        if let Some(vmbus_relay) = &self.vmbus_relay {
+            vmbus_relay.shutdown();
+        }
+
+        if let Some(vnc_server) = &self.vnc_server {
+            vnc_server.shutdown();
+        }
+
         // Wait for all the threads to finish
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?
@@ -1389,11 +1768,31 @@ impl Vm {
     pub fn resize(
         &mut self,
         desired_vcpus: Option<u8>,
+        desired_max_vcpus: Option<u8>,
         desired_memory: Option<u64>,
         desired_balloon: Option<u64>,
     ) -> Result<()> {
         event!("vm", "resizing");
 
+        // Raising the maximum number of vCPUs must happen first, so that the
+        // new vCPUs can be plugged as part of the same request.
+        if let Some(desired_max_vcpus) = desired_max_vcpus {
+            if self
+                .cpu_manager
+                .lock()
+                .unwrap()
+                .resize_max_vcpus(desired_max_vcpus)
+                .map_err(Error::CpuManager)?
+            {
+                self.device_manager
+                    .lock()
+                    .unwrap()
+                    .notify_hotplug(AcpiNotificationFlags::CPU_DEVICES_CHANGED)
+                    .map_err(Error::DeviceManager)?;
+            }
+            self.config.lock().unwrap().cpus.max_vcpus = desired_max_vcpus;
+        }
+
         if let Some(desired_vcpus) = desired_vcpus {
             if self
                 .cpu_manager
@@ -1446,7 +1845,19 @@ impl Vm {
             match memory_config.hotplug_method {
                 HotplugMethod::Acpi => memory_config.size = desired_memory,
                 HotplugMethod::VirtioMem => {
-                    if desired_memory > memory_config.size {
+                    if let Some(boot_size) = memory_config.boot_size {
+                        // The memory size includes what is plugged through
+                        // virtio-mem at boot, while the total amount of
+                        // memory must remain unchanged.
+                        if desired_memory >= boot_size {
+                            let max_size =
+                                memory_config.size + memory_config.hotplug_size.unwrap_or(0);
+                            memory_config.size = desired_memory;
+                            memory_config.hotplug_size = max_size
+                                .checked_sub(desired_memory)
+                                .filter(|size| *size > 0);
+                        }
+                    } else if desired_memory > memory_config.size {
                         memory_config.hotplugged_size = Some(desired_memory - memory_config.size);
                     } else {
                         memory_config.hotplugged_size = None;
@@ -1697,6 +2108,23 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn add_usb_device(&mut self, mut usb_cfg: UsbDeviceConfig) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .add_usb_device(&mut usb_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new device. This is important to
+        // ensure the device would be attached again in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            add_to_config(&mut config.usb_devices, usb_cfg);
+        }
+
+        Ok(())
+    }
+
     pub fn add_vsock(&mut self, mut vsock_cfg: VsockConfig) -> Result<PciDeviceInfo> {
         let pci_device_info = self
             .device_manager
@@ -1722,7 +2150,36 @@ impl Vm {
     }
 
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
+        let mut counters = self.device_manager.lock().unwrap().counters();
+        counters.extend(self.cpu_manager.lock().unwrap().counters());
+
+        Ok(counters)
+    }
+
+    /// Sample the guest clock along with the host wall clock.
+    pub fn clock(&self) -> Result<VmClockResponse> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let clock = self.vm.get_clock().map_err(Error::GetClock)?;
+            let guest_ns = clock.guest_ns();
+            let host_realtime_ns = clock.host_realtime_ns().unwrap_or_else(realtime_ns);
+
+            Ok(VmClockResponse {
+                guest_ns,
+                host_realtime_ns,
+                offset_ns: host_realtime_ns as i64 - guest_ns as i64,
+            })
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        Err(Error::ClockUnsupported)
+    }
+
+    pub fn serial_capture(&self) -> Result<Vec<u8>> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .serial_capture()
+            .ok_or(Error::SerialCaptureNotEnabled)
     }
 
     #[cfg(feature = "tdx")]
@@ -2304,11 +2761,92 @@ impl Vm {
     }
 
     pub fn activate_virtio_devices(&self) -> Result<()> {
-        self.device_manager
+        // Device worker threads are spawned from the current thread during
+        // activation, and inherit its cgroup.
+        if let Some(cgroup_manager) = &self.cgroup_manager {
+            cgroup_manager
+                .attach_current_thread(CgroupThreadGroup::Iothreads)
+                .map_err(Error::Cgroup)?;
+        }
+
+        let ret = self
+            .device_manager
             .lock()
             .unwrap()
             .activate_virtio_devices()
-            .map_err(Error::ActivateVirtioDevices)
+            .map_err(Error::ActivateVirtioDevices);
+
+        if let Some(cgroup_manager) = &self.cgroup_manager {
+            cgroup_manager
+                .attach_current_thread(CgroupThreadGroup::Vmm)
+                .map_err(Error::Cgroup)?;
+        }
+
+        ret
+    }
+
+    pub fn update_vcpus_affinity(&self) -> Result<()> {
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .update_vcpus_affinity()
+            .map_err(Error::CpuManager)
+    }
+
+    /// Move the restored guest clock forward by the time elapsed since it
+    /// was saved, so that the guest doesn't silently lag behind the host.
+    pub fn resync_clock(&mut self) -> Result<()> {
+        // The guest counter can only be moved through the vCPU system
+        // registers, which MSHV doesn't give access to on AArch64.
+        #[cfg(all(target_arch = "aarch64", feature = "mshv"))]
+        if matches!(
+            self.hypervisor.hypervisor_type(),
+            hypervisor::HypervisorType::Mshv
+        ) {
+            return Err(Error::ClockResyncUnsupported);
+        }
+
+        let Some(saved_realtime) = self.saved_clock_realtime else {
+            warn!("No saved clock to resynchronize");
+            return Ok(());
+        };
+        let elapsed = realtime_ns().saturating_sub(saved_realtime);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let Some(clock) = self.saved_clock.as_mut() else {
+                warn!("No saved clock to resynchronize");
+                return Ok(());
+            };
+            clock.advance(elapsed);
+        }
+        // The guest counter was restored along with the vCPU state.
+        #[cfg(target_arch = "aarch64")]
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .advance_clock(elapsed)
+            .map_err(Error::CpuManager)?;
+
+        info!("Guest clock moved forward by {} ns", elapsed);
+        event!("vm", "clock-resynced", "elapsed_ns", elapsed.to_string());
+
+        Ok(())
+    }
+
+    pub fn update_cgroup(&self, resources: &CgroupResources) -> Result<()> {
+        let cgroup_manager = self
+            .cgroup_manager
+            .as_ref()
+            .ok_or(Error::CgroupNotConfigured)?;
+        cgroup_manager.update(resources).map_err(Error::Cgroup)?;
+
+        let mut config = self.config.lock().unwrap();
+        if let Some(cgroup) = config.cgroup.as_mut() {
+            cgroup.resources.merge(resources);
+        }
+
+        Ok(())
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -2431,6 +2969,43 @@ impl Vm {
             + size_of::<elf::Elf64_Phdr>() as u64 * phdr_num as u64
     }
 
+    /// Pauses the VM once the guest entered the S3 sleep state. The guest
+    /// memory and the devices are left as they are until `resume()` wakes the
+    /// guest up.
+    pub fn suspend(&mut self) -> std::result::Result<(), MigratableError> {
+        self.pause()?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.suspended = true;
+        }
+        Ok(())
+    }
+
+    // Have the boot vCPU jump to the waking vector the guest stored in the
+    // FACS before suspending, as firmware would.
+    #[cfg(target_arch = "x86_64")]
+    fn wakeup(&self) -> std::result::Result<(), MigratableError> {
+        let waking_vector = {
+            let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+            crate::acpi::waking_vector(&guest_memory.memory())
+        }
+        .ok_or_else(|| MigratableError::Resume(anyhow!("No waking vector set by the guest")))?;
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .wakeup(waking_vector)
+            .map_err(|e| MigratableError::Resume(anyhow!("Could not wake up vCPUs: {}", e)))?;
+
+        event!(
+            "vm",
+            "waking",
+            "waking_vector",
+            format!("{waking_vector:#x}")
+        );
+        Ok(())
+    }
+
     pub fn nmi(&self) -> Result<()> {
         return self
             .cpu_manager
@@ -2462,6 +3037,7 @@ impl Pausable for Vm {
                 .map_err(|e| MigratableError::Pause(anyhow!("Could not get VM clock: {}", e)))?;
             clock.reset_flags();
             self.saved_clock = Some(clock);
+            self.saved_clock_realtime = Some(realtime_ns());
         }
 
         // Before pausing the vCPUs activate any pending virtio devices that might
@@ -2496,6 +3072,12 @@ impl Pausable for Vm {
             .valid_transition(new_state)
             .map_err(|e| MigratableError::Resume(anyhow!("Invalid transition: {:?}", e)))?;
 
+        #[cfg(target_arch = "x86_64")]
+        if self.suspended {
+            self.wakeup()?;
+            self.suspended = false;
+        }
+
         self.cpu_manager.lock().unwrap().resume()?;
         #[cfg(target_arch = "x86_64")]
         {
@@ -2525,6 +3107,12 @@ impl Pausable for Vm {
 pub struct VmSnapshot {
     #[cfg(target_arch = "x86_64")]
     pub clock: Option<hypervisor::ClockData>,
+    // Host wall clock time, in nanoseconds, at which the clock was saved
+    #[serde(default)]
+    pub clock_realtime: Option<u64>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub suspended: bool,
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     pub common_cpuid: Vec<hypervisor::arch::x86::CpuIdEntry>,
 }
@@ -2547,6 +3135,29 @@ impl Snapshottable for Vm {
             }
         }
 
+        #[cfg(feature = "sev_snp")]
+        {
+            if self.config.lock().unwrap().is_sev_snp_enabled() {
+                return Err(MigratableError::Snapshot(anyhow!(
+                    "Snapshot not possible with SEV-SNP VM"
+                )));
+            }
+        }
+
+        // The private memory lives in guest_memfd, out of reach of the VMM.
+        if self.config.lock().unwrap().memory.guest_memfd {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Snapshot not possible with guest_memfd private memory"
+            )));
+        }
+
+        // The state of the synthetic devices is held by the VMBus backend.
+        if self.config.lock().unwrap().is_vmbus_relay_enabled() {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Snapshot not possible with the VMBus relay"
+            )));
+        }
+
         let current_state = self.get_state().unwrap();
         if current_state != VmState::Paused {
             return Err(MigratableError::Snapshot(anyhow!(
@@ -2577,9 +3188,19 @@ impl Snapshottable for Vm {
             })?
         };
 
+        // The guest counter keeps running while the VM is paused on AArch64,
+        // it is saved along with the vCPU state when taking the snapshot.
+        #[cfg(target_arch = "x86_64")]
+        let clock_realtime = self.saved_clock_realtime;
+        #[cfg(target_arch = "aarch64")]
+        let clock_realtime = Some(realtime_ns());
+
         let vm_snapshot_state = VmSnapshot {
             #[cfg(target_arch = "x86_64")]
             clock: self.saved_clock,
+            clock_realtime,
+            #[cfg(target_arch = "x86_64")]
+            suspended: self.suspended,
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             common_cpuid,
         };
@@ -2624,8 +3245,9 @@ impl Transportable for Vm {
             .open(snapshot_config_path)
             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
-        // Serialize and write the snapshot config
-        let vm_config = serde_json::to_string(self.config.lock().unwrap().deref())
+        // Serialize and write the snapshot config, the NUMA placement being
+        // resolved again on restore.
+        let vm_config = serde_json::to_string(&numa_placement::strip(&self.config.lock().unwrap()))
             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
         snapshot_config_file
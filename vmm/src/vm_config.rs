@@ -2,10 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
+use crate::numa_placement::NumaPlacement;
 use crate::{landlock::LandlockError, Landlock};
 use net_util::MacAddr;
+use pci::PciBdf;
 use serde::{Deserialize, Serialize};
-use std::{fs, net::Ipv4Addr, path::PathBuf, result};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::{fs, path::PathBuf, result};
 use virtio_devices::RateLimiterConfig;
 
 pub type LandlockResult<T> = result::Result<T, LandlockError>;
@@ -22,6 +25,18 @@ pub struct CpuAffinity {
     pub host_cpus: Vec<usize>,
 }
 
+/// Host CPUs a pinned vCPU is moved onto once none of its host CPUs can be
+/// used anymore, because they went offline or left the VMM cpuset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CpuAffinityFallback {
+    /// Any host CPU available to the VMM
+    #[default]
+    Any,
+    /// Host CPUs from the NUMA node of the original host CPUs, or any host
+    /// CPU if none is available
+    Numa,
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CpuFeatures {
     #[cfg(target_arch = "x86_64")]
@@ -59,6 +74,8 @@ pub struct CpusConfig {
     #[serde(default)]
     pub affinity: Option<Vec<CpuAffinity>>,
     #[serde(default)]
+    pub affinity_fallback: CpuAffinityFallback,
+    #[serde(default)]
     pub features: CpuFeatures,
 }
 
@@ -73,11 +90,24 @@ impl Default for CpusConfig {
             kvm_hyperv: false,
             max_phys_bits: DEFAULT_MAX_PHYS_BITS,
             affinity: None,
+            affinity_fallback: CpuAffinityFallback::default(),
             features: CpuFeatures::default(),
         }
     }
 }
 
+impl ApplyLandlock for CpusConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        // Pinned vCPUs follow the host CPUs going offline, and the fallback
+        // policy looks up their NUMA node.
+        if self.affinity.is_some() {
+            landlock.add_rule_with_access("/sys/devices/system/cpu".into(), "r")?;
+            landlock.add_rule_with_access("/sys/devices/system/node".into(), "r")?;
+        }
+        Ok(())
+    }
+}
+
 pub const DEFAULT_NUM_PCI_SEGMENTS: u16 = 1;
 pub fn default_platformconfig_num_pci_segments() -> u16 {
     DEFAULT_NUM_PCI_SEGMENTS
@@ -90,11 +120,19 @@ pub struct PlatformConfig {
     #[serde(default)]
     pub iommu_segments: Option<Vec<u16>>,
     #[serde(default)]
+    pub iommu_hotplug_slots: u8,
+    #[serde(default)]
     pub serial_number: Option<String>,
     #[serde(default)]
     pub uuid: Option<String>,
     #[serde(default)]
     pub oem_strings: Option<Vec<String>>,
+    #[serde(default)]
+    pub oem_string_files: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    pub virtio_mmio: bool,
+    #[serde(default)]
+    pub vmbus_relay: Option<PathBuf>,
     #[cfg(feature = "tdx")]
     #[serde(default)]
     pub tdx: bool,
@@ -103,6 +141,18 @@ pub struct PlatformConfig {
     pub sev_snp: bool,
 }
 
+impl ApplyLandlock for PlatformConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        for file in self.oem_string_files.iter().flatten() {
+            landlock.add_rule_with_access(file.to_path_buf(), "r")?;
+        }
+        if let Some(socket) = &self.vmbus_relay {
+            landlock.add_rule_with_access(socket.to_path_buf(), "rw")?;
+        }
+        Ok(())
+    }
+}
+
 pub const DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT: u32 = 1;
 
 fn default_pci_segment_aperture_weight() -> u32 {
@@ -139,6 +189,8 @@ pub struct MemoryZoneConfig {
     pub hotplugged_size: Option<u64>,
     #[serde(default)]
     pub prefault: bool,
+    #[serde(default)]
+    pub mlock: bool,
 }
 
 impl ApplyLandlock for MemoryZoneConfig {
@@ -173,6 +225,8 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub hotplugged_size: Option<u64>,
     #[serde(default)]
+    pub boot_size: Option<u64>,
+    #[serde(default)]
     pub shared: bool,
     #[serde(default)]
     pub hugepages: bool,
@@ -181,9 +235,15 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub prefault: bool,
     #[serde(default)]
+    pub mlock: bool,
+    #[serde(default)]
     pub zones: Option<Vec<MemoryZoneConfig>>,
     #[serde(default = "default_memoryconfig_thp")]
     pub thp: bool,
+    #[serde(default)]
+    pub dirty_ring: bool,
+    #[serde(default)]
+    pub guest_memfd: bool,
 }
 
 pub const DEFAULT_MEMORY_MB: u64 = 512;
@@ -196,12 +256,16 @@ impl Default for MemoryConfig {
             hotplug_method: HotplugMethod::Acpi,
             hotplug_size: None,
             hotplugged_size: None,
+            boot_size: None,
             shared: false,
             hugepages: false,
             hugepage_size: None,
             prefault: false,
+            mlock: false,
             zones: None,
             thp: true,
+            dirty_ring: false,
+            guest_memfd: false,
         }
     }
 }
@@ -213,6 +277,20 @@ pub enum VhostMode {
     Server,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IoAffinityConfig {
+    pub id: String,
+    #[serde(default = "default_ioaffinityconfig_num_sets")]
+    pub num_sets: usize,
+    pub host_cpus: Vec<usize>,
+}
+
+pub const DEFAULT_IO_AFFINITY_NUM_SETS: usize = 1;
+
+pub fn default_ioaffinityconfig_num_sets() -> usize {
+    DEFAULT_IO_AFFINITY_NUM_SETS
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RateLimiterGroupConfig {
     #[serde(default)]
@@ -244,6 +322,10 @@ pub struct DiskConfig {
     pub vhost_user: bool,
     pub vhost_socket: Option<String>,
     #[serde(default)]
+    pub out_of_process: bool,
+    #[serde(default)]
+    pub exec: Option<Vec<String>>,
+    #[serde(default)]
     pub rate_limit_group: Option<String>,
     #[serde(default)]
     pub rate_limiter_config: Option<RateLimiterConfig>,
@@ -258,9 +340,37 @@ pub struct DiskConfig {
     #[serde(default)]
     pub pci_segment: u16,
     #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+    #[serde(default)]
     pub serial: Option<String>,
     #[serde(default)]
     pub queue_affinity: Option<Vec<VirtQueueAffinity>>,
+    #[serde(default)]
+    pub io_affinity: Option<String>,
+    #[serde(default)]
+    pub busy_poll_us: Option<u64>,
+    #[serde(default)]
+    pub io_uring_event_loop: bool,
+    #[serde(default)]
+    pub coalesce_us: Option<u64>,
+    #[serde(default)]
+    pub completion_poll_us: Option<u64>,
+    #[serde(default)]
+    pub thread_pool_size: Option<usize>,
+    #[serde(default)]
+    pub thread_pool_queue_depth: Option<usize>,
+    #[serde(default)]
+    pub transitional: bool,
+    #[serde(default)]
+    pub overlay: bool,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    // Only accepted through the API, and never serialized so that it can't
+    // leak through the VM information or snapshots.
+    #[serde(default, skip_serializing)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub nvme: Option<String>,
 }
 
 impl ApplyLandlock for DiskConfig {
@@ -268,6 +378,13 @@ impl ApplyLandlock for DiskConfig {
         if let Some(path) = &self.path {
             landlock.add_rule_with_access(path.to_path_buf(), "rw")?;
         }
+        // The overlay is created as an unnamed temporary file.
+        if self.overlay {
+            landlock.add_rule_with_access(std::env::temp_dir(), "rw")?;
+        }
+        if let Some(key_file) = &self.key_file {
+            landlock.add_rule_with_access(key_file.to_path_buf(), "r")?;
+        }
         Ok(())
     }
 }
@@ -288,6 +405,12 @@ pub fn default_diskconfig_queue_size() -> u16 {
 pub struct NetConfig {
     #[serde(default = "default_netconfig_tap")]
     pub tap: Option<String>,
+    #[serde(default)]
+    pub macvtap: Option<String>,
+    #[serde(default)]
+    pub xdp: Option<String>,
+    #[serde(default)]
+    pub xdp_queue: u32,
     #[serde(default = "default_netconfig_ip")]
     pub ip: Ipv4Addr,
     #[serde(default = "default_netconfig_mask")]
@@ -310,6 +433,10 @@ pub struct NetConfig {
     #[serde(default)]
     pub vhost_mode: VhostMode,
     #[serde(default)]
+    pub out_of_process: bool,
+    #[serde(default)]
+    pub exec: Option<Vec<String>>,
+    #[serde(default)]
     pub id: Option<String>,
     #[serde(
         default,
@@ -321,12 +448,51 @@ pub struct NetConfig {
     pub rate_limiter_config: Option<RateLimiterConfig>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
     #[serde(default = "default_netconfig_true")]
     pub offload_tso: bool,
     #[serde(default = "default_netconfig_true")]
     pub offload_ufo: bool,
     #[serde(default = "default_netconfig_true")]
     pub offload_csum: bool,
+    // For testing use only. Not exposed in API.
+    #[serde(default)]
+    pub disable_io_uring: bool,
+    #[serde(default)]
+    pub io_uring_registered_buffers: bool,
+    #[serde(default)]
+    pub io_affinity: Option<String>,
+    #[serde(default)]
+    pub busy_poll_us: Option<u64>,
+    #[serde(default)]
+    pub io_uring_event_loop: bool,
+    #[serde(default)]
+    pub coalesce_us: Option<u64>,
+    #[serde(default)]
+    pub transitional: bool,
+    #[serde(default)]
+    pub passt: bool,
+    #[serde(default)]
+    pub port_forward: Option<Vec<PortForwardConfig>>,
+    #[serde(default)]
+    pub vhost_net: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PortForwardProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// Host port forwarded to a guest port by the userspace networking backend.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PortForwardConfig {
+    #[serde(default)]
+    pub protocol: PortForwardProtocol,
+    pub host_port: u16,
+    pub guest_port: u16,
 }
 
 pub fn default_netconfig_true() -> bool {
@@ -422,6 +588,60 @@ pub struct BalloonConfig {
     /// Option to enable free page reporting from the guest.
     #[serde(default)]
     pub free_page_reporting: bool,
+    /// Policy automatically resizing the balloon based on the guest memory
+    /// statistics.
+    #[serde(default)]
+    pub policy: Option<BalloonPolicyConfig>,
+}
+
+pub const DEFAULT_BALLOON_POLICY_FREE_RESERVE: u64 = 256 << 20;
+
+pub fn default_balloonpolicyconfig_free_reserve() -> u64 {
+    DEFAULT_BALLOON_POLICY_FREE_RESERVE
+}
+
+pub const DEFAULT_BALLOON_POLICY_HYSTERESIS: u64 = 64 << 20;
+
+pub fn default_balloonpolicyconfig_hysteresis() -> u64 {
+    DEFAULT_BALLOON_POLICY_HYSTERESIS
+}
+
+pub const DEFAULT_BALLOON_POLICY_INTERVAL_MS: u64 = 5000;
+
+pub fn default_balloonpolicyconfig_interval_ms() -> u64 {
+    DEFAULT_BALLOON_POLICY_INTERVAL_MS
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BalloonPolicyConfig {
+    /// Guest RAM the policy never shrinks the guest below.
+    #[serde(default)]
+    pub min_guest_ram: u64,
+    /// Guest RAM the policy never grows the guest above, defaulting to all
+    /// of it.
+    #[serde(default)]
+    pub max_guest_ram: Option<u64>,
+    /// Memory kept available to the guest on top of what it uses.
+    #[serde(default = "default_balloonpolicyconfig_free_reserve")]
+    pub free_reserve: u64,
+    /// Minimum change of the balloon size worth applying.
+    #[serde(default = "default_balloonpolicyconfig_hysteresis")]
+    pub hysteresis: u64,
+    /// Interval between two evaluations of the policy.
+    #[serde(default = "default_balloonpolicyconfig_interval_ms")]
+    pub interval_ms: u64,
+}
+
+impl Default for BalloonPolicyConfig {
+    fn default() -> Self {
+        Self {
+            min_guest_ram: 0,
+            max_guest_ram: None,
+            free_reserve: default_balloonpolicyconfig_free_reserve(),
+            hysteresis: default_balloonpolicyconfig_hysteresis(),
+            interval_ms: default_balloonpolicyconfig_interval_ms(),
+        }
+    }
 }
 
 #[cfg(feature = "pvmemcontrol")]
@@ -437,9 +657,15 @@ pub struct FsConfig {
     #[serde(default = "default_fsconfig_queue_size")]
     pub queue_size: u16,
     #[serde(default)]
+    pub shared_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub exec: Option<Vec<String>>,
+    #[serde(default)]
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
 }
 
 pub fn default_fsconfig_num_queues() -> usize {
@@ -457,6 +683,50 @@ impl ApplyLandlock for FsConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Fs9pConfig {
+    pub tag: String,
+    pub path: PathBuf,
+    #[serde(default = "default_fs9pconfig_msize")]
+    pub msize: u32,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+}
+
+pub fn default_fs9pconfig_msize() -> u32 {
+    512 << 10
+}
+
+impl ApplyLandlock for Fs9pConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        landlock.add_rule_with_access(self.path.to_path_buf(), "rw")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct I2cConfig {
+    pub path: PathBuf,
+    pub addresses: Vec<u16>,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+}
+
+impl ApplyLandlock for I2cConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        landlock.add_rule_with_access(self.path.to_path_buf(), "rw")?;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct PmemConfig {
     pub file: PathBuf,
@@ -470,6 +740,12 @@ pub struct PmemConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+    #[serde(default)]
+    pub nvdimm: bool,
+    #[serde(default)]
+    pub label_size: u64,
 }
 
 impl ApplyLandlock for PmemConfig {
@@ -497,6 +773,10 @@ pub struct ConsoleConfig {
     #[serde(default)]
     pub iommu: bool,
     pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    #[serde(default)]
+    pub capture_size: Option<u64>,
 }
 
 pub fn default_consoleconfig_file() -> Option<PathBuf> {
@@ -511,6 +791,9 @@ impl ApplyLandlock for ConsoleConfig {
         if let Some(socket) = &self.socket {
             landlock.add_rule_with_access(socket.to_path_buf(), "rw")?;
         }
+        if let Some(log_file) = &self.log_file {
+            landlock.add_rule_with_access(log_file.to_path_buf(), "rw")?;
+        }
         Ok(())
     }
 }
@@ -555,6 +838,8 @@ pub struct DeviceConfig {
     #[serde(default)]
     pub pci_segment: u16,
     #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+    #[serde(default)]
     pub x_nv_gpudirect_clique: Option<u8>,
 }
 
@@ -581,6 +866,8 @@ pub struct UserDeviceConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
 }
 
 impl ApplyLandlock for UserDeviceConfig {
@@ -601,6 +888,8 @@ pub struct VdpaConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
 }
 
 pub fn default_vdpaconfig_num_queues() -> usize {
@@ -614,6 +903,125 @@ impl ApplyLandlock for VdpaConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IvshmemConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+}
+
+impl ApplyLandlock for IvshmemConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        landlock.add_rule_with_access(self.path.to_path_buf(), "rw")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NvmeConfig {
+    pub id: String,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default = "default_nvmeconfig_num_queues")]
+    pub num_queues: u16,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+}
+
+pub fn default_nvmeconfig_num_queues() -> u16 {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CxlConfig {
+    pub memory_zone: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct XhciConfig {
+    #[serde(default = "default_xhciconfig_num_ports")]
+    pub num_ports: u8,
+}
+
+pub fn default_xhciconfig_num_ports() -> u8 {
+    4
+}
+
+impl Default for XhciConfig {
+    fn default() -> Self {
+        XhciConfig {
+            num_ports: default_xhciconfig_num_ports(),
+        }
+    }
+}
+
+impl ApplyLandlock for XhciConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        landlock.add_rule_with_access("/dev/bus/usb".into(), "rw")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VncConfig {
+    #[serde(default = "default_vncconfig_listen")]
+    pub listen: SocketAddr,
+    #[serde(default = "default_vncconfig_width")]
+    pub width: u32,
+    #[serde(default = "default_vncconfig_height")]
+    pub height: u32,
+}
+
+pub fn default_vncconfig_listen() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, 5900))
+}
+
+pub fn default_vncconfig_width() -> u32 {
+    1024
+}
+
+pub fn default_vncconfig_height() -> u32 {
+    768
+}
+
+impl Default for VncConfig {
+    fn default() -> Self {
+        VncConfig {
+            listen: default_vncconfig_listen(),
+            width: default_vncconfig_width(),
+            height: default_vncconfig_height(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UsbDeviceConfig {
+    #[serde(default)]
+    pub hostbus: Option<u8>,
+    #[serde(default)]
+    pub hostaddr: Option<u8>,
+    #[serde(default)]
+    pub vendor_id: Option<u16>,
+    #[serde(default)]
+    pub product_id: Option<u16>,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct VsockConfig {
     pub cid: u32,
@@ -624,6 +1032,15 @@ pub struct VsockConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_bdf: Option<PciBdf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GuestAgentConfig {
+    pub port: u32,
+    #[serde(default)]
+    pub fs_freeze: bool,
 }
 
 impl ApplyLandlock for VsockConfig {
@@ -651,6 +1068,24 @@ pub struct NumaDistance {
     pub distance: u8,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NumaLatency {
+    #[serde(default)]
+    pub destination: u32,
+    // Access latency in nanoseconds
+    #[serde(default)]
+    pub latency: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NumaBandwidth {
+    #[serde(default)]
+    pub destination: u32,
+    // Access bandwidth in MB/s
+    #[serde(default)]
+    pub bandwidth: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct NumaConfig {
     #[serde(default)]
@@ -660,6 +1095,10 @@ pub struct NumaConfig {
     #[serde(default)]
     pub distances: Option<Vec<NumaDistance>>,
     #[serde(default)]
+    pub latencies: Option<Vec<NumaLatency>>,
+    #[serde(default)]
+    pub bandwidths: Option<Vec<NumaBandwidth>>,
+    #[serde(default)]
     pub memory_zones: Option<Vec<String>>,
     #[cfg(target_arch = "x86_64")]
     #[serde(default)]
@@ -716,6 +1155,10 @@ pub fn default_serial() -> ConsoleConfig {
         mode: ConsoleOutputMode::Null,
         iommu: false,
         socket: None,
+        log_file: None,
+        capture_size: None,
+        log_file: None,
+        capture_size: None,
     }
 }
 
@@ -725,6 +1168,10 @@ pub fn default_console() -> ConsoleConfig {
         mode: ConsoleOutputMode::Tty,
         iommu: false,
         socket: None,
+        log_file: None,
+        capture_size: None,
+        log_file: None,
+        capture_size: None,
     }
 }
 
@@ -740,6 +1187,157 @@ impl ApplyLandlock for TpmConfig {
     }
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CgroupResources {
+    #[serde(default)]
+    pub memory_high: Option<u64>,
+    #[serde(default)]
+    pub io_max: Option<Vec<String>>,
+    #[serde(default)]
+    pub vmm_cpu_weight: Option<u64>,
+    #[serde(default)]
+    pub vcpu_cpu_weight: Option<u64>,
+    #[serde(default)]
+    pub iothread_cpu_weight: Option<u64>,
+}
+
+impl CgroupResources {
+    /// Merge the settings from `other` that are set on top of the current ones.
+    pub fn merge(&mut self, other: &CgroupResources) {
+        if other.memory_high.is_some() {
+            self.memory_high = other.memory_high;
+        }
+        if other.io_max.is_some() {
+            self.io_max.clone_from(&other.io_max);
+        }
+        if other.vmm_cpu_weight.is_some() {
+            self.vmm_cpu_weight = other.vmm_cpu_weight;
+        }
+        if other.vcpu_cpu_weight.is_some() {
+            self.vcpu_cpu_weight = other.vcpu_cpu_weight;
+        }
+        if other.iothread_cpu_weight.is_some() {
+            self.iothread_cpu_weight = other.iothread_cpu_weight;
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CgroupConfig {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub resources: CgroupResources,
+}
+
+impl ApplyLandlock for CgroupConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        landlock.add_rule_with_access(self.path.to_path_buf(), "rw")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SecurityLabelConfig {
+    #[serde(default)]
+    pub selinux_context: Option<String>,
+    #[serde(default)]
+    pub selinux_file_context: Option<String>,
+    #[serde(default)]
+    pub selinux_backend_context: Option<String>,
+    #[serde(default)]
+    pub apparmor_profile: Option<String>,
+    #[serde(default)]
+    pub apparmor_backend_profile: Option<String>,
+}
+
+impl ApplyLandlock for SecurityLabelConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        // Landlock is applied from the VMM thread, which is the one labeled.
+        // The path is resolved here, granting access to the attributes of
+        // that thread only.
+        landlock.add_rule_with_access("/proc/thread-self/attr".into(), "w")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RtcBase {
+    #[default]
+    Utc,
+    Localtime,
+    /// Fixed UTC date and time the RTC starts from, as `YYYY-MM-DDTHH:MM:SS`
+    Datetime(String),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RtcClock {
+    /// Follow the host wall clock, including across pause and restore
+    #[default]
+    Host,
+    /// Only run along with the VM
+    Vm,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RtcConfig {
+    #[serde(default)]
+    pub base: RtcBase,
+    #[serde(default)]
+    pub clock: RtcClock,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PanicAction {
+    /// Only report the panic through the event monitor
+    #[default]
+    None,
+    Pause,
+    /// Dump the guest memory and vCPU state, then pause the VM
+    Coredump,
+    Restart,
+    Shutdown,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PanicConfig {
+    #[serde(default)]
+    pub action: PanicAction,
+    #[serde(default)]
+    pub coredump_file: Option<PathBuf>,
+}
+
+impl ApplyLandlock for PanicConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        // The coredump file is created when the guest panics, so access is
+        // granted to the directory holding it.
+        if let Some(dir) = self.coredump_file.as_ref().and_then(|f| f.parent()) {
+            landlock.add_rule_with_access(dir.to_path_buf(), "rw")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FwCfgConfig {
+    /// Name of the fw_cfg file, e.g. `opt/org.example/blob`
+    pub name: String,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub string: Option<String>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ApplyLandlock for FwCfgConfig {
+    fn apply_landlock(&self, landlock: &mut Landlock) -> LandlockResult<()> {
+        if let Some(file) = &self.file {
+            landlock.add_rule_with_access(file.to_path_buf(), "r")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct LandlockConfig {
     pub path: PathBuf,
@@ -761,12 +1359,15 @@ pub struct VmConfig {
     pub memory: MemoryConfig,
     pub payload: Option<PayloadConfig>,
     pub rate_limit_groups: Option<Vec<RateLimiterGroupConfig>>,
+    pub io_affinities: Option<Vec<IoAffinityConfig>>,
     pub disks: Option<Vec<DiskConfig>>,
     pub net: Option<Vec<NetConfig>>,
     #[serde(default)]
     pub rng: RngConfig,
     pub balloon: Option<BalloonConfig>,
     pub fs: Option<Vec<FsConfig>>,
+    pub fs9p: Option<Vec<Fs9pConfig>>,
+    pub i2c: Option<Vec<I2cConfig>>,
     pub pmem: Option<Vec<PmemConfig>>,
     #[serde(default = "default_serial")]
     pub serial: ConsoleConfig,
@@ -778,18 +1379,34 @@ pub struct VmConfig {
     pub devices: Option<Vec<DeviceConfig>>,
     pub user_devices: Option<Vec<UserDeviceConfig>>,
     pub vdpa: Option<Vec<VdpaConfig>>,
+    pub ivshmem: Option<Vec<IvshmemConfig>>,
+    pub nvme: Option<Vec<NvmeConfig>>,
+    pub cxl: Option<Vec<CxlConfig>>,
+    pub xhci: Option<XhciConfig>,
+    pub usb_devices: Option<Vec<UsbDeviceConfig>>,
+    pub vnc: Option<VncConfig>,
     pub vsock: Option<VsockConfig>,
+    pub guest_agent: Option<GuestAgentConfig>,
     #[cfg(feature = "pvmemcontrol")]
     #[serde(default)]
     pub pvmemcontrol: Option<PvmemcontrolConfig>,
     #[serde(default)]
     pub pvpanic: bool,
     #[serde(default)]
+    pub vmclock: bool,
+    #[serde(default)]
+    pub panic: Option<PanicConfig>,
+    #[serde(default)]
     pub iommu: bool,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<SgxEpcConfig>>,
     pub numa: Option<Vec<NumaConfig>>,
     #[serde(default)]
+    pub numa_placement: bool,
+    // Placement resolved on this host, never serialized.
+    #[serde(skip)]
+    pub numa_placed: Option<NumaPlacement>,
+    #[serde(default)]
     pub watchdog: bool,
     #[cfg(feature = "guest_debug")]
     #[serde(default)]
@@ -797,6 +1414,13 @@ pub struct VmConfig {
     pub pci_segments: Option<Vec<PciSegmentConfig>>,
     pub platform: Option<PlatformConfig>,
     pub tpm: Option<TpmConfig>,
+    #[serde(default)]
+    pub rtc: Option<RtcConfig>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub fw_cfg: Option<Vec<FwCfgConfig>>,
+    pub cgroup: Option<CgroupConfig>,
+    pub security_label: Option<SecurityLabelConfig>,
     // Preserved FDs are the ones that share the same life-time as its holding
     // VmConfig instance, such as FDs for creating TAP devices.
     // Preserved FDs will stay open as long as the holding VmConfig instance is
@@ -812,6 +1436,8 @@ impl VmConfig {
     pub(crate) fn apply_landlock(&self) -> LandlockResult<()> {
         let mut landlock = Landlock::new()?;
 
+        self.cpus.apply_landlock(&mut landlock)?;
+
         if let Some(mem_zones) = &self.memory.zones {
             for zone in mem_zones.iter() {
                 zone.apply_landlock(&mut landlock)?;
@@ -833,6 +1459,18 @@ impl VmConfig {
             }
         }
 
+        if let Some(fs9p_configs) = &self.fs9p {
+            for fs9p_config in fs9p_configs.iter() {
+                fs9p_config.apply_landlock(&mut landlock)?;
+            }
+        }
+
+        if let Some(i2c_configs) = &self.i2c {
+            for i2c_config in i2c_configs.iter() {
+                i2c_config.apply_landlock(&mut landlock)?;
+            }
+        }
+
         if let Some(pmem_configs) = &self.pmem {
             for pmem_config in pmem_configs.iter() {
                 pmem_config.apply_landlock(&mut landlock)?;
@@ -867,6 +1505,16 @@ impl VmConfig {
             }
         }
 
+        if let Some(ivshmem_configs) = &self.ivshmem {
+            for ivshmem_config in ivshmem_configs.iter() {
+                ivshmem_config.apply_landlock(&mut landlock)?;
+            }
+        }
+
+        if let Some(xhci_config) = &self.xhci {
+            xhci_config.apply_landlock(&mut landlock)?;
+        }
+
         if let Some(vsock_config) = &self.vsock {
             vsock_config.apply_landlock(&mut landlock)?;
         }
@@ -875,12 +1523,42 @@ impl VmConfig {
             payload.apply_landlock(&mut landlock)?;
         }
 
+        if let Some(platform_config) = &self.platform {
+            platform_config.apply_landlock(&mut landlock)?;
+        }
+
         if let Some(tpm_config) = &self.tpm {
             tpm_config.apply_landlock(&mut landlock)?;
         }
 
-        if self.net.is_some() {
+        if let Some(panic_config) = &self.panic {
+            panic_config.apply_landlock(&mut landlock)?;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(fw_cfg_configs) = &self.fw_cfg {
+            for fw_cfg_config in fw_cfg_configs.iter() {
+                fw_cfg_config.apply_landlock(&mut landlock)?;
+            }
+        }
+
+        if let Some(cgroup_config) = &self.cgroup {
+            cgroup_config.apply_landlock(&mut landlock)?;
+        }
+
+        if let Some(security_label_config) = &self.security_label {
+            security_label_config.apply_landlock(&mut landlock)?;
+        }
+
+        if self.numa_placement {
+            landlock.add_rule_with_access("/sys/devices/system/node".into(), "r")?;
+        }
+
+        if let Some(net) = &self.net {
             landlock.add_rule_with_access("/dev/net/tun".into(), "rw")?;
+            if net.iter().any(|net| net.vhost_net) {
+                landlock.add_rule_with_access("/dev/vhost-net".into(), "rw")?;
+            }
         }
 
         if let Some(landlock_rules) = &self.landlock_rules {
@@ -0,0 +1,365 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Relay of the VMBus traffic of the guest to a host backend.
+//!
+//! VMBus is built on top of the Hyper-V synthetic interrupt controller
+//! (SynIC): the guest posts messages and signals events to connections, and
+//! is notified through the messages and events delivered to its SynIC
+//! interrupt sources. The relay doesn't implement VMBus itself, it forwards
+//! the messages and events of the guest to a backend connected through a
+//! Unix socket, which provides the VMBus server and the synthetic devices
+//! (netvsc, storvsc...), and delivers what the backend sends to the guest.
+//!
+//! Each frame starts with a header made of the frame type and the size of
+//! the body, followed by the body. All the fields are little endian:
+//!
+//! | Type | Direction  | Body                                                 |
+//! |------|------------|------------------------------------------------------|
+//! | 1    | to backend | connection (u32), message type (u32), payload        |
+//! | 2    | to backend | connection (u32), flag (u16), reserved (u16)         |
+//! | 3    | to guest   | vp (u32), sint (u8), reserved (3 bytes), message type (u32), payload |
+//! | 4    | to guest   | vp (u32), sint (u8), reserved (u8), flag (u16)       |
+
+use hypervisor::HypervisorVmError;
+use seccompiler::{apply_filter, BpfProgram};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+const FRAME_HEADER_SIZE: usize = 8;
+const FRAME_GUEST_MESSAGE: u32 = 1;
+const FRAME_GUEST_EVENT: u32 = 2;
+const FRAME_HOST_MESSAGE: u32 = 3;
+const FRAME_HOST_EVENT: u32 = 4;
+const MESSAGE_HEADER_SIZE: usize = 12;
+const EVENT_SIZE: usize = 8;
+
+// Layout of the HV_MESSAGE delivered to the guest.
+const HV_MESSAGE_SIZE: usize = 256;
+const HV_MESSAGE_HEADER_SIZE: usize = 16;
+const HV_MESSAGE_PAYLOAD_SIZE: usize = HV_MESSAGE_SIZE - HV_MESSAGE_HEADER_SIZE;
+
+// A message can't be posted while the previous one is still pending in the
+// same SynIC slot, give the guest some time to consume it.
+const POST_MESSAGE_RETRIES: u32 = 100;
+const POST_MESSAGE_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot connect to the VMBus backend: {0}")]
+    Connect(#[source] io::Error),
+    #[error("Cannot enable the SynIC relay: {0}")]
+    EnableSynicRelay(#[source] HypervisorVmError),
+    #[error("Cannot send to the VMBus backend: {0}")]
+    Send(#[source] io::Error),
+    #[error("Cannot receive from the VMBus backend: {0}")]
+    Receive(#[source] io::Error),
+    #[error("Invalid frame of type {0} and size {1} from the VMBus backend")]
+    InvalidFrame(u32, usize),
+    #[error("Cannot clone the VMBus backend socket: {0}")]
+    CloneSocket(#[source] io::Error),
+    #[error("Cannot spawn the VMBus relay thread: {0}")]
+    ThreadSpawn(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Message or event sent by the backend to a vCPU of the guest.
+#[derive(Debug, PartialEq, Eq)]
+enum HostFrame {
+    Message { vp: u32, sint: u8, message: Vec<u8> },
+    Event { vp: u32, sint: u8, flag: u16 },
+}
+
+fn frame(frame_type: u32, body: &[&[u8]]) -> Vec<u8> {
+    let size: usize = body.iter().map(|b| b.len()).sum();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + size);
+    frame.extend_from_slice(&frame_type.to_le_bytes());
+    frame.extend_from_slice(&(size as u32).to_le_bytes());
+    for b in body {
+        frame.extend_from_slice(b);
+    }
+    frame
+}
+
+fn guest_message_frame(connection_id: u32, message_type: u32, payload: &[u8]) -> Vec<u8> {
+    frame(
+        FRAME_GUEST_MESSAGE,
+        &[
+            &connection_id.to_le_bytes(),
+            &message_type.to_le_bytes(),
+            payload,
+        ],
+    )
+}
+
+fn guest_event_frame(connection_id: u32, flag: u16) -> Vec<u8> {
+    frame(
+        FRAME_GUEST_EVENT,
+        &[&connection_id.to_le_bytes(), &flag.to_le_bytes(), &[0; 2]],
+    )
+}
+
+/// Build the HV_MESSAGE delivered to the guest, with a zeroed sender.
+fn hv_message(message_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![0u8; HV_MESSAGE_SIZE];
+    message[0..4].copy_from_slice(&message_type.to_le_bytes());
+    message[4] = payload.len() as u8;
+    message[HV_MESSAGE_HEADER_SIZE..HV_MESSAGE_HEADER_SIZE + payload.len()]
+        .copy_from_slice(payload);
+    message
+}
+
+/// Read the next frame sent by the backend, or None if it closed the
+/// connection.
+fn read_host_frame(reader: &mut impl Read) -> Result<Option<HostFrame>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::Receive(e)),
+    }
+    let frame_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+    let valid = match frame_type {
+        FRAME_HOST_MESSAGE => {
+            (MESSAGE_HEADER_SIZE..=MESSAGE_HEADER_SIZE + HV_MESSAGE_PAYLOAD_SIZE).contains(&size)
+        }
+        FRAME_HOST_EVENT => size == EVENT_SIZE,
+        _ => false,
+    };
+    if !valid {
+        return Err(Error::InvalidFrame(frame_type, size));
+    }
+
+    let mut body = vec![0u8; size];
+    reader.read_exact(&mut body).map_err(Error::Receive)?;
+    let vp = u32::from_le_bytes(body[0..4].try_into().unwrap());
+    let sint = body[4];
+
+    Ok(Some(if frame_type == FRAME_HOST_MESSAGE {
+        let message_type = u32::from_le_bytes(body[8..12].try_into().unwrap());
+        HostFrame::Message {
+            vp,
+            sint,
+            message: hv_message(message_type, &body[12..]),
+        }
+    } else {
+        HostFrame::Event {
+            vp,
+            sint,
+            flag: u16::from_le_bytes(body[6..8].try_into().unwrap()),
+        }
+    }))
+}
+
+fn post_message(vm: &dyn hypervisor::Vm, vp: u32, sint: u8, message: &[u8]) {
+    let mut retries = POST_MESSAGE_RETRIES;
+    while let Err(e) = vm.post_synic_message(vp, sint, message) {
+        if retries == 0 {
+            warn!("Dropping VMBus message to vCPU {vp} SINT {sint}: {e}");
+            return;
+        }
+        retries -= 1;
+        thread::sleep(POST_MESSAGE_RETRY_DELAY);
+    }
+}
+
+/// Connection to the VMBus backend.
+pub struct VmbusRelay {
+    socket: Mutex<UnixStream>,
+}
+
+impl VmbusRelay {
+    /// Connect to the backend listening on `path` and have the hypervisor
+    /// hand the SynIC hypercalls of the guest over to the VMM.
+    pub fn new(path: &Path, vm: &Arc<dyn hypervisor::Vm>) -> Result<Arc<Self>> {
+        let socket = UnixStream::connect(path).map_err(Error::Connect)?;
+        vm.enable_synic_relay().map_err(Error::EnableSynicRelay)?;
+
+        Ok(Arc::new(VmbusRelay {
+            socket: Mutex::new(socket),
+        }))
+    }
+
+    /// Forward a message posted by the guest to the backend.
+    pub fn post_message(
+        &self,
+        connection_id: u32,
+        message_type: u32,
+        payload: &[u8],
+    ) -> Result<()> {
+        self.socket
+            .lock()
+            .unwrap()
+            .write_all(&guest_message_frame(connection_id, message_type, payload))
+            .map_err(Error::Send)
+    }
+
+    /// Forward an event signaled by the guest to the backend.
+    pub fn signal_event(&self, connection_id: u32, flag: u16) -> Result<()> {
+        self.socket
+            .lock()
+            .unwrap()
+            .write_all(&guest_event_frame(connection_id, flag))
+            .map_err(Error::Send)
+    }
+
+    /// Spawn the thread delivering the messages and events of the backend
+    /// to the guest, until the backend or the VMM closes the connection.
+    pub fn start(
+        &self,
+        vm: Arc<dyn hypervisor::Vm>,
+        seccomp_filter: BpfProgram,
+    ) -> Result<thread::JoinHandle<()>> {
+        let mut socket = self
+            .socket
+            .lock()
+            .unwrap()
+            .try_clone()
+            .map_err(Error::CloneSocket)?;
+
+        thread::Builder::new()
+            .name("vmbus_relay".to_string())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+                }
+
+                loop {
+                    match read_host_frame(&mut socket) {
+                        Ok(Some(HostFrame::Message { vp, sint, message })) => {
+                            post_message(vm.as_ref(), vp, sint, &message)
+                        }
+                        Ok(Some(HostFrame::Event { vp, sint, flag })) => {
+                            if let Err(e) = vm.signal_synic_event(vp, sint, flag) {
+                                warn!("Dropping VMBus event to vCPU {vp} SINT {sint}: {e}");
+                            }
+                        }
+                        Ok(None) => {
+                            info!("VMBus backend disconnected");
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Stopping the VMBus relay: {}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)
+    }
+
+    /// Close the connection, which stops the relay thread.
+    pub fn shutdown(&self) {
+        if let Err(e) = self.socket.lock().unwrap().shutdown(Shutdown::Both) {
+            warn!("Error closing the VMBus backend connection: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_frames() {
+        assert_eq!(
+            guest_message_frame(0x42, 1, &[0xa, 0xb]),
+            [1, 0, 0, 0, 10, 0, 0, 0, 0x42, 0, 0, 0, 1, 0, 0, 0, 0xa, 0xb]
+        );
+        assert_eq!(
+            guest_event_frame(0x42, 3),
+            [2, 0, 0, 0, 8, 0, 0, 0, 0x42, 0, 0, 0, 3, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_read_host_frames() {
+        let mut stream = Vec::new();
+        stream.extend(frame(
+            FRAME_HOST_MESSAGE,
+            &[
+                &1u32.to_le_bytes(),
+                &[2, 0, 0, 0],
+                &5u32.to_le_bytes(),
+                &[0xa, 0xb],
+            ],
+        ));
+        stream.extend(frame(
+            FRAME_HOST_EVENT,
+            &[&1u32.to_le_bytes(), &[2, 0], &7u16.to_le_bytes()],
+        ));
+        let mut reader = stream.as_slice();
+
+        let mut message = vec![0u8; HV_MESSAGE_SIZE];
+        message[0] = 5;
+        message[4] = 2;
+        message[16] = 0xa;
+        message[17] = 0xb;
+        assert_eq!(
+            read_host_frame(&mut reader).unwrap(),
+            Some(HostFrame::Message {
+                vp: 1,
+                sint: 2,
+                message
+            })
+        );
+        assert_eq!(
+            read_host_frame(&mut reader).unwrap(),
+            Some(HostFrame::Event {
+                vp: 1,
+                sint: 2,
+                flag: 7
+            })
+        );
+        assert_eq!(read_host_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_invalid_host_frames() {
+        // Frames sent by the guest, payload larger than a message, and
+        // truncated events are rejected.
+        for (frame_type, size) in [
+            (FRAME_GUEST_MESSAGE, 8),
+            (
+                FRAME_HOST_MESSAGE,
+                MESSAGE_HEADER_SIZE + HV_MESSAGE_PAYLOAD_SIZE + 1,
+            ),
+            (FRAME_HOST_MESSAGE, 4),
+            (FRAME_HOST_EVENT, 4),
+        ] {
+            let stream = frame(frame_type, &[&vec![0u8; size]]);
+            assert!(matches!(
+                read_host_frame(&mut stream.as_slice()),
+                Err(Error::InvalidFrame(t, s)) if t == frame_type && s == size
+            ));
+        }
+
+        // The largest payload fits in the message.
+        let stream = frame(
+            FRAME_HOST_MESSAGE,
+            &[&vec![0u8; MESSAGE_HEADER_SIZE + HV_MESSAGE_PAYLOAD_SIZE]],
+        );
+        assert!(read_host_frame(&mut stream.as_slice()).unwrap().is_some());
+
+        // A frame cut short is an error rather than a disconnection.
+        let stream = frame(FRAME_HOST_EVENT, &[&[0u8; 8]]);
+        assert!(matches!(
+            read_host_frame(&mut &stream[..12]),
+            Err(Error::Receive(_))
+        ));
+    }
+}
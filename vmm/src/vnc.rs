@@ -0,0 +1,988 @@
+// Copyright © 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! VNC server exposing the display of the virtio-gpu device, and feeding the
+//! keyboard and pointer events of the clients to the virtio-input devices.
+//!
+//! Only the Remote Framebuffer protocol 3.3, 3.7 and 3.8 without security
+//! and the raw encoding are supported. Each client is served by its own
+//! thread, which polls the display for updates between the messages of the
+//! client.
+
+use crate::vm_config::VncConfig;
+use seccompiler::{apply_filter, BpfProgram};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+use virtio_devices::{
+    GpuDisplay, InputEvent, InputEventSender, Rect, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT,
+    EV_ABS, EV_KEY, EV_REL, INPUT_ABS_MAX, REL_WHEEL,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+const RFB_VERSION: &[u8; 12] = b"RFB 003.008\n";
+const SECURITY_NONE: u8 = 1;
+const SECURITY_RESULT_OK: u32 = 0;
+const DESKTOP_NAME: &[u8] = b"Cloud Hypervisor";
+
+// Client to server messages
+const CLIENT_SET_PIXEL_FORMAT: u8 = 0;
+const CLIENT_SET_ENCODINGS: u8 = 2;
+const CLIENT_FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const CLIENT_KEY_EVENT: u8 = 4;
+const CLIENT_POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+// Server to client messages
+const SERVER_FRAMEBUFFER_UPDATE: u8 = 0;
+
+const ENCODING_RAW: i32 = 0;
+// The client can follow the changes of the size of the framebuffer.
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+// Clipboard content larger than this is considered as an attack.
+const MAX_CUT_TEXT_SIZE: usize = 1 << 20;
+const MAX_ENCODINGS: usize = 1024;
+const MAX_CLIENTS: usize = 8;
+
+// Delay between the checks for display updates, when the client is idle.
+const READ_TIMEOUT: Duration = Duration::from_millis(20);
+// Clients not reading their updates are disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const EPOLL_EVENT_LISTENER: u64 = 0;
+const EPOLL_EVENT_KILL: u64 = 1;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot bind the VNC server: {0}")]
+    Bind(#[source] io::Error),
+    #[error("Cannot create the VNC server kill event: {0}")]
+    CreateKillEvent(#[source] io::Error),
+    #[error("Cannot clone the VNC server socket: {0}")]
+    CloneSocket(#[source] io::Error),
+    #[error("Cannot spawn the VNC server thread: {0}")]
+    ThreadSpawn(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Layout of the pixels sent to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_colour: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+// Native format of the display, 0x00RRGGBB.
+const DEFAULT_PIXEL_FORMAT: PixelFormat = PixelFormat {
+    bits_per_pixel: 32,
+    depth: 24,
+    big_endian: false,
+    true_colour: true,
+    red_max: 255,
+    green_max: 255,
+    blue_max: 255,
+    red_shift: 16,
+    green_shift: 8,
+    blue_shift: 0,
+};
+
+impl PixelFormat {
+    fn parse(b: &[u8]) -> io::Result<Self> {
+        let format = PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            true_colour: b[3] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        };
+        // Colour maps are not supported.
+        if !matches!(format.bits_per_pixel, 8 | 16 | 32) || !format.true_colour {
+            return Err(invalid_data("unsupported pixel format"));
+        }
+        if [format.red_shift, format.green_shift, format.blue_shift]
+            .iter()
+            .any(|shift| *shift >= format.bits_per_pixel)
+        {
+            return Err(invalid_data("invalid pixel format"));
+        }
+        Ok(format)
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut b = [0u8; 16];
+        b[0] = self.bits_per_pixel;
+        b[1] = self.depth;
+        b[2] = self.big_endian as u8;
+        b[3] = self.true_colour as u8;
+        b[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        b[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        b[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        b[10] = self.red_shift;
+        b[11] = self.green_shift;
+        b[12] = self.blue_shift;
+        b
+    }
+
+    // Append a 0x00RRGGBB pixel converted to this format.
+    fn encode(&self, rgb: u32, out: &mut Vec<u8>) {
+        let scale = |value: u32, max: u16| (value * max as u32 + 127) / 255;
+        let value = (scale((rgb >> 16) & 0xff, self.red_max) << self.red_shift)
+            | (scale((rgb >> 8) & 0xff, self.green_max) << self.green_shift)
+            | (scale(rgb & 0xff, self.blue_max) << self.blue_shift);
+        let len = self.bits_per_pixel as usize / 8;
+        if self.big_endian {
+            out.extend_from_slice(&value.to_be_bytes()[4 - len..]);
+        } else {
+            out.extend_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest { incremental: bool, rect: Rect },
+    KeyEvent { down: bool, keysym: u32 },
+    PointerEvent { buttons: u8, x: u16, y: u16 },
+    ClientCutText,
+}
+
+fn be_u16(b: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([b[offset], b[offset + 1]])
+}
+
+fn be_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(b[offset..offset + 4].try_into().unwrap())
+}
+
+/// Parse the next message of the client, returning it along with its size,
+/// or None if it hasn't been fully received yet.
+fn parse_message(buf: &[u8]) -> io::Result<Option<(ClientMessage, usize)>> {
+    let Some(message_type) = buf.first() else {
+        return Ok(None);
+    };
+    let len = match *message_type {
+        CLIENT_SET_PIXEL_FORMAT => 20,
+        CLIENT_SET_ENCODINGS => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            let count = be_u16(buf, 2) as usize;
+            if count > MAX_ENCODINGS {
+                return Err(invalid_data("too many encodings"));
+            }
+            4 + 4 * count
+        }
+        CLIENT_FRAMEBUFFER_UPDATE_REQUEST => 10,
+        CLIENT_KEY_EVENT => 8,
+        CLIENT_POINTER_EVENT => 6,
+        CLIENT_CUT_TEXT => {
+            if buf.len() < 8 {
+                return Ok(None);
+            }
+            let size = be_u32(buf, 4) as usize;
+            if size > MAX_CUT_TEXT_SIZE {
+                return Err(invalid_data("clipboard content too large"));
+            }
+            8 + size
+        }
+        _ => return Err(invalid_data("unknown message")),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let message = match *message_type {
+        CLIENT_SET_PIXEL_FORMAT => ClientMessage::SetPixelFormat(PixelFormat::parse(&buf[4..20])?),
+        CLIENT_SET_ENCODINGS => ClientMessage::SetEncodings(
+            buf[4..len]
+                .chunks_exact(4)
+                .map(|c| i32::from_be_bytes(c.try_into().unwrap()))
+                .collect(),
+        ),
+        CLIENT_FRAMEBUFFER_UPDATE_REQUEST => ClientMessage::FramebufferUpdateRequest {
+            incremental: buf[1] != 0,
+            rect: Rect {
+                x: be_u16(buf, 2) as u32,
+                y: be_u16(buf, 4) as u32,
+                width: be_u16(buf, 6) as u32,
+                height: be_u16(buf, 8) as u32,
+            },
+        },
+        CLIENT_KEY_EVENT => ClientMessage::KeyEvent {
+            down: buf[1] != 0,
+            keysym: be_u32(buf, 4),
+        },
+        CLIENT_POINTER_EVENT => ClientMessage::PointerEvent {
+            buttons: buf[1],
+            x: be_u16(buf, 2),
+            y: be_u16(buf, 4),
+        },
+        _ => ClientMessage::ClientCutText,
+    };
+    Ok(Some((message, len)))
+}
+
+/// Linux key code of an X11 keysym, the keys being identified by their
+/// location on a US keyboard.
+fn keysym_to_key(keysym: u32) -> Option<u16> {
+    let key = match keysym {
+        // Letters
+        0x61..=0x7a => return keysym_to_key(keysym - 0x20),
+        0x41 => 30,
+        0x42 => 48,
+        0x43 => 46,
+        0x44 => 32,
+        0x45 => 18,
+        0x46 => 33,
+        0x47 => 34,
+        0x48 => 35,
+        0x49 => 23,
+        0x4a => 36,
+        0x4b => 37,
+        0x4c => 38,
+        0x4d => 50,
+        0x4e => 49,
+        0x4f => 24,
+        0x50 => 25,
+        0x51 => 16,
+        0x52 => 19,
+        0x53 => 31,
+        0x54 => 20,
+        0x55 => 22,
+        0x56 => 47,
+        0x57 => 17,
+        0x58 => 45,
+        0x59 => 21,
+        0x5a => 44,
+        // Digits and their shifted symbols
+        0x31..=0x39 => (keysym - 0x31 + 2) as u16,
+        0x30 | 0x29 => 11,
+        0x21 => 2,
+        0x40 => 3,
+        0x23 => 4,
+        0x24 => 5,
+        0x25 => 6,
+        0x5e => 7,
+        0x26 => 8,
+        0x2a => 9,
+        0x28 => 10,
+        // Punctuation
+        0x20 => 57,
+        0x2d | 0x5f => 12,
+        0x3d | 0x2b => 13,
+        0x5b | 0x7b => 26,
+        0x5d | 0x7d => 27,
+        0x3b | 0x3a => 39,
+        0x27 | 0x22 => 40,
+        0x60 | 0x7e => 41,
+        0x5c | 0x7c => 43,
+        0x2c | 0x3c => 51,
+        0x2e | 0x3e => 52,
+        0x2f | 0x3f => 53,
+        // Function keys, F1 to F10 then F11 and F12
+        0xffbe..=0xffc7 => (keysym - 0xffbe + 59) as u16,
+        0xffc8 => 87,
+        0xffc9 => 88,
+        // Editing and navigation
+        0xff08 => 14,
+        0xff09 => 15,
+        0xff0d => 28,
+        0xff13 => 119,
+        0xff14 => 70,
+        0xff15 | 0xff61 => 99,
+        0xff1b => 1,
+        0xff50 => 102,
+        0xff51 => 105,
+        0xff52 => 103,
+        0xff53 => 106,
+        0xff54 => 108,
+        0xff55 => 104,
+        0xff56 => 109,
+        0xff57 => 107,
+        0xff63 => 110,
+        0xff67 => 127,
+        0xffff => 111,
+        // Modifiers
+        0xffe1 => 42,
+        0xffe2 => 54,
+        0xffe3 => 29,
+        0xffe4 => 97,
+        0xffe5 => 58,
+        0xffe7 | 0xffeb => 125,
+        0xffe8 | 0xffec => 126,
+        0xffe9 => 56,
+        0xffea | 0xfe03 => 100,
+        // Keypad
+        0xff7f => 69,
+        0xff8d => 96,
+        0xffaa => 55,
+        0xffab => 78,
+        0xffad => 74,
+        0xffae | 0xff9f => 83,
+        0xffaf => 98,
+        0xffb0 | 0xff9e => 82,
+        0xffb1 | 0xff9c => 79,
+        0xffb2 | 0xff99 => 80,
+        0xffb3 | 0xff9b => 81,
+        0xffb4 | 0xff96 => 75,
+        0xffb5 | 0xff9d => 76,
+        0xffb6 | 0xff98 => 77,
+        0xffb7 | 0xff95 => 71,
+        0xffb8 | 0xff97 => 72,
+        0xffb9 | 0xff9a => 73,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Connection with a VNC client.
+struct VncClient<S> {
+    stream: S,
+    // Bytes received from the client and not parsed yet.
+    buf: Vec<u8>,
+    pixel_format: PixelFormat,
+    desktop_size: bool,
+    // Size of the framebuffer known by the client.
+    size: (u32, u32),
+    // Generation of the framebuffer last sent to the client.
+    generation: u64,
+    update_request: Option<(bool, Rect)>,
+    buttons: u8,
+    display: GpuDisplay,
+    keyboard: InputEventSender,
+    tablet: InputEventSender,
+    stop: Arc<AtomicBool>,
+}
+
+impl<S: Read + Write> VncClient<S> {
+    fn new(
+        stream: S,
+        display: GpuDisplay,
+        keyboard: InputEventSender,
+        tablet: InputEventSender,
+        stop: Arc<AtomicBool>,
+    ) -> Self {
+        VncClient {
+            stream,
+            buf: Vec::new(),
+            pixel_format: DEFAULT_PIXEL_FORMAT,
+            desktop_size: false,
+            size: (0, 0),
+            generation: 0,
+            update_request: None,
+            buttons: 0,
+            display,
+            keyboard,
+            tablet,
+            stop,
+        }
+    }
+
+    // Read more bytes from the client, returning false if none were
+    // received before the timeout.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.stop.load(Ordering::Acquire) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "VNC server stopped",
+            ));
+        }
+        let mut chunk = [0u8; 4096];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(count) => {
+                self.buf.extend_from_slice(&chunk[..count]);
+                Ok(true)
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_exact(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        self.stream.write_all(RFB_VERSION)?;
+        let version = self.read_exact(RFB_VERSION.len())?;
+        if !version.starts_with(b"RFB 003.") || version[11] != b'\n' {
+            return Err(invalid_data("invalid protocol version"));
+        }
+        // Unknown versions are handled as 3.3.
+        let minor = std::str::from_utf8(&version[8..11])
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(3);
+
+        if minor >= 7 {
+            self.stream.write_all(&[1, SECURITY_NONE])?;
+            if self.read_exact(1)?[0] != SECURITY_NONE {
+                return Err(invalid_data("unsupported security type"));
+            }
+            if minor >= 8 {
+                self.stream.write_all(&SECURITY_RESULT_OK.to_be_bytes())?;
+            }
+        } else {
+            self.stream
+                .write_all(&(SECURITY_NONE as u32).to_be_bytes())?;
+        }
+
+        // The shared flag is ignored, clients always share the display.
+        self.read_exact(1)?;
+
+        let (width, height) = {
+            let fb = self.display.lock();
+            (fb.width, fb.height)
+        };
+        self.size = (width, height);
+        let mut server_init = Vec::new();
+        server_init.extend_from_slice(&(width as u16).to_be_bytes());
+        server_init.extend_from_slice(&(height as u16).to_be_bytes());
+        server_init.extend_from_slice(&self.pixel_format.to_bytes());
+        server_init.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+        server_init.extend_from_slice(DESKTOP_NAME);
+        self.stream.write_all(&server_init)
+    }
+
+    fn handle_message(&mut self, message: ClientMessage) {
+        match message {
+            ClientMessage::SetPixelFormat(format) => self.pixel_format = format,
+            ClientMessage::SetEncodings(encodings) => {
+                self.desktop_size = encodings.contains(&ENCODING_DESKTOP_SIZE)
+            }
+            ClientMessage::FramebufferUpdateRequest { incremental, rect } => {
+                // A pending full update can't become incremental.
+                let incremental =
+                    incremental && self.update_request.map_or(true, |(pending, _)| pending);
+                self.update_request = Some((incremental, rect));
+            }
+            ClientMessage::KeyEvent { down, keysym } => match keysym_to_key(keysym) {
+                Some(key) => self
+                    .keyboard
+                    .send(&[InputEvent::new(EV_KEY, key, down as u32)]),
+                None => debug!("Ignoring unknown keysym 0x{:x}", keysym),
+            },
+            ClientMessage::PointerEvent { buttons, x, y } => self.pointer_event(buttons, x, y),
+            // The clipboard is not shared with the guest.
+            ClientMessage::ClientCutText => {}
+        }
+    }
+
+    fn pointer_event(&mut self, buttons: u8, x: u16, y: u16) {
+        let scale = |value: u16, size: u32| {
+            let max = size.saturating_sub(1).max(1);
+            (value as u32).min(max) * INPUT_ABS_MAX / max
+        };
+        let mut events = vec![
+            InputEvent::new(EV_ABS, ABS_X, scale(x, self.size.0)),
+            InputEvent::new(EV_ABS, ABS_Y, scale(y, self.size.1)),
+        ];
+
+        let changed = buttons ^ self.buttons;
+        for (bit, code) in [(0, BTN_LEFT), (1, BTN_MIDDLE), (2, BTN_RIGHT)] {
+            if changed & (1 << bit) != 0 {
+                events.push(InputEvent::new(EV_KEY, code, ((buttons >> bit) & 1) as u32));
+            }
+        }
+        // The wheel is reported as buttons 4 and 5, for each step.
+        let pressed = changed & buttons;
+        if pressed & (1 << 3) != 0 {
+            events.push(InputEvent::new(EV_REL, REL_WHEEL, 1));
+        }
+        if pressed & (1 << 4) != 0 {
+            events.push(InputEvent::new(EV_REL, REL_WHEEL, -1i32 as u32));
+        }
+        self.buttons = buttons;
+
+        self.tablet.send(&events);
+    }
+
+    // Send the requested update, if the requested area has been updated.
+    fn send_update(&mut self) -> io::Result<()> {
+        let Some((incremental, rect)) = self.update_request else {
+            return Ok(());
+        };
+
+        let fb = self.display.lock();
+        let mut rects = Vec::new();
+        let mut count = 0u16;
+        let area = if (fb.width, fb.height) != self.size && self.desktop_size {
+            self.size = (fb.width, fb.height);
+            for v in [0, 0, fb.width as u16, fb.height as u16] {
+                rects.extend_from_slice(&v.to_be_bytes());
+            }
+            rects.extend_from_slice(&ENCODING_DESKTOP_SIZE.to_be_bytes());
+            count += 1;
+            Some(Rect {
+                x: 0,
+                y: 0,
+                width: fb.width,
+                height: fb.height,
+            })
+        } else if !incremental {
+            Some(rect)
+        } else {
+            match fb.damage_since(self.generation) {
+                Some(damage) => damage.intersection(&rect),
+                // Nothing changed, the request stays pending.
+                None => return Ok(()),
+            }
+        };
+
+        // Clients unaware of resizes only get what fits their framebuffer.
+        let visible = Rect {
+            x: 0,
+            y: 0,
+            width: fb.width.min(self.size.0),
+            height: fb.height.min(self.size.1),
+        };
+        if let Some(area) = area.and_then(|a| a.intersection(&visible)) {
+            for v in [area.x, area.y, area.width, area.height] {
+                rects.extend_from_slice(&(v as u16).to_be_bytes());
+            }
+            rects.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+            for y in area.y..area.y + area.height {
+                let row = (y * fb.width) as usize;
+                for x in area.x..area.x + area.width {
+                    self.pixel_format
+                        .encode(fb.pixels[row + x as usize], &mut rects);
+                }
+            }
+            count += 1;
+        }
+        self.generation = fb.generation();
+        drop(fb);
+
+        self.update_request = None;
+        let mut update = vec![SERVER_FRAMEBUFFER_UPDATE, 0];
+        update.extend_from_slice(&count.to_be_bytes());
+        update.extend_from_slice(&rects);
+        self.stream.write_all(&update)
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.handshake()?;
+        loop {
+            self.fill()?;
+            while let Some((message, len)) = parse_message(&self.buf)? {
+                self.buf.drain(..len);
+                self.handle_message(message);
+            }
+            self.send_update()?;
+        }
+    }
+}
+
+/// VNC server, listening for the clients of the display.
+pub struct VncServer {
+    listener: TcpListener,
+    display: GpuDisplay,
+    keyboard: InputEventSender,
+    tablet: InputEventSender,
+    kill_evt: EventFd,
+}
+
+impl VncServer {
+    pub fn new(
+        config: &VncConfig,
+        display: GpuDisplay,
+        keyboard: InputEventSender,
+        tablet: InputEventSender,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(config.listen).map_err(Error::Bind)?;
+        listener.set_nonblocking(true).map_err(Error::Bind)?;
+        info!("VNC server listening on {}", config.listen);
+
+        Ok(VncServer {
+            listener,
+            display,
+            keyboard,
+            tablet,
+            kill_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::CreateKillEvent)?,
+        })
+    }
+
+    pub fn start(&self, seccomp_filter: BpfProgram) -> Result<thread::JoinHandle<()>> {
+        let listener = self.listener.try_clone().map_err(Error::CloneSocket)?;
+        let kill_evt = self.kill_evt.try_clone().map_err(Error::CreateKillEvent)?;
+        let display = self.display.clone();
+        let keyboard = self.keyboard.clone();
+        let tablet = self.tablet.clone();
+
+        thread::Builder::new()
+            .name("vnc".to_string())
+            .spawn(move || {
+                // The client threads inherit the filter.
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+                }
+
+                if let Err(e) = run_listener(listener, kill_evt, display, keyboard, tablet) {
+                    error!("Stopping the VNC server: {}", e);
+                }
+            })
+            .map_err(Error::ThreadSpawn)
+    }
+
+    pub fn shutdown(&self) {
+        if let Err(e) = self.kill_evt.write(1) {
+            warn!("Error stopping the VNC server: {}", e);
+        }
+    }
+}
+
+fn run_listener(
+    listener: TcpListener,
+    kill_evt: EventFd,
+    display: GpuDisplay,
+    keyboard: InputEventSender,
+    tablet: InputEventSender,
+) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    // SAFETY: epoll_fd is a valid file descriptor owned by nobody else.
+    let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        listener.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, EPOLL_EVENT_LISTENER),
+    )?;
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        kill_evt.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, EPOLL_EVENT_KILL),
+    )?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut clients: Vec<thread::JoinHandle<()>> = Vec::new();
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+    let result = 'outer: loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            match event.data {
+                EPOLL_EVENT_LISTENER => {
+                    let (stream, addr) = match listener.accept() {
+                        Ok(client) => client,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                        Err(e) => break 'outer Err(e),
+                    };
+                    clients.retain(|client| !client.is_finished());
+                    if clients.len() >= MAX_CLIENTS {
+                        warn!("Rejecting VNC client {}: too many clients", addr);
+                        continue;
+                    }
+                    info!("VNC client {} connected", addr);
+
+                    if let Err(e) = stream
+                        .set_read_timeout(Some(READ_TIMEOUT))
+                        .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                        .and_then(|_| stream.set_nodelay(true))
+                    {
+                        warn!("Error configuring VNC client {}: {}", addr, e);
+                        continue;
+                    }
+                    let mut client = VncClient::new(
+                        stream,
+                        display.clone(),
+                        keyboard.clone(),
+                        tablet.clone(),
+                        stop.clone(),
+                    );
+                    match thread::Builder::new()
+                        .name("vnc_client".to_string())
+                        .spawn(move || match client.run() {
+                            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                                info!("VNC client {} disconnected", addr)
+                            }
+                            Err(e) => info!("VNC client {} disconnected: {}", addr, e),
+                            Ok(()) => {}
+                        }) {
+                        Ok(thread) => clients.push(thread),
+                        Err(e) => warn!("Error spawning VNC client thread: {}", e),
+                    }
+                }
+                EPOLL_EVENT_KILL => break 'outer Ok(()),
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    stop.store(true, Ordering::Release);
+    for client in clients {
+        client.join().ok();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use seccompiler::SeccompAction;
+    use std::os::unix::net::UnixStream;
+    use virtio_devices::{Input, InputKind};
+
+    fn input_sender(kind: InputKind) -> InputEventSender {
+        Input::new(
+            "input0".to_string(),
+            kind,
+            false,
+            SeccompAction::Allow,
+            EventFd::new(0).unwrap(),
+            None,
+        )
+        .unwrap()
+        .event_sender()
+    }
+
+    // Start serving a client, returning the socket of the client.
+    fn connect(
+        display: &GpuDisplay,
+        stop: &Arc<AtomicBool>,
+    ) -> (UnixStream, thread::JoinHandle<io::Result<()>>) {
+        let (server, client) = UnixStream::pair().unwrap();
+        server.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut vnc_client = VncClient::new(
+            server,
+            display.clone(),
+            input_sender(InputKind::Keyboard),
+            input_sender(InputKind::Tablet),
+            stop.clone(),
+        );
+        (client, thread::spawn(move || vnc_client.run()))
+    }
+
+    fn read_bytes(stream: &mut UnixStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        let mut stream = vec![CLIENT_SET_ENCODINGS, 0, 0, 2];
+        stream.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+        stream.extend_from_slice(&ENCODING_DESKTOP_SIZE.to_be_bytes());
+        stream.extend_from_slice(&[CLIENT_FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 1, 0, 2, 0, 3, 0, 4]);
+        stream.extend_from_slice(&[CLIENT_KEY_EVENT, 1, 0, 0, 0, 0, 0xff, 0x0d]);
+        stream.extend_from_slice(&[CLIENT_POINTER_EVENT, 5, 0x01, 0x00, 0x00, 0x10]);
+        stream.extend_from_slice(&[CLIENT_CUT_TEXT, 0, 0, 0, 0, 0, 0, 2, b'h', b'i']);
+
+        let expected = [
+            ClientMessage::SetEncodings(vec![ENCODING_RAW, ENCODING_DESKTOP_SIZE]),
+            ClientMessage::FramebufferUpdateRequest {
+                incremental: true,
+                rect: Rect {
+                    x: 1,
+                    y: 2,
+                    width: 3,
+                    height: 4,
+                },
+            },
+            ClientMessage::KeyEvent {
+                down: true,
+                keysym: 0xff0d,
+            },
+            ClientMessage::PointerEvent {
+                buttons: 5,
+                x: 0x100,
+                y: 0x10,
+            },
+            ClientMessage::ClientCutText,
+        ];
+        let mut buf = &stream[..];
+        for message in expected {
+            // Partial messages are kept for later.
+            assert_eq!(parse_message(&buf[..1]).unwrap(), None);
+            let (parsed, len) = parse_message(buf).unwrap().unwrap();
+            assert_eq!(parsed, message);
+            buf = &buf[len..];
+        }
+        assert!(buf.is_empty());
+        assert_eq!(parse_message(buf).unwrap(), None);
+
+        assert!(parse_message(&[0x42]).is_err());
+        assert!(parse_message(&[CLIENT_CUT_TEXT, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let mut message = vec![CLIENT_SET_PIXEL_FORMAT, 0, 0, 0];
+        message.extend_from_slice(&DEFAULT_PIXEL_FORMAT.to_bytes());
+        assert_eq!(
+            parse_message(&message).unwrap(),
+            Some((ClientMessage::SetPixelFormat(DEFAULT_PIXEL_FORMAT), 20))
+        );
+
+        let mut out = Vec::new();
+        DEFAULT_PIXEL_FORMAT.encode(0x12_34_56, &mut out);
+        assert_eq!(out, [0x56, 0x34, 0x12, 0]);
+
+        // RGB565, big endian
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert_eq!(PixelFormat::parse(&rgb565.to_bytes()).unwrap(), rgb565);
+        out.clear();
+        rgb565.encode(0xff_00_ff, &mut out);
+        rgb565.encode(0x00_ff_00, &mut out);
+        assert_eq!(out, [0xf8, 0x1f, 0x07, 0xe0]);
+
+        // Colour maps are not supported.
+        let colour_map = PixelFormat {
+            true_colour: false,
+            ..DEFAULT_PIXEL_FORMAT
+        };
+        assert!(PixelFormat::parse(&colour_map.to_bytes()).is_err());
+        let invalid_shift = PixelFormat {
+            bits_per_pixel: 8,
+            ..DEFAULT_PIXEL_FORMAT
+        };
+        assert!(PixelFormat::parse(&invalid_shift.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_keysyms() {
+        assert_eq!(keysym_to_key(b'a' as u32), Some(30));
+        assert_eq!(keysym_to_key(b'A' as u32), Some(30));
+        assert_eq!(keysym_to_key(b'z' as u32), Some(44));
+        assert_eq!(keysym_to_key(b'1' as u32), Some(2));
+        assert_eq!(keysym_to_key(b'0' as u32), Some(11));
+        assert_eq!(keysym_to_key(b'!' as u32), keysym_to_key(b'1' as u32));
+        assert_eq!(keysym_to_key(b'?' as u32), keysym_to_key(b'/' as u32));
+        assert_eq!(keysym_to_key(0xffbe), Some(59));
+        assert_eq!(keysym_to_key(0xffc9), Some(88));
+        assert_eq!(keysym_to_key(0xff0d), Some(28));
+        assert_eq!(keysym_to_key(0xffe1), Some(42));
+        assert_eq!(keysym_to_key(0xffb5), keysym_to_key(0xff9d));
+        assert_eq!(keysym_to_key(0x20ac), None);
+    }
+
+    #[test]
+    fn test_session() {
+        let display = GpuDisplay::new(4, 2);
+        display.lock().pixels[5] = 0x11_22_33;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (mut client, server) = connect(&display, &stop);
+
+        assert_eq!(read_bytes(&mut client, 12), RFB_VERSION);
+        client.write_all(b"RFB 003.008\n").unwrap();
+        assert_eq!(read_bytes(&mut client, 2), [1, SECURITY_NONE]);
+        client.write_all(&[SECURITY_NONE]).unwrap();
+        assert_eq!(read_bytes(&mut client, 4), [0, 0, 0, 0]);
+        client.write_all(&[1]).unwrap();
+
+        let server_init = read_bytes(&mut client, 24 + DESKTOP_NAME.len());
+        assert_eq!(server_init[..4], [0, 4, 0, 2]);
+        assert_eq!(server_init[4..20], DEFAULT_PIXEL_FORMAT.to_bytes());
+        assert_eq!(&server_init[24..], DESKTOP_NAME);
+
+        // Request the second row.
+        client
+            .write_all(&[CLIENT_FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 0, 0, 1, 0, 4, 0, 1])
+            .unwrap();
+        let update = read_bytes(&mut client, 4 + 12 + 16);
+        assert_eq!(update[..4], [SERVER_FRAMEBUFFER_UPDATE, 0, 0, 1]);
+        assert_eq!(update[4..16], [0, 0, 0, 1, 0, 4, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(update[16..20], [0; 4]);
+        assert_eq!(update[20..24], [0x33, 0x22, 0x11, 0]);
+
+        // Nothing changed, the incremental update waits for changes.
+        client
+            .write_all(&[CLIENT_FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 0, 4, 0, 2])
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert!(client.read(&mut buf).is_err());
+
+        stop.store(true, Ordering::Release);
+        assert_eq!(
+            server.join().unwrap().unwrap_err().kind(),
+            io::ErrorKind::ConnectionAborted
+        );
+    }
+
+    #[test]
+    fn test_legacy_handshake() {
+        let display = GpuDisplay::new(4, 2);
+        let stop = Arc::new(AtomicBool::new(false));
+        let (mut client, server) = connect(&display, &stop);
+
+        read_bytes(&mut client, 12);
+        client.write_all(b"RFB 003.003\n").unwrap();
+        assert_eq!(read_bytes(&mut client, 4), [0, 0, 0, SECURITY_NONE]);
+        client.write_all(&[0]).unwrap();
+        read_bytes(&mut client, 24 + DESKTOP_NAME.len());
+
+        // Unknown messages close the connection.
+        client.write_all(&[0x42]).unwrap();
+        assert_eq!(
+            server.join().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}